@@ -0,0 +1,116 @@
+//! `array_ops` JIT kernels against the equivalent std iterator code,
+//! from L1-resident sizes up to arrays past the non-temporal store threshold,
+//! and each matrix multiply kernel the CPU supports on square matrices.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use nanoforge::array_ops::{
+    matmul_i64_with, matmul_i8_with, vec_add_i64, vec_scale_i64, vec_sum_i64, MatmulKernel,
+};
+
+const SIZES: &[usize] = &[1 << 10, 1 << 16, 1 << 18];
+
+fn input(len: usize) -> Vec<i64> {
+    (0..len as i64).collect()
+}
+
+fn vec_add(c: &mut Criterion) {
+    let mut group = c.benchmark_group("vec_add");
+    for &len in SIZES {
+        let (a, b) = (input(len), input(len));
+        let mut out = vec![0i64; len];
+        group.throughput(Throughput::Elements(len as u64));
+        group.bench_function(BenchmarkId::new("nanoforge", len), |bench| {
+            bench.iter(|| vec_add_i64(black_box(&a), black_box(&b), &mut out))
+        });
+        group.bench_function(BenchmarkId::new("std", len), |bench| {
+            bench.iter(|| {
+                for ((o, x), y) in out.iter_mut().zip(black_box(&a)).zip(black_box(&b)) {
+                    *o = x + y;
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+fn vec_sum(c: &mut Criterion) {
+    let mut group = c.benchmark_group("vec_sum");
+    for &len in SIZES {
+        let a = input(len);
+        group.throughput(Throughput::Elements(len as u64));
+        group.bench_function(BenchmarkId::new("nanoforge", len), |bench| {
+            bench.iter(|| vec_sum_i64(black_box(&a)))
+        });
+        group.bench_function(BenchmarkId::new("std", len), |bench| {
+            bench.iter(|| black_box(&a).iter().sum::<i64>())
+        });
+    }
+    group.finish();
+}
+
+fn vec_scale(c: &mut Criterion) {
+    let mut group = c.benchmark_group("vec_scale");
+    for &len in SIZES {
+        let mut a = input(len);
+        group.throughput(Throughput::Elements(len as u64));
+        group.bench_function(BenchmarkId::new("nanoforge", len), |bench| {
+            bench.iter(|| vec_scale_i64(&mut a, black_box(1)))
+        });
+        group.bench_function(BenchmarkId::new("std", len), |bench| {
+            bench.iter(|| a.iter_mut().for_each(|x| *x *= black_box(1)))
+        });
+    }
+    group.finish();
+}
+
+fn matmul(c: &mut Criterion) {
+    let mut group = c.benchmark_group("matmul");
+    for dim in [16usize, 64, 256] {
+        let a = input(dim * dim);
+        let mut out = vec![0i64; dim * dim];
+        group.throughput(Throughput::Elements((dim * dim * dim) as u64));
+        for kernel in MatmulKernel::available_i64() {
+            group.bench_function(
+                BenchmarkId::new(format!("i64/{}", kernel.name()), dim),
+                |bench| {
+                    bench.iter(|| {
+                        matmul_i64_with(
+                            kernel,
+                            black_box(&a),
+                            black_box(&a),
+                            &mut out,
+                            dim,
+                            dim,
+                            dim,
+                        )
+                    })
+                },
+            );
+        }
+
+        let a: Vec<i8> = (0..dim * dim).map(|i| i as i8).collect();
+        let mut out = vec![0i32; dim * dim];
+        for kernel in MatmulKernel::available_i8() {
+            group.bench_function(
+                BenchmarkId::new(format!("i8/{}", kernel.name()), dim),
+                |bench| {
+                    bench.iter(|| {
+                        matmul_i8_with(
+                            kernel,
+                            black_box(&a),
+                            black_box(&a),
+                            &mut out,
+                            dim,
+                            dim,
+                            dim,
+                        )
+                    })
+                },
+            );
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, vec_add, vec_sum, vec_scale, matmul);
+criterion_main!(benches);
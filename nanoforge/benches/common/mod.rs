@@ -0,0 +1,59 @@
+//! The .nf program suite shared by the benches.
+//!
+//! Every `*.nf` file in `benches/programs` is part of the suite. To add your
+//! own programs without touching the tree, list them in `NANOFORGE_BENCH_FILES`
+//! (separated like `PATH`):
+//!
+//! ```text
+//! NANOFORGE_BENCH_FILES=my_kernel.nf:other.nf cargo bench --bench compile
+//! ```
+//!
+//! A program is benchmarked through its `main`, which receives the sandbox
+//! input as its first argument (`main(n)`); programs whose `main` takes no
+//! arguments simply ignore it.
+#![allow(dead_code)]
+
+use nanoforge::ir::Program;
+use nanoforge::parser::Parser as NanoParser;
+use std::path::{Path, PathBuf};
+
+pub const PROGRAMS_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/benches/programs");
+pub const EXTRA_FILES_VAR: &str = "NANOFORGE_BENCH_FILES";
+
+pub struct BenchProgram {
+    /// File stem, used as the benchmark id
+    pub name: String,
+    pub source: String,
+    pub program: Program,
+}
+
+/// Load the suite; panics with the offending path on unreadable or invalid files
+pub fn suite() -> Vec<BenchProgram> {
+    let mut paths: Vec<PathBuf> = std::fs::read_dir(PROGRAMS_DIR)
+        .unwrap_or_else(|e| panic!("{}: {}", PROGRAMS_DIR, e))
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|p| p.extension().is_some_and(|ext| ext == "nf"))
+        .collect();
+    paths.sort();
+    if let Some(extra) = std::env::var_os(EXTRA_FILES_VAR) {
+        paths.extend(std::env::split_paths(&extra).filter(|p| !p.as_os_str().is_empty()));
+    }
+    paths.iter().map(|p| load(p)).collect()
+}
+
+fn load(path: &Path) -> BenchProgram {
+    let source =
+        std::fs::read_to_string(path).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+    let program = NanoParser::new()
+        .parse(&source)
+        .unwrap_or_else(|e| panic!("{}: parse error: {}", path.display(), e));
+    let name = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.display().to_string());
+    BenchProgram {
+        name,
+        source,
+        program,
+    }
+}
@@ -0,0 +1,49 @@
+//! Compile latency of the JIT pipeline for every program in the suite
+//! (see `common` for adding your own): parsing, optimize + codegen at each
+//! level, and generating the full SOAE variant set.
+
+mod common;
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use nanoforge::compiler::Compiler;
+use nanoforge::parser::Parser as NanoParser;
+use nanoforge::variant_generator::VariantGenerator;
+
+fn parse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse");
+    for p in common::suite() {
+        group.bench_with_input(BenchmarkId::from_parameter(&p.name), &p.source, |b, src| {
+            b.iter(|| NanoParser::new().parse(black_box(src)).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn compile(c: &mut Criterion) {
+    for p in common::suite() {
+        let mut group = c.benchmark_group(format!("compile/{}", p.name));
+        for level in 0..=3u8 {
+            group.bench_with_input(BenchmarkId::new("O", level), &level, |b, &level| {
+                b.iter(|| Compiler::compile_program(black_box(&p.program), level).unwrap())
+            });
+        }
+        group.finish();
+    }
+}
+
+fn variants(c: &mut Criterion) {
+    let generator = VariantGenerator::new();
+    let mut group = c.benchmark_group("generate_variants");
+    group.sample_size(20);
+    for p in common::suite() {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(&p.name),
+            &p.program,
+            |b, prog| b.iter(|| generator.generate_variants(black_box(prog)).unwrap()),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, parse, compile, variants);
+criterion_main!(benches);
@@ -0,0 +1,12 @@
+# Straight-line calls; exercises prologues, argument passing and returns
+fn main(n) {
+    x = mul_add(n, 7, 0)
+    y = mul_add(2, 3, x)
+    return y
+}
+
+fn mul_add(a, b, c) {
+    p = a * b
+    r = p + c
+    return r
+}
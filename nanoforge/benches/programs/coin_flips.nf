@@ -0,0 +1,16 @@
+# Counts the draws of a 64-bit LCG that land below n. With n = 0 the
+# branch is a coin flip (the sign bit); with n = i64::MIN it is never taken.
+fn main(n) {
+    seed = 12345
+    hits = 0
+    i = 0
+    while i < 1000 {
+        seed = seed * 1103515245
+        seed = seed + 12345
+        if seed < n {
+            hits = hits + 1
+        }
+        i = i + 1
+    }
+    return hits
+}
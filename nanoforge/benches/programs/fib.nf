@@ -0,0 +1,19 @@
+# Recursive fib(n); the sandbox runs it with a small input
+fn fib(n) {
+    limit = 2
+    if n < limit goto base
+    n1 = n - 1
+    a = fib(n1)
+    n2 = n - 2
+    b = fib(n2)
+    res = a + b
+    return res
+
+base:
+    return n
+}
+
+fn main(n) {
+    res = fib(n)
+    return res
+}
@@ -0,0 +1,10 @@
+# main(n) = 1 + 2 + ... + n
+fn main(n) {
+    sum = 0
+    i = n
+    while i > 0 {
+        sum = sum + i
+        i = i - 1
+    }
+    return sum
+}
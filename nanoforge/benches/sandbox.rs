@@ -0,0 +1,80 @@
+//! Overhead of the measuring sandbox: a direct call of each suite program's
+//! scalar variant against the same calls made inside
+//! `NanosecondSandbox::benchmark`. Both report per-call throughput, so the
+//! gap is the sandbox's cost per measured iteration.
+//!
+//! `select` puts `coin_flips.nf` through the sandbox with single-assignment
+//! ifs kept as jumps and lowered to cmp+cmov, on data the branch predictor
+//! can and cannot learn.
+
+mod common;
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use nanoforge::compiled_program::CompiledProgram;
+use nanoforge::compiler::CompileOptions;
+use nanoforge::sandbox::{NanosecondSandbox, SandboxConfig};
+use nanoforge::variant_generator::VariantGenerator;
+
+const INPUT: u64 = 10;
+const ITERATIONS: u32 = 100;
+
+fn sandbox_overhead(c: &mut Criterion) {
+    let generator = VariantGenerator::new();
+    // One fixed batch, no warmup, pinning or retries: only the bookkeeping remains
+    let sandbox = NanosecondSandbox::new(SandboxConfig {
+        warmup_iterations: 0,
+        measurement_iterations: ITERATIONS,
+        pin_to_core: None,
+        throttle_retries: 0,
+        target_precision: None,
+        ..SandboxConfig::default()
+    });
+
+    let mut group = c.benchmark_group("sandbox");
+    group.throughput(Throughput::Elements(ITERATIONS as u64));
+    for p in common::suite() {
+        let variants = generator.generate_variants(&p.program).unwrap();
+        let variant = &variants[0];
+        group.bench_function(BenchmarkId::new("direct", &p.name), |b| {
+            b.iter(|| {
+                for _ in 0..ITERATIONS {
+                    black_box(variant.execute(black_box(INPUT)));
+                }
+            })
+        });
+        group.bench_function(BenchmarkId::new("measured", &p.name), |b| {
+            b.iter(|| sandbox.benchmark(variant, black_box(INPUT)))
+        });
+    }
+    group.finish();
+}
+
+fn select_lowering(c: &mut Criterion) {
+    let sandbox = NanosecondSandbox::default();
+    let suite = common::suite();
+    let coin_flips = suite
+        .iter()
+        .find(|p| p.name == "coin_flips")
+        .expect("benches/programs/coin_flips.nf");
+
+    let mut group = c.benchmark_group("select");
+    for (lowering, keep_branches) in [("branch", true), ("cmov", false)] {
+        let options = CompileOptions::new(2).keep_branches(keep_branches);
+        let program = CompiledProgram::compile(&coin_flips.program, &options).unwrap();
+        let main: extern "C" fn(u64) -> u64 = program.get("main").unwrap();
+        for (data, input) in [("random", 0), ("predictable", i64::MIN as u64)] {
+            let cycles = sandbox.benchmark_fn(main, input).cycles_per_op;
+            println!(
+                "select/{}/{}: {} cycles per call (sandbox)",
+                lowering, data, cycles
+            );
+            group.bench_function(BenchmarkId::new(lowering, data), |b| {
+                b.iter(|| main(black_box(input)))
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, sandbox_overhead, select_lowering);
+criterion_main!(benches);
@@ -0,0 +1,209 @@
+//! Alignment Analysis
+//!
+//! Aligned (vmovdqa) and non-temporal (vmovntdq) vector accesses fault on
+//! an address that isn't a multiple of the vector size, so they may only
+//! be used where that is provable. This pass tracks, for every register
+//! before every instruction, how many low bits are known to be zero:
+//!
+//! - `Alloc` with an alignment gives log2 of it; plain `Alloc` gives 4
+//!   (malloc returns 16-byte aligned blocks on x86-64)
+//! - immediates give their trailing zeros; `Add`/`Sub` keep the fewer of
+//!   their operands', `Mul` adds them
+//! - anything else (arguments, loads, calls) is unknown: 0
+//!
+//! At a label the facts of every path in are met (the fewest bits win), so
+//! a counter stepped by 4 from 0 is known to be a multiple of 4 only until
+//! a path steps it by 1. `alloc_aligned` memory comes from the runtime's
+//! `nf_alloc_aligned`.
+
+use crate::ir::{Function, Opcode, Operand};
+use std::collections::HashMap;
+
+/// Known zero bits are capped here (4096 bytes, the largest `alloc_aligned`)
+pub const MAX_BITS: u32 = 12;
+
+/// Alignments `alloc_aligned` accepts
+pub const ALIGNMENTS: std::ops::RangeInclusive<u32> = 8..=(1 << MAX_BITS);
+
+/// Known zero low bits per register at one program point; registers not
+/// listed have none
+pub type Facts = HashMap<u8, u32>;
+
+/// Bits of `v` known to be zero
+fn imm_bits(v: i64) -> u32 {
+    v.trailing_zeros().min(MAX_BITS)
+}
+
+fn bits(facts: &Facts, op: &Option<Operand>) -> u32 {
+    match op {
+        Some(Operand::Reg(r)) => facts.get(r).copied().unwrap_or(0),
+        Some(Operand::Imm(v)) => imm_bits(*v as i64),
+        _ => 0,
+    }
+}
+
+/// Facts before every instruction of `func` (None where unreachable)
+pub fn analyze(func: &Function) -> Vec<Option<Facts>> {
+    let instrs = &func.instructions;
+    let labels: HashMap<&str, usize> = instrs
+        .iter()
+        .enumerate()
+        .filter_map(|(i, instr)| match (&instr.op, &instr.dest) {
+            (Opcode::Label, Some(Operand::Label(name))) => Some((name.as_str(), i)),
+            _ => None,
+        })
+        .collect();
+
+    let mut before: Vec<Option<Facts>> = vec![None; instrs.len()];
+    if instrs.is_empty() {
+        return before;
+    }
+    before[0] = Some(Facts::new());
+    let mut work = vec![0];
+    while let Some(i) = work.pop() {
+        let Some(facts) = before[i].clone() else {
+            continue;
+        };
+        let instr = &instrs[i];
+        let after = transfer(facts, instr);
+
+        let targets = instr
+            .jump_targets()
+            .into_iter()
+            .filter_map(|name| labels.get(name).copied());
+        let falls_through = !instr.ends_block();
+        let successors = targets.chain((falls_through && i + 1 < instrs.len()).then_some(i + 1));
+        for next in successors {
+            let merged = match &before[next] {
+                None => after.clone(),
+                Some(old) => meet(old, &after),
+            };
+            if before[next].as_ref() != Some(&merged) {
+                before[next] = Some(merged);
+                work.push(next);
+            }
+        }
+    }
+    before
+}
+
+/// Facts true on both paths: the fewer known bits of each register
+fn meet(a: &Facts, b: &Facts) -> Facts {
+    a.iter()
+        .filter_map(|(r, &x)| b.get(r).map(|&y| (*r, x.min(y))))
+        .filter(|&(_, n)| n > 0)
+        .collect()
+}
+
+fn transfer(mut facts: Facts, instr: &crate::ir::Instruction) -> Facts {
+    let Some(Operand::Reg(dest)) = instr.dest else {
+        return facts;
+    };
+    if matches!(
+        instr.op,
+        Opcode::Store(_) | Opcode::VStore(..) | Opcode::PrefetchT0
+    ) {
+        return facts;
+    }
+    let old = bits(&facts, &instr.dest);
+    let src = bits(&facts, &instr.src1);
+    let known = match instr.op {
+        Opcode::Mov | Opcode::Abs => src,
+        Opcode::Add | Opcode::Sub | Opcode::Min | Opcode::Max | Opcode::CMov(_) => old.min(src),
+        Opcode::Mul => (old + src).min(MAX_BITS),
+        Opcode::Alloc => match instr.src2 {
+            Some(Operand::Imm(align)) => imm_bits(align as i64),
+            _ => 4,
+        },
+        _ => 0,
+    };
+    if known > 0 {
+        facts.insert(dest, known);
+    } else {
+        facts.remove(&dest);
+    }
+    facts
+}
+
+/// Byte alignment provable for the address of a Load, Store, VLoad or
+/// VStore, given the facts before it
+pub fn access_alignment(facts: &Facts, instr: &crate::ir::Instruction) -> u32 {
+    let (base, index) = match instr.op {
+        Opcode::Load(_) | Opcode::VLoad(..) => (&instr.src1, &instr.src2),
+        Opcode::Store(_) | Opcode::VStore(..) => (&instr.dest, &instr.src1),
+        _ => return 1,
+    };
+    let disp = instr.op.displacement().unwrap_or(0) as i64;
+    // base + (index + disp) * 8
+    let element = match index {
+        Some(Operand::Imm(i)) => imm_bits(*i as i64 + disp),
+        _ => bits(facts, index).min(imm_bits(disp)),
+    };
+    1 << bits(facts, base).min(element + 3).min(MAX_BITS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    fn main_of(src: &str) -> Function {
+        Parser::new().parse(src).unwrap().functions.remove(0)
+    }
+
+    /// Provable alignment of every Load/Store in `func`, in order
+    fn alignments(func: &Function) -> Vec<u32> {
+        let facts = analyze(func);
+        func.instructions
+            .iter()
+            .zip(&facts)
+            .filter(|(i, _)| matches!(i.op, Opcode::Load(_) | Opcode::Store(_)))
+            .map(|(i, f)| access_alignment(f.as_ref().unwrap(), i))
+            .collect()
+    }
+
+    #[test]
+    fn test_tracks_allocations_and_indices() {
+        let func = main_of(
+            "fn main(p) {
+                A = alloc_aligned(256, 64)
+                B = alloc(256)
+                A[0] = 1
+                A[1] = 1
+                A[4] = 1
+                B[8] = 1
+                p[0] = 1
+                k = 16
+                A[k] = 1
+                A[k+2] = 1
+                return 0
+            }",
+        );
+        assert_eq!(alignments(&func), [64, 8, 32, 16, 1, 64, 16]);
+    }
+
+    #[test]
+    fn test_paths_meet_at_labels() {
+        // i steps by 4 in the first loop and by 1 in the second: in the
+        // first it is a multiple of 4, so A[i] is 32-byte aligned
+        let func = main_of(
+            "fn main(n) {
+                A = alloc_aligned(800, 32)
+                i = 0
+                label quad
+                if i == 96 goto single
+                A[i] = 1
+                i = i + 4
+                goto quad
+                label single
+                if i == n goto done
+                A[i] = 1
+                i = i + 1
+                goto single
+                label done
+                return 0
+            }",
+        );
+        assert_eq!(alignments(&func), [32, 8]);
+    }
+}
@@ -0,0 +1,220 @@
+//! Abstract Syntax Tree
+//!
+//! `Parser::parse_ast` turns a script into this tree without allocating a
+//! single register; `lowering::lower` then walks it to emit IR. Keeping the
+//! two apart lets the language grow (else branches, precedence, imports)
+//! without threading every feature through instruction emission.
+
+use crate::ir::Hints;
+use std::fmt;
+
+/// Where a construct starts in the source, for error messages
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Span {
+    pub line: usize,
+    pub col: usize,
+}
+
+/// A leaf operand: an integer literal or a named variable
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Int(i32),
+    Var(String),
+}
+
+impl Value {
+    /// A literal if `text` parses as one, a variable otherwise
+    pub fn from_token(text: &str) -> Self {
+        match text.parse::<i32>() {
+            Ok(n) => Value::Int(n),
+            Err(_) => Value::Var(text.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Int(n) => write!(f, "{}", n),
+            Value::Var(name) => f.write_str(name),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Min,
+    Max,
+}
+
+/// Single-operand intrinsics: `popcnt(x)`, `ctz(x)`, `clz(x)`, `abs(x)`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOp {
+    Popcnt,
+    Ctz,
+    Clz,
+    Abs,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CmpOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// `lhs op rhs`, as used by `if`, `while` and `for`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Condition {
+    pub lhs: Value,
+    pub op: CmpOp,
+    pub rhs: Value,
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let op = match self.op {
+            CmpOp::Eq => "==",
+            CmpOp::Ne => "!=",
+            CmpOp::Lt => "<",
+            CmpOp::Le => "<=",
+            CmpOp::Gt => ">",
+            CmpOp::Ge => ">=",
+        };
+        write!(f, "{} {} {}", self.lhs, op, self.rhs)
+    }
+}
+
+/// `array[index]`, or `array[row, col]` for 2D arrays; the last index may
+/// add a constant (`a[i+1]`, `m[r, c-1]`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Index {
+    pub array: String,
+    pub index: Value,
+    pub col: Option<Value>,
+    /// Constant added to the last index
+    pub offset: i32,
+    /// Position of the (row) index
+    pub at: Span,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    Value(Value),
+    /// `a + b`, `min(a, b)`, ...
+    Binary(BinOp, Value, Value),
+    Unary(UnaryOp, Value),
+    Load(Index),
+    Call {
+        name: String,
+        args: Vec<Value>,
+    },
+    /// `alloc(bytes)`, or `alloc_aligned(bytes, align)` at a multiple of
+    /// `align`
+    Alloc {
+        size: Value,
+        align: Option<u32>,
+    },
+    /// `alloc2d(rows, cols)`: rows * cols elements with row stride cols
+    Alloc2d {
+        rows: Value,
+        cols: Value,
+        at: Span,
+    },
+}
+
+/// `dest = value`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Assign {
+    pub dest: String,
+    pub value: Expr,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Stmt {
+    Assign(Assign),
+    /// `let dest = value`: a new variable in this block, shadowing any
+    /// outer one of the same name
+    Let(Assign),
+    /// `array[index] = value`
+    Store {
+        index: Index,
+        value: Value,
+    },
+    Return(Value),
+    /// `label name` or `name:`, with the hints of the loop it heads
+    Label {
+        name: String,
+        hints: Option<Hints>,
+    },
+    Goto(String),
+    /// `if cond goto label`
+    IfGoto {
+        cond: Condition,
+        label: String,
+    },
+    /// `if cond { body }`
+    If {
+        cond: Condition,
+        body: Block,
+    },
+    While {
+        cond: Condition,
+        body: Block,
+        hints: Option<Hints>,
+    },
+    /// `for (init; cond; step) { body }`
+    For {
+        init: Box<Assign>,
+        cond: Condition,
+        step: Option<Box<Assign>>,
+        body: Block,
+        hints: Option<Hints>,
+    },
+    /// `switch value { 0 => { .. } 1 => stmt _ => { .. } }`: runs the arm
+    /// whose constant equals value, else the `_` arm if any (no fallthrough)
+    Switch {
+        value: Value,
+        cases: Vec<(i32, Block)>,
+        default: Option<Block>,
+    },
+    Free(Value),
+    /// `assert cond`: checked below -O2 (see `Opcode::Assert`)
+    Assert(Condition),
+    /// `stride(array, cols)`: row stride of a 2D array allocated elsewhere
+    Stride {
+        array: String,
+        cols: Value,
+    },
+}
+
+/// A statement and where it starts
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Spanned<T> {
+    pub node: T,
+    pub at: Span,
+}
+
+/// Statements of a `{ ... }` block
+pub type Block = Vec<Spanned<Stmt>>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Function {
+    pub name: String,
+    pub args: Vec<String>,
+    pub hints: Hints,
+    pub body: Block,
+    /// Where the function's name starts in its header
+    pub at: Span,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Program {
+    pub functions: Vec<Function>,
+}
@@ -0,0 +1,138 @@
+//! Constant-Time Mode
+//!
+//! With `CompileOptions::constant_time`, the compiler if-converts every `if`
+//! it can into CMov selects, then audits what is left: the only conditional
+//! jumps allowed are loop control (a loop's entry test, its exits and its
+//! back edge), so a kernel's latency depends on its trip counts alone.
+//! Anything else is a data-dependent branch and the program is rejected.
+//!
+//! Loop bounds are assumed public: keep secrets out of loop conditions.
+//! Memory access patterns are not checked.
+
+use crate::ir::{Function, Opcode, Operand, Program};
+use std::collections::HashMap;
+
+/// Every data-dependent branch left in `prog`, or Ok if there are none
+pub fn check_program(prog: &Program) -> Result<(), String> {
+    let problems: Vec<String> = prog.functions.iter().flat_map(secret_branches).collect();
+    if problems.is_empty() {
+        return Ok(());
+    }
+    Err(format!(
+        "constant-time mode: {} branch(es) could not be made branchless \
+         (only Mov/Add/Sub/Mul bodies can be if-converted):\n  {}",
+        problems.len(),
+        problems.join("\n  ")
+    ))
+}
+
+/// Conditional jumps in `func` that are not loop control
+fn secret_branches(func: &Function) -> Vec<String> {
+    let labels: HashMap<&str, usize> = func
+        .instructions
+        .iter()
+        .enumerate()
+        .filter_map(|(idx, i)| match (&i.op, &i.dest) {
+            (Opcode::Label, Some(Operand::Label(name))) => Some((name.as_str(), idx)),
+            _ => None,
+        })
+        .collect();
+    let target = |idx: usize| match &func.instructions[idx].dest {
+        Some(Operand::Label(name)) => labels.get(name.as_str()).copied(),
+        _ => None,
+    };
+    let is_jump = |op: &Opcode| {
+        matches!(
+            op,
+            Opcode::Jmp
+                | Opcode::Jnz
+                | Opcode::Je
+                | Opcode::Jne
+                | Opcode::Jl
+                | Opcode::Jle
+                | Opcode::Jg
+                | Opcode::Jge
+                | Opcode::JumpTable(..)
+        )
+    };
+
+    // (header, back edge) of every loop
+    let loops: Vec<(usize, usize)> = (0..func.instructions.len())
+        .filter(|&idx| is_jump(&func.instructions[idx].op))
+        .filter_map(|idx| {
+            target(idx)
+                .filter(|&head| head < idx)
+                .map(|head| (head, idx))
+        })
+        .collect();
+
+    let loop_control = |idx: usize| {
+        let to = target(idx);
+        loops.iter().any(|&(head, back)| {
+            let entry_test = idx == head + 2 && func.instructions[head + 1].op == Opcode::Cmp;
+            let exit = to.is_some_and(|t| t < head || t > back);
+            head < idx && idx <= back && (entry_test || exit || to == Some(head))
+        })
+    };
+
+    func.instructions
+        .iter()
+        .enumerate()
+        .filter(|(idx, i)| is_jump(&i.op) && i.op != Opcode::Jmp && !loop_control(*idx))
+        .map(|(idx, i)| {
+            let test = func.instructions[..idx]
+                .iter()
+                .rev()
+                .find(|c| c.op == Opcode::Cmp)
+                .map_or_else(String::new, |c| format!(" after `{}`", c));
+            format!("{}: `{}`{}", func.name, i, test)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    #[test]
+    fn test_loop_control_is_allowed() {
+        let prog = Parser::new()
+            .parse(
+                "fn main(n) {
+                    sum = 0
+                    i = 0
+                    while i < n {
+                        sum = sum + i
+                        i = i + 1
+                    }
+                    loop:
+                    if n == 0 goto done
+                    n = n - 1
+                    goto loop
+                    done:
+                    return sum
+                }",
+            )
+            .unwrap();
+        assert_eq!(check_program(&prog), Ok(()));
+    }
+
+    #[test]
+    fn test_data_dependent_if_is_reported() {
+        let prog = Parser::new()
+            .parse(
+                "fn main(k) {
+                    x = 1
+                    if k > 5 {
+                        x = 2
+                    }
+                    return x
+                }",
+            )
+            .unwrap();
+        let err = check_program(&prog).unwrap_err();
+        assert!(err.contains("1 branch(es)"), "{}", err);
+        assert!(err.contains("main: `Jg"), "{}", err);
+    }
+}
@@ -0,0 +1,427 @@
+//! Diagnostics
+//!
+//! Checks run on the AST after parsing and before lowering. Warnings point
+//! at code that is legal but almost certainly not what was meant; errors
+//! point at code the backend would choke on (a call to an undefined
+//! function only surfaces as a missing label when the code is finalized).
+//!
+//! | Code | Severity | Finds |
+//! |---|---|---|
+//! | `unused-variable` | warning | variables assigned but never read |
+//! | `unreachable-code` | warning | statements after `return` or `goto` |
+//! | `constant-condition` | warning | conditions like `0 < 0` or `i == i` |
+//! | `undefined-function` | error | calls to functions the program lacks |
+//!
+//! Variables whose name starts with `_` are exempt from `unused-variable`.
+//! Calls to undefined functions suggest the closest defined name.
+
+use crate::ast::{
+    Assign, CmpOp, Condition, Expr, Function, Index, Program, Span, Spanned, Stmt, Value,
+};
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        })
+    }
+}
+
+/// One finding, tied to the statement it is about
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// Name of the check, e.g. `unused-variable`
+    pub code: &'static str,
+    /// Function the statement belongs to
+    pub function: String,
+    pub at: Span,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    /// `warning[unused-variable]: line 3:5 in main(): ...`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}[{}]: line {}:{} in {}(): {}",
+            self.severity, self.code, self.at.line, self.at.col, self.function, self.message
+        )
+    }
+}
+
+/// Every diagnostic for `program`, in source order
+pub fn check(program: &Program) -> Vec<Diagnostic> {
+    let mut functions: Vec<&str> = program.functions.iter().map(|f| f.name.as_str()).collect();
+    functions.sort_unstable();
+    let mut diagnostics: Vec<Diagnostic> = program
+        .functions
+        .iter()
+        .flat_map(|f| Checker::new(f, &functions).run())
+        .collect();
+    diagnostics.sort_by_key(|d| (d.at.line, d.at.col));
+    diagnostics
+}
+
+/// Whether any of `diagnostics` is an error
+pub fn has_errors(diagnostics: &[Diagnostic]) -> bool {
+    diagnostics.iter().any(|d| d.severity == Severity::Error)
+}
+
+/// The name in `known` closest to `name`, if close enough to be a typo of it
+pub fn closest<'a>(name: &str, known: &[&'a str]) -> Option<&'a str> {
+    let limit = (name.chars().count() / 3).max(1);
+    known
+        .iter()
+        .map(|k| (edit_distance(name, k), *k))
+        .filter(|&(d, _)| d <= limit)
+        .min()
+        .map(|(_, k)| k)
+}
+
+/// "call to undefined function `ad`; did you mean `add`? (defined: add, main)"
+pub fn undefined_function(name: &str, known: &[&str]) -> String {
+    let mut message = format!("call to undefined function `{}`", name);
+    if let Some(suggestion) = closest(name, known) {
+        message.push_str(&format!("; did you mean `{}`?", suggestion));
+    }
+    message.push_str(&format!(" (defined: {})", known.join(", ")));
+    message
+}
+
+/// Edit distance counting a swap of adjacent characters as one edit
+/// (optimal string alignment)
+fn edit_distance(a: &str, b: &str) -> usize {
+    let (a, b): (Vec<char>, Vec<char>) = (a.chars().collect(), b.chars().collect());
+    let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    d[0] = (0..=b.len()).collect();
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = (a[i - 1] != b[j - 1]) as usize;
+            d[i][j] = (d[i - 1][j] + 1)
+                .min(d[i][j - 1] + 1)
+                .min(d[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
+            }
+        }
+    }
+    d[a.len()][b.len()]
+}
+
+/// A variable declared in some block
+struct Binding {
+    name: String,
+    /// Statement that declared it
+    at: Span,
+    read: bool,
+    /// Parameters may go unused
+    param: bool,
+}
+
+struct Checker<'a> {
+    func: &'a Function,
+    /// Names of the program's functions, sorted
+    functions: &'a [&'a str],
+    bindings: Vec<Binding>,
+    /// Index into `bindings` of each visible name, per enclosing block
+    scopes: Vec<HashMap<String, usize>>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl<'a> Checker<'a> {
+    fn new(func: &'a Function, functions: &'a [&'a str]) -> Self {
+        Self {
+            func,
+            functions,
+            bindings: Vec::new(),
+            scopes: vec![HashMap::new()],
+            diagnostics: Vec::new(),
+        }
+    }
+
+    fn run(mut self) -> Vec<Diagnostic> {
+        for arg in &self.func.args {
+            self.declare(arg, Span::default(), true);
+        }
+        self.block(&self.func.body);
+        for b in &self.bindings {
+            if !b.read && !b.param && !b.name.starts_with('_') {
+                let message = format!("`{}` is assigned but never read", b.name);
+                self.diagnostics.push(Diagnostic {
+                    severity: Severity::Warning,
+                    code: "unused-variable",
+                    function: self.func.name.clone(),
+                    at: b.at,
+                    message,
+                });
+            }
+        }
+        self.diagnostics
+    }
+
+    fn report(&mut self, severity: Severity, code: &'static str, at: Span, message: String) {
+        self.diagnostics.push(Diagnostic {
+            severity,
+            code,
+            function: self.func.name.clone(),
+            at,
+            message,
+        });
+    }
+
+    fn declare(&mut self, name: &str, at: Span, param: bool) -> usize {
+        self.bindings.push(Binding {
+            name: name.to_string(),
+            at,
+            read: false,
+            param,
+        });
+        let index = self.bindings.len() - 1;
+        self.scopes
+            .last_mut()
+            .expect("checking outside a function")
+            .insert(name.to_string(), index);
+        index
+    }
+
+    /// Binding of `name`, declared in the innermost block if unknown
+    /// (as lowering does)
+    fn binding(&mut self, name: &str, at: Span) -> usize {
+        match self.scopes.iter().rev().find_map(|s| s.get(name).copied()) {
+            Some(index) => index,
+            None => self.declare(name, at, false),
+        }
+    }
+
+    fn read(&mut self, value: &Value, at: Span) {
+        if let Value::Var(name) = value {
+            let index = self.binding(name, at);
+            self.bindings[index].read = true;
+        }
+    }
+
+    fn read_index(&mut self, index: &Index, at: Span) {
+        self.read(&Value::Var(index.array.clone()), at);
+        self.read(&index.index, at);
+        if let Some(col) = &index.col {
+            self.read(col, at);
+        }
+    }
+
+    fn scoped(&mut self, check: impl FnOnce(&mut Self)) {
+        self.scopes.push(HashMap::new());
+        check(self);
+        self.scopes.pop();
+    }
+
+    fn block(&mut self, body: &[Spanned<Stmt>]) {
+        // The `return`/`goto` ending the reachable code; labels resume it
+        let mut exit = None;
+        let mut warned = false;
+        for stmt in body {
+            if let Stmt::Label { .. } = stmt.node {
+                (exit, warned) = (None, false);
+            } else if let Some(exit) = exit.filter(|_| !warned) {
+                let message = format!("unreachable statement after `{}`", exit);
+                self.report(Severity::Warning, "unreachable-code", stmt.at, message);
+                warned = true;
+            }
+            match stmt.node {
+                Stmt::Return(_) => exit = Some("return"),
+                Stmt::Goto(_) => exit = Some("goto"),
+                _ => {}
+            }
+            self.stmt(&stmt.node, stmt.at);
+        }
+    }
+
+    fn stmt(&mut self, stmt: &Stmt, at: Span) {
+        match stmt {
+            Stmt::Assign(assign) => self.assign(assign, at, false),
+            Stmt::Let(assign) => self.assign(assign, at, true),
+            Stmt::Store { index, value } => {
+                self.read_index(index, at);
+                self.read(value, at);
+            }
+            Stmt::Return(value) | Stmt::Free(value) => self.read(value, at),
+            Stmt::Label { .. } | Stmt::Goto(_) => {}
+            Stmt::IfGoto { cond, .. } | Stmt::Assert(cond) => self.condition(cond, at),
+            Stmt::If { cond, body } | Stmt::While { cond, body, .. } => {
+                self.condition(cond, at);
+                self.scoped(|this| this.block(body));
+            }
+            Stmt::For {
+                init,
+                cond,
+                step,
+                body,
+                ..
+            } => self.scoped(|this| {
+                this.assign(init, at, false);
+                this.condition(cond, at);
+                this.scoped(|this| this.block(body));
+                if let Some(step) = step {
+                    this.assign(step, at, false);
+                }
+            }),
+            Stmt::Switch { value, cases, default } => {
+                self.read(value, at);
+                for body in cases.iter().map(|(_, body)| body).chain(default) {
+                    self.scoped(|this| this.block(body));
+                }
+            }
+            Stmt::Stride { array, cols } => {
+                self.read(&Value::Var(array.clone()), at);
+                self.read(cols, at);
+            }
+        }
+    }
+
+    fn assign(&mut self, assign: &Assign, at: Span, declare: bool) {
+        match &assign.value {
+            Expr::Value(v) | Expr::Unary(_, v) | Expr::Alloc { size: v, .. } => self.read(v, at),
+            Expr::Binary(_, a, b) => {
+                self.read(a, at);
+                self.read(b, at);
+            }
+            Expr::Alloc2d { rows, cols, .. } => {
+                self.read(rows, at);
+                self.read(cols, at);
+            }
+            Expr::Load(index) => self.read_index(index, at),
+            Expr::Call { name, args } => {
+                for arg in args {
+                    self.read(arg, at);
+                }
+                if !self.functions.contains(&name.as_str()) {
+                    let message = undefined_function(name, self.functions);
+                    self.report(Severity::Error, "undefined-function", at, message);
+                }
+            }
+        }
+        if declare {
+            self.declare(&assign.dest, at, false);
+        } else {
+            self.binding(&assign.dest, at);
+        }
+    }
+
+    fn condition(&mut self, cond: &Condition, at: Span) {
+        self.read(&cond.lhs, at);
+        self.read(&cond.rhs, at);
+        let outcome = match (&cond.lhs, &cond.rhs) {
+            (Value::Int(a), Value::Int(b)) => Some(match cond.op {
+                CmpOp::Eq => a == b,
+                CmpOp::Ne => a != b,
+                CmpOp::Lt => a < b,
+                CmpOp::Le => a <= b,
+                CmpOp::Gt => a > b,
+                CmpOp::Ge => a >= b,
+            }),
+            (Value::Var(a), Value::Var(b)) if a == b => {
+                Some(matches!(cond.op, CmpOp::Eq | CmpOp::Le | CmpOp::Ge))
+            }
+            _ => None,
+        };
+        if let Some(outcome) = outcome {
+            let message = format!("condition `{}` is always {}", cond, outcome);
+            self.report(Severity::Warning, "constant-condition", at, message);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    fn codes(src: &str) -> Vec<(&'static str, usize)> {
+        let ast = Parser::new().parse_ast(src).unwrap();
+        check(&ast).iter().map(|d| (d.code, d.at.line)).collect()
+    }
+
+    #[test]
+    fn test_reports_each_check() {
+        let found = codes(
+            "fn main(n, unused_arg) {
+                t = n * 2
+                _scratch = 1
+                s = 0
+                while 0 < 0 {
+                    s = s + 1
+                }
+                if n == n goto done
+                label done
+                x = helper(s)
+                return x
+                y = 1
+                y = 2
+                label later
+                return 0
+            }",
+        );
+        assert_eq!(
+            found,
+            [
+                ("unused-variable", 2),
+                ("constant-condition", 5),
+                ("constant-condition", 8),
+                ("undefined-function", 10),
+                ("unreachable-code", 12),
+                ("unused-variable", 12),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_suggests_close_names_only() {
+        let known = ["add", "main", "popcount_all"];
+        assert_eq!(closest("ad", &known), Some("add"));
+        assert_eq!(closest("mian", &known), Some("main"));
+        assert_eq!(closest("popcnt_all", &known), Some("popcount_all"));
+        assert_eq!(closest("sub", &known), None);
+        assert_eq!(
+            undefined_function("sub", &known),
+            "call to undefined function `sub` (defined: add, main, popcount_all)"
+        );
+    }
+
+    #[test]
+    fn test_scoped_and_shadowed_variables() {
+        // The inner t is read; the outer one is not
+        let found = codes(
+            "fn main(n) {
+                t = 1
+                if n > 0 {
+                    let t = n
+                    n = t
+                }
+                return n
+            }",
+        );
+        assert_eq!(found, [("unused-variable", 2)]);
+
+        let ast = Parser::new()
+            .parse_ast("fn main() {\nx = f()\nreturn x\n}")
+            .unwrap();
+        let diagnostics = check(&ast);
+        assert!(has_errors(&diagnostics));
+        assert_eq!(
+            diagnostics[0].to_string(),
+            "error[undefined-function]: line 2:1 in main(): call to undefined function `f` (defined: main)"
+        );
+    }
+}
@@ -0,0 +1,382 @@
+use crate::loops::LoopInfo;
+use crate::passes::PassSet;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Operand {
+    Reg(u8),       // Virtual Integer Register
+    Ymm(u8),       // Virtual Vector Register (AVX2)
+    Imm(i32),      // Immediate value
+    Label(String), // Label name
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Opcode {
+    /// Mov dest, src
+    Mov,
+    /// Add dest, src (dest += src)
+    Add,
+    /// Mul dest, src (dest *= src)
+    Mul,
+    /// Sub dest, src (dest -= src)
+    Sub,
+    /// Return the value in the first operand (or Accumulator/Reg(0))
+    Ret,
+    /// Define a label
+    Label,
+    /// Unconditional Jump
+    Jmp,
+    /// Alloc(dest, size[, Imm(align)]) -> dest = malloc(size), or
+    /// posix_memalign with a power-of-two alignment
+    Alloc,
+    /// Free(ptr) -> free(ptr)
+    Free,
+    /// Load(disp)(dest, base, index) -> dest = MEM[base + (index + disp) * 8]
+    Load(i32),
+    /// Store(disp)(base, index, src) -> MEM[base + (index + disp) * 8] = src
+    Store(i32),
+    SetArg(usize), // Set Argument i for Call
+    /// Jump if Not Zero (Legacy, kept for sugar or simple checks)
+    Jnz,
+    /// Compare two operands (sets flags)
+    Cmp,
+    /// Jump Equal
+    Je,
+    /// Jump Not Equal
+    Jne,
+    /// Jump Less
+    Jl,
+    /// Jump Less or Equal
+    Jle,
+    /// Jump Greater
+    Jg,
+    /// Jump Greater or Equal
+    Jge,
+    /// Call a function
+    Call,
+    /// Load Argument from Stack (index 0-based)
+    LoadArg(usize),
+    /// VLoad(disp, access)(ymm_dest, base, index) -> ymm_dest = MEM[base + (index + disp) * 8] (Vector Load)
+    VLoad(i32, Access),
+    /// VStore(disp, access)(base, index, ymm_src) -> MEM[base + (index + disp) * 8] = ymm_src (Vector Store)
+    VStore(i32, Access),
+    /// PrefetchT0(base, index, Imm(bytes)) -> fetch MEM[base + index * 8 + bytes] into all cache levels
+    PrefetchT0,
+    /// VAdd(ymm_dest, ymm_src1, ymm_src2) -> ymm_dest = ymm_src1 + ymm_src2 (Packed Add)
+    VAdd,
+    /// VMin(ymm_dest, ymm_src1, ymm_src2) -> ymm_dest = lanewise signed min (Packed Min)
+    VMin,
+    /// VMax(ymm_dest, ymm_src1, ymm_src2) -> ymm_dest = lanewise signed max (Packed Max)
+    VMax,
+    /// VAbs(ymm_dest, ymm_src) -> ymm_dest = lanewise absolute value (Packed Abs)
+    VAbs,
+    /// CMov(dest, src) -> dest = src if the last Cmp satisfied the condition
+    CMov(Cond),
+    /// Popcnt(dest, src) -> dest = number of set bits in src
+    Popcnt,
+    /// Ctz(dest, src) -> dest = trailing zero bits of src (64 if src is 0)
+    Ctz,
+    /// Clz(dest, src) -> dest = leading zero bits of src (64 if src is 0)
+    Clz,
+    /// Min(dest, src) -> dest = signed min(dest, src)
+    Min,
+    /// Max(dest, src) -> dest = signed max(dest, src)
+    Max,
+    /// Abs(dest, src) -> dest = |src| (wrapping: |i64::MIN| is i64::MIN)
+    Abs,
+    /// JumpTable(low, targets)(default, index) -> jump to targets[index - low]
+    /// if that is in range, else to the default label
+    JumpTable(i32, Vec<String>),
+    /// Assert(cond, text)(_, lhs, rhs) -> report a failed `assert text`
+    /// unless lhs cond rhs (only compiled below -O2)
+    Assert(Cond, String),
+}
+
+impl Opcode {
+    /// Element displacement of a Load, Store, VLoad or VStore
+    pub fn displacement(&self) -> Option<i32> {
+        match self {
+            Opcode::Load(disp) | Opcode::Store(disp) | Opcode::VLoad(disp, _) | Opcode::VStore(disp, _) => {
+                Some(*disp)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// How a vector load or store reaches memory (see `alignment`)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Access {
+    /// Any address (vmovdqu)
+    #[default]
+    Unaligned,
+    /// The address is a multiple of the vector size (vmovdqa)
+    Aligned,
+    /// Aligned, and the store bypasses the caches (vmovntdq); stores only
+    NonTemporal,
+}
+
+/// Condition of a conditional jump or move, as set by Cmp
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Cond {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Cond {
+    /// Condition tested by a conditional jump opcode
+    pub fn of_jump(op: &Opcode) -> Option<Self> {
+        match op {
+            Opcode::Je => Some(Cond::Eq),
+            Opcode::Jne => Some(Cond::Ne),
+            Opcode::Jl => Some(Cond::Lt),
+            Opcode::Jle => Some(Cond::Le),
+            Opcode::Jg => Some(Cond::Gt),
+            Opcode::Jge => Some(Cond::Ge),
+            _ => None,
+        }
+    }
+
+    /// The conditional jump taken when the condition holds
+    pub fn jump(self) -> Opcode {
+        match self {
+            Cond::Eq => Opcode::Je,
+            Cond::Ne => Opcode::Jne,
+            Cond::Lt => Opcode::Jl,
+            Cond::Le => Opcode::Jle,
+            Cond::Gt => Opcode::Jg,
+            Cond::Ge => Opcode::Jge,
+        }
+    }
+
+    pub fn negate(self) -> Self {
+        match self {
+            Cond::Eq => Cond::Ne,
+            Cond::Ne => Cond::Eq,
+            Cond::Lt => Cond::Ge,
+            Cond::Le => Cond::Gt,
+            Cond::Gt => Cond::Le,
+            Cond::Ge => Cond::Lt,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Instruction {
+    pub op: Opcode,
+    pub dest: Option<Operand>,
+    pub src1: Option<Operand>,
+    pub src2: Option<Operand>,
+}
+
+impl Instruction {
+    /// Labels control may go to other than the next instruction: a jump's
+    /// target, or a jump table's default and then its entries
+    pub fn jump_targets(&self) -> Vec<&str> {
+        let target = match (&self.op, &self.dest) {
+            (Opcode::Label, _) => None,
+            (_, Some(Operand::Label(name))) => Some(name.as_str()),
+            _ => None,
+        };
+        let table = match &self.op {
+            Opcode::JumpTable(_, targets) => targets.as_slice(),
+            _ => &[],
+        };
+        target.into_iter().chain(table.iter().map(String::as_str)).collect()
+    }
+
+    /// Whether control never reaches the next instruction
+    pub fn ends_block(&self) -> bool {
+        matches!(self.op, Opcode::Jmp | Opcode::Ret | Opcode::JumpTable(..))
+    }
+}
+
+impl std::fmt::Display for Instruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let operands: Vec<String> = [&self.dest, &self.src1, &self.src2]
+            .iter()
+            .filter_map(|o| o.as_ref().map(|o| format!("{:?}", o)))
+            .collect();
+        if operands.is_empty() {
+            write!(f, "{:?}", self.op)
+        } else {
+            write!(f, "{:?} {}", self.op, operands.join(", "))
+        }
+    }
+}
+
+/// Optimizer hints from `#pragma` lines, attached to a function or a loop
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Hints {
+    /// `unroll(N)`: copies of the body per iteration (1 disables unrolling)
+    pub unroll: Option<u8>,
+    /// `novectorize`
+    pub no_vectorize: bool,
+    /// `align(N)`: byte alignment of the function entry or loop head
+    pub align: Option<u32>,
+    /// `nontemporal`: vectorized stores that are provably aligned bypass
+    /// the caches (for output that won't be read again soon)
+    pub nontemporal: bool,
+    /// `opt(N)`: optimization level for the function, overriding the
+    /// program's (functions only)
+    pub opt_level: Option<u8>,
+    /// Passes skipped in this function on top of the program's; set by a
+    /// learned pass schedule rather than a pragma (functions only)
+    #[serde(default)]
+    pub disabled_passes: PassSet,
+    /// Argument values seen on nearly every call, by argument index; set
+    /// from profiled calls rather than a pragma (functions only)
+    #[serde(default)]
+    pub expected_args: BTreeMap<usize, i32>,
+    /// Argument values to compile a whole version of the function for,
+    /// behind a guard; set from a profile rather than a pragma (functions
+    /// only)
+    #[serde(default)]
+    pub constant_args: BTreeMap<usize, i32>,
+}
+
+impl Hints {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Function {
+    pub name: String,
+    pub args: Vec<String>,
+    pub instructions: Vec<Instruction>,
+    pub hints: Hints,
+    /// Hints for the loop headed by each label
+    pub loop_hints: BTreeMap<String, Hints>,
+    /// The loop headed by each label, as of the last `loops::annotate`
+    /// (lowering and the passes that move code keep it current)
+    #[serde(skip)]
+    pub loops: BTreeMap<String, LoopInfo>,
+    /// Source line each instruction was lowered from (0: none), as of
+    /// lowering; passes don't keep it, `carry_lines` re-attaches it
+    #[serde(skip)]
+    pub lines: Vec<usize>,
+}
+
+impl Function {
+    pub fn new(name: &str, args: Vec<String>) -> Self {
+        Self {
+            name: name.to_string(),
+            args,
+            instructions: Vec::new(),
+            hints: Hints::default(),
+            loop_hints: BTreeMap::new(),
+            loops: BTreeMap::new(),
+            lines: Vec::new(),
+        }
+    }
+
+    /// Source line of instruction `at` (0: none)
+    pub fn line(&self, at: usize) -> usize {
+        self.lines.get(at).copied().unwrap_or(0)
+    }
+
+    /// Give each instruction the line of the same instruction in `written`
+    /// (this function before the passes ran), the one nearest to where the
+    /// last match left off: passes mostly keep the order, and copies sit
+    /// just after their original. A constant folded from several writes
+    /// takes the line of the last of them, and other instructions a pass
+    /// made up take the line of the one before.
+    pub fn carry_lines(&mut self, written: &Function) {
+        let mut next = 0;
+        let mut line = 0;
+        let mut lines = Vec::with_capacity(self.instructions.len());
+        for instr in &self.instructions {
+            let nearest = (0..written.instructions.len())
+                .filter(|&at| written.instructions[at] == *instr)
+                .min_by_key(|&at| (at.abs_diff(next), at < next))
+                .or_else(|| written.folded_from(instr, next));
+            if let Some(at) = nearest {
+                next = next.max(at + 1);
+                line = written.line(at);
+            }
+            lines.push(line);
+        }
+        self.lines = lines;
+    }
+
+    /// Where constant folding got `instr` if it is a `Mov r, Imm` matching
+    /// nothing written: the end of the run of writes to `r` nearest `next`
+    /// (`Mov r, 2; Add r, 3` became `Mov r, 5`)
+    fn folded_from(&self, instr: &Instruction, next: usize) -> Option<usize> {
+        if instr.op != Opcode::Mov || !matches!(instr.src1, Some(Operand::Imm(_))) {
+            return None;
+        }
+        let writes = |at: usize| {
+            self.instructions.get(at).is_some_and(|w| w.dest.is_some() && w.dest == instr.dest)
+        };
+        let mut at = (0..self.instructions.len())
+            .filter(|&at| writes(at))
+            .min_by_key(|&at| (at.abs_diff(next), at < next))?;
+        while writes(at + 1) {
+            at += 1;
+        }
+        Some(at)
+    }
+
+    /// Hints for the loop headed by `label`, falling back to the function's
+    /// (alignment is never inherited: it applies to where the pragma sits)
+    pub fn hints_for_loop(&self, label: &str) -> Hints {
+        let own = self.loop_hints.get(label);
+        Hints {
+            unroll: own.and_then(|h| h.unroll).or(self.hints.unroll),
+            no_vectorize: self.hints.no_vectorize || own.is_some_and(|h| h.no_vectorize),
+            align: own.and_then(|h| h.align),
+            nontemporal: self.hints.nontemporal || own.is_some_and(|h| h.nontemporal),
+            opt_level: None,
+            disabled_passes: PassSet::default(),
+            expected_args: BTreeMap::new(),
+            constant_args: BTreeMap::new(),
+        }
+    }
+
+    pub fn push(&mut self, instr: Instruction) {
+        self.instructions.push(instr);
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Program {
+    pub functions: Vec<Function>,
+}
+
+impl Program {
+    pub fn new() -> Self {
+        Self {
+            functions: Vec::new(),
+        }
+    }
+
+    pub fn add_function(&mut self, func: Function) {
+        self.functions.push(func);
+    }
+
+    /// Optimize `function` at `level` whatever the program is compiled at,
+    /// like `#pragma opt(level)`
+    pub fn set_opt_level(&mut self, function: &str, level: u8) -> Result<(), String> {
+        let func = self
+            .functions
+            .iter_mut()
+            .find(|f| f.name == function)
+            .ok_or_else(|| format!("No function named '{}'", function))?;
+        func.hints.opt_level = Some(level.min(3));
+        Ok(())
+    }
+}
+
+impl Default for Program {
+    fn default() -> Self {
+        Self::new()
+    }
+}
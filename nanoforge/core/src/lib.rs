@@ -0,0 +1,30 @@
+//! NanoForge Core
+//!
+//! The parts of NanoForge that never touch the host: the parser and AST,
+//! lowering to IR, the IR checks (`verifier`, `types`, `pure`,
+//! `constant_time`) and the optimizer with its analyses. Nothing here
+//! generates or runs machine code, calls into libc or reads performance
+//! counters, so it builds for targets the JIT doesn't support (such as
+//! wasm32) and can feed other backends.
+//!
+//! The `nanoforge` crate re-exports every module under the same path.
+
+pub mod alignment;
+pub mod ast;
+pub mod constant_time;
+pub mod diagnostics;
+pub mod ir;
+pub mod loops;
+pub mod lowering;
+pub mod optimizer;
+pub mod parser;
+pub mod passes;
+pub mod pipeliner;
+pub mod pure;
+pub mod ranges;
+pub mod report;
+pub mod scheduler;
+pub mod specialize;
+pub mod target;
+pub mod types;
+pub mod verifier;
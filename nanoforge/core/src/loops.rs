@@ -0,0 +1,537 @@
+//! Loop Analysis
+//!
+//! Finds the loops of a function once and describes each in a side table,
+//! `Function::loops`, keyed by header label: where the loop starts and ends
+//! and, for a counted loop, its induction variable, step, bound and (when
+//! the start and bound are constants) trip count. The unroller, jammer,
+//! vectorizer, pipeliner, the backend's fuel checks and the variant
+//! generator all read it instead of matching loops their own way.
+//!
+//! A loop is a label some later instruction jumps back to. It is counted
+//! when it tests its induction variable first, in either shape lowering
+//! emits:
+//!
+//! ```text
+//! L: cmp i, n; jcc exit; body; jmp L                    (`if .. goto`)
+//! L: cmp i, n; jcc B; jmp exit; B: body; jmp L          (`while`, `for`)
+//! ```
+//!
+//! and the body steps i by a constant exactly once, on every path that
+//! doesn't leave the loop. Indices go stale when instructions move: lowering
+//! and the passes that rewrite code call `annotate` again.
+//!
+//! # Rotation
+//!
+//! Both shapes take two branches per iteration: the header's test and the
+//! jump back to it. `rotate` turns them into do-while loops, guarded by the
+//! original test and closed by a copy of it, which takes one:
+//!
+//! ```text
+//! L: cmp i, n; jcc exit; L_rotated: body; cmp i, n; jncc L_rotated; exit:
+//! L: cmp i, n; jcc B; jmp exit; B: body; cmp i, n; jcc B; exit:
+//! ```
+//!
+//! It runs after every pass that matches the shapes above.
+
+use crate::ir::{Cond, Function, Instruction, Opcode, Operand, Program};
+use crate::ranges;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// A loop, by instruction index
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LoopInfo {
+    /// The header label
+    pub head: usize,
+    /// The last jump back to the header
+    pub back_jump: usize,
+    pub induction: Option<Induction>,
+}
+
+impl LoopInfo {
+    /// Instructions between the header label and the back jump, exclusive
+    pub fn body(&self) -> std::ops::Range<usize> {
+        self.head + 1..self.back_jump
+    }
+}
+
+/// How a counted loop steps and exits
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Induction {
+    /// The induction variable
+    pub var: Operand,
+    /// Added to `var` each iteration (negative for a `Sub`)
+    pub step: i64,
+    /// The `Add` or `Sub` stepping `var`
+    pub step_at: usize,
+    /// The header's `Cmp`
+    pub test: usize,
+    /// The jump leaving the loop when the test says so
+    pub exit_jump: usize,
+    /// First instruction after the header test
+    pub body_start: usize,
+    /// The loop exits when `var exit_when bound`
+    pub exit_when: Cond,
+    /// The other side of the test, never written in the loop
+    pub bound: Operand,
+    /// `var` on entry, when a constant
+    pub start: Option<i64>,
+    /// Times the body runs unless it leaves early, when `start` and
+    /// `bound` are constants and `var` reaches the exit without wrapping
+    pub trip_count: Option<u64>,
+}
+
+/// Recompute `func.loops`
+pub fn annotate(func: &mut Function) {
+    func.loops = analyze(func);
+}
+
+pub fn annotate_program(prog: &mut Program) {
+    for func in &mut prog.functions {
+        annotate(func);
+    }
+}
+
+/// The loops of `func` by header label
+pub fn analyze(func: &Function) -> BTreeMap<String, LoopInfo> {
+    let instrs = &func.instructions;
+    let labels = label_indices(instrs);
+    let mut loops = BTreeMap::new();
+    for (idx, instr) in instrs.iter().enumerate() {
+        for target in instr.jump_targets() {
+            let Some(&head) = labels.get(target) else {
+                continue;
+            };
+            if head < idx {
+                let info = loops.entry(target.to_string()).or_insert(LoopInfo {
+                    head,
+                    back_jump: idx,
+                    induction: None,
+                });
+                info.back_jump = idx;
+            }
+        }
+    }
+    for info in loops.values_mut() {
+        info.induction = induction(instrs, &labels, info);
+    }
+    loops
+}
+
+pub(crate) fn label_indices(instrs: &[Instruction]) -> BTreeMap<&str, usize> {
+    let mut labels = BTreeMap::new();
+    for (idx, instr) in instrs.iter().enumerate() {
+        if let (Opcode::Label, Some(Operand::Label(name))) = (&instr.op, &instr.dest) {
+            labels.insert(name.as_str(), idx);
+        }
+    }
+    labels
+}
+
+/// The register or operand `instr` assigns, if any
+pub(crate) fn writes(instr: &Instruction) -> Option<&Operand> {
+    match instr.op {
+        Opcode::Store(_) | Opcode::VStore(..) | Opcode::PrefetchT0 => None,
+        // `x = x + 1` parses as an identity move before the add
+        Opcode::Mov if instr.dest == instr.src1 => None,
+        _ => instr.dest.as_ref(),
+    }
+}
+
+fn induction(
+    instrs: &[Instruction],
+    labels: &BTreeMap<&str, usize>,
+    info: &LoopInfo,
+) -> Option<Induction> {
+    let (head, back_jump) = (info.head, info.back_jump);
+    let inside = |at: usize| (head..=back_jump).contains(&at);
+    let leaves = |instr: &Instruction| match &instr.dest {
+        Some(Operand::Label(target)) => labels.get(target.as_str()).is_some_and(|&at| !inside(at)),
+        _ => false,
+    };
+
+    // The header test, in either shape
+    let test = head + 1;
+    let cmp = instrs.get(test).filter(|i| i.op == Opcode::Cmp)?;
+    let jump = instrs.get(test + 1)?;
+    let cond = Cond::of_jump(&jump.op)?;
+    let (exit_jump, body_start, exit_when) = if leaves(jump) {
+        (test + 1, test + 2, cond)
+    } else {
+        instrs.get(test + 2).filter(|i| i.op == Opcode::Jmp && leaves(i))?;
+        let body = instrs.get(test + 3)?;
+        if body.op != Opcode::Label || body.dest != jump.dest {
+            return None;
+        }
+        (test + 2, test + 4, cond.negate())
+    };
+    if body_start > back_jump {
+        return None;
+    }
+
+    let loop_range = &instrs[head..=back_jump];
+    let written = |op: &Operand| loop_range.iter().filter(|i| writes(i) == Some(op)).count();
+    let (var, bound, exit_when) = match (&cmp.src1, &cmp.src2) {
+        (Some(a @ Operand::Reg(_)), Some(b)) if written(a) == 1 && written(b) == 0 => {
+            (a, b, exit_when)
+        }
+        (Some(a), Some(b @ Operand::Reg(_))) if written(b) == 1 && written(a) == 0 => {
+            (b, a, swapped(exit_when))
+        }
+        _ => return None,
+    };
+    let step_at = (body_start..back_jump).find(|&at| writes(&instrs[at]) == Some(var))?;
+    let step = match (&instrs[step_at].op, &instrs[step_at].src1) {
+        (Opcode::Add, Some(Operand::Imm(k))) => *k as i64,
+        (Opcode::Sub, Some(Operand::Imm(k))) => -(*k as i64),
+        _ => return None,
+    };
+    if step == 0 || !steps_every_iteration(instrs, labels, head, body_start, step_at, back_jump) {
+        return None;
+    }
+
+    // Only falling into the header enters the loop
+    let entered_elsewhere = instrs.iter().enumerate().any(|(at, i)| {
+        !inside(at) && i.jump_targets().iter().any(|t| labels.get(t).is_some_and(|&to| inside(to)))
+    });
+    let start = if entered_elsewhere {
+        None
+    } else {
+        constant_before(instrs, head, var)
+    };
+    let bound_value = match bound {
+        Operand::Imm(n) => Some(*n as i64),
+        Operand::Reg(_) => constant_before(instrs, head, bound),
+        _ => None,
+    };
+    let trip_count = start
+        .zip(bound_value)
+        .and_then(|(start, bound)| trip_count(start, step, exit_when, bound));
+    Some(Induction {
+        var: var.clone(),
+        step,
+        step_at,
+        test,
+        exit_jump,
+        body_start,
+        exit_when,
+        bound: bound.clone(),
+        start,
+        trip_count,
+    })
+}
+
+/// Rotate every loop in either header shape into a do-while (see the
+/// module docs), except those short enough to skip their fuel checks: the
+/// copy of the test would leave them uncounted and pay one instead. Leaves
+/// `func.loops` current; true if any loop was rotated.
+pub fn rotate(func: &mut Function) -> bool {
+    annotate(func);
+    let ranges = ranges::analyze(func);
+    let mut done: BTreeSet<String> = ranges::unfueled_loops(func, &ranges, ranges::MAX_UNFUELED_TRIPS);
+    let mut rotated = false;
+    loop {
+        let next = func
+            .loops
+            .iter()
+            .filter(|(label, _)| !done.contains(*label))
+            .min_by_key(|(_, info)| info.head)
+            .map(|(label, info)| (label.clone(), info.clone()));
+        let Some((label, info)) = next else {
+            return rotated;
+        };
+        done.insert(label.clone());
+        if let Some(body) = rotate_loop(func, &label, &info) {
+            if let Some(hints) = func.loop_hints.remove(&label) {
+                func.loop_hints.insert(body.clone(), hints);
+            }
+            done.insert(body);
+            rotated = true;
+            annotate(func);
+        }
+    }
+}
+
+/// Close the loop headed by `label` with a copy of its test instead of the
+/// jump back; returns the label the copy jumps to
+fn rotate_loop(func: &mut Function, label: &str, info: &LoopInfo) -> Option<String> {
+    let instrs = &func.instructions;
+    let (head, back_jump) = (info.head, info.back_jump);
+    let back = &instrs[back_jump];
+    if back.op != Opcode::Jmp || back.dest != Some(Operand::Label(label.to_string())) {
+        return None;
+    }
+    let cmp = instrs.get(head + 1).filter(|i| i.op == Opcode::Cmp)?.clone();
+    let jump = instrs.get(head + 2)?;
+    let cond = Cond::of_jump(&jump.op)?;
+    let exit = instrs.get(back_jump + 1).filter(|i| i.op == Opcode::Label)?;
+    let target = |instr: &Instruction| match &instr.dest {
+        Some(Operand::Label(name)) => Some(name.clone()),
+        _ => None,
+    };
+    let exit = target(exit)?;
+    let jump_to = target(jump)?;
+
+    let exits_on_test = jump_to == exit;
+    let (body, close) = if exits_on_test {
+        // The copy loops back while the test fails, to a new label
+        let body = format!("{}_rotated", label);
+        if label_indices(instrs).contains_key(body.as_str()) {
+            return None;
+        }
+        (body, cond.negate().jump())
+    } else {
+        let leaves = instrs.get(head + 3).filter(|i| i.op == Opcode::Jmp).and_then(target);
+        let enters = instrs.get(head + 4).filter(|i| i.op == Opcode::Label).and_then(target);
+        if leaves != Some(exit) || enters.as_ref() != Some(&jump_to) {
+            return None;
+        }
+        (jump_to, jump.op.clone())
+    };
+
+    let to_body = Some(Operand::Label(body.clone()));
+    func.instructions[back_jump] = Instruction {
+        op: close,
+        dest: to_body.clone(),
+        src1: None,
+        src2: None,
+    };
+    func.instructions.insert(back_jump, cmp);
+    if exits_on_test {
+        let entry = Instruction {
+            op: Opcode::Label,
+            dest: to_body,
+            src1: None,
+            src2: None,
+        };
+        func.instructions.insert(head + 3, entry);
+    }
+    Some(body)
+}
+
+/// `a cond b` as `b cond' a`
+pub(crate) fn swapped(cond: Cond) -> Cond {
+    match cond {
+        Cond::Lt => Cond::Gt,
+        Cond::Le => Cond::Ge,
+        Cond::Gt => Cond::Lt,
+        Cond::Ge => Cond::Le,
+        same => same,
+    }
+}
+
+/// Whether every iteration that stays in the loop passes `step_at`: no
+/// jump before it skips ahead or back to the header, and nothing after it
+/// jumps back above it
+fn steps_every_iteration(
+    instrs: &[Instruction],
+    labels: &BTreeMap<&str, usize>,
+    head: usize,
+    body_start: usize,
+    step_at: usize,
+    back_jump: usize,
+) -> bool {
+    let before = body_start..step_at;
+    instrs[head..=back_jump].iter().enumerate().all(|(offset, instr)| {
+        let at = head + offset;
+        instr.jump_targets().iter().all(|target| {
+            let Some(&to) = labels.get(target) else {
+                return false;
+            };
+            let leaves = to < head || to > back_jump;
+            if before.contains(&at) {
+                leaves || before.contains(&to)
+            } else {
+                // The header test, or after the step
+                leaves || to == head || to > step_at || at < body_start
+            }
+        })
+    })
+}
+
+/// The constant `op` holds when control falls into `at`, if the straight
+/// line above it sets one
+fn constant_before(instrs: &[Instruction], at: usize, op: &Operand) -> Option<i64> {
+    for instr in instrs[..at].iter().rev() {
+        if instr.op == Opcode::Label || instr.ends_block() || Cond::of_jump(&instr.op).is_some() {
+            return None;
+        }
+        if writes(instr) == Some(op) {
+            return match (&instr.op, &instr.src1) {
+                (Opcode::Mov, Some(Operand::Imm(n))) => Some(*n as i64),
+                _ => None,
+            };
+        }
+    }
+    None
+}
+
+/// Iterations before `var exit_when bound` holds, `var` starting at
+/// `start` and stepping by `step`; None if it never does or would wrap
+pub fn trip_count(start: i64, step: i64, exit_when: Cond, bound: i64) -> Option<u64> {
+    let (start, step, bound) = (start as i128, step as i128, bound as i128);
+    let exits = |var: i128| match exit_when {
+        Cond::Eq => var == bound,
+        Cond::Ne => var != bound,
+        Cond::Lt => var < bound,
+        Cond::Le => var <= bound,
+        Cond::Gt => var > bound,
+        Cond::Ge => var >= bound,
+    };
+    let n = if exits(start) {
+        0
+    } else {
+        // Distance to cover in the step's direction
+        let distance = (bound - start) * step.signum();
+        let k = step.abs();
+        match exit_when {
+            Cond::Ne => 1,
+            Cond::Eq if distance > 0 && distance % k == 0 => distance / k,
+            Cond::Ge | Cond::Le if distance > 0 => (distance + k - 1) / k,
+            Cond::Gt | Cond::Lt if distance >= 0 => distance / k + 1,
+            _ => return None,
+        }
+    };
+    // Lt/Le need a falling var and Gt/Ge a rising one to get there
+    let last = start + n * step;
+    if !exits(last) || i64::try_from(last).is_err() {
+        return None;
+    }
+    u64::try_from(n).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    fn main_loops(src: &str) -> BTreeMap<String, LoopInfo> {
+        let prog = Parser::new().parse(src).unwrap();
+        prog.functions.into_iter().find(|f| f.name == "main").unwrap().loops
+    }
+
+    #[test]
+    fn test_counted_loops_in_both_header_shapes() {
+        let loops = main_loops(
+            "fn main(n) {
+                s = 0
+                i = 0
+                label up
+                if i == 10 goto up_done
+                s = s + i
+                i = i + 1
+                goto up
+                label up_done
+                j = 20
+                while j > 3 {
+                    s = s + j
+                    j = j - 4
+                }
+                k = 0
+                while k < n {
+                    k = k + 1
+                }
+                return s
+            }",
+        );
+        let up = loops["up"].induction.clone().unwrap();
+        assert_eq!((up.step, up.exit_when, up.start), (1, Cond::Eq, Some(0)));
+        assert_eq!(up.trip_count, Some(10));
+
+        let mut whiles = loops.iter().filter(|(label, _)| label.starts_with("while_start"));
+        let down = whiles.next().unwrap().1.induction.clone().unwrap();
+        assert_eq!((down.step, down.exit_when, down.bound), (-4, Cond::Le, Operand::Imm(3)));
+        // 20, 16, 12, 8, 4
+        assert_eq!(down.trip_count, Some(5));
+        let unknown = whiles.next().unwrap().1.induction.clone().unwrap();
+        assert_eq!((unknown.start, unknown.trip_count), (Some(0), None));
+    }
+
+    #[test]
+    fn test_conditional_steps_and_reentered_headers_are_not_counted() {
+        let loops = main_loops(
+            "fn main(n) {
+                i = 0
+                label skip
+                if i == 8 goto done
+                if n == 0 goto after
+                i = i + 1
+                label after
+                goto skip
+                label done
+                j = 0
+                if n == 1 goto inside
+                label again
+                if j >= 8 goto out
+                label inside
+                j = j + 2
+                goto again
+                label out
+                return i
+            }",
+        );
+        assert_eq!(loops["skip"].induction, None);
+        let again = loops["again"].induction.clone().unwrap();
+        assert_eq!((again.start, again.trip_count), (None, None));
+    }
+
+    #[test]
+    fn test_rotation_leaves_one_branch_per_iteration() {
+        let mut func = Parser::new()
+            .parse(
+                "fn main(n) {
+                    s = 0
+                    i = 0
+                    label up
+                    if i >= n goto up_done
+                    s = s + i
+                    i = i + 1
+                    goto up
+                    label up_done
+                    #pragma align(32)
+                    while s > 0 {
+                        s = s - 3
+                    }
+                    k = 0
+                    while k < 4 {
+                        k = k + 1
+                    }
+                    s = s + k
+                    return s
+                }",
+            )
+            .unwrap()
+            .functions
+            .remove(0);
+        let before = func.loops.clone();
+        assert!(rotate(&mut func));
+        assert!(!rotate(&mut func));
+
+        // Closed by a copy of the test, looping back while it fails
+        let up = &func.loops["up_rotated"];
+        let close = &func.instructions[up.back_jump];
+        assert_eq!(close.op, Opcode::Jl);
+        assert_eq!(func.instructions[up.back_jump - 1].op, Opcode::Cmp);
+        assert!(!func.loops.contains_key("up"));
+
+        let body = func.loops.keys().find(|l| l.starts_with("while_body")).unwrap();
+        assert_eq!(func.instructions[func.loops[body].back_jump].op, Opcode::Jg);
+        assert!(func.loop_hints[body].align.is_some());
+        // Four trips skip their fuel checks, so keep the header test
+        let short = before.keys().filter(|l| l.starts_with("while_start")).nth(1).unwrap();
+        assert_eq!(func.loops[short].back_jump, before[short].back_jump + 3);
+    }
+
+    #[test]
+    fn test_trip_counts() {
+        assert_eq!(trip_count(0, 1, Cond::Ge, 10), Some(10));
+        assert_eq!(trip_count(0, 3, Cond::Ge, 10), Some(4));
+        assert_eq!(trip_count(0, 3, Cond::Gt, 9), Some(4));
+        assert_eq!(trip_count(10, 1, Cond::Ge, 0), Some(0));
+        // Steps over the bound and would wrap around
+        assert_eq!(trip_count(0, 3, Cond::Eq, 10), None);
+        assert_eq!(trip_count(0, -1, Cond::Ge, 10), None);
+        assert_eq!(trip_count(i64::MAX - 1, 1, Cond::Gt, i64::MAX), None);
+        assert_eq!(trip_count(5, 1, Cond::Ne, 5), Some(1));
+    }
+}
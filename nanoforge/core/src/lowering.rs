@@ -0,0 +1,636 @@
+//! AST Lowering
+//!
+//! Turns the `ast` produced by the parser into IR. Variables get virtual
+//! registers from 10 up in order of first use (0-9 are reserved for return
+//! values and arguments), and structured control flow becomes labels and
+//! jumps: `while c { b }` lowers to
+//!
+//! ```text
+//! label while_start_N; cmp; jcc while_body_N; jmp while_end_N
+//! label while_body_N; b; jmp while_start_N; label while_end_N
+//! ```
+//!
+//! A `switch` with at least `JUMP_TABLE_MIN_CASES` cases filling at least
+//! half of the range between its smallest and largest becomes one
+//! `JumpTable` (codegen bounds-checks the value and jumps through a table
+//! in the code region); sparser ones become a `cmp; je` per case.
+//!
+//! Blocks are scopes. Assigning a name no enclosing block knows declares it
+//! in the innermost one, `let` declares it there even if an outer block has
+//! one (shadowing it), and a block's variables die at its `}`: their
+//! registers go back to the pool for whatever is declared next.
+
+use crate::ast::{
+    self, Assign, BinOp, CmpOp, Condition, Expr, Index, Span, Spanned, Stmt, UnaryOp, Value,
+};
+use crate::ir::{Cond, Function, Hints, Instruction, Opcode, Operand, Program};
+use crate::loops;
+use std::collections::HashMap;
+
+/// Fewest cases worth a jump table over a compare chain
+pub const JUMP_TABLE_MIN_CASES: usize = 4;
+
+/// Whether a switch over `cases` lowers to a jump table
+pub fn use_jump_table(cases: &[i32]) -> bool {
+    let (Some(low), Some(high)) = (cases.iter().min(), cases.iter().max()) else {
+        return false;
+    };
+    let span = *high as i64 - *low as i64 + 1;
+    cases.len() >= JUMP_TABLE_MIN_CASES && span <= 2 * cases.len() as i64
+}
+
+/// Lower a parsed program to IR
+pub fn lower(program: &ast::Program) -> Result<Program, String> {
+    let mut lowerer = Lowerer::default();
+    let mut prog = Program::new();
+    for func in &program.functions {
+        prog.add_function(lowerer.function(func)?);
+    }
+    loops::annotate_program(&mut prog);
+    Ok(prog)
+}
+
+#[derive(Default)]
+struct Lowerer {
+    /// Register of each variable, per enclosing block (innermost last)
+    scopes: Vec<HashMap<String, u8>>,
+    next_reg: u8,
+    /// Labels are global, so the counter is not
+    label_counter: usize,
+    /// Row stride (in elements) of each 2D array, by register
+    strides: HashMap<u8, Operand>,
+    /// Source line of the innermost statement being lowered
+    line: usize,
+}
+
+fn emit(
+    func: &mut Function,
+    op: Opcode,
+    dest: Option<Operand>,
+    src1: Option<Operand>,
+    src2: Option<Operand>,
+) {
+    func.push(Instruction {
+        op,
+        dest,
+        src1,
+        src2,
+    });
+}
+
+fn label(func: &mut Function, name: &str) {
+    emit(
+        func,
+        Opcode::Label,
+        Some(Operand::Label(name.to_string())),
+        None,
+        None,
+    );
+}
+
+fn jump(func: &mut Function, op: Opcode, target: &str) {
+    emit(
+        func,
+        op,
+        Some(Operand::Label(target.to_string())),
+        None,
+        None,
+    );
+}
+
+impl Lowerer {
+    /// Register of the innermost visible `name`
+    fn lookup(&self, name: &str) -> Option<u8> {
+        self.scopes.iter().rev().find_map(|s| s.get(name).copied())
+    }
+
+    /// Register of `name`, declaring it in the innermost block if unknown
+    fn reg(&mut self, name: &str) -> u8 {
+        match self.lookup(name) {
+            Some(reg) => reg,
+            None => self.declare(name),
+        }
+    }
+
+    /// A fresh register for `name` in the innermost block
+    fn declare(&mut self, name: &str) -> u8 {
+        let reg = self.next_reg;
+        self.next_reg += 1;
+        self.scopes
+            .last_mut()
+            .expect("lowering outside a function")
+            .insert(name.to_string(), reg);
+        reg
+    }
+
+    fn dest(&mut self, name: &str, declare: bool) -> u8 {
+        if declare {
+            self.declare(name)
+        } else {
+            self.reg(name)
+        }
+    }
+
+    /// Lower `body` in a new scope, then release its registers
+    fn scoped(
+        &mut self,
+        func: &mut Function,
+        body: impl FnOnce(&mut Self, &mut Function) -> Result<(), String>,
+    ) -> Result<(), String> {
+        let first_reg = self.next_reg;
+        self.scopes.push(HashMap::new());
+        let result = body(self, func);
+        self.scopes.pop();
+        self.strides.retain(|&reg, _| reg < first_reg);
+        self.next_reg = first_reg;
+        result
+    }
+
+    fn operand(&mut self, value: &Value) -> Operand {
+        match value {
+            Value::Int(n) => Operand::Imm(*n),
+            Value::Var(name) => Operand::Reg(self.reg(name)),
+        }
+    }
+
+    fn generate_label(&mut self, prefix: &str) -> String {
+        self.label_counter += 1;
+        format!("{}_{}", prefix, self.label_counter)
+    }
+
+    fn function(&mut self, ast: &ast::Function) -> Result<Function, String> {
+        self.scopes = vec![HashMap::new()];
+        self.strides.clear();
+        self.next_reg = 10;
+        self.line = ast.at.line;
+
+        let mut func = Function::new(&ast.name, ast.args.clone());
+        func.hints = ast.hints.clone();
+        for (i, arg) in ast.args.iter().enumerate() {
+            let reg = self.reg(arg);
+            emit(
+                &mut func,
+                Opcode::LoadArg(i),
+                Some(Operand::Reg(reg)),
+                None,
+                None,
+            );
+        }
+        self.block(&mut func, &ast.body)?;
+        func.lines.resize(func.instructions.len(), ast.at.line);
+        Ok(func)
+    }
+
+    fn block(&mut self, func: &mut Function, body: &[Spanned<Stmt>]) -> Result<(), String> {
+        body.iter().try_for_each(|stmt| {
+            // What the enclosing statement emitted so far (a loop's test) is
+            // its own
+            func.lines.resize(func.instructions.len(), self.line);
+            let outer = std::mem::replace(&mut self.line, stmt.at.line);
+            self.stmt(func, &stmt.node)?;
+            func.lines.resize(func.instructions.len(), stmt.at.line);
+            self.line = outer;
+            Ok(())
+        })
+    }
+
+    /// A `{ ... }` block in its own scope
+    fn scoped_block(&mut self, func: &mut Function, body: &[Spanned<Stmt>]) -> Result<(), String> {
+        self.scoped(func, |this, func| this.block(func, body))
+    }
+
+    /// Hand pragma hints to the loop headed by `label`
+    fn attach_loop_hints(func: &mut Function, label: &str, hints: &Option<Hints>) {
+        if let Some(hints) = hints {
+            func.loop_hints.insert(label.to_string(), hints.clone());
+        }
+    }
+
+    /// Compare and return the jump taken when `cond` holds
+    fn condition(&mut self, func: &mut Function, cond: &Condition) -> Opcode {
+        let lhs = self.operand(&cond.lhs);
+        let rhs = self.operand(&cond.rhs);
+        emit(func, Opcode::Cmp, None, Some(lhs), Some(rhs));
+        match cond.op {
+            CmpOp::Eq => Opcode::Je,
+            CmpOp::Ne => Opcode::Jne,
+            CmpOp::Lt => Opcode::Jl,
+            CmpOp::Le => Opcode::Jle,
+            CmpOp::Gt => Opcode::Jg,
+            CmpOp::Ge => Opcode::Jge,
+        }
+    }
+
+    /// `jcc body; jmp end; label body`
+    fn branch_to_body(func: &mut Function, jump_op: Opcode, body: &str, end: &str) {
+        jump(func, jump_op, body);
+        jump(func, Opcode::Jmp, end);
+        label(func, body);
+    }
+
+    fn stmt(&mut self, func: &mut Function, stmt: &Stmt) -> Result<(), String> {
+        match stmt {
+            Stmt::Assign(assign) => self.assign(func, assign, false)?,
+            Stmt::Let(assign) => self.assign(func, assign, true)?,
+            Stmt::Store { index, value } => {
+                let disp = index.offset;
+                let (base, index) = self.index(func, index)?;
+                let value = self.operand(value);
+                emit(func, Opcode::Store(disp), Some(base), Some(index), Some(value));
+            }
+            Stmt::Return(value) => {
+                let value = self.operand(value);
+                emit(func, Opcode::Mov, Some(Operand::Reg(0)), Some(value), None);
+                emit(func, Opcode::Ret, None, None, None);
+            }
+            Stmt::Label { name, hints } => {
+                Self::attach_loop_hints(func, name, hints);
+                label(func, name);
+            }
+            Stmt::Goto(target) => jump(func, Opcode::Jmp, target),
+            Stmt::IfGoto { cond, label } => {
+                let jump_op = self.condition(func, cond);
+                jump(func, jump_op, label);
+            }
+            Stmt::If { cond, body } => {
+                let jump_op = self.condition(func, cond);
+                let body_label = self.generate_label("if_body");
+                let end_label = self.generate_label("if_end");
+                Self::branch_to_body(func, jump_op, &body_label, &end_label);
+                self.scoped_block(func, body)?;
+                label(func, &end_label);
+            }
+            Stmt::While { cond, body, hints } => {
+                let start_label = self.generate_label("while_start");
+                let body_label = self.generate_label("while_body");
+                let end_label = self.generate_label("while_end");
+                Self::attach_loop_hints(func, &start_label, hints);
+                label(func, &start_label);
+                let jump_op = self.condition(func, cond);
+                Self::branch_to_body(func, jump_op, &body_label, &end_label);
+                self.scoped_block(func, body)?;
+                jump(func, Opcode::Jmp, &start_label);
+                label(func, &end_label);
+            }
+            Stmt::For {
+                init,
+                cond,
+                step,
+                body,
+                hints,
+            } => {
+                // Variables declared by the header live until the loop ends
+                self.scoped(func, |this, func| {
+                    this.assign(func, init, false)?;
+                    let start_label = this.generate_label("for_start");
+                    let body_label = this.generate_label("for_body");
+                    let end_label = this.generate_label("for_end");
+                    // Unused, but numbered so label names stay stable
+                    let _step_label = this.generate_label("for_step");
+                    Self::attach_loop_hints(func, &start_label, hints);
+                    label(func, &start_label);
+                    let jump_op = this.condition(func, cond);
+                    Self::branch_to_body(func, jump_op, &body_label, &end_label);
+                    this.scoped_block(func, body)?;
+                    if let Some(step) = step {
+                        this.assign(func, step, false)?;
+                    }
+                    jump(func, Opcode::Jmp, &start_label);
+                    label(func, &end_label);
+                    Ok(())
+                })?;
+            }
+            Stmt::Switch {
+                value,
+                cases,
+                default,
+            } => self.switch(func, value, cases, default.as_deref())?,
+            Stmt::Free(ptr) => {
+                let ptr = self.operand(ptr);
+                emit(func, Opcode::Free, None, Some(ptr), None);
+            }
+            Stmt::Assert(cond) => {
+                let lhs = self.operand(&cond.lhs);
+                let rhs = self.operand(&cond.rhs);
+                let holds = match cond.op {
+                    CmpOp::Eq => Cond::Eq,
+                    CmpOp::Ne => Cond::Ne,
+                    CmpOp::Lt => Cond::Lt,
+                    CmpOp::Le => Cond::Le,
+                    CmpOp::Gt => Cond::Gt,
+                    CmpOp::Ge => Cond::Ge,
+                };
+                let op = Opcode::Assert(holds, cond.to_string());
+                emit(func, op, None, Some(lhs), Some(rhs));
+            }
+            Stmt::Stride { array, cols } => {
+                let cols = self.operand(cols);
+                let array = self.reg(array);
+                self.strides.insert(array, cols);
+            }
+        }
+        Ok(())
+    }
+
+    /// Dispatch on `value` (see the module docs), then each arm in its own
+    /// scope, ending in a jump past the rest
+    fn switch(
+        &mut self,
+        func: &mut Function,
+        value: &Value,
+        cases: &[(i32, ast::Block)],
+        default: Option<&[Spanned<Stmt>]>,
+    ) -> Result<(), String> {
+        let case_labels: Vec<String> = cases
+            .iter()
+            .map(|_| self.generate_label("switch_case"))
+            .collect();
+        let default_label = default.map(|_| self.generate_label("switch_default"));
+        let end_label = self.generate_label("switch_end");
+        let otherwise = default_label.clone().unwrap_or_else(|| end_label.clone());
+
+        let value = match self.operand(value) {
+            Operand::Imm(n) => {
+                let reg = Operand::Reg(self.reg("%switch"));
+                emit(func, Opcode::Mov, Some(reg.clone()), Some(Operand::Imm(n)), None);
+                reg
+            }
+            reg => reg,
+        };
+        let keys: Vec<i32> = cases.iter().map(|(key, _)| *key).collect();
+        if use_jump_table(&keys) {
+            let low = *keys.iter().min().expect("cases");
+            let high = *keys.iter().max().expect("cases");
+            let targets = (low..=high)
+                .map(|key| match keys.iter().position(|&k| k == key) {
+                    Some(i) => case_labels[i].clone(),
+                    None => otherwise.clone(),
+                })
+                .collect();
+            emit(
+                func,
+                Opcode::JumpTable(low, targets),
+                Some(Operand::Label(otherwise)),
+                Some(value),
+                None,
+            );
+        } else {
+            for (key, case_label) in keys.iter().zip(&case_labels) {
+                emit(func, Opcode::Cmp, None, Some(value.clone()), Some(Operand::Imm(*key)));
+                jump(func, Opcode::Je, case_label);
+            }
+            jump(func, Opcode::Jmp, &otherwise);
+        }
+
+        for ((_, body), case_label) in cases.iter().zip(&case_labels) {
+            label(func, case_label);
+            self.scoped_block(func, body)?;
+            jump(func, Opcode::Jmp, &end_label);
+        }
+        if let (Some(body), Some(default_label)) = (default, &default_label) {
+            label(func, default_label);
+            self.scoped_block(func, body)?;
+        }
+        label(func, &end_label);
+        Ok(())
+    }
+
+    /// Lower `dest = value`; with `declare`, `dest` is a new variable even
+    /// if an enclosing block has one (the value still sees the old one)
+    fn assign(
+        &mut self,
+        func: &mut Function,
+        assign: &Assign,
+        declare: bool,
+    ) -> Result<(), String> {
+        let dest_name = &assign.dest;
+        match &assign.value {
+            Expr::Value(value) => {
+                let src = self.operand(value);
+                let dest = Operand::Reg(self.dest(dest_name, declare));
+                emit(func, Opcode::Mov, Some(dest), Some(src), None);
+            }
+            Expr::Binary(op, a, b) => {
+                let mut a = self.operand(a);
+                let mut b = self.operand(b);
+                let dest = Operand::Reg(self.dest(dest_name, declare));
+                let op = match op {
+                    BinOp::Add => Opcode::Add,
+                    BinOp::Sub => Opcode::Sub,
+                    BinOp::Mul => Opcode::Mul,
+                    BinOp::Min => Opcode::Min,
+                    BinOp::Max => Opcode::Max,
+                };
+                // `b = min(a, b)` must not overwrite b before reading it
+                if matches!(op, Opcode::Min | Opcode::Max) && b == dest {
+                    std::mem::swap(&mut a, &mut b);
+                }
+                emit(func, Opcode::Mov, Some(dest.clone()), Some(a), None);
+                emit(func, op, Some(dest), Some(b), None);
+            }
+            Expr::Unary(op, arg) => {
+                let arg = self.operand(arg);
+                let dest = Operand::Reg(self.dest(dest_name, declare));
+                let op = match op {
+                    UnaryOp::Popcnt => Opcode::Popcnt,
+                    UnaryOp::Ctz => Opcode::Ctz,
+                    UnaryOp::Clz => Opcode::Clz,
+                    UnaryOp::Abs => Opcode::Abs,
+                };
+                emit(func, op, Some(dest), Some(arg), None);
+            }
+            Expr::Load(index) => {
+                let disp = index.offset;
+                let (base, index) = self.index(func, index)?;
+                let dest = Operand::Reg(self.dest(dest_name, declare));
+                emit(func, Opcode::Load(disp), Some(dest), Some(base), Some(index));
+            }
+            Expr::Call { name, args } => {
+                let args: Vec<Operand> = args.iter().map(|a| self.operand(a)).collect();
+                for (i, arg) in args.into_iter().enumerate() {
+                    let arg_phys_vreg = (i + 1) as u8;
+                    emit(
+                        func,
+                        Opcode::SetArg(i),
+                        Some(Operand::Reg(arg_phys_vreg)),
+                        Some(arg),
+                        None,
+                    );
+                }
+                let dest = Operand::Reg(self.dest(dest_name, declare));
+                emit(
+                    func,
+                    Opcode::Call,
+                    Some(dest),
+                    Some(Operand::Label(name.clone())),
+                    None,
+                );
+            }
+            Expr::Alloc { size, align } => {
+                let size = self.operand(size);
+                let dest = Operand::Reg(self.dest(dest_name, declare));
+                let align = align.map(|a| Operand::Imm(a as i32));
+                emit(func, Opcode::Alloc, Some(dest), Some(size), align);
+            }
+            Expr::Alloc2d { rows, cols, at } => {
+                let rows = self.operand(rows);
+                let cols = self.operand(cols);
+                let size = match (&rows, &cols) {
+                    (Operand::Imm(r), Operand::Imm(c)) => Operand::Imm(
+                        r.checked_mul(*c)
+                            .and_then(|n| n.checked_mul(8))
+                            .ok_or_else(|| {
+                                format!("alloc2d size too large at line {}:{}", at.line, at.col)
+                            })?,
+                    ),
+                    _ => {
+                        let size = Operand::Reg(self.reg("%row"));
+                        for (op, src) in [
+                            (Opcode::Mov, rows),
+                            (Opcode::Mul, cols.clone()),
+                            (Opcode::Mul, Operand::Imm(8)),
+                        ] {
+                            emit(func, op, Some(size.clone()), Some(src), None);
+                        }
+                        size
+                    }
+                };
+                let dest = self.dest(dest_name, declare);
+                emit(
+                    func,
+                    Opcode::Alloc,
+                    Some(Operand::Reg(dest)),
+                    Some(size),
+                    None,
+                );
+                self.strides.insert(dest, cols);
+            }
+        }
+        Ok(())
+    }
+
+    /// Base and index operands for a Load/Store: a 2D index addresses the
+    /// row first (see `row_address`) and indexes it by the column.
+    fn index(&mut self, func: &mut Function, index: &Index) -> Result<(Operand, Operand), String> {
+        let index_op = self.operand(&index.index);
+        match &index.col {
+            Some(col) => {
+                let col_op = self.operand(col);
+                let row = self.row_address(func, &index.array, index_op, index.at)?;
+                Ok((row, col_op))
+            }
+            None => Ok((Operand::Reg(self.reg(&index.array)), index_op)),
+        }
+    }
+
+    /// Emit `%row = array + row * stride * 8`, the address of a 2D array's row
+    fn row_address(
+        &mut self,
+        func: &mut Function,
+        array: &str,
+        row: Operand,
+        at: Span,
+    ) -> Result<Operand, String> {
+        let stride = self
+            .lookup(array)
+            .and_then(|reg| self.strides.get(&reg).cloned());
+        let stride = stride.ok_or_else(|| {
+            format!(
+                "'{}' has no row stride at line {}:{}; allocate it with alloc2d(rows, cols) or declare stride({}, cols)",
+                array, at.line, at.col, array
+            )
+        })?;
+        let base = Operand::Reg(self.reg(array));
+        let address = Operand::Reg(self.reg("%row"));
+        let offset_overflow = || format!("Row offset too large at line {}:{}", at.line, at.col);
+        let mut push = |op, src1| emit(func, op, Some(address.clone()), Some(src1), None);
+        match (&row, &stride) {
+            (Operand::Imm(r), Operand::Imm(s)) => {
+                let offset = r.checked_mul(*s).and_then(|o| o.checked_mul(8));
+                push(
+                    Opcode::Mov,
+                    Operand::Imm(offset.ok_or_else(offset_overflow)?),
+                );
+            }
+            (_, Operand::Imm(s)) => {
+                push(Opcode::Mov, row);
+                push(
+                    Opcode::Mul,
+                    Operand::Imm(s.checked_mul(8).ok_or_else(offset_overflow)?),
+                );
+            }
+            _ => {
+                push(Opcode::Mov, row);
+                push(Opcode::Mul, stride);
+                push(Opcode::Mul, Operand::Imm(8));
+            }
+        }
+        push(Opcode::Add, base);
+        Ok(address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    #[test]
+    fn test_lowers_for_loop_with_step_after_body() {
+        let ast = Parser::new()
+            .parse_ast(
+                "fn main(n) {\ns = 0\nfor (i = 0; i < n; i = i + 1) {\ns = s + i\n}\nreturn s\n}",
+            )
+            .unwrap();
+        let prog = lower(&ast).unwrap();
+        let text: Vec<String> = prog.functions[0]
+            .instructions
+            .iter()
+            .map(|i| i.to_string())
+            .collect();
+        let body = text
+            .iter()
+            .position(|t| t.starts_with("Label") && t.contains("for_body"))
+            .unwrap();
+        // n=10, s=11, i=12: the body's add comes before the step's
+        assert_eq!(text[body + 2], "Add Reg(11), Reg(12)");
+        assert_eq!(text[body + 4], "Add Reg(12), Imm(1)");
+        assert!(text[body + 5].starts_with("Jmp") && text[body + 5].contains("for_start"));
+
+        // The header's test and step belong to line 3, the body to line 4
+        let func = &prog.functions[0];
+        assert_eq!(func.lines.len(), text.len());
+        assert_eq!((func.line(0), func.line(1)), (1, 2));
+        assert_eq!(func.line(body + 2), 4);
+        assert_eq!((func.line(body + 4), func.line(body + 5)), (3, 3));
+        assert_eq!(func.line(text.len() - 1), 6);
+    }
+
+    #[test]
+    fn test_switch_lowers_by_case_density() {
+        assert!(use_jump_table(&[0, 1, 2, 3]));
+        assert!(use_jump_table(&[10, 17, 12, 13]));
+        assert!(!use_jump_table(&[0, 1, 2]));
+        assert!(!use_jump_table(&[0, 1, 2, 9]));
+        assert!(!use_jump_table(&[i32::MIN, 0, 1, i32::MAX]));
+
+        let lowered = |cases: &str| {
+            let src = format!("fn main(x) {{\nr = 0\nswitch x {{\n{}\n_ => r = 9\n}}\nreturn r\n}}", cases);
+            lower(&Parser::new().parse_ast(&src).unwrap()).unwrap().functions[0].clone()
+        };
+        // Slot 2 has no case: it goes to the default arm
+        let dense = lowered("1 => r = 1\n3 => r = 3\n4 => r = 4\n5 => r = 5");
+        let table = dense.instructions.iter().find(|i| matches!(i.op, Opcode::JumpTable(..))).unwrap();
+        let Opcode::JumpTable(low, targets) = &table.op else { unreachable!() };
+        assert_eq!(*low, 1);
+        assert_eq!(targets.len(), 5);
+        assert_eq!(Some(&Operand::Label(targets[1].clone())), table.dest.as_ref());
+        assert!(targets[1].starts_with("switch_default"));
+        assert!(!dense.instructions.iter().any(|i| i.op == Opcode::Cmp));
+
+        let sparse = lowered("1 => r = 1\n30 => r = 3\n400 => r = 4\n5000 => r = 5");
+        assert!(!sparse.instructions.iter().any(|i| matches!(i.op, Opcode::JumpTable(..))));
+        assert_eq!(sparse.instructions.iter().filter(|i| i.op == Opcode::Je).count(), 4);
+    }
+
+}
@@ -0,0 +1,1335 @@
+use crate::alignment;
+use crate::target::VectorWidth;
+use crate::ir::{Access, Cond, Function, Instruction, Opcode, Operand};
+use crate::loops;
+use crate::passes::{Pass, PassSet};
+use crate::report::{FunctionReport, OptimizationReport, Transform};
+use crate::specialize;
+
+pub struct Optimizer;
+
+impl Optimizer {
+    pub fn optimize_program(prog: &mut crate::ir::Program, level: u8) {
+        Self::optimize_program_for(prog, level, VectorWidth::default());
+    }
+
+    /// `optimize_program`, vectorizing loops `width` lanes at a time
+    pub fn optimize_program_for(prog: &mut crate::ir::Program, level: u8, width: VectorWidth) {
+        let mut report = OptimizationReport::new(prog, level);
+        Self::optimize_program_with_report(prog, level, width, &mut report);
+    }
+
+    /// `optimize_program_for`, recording what each pass did in `report` (one
+    /// entry per function, in program order)
+    pub fn optimize_program_with_report(
+        prog: &mut crate::ir::Program,
+        level: u8,
+        width: VectorWidth,
+        report: &mut OptimizationReport,
+    ) {
+        Self::optimize_program_except(prog, level, width, PassSet::default(), report);
+    }
+
+    /// `optimize_program_with_report`, skipping the passes in `disabled`.
+    /// Functions with an `opt_level` hint are optimized at that level instead
+    /// of `level`, and also skip the passes in their `disabled_passes` hint.
+    pub fn optimize_program_except(
+        prog: &mut crate::ir::Program,
+        level: u8,
+        width: VectorWidth,
+        disabled: PassSet,
+        report: &mut OptimizationReport,
+    ) {
+        for (func, func_report) in prog.functions.iter_mut().zip(&mut report.functions) {
+            let level = func.hints.opt_level.unwrap_or(level);
+            let disabled = disabled.union(func.hints.disabled_passes);
+            Self::optimize_function(func, level, width, disabled, func_report);
+        }
+    }
+
+    fn optimize_function(
+        func: &mut Function,
+        level: u8,
+        width: VectorWidth,
+        disabled: PassSet,
+        report: &mut FunctionReport,
+    ) {
+        let on = |pass| !disabled.contains(pass);
+        // First, so the passes below see the constants in the copy
+        if level >= 2 && on(Pass::SpecializeConstantArgs) {
+            let specialized = specialize::specialize_constant_args(func);
+            report.note("specialize_constant_args", specialized, func);
+        }
+        // The loop passes read `func.loops`: re-annotate whenever code moved
+        loops::annotate(func);
+        let mut changed = true;
+        while changed {
+            changed = false;
+            if on(Pass::RemoveIdentityMoves) {
+                changed |= report.note(
+                    "remove_identity_moves",
+                    Self::remove_identity_moves(func),
+                    func,
+                );
+            }
+            if on(Pass::ConstantFolding) {
+                changed |= report.note("constant_folding", Self::constant_folding(func), func);
+            }
+            if on(Pass::DeadCodeElimination) {
+                changed |= report.note(
+                    "dead_code_elimination",
+                    Self::dead_code_elimination(func),
+                    func,
+                );
+            }
+            if level >= 1 && on(Pass::DeadStoreElimination) {
+                changed |= report.note(
+                    "dead_store_elimination",
+                    Self::dead_store_elimination(func),
+                    func,
+                );
+            }
+            if changed {
+                loops::annotate(func);
+            }
+            if level >= 3 && on(Pass::VectorizeLoop) {
+                let vectorized = Self::vectorize_loop(func, width, report);
+                if report.note("vectorize_loop", vectorized, func) {
+                    loops::annotate(func);
+                    changed = true;
+                }
+            }
+            // Between the two: the vectorizer gets first pick of the loops,
+            // and the unroller leaves specialized ones alone
+            if level >= 2 && on(Pass::SpecializeTripCounts) {
+                let specialized = specialize::specialize_trip_counts(func, report);
+                if report.note("specialize_trip_counts", specialized, func) {
+                    changed = true;
+                }
+            }
+            if level >= 2 && on(Pass::LoopUnrolling) {
+                let unrolled = Self::loop_unrolling(func, report);
+                if report.note("loop_unrolling", unrolled, func) {
+                    loops::annotate(func);
+                    changed = true;
+                }
+            }
+        }
+        if level >= 2 && on(Pass::JamUnrolledLoops) {
+            let jammed = Self::jam_unrolled_loops(func, report);
+            report.note("jam_unrolled_loops", jammed, func);
+        }
+        // Last: unrolling and jamming move accesses
+        if level >= 3 && on(Pass::SelectVectorAccess) {
+            report.note(
+                "select_vector_access",
+                Self::select_vector_access(func, width),
+                func,
+            );
+        }
+    }
+
+    fn remove_identity_moves(func: &mut Function) -> bool {
+        let mut changed = false;
+        let mut i = 0;
+        while i < func.instructions.len() {
+            if let (Opcode::Mov, Some(Operand::Reg(d)), Some(Operand::Reg(s))) = (
+                &func.instructions[i].op,
+                &func.instructions[i].dest,
+                &func.instructions[i].src1,
+            ) {
+                if d == s {
+                    func.instructions.remove(i);
+                    changed = true;
+                    continue;
+                }
+            }
+            i += 1;
+        }
+        changed
+    }
+
+    /// Fold: Mov R, Imm(A) ; Add R, Imm(B) -> Mov R, Imm(A+B)
+    /// Also: Mov R, Imm(A) ; Mov R2, R -> Mov R2, Imm(A) (Constant Propagation)
+    /// And bit intrinsics of an immediate: Popcnt R, Imm(A) -> Mov R, Imm(popcount(A))
+    fn constant_folding(func: &mut Function) -> bool {
+        let mut changed = false;
+        for instr in &mut func.instructions {
+            if let Some(Operand::Imm(v)) = instr.src1 {
+                // Immediates are sign-extended to 64 bits, as in codegen
+                let folded = match instr.op {
+                    Opcode::Popcnt => (v as i64).count_ones(),
+                    Opcode::Ctz => (v as i64).trailing_zeros(),
+                    Opcode::Clz => (v as i64).leading_zeros(),
+                    _ => continue,
+                };
+                instr.op = Opcode::Mov;
+                instr.src1 = Some(Operand::Imm(folded as i32));
+                changed = true;
+            }
+        }
+        let mut i = 0;
+
+        while i < func.instructions.len() - 1 {
+            let left_idx = i;
+            let right_idx = i + 1;
+
+            // Check if we can merge left and right
+            let left = &func.instructions[left_idx];
+            let right = &func.instructions[right_idx];
+
+            // Case 1: Mov R, Imm; Add R, Imm
+            if let (Opcode::Mov, Some(Operand::Reg(r1)), Some(Operand::Imm(v1))) =
+                (&left.op, &left.dest, &left.src1)
+            {
+                if let (Opcode::Add, Some(Operand::Reg(r2)), Some(Operand::Imm(v2))) =
+                    (&right.op, &right.dest, &right.src1)
+                {
+                    // Requires r1 == r2 (operating on same register), and a
+                    // sum that still fits an i32 immediate
+                    let sum = if r1 == r2 { v1.checked_add(*v2) } else { None };
+                    if let Some(new_val) = sum {
+                        // Merge!
+                        // Left becomes: Mov R, Imm(v1 + v2)
+                        // Right becomes: NOP (or removed)
+                        func.instructions[left_idx].src1 = Some(Operand::Imm(new_val));
+                        func.instructions.remove(right_idx);
+                        changed = true;
+                        continue; // Restart loop or check next
+                    }
+                }
+            }
+
+            i += 1;
+        }
+        changed
+    }
+
+    fn dead_code_elimination(func: &mut Function) -> bool {
+        let mut changed = false;
+        let mut i = 0;
+        let mut dead_zone = false;
+
+        while i < func.instructions.len() {
+            let op = &func.instructions[i].op;
+
+            if matches!(op, Opcode::Label) {
+                dead_zone = false;
+            }
+
+            if dead_zone {
+                func.instructions.remove(i);
+                changed = true;
+                continue; // Do no increment i
+            }
+
+            if func.instructions[i].ends_block() {
+                dead_zone = true;
+            }
+
+            i += 1;
+        }
+        changed
+    }
+
+    /// Remove arrays that are written but never read.
+    ///
+    /// Conservative: a pointer qualifies only if it is defined by a single
+    /// `Alloc` and otherwise appears solely as the base of `Store`/`VStore`
+    /// or the argument of `Free`. Any other use (Load, Mov, SetArg, Ret,
+    /// arithmetic, being stored) means it may be read or escape, so it is kept.
+    /// The Alloc, its Stores and its Free are all removed.
+    fn dead_store_elimination(func: &mut Function) -> bool {
+        use std::collections::HashMap;
+
+        #[derive(Default)]
+        struct Uses {
+            allocs: usize,
+            escapes: bool,
+        }
+
+        let mut uses: HashMap<u8, Uses> = HashMap::new();
+        for instr in &func.instructions {
+            let operands = [
+                (&instr.dest, 0),
+                (&instr.src1, 1),
+                (&instr.src2, 2),
+            ];
+            for (operand, slot) in operands {
+                let Some(Operand::Reg(r)) = operand else {
+                    continue;
+                };
+                let entry = uses.entry(*r).or_default();
+                match (&instr.op, slot) {
+                    (Opcode::Alloc, 0) => entry.allocs += 1,
+                    (Opcode::Store(_) | Opcode::VStore(..), 0) | (Opcode::Free, 1) => {}
+                    _ => entry.escapes = true,
+                }
+            }
+        }
+
+        let dead: Vec<u8> = uses
+            .into_iter()
+            .filter(|(_, u)| u.allocs == 1 && !u.escapes)
+            .map(|(r, _)| r)
+            .collect();
+        if dead.is_empty() {
+            return false;
+        }
+
+        let is_dead = |op: &Option<Operand>| matches!(op, Some(Operand::Reg(r)) if dead.contains(r));
+        func.instructions.retain(|instr| match instr.op {
+            Opcode::Alloc | Opcode::Store(_) | Opcode::VStore(..) => !is_dead(&instr.dest),
+            Opcode::Free => !is_dead(&instr.src1),
+            _ => true,
+        });
+        true
+    }
+
+    fn loop_unrolling(func: &mut Function, report: &mut FunctionReport) -> bool {
+        // Only handle unconditional backward jumps for now (simple loops)
+        let mut loops: Vec<(String, loops::LoopInfo)> = func
+            .loops
+            .iter()
+            .filter(|(_, l)| func.instructions[l.back_jump].op == Opcode::Jmp)
+            .map(|(label, l)| (label.clone(), l.clone()))
+            .collect();
+        loops.sort_by_key(|(_, l)| l.back_jump);
+
+        for (target, info) in &loops {
+            let body = info.body();
+            let body_len = body.len();
+
+            // `#pragma unroll(N)` pins the factor: N - 1 extra copies
+            // in one go, then the hint is marked spent (unroll(1))
+            let pinned = func.hints_for_loop(target).unroll;
+            if pinned == Some(1) {
+                report.decide(target, Transform::Unroll, false, "#pragma unroll(1)".into());
+                continue;
+            }
+            let trips = info.induction.as_ref().and_then(|i| i.trip_count);
+
+            // Heuristic: Small-ish loops only
+            if body_len == 0 {
+                report.decide(target, Transform::Unroll, false, "empty body".into());
+            } else if let Some(trips @ (0 | 1)) = trips {
+                // A copy would never run: the exit test always leaves first
+                let reason = format!("runs {} time{}", trips, if trips == 1 { "" } else { "s" });
+                report.decide(target, Transform::Unroll, false, reason);
+            } else if pinned.is_none() && body_len >= 50 {
+                let reason = format!("body of {} instructions exceeds the 50-instruction limit", body_len);
+                report.decide(target, Transform::Unroll, false, reason);
+            } else if func.instructions[body.clone()].iter().any(|inst| inst.op == Opcode::Label) {
+                // Safety: a copied label would be defined twice
+                report.decide(target, Transform::Unroll, false, "body contains labels".into());
+            } else {
+                let reason = match pinned {
+                    Some(n) => format!("#pragma unroll({})", n),
+                    None => format!("body of {} instructions", body_len),
+                };
+                report.decide(target, Transform::Unroll, true, reason);
+                // Insert the copies before the back jump
+                let copies = pinned.map_or(1, |n| n as usize - 1);
+                let copied: Vec<Instruction> =
+                    func.instructions[body].iter().cycle().take(body_len * copies).cloned().collect();
+                func.instructions.splice(info.back_jump..info.back_jump, copied);
+                if pinned.is_some() {
+                    func.loop_hints.entry(target.clone()).or_default().unroll = Some(1);
+                }
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Unroll-and-jam for loops the unroller copied. A body of U copies of
+    /// `Cmp i, n; Je exit; W; Add i, 1`, where W uses i only as an array
+    /// index, becomes a main loop that runs U iterations at once, with copy
+    /// k of W addressing at displacement +k and a single index step, and
+    /// the original body as the remainder:
+    ///
+    /// ```text
+    /// label L
+    ///   Mov t, i; Add t, U-1; Cmp t, n; Jge L_rest
+    ///   W(+0); W(+1); ...; W(+U-1)
+    ///   Add i, U
+    ///   Jmp L
+    /// label L_rest
+    ///   Cmp i, n; Je exit; W; Add i, 1
+    ///   Jmp L_rest
+    /// ```
+    ///
+    /// `Jge` exits are handled the same way, `Jg` exits with a `Jg` guard.
+    fn jam_unrolled_loops(func: &mut Function, report: &mut FunctionReport) -> bool {
+        let mut labels: Vec<(usize, String)> = func
+            .loops
+            .iter()
+            .filter(|(_, l)| func.instructions[l.back_jump].op == Opcode::Jmp)
+            .map(|(label, l)| (l.back_jump, label.clone()))
+            .collect();
+        labels.sort();
+
+        let mut changed = false;
+        for (_, label) in labels {
+            // Earlier jams moved the code after them
+            let Some(info) = func.loops.get(&label) else {
+                continue;
+            };
+            let (start, end) = (info.head, info.back_jump);
+            match Self::jam(func, &label, start, end) {
+                Ok(Some((jammed, copies))) => {
+                    func.instructions.splice(start..=end, jammed);
+                    loops::annotate(func);
+                    let reason = format!("{} copies, one index step", copies);
+                    report.decide(&label, Transform::Jam, true, reason);
+                    changed = true;
+                }
+                Ok(None) => {}
+                Err(reason) => report.decide(&label, Transform::Jam, false, reason),
+            }
+        }
+        changed
+    }
+
+    /// The jammed replacement of the loop `start..=end` (its label through
+    /// its back jump) and the copies merged; None if it wasn't unrolled
+    fn jam(
+        func: &Function,
+        label: &str,
+        start: usize,
+        end: usize,
+    ) -> Result<Option<(Vec<Instruction>, usize)>, String> {
+        let body = &func.instructions[start + 1..end];
+        // The unroller appends whole copies of the body
+        let Some(period) = (1..=body.len() / 2)
+            .find(|&m| body.len().is_multiple_of(m) && body.chunks(m).all(|c| c == &body[..m]))
+        else {
+            return Ok(None);
+        };
+        let copies = body.len() / period;
+        let original = &body[..period];
+
+        let [cmp, exit, work @ .., step] = original else {
+            return Err("body too short".to_string());
+        };
+        let guard = match exit.op {
+            Opcode::Je | Opcode::Jge => Opcode::Jge,
+            Opcode::Jg => Opcode::Jg,
+            _ => return Err("no `if i == n goto` exit test at the top".to_string()),
+        };
+        let (Opcode::Cmp, Some(index @ Operand::Reg(_)), Some(bound)) = (&cmp.op, &cmp.src1, &cmp.src2)
+        else {
+            return Err("no `if i == n goto` exit test at the top".to_string());
+        };
+        if target_of(exit) == Some(label) {
+            return Err("the exit test jumps back to the loop".to_string());
+        }
+        if step.op != Opcode::Add || step.dest.as_ref() != Some(index) || step.src1 != Some(Operand::Imm(1)) {
+            return Err("the index is not stepped by 1 at the end".to_string());
+        }
+        for instr in work {
+            let control = matches!(
+                instr.op,
+                Opcode::Label | Opcode::Jmp | Opcode::Jnz | Opcode::JumpTable(..) | Opcode::Call | Opcode::Ret
+            ) || Cond::of_jump(&instr.op).is_some();
+            if control {
+                return Err("the body branches or calls".to_string());
+            }
+            // Operands other than the array index
+            let others: &[&Option<Operand>] = match instr.op {
+                Opcode::Load(_) | Opcode::VLoad(..) => &[&instr.dest, &instr.src1],
+                Opcode::Store(_) | Opcode::VStore(..) => &[&instr.dest, &instr.src2],
+                _ => &[&instr.dest, &instr.src1, &instr.src2],
+            };
+            if others.iter().any(|o| o.as_ref() == Some(index)) {
+                return Err("the index is used outside array subscripts".to_string());
+            }
+            let writes = !matches!(instr.op, Opcode::Store(_) | Opcode::VStore(..) | Opcode::PrefetchT0);
+            if writes && instr.dest.as_ref() == Some(bound) {
+                return Err("the bound changes in the body".to_string());
+            }
+        }
+
+        let rest = format!("{}_rest", label);
+        if func.instructions.iter().any(|i| target_of(i) == Some(rest.as_str())) {
+            return Err(format!("label {} is taken", rest));
+        }
+        let used: std::collections::HashSet<u8> = func
+            .instructions
+            .iter()
+            .flat_map(|i| [&i.dest, &i.src1, &i.src2])
+            .filter_map(|o| match o {
+                Some(Operand::Reg(r)) => Some(*r),
+                _ => None,
+            })
+            .collect();
+        let Some(temp) = (10..=u8::MAX).find(|r| !used.contains(r)) else {
+            return Err("no free register".to_string());
+        };
+        let instr = |op, dest, src1, src2| Instruction { op, dest, src1, src2 };
+        let temp = Operand::Reg(temp);
+        let last = Operand::Imm(copies as i32 - 1);
+
+        let mut out = vec![
+            func.instructions[start].clone(),
+            instr(Opcode::Mov, Some(temp.clone()), Some(index.clone()), None),
+            instr(Opcode::Add, Some(temp.clone()), Some(last), None),
+            instr(Opcode::Cmp, None, Some(temp), Some(bound.clone())),
+            instr(guard, Some(Operand::Label(rest.clone())), None, None),
+        ];
+        for k in 0..copies as i32 {
+            for w in work {
+                let mut w = w.clone();
+                let indexed = match w.op {
+                    Opcode::Load(_) | Opcode::VLoad(..) => w.src2.as_ref() == Some(index),
+                    Opcode::Store(_) | Opcode::VStore(..) => w.src1.as_ref() == Some(index),
+                    _ => false,
+                };
+                if indexed {
+                    let disp = w.op.displacement().and_then(|d| d.checked_add(k));
+                    let disp = disp
+                        .filter(|d| d.checked_mul(8).is_some())
+                        .ok_or("displacement out of range")?;
+                    // A shifted vector access may no longer be aligned
+                    w.op = match w.op {
+                        Opcode::Load(_) => Opcode::Load(disp),
+                        Opcode::VLoad(..) => Opcode::VLoad(disp, Access::Unaligned),
+                        Opcode::Store(_) => Opcode::Store(disp),
+                        _ => Opcode::VStore(disp, Access::Unaligned),
+                    };
+                }
+                out.push(w);
+            }
+        }
+        out.push(instr(Opcode::Add, Some(index.clone()), Some(Operand::Imm(copies as i32)), None));
+        out.push(func.instructions[end].clone());
+        out.push(instr(Opcode::Label, Some(Operand::Label(rest.clone())), None, None));
+        out.extend(original.iter().cloned());
+        out.push(instr(Opcode::Jmp, Some(Operand::Label(rest)), None, None));
+        Ok(Some((out, copies)))
+    }
+
+    fn vectorize_loop(func: &mut Function, width: VectorWidth, report: &mut FunctionReport) -> bool {
+        let mut loops: Vec<(String, loops::LoopInfo)> =
+            func.loops.iter().map(|(label, l)| (label.clone(), l.clone())).collect();
+        loops.sort_by_key(|(_, l)| l.head);
+        for (label, info) in loops {
+            // Our own vector loops, and the loops they came from, are done
+            let vectorized = format!("{}_vec", label);
+            if label.ends_with("_vec") || func.loops.contains_key(&vectorized) {
+                continue;
+            }
+            if Self::vectorize(func, label, &info, width, report) {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn vectorize(
+        func: &mut Function,
+        label_name: String,
+        info: &loops::LoopInfo,
+        width: VectorWidth,
+        report: &mut FunctionReport,
+    ) -> bool {
+        // Simple Pattern Matcher for:
+        // Load v1, A, i
+        // Load v2, B, i
+        // Add v3, v1, v2   (or Min/Max; or Abs v3, v1 with the second load optional)
+        // Store C, i, v3
+        // Add i, 1 (or Inc)
+        let (start, end) = (info.head, info.back_jump);
+        let mut reject = |reason: &str| {
+            report.decide(&label_name, Transform::Vectorize, false, reason.to_string());
+            false
+        };
+        if func.hints_for_loop(&label_name).no_vectorize {
+            return reject("#pragma novectorize");
+        }
+
+        // 2. Analyze Body
+        // We look for Load/Load/Add/Store with same index.
+        // We need to capture:
+        // - Index Reg
+        // - Base A, Base B, Base C
+        // - Destination Add Reg
+
+        let mut load_a = None;
+        let mut load_b = None;
+        let mut elem_op = None;
+        let mut abs_of = 0;
+        let mut store_op = None;
+        let mut inc_op = None;
+
+        // Scan specific instructions in the loop body
+        for idx in start..end {
+            if matches!(func.instructions[idx].op, Opcode::Load(_)) {
+                if load_a.is_none() {
+                    load_a = Some(idx);
+                } else if load_b.is_none() {
+                    load_b = Some(idx);
+                }
+            }
+        }
+        let Some(la) = load_a else {
+            return reject("no array load in the body");
+        };
+        // Both loads must walk the same index; anything else in the body
+        // (e.g. a 2D row pointer `Add row, base`) is copied through unchanged
+        let index = func.instructions[la].src2.clone();
+        if load_b.is_some_and(|lb| func.instructions[lb].src2 != index) {
+            return reject("the loads use different indices");
+        }
+        // New YMM regs, for each load and the result
+        let (y1, y2, y3) = (100, 101, 102);
+        // (loaded register, its vector register)
+        let mut loaded = vec![(&func.instructions[la].dest, y1)];
+        if let Some(lb) = load_b {
+            loaded.push((&func.instructions[lb].dest, y2));
+        }
+        let vector_of = |op: &Option<Operand>| loaded.iter().find(|(d, _)| *d == op).map(|(_, y)| *y);
+
+        for idx in start..end {
+            let instr = &func.instructions[idx];
+            match instr.op {
+                Opcode::Add if instr.dest == index && instr.src1 == Some(Operand::Imm(1)) => {
+                    // Add instructions are Dest += Src, so `Add i, 1` is the increment
+                    inc_op = Some(idx);
+                }
+                // Elementwise ops of both loads, `Mov v3, v1; Add v3, v2`
+                Opcode::Add | Opcode::Min | Opcode::Max if load_b.is_some() => {
+                    let prev = &func.instructions[idx - 1];
+                    let (Some(a), Some(b)) = (vector_of(&prev.src1), vector_of(&instr.src1)) else {
+                        continue;
+                    };
+                    if prev.op == Opcode::Mov && prev.dest == instr.dest && a != b {
+                        elem_op = Some(idx);
+                    }
+                }
+                Opcode::Abs => {
+                    if let Some(y) = vector_of(&instr.src1) {
+                        elem_op = Some(idx);
+                        abs_of = y;
+                    }
+                }
+                Opcode::Store(_) => {
+                    // Must store the computed value at the same index
+                    let value = elem_op.map(|a| &func.instructions[a].dest);
+                    if instr.src1 == index && value == Some(&instr.src2) {
+                        store_op = Some(idx);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        // 3. Verify Pattern validity
+        if let (Some(elem), Some(st), Some(inc)) = (elem_op, store_op, inc_op) {
+            // `a[i+1] = a[i] + ..` reads what the previous iteration stored;
+            // a vector iteration would load those lanes before storing them
+            let store = &func.instructions[st];
+            let carried = [Some(la), load_b].into_iter().flatten().any(|l| {
+                let load = &func.instructions[l];
+                load.src1 == store.dest && load.op.displacement() < store.op.displacement()
+            });
+            if carried {
+                return reject("a load reads an element an earlier iteration stored");
+            }
+            let reason = format!("elementwise {:?} at unit stride", func.instructions[elem].op);
+            // Check operands match
+            // Load A: dest=r1, base=A, index=i
+            // Load B: dest=r2, base=B, index=i
+            // Add: dest=r3, src1=r1, src2=r2
+            // Store: base=C, index=i, src=r3
+            // Inc: dest=i, src=1
+
+            // Assume we found it.
+            // println!(
+            //     "Optimizer: Vectorization Pattern Candidates Found in '{}'!",
+            //     func.name
+            // );
+
+            // 4. Transform!
+            // Strategy:
+            // Rewrite the loop into TWO loops:
+            // 1. Vector Loop (Steps of `lanes`)
+            // 2. Scalar Cleanup Loop (Steps of 1)
+
+            // The vector guard `i + lanes > n` replaces the loop's own test,
+            // so that must exit at `i == n` or `i >= n` with i stepping by 1
+            let induction = info.induction.as_ref();
+            let Some(induction) = induction.filter(|ind| Some(&ind.var) == index.as_ref()) else {
+                return reject("no compare of the index against a bound");
+            };
+            if induction.step_at != inc || !matches!(induction.exit_when, Cond::Eq | Cond::Ge) {
+                return reject("the loop does not exit when the index reaches the bound");
+            }
+            let lanes = width.lanes() as i32;
+            if let Some(trips) = induction.trip_count.filter(|&t| t < lanes as u64) {
+                return reject(&format!("runs {} times, fewer than the {} lanes", trips, lanes));
+            }
+            let body = induction.body_start..end;
+            if func.instructions[body.clone()]
+                .iter()
+                .any(|i| i.op == Opcode::Label || !i.jump_targets().is_empty())
+            {
+                return reject("the body branches");
+            }
+            // Vectorized values only exist in vector registers
+            let vectorized = [Some(la), load_b, Some(elem)];
+            let scalars: Vec<&Option<Operand>> =
+                vectorized.iter().flatten().map(|&i| &func.instructions[i].dest).collect();
+            let binary = func.instructions[elem].op != Opcode::Abs;
+            let used_elsewhere = body.clone().any(|i| {
+                let instr = &func.instructions[i];
+                // `Mov v3, v1` starts the elementwise op
+                let part_of_elem = i + 1 == elem && binary && instr.op == Opcode::Mov;
+                !vectorized.contains(&Some(i))
+                    && i != st
+                    && !part_of_elem
+                    && [&instr.dest, &instr.src1, &instr.src2].iter().any(|o| scalars.contains(o))
+            });
+            if used_elsewhere {
+                return reject("a vectorized value is used outside the elementwise op");
+            }
+            let limit = induction.bound.clone();
+
+            // Create New Instruction Stream
+            let mut new_instrs = Vec::new();
+
+            // Copy everything UP TO the loop start
+            for i in 0..start {
+                new_instrs.push(func.instructions[i].clone());
+            }
+
+            // --- VECTOR LOOP ---
+            let vec_loop_label = format!("{}_vec", label_name);
+            let scalar_loop_label = format!("{}_cleanup", label_name);
+
+            new_instrs.push(Instruction {
+                op: Opcode::Label,
+                dest: Some(Operand::Label(vec_loop_label.clone())),
+                src1: None,
+                src2: None,
+            });
+
+            // Vector Guard: if (i + lanes > limit) goto scalar_loop
+            // Temp = i
+            // Temp += lanes
+            // Cmp Temp, Limit
+            // Jg ScalarLoop
+
+            let idx_reg = match func.instructions[la].src2 {
+                Some(Operand::Reg(r)) => r,
+                _ => return reject("the index is not a register"),
+            };
+
+            let temp_reg = 200; // Reserved safe temp
+
+            // Mov temp, i
+            new_instrs.push(Instruction {
+                op: Opcode::Mov,
+                dest: Some(Operand::Reg(temp_reg)),
+                src1: Some(Operand::Reg(idx_reg)),
+                src2: None,
+            });
+            // Add temp, lanes
+            new_instrs.push(Instruction {
+                op: Opcode::Add,
+                dest: Some(Operand::Reg(temp_reg)),
+                src1: Some(Operand::Imm(lanes)),
+                src2: None,
+            });
+            // Cmp temp, limit
+            new_instrs.push(Instruction {
+                op: Opcode::Cmp,
+                dest: None,
+                src1: Some(Operand::Reg(temp_reg)),
+                src2: Some(limit),
+            });
+            // Jg scalar_loop
+            new_instrs.push(Instruction {
+                op: Opcode::Jg,
+                dest: Some(Operand::Label(scalar_loop_label.clone())),
+                src1: None,
+                src2: None,
+            });
+
+            // Loop Body (Vectorized)
+            // The body after the loop's own test, which the guard replaces:
+            // inside the vector loop i + lanes <= n, so it never exits there
+            for i in body {
+                let mut inst = func.instructions[i].clone();
+
+                // Transform OpCodes
+                let disp = inst.op.displacement().unwrap_or(0);
+                if i == la {
+                    inst.op = Opcode::VLoad(disp, Access::Unaligned);
+                    inst.dest = Some(Operand::Ymm(y1));
+                } else if Some(i) == load_b {
+                    inst.op = Opcode::VLoad(disp, Access::Unaligned);
+                    inst.dest = Some(Operand::Ymm(y2));
+                } else if i == elem {
+                    let (op, src1, src2) = match inst.op {
+                        Opcode::Abs => (Opcode::VAbs, abs_of, None),
+                        Opcode::Min => (Opcode::VMin, y1, Some(Operand::Ymm(y2))),
+                        Opcode::Max => (Opcode::VMax, y1, Some(Operand::Ymm(y2))),
+                        _ => (Opcode::VAdd, y1, Some(Operand::Ymm(y2))),
+                    };
+                    inst.op = op;
+                    inst.dest = Some(Operand::Ymm(y3));
+                    inst.src1 = Some(Operand::Ymm(src1));
+                    inst.src2 = src2;
+                } else if i == st {
+                    inst.op = Opcode::VStore(disp, Access::Unaligned);
+                    inst.src2 = Some(Operand::Ymm(y3));
+                } else if i == inc {
+                    inst.src1 = Some(Operand::Imm(lanes)); // Add i, lanes
+                }
+
+                new_instrs.push(inst);
+            }
+
+            // Loop Back
+            new_instrs.push(Instruction {
+                op: Opcode::Jmp,
+                dest: Some(Operand::Label(vec_loop_label)),
+                src1: None,
+                src2: None,
+            });
+
+            // --- SCALAR CLEANUP LOOP ---
+            new_instrs.push(Instruction {
+                op: Opcode::Label,
+                dest: Some(Operand::Label(scalar_loop_label)),
+                src1: None,
+                src2: None,
+            });
+
+            // Copy original body exactly as is (Start+1 .. End) + Jmp
+            // Or just reuse the Label?
+            // Original:
+            // Loop:
+            //   Check
+            //   Body
+            //   Jmp Loop
+
+            // We can just append the Start Label and the rest of the function!
+            // But we already consumed 0..start.
+            // So we just iterate `start..func.len()` and append.
+
+            for i in start..func.instructions.len() {
+                new_instrs.push(func.instructions[i].clone());
+            }
+
+            // Replace instructions
+            func.instructions = new_instrs;
+
+            // println!("Optimizer: VECTORIZED Loop with CLEANUP!");
+            report.decide(&label_name, Transform::Vectorize, true, reason);
+            return true;
+        }
+
+        reject(match (elem_op, store_op) {
+            (None, _) => "no elementwise add/min/max/abs of the loaded values",
+            (_, None) => "the result is not stored at the loop index",
+            _ => "the index does not step by 1",
+        })
+    }
+
+    /// If-conversion: replace forward branches around straight-line arithmetic
+    /// with branch-free selects. Handles both shapes the parser emits:
+    ///
+    /// `Cmp; Jcc body; Jmp end; Label body; ...; Label end` (block `if`) and
+    /// `Cmp; Jcc end; ...; Label end` (`if .. goto`).
+    ///
+    /// The body runs unconditionally into fresh registers, then the Cmp is
+    /// redone and each result is committed with a `CMov`. Only bodies of
+    /// Mov/Add/Sub/Mul (and already converted Cmp/CMov) qualify: no memory
+    /// accesses, calls or jumps. Innermost ifs go first, so nested ones
+    /// collapse too.
+    pub fn if_convert(func: &mut Function) -> bool {
+        Self::convert_ifs(func, |_| true)
+    }
+
+    /// Mark vector loads and stores whose address is provably a multiple of
+    /// the vector size as aligned (see `alignment`). In loops under
+    /// `#pragma nontemporal` such stores also bypass the caches
+    pub fn select_vector_access(func: &mut Function, width: VectorWidth) -> bool {
+        let facts = alignment::analyze(func);
+        let mut changed = false;
+        let mut nontemporal = false;
+        for (idx, before) in facts.iter().enumerate() {
+            let instr = &func.instructions[idx];
+            if let (Opcode::Label, Some(name)) = (&instr.op, target_of(instr)) {
+                // A vectorized loop keeps the hints of the loop it came from
+                nontemporal = func
+                    .hints_for_loop(name.trim_end_matches("_vec"))
+                    .nontemporal;
+                continue;
+            }
+            let (Opcode::VLoad(_, Access::Unaligned) | Opcode::VStore(_, Access::Unaligned)) =
+                instr.op
+            else {
+                continue;
+            };
+            let Some(before) = before else {
+                continue;
+            };
+            if alignment::access_alignment(before, instr) < width.bytes() as u32 {
+                continue;
+            }
+            let access = match instr.op {
+                Opcode::VStore(..) if nontemporal => Access::NonTemporal,
+                _ => Access::Aligned,
+            };
+            let disp = instr.op.displacement().unwrap_or(0);
+            let op = match instr.op {
+                Opcode::VLoad(..) => Opcode::VLoad(disp, access),
+                _ => Opcode::VStore(disp, access),
+            };
+            func.instructions[idx].op = op;
+            changed = true;
+        }
+        changed
+    }
+
+    /// Prefetch `distance` bytes ahead of every array a vectorized loop
+    /// (`{label}_vec`) loads from, once per array, ahead of its first VLoad.
+    /// Idempotent; true if any prefetch was inserted
+    pub fn insert_prefetches(func: &mut Function, distance: i32) -> bool {
+        let mut changed = false;
+        let mut i = 0;
+        while i < func.instructions.len() {
+            let instr = &func.instructions[i];
+            let Some(name) = target_of(instr).filter(|n| instr.op == Opcode::Label && n.ends_with("_vec"))
+            else {
+                i += 1;
+                continue;
+            };
+            let target = Some(Operand::Label(name.to_string()));
+            let Some(back) = (i + 1..func.instructions.len())
+                .find(|&j| func.instructions[j].op == Opcode::Jmp && func.instructions[j].dest == target)
+            else {
+                i += 1;
+                continue;
+            };
+
+            let mut fetched: Vec<Option<Operand>> = func.instructions[i..back]
+                .iter()
+                .filter(|instr| instr.op == Opcode::PrefetchT0)
+                .map(|instr| instr.dest.clone())
+                .collect();
+            let mut j = i + 1;
+            let mut end = back;
+            while j < end {
+                let instr = &func.instructions[j];
+                if matches!(instr.op, Opcode::VLoad(..)) && !fetched.contains(&instr.src1) {
+                    fetched.push(instr.src1.clone());
+                    let prefetch = Instruction {
+                        op: Opcode::PrefetchT0,
+                        dest: instr.src1.clone(),
+                        src1: instr.src2.clone(),
+                        src2: Some(Operand::Imm(distance)),
+                    };
+                    func.instructions.insert(j, prefetch);
+                    changed = true;
+                    j += 1;
+                    end += 1;
+                }
+                j += 1;
+            }
+            i = end + 1;
+        }
+        changed
+    }
+
+    /// Select lowering: if-convert only single assignments (`if .. { x = a + b }`),
+    /// where cmp+cmov beats a jump diamond that mispredicts on random data
+    pub fn select_lowering(func: &mut Function) -> bool {
+        Self::convert_ifs(func, |body| {
+            let mut dests = body.iter().map(|i| &i.dest);
+            let first = dests.next();
+            body.len() <= 3
+                && body
+                    .iter()
+                    .all(|i| matches!(i.op, Opcode::Mov | Opcode::Add | Opcode::Sub | Opcode::Mul))
+                && first.is_some_and(|d| dests.all(|o| o == d))
+        })
+    }
+
+    fn convert_ifs(func: &mut Function, accept: impl Fn(&[Instruction]) -> bool) -> bool {
+        let mut changed = false;
+        while let Some((start, end, cond, body)) = Self::find_diamond(func, &accept) {
+            let cmp = func.instructions[start].clone();
+            let select = Self::select(func, cmp, cond, body);
+            func.instructions.splice(start..=end, select);
+            changed = true;
+        }
+        changed
+    }
+
+    /// First convertible if: (Cmp index, end Label index, condition under
+    /// which the body runs, body range)
+    fn find_diamond(
+        func: &Function,
+        accept: &impl Fn(&[Instruction]) -> bool,
+    ) -> Option<(usize, usize, Cond, std::ops::Range<usize>)> {
+        let instrs = &func.instructions;
+        let label_at = |idx: usize| match instrs.get(idx) {
+            Some(Instruction {
+                op: Opcode::Label,
+                dest: Some(Operand::Label(name)),
+                ..
+            }) => Some(name.as_str()),
+            _ => None,
+        };
+        let target = |idx: usize| target_of(instrs.get(idx)?);
+        let jumps_to = |name: &str| {
+            instrs
+                .iter()
+                .flat_map(Instruction::jump_targets)
+                .filter(|&target| target == name)
+                .count()
+        };
+        let find_label =
+            |name: &str, from: usize| (from..instrs.len()).find(|&j| label_at(j) == Some(name));
+
+        for start in 0..instrs.len().saturating_sub(2) {
+            if instrs[start].op != Opcode::Cmp {
+                continue;
+            }
+            let Some(cond) = Cond::of_jump(&instrs[start + 1].op) else {
+                continue;
+            };
+            let Some(taken) = target(start + 1) else {
+                continue;
+            };
+
+            let block_if =
+                instrs[start + 2].op == Opcode::Jmp && label_at(start + 3) == Some(taken);
+            let (cond, body_start, end_label) = if block_if {
+                (cond, start + 4, target(start + 2))
+            } else {
+                (cond.negate(), start + 2, Some(taken))
+            };
+            let Some(end) = end_label.and_then(|name| find_label(name, body_start)) else {
+                continue;
+            };
+            let end_label = label_at(end)?;
+            let single_use = jumps_to(taken) == 1 && jumps_to(end_label) == 1;
+            let straight_line = instrs[body_start..end].iter().all(|i| {
+                let operands_ok = [&i.dest, &i.src1, &i.src2]
+                    .iter()
+                    .all(|o| matches!(o, None | Some(Operand::Reg(_)) | Some(Operand::Imm(_))));
+                let writes_reg = matches!(i.dest, Some(Operand::Reg(_)));
+                operands_ok
+                    && match i.op {
+                        Opcode::Mov | Opcode::Add | Opcode::Sub | Opcode::Mul | Opcode::CMov(_) => {
+                            writes_reg
+                        }
+                        Opcode::Cmp => true,
+                        _ => false,
+                    }
+            });
+            if single_use && straight_line && accept(&instrs[body_start..end]) {
+                return Some((start, end, cond, body_start..end));
+            }
+        }
+        None
+    }
+
+    /// `cmp` + `body` (run when `cond` holds) as straight-line code ending in CMovs
+    fn select(
+        func: &Function,
+        cmp: Instruction,
+        cond: Cond,
+        body: std::ops::Range<usize>,
+    ) -> Vec<Instruction> {
+        let used: std::collections::HashSet<u8> = func
+            .instructions
+            .iter()
+            .flat_map(|i| [&i.dest, &i.src1, &i.src2])
+            .filter_map(|o| match o {
+                Some(Operand::Reg(r)) => Some(*r),
+                _ => None,
+            })
+            .collect();
+        let mut fresh = (10..=u8::MAX).filter(|r| !used.contains(r));
+
+        let body = &func.instructions[body];
+        // Each register the body writes, with its stand-in
+        let mut renamed: Vec<(u8, u8)> = Vec::new();
+        for instr in body {
+            if let Some(Operand::Reg(d)) = instr.dest {
+                if !renamed.iter().any(|&(r, _)| r == d) {
+                    let temp = fresh.next().expect("if-conversion ran out of registers");
+                    renamed.push((d, temp));
+                }
+            }
+        }
+        let rename = |o: &Option<Operand>| match o {
+            Some(Operand::Reg(r)) => Some(Operand::Reg(
+                renamed
+                    .iter()
+                    .find(|&&(d, _)| d == *r)
+                    .map_or(*r, |&(_, t)| t),
+            )),
+            other => other.clone(),
+        };
+
+        let mut out = Vec::new();
+        for &(d, temp) in &renamed {
+            // Not needed when the body's first touch of `d` overwrites it
+            let first = body.iter().find(|i| {
+                [&i.dest, &i.src1, &i.src2]
+                    .iter()
+                    .any(|o| **o == Some(Operand::Reg(d)))
+            });
+            let overwritten =
+                first.is_some_and(|i| i.op == Opcode::Mov && i.src1 != Some(Operand::Reg(d)));
+            if !overwritten {
+                out.push(Instruction {
+                    op: Opcode::Mov,
+                    dest: Some(Operand::Reg(temp)),
+                    src1: Some(Operand::Reg(d)),
+                    src2: None,
+                });
+            }
+        }
+        out.extend(body.iter().map(|i| Instruction {
+            op: i.op.clone(),
+            dest: rename(&i.dest),
+            src1: rename(&i.src1),
+            src2: rename(&i.src2),
+        }));
+        out.push(cmp);
+        out.extend(renamed.iter().map(|&(d, temp)| Instruction {
+            op: Opcode::CMov(cond),
+            dest: Some(Operand::Reg(d)),
+            src1: Some(Operand::Reg(temp)),
+            src2: None,
+        }));
+        out
+    }
+}
+
+fn target_of(instr: &Instruction) -> Option<&str> {
+    match &instr.dest {
+        Some(Operand::Label(name)) => Some(name),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    fn optimized_main(src: &str, level: u8) -> Function {
+        let mut prog = Parser::new().parse(src).unwrap();
+        Optimizer::optimize_program(&mut prog, level);
+        prog.functions.into_iter().find(|f| f.name == "main").unwrap()
+    }
+
+    fn count(func: &Function, op: Opcode) -> usize {
+        func.instructions.iter().filter(|i| i.op == op).count()
+    }
+
+    #[test]
+    fn test_dead_store_elimination_removes_unread_array() {
+        let src = "fn main() {
+            a = alloc(80)
+            b = alloc(80)
+            a[0] = 1
+            a[1] = 2
+            b[0] = 7
+            x = b[0]
+            free(a)
+            free(b)
+            return x
+        }";
+        let func = optimized_main(src, 1);
+        // `a` is gone entirely, `b` is read and survives
+        assert_eq!(count(&func, Opcode::Alloc), 1);
+        assert_eq!(count(&func, Opcode::Store(0)), 1);
+        assert_eq!(count(&func, Opcode::Free), 1);
+
+        // O0 leaves the program untouched
+        let func = optimized_main(src, 0);
+        assert_eq!(count(&func, Opcode::Alloc), 2);
+    }
+
+    #[test]
+    fn test_dead_store_elimination_keeps_escaping_pointers() {
+        // Returned or passed to a call: the callee may read it
+        let returned = optimized_main(
+            "fn main() {
+                a = alloc(8)
+                a[0] = 1
+                return a
+            }",
+            2,
+        );
+        assert_eq!(count(&returned, Opcode::Store(0)), 1);
+
+        let passed = optimized_main(
+            "fn peek(p) {
+                v = p[0]
+                return v
+            }
+            fn main() {
+                a = alloc(8)
+                a[0] = 1
+                r = peek(a)
+                return r
+            }",
+            2,
+        );
+        assert_eq!(count(&passed, Opcode::Store(0)), 1);
+    }
+
+    const COUNTDOWN: &str = "fn main(n) {
+        sum = 0
+        loop:
+        if n == 0 goto done
+        sum = sum + n
+        n = n - 1
+        goto loop
+        done:
+        return sum
+    }";
+
+    #[test]
+    fn test_unroll_pragma_pins_factor() {
+        // Default O2 heuristic keeps doubling the body
+        let default = count(&optimized_main(COUNTDOWN, 2), Opcode::Cmp);
+        assert!(default > 4, "{}", default);
+
+        let pinned = COUNTDOWN.replace("loop:", "#pragma unroll(4)\nloop:");
+        assert_eq!(count(&optimized_main(&pinned, 2), Opcode::Cmp), 4);
+
+        let off = COUNTDOWN.replace("fn main", "#pragma unroll(1)\nfn main");
+        assert_eq!(count(&optimized_main(&off, 2), Opcode::Cmp), 1);
+    }
+
+    #[test]
+    fn test_bit_intrinsics_of_constants_fold() {
+        let func = optimized_main(
+            "fn main() {
+                a = popcnt(255)
+                b = ctz(0)
+                c = clz(1)
+                d = ctz(40)
+                r = a + b
+                r = r + c
+                r = r + d
+                return r
+            }",
+            0,
+        );
+        assert_eq!(count(&func, Opcode::Popcnt) + count(&func, Opcode::Ctz) + count(&func, Opcode::Clz), 0);
+        let folded: Vec<String> = func.instructions.iter().take(4).map(|i| i.to_string()).collect();
+        assert_eq!(
+            folded,
+            ["Mov Reg(10), Imm(8)", "Mov Reg(11), Imm(64)", "Mov Reg(12), Imm(63)", "Mov Reg(13), Imm(3)"]
+        );
+    }
+
+    #[test]
+    fn test_novectorize_pragma() {
+        let src = "fn main() {
+            n = 16
+            a = alloc(128)
+            b = alloc(128)
+            c = alloc(128)
+            i = 0
+            label loop
+            if i == n goto end
+            v = a[i]
+            w = b[i]
+            s = v + w
+            c[i] = s
+            i = i + 1
+            goto loop
+            label end
+            r = c[10]
+            return r
+        }";
+        let vectorized = optimized_main(src, 3);
+        assert!(count(&vectorized, Opcode::VAdd) > 0);
+
+        let src = src.replace("fn main", "#pragma novectorize\nfn main");
+        assert_eq!(count(&optimized_main(&src, 3), Opcode::VAdd), 0);
+    }
+
+    #[test]
+    fn test_loop_passes_use_trip_counts() {
+        let src = |n: i32| {
+            format!(
+                "fn main() {{
+                    n = {}
+                    a = alloc(64)
+                    b = alloc(64)
+                    i = 0
+                    label walk
+                    if i >= n goto done
+                    v = a[i]
+                    w = abs(v)
+                    b[i] = w
+                    i = i + 1
+                    goto walk
+                    label done
+                    r = b[0]
+                    return r
+                }}",
+                n
+            )
+        };
+        // Any counted loop vectorizes, whatever its label
+        assert!(count(&optimized_main(&src(8), 3), Opcode::VAbs) > 0);
+        // Too short to fill a vector, or to run an unrolled copy
+        assert_eq!(count(&optimized_main(&src(1), 3), Opcode::VAbs), 0);
+        assert_eq!(count(&optimized_main(&src(1), 2), Opcode::Abs), 1);
+        assert!(count(&optimized_main(&src(2), 2), Opcode::Abs) > 1);
+    }
+
+    #[test]
+    fn test_select_lowering_single_assignments_only() {
+        let mut func = Parser::new()
+            .parse(
+                "fn main(a, b) {
+                    m = a
+                    if b > a {
+                        m = b
+                    }
+                    if m >= 0 goto positive
+                    m = m * 2
+                    positive:
+                    if a == b {
+                        m = 0
+                        a = 1
+                    }
+                    return m
+                }",
+            )
+            .unwrap()
+            .functions
+            .remove(0);
+        assert!(Optimizer::select_lowering(&mut func));
+        let cmovs: Vec<_> = func
+            .instructions
+            .iter()
+            .filter_map(|i| match i.op {
+                Opcode::CMov(cond) => Some(cond),
+                _ => None,
+            })
+            .collect();
+        // Block if keeps its condition, `goto` form inverts it
+        assert_eq!(cmovs, [Cond::Gt, Cond::Lt]);
+        // Two assignments: still a branch
+        assert_eq!(count(&func, Opcode::Je), 1);
+        assert!(!Optimizer::select_lowering(&mut func));
+    }
+}
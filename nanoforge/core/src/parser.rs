@@ -0,0 +1,1016 @@
+use crate::ast::{
+    self, Assign, BinOp, Block, CmpOp, Condition, Expr, Index, Span, Spanned, Stmt, UnaryOp, Value,
+};
+use crate::ir::{Hints, Program};
+use crate::lowering;
+
+const PRAGMA: &str = "#pragma";
+
+#[derive(Debug, Clone)]
+pub struct Token {
+    pub content: String,
+    pub line: usize,
+    pub col: usize,
+}
+
+impl Token {
+    fn span(&self) -> Span {
+        Span {
+            line: self.line,
+            col: self.col,
+        }
+    }
+}
+
+/// Builds an `ast::Program` from source; `parse` also lowers it to IR
+pub struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    /// `#pragma` hints waiting for the next function, loop or label
+    pending_hints: Option<(Hints, Token)>,
+}
+
+impl Parser {
+    pub fn new() -> Self {
+        Self {
+            tokens: Vec::new(),
+            pos: 0,
+            pending_hints: None,
+        }
+    }
+
+    fn tokenize(source: &str) -> Vec<Token> {
+        let mut tokens = Vec::new();
+        let mut current = String::new();
+        let chars: Vec<char> = source.chars().collect();
+        let mut i = 0;
+        let mut line = 1;
+        let mut col = 1;
+
+        while i < chars.len() {
+            let c = chars[i];
+
+            if c == '#'
+                && chars[i..].iter().copied().take(PRAGMA.len()).eq(PRAGMA.chars())
+                && chars.get(i + PRAGMA.len()).is_none_or(|c| c.is_whitespace())
+            {
+                // Pragma: the rest of the line is one token
+                if !current.is_empty() {
+                    tokens.push(Token {
+                        content: current.clone(),
+                        line,
+                        col: col - current.len(),
+                    });
+                    current.clear();
+                }
+                let start = i;
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+                let content: String = chars[start..i].iter().collect();
+                tokens.push(Token {
+                    content: content.trim_end().to_string(),
+                    line,
+                    col,
+                });
+                col += i - start;
+                continue;
+            }
+
+            if c == '#' {
+                // Comment: skip until newline
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+                // Newline consumption handled by loop or next iteration
+                continue;
+            }
+
+            if c == '\n' {
+                if !current.is_empty() {
+                    tokens.push(Token {
+                        content: current.clone(),
+                        line,
+                        col: col - current.len(),
+                    });
+                    current.clear();
+                }
+                line += 1;
+                col = 1;
+                i += 1;
+                continue;
+            }
+
+            if c.is_whitespace() {
+                if !current.is_empty() {
+                    tokens.push(Token {
+                        content: current.clone(),
+                        line,
+                        col: col - current.len(),
+                    });
+                    current.clear();
+                }
+                i += 1;
+                col += 1;
+            } else if "(){},=+-[]:;<>!".contains(c) {
+                if !current.is_empty() {
+                    tokens.push(Token {
+                        content: current.clone(),
+                        line,
+                        col: col - current.len(),
+                    });
+                    current.clear();
+                }
+                // Check for ==, !=, <=, >= and the => of a switch arm
+                if i + 1 < chars.len() {
+                    let next = chars[i + 1];
+                    if ((c == '=' || c == '!' || c == '<' || c == '>') && next == '=') || (c == '=' && next == '>') {
+                        tokens.push(Token {
+                            content: format!("{}{}", c, next),
+                            line,
+                            col,
+                        });
+                        i += 2;
+                        col += 2;
+                        continue;
+                    }
+                }
+                tokens.push(Token {
+                    content: c.to_string(),
+                    line,
+                    col,
+                });
+                i += 1;
+                col += 1;
+            } else {
+                current.push(c);
+                i += 1;
+                col += 1;
+            }
+        }
+        if !current.is_empty() {
+            tokens.push(Token {
+                content: current,
+                line,
+                col, // approx
+            });
+        }
+        tokens
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn peek_is(&self, content: &str) -> bool {
+        self.peek().is_some_and(|t| t.content == content)
+    }
+
+    fn consume(&mut self) -> Option<Token> {
+        if self.pos < self.tokens.len() {
+            let t = self.tokens[self.pos].clone();
+            self.pos += 1;
+            Some(t)
+        } else {
+            None
+        }
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), String> {
+        let t = self.consume().ok_or("Unexpected end of input")?;
+        if t.content == expected {
+            Ok(())
+        } else {
+            Err(format!(
+                "Expected '{}', found '{}' at line {}:{}",
+                expected, t.content, t.line, t.col
+            ))
+        }
+    }
+
+    fn value(&mut self, what: &str) -> Result<Value, String> {
+        let token = self.consume().ok_or_else(|| format!("Expected {}", what))?;
+        Ok(Value::from_token(&token.content))
+    }
+
+    /// Parse `#pragma unroll(8) novectorize align(64) nontemporal opt(3)` into the pending hints
+    fn parse_pragma(&mut self, token: &Token) -> Result<(), String> {
+        let (mut hints, at) = self
+            .pending_hints
+            .take()
+            .unwrap_or_else(|| (Hints::default(), token.clone()));
+        let directives: Vec<&str> = token.content[PRAGMA.len()..].split_whitespace().collect();
+        if directives.is_empty() {
+            return Err(format!("Empty #pragma at line {}:{}", token.line, token.col));
+        }
+        for d in directives {
+            let err = |msg: &str| format!("{} in '#pragma {}' at line {}:{}", msg, d, token.line, token.col);
+            let arg = |name: &str| {
+                d.strip_prefix(name)
+                    .and_then(|r| r.strip_prefix('('))
+                    .and_then(|r| r.strip_suffix(')'))
+                    .map(|n| n.parse::<u32>())
+            };
+            if d == "novectorize" {
+                hints.no_vectorize = true;
+            } else if d == "nontemporal" {
+                hints.nontemporal = true;
+            } else if let Some(n) = arg("unroll") {
+                match n {
+                    Ok(n @ 1..=64) => hints.unroll = Some(n as u8),
+                    _ => return Err(err("Unroll factor must be 1-64")),
+                }
+            } else if let Some(n) = arg("opt") {
+                match n {
+                    Ok(n @ 0..=3) => hints.opt_level = Some(n as u8),
+                    _ => return Err(err("Opt level must be 0-3")),
+                }
+            } else if let Some(n) = arg("align") {
+                match n {
+                    Ok(n) if n.is_power_of_two() && n <= 4096 => hints.align = Some(n),
+                    _ => return Err(err("Alignment must be a power of two up to 4096")),
+                }
+            } else {
+                return Err(err("Unknown pragma"));
+            }
+        }
+        self.pending_hints = Some((hints, at));
+        Ok(())
+    }
+
+    /// Pragmas must be followed by what they annotate
+    fn no_dangling_pragma(&self) -> Result<(), String> {
+        match &self.pending_hints {
+            Some((_, at)) => Err(format!(
+                "#pragma at line {}:{} must precede a function, loop or label",
+                at.line, at.col
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Pending pragma hints, for the loop or label being parsed
+    fn take_hints(&mut self) -> Result<Option<Hints>, String> {
+        match self.pending_hints.take() {
+            Some((hints, at)) if hints.opt_level.is_some() => Err(format!(
+                "#pragma opt at line {}:{} applies to functions, not loops or labels",
+                at.line, at.col
+            )),
+            pending => Ok(pending.map(|(hints, _)| hints)),
+        }
+    }
+
+    /// Parse and lower `source`
+    pub fn parse(&mut self, source: &str) -> Result<Program, String> {
+        let ast = self.parse_ast(source)?;
+        lowering::lower(&ast)
+    }
+
+    /// Parse `source` without lowering it
+    pub fn parse_ast(&mut self, source: &str) -> Result<ast::Program, String> {
+        self.tokens = Self::tokenize(source);
+        self.pos = 0;
+        self.pending_hints = None;
+        let mut program = ast::Program::default();
+
+        while let Some(t) = self.peek() {
+            if t.content == "fn" {
+                let func = self.parse_function()?;
+                program.functions.push(func);
+            } else if t.content.starts_with(PRAGMA) {
+                let t = self.consume().unwrap();
+                self.parse_pragma(&t)?;
+            } else {
+                return Err(format!(
+                    "Unexpected token '{}' at line {}:{}. Top-level code is not allowed. Wrap in 'fn main() {{ ... }}'.",
+                    t.content, t.line, t.col
+                ));
+            }
+        }
+
+        self.no_dangling_pragma()?;
+
+        // Check for entry point
+        if !program.functions.iter().any(|f| f.name == "main") {
+            return Err("Missing entry point: fn main() not found".to_string());
+        }
+
+        Ok(program)
+    }
+
+    fn parse_function(&mut self) -> Result<ast::Function, String> {
+        self.expect("fn")?;
+        let name = self.consume().ok_or("Expected function name")?;
+        let at = name.span();
+        self.expect("(")?;
+
+        let mut args = Vec::new();
+        while let Some(t) = self.peek() {
+            if t.content == ")" {
+                break;
+            }
+            if t.content == "," {
+                self.consume();
+                continue;
+            }
+            let arg_token = self.consume().unwrap();
+            args.push(arg_token.content);
+        }
+        self.consume(); // )
+
+        let hints = self.pending_hints.take().map(|(hints, _)| hints).unwrap_or_default();
+        let body = self.parse_block()?;
+        Ok(ast::Function {
+            name: name.content,
+            args,
+            hints,
+            body,
+            at,
+        })
+    }
+
+    fn parse_block(&mut self) -> Result<Block, String> {
+        self.expect("{")?;
+        self.parse_block_rest()
+    }
+
+    /// Statements up to and including the `}` closing a block
+    fn parse_block_rest(&mut self) -> Result<Block, String> {
+        let mut body = Vec::new();
+        while let Some(t) = self.peek() {
+            if t.content == "}" {
+                self.no_dangling_pragma()?;
+                self.consume();
+                return Ok(body);
+            }
+            if t.content.starts_with(PRAGMA) {
+                let t = self.consume().unwrap();
+                self.parse_pragma(&t)?;
+                continue;
+            }
+            let at = t.span();
+            let node = self.parse_statement()?;
+            body.push(Spanned { node, at });
+        }
+        Err("Expected '}'".to_string())
+    }
+
+    /// Parse the rest of an index after `[`: `i]` or `i, j]`, the last
+    /// index optionally `+ k` or `- k`
+    fn parse_index(&mut self, array: String) -> Result<Index, String> {
+        let index_token = self.consume().ok_or("Expected index")?;
+        let mut offset = self.index_offset()?;
+        let mut col = None;
+        if self.peek_is(",") {
+            if offset != 0 {
+                return Err(format!(
+                    "Only the column of a 2D index can have an offset at line {}:{}",
+                    index_token.line, index_token.col
+                ));
+            }
+            self.consume(); // ,
+            col = Some(self.value("column index")?);
+            offset = self.index_offset()?;
+        }
+        self.expect("]")?;
+        Ok(Index {
+            array,
+            index: Value::from_token(&index_token.content),
+            col,
+            offset,
+            at: index_token.span(),
+        })
+    }
+
+    /// `+ k` or `- k` after an index, or 0; the byte displacement
+    /// `k * 8` must fit in 32 bits
+    fn index_offset(&mut self) -> Result<i32, String> {
+        let negative = match self.peek() {
+            Some(t) if t.content == "+" => false,
+            Some(t) if t.content == "-" => true,
+            _ => return Ok(0),
+        };
+        self.consume(); // + or -
+        let t = self.consume().ok_or("Expected index offset")?;
+        let offset = t
+            .content
+            .parse::<i32>()
+            .ok()
+            .map(|k| if negative { -k } else { k })
+            .filter(|k| k.checked_mul(8).is_some());
+        offset.ok_or_else(|| {
+            format!(
+                "Index offset must be a constant below 2^28 at line {}:{}",
+                t.line, t.col
+            )
+        })
+    }
+
+    /// `lhs op rhs`
+    fn parse_condition(&mut self) -> Result<Condition, String> {
+        let lhs = self.value("condition lhs")?;
+        let op_token = self.consume().ok_or("Expected condition op")?;
+        let rhs = self.value("condition rhs")?;
+        let op = match op_token.content.as_str() {
+            "==" => CmpOp::Eq,
+            "!=" => CmpOp::Ne,
+            "<" => CmpOp::Lt,
+            "<=" => CmpOp::Le,
+            ">" => CmpOp::Gt,
+            ">=" => CmpOp::Ge,
+            _ => {
+                return Err(format!(
+                    "Unknown op {} at line {}:{}",
+                    op_token.content, op_token.line, op_token.col
+                ))
+            }
+        };
+        Ok(Condition { lhs, op, rhs })
+    }
+
+    fn binary_op(token: &Token) -> Result<BinOp, String> {
+        match token.content.as_str() {
+            "+" => Ok(BinOp::Add),
+            "-" => Ok(BinOp::Sub),
+            "*" => Ok(BinOp::Mul),
+            _ => Err(format!(
+                "Only +, -, and * supported at line {}:{}",
+                token.line, token.col
+            )),
+        }
+    }
+
+    /// Right-hand side of an assignment
+    fn parse_expression(&mut self) -> Result<Expr, String> {
+        let token1 = self.consume().ok_or("Expected RHS")?;
+
+        // Array Load: `y = x[i]` or `y = x[i, j]`
+        if self.peek_is("[") {
+            self.consume(); // [
+            return Ok(Expr::Load(self.parse_index(token1.content)?));
+        }
+
+        // Function Call: `y = func(...)`
+        if self.peek_is("(") {
+            self.consume(); // (
+            return self.parse_call(token1);
+        }
+
+        // Binary Op: `y = a + b`, on one line (a `-` starting the next is
+        // a negative switch case)
+        if self.peek().is_some_and(|next| next.line == token1.line && "+-*/".contains(&next.content)) {
+            let op_token = self.consume().unwrap();
+            let op = Self::binary_op(&op_token)?;
+            let b = self.value("operand 2")?;
+            return Ok(Expr::Binary(op, Value::from_token(&token1.content), b));
+        }
+
+        // Simple Assign: `y = x`
+        Ok(Expr::Value(Value::from_token(&token1.content)))
+    }
+
+    /// Rest of `name(...)` after the `(`: intrinsics, allocation or a call
+    fn parse_call(&mut self, name: Token) -> Result<Expr, String> {
+        // `alloc2d(rows, cols)`: rows * cols elements, row stride cols
+        if name.content == "alloc2d" {
+            let rows_token = self.consume().ok_or("Expected row count")?;
+            self.expect(",")?;
+            let cols = self.value("column count")?;
+            self.expect(")")?;
+            return Ok(Expr::Alloc2d {
+                rows: Value::from_token(&rows_token.content),
+                cols,
+                at: rows_token.span(),
+            });
+        }
+
+        // `min(a, b)`, `max(a, b)`
+        let select = match name.content.as_str() {
+            "min" => Some(BinOp::Min),
+            "max" => Some(BinOp::Max),
+            _ => None,
+        };
+        if let Some(op) = select {
+            let arity_error = || {
+                format!(
+                    "{}() takes two arguments at line {}:{}",
+                    name.content, name.line, name.col
+                )
+            };
+            let a = self.value("argument")?;
+            if !self.peek_is(",") {
+                return Err(arity_error());
+            }
+            self.consume();
+            let b = self.value("argument")?;
+            if self.peek().is_some_and(|t| t.content != ")") {
+                return Err(arity_error());
+            }
+            self.expect(")")?;
+            return Ok(Expr::Binary(op, a, b));
+        }
+
+        // Unary intrinsics: `popcnt(x)`, `ctz(x)`, `clz(x)`, `abs(x)`
+        let intrinsic = match name.content.as_str() {
+            "popcnt" => Some(UnaryOp::Popcnt),
+            "ctz" => Some(UnaryOp::Ctz),
+            "clz" => Some(UnaryOp::Clz),
+            "abs" => Some(UnaryOp::Abs),
+            _ => None,
+        };
+        if let Some(op) = intrinsic {
+            let arg = self.value("argument")?;
+            if self.peek().is_some_and(|t| t.content != ")") {
+                return Err(format!(
+                    "{}() takes one argument at line {}:{}",
+                    name.content, name.line, name.col
+                ));
+            }
+            self.expect(")")?;
+            return Ok(Expr::Unary(op, arg));
+        }
+
+        if name.content == "alloc" {
+            let size = self.value("size")?;
+            self.expect(")")?;
+            return Ok(Expr::Alloc { size, align: None });
+        }
+
+        // `alloc_aligned(bytes, align)`: align is a constant power of two
+        if name.content == "alloc_aligned" {
+            let size = self.value("size")?;
+            self.expect(",")?;
+            let align_token = self.consume().ok_or("Expected alignment")?;
+            let align = align_token
+                .content
+                .parse::<u32>()
+                .ok()
+                .filter(|a| a.is_power_of_two() && crate::alignment::ALIGNMENTS.contains(a))
+                .ok_or_else(|| {
+                    format!(
+                        "alloc_aligned() alignment must be a power of two from {} to {} at line {}:{}",
+                        crate::alignment::ALIGNMENTS.start(),
+                        crate::alignment::ALIGNMENTS.end(),
+                        align_token.line,
+                        align_token.col
+                    )
+                })?;
+            self.expect(")")?;
+            return Ok(Expr::Alloc {
+                size,
+                align: Some(align),
+            });
+        }
+
+        let mut args = Vec::new();
+        while let Some(t) = self.peek() {
+            if t.content == ")" {
+                break;
+            }
+            if t.content == "," {
+                self.consume();
+                continue;
+            }
+            let arg_tok = self.consume().unwrap();
+            args.push(Value::from_token(&arg_tok.content));
+        }
+        self.expect(")")?;
+        Ok(Expr::Call {
+            name: name.content,
+            args,
+        })
+    }
+
+    /// `(init; cond; step)` and the body of a `for`, after the keyword
+    fn parse_for(&mut self, hints: Option<Hints>) -> Result<Stmt, String> {
+        self.expect("(")?;
+        let init_var = self.consume().ok_or("Expected init var")?;
+        self.expect("=")?;
+        let init = Box::new(Assign {
+            dest: init_var.content,
+            value: self.parse_expression()?,
+        });
+        self.expect(";")?;
+        let cond = self.parse_condition()?;
+        self.expect(";")?;
+
+        // Step: `i = x` or `i = a op b`
+        let mut step_tokens = Vec::new();
+        while let Some(t) = self.peek() {
+            if t.content == ")" {
+                break;
+            }
+            step_tokens.push(self.consume().unwrap());
+        }
+        self.expect(")")?;
+        let step = match step_tokens.as_slice() {
+            [] => None,
+            [_, eq, ..] if eq.content != "=" => return Err("Expected = in step".to_string()),
+            [dest, _, src] => Some(Box::new(Assign {
+                dest: dest.content.clone(),
+                value: Expr::Value(Value::from_token(&src.content)),
+            })),
+            [dest, _, a, op, b] => {
+                let op = Self::binary_op(op).map_err(|_| "Only +, -, * in loop step".to_string())?;
+                Some(Box::new(Assign {
+                    dest: dest.content.clone(),
+                    value: Expr::Binary(op, Value::from_token(&a.content), Value::from_token(&b.content)),
+                }))
+            }
+            [_] => return Err("Expected = in step".to_string()),
+            _ => return Err("Complex step not supported yet".to_string()),
+        };
+
+        let body = self.parse_block()?;
+        Ok(Stmt::For {
+            init,
+            cond,
+            step,
+            body,
+            hints,
+        })
+    }
+
+    /// `value { N => arm ... _ => arm }` after `switch`; an arm is a block
+    /// or a single statement, and cases are integer constants
+    fn parse_switch(&mut self) -> Result<Stmt, String> {
+        let value = self.value("switch value")?;
+        self.expect("{")?;
+        let mut cases: Vec<(i32, Block)> = Vec::new();
+        let mut default = None;
+        loop {
+            let t = self.consume().ok_or("Expected '}'")?;
+            if t.content == "}" {
+                break;
+            }
+            let case = match t.content.as_str() {
+                "_" => None,
+                "-" => {
+                    let n = self.consume().ok_or("Expected case value")?;
+                    Some(format!("-{}", n.content))
+                }
+                n => Some(n.to_string()),
+            };
+            let case = case
+                .map(|text| {
+                    text.parse::<i32>().map_err(|_| {
+                        format!(
+                            "Expected a constant case or '_', found '{}' at line {}:{}",
+                            text, t.line, t.col
+                        )
+                    })
+                })
+                .transpose()?;
+            self.expect("=>")?;
+            let body = if self.peek_is("{") {
+                self.parse_block()?
+            } else {
+                let at = self.peek().ok_or("Expected a switch arm")?.span();
+                vec![Spanned {
+                    node: self.parse_statement()?,
+                    at,
+                }]
+            };
+            if self.peek_is(",") {
+                self.consume();
+            }
+            match case {
+                None if default.is_some() => {
+                    return Err(format!("Second '_' arm at line {}:{}", t.line, t.col));
+                }
+                None => default = Some(body),
+                Some(n) if cases.iter().any(|(c, _)| *c == n) => {
+                    return Err(format!("Duplicate case {} at line {}:{}", n, t.line, t.col));
+                }
+                Some(n) => cases.push((n, body)),
+            }
+        }
+        Ok(Stmt::Switch {
+            value,
+            cases,
+            default,
+        })
+    }
+
+    fn parse_statement(&mut self) -> Result<Stmt, String> {
+        let t = self.consume().ok_or("Unexpected EOF")?;
+
+        let heads_loop = matches!(t.content.as_str(), "while" | "for" | "label") || self.peek_is(":");
+        if !heads_loop {
+            self.no_dangling_pragma()?;
+        }
+
+        match t.content.as_str() {
+            "return" => Ok(Stmt::Return(self.value("return value")?)),
+            "let" => {
+                let name = self.consume().ok_or("Expected variable name")?;
+                self.expect("=")?;
+                Ok(Stmt::Let(Assign {
+                    dest: name.content,
+                    value: self.parse_expression()?,
+                }))
+            }
+            "label" => {
+                let name = self.consume().ok_or("Expected label name")?;
+                Ok(Stmt::Label {
+                    name: name.content,
+                    hints: self.take_hints()?,
+                })
+            }
+            "goto" => {
+                let name = self.consume().ok_or("Expected goto label")?;
+                Ok(Stmt::Goto(name.content))
+            }
+            "while" => {
+                let hints = self.take_hints()?;
+                let cond = self.parse_condition()?;
+                let body = self.parse_block()?;
+                Ok(Stmt::While { cond, body, hints })
+            }
+            "for" => {
+                let hints = self.take_hints()?;
+                self.parse_for(hints)
+            }
+            "switch" => self.parse_switch(),
+            "free" => {
+                self.expect("(")?;
+                let ptr = self.value("pointer")?;
+                self.expect(")")?;
+                Ok(Stmt::Free(ptr))
+            }
+            // Unless it names a variable or label
+            "assert" if !self.peek_is("=") && !self.peek_is(":") && !self.peek_is("[") => {
+                Ok(Stmt::Assert(self.parse_condition()?))
+            }
+            "if" => {
+                if let Some(next) = self.tokens.get(self.pos + 1) {
+                    if next.content == "goto" || next.content == "{" {
+                        return Err(format!(
+                            "Expected a comparison at line {}:{}; use 'if x == y goto L' or 'if x == y {{ }}'",
+                            next.line, next.col
+                        ));
+                    }
+                }
+                let cond = self.parse_condition()?;
+                let action = self.consume().ok_or("Expected goto or {")?;
+                match action.content.as_str() {
+                    "goto" => {
+                        let label = self.consume().ok_or("Expected label")?;
+                        Ok(Stmt::IfGoto {
+                            cond,
+                            label: label.content,
+                        })
+                    }
+                    "{" => Ok(Stmt::If {
+                        cond,
+                        body: self.parse_block_rest()?,
+                    }),
+                    _ => Err("Expected 'goto' or '{'".to_string()),
+                }
+            }
+            _ => {
+                let dest_name = t.content;
+
+                // Label: `name:`
+                if self.peek_is(":") {
+                    self.consume(); // :
+                    return Ok(Stmt::Label {
+                        name: dest_name,
+                        hints: self.take_hints()?,
+                    });
+                }
+
+                // Row stride of a 2D array from elsewhere: `stride(a, cols)`
+                if dest_name == "stride" && self.peek_is("(") {
+                    self.consume(); // (
+                    let array = self.consume().ok_or("Expected array name")?;
+                    self.expect(",")?;
+                    let cols = self.value("row stride")?;
+                    self.expect(")")?;
+                    return Ok(Stmt::Stride {
+                        array: array.content,
+                        cols,
+                    });
+                }
+
+                // Array Store: `dest[i] = val` or `dest[i, j] = val`
+                if self.peek_is("[") {
+                    self.consume(); // [
+                    let index = self.parse_index(dest_name)?;
+                    self.expect("=")?;
+                    let value = self.value("value")?;
+                    return Ok(Stmt::Store { index, value });
+                }
+
+                let eq = self.consume().ok_or("Expected =")?;
+                if eq.content != "=" {
+                    return Err(format!(
+                        "Expected =, found {} at line {}:{}",
+                        eq.content, eq.line, eq.col
+                    ));
+                }
+                Ok(Stmt::Assign(Assign {
+                    dest: dest_name,
+                    value: self.parse_expression()?,
+                }))
+            }
+        }
+    }
+}
+
+impl Default for Parser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::Opcode;
+
+    #[test]
+    fn test_pragmas_attach_to_following_item() {
+        let script = "
+            #pragma align(64) novectorize
+            fn main(n) {
+                sum = 0
+                #pragma unroll(4) nontemporal
+                while n > 0 {
+                    sum = sum + n
+                    n = n - 1
+                }
+                #pragma align(32)
+                #pragma unroll(2)
+                again:
+                return sum
+            }
+        ";
+        let prog = Parser::new().parse(script).expect("Parsing failed");
+        let main = &prog.functions[0];
+        assert_eq!(main.hints.align, Some(64));
+        assert!(main.hints.no_vectorize);
+        assert_eq!(main.hints.unroll, None);
+
+        let (head, hints) = main.loop_hints.iter().find(|(l, _)| l.starts_with("while_start")).unwrap();
+        assert_eq!(hints.unroll, Some(4));
+        // Loops inherit function-level novectorize, but not its alignment
+        let merged = main.hints_for_loop(head);
+        assert!(merged.no_vectorize && merged.nontemporal);
+        assert_eq!(merged.align, None);
+
+        let again = &main.loop_hints["again"];
+        assert_eq!((again.unroll, again.align), (Some(2), Some(32)));
+
+        let prog = Parser::new()
+            .parse("#pragma opt(3)\nfn main() {\nreturn 0\n}")
+            .expect("Parsing failed");
+        assert_eq!(prog.functions[0].hints.opt_level, Some(3));
+    }
+
+    #[test]
+    fn test_alloc_aligned() {
+        let prog = Parser::new()
+            .parse("fn main(n) {\nA = alloc_aligned(n, 64)\nfree(A)\nreturn 0\n}")
+            .unwrap();
+        let alloc = &prog.functions[0].instructions[1];
+        assert_eq!(alloc.op, Opcode::Alloc);
+        assert_eq!(alloc.src2, Some(crate::ir::Operand::Imm(64)));
+
+        for bad in ["48", "4", "8192", "n"] {
+            let src = format!(
+                "fn main(n) {{\nA = alloc_aligned(64, {})\nreturn 0\n}}",
+                bad
+            );
+            let err = Parser::new().parse(&src).unwrap_err();
+            assert!(
+                err.contains("power of two from 8 to 4096 at line 2"),
+                "{}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn test_pragma_errors() {
+        let parse = |src: &str| Parser::new().parse(src).unwrap_err();
+        assert!(parse("#pragma unroll(0)\nfn main() {\nreturn 0\n}").contains("1-64"));
+        assert!(parse("#pragma align(48)\nfn main() {\nreturn 0\n}").contains("power of two"));
+        assert!(parse("#pragma fast\nfn main() {\nreturn 0\n}").contains("Unknown pragma"));
+        assert!(parse("#pragma opt(4)\nfn main() {\nreturn 0\n}").contains("0-3"));
+        let on_loop = parse("fn main(n) {\n#pragma opt(3)\nwhile n > 0 {\nn = n - 1\n}\nreturn n\n}");
+        assert!(on_loop.contains("applies to functions"), "{}", on_loop);
+        let dangling = parse("fn main() {\n#pragma unroll(2)\nx = 1\nreturn x\n}");
+        assert!(dangling.contains("line 2"), "{}", dangling);
+        assert!(parse("fn main() {\nreturn 0\n}\n#pragma novectorize").contains("must precede"));
+
+        // Anything else after '#' is still a comment
+        assert!(Parser::new().parse("#pragmatic\nfn main() {\nreturn 0\n}").is_ok());
+    }
+
+    #[test]
+    fn test_bit_intrinsics_take_one_argument() {
+        let prog = Parser::new()
+            .parse("fn main(x) {\nc = popcnt(x)\nreturn c\n}")
+            .expect("Parsing failed");
+        assert_eq!(prog.functions[0].instructions[1].op, Opcode::Popcnt);
+        let err = Parser::new()
+            .parse("fn main(x) {\nc = clz(x, 2)\nreturn c\n}")
+            .unwrap_err();
+        assert!(err.contains("clz() takes one argument at line 2"), "{}", err);
+    }
+
+    #[test]
+    fn test_min_max_take_two_arguments() {
+        // `b = min(a, b)` swaps operands rather than clobber b first
+        let prog = Parser::new()
+            .parse("fn main(a, b) {\nb = min(a, b)\nreturn b\n}")
+            .expect("Parsing failed");
+        let text: Vec<String> =
+            prog.functions[0].instructions[2..4].iter().map(|i| i.to_string()).collect();
+        assert_eq!(text, ["Mov Reg(11), Reg(11)", "Min Reg(11), Reg(10)"]);
+
+        for (src, name) in [("max(a)", "max"), ("min(a, b, 1)", "min")] {
+            let err = Parser::new()
+                .parse(&format!("fn main(a, b) {{\nc = {}\nreturn c\n}}", src))
+                .unwrap_err();
+            assert!(err.contains(&format!("{}() takes two arguments at line 2", name)), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_switch_arms() {
+        let ast = Parser::new()
+            .parse_ast("fn main(x) {\nswitch x {\n0 => x = 1\n-2 => {\nx = 5\nreturn x\n}\n_ => goto end\n}\nend:\nreturn x\n}")
+            .expect("Parsing failed");
+        let Stmt::Switch { value, cases, default } = &ast.functions[0].body[0].node else {
+            panic!("{:?}", ast.functions[0].body[0]);
+        };
+        assert_eq!(*value, Value::Var("x".to_string()));
+        // `x = 1` ends at its line: the `-` is the next case's sign
+        let keys: Vec<i32> = cases.iter().map(|(key, _)| *key).collect();
+        assert_eq!(keys, [0, -2]);
+        assert_eq!(cases[1].1.len(), 2);
+        assert_eq!(default.as_ref().map(|d| &d[0].node), Some(&Stmt::Goto("end".to_string())));
+
+        let parse = |arms: &str| {
+            Parser::new()
+                .parse(&format!("fn main(x) {{\nswitch x {{\n{}\n}}\nreturn x\n}}", arms))
+                .unwrap_err()
+        };
+        assert!(parse("1 => x = 1\n1 => x = 2").contains("Duplicate case 1 at line 4"));
+        assert!(parse("_ => x = 1\n_ => x = 2").contains("Second '_' arm at line 4"));
+        assert!(parse("y => x = 1").contains("Expected a constant case or '_', found 'y' at line 3"));
+        assert!(parse("1 x = 1").contains("Expected '=>'"));
+    }
+
+    #[test]
+    fn test_assert_unless_it_names_a_variable() {
+        let ast = Parser::new()
+            .parse_ast("fn main(x) {\nassert x != 3\nassert = x\nreturn assert\n}")
+            .expect("Parsing failed");
+        let body = &ast.functions[0].body;
+        let Stmt::Assert(cond) = &body[0].node else {
+            panic!("{:?}", body[0]);
+        };
+        assert_eq!(cond.to_string(), "x != 3");
+        assert!(matches!(&body[1].node, Stmt::Assign(a) if a.dest == "assert"));
+    }
+
+    #[test]
+    fn test_2d_index_without_stride_is_an_error() {
+        let err = Parser::new()
+            .parse("fn main() {\na = alloc(64)\nx = a[1, 2]\nreturn x\n}")
+            .unwrap_err();
+        assert!(err.contains("'a' has no row stride at line 3"), "{}", err);
+        // Strides don't leak between functions
+        let err = Parser::new()
+            .parse("fn f() {\na = alloc2d(2, 2)\nreturn 0\n}\nfn main() {\na = alloc(32)\na[1, 1] = 3\nreturn 0\n}")
+            .unwrap_err();
+        assert!(err.contains("line 7"), "{}", err);
+    }
+
+    #[test]
+    fn test_parse_ast_keeps_structure() {
+        let ast = Parser::new()
+            .parse_ast("fn main(n) {\n#pragma unroll(2)\nwhile n > 0 {\nn = n - 1\n}\nreturn n\n}")
+            .expect("Parsing failed");
+        let main = &ast.functions[0];
+        assert_eq!(main.args, ["n"]);
+        let Stmt::While { cond, body, hints } = &main.body[0].node else {
+            panic!("{:?}", main.body[0]);
+        };
+        assert_eq!(cond.op, CmpOp::Gt);
+        assert_eq!(hints.as_ref().and_then(|h| h.unroll), Some(2));
+        let n = || Value::Var("n".to_string());
+        assert_eq!(body[0].at, Span { line: 4, col: 1 });
+        assert_eq!(
+            body[0].node,
+            Stmt::Assign(Assign {
+                dest: "n".to_string(),
+                value: Expr::Binary(BinOp::Sub, n(), Value::Int(1)),
+            })
+        );
+        assert_eq!(main.body[1].node, Stmt::Return(n()));
+    }
+}
@@ -0,0 +1,143 @@
+//! Pass Selection
+//!
+//! Names for the optimizer and codegen passes that can be switched off one
+//! at a time, e.g. to measure what each contributes. A `Pass`'s name is the
+//! one it is counted under in `FunctionReport::passes`. Passes that
+//! correctness depends on (`if_convert` in constant-time builds) are not
+//! listed.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Pass {
+    RemoveIdentityMoves,
+    ConstantFolding,
+    DeadCodeElimination,
+    DeadStoreElimination,
+    VectorizeLoop,
+    LoopUnrolling,
+    JamUnrolledLoops,
+    SelectVectorAccess,
+    SelectLowering,
+    SoftwarePipeline,
+    InsertPrefetches,
+    Schedule,
+    SpecializeTripCounts,
+    FoldPureCalls,
+    SpecializeConstantArgs,
+    RotateLoops,
+}
+
+impl Pass {
+    pub const ALL: [Pass; 16] = [
+        Pass::RemoveIdentityMoves,
+        Pass::ConstantFolding,
+        Pass::DeadCodeElimination,
+        Pass::DeadStoreElimination,
+        Pass::VectorizeLoop,
+        Pass::LoopUnrolling,
+        Pass::JamUnrolledLoops,
+        Pass::SelectVectorAccess,
+        Pass::SelectLowering,
+        Pass::SoftwarePipeline,
+        Pass::InsertPrefetches,
+        Pass::Schedule,
+        Pass::SpecializeTripCounts,
+        Pass::FoldPureCalls,
+        Pass::SpecializeConstantArgs,
+        Pass::RotateLoops,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Pass::RemoveIdentityMoves => "remove_identity_moves",
+            Pass::ConstantFolding => "constant_folding",
+            Pass::DeadCodeElimination => "dead_code_elimination",
+            Pass::DeadStoreElimination => "dead_store_elimination",
+            Pass::VectorizeLoop => "vectorize_loop",
+            Pass::LoopUnrolling => "loop_unrolling",
+            Pass::JamUnrolledLoops => "jam_unrolled_loops",
+            Pass::SelectVectorAccess => "select_vector_access",
+            Pass::SelectLowering => "select_lowering",
+            Pass::SoftwarePipeline => "software_pipeline",
+            Pass::InsertPrefetches => "insert_prefetches",
+            Pass::Schedule => "schedule",
+            Pass::SpecializeTripCounts => "specialize_trip_counts",
+            Pass::FoldPureCalls => "fold_pure_calls",
+            Pass::SpecializeConstantArgs => "specialize_constant_args",
+            Pass::RotateLoops => "rotate_loops",
+        }
+    }
+
+    fn bit(self) -> u32 {
+        1 << Pass::ALL.iter().position(|&p| p == self).unwrap()
+    }
+}
+
+impl fmt::Display for Pass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Pass {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        Pass::ALL
+            .into_iter()
+            .find(|p| p.name() == s)
+            .ok_or_else(|| format!("unknown pass '{}'", s))
+    }
+}
+
+/// A set of passes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PassSet(u32);
+
+impl PassSet {
+    pub fn with(mut self, pass: Pass) -> Self {
+        self.0 |= pass.bit();
+        self
+    }
+
+    pub fn contains(self, pass: Pass) -> bool {
+        self.0 & pass.bit() != 0
+    }
+
+    pub fn union(self, other: PassSet) -> Self {
+        PassSet(self.0 | other.0)
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// The passes in the set, in `Pass::ALL` order
+    pub fn passes(self) -> Vec<Pass> {
+        Pass::ALL.into_iter().filter(|&p| self.contains(p)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_names_round_trip_and_sets() {
+        for pass in Pass::ALL {
+            assert_eq!(pass.name().parse::<Pass>(), Ok(pass));
+        }
+        assert!("inline".parse::<Pass>().is_err());
+
+        let set = PassSet::default().with(Pass::Schedule).with(Pass::ConstantFolding);
+        assert!(set.contains(Pass::Schedule) && set.contains(Pass::ConstantFolding));
+        assert!(!set.contains(Pass::LoopUnrolling));
+        assert!(PassSet::default().is_empty());
+        let both = PassSet::default().with(Pass::LoopUnrolling).union(set);
+        assert_eq!(both.passes(), vec![Pass::ConstantFolding, Pass::LoopUnrolling, Pass::Schedule]);
+    }
+}
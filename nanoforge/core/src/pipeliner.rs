@@ -0,0 +1,339 @@
+//! Software Pipelining
+//!
+//! Two-stage modulo scheduling for small counted loops. Loads indexed by the
+//! induction variable form stage 0, everything else stage 1: a prologue
+//! issues the first iteration's loads, the kernel issues iteration i+1's
+//! loads next to iteration i's arithmetic (one straight-line run, so the
+//! scheduler can interleave them), and an epilogue finishes the last
+//! iteration without loading past the end.
+//!
+//! ```text
+//! L:      cmp i, n; jcc exit         L:      cmp i, n; jcc exit
+//!         load x, a, i                       load x, a, i
+//!         <body using x>      ==>    L_swp:  mov x', x
+//!         add i, 1                           mov i', i; add i', 1
+//!         jmp L                              cmp i', n; jcc L_swp_last
+//!                                            load x, a, i'
+//!                                            <body using x'>
+//!                                            jmp L_swp
+//!                                    L_swp_last:
+//!                                            <body using x'>
+//!                                            jmp exit
+//! ```
+//!
+//! Only run at -O3 when asked for (`CompileOptions::software_pipeline`), or
+//! as its own variant, so the sandbox decides whether it wins on a machine.
+
+use crate::ir::{Function, Instruction, Opcode, Operand, Program};
+use crate::loops::{self, writes};
+use crate::scheduler::is_schedulable;
+use std::collections::HashSet;
+
+/// Longest loop body (excluding the header test and back jump) pipelined
+pub const MAX_BODY: usize = 24;
+
+/// Pipeline every eligible loop; true if any loop was transformed
+pub fn pipeline_program(prog: &mut Program) -> bool {
+    let mut changed = false;
+    for func in &mut prog.functions {
+        changed |= pipeline_function(func);
+    }
+    changed
+}
+
+/// Pipeline every eligible loop in `func`; true if any was transformed
+pub fn pipeline_function(func: &mut Function) -> bool {
+    let mut changed = false;
+    let mut from = 0;
+    loops::annotate(func);
+    while let Some(loop_) = find_loop(func, from) {
+        if let Some(replacement) = pipeline_loop(func, &loop_) {
+            let kernel = format!("{}_swp", loop_.label);
+            let inserted = replacement.len();
+            func.instructions
+                .splice(loop_.body_start..=loop_.back_jump, replacement);
+            loops::annotate(func);
+            // The kernel is already scheduled across iterations: don't unroll
+            // or vectorize it (the vectorizer's pattern ignores the copies)
+            let hints = func.loop_hints.entry(kernel).or_default();
+            hints.unroll = Some(1);
+            hints.no_vectorize = true;
+            from = loop_.body_start + inserted;
+            changed = true;
+        } else {
+            from = loop_.back_jump + 1;
+        }
+    }
+    changed
+}
+
+/// A counted loop of the form `L: cmp; jcc exit; body; jmp L`
+struct CountedLoop {
+    label: String,
+    header_cmp: usize,
+    exit_jump: usize,
+    body_start: usize,
+    back_jump: usize,
+    induction: Operand,
+    step_at: usize,
+}
+
+/// Next loop shaped like `CountedLoop` whose back jump is at or after `from`
+fn find_loop(func: &Function, from: usize) -> Option<CountedLoop> {
+    func.loops
+        .iter()
+        .filter(|(_, l)| l.back_jump >= from && func.instructions[l.back_jump].op == Opcode::Jmp)
+        .filter_map(|(label, l)| {
+            let induction = l.induction.as_ref()?;
+            // The exit test jumps out itself
+            (induction.exit_jump == induction.test + 1).then(|| CountedLoop {
+                label: label.clone(),
+                header_cmp: induction.test,
+                exit_jump: induction.exit_jump,
+                body_start: induction.body_start,
+                back_jump: l.back_jump,
+                induction: induction.var.clone(),
+                step_at: induction.step_at,
+            })
+        })
+        .min_by_key(|l| l.back_jump)
+}
+
+fn reads(instr: &Instruction) -> Vec<&Operand> {
+    let mut ops: Vec<&Operand> = [&instr.src1, &instr.src2]
+        .into_iter()
+        .filter_map(|o| o.as_ref())
+        .collect();
+    if matches!(
+        instr.op,
+        Opcode::Add
+            | Opcode::Sub
+            | Opcode::Mul
+            | Opcode::Min
+            | Opcode::Max
+            | Opcode::Store(_)
+            | Opcode::VStore(..)
+            | Opcode::PrefetchT0
+    ) {
+        ops.extend(instr.dest.as_ref());
+    }
+    ops
+}
+
+/// The instructions replacing `body..=back_jump`, or None if the loop
+/// isn't eligible
+fn pipeline_loop(func: &Function, loop_: &CountedLoop) -> Option<Vec<Instruction>> {
+    let body = &func.instructions[loop_.body_start..loop_.back_jump];
+    if body.is_empty() || body.len() > MAX_BODY || !body.iter().all(|i| is_schedulable(&i.op)) {
+        return None;
+    }
+    let written_in_body = |op: &Operand| body.iter().filter(|i| writes(i) == Some(op)).count();
+
+    let cmp = &func.instructions[loop_.header_cmp];
+    let (step_idx, induction) = (loop_.step_at - loop_.body_start, loop_.induction.clone());
+
+    // Stage 0: loads before the step from an invariant base at the induction
+    // variable (or an invariant index), into a register the body doesn't
+    // touch earlier and nothing outside the loop uses (stage 1 works on a copy)
+    let outside = func.instructions[..loop_.header_cmp - 1]
+        .iter()
+        .chain(&func.instructions[loop_.back_jump..]);
+    let used_outside: HashSet<&Operand> = outside
+        .flat_map(|i| reads(i).into_iter().chain(writes(i)))
+        .collect();
+    let mentions = |i: &Instruction, op: &Operand| reads(i).contains(&op) || writes(i) == Some(op);
+    let stage0: Vec<usize> = (0..step_idx)
+        .filter(|&idx| {
+            let load = &body[idx];
+            let (Opcode::Load(_), Some(dest), Some(base), Some(index)) =
+                (&load.op, &load.dest, &load.src1, &load.src2)
+            else {
+                return false;
+            };
+            written_in_body(base) == 0
+                && (*index == induction || written_in_body(index) == 0)
+                && !used_outside.contains(dest)
+                && [&cmp.src1, &cmp.src2]
+                    .into_iter()
+                    .flatten()
+                    .all(|op| op != dest)
+                && !body[..idx].iter().any(|i| mentions(i, dest))
+        })
+        .collect();
+    if stage0.is_empty() || !stores_are_disjoint(func, body, &stage0) {
+        return None;
+    }
+
+    let mut fresh = FreshRegs::new(func)?;
+    let next_index = fresh.next()?;
+    let renames: Vec<(Operand, Operand)> = stage0
+        .iter()
+        .map(|&idx| Some((body[idx].dest.clone()?, fresh.next()?)))
+        .collect::<Option<_>>()?;
+    let rename = |op: &Option<Operand>| {
+        op.as_ref().map(|o| {
+            renames
+                .iter()
+                .find(|(from, _)| from == o)
+                .map_or_else(|| o.clone(), |(_, to)| to.clone())
+        })
+    };
+    let stage1: Vec<Instruction> = body
+        .iter()
+        .enumerate()
+        .filter(|(idx, _)| !stage0.contains(idx))
+        .map(|(_, i)| Instruction {
+            op: i.op.clone(),
+            dest: rename(&i.dest),
+            src1: rename(&i.src1),
+            src2: rename(&i.src2),
+        })
+        .collect();
+    let next_loads = stage0.iter().map(|&idx| {
+        let mut load = body[idx].clone();
+        if load.src2.as_ref() == Some(&induction) {
+            load.src2 = Some(next_index.clone());
+        }
+        load
+    });
+
+    let kernel = format!("{}_swp", loop_.label);
+    let last = format!("{}_swp_last", loop_.label);
+    let exit = func.instructions[loop_.exit_jump].dest.clone();
+    let label = |name: &str| instr(Opcode::Label, Some(Operand::Label(name.to_string())), None);
+    let at_next = |op: &Option<Operand>| {
+        if op.as_ref() == Some(&induction) {
+            Some(next_index.clone())
+        } else {
+            op.clone()
+        }
+    };
+
+    let mut out: Vec<Instruction> = stage0.iter().map(|&idx| body[idx].clone()).collect();
+    out.push(label(&kernel));
+    for (loaded, current) in &renames {
+        out.push(instr(
+            Opcode::Mov,
+            Some(current.clone()),
+            Some(loaded.clone()),
+        ));
+    }
+    out.push(instr(
+        Opcode::Mov,
+        Some(next_index.clone()),
+        Some(induction.clone()),
+    ));
+    let step = &body[step_idx];
+    out.push(instr(
+        step.op.clone(),
+        Some(next_index.clone()),
+        step.src1.clone(),
+    ));
+    out.push(Instruction {
+        op: Opcode::Cmp,
+        dest: None,
+        src1: at_next(&cmp.src1),
+        src2: at_next(&cmp.src2),
+    });
+    out.push(instr(
+        func.instructions[loop_.exit_jump].op.clone(),
+        Some(Operand::Label(last.clone())),
+        None,
+    ));
+    out.extend(next_loads);
+    out.extend(stage1.iter().cloned());
+    out.push(instr(Opcode::Jmp, Some(Operand::Label(kernel)), None));
+    out.push(label(&last));
+    out.extend(stage1);
+    out.push(instr(Opcode::Jmp, exit, None));
+    Some(out)
+}
+
+fn instr(op: Opcode, dest: Option<Operand>, src1: Option<Operand>) -> Instruction {
+    Instruction {
+        op,
+        dest,
+        src1,
+        src2: None,
+    }
+}
+
+/// Loads of iteration i+1 move above the stores of iteration i, so every
+/// store base and hoisted load base must be a distinct, never-reassigned
+/// allocation (bases that may alias keep the loop as it is)
+fn stores_are_disjoint(func: &Function, body: &[Instruction], stage0: &[usize]) -> bool {
+    let stores: Vec<&Operand> = body
+        .iter()
+        .filter(|i| matches!(i.op, Opcode::Store(_) | Opcode::VStore(..)))
+        .filter_map(|i| i.dest.as_ref())
+        .collect();
+    if stores.is_empty() {
+        return true;
+    }
+    let fresh_allocation = |base: &Operand| {
+        let defs: Vec<&Instruction> = func
+            .instructions
+            .iter()
+            .filter(|i| writes(i) == Some(base))
+            .collect();
+        defs.len() == 1 && defs[0].op == Opcode::Alloc
+    };
+    stage0.iter().all(|&idx| {
+        let base = body[idx].src1.as_ref().unwrap();
+        fresh_allocation(base) && !stores.contains(&base)
+    }) && stores.iter().all(|base| fresh_allocation(base))
+}
+
+/// Virtual registers the function doesn't use yet
+struct FreshRegs {
+    next: u16,
+}
+
+impl FreshRegs {
+    fn new(func: &Function) -> Option<Self> {
+        let used: HashSet<u8> = func
+            .instructions
+            .iter()
+            .flat_map(|i| [&i.dest, &i.src1, &i.src2])
+            .filter_map(|o| match o {
+                Some(Operand::Reg(r)) => Some(*r),
+                _ => None,
+            })
+            .collect();
+        Some(Self {
+            next: used.iter().max().map_or(0, |&r| r as u16 + 1),
+        })
+    }
+
+    fn next(&mut self) -> Option<Operand> {
+        let reg = u8::try_from(self.next).ok()?;
+        self.next += 1;
+        Some(Operand::Reg(reg))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    #[test]
+    fn test_possibly_aliasing_stores_block_pipelining() {
+        // b is a copy of a: the next iteration's load may read this store
+        let source = "fn main(n) {
+            a = alloc(16)
+            b = a
+            i = 0
+            loop:
+            if i == n goto done
+            x = a[i]
+            b[i] = x
+            i = i + 1
+            goto loop
+            done:
+            return 0
+        }";
+        let mut prog = Parser::new().parse(source).unwrap();
+        assert!(!pipeline_program(&mut prog));
+    }
+}
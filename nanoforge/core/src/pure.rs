@@ -0,0 +1,396 @@
+//! Pure Mode
+//!
+//! For services that compile untrusted scripts: with `CompileOptions::pure`
+//! a program may only compute over the buffers its caller passes in. The
+//! checks run on the IR before optimization and reject:
+//!
+//! - `Alloc` and `Free`, which call libc malloc/free
+//! - calls to anything but the program's own functions (and lazy calls,
+//!   whose callees are resolved at run time)
+//! - memory accesses whose base isn't a buffer parameter plus an integer
+//!   offset, such as dereferencing an integer or a loaded value
+//! - pointers leaking into integers: compared, stored, returned, multiplied,
+//!   or used as an index or offset
+//!
+//! A parameter is a buffer if `types` infers it as a pointer, i.e. the
+//! function dereferences it, or if it is passed on as another function's
+//! buffer parameter; the others are integers. Offsets aren't bounded
+//! here: the backend checks every access against the buffers the host
+//! handed the calling thread (`sanitizer::set_buffers`) and traps outside
+//! them.
+
+use crate::ir::{Function, Instruction, Opcode, Operand, Program};
+use crate::types::TypeMap;
+use std::collections::HashMap;
+
+/// What a register may hold at a program point
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Int,
+    /// A buffer parameter plus an integer offset
+    Ptr,
+    /// Anything else computed from a pointer
+    Mixed,
+}
+
+impl Kind {
+    fn join(self, other: Kind) -> Kind {
+        if self == other {
+            self
+        } else {
+            Kind::Mixed
+        }
+    }
+}
+
+type State = HashMap<u8, Kind>;
+
+fn is_jump(op: &Opcode) -> bool {
+    matches!(
+        op,
+        Opcode::Jmp
+            | Opcode::Jnz
+            | Opcode::Je
+            | Opcode::Jne
+            | Opcode::Jl
+            | Opcode::Jle
+            | Opcode::Jg
+            | Opcode::Jge
+            | Opcode::JumpTable(..)
+    )
+}
+
+/// Every instruction `prog` may not contain in pure mode, or Ok if none
+pub fn check_program(prog: &Program) -> Result<(), String> {
+    let params = param_kinds(prog);
+    let problems: Vec<String> = prog
+        .functions
+        .iter()
+        .flat_map(|f| violations(f, &params))
+        .collect();
+    if problems.is_empty() {
+        return Ok(());
+    }
+    Err(format!(
+        "pure mode: {} instruction(s) reach outside the buffers passed in:\n  {}",
+        problems.len(),
+        problems.join("\n  ")
+    ))
+}
+
+/// Buffer parameters are the ones a function dereferences or passes on as
+/// another function's buffer parameter
+fn param_kinds(prog: &Program) -> HashMap<&str, Vec<Kind>> {
+    // Register each function loads its parameters into
+    let arg_regs: HashMap<&str, HashMap<u8, usize>> = prog
+        .functions
+        .iter()
+        .map(|f| {
+            let regs = f
+                .instructions
+                .iter()
+                .filter_map(|instr| match (&instr.op, &instr.dest) {
+                    (Opcode::LoadArg(i), Some(Operand::Reg(r))) if *i < f.args.len() => {
+                        Some((*r, *i))
+                    }
+                    _ => None,
+                })
+                .collect();
+            (f.name.as_str(), regs)
+        })
+        .collect();
+    let mut kinds: HashMap<&str, Vec<Kind>> = prog
+        .functions
+        .iter()
+        .map(|f| {
+            let types = TypeMap::infer(f);
+            let mut kinds = vec![Kind::Int; f.args.len()];
+            for (r, i) in &arg_regs[f.name.as_str()] {
+                if types.get(*r).is_some_and(|t| t.is_ptr()) {
+                    kinds[*i] = Kind::Ptr;
+                }
+            }
+            (f.name.as_str(), kinds)
+        })
+        .collect();
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for func in &prog.functions {
+            let instrs = &func.instructions;
+            for (idx, instr) in instrs.iter().enumerate() {
+                let (Opcode::SetArg(i), Some(Operand::Reg(r))) = (&instr.op, &instr.src1) else {
+                    continue;
+                };
+                let Some(&param) = arg_regs[func.name.as_str()].get(r) else {
+                    continue;
+                };
+                let callee = instrs[idx..].iter().find_map(|c| match (&c.op, &c.src1) {
+                    (Opcode::Call, Some(Operand::Label(target))) => Some(target.as_str()),
+                    _ => None,
+                });
+                let forwards_buffer = callee
+                    .and_then(|c| kinds.get(c))
+                    .is_some_and(|k| k.get(*i) == Some(&Kind::Ptr));
+                let own = kinds.get_mut(func.name.as_str()).unwrap();
+                if forwards_buffer && own[param] != Kind::Ptr {
+                    own[param] = Kind::Ptr;
+                    changed = true;
+                }
+            }
+        }
+    }
+    kinds
+}
+
+fn kind(state: &State, op: &Option<Operand>) -> Kind {
+    match op {
+        // Registers not yet written hold no pointer this program made
+        Some(Operand::Reg(r)) => state.get(r).copied().unwrap_or(Kind::Int),
+        _ => Kind::Int,
+    }
+}
+
+/// Kind of `instr`'s destination register after it runs, if it writes one
+fn transfer(instr: &Instruction, state: &State, params: &[Kind]) -> Option<(u8, Kind)> {
+    let Some(Operand::Reg(dest)) = instr.dest else {
+        return None;
+    };
+    let d = kind(state, &instr.dest);
+    let s = kind(state, &instr.src1);
+    let result = match instr.op {
+        Opcode::LoadArg(i) => params.get(i).copied().unwrap_or(Kind::Int),
+        Opcode::Mov | Opcode::SetArg(_) => s,
+        Opcode::CMov(_) => d.join(s),
+        Opcode::Add => match (d, s) {
+            (Kind::Int, k) | (k, Kind::Int) => k,
+            _ => Kind::Mixed,
+        },
+        Opcode::Sub => match (d, s) {
+            (k, Kind::Int) => k,
+            _ => Kind::Mixed,
+        },
+        // Loaded data and call results are plain values
+        Opcode::Load(_) | Opcode::Call | Opcode::Alloc => Kind::Int,
+        Opcode::Store(_) | Opcode::PrefetchT0 | Opcode::Cmp | Opcode::Label => return None,
+        _ if is_jump(&instr.op) => return None,
+        _ => {
+            if d == Kind::Int && s == Kind::Int && kind(state, &instr.src2) == Kind::Int {
+                Kind::Int
+            } else {
+                Kind::Mixed
+            }
+        }
+    };
+    Some((dest, result))
+}
+
+/// Problems with `instr` given the kinds of registers before it
+fn check(instr: &Instruction, state: &State, params: &HashMap<&str, Vec<Kind>>) -> Option<String> {
+    let is_int = |op: &Option<Operand>| kind(state, op) == Kind::Int;
+    let is_ptr = |op: &Option<Operand>| kind(state, op) == Kind::Ptr;
+    match instr.op {
+        Opcode::Alloc => Some("allocates (malloc)".to_string()),
+        Opcode::Free => Some("frees (free)".to_string()),
+        Opcode::Call => {
+            let Some(Operand::Label(target)) = &instr.src1 else {
+                return Some("calls an unnamed function".to_string());
+            };
+            let Some(callee) = params.get(target.as_str()) else {
+                return Some(format!("calls '{}', which is not in the program", target));
+            };
+            // SetArg(i) writes Reg(i + 1)
+            callee
+                .iter()
+                .enumerate()
+                .find(|(i, expected)| kind(state, &Some(Operand::Reg(*i as u8 + 1))) != **expected)
+                .map(|(i, expected)| match expected {
+                    Kind::Ptr => format!("argument {} of '{}' is not a buffer", i, target),
+                    _ => format!("passes a pointer as integer argument {} of '{}'", i, target),
+                })
+        }
+        Opcode::Load(_) | Opcode::VLoad(..) | Opcode::PrefetchT0 | Opcode::Store(_) | Opcode::VStore(..) => {
+            let (base, index) = match instr.op {
+                Opcode::Load(_) | Opcode::VLoad(..) => (&instr.src1, &instr.src2),
+                _ => (&instr.dest, &instr.src1),
+            };
+            if !is_ptr(base) {
+                Some("base is not a buffer parameter plus an offset".to_string())
+            } else if !is_int(index) {
+                Some("index is computed from a pointer".to_string())
+            } else if matches!(instr.op, Opcode::Store(_)) && !is_int(&instr.src2) {
+                Some("stores a pointer".to_string())
+            } else {
+                None
+            }
+        }
+        Opcode::Cmp if !is_int(&instr.src1) || !is_int(&instr.src2) => {
+            Some("compares a pointer".to_string())
+        }
+        // Scripts return through Reg(0)
+        Opcode::Ret if !is_int(&instr.dest) || !is_int(&Some(Operand::Reg(0))) => {
+            Some("returns a pointer".to_string())
+        }
+        Opcode::Add | Opcode::Sub if is_ptr(&instr.dest) && !is_int(&instr.src1) => {
+            Some("offsets a pointer by a pointer".to_string())
+        }
+        _ => None,
+    }
+}
+
+/// Forward dataflow over `func`'s instructions, then check each one
+fn violations(func: &Function, params: &HashMap<&str, Vec<Kind>>) -> Vec<String> {
+    let instrs = &func.instructions;
+    let labels: HashMap<&str, usize> = instrs
+        .iter()
+        .enumerate()
+        .filter_map(|(idx, i)| match (&i.op, &i.dest) {
+            (Opcode::Label, Some(Operand::Label(name))) => Some((name.as_str(), idx)),
+            _ => None,
+        })
+        .collect();
+    let own = &params[func.name.as_str()];
+
+    let mut before: Vec<Option<State>> = vec![None; instrs.len()];
+    let mut work = Vec::new();
+    if !instrs.is_empty() {
+        before[0] = Some(State::new());
+        work.push(0);
+    }
+    while let Some(idx) = work.pop() {
+        let Some(mut state) = before[idx].clone() else {
+            continue;
+        };
+        let instr = &instrs[idx];
+        if let Some((reg, k)) = transfer(instr, &state, own) {
+            state.insert(reg, k);
+        }
+        let mut next: Vec<usize> = instr
+            .jump_targets()
+            .into_iter()
+            .filter_map(|target| labels.get(target).copied())
+            .collect();
+        if !instr.ends_block() && idx + 1 < instrs.len() {
+            next.push(idx + 1);
+        }
+        for succ in next {
+            let merged = match &before[succ] {
+                None => state.clone(),
+                Some(old) => {
+                    let mut merged = old.clone();
+                    for (reg, k) in &state {
+                        merged
+                            .entry(*reg)
+                            .and_modify(|m| *m = m.join(*k))
+                            .or_insert(*k);
+                    }
+                    merged
+                }
+            };
+            if before[succ].as_ref() != Some(&merged) {
+                before[succ] = Some(merged);
+                work.push(succ);
+            }
+        }
+    }
+
+    instrs
+        .iter()
+        .enumerate()
+        .filter_map(|(idx, instr)| {
+            let state = before[idx].as_ref()?;
+            let problem = check(instr, state, params)?;
+            Some(format!("{}[{}]: {}: {}", func.name, idx, instr, problem))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    fn check(src: &str) -> Result<(), String> {
+        check_program(&Parser::new().parse(src).unwrap())
+    }
+
+    #[test]
+    fn test_buffer_kernels_pass() {
+        check(
+            "fn sum(a, n) {
+                s = 0
+                i = 0
+                while i < n {
+                    v = a[i]
+                    s = s + v
+                    i = i + 1
+                }
+                return s
+            }
+            fn main(a, out, n) {
+                s = sum(a, n)
+                out[0] = s
+                stride(out, n)
+                out[1, 0] = s
+                return s
+            }",
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_rejects_allocation_and_escapes() {
+        let err = check(
+            "fn main(a, n) {
+                b = alloc(8)
+                b[0] = n
+                free(b)
+                x = 4096
+                v = x[0]
+                a[0] = a
+                if n < a {
+                    return 1
+                }
+                return a
+            }",
+        )
+        .unwrap_err();
+        for problem in [
+            "allocates (malloc)",
+            "frees (free)",
+            "base is not a buffer parameter",
+            "stores a pointer",
+            "compares a pointer",
+            "returns a pointer",
+        ] {
+            assert!(err.contains(problem), "missing '{}' in:\n{}", problem, err);
+        }
+    }
+
+    #[test]
+    fn test_rejects_pointer_arithmetic_and_bad_arguments() {
+        let err = check(
+            "fn peek(p) {
+                v = p[0]
+                return v
+            }
+            fn main(a) {
+                u = a[0]
+                z = a * 1
+                b = a - z
+                b = b + 4096
+                v = b[0]
+                w = peek(7)
+                return w
+            }",
+        )
+        .unwrap_err();
+        assert!(err.contains("offsets a pointer by a pointer"), "{}", err);
+        assert!(
+            err.contains("argument 0 of 'peek' is not a buffer"),
+            "{}",
+            err
+        );
+    }
+}
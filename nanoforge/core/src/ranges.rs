@@ -0,0 +1,630 @@
+//! Value-Range Analysis
+//!
+//! An interval for every integer register before every instruction, found
+//! by abstract interpretation: arithmetic on intervals, narrowed on each
+//! edge of a compare-and-branch, joined where paths meet and widened at
+//! loop headers so loops converge. The induction variable of a loop with a
+//! constant trip count (`loops`) is pinned to the values it takes, which
+//! widening alone would lose to `if i == n goto done` exits. Alongside,
+//! it tracks which registers hold an allocation this function made and
+//! hasn't freed since, and the fewest bytes it can have.
+//!
+//! The backend uses it to drop safety checks that can't fire:
+//!
+//! - the fuel decrement of loops that provably stop within
+//!   `MAX_UNFUELED_TRIPS` iterations and the function's fuel budget
+//!   (`unfueled_loops`)
+//! - sanitizer checks of loads and stores that provably land inside such
+//!   an allocation (`Ranges::in_bounds`)
+
+use crate::ir::{Cond, Function, Instruction, Opcode, Operand};
+use crate::loops::{self, label_indices, swapped, writes};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+/// Most loop iterations one unit of fuel may cover: a loop skips its
+/// fuel check only if it stops within this many, counting the iterations
+/// of unfueled loops around it
+pub const MAX_UNFUELED_TRIPS: u64 = 1 << 16;
+
+/// Visits of a loop header before its intervals are widened
+const WIDEN_AFTER: u32 = 3;
+
+/// The values a register may hold, inclusive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interval {
+    pub lo: i64,
+    pub hi: i64,
+}
+
+impl Interval {
+    /// Nothing known
+    pub const FULL: Interval = Interval { lo: i64::MIN, hi: i64::MAX };
+
+    pub fn point(value: i64) -> Self {
+        Self { lo: value, hi: value }
+    }
+
+    fn new(lo: i64, hi: i64) -> Option<Self> {
+        (lo <= hi).then_some(Self { lo, hi })
+    }
+
+    fn join(self, other: Self) -> Self {
+        Self {
+            lo: self.lo.min(other.lo),
+            hi: self.hi.max(other.hi),
+        }
+    }
+
+    fn meet(self, other: Self) -> Option<Self> {
+        Self::new(self.lo.max(other.lo), self.hi.min(other.hi))
+    }
+
+    fn add(self, other: Self) -> Self {
+        match (self.lo.checked_add(other.lo), self.hi.checked_add(other.hi)) {
+            (Some(lo), Some(hi)) => Self { lo, hi },
+            _ => Self::FULL,
+        }
+    }
+
+    fn sub(self, other: Self) -> Self {
+        match (self.lo.checked_sub(other.hi), self.hi.checked_sub(other.lo)) {
+            (Some(lo), Some(hi)) => Self { lo, hi },
+            _ => Self::FULL,
+        }
+    }
+
+    fn mul(self, other: Self) -> Self {
+        let corners = [
+            self.lo.checked_mul(other.lo),
+            self.lo.checked_mul(other.hi),
+            self.hi.checked_mul(other.lo),
+            self.hi.checked_mul(other.hi),
+        ];
+        match corners.into_iter().collect::<Option<Vec<i64>>>() {
+            Some(c) => Self {
+                lo: *c.iter().min().expect("four corners"),
+                hi: *c.iter().max().expect("four corners"),
+            },
+            None => Self::FULL,
+        }
+    }
+
+    fn abs(self) -> Self {
+        match (self.lo, self.hi) {
+            // |i64::MIN| wraps to itself
+            (i64::MIN, _) => Self::FULL,
+            (lo, _) if lo >= 0 => self,
+            (lo, hi) if hi <= 0 => Self { lo: -hi, hi: -lo },
+            (lo, hi) => Self { lo: 0, hi: hi.max(-lo) },
+        }
+    }
+
+    /// The values of `self` for which `self cond` some value of `other` holds
+    fn narrow(self, cond: Cond, other: Self) -> Option<Self> {
+        match cond {
+            Cond::Eq => self.meet(other),
+            Cond::Ne if other.lo == other.hi => match (self.lo == other.lo, self.hi == other.lo) {
+                (true, true) => None,
+                (true, false) => Self::new(self.lo + 1, self.hi),
+                (false, true) => Self::new(self.lo, self.hi - 1),
+                (false, false) => Some(self),
+            },
+            Cond::Ne => Some(self),
+            Cond::Lt => Self::new(self.lo, self.hi.min(other.hi.checked_sub(1)?)),
+            Cond::Le => Self::new(self.lo, self.hi.min(other.hi)),
+            Cond::Gt => Self::new(self.lo.max(other.lo.checked_add(1)?), self.hi),
+            Cond::Ge => Self::new(self.lo.max(other.lo), self.hi),
+        }
+    }
+}
+
+/// An allocation made by the function's `Alloc` at `site`, not freed since
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Allocation {
+    site: usize,
+    min_bytes: i64,
+}
+
+/// What is known before one instruction; a register missing from `ints`
+/// may hold anything
+#[derive(Debug, Clone, Default, PartialEq)]
+struct State {
+    ints: BTreeMap<u8, Interval>,
+    allocs: BTreeMap<u8, Allocation>,
+}
+
+impl State {
+    fn get(&self, op: &Option<Operand>) -> Interval {
+        match op {
+            Some(Operand::Imm(n)) => Interval::point(*n as i64),
+            Some(Operand::Reg(r)) => self.ints.get(r).copied().unwrap_or(Interval::FULL),
+            _ => Interval::FULL,
+        }
+    }
+
+    fn set(&mut self, reg: u8, value: Interval) {
+        if value == Interval::FULL {
+            self.ints.remove(&reg);
+        } else {
+            self.ints.insert(reg, value);
+        }
+    }
+
+    fn join(&self, other: &State) -> State {
+        let ints = self
+            .ints
+            .iter()
+            .filter_map(|(r, a)| other.ints.get(r).map(|b| (*r, a.join(*b))))
+            .collect();
+        let allocs = self
+            .allocs
+            .iter()
+            .filter_map(|(r, a)| match other.allocs.get(r) {
+                Some(b) if a.site == b.site => Some((
+                    *r,
+                    Allocation {
+                        site: a.site,
+                        min_bytes: a.min_bytes.min(b.min_bytes),
+                    },
+                )),
+                _ => None,
+            })
+            .collect();
+        State { ints, allocs }
+    }
+
+    /// `self` joined with a later `next`, sending every bound that moved to
+    /// infinity
+    fn widen(&self, next: &State) -> State {
+        let mut widened = next.clone();
+        for (r, new) in widened.ints.iter_mut() {
+            let old = self.ints[r];
+            if new.lo < old.lo {
+                new.lo = i64::MIN;
+            }
+            if new.hi > old.hi {
+                new.hi = i64::MAX;
+            }
+        }
+        widened.ints.retain(|_, v| *v != Interval::FULL);
+        widened
+    }
+
+    /// Apply `instr`, the instruction at `at`
+    fn transfer(&mut self, at: usize, instr: &Instruction) {
+        let Some(Operand::Reg(dest)) = writes(instr).cloned() else {
+            match (&instr.op, &instr.src1) {
+                (Opcode::Free, Some(Operand::Reg(ptr))) => match self.allocs.get(ptr).copied() {
+                    Some(freed) => self.allocs.retain(|_, a| a.site != freed.site),
+                    // Whatever it pointed to is gone
+                    None => self.allocs.clear(),
+                },
+                (Opcode::Free, _) => self.allocs.clear(),
+                _ => {}
+            }
+            return;
+        };
+        let old = self.get(&instr.dest);
+        let src = self.get(&instr.src1);
+        let copied = match &instr.src1 {
+            Some(Operand::Reg(r)) => self.allocs.get(r).copied(),
+            _ => None,
+        };
+        self.allocs.remove(&dest);
+        let value = match &instr.op {
+            Opcode::Mov | Opcode::SetArg(_) => {
+                if let Some(alloc) = copied {
+                    self.allocs.insert(dest, alloc);
+                }
+                src
+            }
+            Opcode::Add => old.add(src),
+            Opcode::Sub => old.sub(src),
+            Opcode::Mul => old.mul(src),
+            Opcode::Min => Interval { lo: old.lo.min(src.lo), hi: old.hi.min(src.hi) },
+            Opcode::Max => Interval { lo: old.lo.max(src.lo), hi: old.hi.max(src.hi) },
+            Opcode::Abs => src.abs(),
+            Opcode::Popcnt | Opcode::Ctz | Opcode::Clz => Interval { lo: 0, hi: 64 },
+            Opcode::CMov(_) => old.join(src),
+            Opcode::Alloc => {
+                self.allocs.insert(
+                    dest,
+                    Allocation {
+                        site: at,
+                        min_bytes: src.lo.max(0),
+                    },
+                );
+                Interval::FULL
+            }
+            Opcode::Call => {
+                // The callee may free anything it can reach
+                self.allocs.clear();
+                Interval::FULL
+            }
+            _ => Interval::FULL,
+        };
+        self.set(dest, value);
+    }
+
+    /// `self` on the edge where the `Cmp` before a jump makes `cond` hold;
+    /// None if it can't
+    fn refine(&self, cmp: &Instruction, cond: Cond) -> Option<State> {
+        let (a, b) = (self.get(&cmp.src1), self.get(&cmp.src2));
+        let (a_now, b_now) = (a.narrow(cond, b)?, b.narrow(swapped(cond), a)?);
+        let mut refined = self.clone();
+        for (op, value) in [(&cmp.src1, a_now), (&cmp.src2, b_now)] {
+            if let Some(Operand::Reg(r)) = op {
+                refined.set(*r, value);
+            }
+        }
+        Some(refined)
+    }
+}
+
+/// The result of `analyze`
+#[derive(Debug, Clone)]
+pub struct Ranges {
+    /// State before each instruction (None: unreachable)
+    before: Vec<Option<State>>,
+    /// State entering each loop from outside it; None if something outside
+    /// jumps past the header into the body
+    entries: BTreeMap<String, Option<State>>,
+}
+
+impl Ranges {
+    /// The values `op` may hold just before the instruction at `at` (None:
+    /// it is unreachable)
+    pub fn at(&self, at: usize, op: &Operand) -> Option<Interval> {
+        let state = self.before.get(at)?.as_ref()?;
+        Some(state.get(&Some(op.clone())))
+    }
+
+    /// Most times the loop headed by `label` runs its body, if bounded: a
+    /// constant trip count, or one bounded by the intervals of its start
+    /// and bound on entry
+    pub fn max_trip_count(&self, func: &Function, label: &str) -> Option<u64> {
+        let induction = func.loops.get(label)?.induction.as_ref()?;
+        if let Some(trips) = induction.trip_count {
+            return Some(trips);
+        }
+        let entry = self.entries.get(label)?.as_ref()?;
+        let start = entry.get(&Some(induction.var.clone()));
+        let bound = entry.get(&Some(induction.bound.clone()));
+        // `==` and `!=` exits aren't monotonic in the start and bound:
+        // between the corners, a step over 1 may skip past the bound
+        let monotonic = !matches!(induction.exit_when, Cond::Eq | Cond::Ne);
+        let single = start.lo == start.hi && bound.lo == bound.hi;
+        if !monotonic && induction.step.abs() != 1 && !single {
+            return None;
+        }
+        let corners = [
+            (start.lo, bound.lo),
+            (start.lo, bound.hi),
+            (start.hi, bound.lo),
+            (start.hi, bound.hi),
+        ];
+        corners
+            .into_iter()
+            .map(|(s, b)| loops::trip_count(s, induction.step, induction.exit_when, b))
+            .collect::<Option<Vec<u64>>>()?
+            .into_iter()
+            .max()
+    }
+
+    /// The `Alloc` a `Load` or `Store` at `at` provably accesses inside of,
+    /// if any: its base holds that allocation, not freed since, and every
+    /// element the index can reach lies within its smallest size
+    pub fn in_bounds(&self, func: &Function, at: usize) -> Option<usize> {
+        let instr = func.instructions.get(at)?;
+        let (base, index) = match instr.op {
+            Opcode::Load(_) => (&instr.src1, &instr.src2),
+            Opcode::Store(_) => (&instr.dest, &instr.src1),
+            _ => return None,
+        };
+        let state = self.before.get(at)?.as_ref()?;
+        let Some(Operand::Reg(base)) = base else {
+            return None;
+        };
+        let alloc = state.allocs.get(base)?;
+        let disp = Interval::point(instr.op.displacement()? as i64);
+        let element = state.get(index).add(disp);
+        let end = element.hi.checked_add(1)?.checked_mul(8)?;
+        (element.lo >= 0 && end <= alloc.min_bytes).then_some(alloc.site)
+    }
+}
+
+/// Intervals and live allocations throughout `func`, whose `loops` must be
+/// current
+pub fn analyze(func: &Function) -> Ranges {
+    let instrs = &func.instructions;
+    let labels = label_indices(instrs);
+    let heads: BTreeMap<usize, &str> = func
+        .loops
+        .iter()
+        .map(|(label, l)| (l.head, label.as_str()))
+        .collect();
+    let mut before: Vec<Option<State>> = vec![None; instrs.len()];
+    let mut visits = vec![0u32; instrs.len()];
+    let mut work = VecDeque::new();
+    if !instrs.is_empty() {
+        before[0] = Some(State::default());
+        work.push_back(0);
+    }
+
+    while let Some(at) = work.pop_front() {
+        let Some(mut state) = before[at].clone() else {
+            continue;
+        };
+        state.transfer(at, &instrs[at]);
+        for (to, incoming) in edges(instrs, &labels, at, &state) {
+            let merged = match &before[to] {
+                None => incoming,
+                Some(old) => {
+                    let joined = old.join(&incoming);
+                    visits[to] += 1;
+                    // Every cycle passes a loop header
+                    if heads.contains_key(&to) && visits[to] > WIDEN_AFTER {
+                        old.widen(&joined)
+                    } else {
+                        joined
+                    }
+                }
+            };
+            let merged = match heads.get(&to) {
+                Some(label) => pin_induction(func, label, merged),
+                None => merged,
+            };
+            if before[to].as_ref() != Some(&merged) {
+                before[to] = Some(merged);
+                work.push_back(to);
+            }
+        }
+    }
+
+    // What flows into each loop from outside it
+    let mut entries: BTreeMap<String, Option<State>> = BTreeMap::new();
+    for (at, state) in before.iter().enumerate() {
+        let Some(mut state) = state.clone() else {
+            continue;
+        };
+        state.transfer(at, &instrs[at]);
+        for (to, incoming) in edges(instrs, &labels, at, &state) {
+            for (label, l) in &func.loops {
+                if (l.head..=l.back_jump).contains(&at) || !(l.head..=l.back_jump).contains(&to) {
+                    continue;
+                }
+                let entry = entries.entry(label.clone()).or_insert_with(|| Some(incoming.clone()));
+                *entry = match entry.take() {
+                    Some(old) if to == l.head => Some(old.join(&incoming)),
+                    _ => None,
+                };
+            }
+        }
+    }
+    Ranges { before, entries }
+}
+
+/// Where control goes after the instruction at `at`, with `after` the
+/// state it leaves
+fn edges(
+    instrs: &[Instruction],
+    labels: &BTreeMap<&str, usize>,
+    at: usize,
+    after: &State,
+) -> Vec<(usize, State)> {
+    let instr = &instrs[at];
+    let mut out = Vec::new();
+    // A conditional jump right after its compare narrows both ways
+    let cmp = at
+        .checked_sub(1)
+        .map(|prev| &instrs[prev])
+        .filter(|prev| prev.op == Opcode::Cmp);
+    let (taken, fallthrough) = match (Cond::of_jump(&instr.op), cmp) {
+        (Some(cond), Some(cmp)) => (after.refine(cmp, cond), after.refine(cmp, cond.negate())),
+        _ => (Some(after.clone()), Some(after.clone())),
+    };
+    if let Some(taken) = taken {
+        for target in instr.jump_targets() {
+            if let Some(&to) = labels.get(target) {
+                out.push((to, taken.clone()));
+            }
+        }
+    }
+    if let (Some(fallthrough), false) = (fallthrough, instr.ends_block()) {
+        if at + 1 < instrs.len() {
+            out.push((at + 1, fallthrough));
+        }
+    }
+    out
+}
+
+/// `state` at the header of the loop `label`, with a constant trip count
+/// pinning its induction variable to the values it takes there
+fn pin_induction(func: &Function, label: &str, mut state: State) -> State {
+    let Some(induction) = func.loops[label].induction.as_ref() else {
+        return state;
+    };
+    let (Some(start), Some(trips), Operand::Reg(var)) =
+        (induction.start, induction.trip_count, &induction.var)
+    else {
+        return state;
+    };
+    let Some(last) = (trips as i64)
+        .checked_mul(induction.step)
+        .and_then(|distance| start.checked_add(distance))
+    else {
+        return state;
+    };
+    let taken = Interval {
+        lo: start.min(last),
+        hi: start.max(last),
+    };
+    if let Some(pinned) = state.get(&Some(induction.var.clone())).meet(taken) {
+        state.set(*var, pinned);
+    }
+    state
+}
+
+/// Loops that may skip the backend's per-iteration fuel check: those that
+/// provably stop within `MAX_UNFUELED_TRIPS` iterations, and within `fuel`
+/// (the budget the function runs with), times the iterations of the
+/// unfueled loops around them (a loop around that keeps its check burns
+/// fuel for every run of the inner one)
+pub fn unfueled_loops(func: &Function, ranges: &Ranges, fuel: u64) -> BTreeSet<String> {
+    let max_trips = MAX_UNFUELED_TRIPS.min(fuel);
+    let mut by_head: Vec<(&String, &loops::LoopInfo)> = func.loops.iter().collect();
+    by_head.sort_by_key(|(_, l)| l.head);
+    // Iterations per unit of fuel, by loop, outermost first
+    let mut unfueled: BTreeMap<&str, u64> = BTreeMap::new();
+    for &(label, info) in &by_head {
+        let Some(trips) = ranges.max_trip_count(func, label) else {
+            continue;
+        };
+        let around = by_head
+            .iter()
+            .filter(|(_, outer)| outer.head < info.head && info.back_jump <= outer.back_jump)
+            .max_by_key(|(_, outer)| outer.head)
+            .map_or(1, |(outer, _)| unfueled.get(outer.as_str()).copied().unwrap_or(1));
+        let total = trips.max(1).saturating_mul(around);
+        if total <= max_trips {
+            unfueled.insert(label, total);
+        }
+    }
+    unfueled.into_keys().map(str::to_string).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    fn main_of(src: &str) -> Function {
+        Parser::new().parse(src).unwrap().functions.remove(0)
+    }
+
+    /// Index of the first instruction with `op`
+    fn first(func: &Function, op: Opcode) -> usize {
+        func.instructions.iter().position(|i| i.op == op).unwrap()
+    }
+
+    #[test]
+    fn test_branches_narrow_and_loops_converge() {
+        let func = main_of(
+            "fn main(n) {
+                a = alloc(80)
+                i = 0
+                while i < 10 {
+                    a[i] = i
+                    i = i + 1
+                }
+                m = min(n, 100)
+                j = 0
+                label walk
+                if j == m goto done
+                j = j + 1
+                goto walk
+                label done
+                b = a[10]
+                free(a)
+                return j
+            }",
+        );
+        let ranges = analyze(&func);
+        let store = first(&func, Opcode::Store(0));
+        let i = func.instructions[store].src1.clone().unwrap();
+        assert_eq!(ranges.at(store, &i), Some(Interval { lo: 0, hi: 9 }));
+        assert!(ranges.in_bounds(&func, store).is_some());
+        // a[10] is past the 80 bytes
+        assert_eq!(ranges.in_bounds(&func, first(&func, Opcode::Load(0))), None);
+
+        // The while loop runs 10 times; the goto loop's bound is anywhere
+        // up to 100, but may be negative, which `==` never reaches
+        assert_eq!(ranges.max_trip_count(&func, "while_start_1"), Some(10));
+        assert_eq!(ranges.max_trip_count(&func, "walk"), None);
+        let unfueled = unfueled_loops(&func, &ranges, MAX_UNFUELED_TRIPS);
+        assert!(unfueled.contains("while_start_1"), "{:?}", unfueled);
+        assert!(!unfueled.contains("walk"));
+        // Not with less fuel than its trips
+        assert!(unfueled_loops(&func, &ranges, 9).is_empty());
+
+        let clamped = func_with_clamped_bound();
+        let ranges = analyze(&clamped);
+        assert_eq!(ranges.max_trip_count(&clamped, "walk"), Some(100));
+    }
+
+    fn func_with_clamped_bound() -> Function {
+        main_of(
+            "fn main(n) {
+                m = min(n, 100)
+                m = max(m, 0)
+                j = 0
+                label walk
+                if j == m goto done
+                j = j + 1
+                goto walk
+                label done
+                return j
+            }",
+        )
+    }
+
+    #[test]
+    fn test_frees_and_calls_end_allocations() {
+        let func = main_of(
+            "fn main(n) {
+                a = alloc(64)
+                a[0] = 1
+                free(a)
+                a[1] = 2
+                b = alloc(64)
+                b[2] = 3
+                c = peek(b)
+                b[3] = 4
+                return c
+            }
+            fn peek(p) {
+                v = p[0]
+                return v
+            }",
+        );
+        let ranges = analyze(&func);
+        let stores: Vec<usize> = (0..func.instructions.len())
+            .filter(|&at| matches!(func.instructions[at].op, Opcode::Store(_)))
+            .collect();
+        let checked: Vec<bool> =
+            stores.iter().map(|&at| ranges.in_bounds(&func, at).is_none()).collect();
+        assert_eq!(checked, [false, true, false, true]);
+    }
+
+    #[test]
+    fn test_nested_unfueled_loops_share_the_limit() {
+        let nest = |outer: u64, inner: u64| {
+            main_of(&format!(
+                "fn main() {{
+                    s = 0
+                    i = 0
+                    while i < {} {{
+                        j = 0
+                        while j < {} {{
+                            s = s + j
+                            j = j + 1
+                        }}
+                        i = i + 1
+                    }}
+                    return s
+                }}",
+                outer, inner
+            ))
+        };
+        let func = nest(100, 100);
+        let unfueled = unfueled_loops(&func, &analyze(&func), MAX_UNFUELED_TRIPS);
+        assert_eq!(unfueled.len(), 2, "{:?}", unfueled);
+
+        // The inner loop alone is fine; under the outer one it is too much
+        let func = nest(1000, 1000);
+        let unfueled = unfueled_loops(&func, &analyze(&func), MAX_UNFUELED_TRIPS);
+        assert_eq!(unfueled.len(), 1, "{:?}", unfueled);
+        let outer = func.loops.iter().min_by_key(|(_, l)| l.head).unwrap().0;
+        assert!(unfueled.contains(outer));
+    }
+}
@@ -0,0 +1,291 @@
+//! Optimization Report
+//!
+//! A record of the decisions made while compiling a program: which passes
+//! changed each function, which loops were unrolled, jammed or vectorized (and why
+//! the others weren't), which virtual registers spilled and how big the
+//! code came out. Diffing the reports of two versions of a script shows why
+//! an innocent-looking edit made the generated code worse.
+
+use crate::ir::{Function, Operand, Program};
+use crate::verifier;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// Loop transformation a `LoopDecision` is about
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transform {
+    Unroll,
+    Vectorize,
+    /// Unrolled copies merged into one body indexing at displacements
+    Jam,
+}
+
+impl Transform {
+    fn name(self) -> &'static str {
+        match self {
+            Transform::Unroll => "unroll",
+            Transform::Vectorize => "vectorize",
+            Transform::Jam => "jam",
+        }
+    }
+}
+
+/// Whether a loop got a transformation, and why (not)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LoopDecision {
+    /// Label of the loop header
+    pub label: String,
+    pub transform: Transform,
+    /// How many times it was applied (0: rejected)
+    pub applied: usize,
+    pub reason: String,
+}
+
+/// A loop whose trip count is known, and whether it got a version
+/// specialized to it (`specialize::specialize_trip_counts`)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Specialization {
+    /// Label of the loop header
+    pub label: String,
+    pub trip_count: u64,
+    /// Argument the count was profiled from, checked at runtime before the
+    /// specialized version runs (None: a compile-time constant)
+    pub guarded_arg: Option<usize>,
+    pub applied: bool,
+    /// The version generated, or why there is none
+    pub version: String,
+}
+
+/// Decisions made for one function
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct FunctionReport {
+    pub name: String,
+    /// Level the function was optimized at: the program's unless
+    /// `#pragma opt(N)` overrides it
+    pub opt_level: u8,
+    /// Number of times each pass changed the function, by pass name
+    pub passes: BTreeMap<String, usize>,
+    pub loops: Vec<LoopDecision>,
+    /// Specialization candidates, in the order they were found
+    pub specializations: Vec<Specialization>,
+    /// Virtual registers left without a machine register
+    pub spilled: Vec<String>,
+    /// General-purpose virtual registers given one, precolored ones included
+    pub allocated: Vec<String>,
+    /// Bytes of machine code, alignment padding included
+    pub code_size: usize,
+}
+
+impl FunctionReport {
+    /// Count a run of `pass` if it `changed` `func`; returns `changed`.
+    ///
+    /// Debug builds also re-run the verifier on the changed function, which
+    /// re-infers its register types from scratch: a pass that renumbers or
+    /// rewrites registers into an ill-typed function fails here, naming the
+    /// pass, rather than somewhere in the backend.
+    pub fn note(&mut self, pass: &str, changed: bool, func: &Function) -> bool {
+        if changed {
+            *self.passes.entry(pass.to_string()).or_default() += 1;
+            if cfg!(debug_assertions) {
+                let errors = verifier::verify_function(func, None);
+                assert!(errors.is_empty(), "{} left invalid IR:\n{}", pass, errors.join("\n"));
+            }
+        }
+        changed
+    }
+
+    /// Record a decision about `label`. Passes rerun until nothing changes,
+    /// so they see the same loop many times: applications are counted and
+    /// override rejections, and otherwise the first rejection sticks.
+    pub fn decide(&mut self, label: &str, transform: Transform, applied: bool, reason: String) {
+        match self
+            .loops
+            .iter_mut()
+            .find(|d| d.label == label && d.transform == transform)
+        {
+            Some(decision) if applied => {
+                if decision.applied == 0 {
+                    decision.reason = reason;
+                }
+                decision.applied += 1;
+            }
+            Some(_) => {}
+            None => self.loops.push(LoopDecision {
+                label: label.to_string(),
+                transform,
+                applied: applied as usize,
+                reason,
+            }),
+        }
+    }
+
+    /// Record a specialization candidate. As with `decide`, an application
+    /// overrides a rejection and otherwise the first record sticks.
+    pub fn specialize(&mut self, candidate: Specialization) {
+        match self.specializations.iter_mut().find(|s| s.label == candidate.label) {
+            Some(existing) if candidate.applied && !existing.applied => *existing = candidate,
+            Some(_) => {}
+            None => self.specializations.push(candidate),
+        }
+    }
+
+    /// Record `operand` as spilled
+    pub fn spill(&mut self, operand: &Operand) {
+        self.spilled.push(match operand {
+            Operand::Reg(r) => format!("r{}", r),
+            Operand::Ymm(r) => format!("ymm{}", r),
+            other => format!("{:?}", other),
+        });
+    }
+}
+
+/// Everything `Compiler::compile_with_options` decided, per function
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct OptimizationReport {
+    pub opt_level: u8,
+    /// Functions in program order
+    pub functions: Vec<FunctionReport>,
+    /// Bytes of machine code for the whole program
+    pub code_size: usize,
+}
+
+impl OptimizationReport {
+    /// An empty report with one entry per function of `prog`
+    pub fn new(prog: &Program, opt_level: u8) -> Self {
+        Self {
+            opt_level,
+            functions: prog
+                .functions
+                .iter()
+                .map(|f| FunctionReport {
+                    name: f.name.clone(),
+                    opt_level: f.hints.opt_level.unwrap_or(opt_level),
+                    ..Default::default()
+                })
+                .collect(),
+            code_size: 0,
+        }
+    }
+
+    pub fn function(&self, name: &str) -> Option<&FunctionReport> {
+        self.functions.iter().find(|f| f.name == name)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("report serializes")
+    }
+
+    pub fn to_markdown(&self) -> String {
+        let mut out = format!(
+            "# Optimization report (-O{})\n\nTotal code size: {} bytes\n",
+            self.opt_level, self.code_size
+        );
+        for f in &self.functions {
+            let level = match f.opt_level {
+                level if level == self.opt_level => String::new(),
+                level => format!(", -O{}", level),
+            };
+            let _ = write!(out, "\n## fn {} ({} bytes{})\n\n", f.name, f.code_size, level);
+            if f.passes.is_empty() {
+                out.push_str("No pass changed this function.\n");
+            } else {
+                out.push_str("| Pass | Changes |\n|---|---|\n");
+                for (pass, count) in &f.passes {
+                    let _ = writeln!(out, "| {} | {} |", pass, count);
+                }
+            }
+            if !f.loops.is_empty() {
+                out.push_str("\n| Loop | Transform | Applied | Reason |\n|---|---|---|---|\n");
+                for d in &f.loops {
+                    let applied = match d.applied {
+                        0 => "no".to_string(),
+                        1 => "yes".to_string(),
+                        n => format!("yes (x{})", n),
+                    };
+                    let _ = writeln!(
+                        out,
+                        "| {} | {} | {} | {} |",
+                        d.label,
+                        d.transform.name(),
+                        applied,
+                        d.reason
+                    );
+                }
+            }
+            if !f.specializations.is_empty() {
+                out.push_str("\n| Loop | Trip count | Known from | Specialized | Version |\n");
+                out.push_str("|---|---|---|---|---|\n");
+                for c in &f.specializations {
+                    let known = match c.guarded_arg {
+                        Some(arg) => format!("arg {} (profiled, guarded)", arg),
+                        None => "constant".to_string(),
+                    };
+                    let applied = if c.applied { "yes" } else { "no" };
+                    let _ = writeln!(
+                        out,
+                        "| {} | {} | {} | {} | {} |",
+                        c.label, c.trip_count, known, applied, c.version
+                    );
+                }
+            }
+            let spilled = if f.spilled.is_empty() {
+                "none".to_string()
+            } else {
+                f.spilled.join(", ")
+            };
+            let _ = write!(out, "\nSpilled: {}\n", spilled);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_applications_override_rejections() {
+        let mut f = FunctionReport::default();
+        f.decide("l", Transform::Unroll, true, "small body".into());
+        f.decide("l", Transform::Unroll, true, "small body".into());
+        f.decide("l", Transform::Unroll, false, "too big".into());
+        f.decide("l", Transform::Vectorize, false, "no loads".into());
+        f.decide("l", Transform::Vectorize, false, "no store".into());
+        assert_eq!(f.loops.len(), 2);
+        assert_eq!(
+            (f.loops[0].applied, f.loops[0].reason.as_str()),
+            (2, "small body")
+        );
+        assert_eq!(
+            (f.loops[1].applied, f.loops[1].reason.as_str()),
+            (0, "no loads")
+        );
+
+        f.decide("l", Transform::Vectorize, true, "elementwise add".into());
+        assert_eq!(
+            (f.loops[1].applied, f.loops[1].reason.as_str()),
+            (1, "elementwise add")
+        );
+    }
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "rewrite left invalid IR")]
+    fn test_note_retypes_after_each_pass() {
+        use crate::ir::Opcode;
+
+        let prog = crate::parser::Parser::new()
+            .parse("fn main() {\n a = alloc(8)\n a[0] = 1\n return 0\n}")
+            .unwrap();
+        let mut func = prog.functions[0].clone();
+        let mut f = FunctionReport::default();
+        assert!(!f.note("rewrite", false, &func));
+
+        // A pass that turns the array's register into a plain integer
+        let alloc = func.instructions.iter_mut().find(|i| i.op == Opcode::Alloc).unwrap();
+        alloc.op = Opcode::Mov;
+        alloc.src1 = Some(Operand::Imm(5));
+        f.note("rewrite", true, &func);
+    }
+}
@@ -0,0 +1,317 @@
+//! Instruction Scheduling
+//!
+//! List scheduling within basic blocks. The parser emits instructions in
+//! source order, so a multiply or load is often followed straight away by
+//! its consumer; this pass builds each block's dependency graph and reorders
+//! independent instructions to fill those latency gaps, using the latency
+//! and issue-width table of the detected micro-architecture.
+//!
+//! Only straight-line runs of register arithmetic and memory accesses are
+//! reordered. Labels, jumps, calls, argument moves, allocation and anything
+//! that sets or reads flags stay where they are and split the runs.
+
+use crate::target::Microarch;
+use crate::ir::{Function, Instruction, Opcode, Operand, Program};
+
+/// Result latency of `op` in cycles
+pub fn latency(uarch: Microarch, op: &Opcode) -> u32 {
+    match (op, uarch) {
+        (Opcode::Mul, Microarch::IntelAtom) => 4,
+        (Opcode::Mul, _) => 3,
+        (Opcode::Load(_), Microarch::IntelCore | Microarch::IntelAtom) => 5,
+        (Opcode::Load(_), _) => 4,
+        (Opcode::VLoad(..), Microarch::Zen) => 8,
+        (Opcode::VLoad(..), _) => 7,
+        (Opcode::VAdd, Microarch::IntelAtom) => 2,
+        (Opcode::Popcnt | Opcode::Ctz | Opcode::Clz, Microarch::Zen) => 1,
+        (Opcode::Popcnt | Opcode::Ctz | Opcode::Clz, _) => 3,
+        // cmp (or neg) feeding a cmov
+        (Opcode::Min | Opcode::Max | Opcode::Abs, _) => 2,
+        _ => 1,
+    }
+}
+
+/// Independent instructions started per cycle
+pub fn issue_width(uarch: Microarch) -> usize {
+    match uarch {
+        Microarch::IntelCore | Microarch::Zen => 4,
+        Microarch::IntelAtom | Microarch::Generic => 2,
+    }
+}
+
+/// Schedule every function; true if any instruction moved
+pub fn schedule_program(prog: &mut Program, uarch: Microarch) -> bool {
+    let mut changed = false;
+    for func in &mut prog.functions {
+        changed |= schedule_function(func, uarch);
+    }
+    changed
+}
+
+/// Reorder each straight-line run in `func`; true if any instruction moved
+pub fn schedule_function(func: &mut Function, uarch: Microarch) -> bool {
+    let mut changed = false;
+    let mut start = 0;
+    for i in 0..=func.instructions.len() {
+        if i < func.instructions.len() && is_schedulable(&func.instructions[i].op) {
+            continue;
+        }
+        if i - start > 1 {
+            let region = &mut func.instructions[start..i];
+            let order = list_schedule(region, uarch);
+            if order.iter().enumerate().any(|(pos, &idx)| pos != idx) {
+                let scheduled: Vec<Instruction> =
+                    order.iter().map(|&idx| region[idx].clone()).collect();
+                region.clone_from_slice(&scheduled);
+                changed = true;
+            }
+        }
+        start = i + 1;
+    }
+    changed
+}
+
+/// Cycles to run `instrs` in order on an in-order machine of `uarch`'s
+/// width and latencies (how the scheduler scores a block)
+pub fn estimate_cycles(instrs: &[Instruction], uarch: Microarch) -> u32 {
+    let graph = DepGraph::build(instrs, uarch);
+    let mut ready_at = vec![0u32; instrs.len()];
+    let (mut cycle, mut issued) = (0, 0);
+    for i in 0..instrs.len() {
+        let earliest = graph.preds[i]
+            .iter()
+            .map(|&(p, lat)| ready_at[p] + lat)
+            .max()
+            .unwrap_or(0);
+        if earliest > cycle || issued == issue_width(uarch) {
+            cycle = earliest.max(cycle + 1);
+            issued = 0;
+        }
+        ready_at[i] = cycle;
+        issued += 1;
+    }
+    instrs
+        .iter()
+        .enumerate()
+        .map(|(i, instr)| ready_at[i] + latency(uarch, &instr.op))
+        .max()
+        .unwrap_or(0)
+}
+
+/// Instructions that may move within a run
+pub fn is_schedulable(op: &Opcode) -> bool {
+    matches!(
+        op,
+        Opcode::Mov
+            | Opcode::Add
+            | Opcode::Sub
+            | Opcode::Mul
+            | Opcode::Load(_)
+            | Opcode::Store(_)
+            | Opcode::VLoad(..)
+            | Opcode::VStore(..)
+            | Opcode::PrefetchT0
+            | Opcode::VAdd
+            | Opcode::VMin
+            | Opcode::VMax
+            | Opcode::VAbs
+            | Opcode::Popcnt
+            | Opcode::Ctz
+            | Opcode::Clz
+            | Opcode::Min
+            | Opcode::Max
+            | Opcode::Abs
+    )
+}
+
+/// Registers read and written by a schedulable instruction
+fn reads_writes(instr: &Instruction) -> (Vec<&Operand>, Vec<&Operand>) {
+    match instr.op {
+        // Two-operand arithmetic: dest is also a source
+        Opcode::Add | Opcode::Sub | Opcode::Mul | Opcode::Min | Opcode::Max => {
+            (regs(&[&instr.dest, &instr.src1]), regs(&[&instr.dest]))
+        }
+        Opcode::Store(_) | Opcode::VStore(..) | Opcode::PrefetchT0 => {
+            (regs(&[&instr.dest, &instr.src1, &instr.src2]), vec![])
+        }
+        _ => (regs(&[&instr.src1, &instr.src2]), regs(&[&instr.dest])),
+    }
+}
+
+fn regs<'a>(ops: &[&'a Option<Operand>]) -> Vec<&'a Operand> {
+    ops.iter()
+        .filter_map(|o| o.as_ref())
+        .filter(|o| matches!(o, Operand::Reg(_) | Operand::Ymm(_)))
+        .collect()
+}
+
+struct DepGraph {
+    /// (predecessor, cycles it must issue before this instruction)
+    preds: Vec<Vec<(usize, u32)>>,
+    succs: Vec<Vec<(usize, u32)>>,
+}
+
+impl DepGraph {
+    fn build(instrs: &[Instruction], uarch: Microarch) -> Self {
+        let n = instrs.len();
+        let mut graph = DepGraph {
+            preds: vec![Vec::new(); n],
+            succs: vec![Vec::new(); n],
+        };
+        let accesses: Vec<_> = instrs.iter().map(reads_writes).collect();
+        let is_store = |i: usize| matches!(instrs[i].op, Opcode::Store(_) | Opcode::VStore(..));
+        let is_load = |i: usize| matches!(instrs[i].op, Opcode::Load(_) | Opcode::VLoad(..));
+        for j in 0..n {
+            let (reads_j, writes_j) = &accesses[j];
+            for i in 0..j {
+                let (reads_i, writes_i) = &accesses[i];
+                let raw = writes_i.iter().any(|r| reads_j.contains(r));
+                let waw = writes_i.iter().any(|r| writes_j.contains(r));
+                let war = reads_i.iter().any(|r| writes_j.contains(r));
+                // Memory isn't disambiguated: stores stay ordered against every access
+                let mem_raw = is_store(i) && is_load(j);
+                let mem_order = (is_store(i) && is_store(j)) || (is_load(i) && is_store(j));
+                let lat = if raw {
+                    latency(uarch, &instrs[i].op)
+                } else if mem_raw || waw {
+                    1
+                } else if war || mem_order {
+                    0
+                } else {
+                    continue;
+                };
+                graph.preds[j].push((i, lat));
+                graph.succs[i].push((j, lat));
+            }
+        }
+        graph
+    }
+
+    /// Latency-weighted length of the longest path from each instruction
+    /// to the end of the block
+    fn heights(&self, instrs: &[Instruction], uarch: Microarch) -> Vec<u32> {
+        let mut heights = vec![0; instrs.len()];
+        for i in (0..instrs.len()).rev() {
+            heights[i] = self.succs[i]
+                .iter()
+                .map(|&(s, lat)| lat + heights[s])
+                .max()
+                .unwrap_or(0)
+                .max(latency(uarch, &instrs[i].op));
+        }
+        heights
+    }
+}
+
+/// New order of `instrs`, as indices into it
+fn list_schedule(instrs: &[Instruction], uarch: Microarch) -> Vec<usize> {
+    let n = instrs.len();
+    let graph = DepGraph::build(instrs, uarch);
+    let heights = graph.heights(instrs, uarch);
+    let mut unscheduled_preds: Vec<usize> = graph.preds.iter().map(Vec::len).collect();
+    let mut earliest = vec![0u32; n];
+    let mut ready: Vec<usize> = (0..n).filter(|&i| unscheduled_preds[i] == 0).collect();
+    let mut order = Vec::with_capacity(n);
+    let mut cycle = 0;
+
+    while order.len() < n {
+        let mut issued = 0;
+        while issued < issue_width(uarch) {
+            // Longest remaining path first; source order breaks ties
+            let Some(pos) = ready
+                .iter()
+                .enumerate()
+                .filter(|&(_, &i)| earliest[i] <= cycle)
+                .max_by_key(|&(_, &i)| (heights[i], std::cmp::Reverse(i)))
+                .map(|(pos, _)| pos)
+            else {
+                break;
+            };
+            let i = ready.swap_remove(pos);
+            order.push(i);
+            issued += 1;
+            for &(s, lat) in &graph.succs[i] {
+                earliest[s] = earliest[s].max(cycle + lat);
+                unscheduled_preds[s] -= 1;
+                if unscheduled_preds[s] == 0 {
+                    ready.push(s);
+                }
+            }
+        }
+        cycle += 1;
+    }
+    order
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    fn instr(op: Opcode, dest: Operand, src1: Operand) -> Instruction {
+        Instruction {
+            op,
+            dest: Some(dest),
+            src1: Some(src1),
+            src2: None,
+        }
+    }
+
+    #[test]
+    fn test_independent_work_fills_multiply_latency() {
+        use Operand::{Imm, Reg};
+        // Two dependent multiply chains written one after the other
+        let mut func = Function::new("f", vec![]);
+        func.instructions = vec![
+            instr(Opcode::Mov, Reg(1), Imm(3)),
+            instr(Opcode::Mul, Reg(1), Reg(1)),
+            instr(Opcode::Mul, Reg(1), Reg(1)),
+            instr(Opcode::Mov, Reg(2), Imm(5)),
+            instr(Opcode::Mul, Reg(2), Reg(2)),
+            instr(Opcode::Mul, Reg(2), Reg(2)),
+            instr(Opcode::Add, Reg(1), Reg(2)),
+        ];
+        let before = estimate_cycles(&func.instructions, Microarch::Generic);
+        assert!(schedule_function(&mut func, Microarch::Generic));
+        let after = estimate_cycles(&func.instructions, Microarch::Generic);
+        assert!(after < before, "{} -> {} cycles", before, after);
+
+        // The chains interleave, each in its own order, and the sum stays last
+        let ops: Vec<String> = func.instructions.iter().map(|i| i.to_string()).collect();
+        assert_eq!(ops[0..2], ["Mov Reg(1), Imm(3)", "Mov Reg(2), Imm(5)"]);
+        assert_eq!(ops[6], "Add Reg(1), Reg(2)");
+        assert!(!schedule_function(&mut func, Microarch::Generic));
+    }
+
+    #[test]
+    fn test_barriers_and_memory_order_are_kept() {
+        let source = "
+            fn main(n) {
+                a = alloc(2)
+                a[0] = n
+                x = a[0]
+                y = n * n
+                if x > 3 goto skip
+                y = y + x
+                skip:
+                free(a)
+                return y
+            }
+        ";
+        let prog = Parser::new().parse(source).unwrap();
+        let original = prog.functions[0].instructions.clone();
+        let mut func = prog.functions[0].clone();
+        schedule_function(&mut func, Microarch::IntelCore);
+
+        let position = |op: Opcode| func.instructions.iter().position(|i| i.op == op).unwrap();
+        assert!(position(Opcode::Store(0)) < position(Opcode::Load(0)));
+        // Non-movable instructions keep their exact indices
+        for (idx, instr) in original.iter().enumerate() {
+            if !is_schedulable(&instr.op) {
+                assert_eq!(&func.instructions[idx], instr);
+            }
+        }
+        // Independent work from `y = n * n` fills the store-to-load gap
+        assert!(position(Opcode::Load(0)) > position(Opcode::Store(0)) + 1);
+    }
+}
@@ -0,0 +1,505 @@
+//! Trip-Count Specialization
+//!
+//! A counted loop whose trip count is known, either a compile-time constant
+//! or set by an argument profiled to be nearly constant
+//! (`Hints::expected_args`), gets a version for exactly that count:
+//!
+//! - fully unrolled, when N copies of the body stay small: no test at all
+//! - otherwise remainder-free: U copies of the body per test, U dividing N
+//!
+//! A constant count can't change, so that version replaces the loop. A
+//! profiled one can: the version sits behind a check of the argument, and
+//! any other value falls back to the generic loop, which is kept:
+//!
+//! ```text
+//!         cmp n, 16; jne L
+//!         body x16                        (or L_spec: cmp i, 16; jcc exit;
+//!         jmp exit                                    body xU; jmp L_spec)
+//! L:      cmp i, n; jcc exit; body; jmp L
+//! ```
+//!
+//! Runs after the vectorizer, which gets first pick of the loops, and
+//! before the unroller, which leaves specialized loops alone.
+//!
+//! # Constant Arguments
+//!
+//! Arguments profiled to be nearly constant can instead be set as
+//! `Hints::constant_args`, to specialize the whole function:
+//! `specialize_constant_args` puts a copy of the body with the values in
+//! place of the arguments behind one compare per argument, so every loop
+//! they bound gets a constant trip count and the passes after it fold,
+//! unroll and vectorize the copy as if the values were written in the
+//! source. Any other value runs the generic body, which is kept:
+//!
+//! ```text
+//!         n = arg 0; cmp n, 1000; jne generic
+//!         n = 1000; body'                 (labels renamed)
+//! generic:
+//!         body
+//! ```
+
+use crate::ir::{Function, Instruction, Opcode, Operand};
+use crate::loops::{self, writes, Induction, LoopInfo};
+use crate::report::{FunctionReport, Specialization};
+use std::collections::{BTreeMap, VecDeque};
+
+/// Most instructions the body copies of a specialized loop may take
+pub const MAX_COPIED: u64 = 64;
+
+/// Copies per test tried for a remainder-free version, largest first
+const FACTORS: [u64; 3] = [8, 4, 2];
+
+/// Share of the recent calls an argument value needs to be expected
+pub const NEARLY_CONSTANT: f64 = 0.9;
+
+/// Calls an `ArgProfile` remembers
+const PROFILE_CALLS: usize = 64;
+
+/// Calls needed before any value is expected
+const MIN_PROFILED_CALLS: usize = 4;
+
+/// Most instructions a function may have to get a constant-argument copy
+pub const MAX_CLONED: usize = 512;
+
+enum Version {
+    Unrolled,
+    RemainderFree(u64),
+}
+
+/// Specialize the first eligible loop of `func` and record every candidate
+/// seen on the way in `report`; true if a loop was specialized. The
+/// optimizer reruns its passes until nothing changes, which reaches the
+/// rest.
+pub fn specialize_trip_counts(func: &mut Function, report: &mut FunctionReport) -> bool {
+    let mut candidates: Vec<(String, LoopInfo)> = func
+        .loops
+        .iter()
+        .filter(|(_, l)| func.instructions[l.back_jump].op == Opcode::Jmp)
+        .map(|(label, l)| (label.clone(), l.clone()))
+        .collect();
+    candidates.sort_by_key(|(_, l)| l.head);
+
+    for (label, info) in &candidates {
+        let Some(induction) = &info.induction else {
+            continue;
+        };
+        let Some((trips, guard)) = known_trip_count(func, induction) else {
+            continue;
+        };
+        let mut candidate = Specialization {
+            label: label.clone(),
+            trip_count: trips,
+            guarded_arg: guard.map(|(arg, _)| arg),
+            applied: false,
+            version: String::new(),
+        };
+        match plan(func, label, info, induction, trips) {
+            Err(reason) => {
+                candidate.version = reason;
+                report.specialize(candidate);
+            }
+            Ok(version) => {
+                candidate.applied = true;
+                candidate.version = match version {
+                    Version::Unrolled => "fully unrolled".to_string(),
+                    Version::RemainderFree(u) => format!("remainder-free, {} copies per test", u),
+                };
+                report.specialize(candidate);
+                apply(func, label, info, induction, trips, guard, version);
+                loops::annotate(func);
+                return true;
+            }
+        }
+    }
+    false
+}
+
+/// The loop's trip count, and the argument and value it was profiled at
+/// unless it is a compile-time constant
+fn known_trip_count(func: &Function, induction: &Induction) -> Option<(u64, Option<(usize, i32)>)> {
+    if let Some(trips) = induction.trip_count {
+        return Some((trips, None));
+    }
+    let start = induction.start?;
+    let arg = arg_in(func, &induction.bound)?;
+    let &value = func.hints.expected_args.get(&arg)?;
+    let trips = loops::trip_count(start, induction.step, induction.exit_when, value as i64)?;
+    Some((trips, Some((arg, value))))
+}
+
+/// The argument `op` holds throughout `func`: only its `LoadArg` writes it
+fn arg_in(func: &Function, op: &Operand) -> Option<usize> {
+    let mut writers = func.instructions.iter().filter(|i| writes(i) == Some(op));
+    match (writers.next().map(|i| &i.op), writers.next()) {
+        (Some(Opcode::LoadArg(arg)), None) => Some(*arg),
+        _ => None,
+    }
+}
+
+fn plan(
+    func: &Function,
+    label: &str,
+    info: &LoopInfo,
+    induction: &Induction,
+    trips: u64,
+) -> Result<Version, String> {
+    // A pinned factor is the user's call (and `unroll(1)` marks loops
+    // already specialized or pipelined)
+    if let Some(n) = func.hints_for_loop(label).unroll {
+        return Err(format!("#pragma unroll({})", n));
+    }
+    let body = &func.instructions[induction.body_start..info.back_jump];
+    if body
+        .iter()
+        .any(|i| i.op == Opcode::Label || i.ends_block() || !i.jump_targets().is_empty())
+    {
+        return Err("the body branches".to_string());
+    }
+    let len = body.len() as u64;
+    if trips.saturating_mul(len) <= MAX_COPIED {
+        return Ok(Version::Unrolled);
+    }
+    let fits = |u: u64| trips.is_multiple_of(u) && u * len <= MAX_COPIED;
+    if let Some(u) = FACTORS.into_iter().find(|&u| fits(u)) {
+        return Ok(Version::RemainderFree(u));
+    }
+    Err(if FACTORS[FACTORS.len() - 1] * len > MAX_COPIED {
+        format!("body of {} instructions is too long to copy", len)
+    } else {
+        format!("no factor of {:?} divides {} trips", FACTORS, trips)
+    })
+}
+
+fn apply(
+    func: &mut Function,
+    label: &str,
+    info: &LoopInfo,
+    induction: &Induction,
+    trips: u64,
+    guard: Option<(usize, i32)>,
+    version: Version,
+) {
+    let instrs = &func.instructions;
+    let body = &instrs[induction.body_start..info.back_jump];
+    let copies = |n: u64| -> Vec<Instruction> {
+        body.iter().cycle().take(body.len() * n as usize).cloned().collect()
+    };
+    let exit = instrs[induction.exit_jump].dest.clone();
+    let jump = |op: Opcode, dest: Option<Operand>| Instruction {
+        op,
+        dest,
+        src1: None,
+        src2: None,
+    };
+
+    let Some((_, value)) = guard else {
+        match version {
+            Version::Unrolled => {
+                let mut code = copies(trips);
+                code.push(jump(Opcode::Jmp, exit));
+                func.instructions.splice(info.head + 1..=info.back_jump, code);
+            }
+            Version::RemainderFree(u) => {
+                let code = copies(u - 1);
+                func.instructions.splice(info.back_jump..info.back_jump, code);
+                func.loop_hints.entry(label.to_string()).or_default().unroll = Some(1);
+            }
+        }
+        return;
+    };
+
+    let mut code = vec![
+        Instruction {
+            op: Opcode::Cmp,
+            dest: None,
+            src1: Some(induction.bound.clone()),
+            src2: Some(Operand::Imm(value)),
+        },
+        jump(Opcode::Jne, Some(Operand::Label(label.to_string()))),
+    ];
+    let mut spec_label = None;
+    match version {
+        Version::Unrolled => {
+            code.extend(copies(trips));
+            code.push(jump(Opcode::Jmp, exit));
+        }
+        Version::RemainderFree(u) => {
+            // The header test again, against the value the guard checked,
+            // with its own labels
+            let header = &instrs[induction.test..induction.body_start];
+            let inner: Vec<&Operand> = header
+                .iter()
+                .filter(|i| i.op == Opcode::Label)
+                .filter_map(|i| i.dest.as_ref())
+                .collect();
+            let spec = format!("{}_spec", label);
+            code.push(jump(Opcode::Label, Some(Operand::Label(spec.clone()))));
+            for instr in header {
+                let mut instr = instr.clone();
+                if let Some(Operand::Label(name)) = &mut instr.dest {
+                    if inner.iter().any(|l| **l == Operand::Label(name.clone())) {
+                        *name = format!("{}_spec", name);
+                    }
+                }
+                if instr.op == Opcode::Cmp && instr.src2.as_ref() == Some(&induction.bound) {
+                    instr.src2 = Some(Operand::Imm(value));
+                }
+                code.push(instr);
+            }
+            code.extend(copies(u));
+            code.push(jump(Opcode::Jmp, Some(Operand::Label(spec.clone()))));
+            spec_label = Some(spec);
+        }
+    }
+    func.instructions.splice(info.head..info.head, code);
+    if let Some(spec) = spec_label {
+        let hints = func.loop_hints.entry(spec).or_default();
+        hints.unroll = Some(1);
+        hints.no_vectorize = true;
+    }
+}
+
+/// Give `func` a copy of its body for the values of its `constant_args`
+/// hint, guarded and falling back to the generic body as described above;
+/// true if it got one. The hint is consumed, and the arguments it covered
+/// are no longer `expected_args` of the generic body, which the guard only
+/// lets other values into.
+pub fn specialize_constant_args(func: &mut Function) -> bool {
+    let constant = std::mem::take(&mut func.hints.constant_args);
+    let len = func.instructions.len();
+    if constant.is_empty() || len > MAX_CLONED || !func.instructions[len - 1].ends_block() {
+        return false;
+    }
+    // Arguments loaded on entry into a register nothing else writes
+    let prologue = func
+        .instructions
+        .iter()
+        .take_while(|i| matches!(i.op, Opcode::LoadArg(_)))
+        .count();
+    let args: Vec<(usize, Operand, i32)> = func.instructions[..prologue]
+        .iter()
+        .filter_map(|i| match (&i.op, &i.dest) {
+            (Opcode::LoadArg(arg), Some(dest)) => Some((*arg, dest)),
+            _ => None,
+        })
+        .filter(|(_, dest)| arg_in(func, dest).is_some())
+        .filter_map(|(arg, dest)| constant.get(&arg).map(|&value| (arg, dest.clone(), value)))
+        .collect();
+    if args.is_empty() {
+        return false;
+    }
+
+    let defined: Vec<String> = func
+        .instructions
+        .iter()
+        .filter(|i| i.op == Opcode::Label)
+        .filter_map(|i| match &i.dest {
+            Some(Operand::Label(name)) => Some(name.clone()),
+            _ => None,
+        })
+        .collect();
+    let renamed = |name: &str| format!("{}_const", name);
+    let generic = format!("{}_generic", func.name);
+    let instr = |op: Opcode, dest: Option<Operand>, src1: Option<Operand>| Instruction {
+        op,
+        dest,
+        src1,
+        src2: None,
+    };
+
+    let mut code = Vec::with_capacity(len + 3 * args.len() + 1);
+    for (_, reg, value) in &args {
+        let mut cmp = instr(Opcode::Cmp, None, Some(reg.clone()));
+        cmp.src2 = Some(Operand::Imm(*value));
+        code.push(cmp);
+        code.push(instr(Opcode::Jne, Some(Operand::Label(generic.clone())), None));
+    }
+    for (_, reg, value) in &args {
+        code.push(instr(Opcode::Mov, Some(reg.clone()), Some(Operand::Imm(*value))));
+    }
+    for original in &func.instructions[prologue..] {
+        let mut copy = original.clone();
+        if let Some(Operand::Label(name)) = &mut copy.dest {
+            if defined.contains(name) {
+                *name = renamed(name);
+            }
+        }
+        if let Opcode::JumpTable(_, targets) = &mut copy.op {
+            for target in targets.iter_mut().filter(|t| defined.contains(t)) {
+                *target = renamed(target);
+            }
+        }
+        code.push(copy);
+    }
+    code.push(instr(Opcode::Label, Some(Operand::Label(generic)), None));
+    func.instructions.splice(prologue..prologue, code);
+
+    let copied: Vec<(String, _)> = func
+        .loop_hints
+        .iter()
+        .filter(|(label, _)| defined.contains(label))
+        .map(|(label, hints)| (renamed(label), hints.clone()))
+        .collect();
+    func.loop_hints.extend(copied);
+    for (arg, _, _) in &args {
+        func.hints.expected_args.remove(arg);
+    }
+    true
+}
+
+/// The arguments of recent calls to a function, for finding the ones that
+/// barely change
+#[derive(Debug, Clone, Default)]
+pub struct ArgProfile {
+    calls: VecDeque<Vec<i64>>,
+}
+
+impl ArgProfile {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, args: &[i64]) {
+        if self.calls.len() == PROFILE_CALLS {
+            self.calls.pop_front();
+        }
+        self.calls.push_back(args.to_vec());
+    }
+
+    /// Each argument that took one value in at least `NEARLY_CONSTANT` of
+    /// the recent calls, with that value: what `Hints::expected_args` wants
+    pub fn expected(&self) -> BTreeMap<usize, i32> {
+        let mut expected = BTreeMap::new();
+        if self.calls.len() < MIN_PROFILED_CALLS {
+            return expected;
+        }
+        let arity = self.calls.iter().map(Vec::len).max().unwrap_or(0);
+        for arg in 0..arity {
+            let mut counts: BTreeMap<i64, usize> = BTreeMap::new();
+            for call in &self.calls {
+                if let Some(&value) = call.get(arg) {
+                    *counts.entry(value).or_default() += 1;
+                }
+            }
+            let Some((&value, &count)) = counts.iter().max_by_key(|(_, &count)| count) else {
+                continue;
+            };
+            let share = count as f64 / self.calls.len() as f64;
+            if let (true, Ok(value)) = (share >= NEARLY_CONSTANT, i32::try_from(value)) {
+                expected.insert(arg, value);
+            }
+        }
+        expected
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    fn lowered(src: &str) -> Function {
+        Parser::new().parse(src).unwrap().functions.remove(0)
+    }
+
+    fn count(func: &Function, op: Opcode) -> usize {
+        func.instructions.iter().filter(|i| i.op == op).count()
+    }
+
+    const SUM_TO: &str = "fn main(n) {
+        s = 0
+        i = 0
+        while i < n {
+            s = s + i
+            i = i + 1
+        }
+        return s
+    }";
+
+    #[test]
+    fn test_constant_trip_counts() {
+        let mut func = lowered(&SUM_TO.replace("fn main(n) {", "fn main() {\n n = 5"));
+        let mut report = FunctionReport::default();
+        assert!(specialize_trip_counts(&mut func, &mut report));
+        assert_eq!(count(&func, Opcode::Cmp), 0, "fully unrolled: no test left");
+        assert_eq!(report.specializations[0].trip_count, 5);
+        assert_eq!(report.specializations[0].guarded_arg, None);
+        assert!(!specialize_trip_counts(&mut func, &mut report));
+
+        // 40 trips of a 4-instruction body: 8 copies per test
+        let mut func = lowered(&SUM_TO.replace("fn main(n) {", "fn main() {\n n = 40"));
+        let mut report = FunctionReport::default();
+        assert!(specialize_trip_counts(&mut func, &mut report));
+        assert_eq!(count(&func, Opcode::Add), 16);
+        assert_eq!(report.specializations[0].version, "remainder-free, 8 copies per test");
+        assert!(!specialize_trip_counts(&mut func, &mut report));
+
+        // 41 is prime, and the loop is left to the unroller
+        let mut func = lowered(&SUM_TO.replace("fn main(n) {", "fn main() {\n n = 41"));
+        let mut report = FunctionReport::default();
+        assert!(!specialize_trip_counts(&mut func, &mut report));
+        assert!(!report.specializations[0].applied);
+    }
+
+    #[test]
+    fn test_profiled_trip_counts_are_guarded() {
+        let mut func = lowered(SUM_TO);
+        let mut report = FunctionReport::default();
+        assert!(!specialize_trip_counts(&mut func, &mut report));
+        assert!(report.specializations.is_empty());
+
+        func.hints.expected_args.insert(0, 6);
+        assert!(specialize_trip_counts(&mut func, &mut report));
+        // The guard, then the generic loop's own test
+        assert_eq!(count(&func, Opcode::Cmp), 2);
+        assert_eq!(count(&func, Opcode::Jne), 1);
+        assert_eq!(report.specializations[0].guarded_arg, Some(0));
+        assert_eq!(report.specializations[0].trip_count, 6);
+        // The generic loop is entered from the guard: no longer a candidate
+        assert!(!specialize_trip_counts(&mut func, &mut report));
+    }
+
+    #[test]
+    fn test_constant_args_get_a_guarded_copy() {
+        let mut func = lowered(SUM_TO);
+        assert!(!specialize_constant_args(&mut func));
+        let generic = func.instructions.len();
+
+        func.hints.constant_args.insert(0, 6);
+        func.hints.expected_args.insert(0, 6);
+        assert!(specialize_constant_args(&mut func));
+        assert!(func.hints.constant_args.is_empty() && func.hints.expected_args.is_empty());
+        // The load, the guard, the constant, the copy and the generic body
+        assert_eq!(func.instructions.len(), 2 * generic + 3);
+        assert_eq!(count(&func, Opcode::Jne), 1);
+        let constant = &func.instructions[3];
+        assert_eq!((&constant.op, &constant.src1), (&Opcode::Mov, &Some(Operand::Imm(6))));
+
+        // The copy's loop has a constant trip count, the generic one none
+        loops::annotate(&mut func);
+        let mut report = FunctionReport::default();
+        assert!(specialize_trip_counts(&mut func, &mut report));
+        assert_eq!(report.specializations.len(), 1);
+        assert_eq!(report.specializations[0].guarded_arg, None);
+        assert_eq!(report.specializations[0].trip_count, 6);
+        assert!(!specialize_trip_counts(&mut func, &mut report));
+
+        // Arguments the function writes can't be replaced
+        let mut func = lowered(&SUM_TO.replace("s = 0", "s = 0\n n = n + 1"));
+        func.hints.constant_args.insert(0, 6);
+        assert!(!specialize_constant_args(&mut func));
+    }
+
+    #[test]
+    fn test_arg_profile_expects_nearly_constant_args() {
+        let mut profile = ArgProfile::new();
+        for call in 0..20 {
+            profile.record(&[16, call, if call == 7 { 3 } else { 8 }]);
+        }
+        assert_eq!(profile.expected(), BTreeMap::from([(0, 16), (2, 8)]));
+        for _ in 0..PROFILE_CALLS {
+            profile.record(&[17, 0, 1]);
+        }
+        assert_eq!(profile.expected(), BTreeMap::from([(0, 17), (1, 0), (2, 1)]));
+    }
+}
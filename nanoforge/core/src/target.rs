@@ -0,0 +1,159 @@
+//! Target Descriptions
+//!
+//! What the optimizer needs to know about the machine it optimizes for:
+//! the vector width loops are vectorized at and the micro-architecture whose
+//! latencies the scheduler uses. Feature detection proper lives in the
+//! `nanoforge` crate's `cpu_features`.
+
+use serde::Serialize;
+use std::sync::OnceLock;
+
+/// Lanes per vector the vectorizer emits loops for, and the register the
+/// backend holds them in: xmm (2), ymm (4) or zmm (8) of 64-bit integers
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize)]
+pub enum VectorWidth {
+    Lanes2,
+    #[default]
+    Lanes4,
+    Lanes8,
+}
+
+impl VectorWidth {
+    pub fn from_lanes(lanes: u32) -> Option<Self> {
+        match lanes {
+            2 => Some(VectorWidth::Lanes2),
+            4 => Some(VectorWidth::Lanes4),
+            8 => Some(VectorWidth::Lanes8),
+            _ => None,
+        }
+    }
+
+    pub fn lanes(&self) -> u32 {
+        match self {
+            VectorWidth::Lanes2 => 2,
+            VectorWidth::Lanes4 => 4,
+            VectorWidth::Lanes8 => 8,
+        }
+    }
+
+    /// Size of one vector, and so of its spill slot and their alignment
+    pub fn bytes(&self) -> i32 {
+        self.lanes() as i32 * 8
+    }
+}
+
+/// Micro-architecture family, for the scheduler's latency tables
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize)]
+pub enum Microarch {
+    /// Unknown CPU: middle-of-the-road latencies
+    #[default]
+    Generic,
+    /// Intel big cores (Skylake through Golden Cove)
+    IntelCore,
+    /// Intel E-cores and Atoms (Goldmont, Tremont, Gracemont)
+    IntelAtom,
+    /// AMD Zen 1-5
+    Zen,
+}
+
+/// Intel family 6 models that are Atom/E-core designs
+const ATOM_MODELS: [u32; 15] = [
+    0x1C, 0x26, 0x36, 0x37, 0x4A, 0x4C, 0x4D, 0x5A, 0x5C, 0x5F, 0x7A, 0x86, 0x96, 0x9C, 0xBE,
+];
+
+impl Microarch {
+    /// Classify this CPU from its CPUID vendor, family and model (Generic
+    /// off x86-64)
+    pub fn detect() -> Self {
+        cpu_identity().map_or(Microarch::Generic, |(vendor, signature)| {
+            Self::classify(&vendor, signature)
+        })
+    }
+
+    /// Detected once per process
+    pub fn cached() -> Self {
+        static MICROARCH: OnceLock<Microarch> = OnceLock::new();
+        *MICROARCH.get_or_init(Self::detect)
+    }
+
+    /// Classify from the 12-byte vendor string and the CPUID leaf 1 EAX signature
+    pub fn classify(vendor: &[u8], signature: u32) -> Self {
+        let (family, model) = family_model(signature);
+        match vendor {
+            b"GenuineIntel" if family == 6 && ATOM_MODELS.contains(&model) => Microarch::IntelAtom,
+            b"GenuineIntel" if family == 6 => Microarch::IntelCore,
+            b"AuthenticAMD" | b"HygonGenuine" if family >= 0x17 => Microarch::Zen,
+            _ => Microarch::Generic,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Microarch::Generic => "generic",
+            Microarch::IntelCore => "Intel Core",
+            Microarch::IntelAtom => "Intel Atom",
+            Microarch::Zen => "AMD Zen",
+        }
+    }
+}
+
+/// The 12-byte CPUID vendor string and the leaf 1 EAX signature (None off
+/// x86-64)
+#[cfg(target_arch = "x86_64")]
+pub fn cpu_identity() -> Option<([u8; 12], u32)> {
+    use std::arch::x86_64::__cpuid;
+    #[allow(unused_unsafe)]
+    let (leaf0, signature) = unsafe { (__cpuid(0), __cpuid(1).eax) };
+    let mut vendor = [0; 12];
+    for (chunk, reg) in vendor.chunks_mut(4).zip([leaf0.ebx, leaf0.edx, leaf0.ecx]) {
+        chunk.copy_from_slice(&reg.to_le_bytes());
+    }
+    Some((vendor, signature))
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+pub fn cpu_identity() -> Option<([u8; 12], u32)> {
+    None
+}
+
+/// Display family and model of a CPUID signature, extended fields included
+pub fn family_model(signature: u32) -> (u32, u32) {
+    let base_family = (signature >> 8) & 0xF;
+    let family = if base_family == 0xF {
+        base_family + ((signature >> 20) & 0xFF)
+    } else {
+        base_family
+    };
+    let model = if base_family == 0x6 || base_family == 0xF {
+        ((signature >> 4) & 0xF) | ((signature >> 12) & 0xF0)
+    } else {
+        (signature >> 4) & 0xF
+    };
+    (family, model)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vector_width_lanes() {
+        for width in [VectorWidth::Lanes2, VectorWidth::Lanes4, VectorWidth::Lanes8] {
+            assert_eq!(VectorWidth::from_lanes(width.lanes()), Some(width));
+        }
+        assert_eq!(VectorWidth::from_lanes(3), None);
+        assert_eq!(VectorWidth::Lanes8.bytes(), 64);
+    }
+
+    #[test]
+    fn test_microarch_classification() {
+        // Skylake, Alder Lake E-core, Zen 3, an old AMD family and an unknown vendor
+        assert_eq!(Microarch::classify(b"GenuineIntel", 0x000506E3), Microarch::IntelCore);
+        assert_eq!(Microarch::classify(b"GenuineIntel", 0x000B06E0), Microarch::IntelAtom);
+        assert_eq!(Microarch::classify(b"AuthenticAMD", 0x00A20F10), Microarch::Zen);
+        assert_eq!(Microarch::classify(b"AuthenticAMD", 0x00600F20), Microarch::Generic);
+        assert_eq!(Microarch::classify(b"SomeVendorXX", 0x000506E3), Microarch::Generic);
+        assert_eq!(family_model(0x00A20F10), (0x19, 0x21));
+        assert_eq!(family_model(0x000506E3), (6, 0x5E));
+    }
+}
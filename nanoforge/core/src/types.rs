@@ -0,0 +1,302 @@
+//! IR Type Inference
+//!
+//! Every IR register is an i64, so a pointer and a loop counter look the
+//! same. This pass recovers a coarse type per virtual register from how
+//! values are produced and used:
+//!
+//! - `Alloc` produces a pointer; immediates, `Mul` and indices are integers
+//! - `Load`/`Store`/`Free` bases are pointers (used for parameters, whose
+//!   type is otherwise unknown)
+//! - `Store` values determine the pointer's element type, `Load` reads it back
+//!
+//! The lattice is `(no info) < Int | Ptr(elem) < Unknown`, where `Unknown`
+//! means the register is used as both and nothing can be assumed.
+//!
+//! Types are not stored on `Operand`s. A `TypeMap` is a side table keyed by
+//! register number, so it is only valid for the instructions it was inferred
+//! from: passes rewrite and renumber registers freely, and a map kept across
+//! one would describe registers that no longer hold the same value. Every
+//! user (`verifier`, `pure`) infers a fresh map from the function in front
+//! of it, and `FunctionReport::note` re-runs the verifier after every pass
+//! that changes a function in debug builds, so each pass's output is
+//! re-typed and re-checked rather than trusted.
+
+use crate::ir::{Function, Opcode, Operand};
+use std::collections::HashMap;
+use std::fmt;
+
+/// Element type of a pointer (arrays hold either integers or pointers)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ElemTy {
+    /// Nothing has been stored through this pointer
+    Uninit,
+    Int,
+    Ptr,
+    Unknown,
+}
+
+/// Inferred type of a register or operand
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ValueType {
+    Int,
+    Ptr(ElemTy),
+    Unknown,
+}
+
+impl ElemTy {
+    pub fn join(self, other: ElemTy) -> ElemTy {
+        match (self, other) {
+            (ElemTy::Uninit, e) | (e, ElemTy::Uninit) => e,
+            (a, b) if a == b => a,
+            _ => ElemTy::Unknown,
+        }
+    }
+
+    fn of(ty: ValueType) -> ElemTy {
+        match ty {
+            ValueType::Int => ElemTy::Int,
+            ValueType::Ptr(_) => ElemTy::Ptr,
+            ValueType::Unknown => ElemTy::Unknown,
+        }
+    }
+
+    /// Type of a value loaded from an array of this element type
+    fn loaded(self) -> ValueType {
+        match self {
+            ElemTy::Int => ValueType::Int,
+            // The pointee of a loaded pointer isn't tracked
+            ElemTy::Ptr => ValueType::Ptr(ElemTy::Unknown),
+            ElemTy::Uninit | ElemTy::Unknown => ValueType::Unknown,
+        }
+    }
+}
+
+impl ValueType {
+    /// Least upper bound
+    pub fn join(self, other: ValueType) -> ValueType {
+        match (self, other) {
+            (a, b) if a == b => a,
+            (ValueType::Ptr(a), ValueType::Ptr(b)) => ValueType::Ptr(a.join(b)),
+            _ => ValueType::Unknown,
+        }
+    }
+
+    pub fn is_ptr(self) -> bool {
+        matches!(self, ValueType::Ptr(_))
+    }
+
+    pub fn is_int(self) -> bool {
+        self == ValueType::Int
+    }
+}
+
+impl fmt::Display for ValueType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValueType::Int => write!(f, "int"),
+            ValueType::Ptr(ElemTy::Int) => write!(f, "ptr<int>"),
+            ValueType::Ptr(ElemTy::Ptr) => write!(f, "ptr<ptr>"),
+            ValueType::Ptr(ElemTy::Uninit) => write!(f, "ptr<uninit>"),
+            ValueType::Ptr(ElemTy::Unknown) => write!(f, "ptr<?>"),
+            ValueType::Unknown => write!(f, "?"),
+        }
+    }
+}
+
+/// Register types for one function
+#[derive(Debug, Clone, Default)]
+pub struct TypeMap {
+    regs: HashMap<u8, ValueType>,
+}
+
+impl TypeMap {
+    /// Infer types for every register in `func` (flow-insensitive fixpoint)
+    pub fn infer(func: &Function) -> Self {
+        let mut map = TypeMap::default();
+        loop {
+            let mut changed = false;
+            for instr in &func.instructions {
+                changed |= map.apply_definition(&instr.op, &instr.dest, &instr.src1, &instr.src2);
+            }
+            // Usage only fills gaps (e.g. parameters): a register that is
+            // defined as an integer and then dereferenced must stay Int so the
+            // verifier can report it.
+            if !changed {
+                for instr in &func.instructions {
+                    changed |= map.apply_usage(&instr.op, &instr.dest, &instr.src1, &instr.src2);
+                }
+            }
+            if !changed {
+                return map;
+            }
+        }
+    }
+
+    /// Inferred type of a register; `None` if nothing is known about it
+    pub fn get(&self, reg: u8) -> Option<ValueType> {
+        self.regs.get(&reg).copied()
+    }
+
+    /// Type of an operand as seen by an instruction
+    pub fn operand(&self, op: &Operand) -> ValueType {
+        match op {
+            Operand::Imm(_) => ValueType::Int,
+            Operand::Reg(r) => self.get(*r).unwrap_or(ValueType::Unknown),
+            Operand::Ymm(_) | Operand::Label(_) => ValueType::Unknown,
+        }
+    }
+
+    fn known(&self, op: &Option<Operand>) -> Option<ValueType> {
+        match op {
+            Some(Operand::Imm(_)) => Some(ValueType::Int),
+            Some(Operand::Reg(r)) => self.get(*r),
+            _ => None,
+        }
+    }
+
+    fn constrain(&mut self, op: &Option<Operand>, ty: ValueType) -> bool {
+        let Some(Operand::Reg(r)) = op else {
+            return false;
+        };
+        let joined = match self.regs.get(r) {
+            Some(old) => old.join(ty),
+            None => ty,
+        };
+        self.regs.insert(*r, joined) != Some(joined)
+    }
+
+    fn apply_definition(
+        &mut self,
+        op: &Opcode,
+        dest: &Option<Operand>,
+        src1: &Option<Operand>,
+        src2: &Option<Operand>,
+    ) -> bool {
+        match op {
+            Opcode::Mov => match self.known(src1) {
+                Some(ty) => self.constrain(dest, ty),
+                None => false,
+            },
+            // Ptr +/- Int stays a pointer, Int +/- Int stays an integer;
+            // mixing in a pointer operand makes the result unknowable
+            Opcode::Add | Opcode::Sub => match self.known(src1) {
+                Some(ValueType::Int) | None => false,
+                Some(_) => self.constrain(dest, ValueType::Unknown),
+            },
+            Opcode::Mul
+            | Opcode::Popcnt
+            | Opcode::Ctz
+            | Opcode::Clz
+            | Opcode::Min
+            | Opcode::Max
+            | Opcode::Abs => self.constrain(dest, ValueType::Int),
+            // The element type is filled in by the stores
+            Opcode::Alloc => self.constrain(dest, ValueType::Ptr(ElemTy::Uninit)),
+            Opcode::Load(_) => match self.known(src1) {
+                Some(ValueType::Ptr(elem)) => self.constrain(dest, elem.loaded()),
+                _ => false,
+            },
+            Opcode::Store(_) => match (self.known(dest), self.known(src2)) {
+                (Some(ValueType::Ptr(_)), Some(val)) => {
+                    self.constrain(dest, ValueType::Ptr(ElemTy::of(val)))
+                }
+                _ => false,
+            },
+            Opcode::Call => self.constrain(dest, ValueType::Unknown),
+            _ => false,
+        }
+    }
+
+    fn apply_usage(
+        &mut self,
+        op: &Opcode,
+        dest: &Option<Operand>,
+        src1: &Option<Operand>,
+        src2: &Option<Operand>,
+    ) -> bool {
+        let (base, int_operand) = match op {
+            Opcode::Load(_) | Opcode::VLoad(..) => (Some(src1), src2),
+            Opcode::Store(_) | Opcode::VStore(..) => (Some(dest), src1),
+            Opcode::Free => (Some(src1), &None),
+            Opcode::Alloc => (None, src1),
+            _ => return false,
+        };
+        let mut changed = false;
+        if let Some(base) = base {
+            if self.known(base).is_none() {
+                changed |= self.constrain(base, ValueType::Ptr(ElemTy::Unknown));
+            }
+        }
+        if self.known(int_operand).is_none() {
+            changed |= self.constrain(int_operand, ValueType::Int);
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    fn types_of(src: &str) -> (Function, TypeMap) {
+        let prog = Parser::new().parse(src).unwrap();
+        let main = prog
+            .functions
+            .into_iter()
+            .find(|f| f.name == "main")
+            .unwrap();
+        let types = TypeMap::infer(&main);
+        (main, types)
+    }
+
+    fn reg_of(func: &Function, op: Opcode) -> u8 {
+        match func.instructions.iter().find(|i| i.op == op).unwrap().dest {
+            Some(Operand::Reg(r)) => r,
+            _ => panic!("no register dest"),
+        }
+    }
+
+    #[test]
+    fn test_infers_pointers_and_ints() {
+        let (func, types) = types_of(
+            "fn main() {
+                a = alloc(80)
+                i = 3
+                a[i] = 7
+                v = a[i]
+                return v
+            }",
+        );
+        let a = reg_of(&func, Opcode::Alloc);
+        let v = reg_of(&func, Opcode::Load(0));
+        assert_eq!(types.get(a), Some(ValueType::Ptr(ElemTy::Int)));
+        assert_eq!(types.get(v), Some(ValueType::Int));
+    }
+
+    #[test]
+    fn test_parameters_typed_from_usage() {
+        let (func, types) = types_of(
+            "fn main(a, n) {
+                v = a[n]
+                return v
+            }",
+        );
+        let Some(Operand::Reg(a)) = func.instructions[0].dest else {
+            panic!("expected LoadArg first");
+        };
+        assert!(types.get(a).unwrap().is_ptr());
+    }
+
+    #[test]
+    fn test_join() {
+        let ptr = ValueType::Ptr(ElemTy::Int);
+        assert_eq!(ptr.join(ptr), ptr);
+        assert_eq!(ptr.join(ValueType::Int), ValueType::Unknown);
+        assert_eq!(
+            ptr.join(ValueType::Ptr(ElemTy::Ptr)),
+            ValueType::Ptr(ElemTy::Unknown)
+        );
+        assert_eq!(ValueType::Ptr(ElemTy::Uninit).join(ptr), ptr);
+    }
+}
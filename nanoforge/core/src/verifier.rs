@@ -0,0 +1,227 @@
+//! IR Verifier
+//!
+//! Structural and type checks on IR before it reaches the backend:
+//! labels are unique and every jump (and jump table entry) has a target, calls name a function in
+//! the program, memory operations have the operand shapes codegen expects,
+//! and values inferred as integers are never dereferenced (see `types`).
+
+use crate::ir::{Function, Opcode, Operand, Program};
+use crate::types::{TypeMap, ValueType};
+use std::collections::HashSet;
+
+/// Verify every function; all problems are reported, one per line
+pub fn verify_program(prog: &Program) -> Result<(), String> {
+    let names: HashSet<&str> = prog.functions.iter().map(|f| f.name.as_str()).collect();
+
+    let errors: Vec<String> = prog
+        .functions
+        .iter()
+        .flat_map(|f| verify_function(f, Some(&names)))
+        .collect();
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors.join("\n"))
+    }
+}
+
+/// Check one function. `functions`, when given, is used to validate call targets.
+pub fn verify_function(func: &Function, functions: Option<&HashSet<&str>>) -> Vec<String> {
+    let mut errors = Vec::new();
+    let mut err = |idx: usize, msg: String| errors.push(format!("{}[{}]: {}", func.name, idx, msg));
+
+    let mut labels = HashSet::new();
+    for (idx, instr) in func.instructions.iter().enumerate() {
+        if instr.op == Opcode::Label {
+            match &instr.dest {
+                Some(Operand::Label(name)) => {
+                    if !labels.insert(name.as_str()) {
+                        err(idx, format!("duplicate label '{}'", name));
+                    }
+                }
+                _ => err(idx, "label without a name".to_string()),
+            }
+        }
+    }
+
+    let types = TypeMap::infer(func);
+    let is_reg = |op: &Option<Operand>| matches!(op, Some(Operand::Reg(_)));
+    let ty = |op: &Option<Operand>| op.as_ref().map(|o| types.operand(o));
+
+    for (idx, instr) in func.instructions.iter().enumerate() {
+        match instr.op {
+            Opcode::Jmp
+            | Opcode::Jnz
+            | Opcode::Je
+            | Opcode::Jne
+            | Opcode::Jl
+            | Opcode::Jle
+            | Opcode::Jg
+            | Opcode::Jge => match &instr.dest {
+                Some(Operand::Label(target)) if labels.contains(target.as_str()) => {}
+                Some(Operand::Label(target)) => {
+                    err(idx, format!("jump to undefined label '{}'", target))
+                }
+                _ => err(idx, "jump without a label target".to_string()),
+            },
+            Opcode::JumpTable(_, ref targets) => {
+                if targets.is_empty() {
+                    err(idx, "jump table without entries".to_string());
+                }
+                if !is_reg(&instr.src1) {
+                    err(idx, "jump table index must be a register".to_string());
+                }
+                if !matches!(instr.dest, Some(Operand::Label(_))) {
+                    err(idx, "jump table without a default label".to_string());
+                }
+                for target in instr.jump_targets() {
+                    if !labels.contains(target) {
+                        err(idx, format!("jump to undefined label '{}'", target));
+                    }
+                }
+            }
+            Opcode::Call => match (&instr.src1, functions) {
+                (Some(Operand::Label(target)), Some(names)) if !names.contains(target.as_str()) => {
+                    err(idx, format!("call to undefined function '{}'", target))
+                }
+                (Some(Operand::Label(_)), _) => {}
+                _ => err(idx, "call without a function name".to_string()),
+            },
+            Opcode::Alloc => {
+                if !is_reg(&instr.dest) {
+                    err(idx, "alloc result must be a register".to_string());
+                }
+                if ty(&instr.src1).is_some_and(ValueType::is_ptr) {
+                    err(idx, "alloc size is a pointer".to_string());
+                }
+                match &instr.src2 {
+                    None => {}
+                    Some(Operand::Imm(a))
+                        if (*a as u32).is_power_of_two()
+                            && crate::alignment::ALIGNMENTS.contains(&(*a as u32)) => {}
+                    Some(other) => err(
+                        idx,
+                        format!(
+                            "alloc alignment {:?} is not a power of two from 8 to 4096",
+                            other
+                        ),
+                    ),
+                }
+            }
+            Opcode::Load(_) | Opcode::Store(_) | Opcode::Free => {
+                let (base, index) = match instr.op {
+                    Opcode::Load(_) => (&instr.src1, Some(&instr.src2)),
+                    Opcode::Store(_) => (&instr.dest, Some(&instr.src1)),
+                    _ => (&instr.src1, None),
+                };
+                if matches!(instr.op, Opcode::Load(_)) && !is_reg(&instr.dest) {
+                    err(idx, "load result must be a register".to_string());
+                }
+                if !is_reg(base) {
+                    err(idx, format!("{:?} base must be a register", instr.op));
+                } else if ty(base).is_some_and(ValueType::is_int) {
+                    err(
+                        idx,
+                        format!("{:?} through an integer (not a pointer)", instr.op),
+                    );
+                }
+                if let Some(index) = index {
+                    if ty(index).is_some_and(ValueType::is_ptr) {
+                        err(idx, format!("{:?} index is a pointer", instr.op));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    fn verify(src: &str) -> Result<(), String> {
+        verify_program(&Parser::new().parse(src).unwrap())
+    }
+
+    #[test]
+    fn test_valid_programs_pass() {
+        verify(
+            "fn sum(a, n) {
+                s = 0
+                i = 0
+                while i < n {
+                    v = a[i]
+                    s = s + v
+                    i = i + 1
+                }
+                return s
+            }
+            fn main() {
+                a = alloc(80)
+                a[0] = 5
+                r = sum(a, 1)
+                free(a)
+                return r
+            }",
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_integer_dereference_rejected() {
+        let err = verify(
+            "fn main() {
+                x = 5
+                x[0] = 1
+                return 0
+            }",
+        )
+        .unwrap_err();
+        assert!(err.contains("through an integer"), "{}", err);
+
+        let err = verify(
+            "fn main() {
+                a = alloc(8)
+                b = alloc(8)
+                v = a[b]
+                return v
+            }",
+        )
+        .unwrap_err();
+        assert!(err.contains("index is a pointer"), "{}", err);
+    }
+
+    #[test]
+    fn test_structural_errors() {
+        let mut prog = Parser::new()
+            .parse("fn main() {\n x = 1\n return x\n}")
+            .unwrap();
+        let main = &mut prog.functions[0];
+        main.instructions.insert(
+            0,
+            crate::ir::Instruction {
+                op: Opcode::Jmp,
+                dest: Some(Operand::Label("nowhere".to_string())),
+                src1: None,
+                src2: None,
+            },
+        );
+        main.instructions.insert(
+            0,
+            crate::ir::Instruction {
+                op: Opcode::Call,
+                dest: Some(Operand::Reg(20)),
+                src1: Some(Operand::Label("missing".to_string())),
+                src2: None,
+            },
+        );
+        let err = verify_program(&prog).unwrap_err();
+        assert!(err.contains("undefined label 'nowhere'"), "{}", err);
+        assert!(err.contains("undefined function 'missing'"), "{}", err);
+    }
+}
@@ -0,0 +1,26 @@
+fn main(n) {
+    sz = n * 8
+    A = alloc(sz)
+    B = alloc(sz)
+
+    i = 0
+    while i < n {
+        A[i] = i
+        b = i + 1
+        B[i] = b
+        i = i + 1
+    }
+
+    s = 0
+    i = 0
+    while i < n {
+        a = A[i]
+        b = B[i]
+        p = a * b
+        s = s + p
+        i = i + 1
+    }
+    free(A)
+    free(B)
+    return s
+}
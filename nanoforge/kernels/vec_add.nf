@@ -0,0 +1,35 @@
+fn main(n) {
+    sz = n * 8
+    A = alloc(sz)
+    B = alloc(sz)
+    C = alloc(sz)
+
+    i = 0
+    while i < n {
+        A[i] = i
+        b = i + i
+        B[i] = b
+        i = i + 1
+    }
+
+    i = 0
+    while i < n {
+        a = A[i]
+        b = B[i]
+        c = a + b
+        C[i] = c
+        i = i + 1
+    }
+
+    s = 0
+    i = 0
+    while i < n {
+        c = C[i]
+        s = s + c
+        i = i + 1
+    }
+    free(A)
+    free(B)
+    free(C)
+    return s
+}
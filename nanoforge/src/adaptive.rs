@@ -0,0 +1,680 @@
+//! Adaptive Runtime
+//!
+//! Runs a program's entry point through a ladder of tiers. Every step is
+//! decided by a `TieringPolicy`, so its hysteresis, cool-down and recompile
+//! cap apply:
+//!
+//! 1. Interpreted by `interpreter`, with no compile cost; or, started in
+//!    `Tier::Template`, stitched from machine-code templates by
+//!    `template_jit` in microseconds (the compiler at level 1 stands in for
+//!    programs it has no templates for)
+//! 2. Baseline: compiled at level 1 once the entry has been called
+//!    `jit_after_calls` times
+//! 3. Optimized: the hottest function of a per-function profile is
+//!    recompiled at level 3, and the rest stay at level 1, when the baseline
+//!    or template program
+//!    is hot, i.e. calls into it kept the caller busy for at least
+//!    `promote_share` of the wall time over a `window` (and demoted again
+//!    below `demote_share`). Entry arguments that barely changed over the
+//!    recent calls become `Hints::constant_args`, so the entry gets a
+//!    guarded copy with them as constants, and `Hints::expected_args`, so
+//!    loops they bound get guarded trip-count-specialized versions if it
+//!    can't (`specialize`)
+//! 4. Evolved (optional): the hottest function of a per-function profile is
+//!    evolved on a background thread; the winner is swapped in once it
+//!    re-verifies and the entry still returns the same result
+//!
+//! The tier is tracked for the entry, whose calls are what gets timed.
+//! With an `AuditLog` attached, every change is audited with the time per
+//! call in the old tier and of the first call in the new one.
+
+use crate::audit::{AuditAction, AuditEvent, AuditLog};
+use crate::compiled_program::CompiledProgram;
+use crate::compiler::CompileOptions;
+use crate::evolution::{EvolutionConfig, EvolutionEngine};
+use crate::function_profiler::ProfileSession;
+use crate::interpreter::Interpreter;
+use crate::ir::{Function, Program};
+use crate::live_evolution::evolvable;
+use crate::specialize::ArgProfile;
+use crate::template_jit::TemplateProgram;
+use crate::tiering::{Tier, TierDecision, TieringConfig, TieringPolicy};
+use crate::validator::{TestCase, Validator};
+use std::collections::BTreeMap;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Inputs the evolved function is checked against
+const EVOLUTION_INPUTS: [i64; 3] = [10, 100, 1000];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EvolveOptions {
+    pub generations: u32,
+    pub population: usize,
+    pub seed: u64,
+}
+
+impl Default for EvolveOptions {
+    fn default() -> Self {
+        Self {
+            generations: 10,
+            population: 16,
+            seed: 42,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AdaptiveConfig {
+    pub tiering: TieringConfig,
+    /// Wall time over which hotness is measured
+    pub window: Duration,
+    /// Evolve the hottest function once the program is optimized
+    pub evolve: Option<EvolveOptions>,
+}
+
+impl Default for AdaptiveConfig {
+    fn default() -> Self {
+        Self {
+            tiering: TieringConfig::default().start(Tier::Interpreted),
+            window: Duration::from_millis(100),
+            evolve: None,
+        }
+    }
+}
+
+impl AdaptiveConfig {
+    pub fn tiering(mut self, tiering: TieringConfig) -> Self {
+        self.tiering = tiering;
+        self
+    }
+
+    pub fn window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+
+    pub fn evolve(mut self, options: Option<EvolveOptions>) -> Self {
+        self.evolve = options;
+        self
+    }
+}
+
+/// A tier change, with the call that triggered it
+#[derive(Debug, Clone, PartialEq)]
+pub struct TierChange {
+    pub call: u64,
+    pub from: Tier,
+    pub to: Tier,
+    pub reason: String,
+}
+
+/// Calls made and time spent in one tier
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TierStats {
+    pub calls: u64,
+    pub busy: Duration,
+    /// Time spent compiling into the tier, over every time it was entered
+    pub compile: Duration,
+}
+
+impl TierStats {
+    pub fn avg_ns(&self) -> f64 {
+        if self.calls == 0 {
+            0.0
+        } else {
+            self.busy.as_nanos() as f64 / self.calls as f64
+        }
+    }
+}
+
+struct Evolution {
+    function: String,
+    handle: JoinHandle<Option<Function>>,
+}
+
+struct Audit {
+    log: AuditLog,
+    program: String,
+    /// The last change, recorded once the new tier has timed a call
+    pending: Option<AuditEvent>,
+}
+
+pub struct AdaptiveRuntime {
+    program: Program,
+    /// `program` with the evolved function swapped in
+    evolved: Option<Program>,
+    entry: String,
+    config: AdaptiveConfig,
+    policy: TieringPolicy,
+    compiled: Option<CompiledProgram>,
+    /// The template tier's code, when it had templates for the program
+    stitched: Option<TemplateProgram>,
+    /// Function optimized at level 3 in the optimized tier
+    hot: Option<String>,
+    /// Arguments of the entry's recent calls
+    args: ArgProfile,
+    calls: u64,
+    window_start: Instant,
+    window_busy: Duration,
+    window_calls: u64,
+    evolution: Option<Evolution>,
+    evolution_tried: bool,
+    stats: BTreeMap<Tier, TierStats>,
+    changes: Vec<TierChange>,
+    audit: Option<Audit>,
+}
+
+impl AdaptiveRuntime {
+    pub fn new(program: Program, entry: &str, config: AdaptiveConfig) -> Result<Self, String> {
+        if !program.functions.iter().any(|f| f.name == entry) {
+            return Err(format!("no function named '{}'", entry));
+        }
+        let policy = TieringPolicy::new(config.tiering)?;
+        let mut runtime = Self {
+            program,
+            evolved: None,
+            entry: entry.to_string(),
+            config,
+            policy,
+            compiled: None,
+            stitched: None,
+            hot: None,
+            args: ArgProfile::new(),
+            calls: 0,
+            window_start: Instant::now(),
+            window_busy: Duration::ZERO,
+            window_calls: 0,
+            evolution: None,
+            evolution_tried: false,
+            stats: BTreeMap::new(),
+            changes: Vec::new(),
+            audit: None,
+        };
+        let start = runtime.tier();
+        if start != Tier::Interpreted {
+            runtime.compile(start, None)?;
+        }
+        Ok(runtime)
+    }
+
+    /// Audit tier changes to `log`, as changes of `program`
+    pub fn with_audit(mut self, log: AuditLog, program: &str) -> Self {
+        self.audit = Some(Audit {
+            log,
+            program: program.to_string(),
+            pending: None,
+        });
+        self
+    }
+
+    pub fn tier(&self) -> Tier {
+        self.policy.tier(&self.entry)
+    }
+
+    pub fn calls(&self) -> u64 {
+        self.calls
+    }
+
+    pub fn changes(&self) -> &[TierChange] {
+        &self.changes
+    }
+
+    /// The function the optimized tier compiled at level 3 (None if it
+    /// hasn't been reached, or started there before a call could be profiled)
+    pub fn hot_function(&self) -> Option<&str> {
+        self.hot.as_deref()
+    }
+
+    /// Calls and time per tier, in ladder order
+    pub fn stats(&self) -> &BTreeMap<Tier, TierStats> {
+        &self.stats
+    }
+
+    /// Call the entry with `args` in the current tier, then let the policy
+    /// move it up or down the ladder
+    pub fn call(&mut self, args: &[i64]) -> Result<i64, String> {
+        let tier = self.tier();
+        let start = Instant::now();
+        let result = self.run(args)?;
+        let elapsed = start.elapsed();
+
+        self.args.record(args);
+        self.calls += 1;
+        self.window_calls += 1;
+        self.window_busy += elapsed;
+        let stats = self.stats.entry(tier).or_default();
+        stats.calls += 1;
+        stats.busy += elapsed;
+        if let Some(audit) = &mut self.audit {
+            if let Some(mut event) = audit.pending.take() {
+                event.after = Some(elapsed.as_nanos() as f64);
+                audit.log.record(event);
+            }
+        }
+
+        self.step(args, result)?;
+        Ok(result)
+    }
+
+    fn run(&self, args: &[i64]) -> Result<i64, String> {
+        if let Some(stitched) = &self.stitched {
+            return stitched.call(&self.entry, args);
+        }
+        match &self.compiled {
+            Some(compiled) => compiled
+                .get_fn(&self.entry)
+                .ok_or_else(|| format!("{}() was not compiled", self.entry))?
+                .call(args),
+            None => Interpreter::new(&self.program).call(&self.entry, args),
+        }
+    }
+
+    fn step(&mut self, args: &[i64], result: i64) -> Result<(), String> {
+        let now = Instant::now();
+        if self.tier() == Tier::Interpreted {
+            let decision = self.policy.on_calls(&self.entry, self.calls, now);
+            let reason = format!("called {} times", self.calls);
+            return self.apply(decision, Tier::Interpreted, reason, args);
+        }
+
+        let wall = now.saturating_duration_since(self.window_start);
+        if wall >= self.config.window {
+            let share = if wall.is_zero() {
+                1.0
+            } else {
+                (self.window_busy.as_secs_f64() / wall.as_secs_f64()).min(1.0)
+            };
+            let from = self.tier();
+            let decision = self.policy.decide(&self.entry, share, now);
+            let reason = format!(
+                "busy {:.0}% of the last {} calls",
+                share * 100.0,
+                self.window_calls
+            );
+            self.apply(decision, from, reason, args)?;
+            self.reset_window();
+        }
+
+        if self.tier() == Tier::Optimized && !self.evolution_tried {
+            if let Some(options) = self.config.evolve {
+                self.evolution_tried = true;
+                self.start_evolution(args, options)?;
+            }
+        }
+        if self
+            .evolution
+            .as_ref()
+            .is_some_and(|e| e.handle.is_finished())
+        {
+            let evolution = self.evolution.take().expect("checked above");
+            let winner = evolution.handle.join().unwrap_or(None);
+            if let Some(winner) = winner {
+                self.try_swap(&evolution.function, winner, args, result)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn apply(
+        &mut self,
+        decision: TierDecision,
+        from: Tier,
+        reason: String,
+        args: &[i64],
+    ) -> Result<(), String> {
+        match decision {
+            TierDecision::Change(to) => {
+                self.compile(to, Some(args))?;
+                // Hotness is measured afresh in the new tier
+                self.reset_window();
+                if let Some(audit) = &mut self.audit {
+                    let action = match to {
+                        Tier::Evolved => AuditAction::Swap,
+                        _ if to > from => AuditAction::Promote,
+                        _ => AuditAction::Demote,
+                    };
+                    let before = self.stats.get(&from).map(|s| s.avg_ns());
+                    let to = tier_name(to);
+                    let event = AuditEvent::new(&audit.program, &self.entry, action, &to)
+                        .from(&tier_name(from))
+                        .trigger(reason.as_str())
+                        .before("ns/call", before);
+                    // A change before the last one's first call goes unmeasured
+                    if let Some(unmeasured) = audit.pending.replace(event) {
+                        audit.log.record(unmeasured);
+                    }
+                }
+                self.changes.push(TierChange {
+                    call: self.calls,
+                    from,
+                    to,
+                    reason,
+                });
+            }
+            TierDecision::Deferred(to, why) => {
+                tracing::debug!("{}: {:?} -> {:?} deferred: {:?}", self.entry, from, to, why)
+            }
+            TierDecision::Stay => {}
+        }
+        Ok(())
+    }
+
+    fn reset_window(&mut self) {
+        self.window_start = Instant::now();
+        self.window_busy = Duration::ZERO;
+        self.window_calls = 0;
+    }
+
+    /// Compile for `tier`; the optimized tier profiles a call with `args` to
+    /// find the function worth optimizing
+    fn compile(&mut self, tier: Tier, args: Option<&[i64]>) -> Result<(), String> {
+        let start = Instant::now();
+        self.stitched = None;
+        self.compiled = match tier {
+            Tier::Interpreted => None,
+            Tier::Template => match TemplateProgram::compile(&self.program) {
+                Ok(stitched) => {
+                    self.stitched = Some(stitched);
+                    None
+                }
+                Err(e) => {
+                    tracing::info!("No templates for {}(): {}; compiling at -O1", self.entry, e);
+                    Some(CompiledProgram::compile(&self.program, &CompileOptions::new(1))?)
+                }
+            },
+            Tier::Baseline => Some(CompiledProgram::compile(
+                &self.program,
+                &CompileOptions::new(1),
+            )?),
+            Tier::Optimized => {
+                self.hot = match args {
+                    Some(args) => self.hottest(args)?,
+                    None => None,
+                };
+                let mut program = self.program.clone();
+                let entry = program
+                    .functions
+                    .iter_mut()
+                    .find(|f| f.name == self.entry)
+                    .ok_or_else(|| format!("no function named '{}'", self.entry))?;
+                entry.hints.expected_args = self.args.expected();
+                entry.hints.constant_args = entry.hints.expected_args.clone();
+                match &self.hot {
+                    Some(hot) => {
+                        program.set_opt_level(hot, 3)?;
+                        Some(CompiledProgram::compile(&program, &CompileOptions::new(1))?)
+                    }
+                    None => Some(CompiledProgram::compile(&program, &CompileOptions::new(3))?),
+                }
+            }
+            Tier::Evolved => {
+                let evolved = self.evolved.as_ref().ok_or("no evolved program")?;
+                Some(CompiledProgram::compile(evolved, &CompileOptions::new(3))?)
+            }
+        };
+        self.stats.entry(tier).or_default().compile += start.elapsed();
+        Ok(())
+    }
+
+    /// Profile one call of the entry with `args` and return the function
+    /// that spent the most cycles in its own code
+    fn hottest(&self, args: &[i64]) -> Result<Option<String>, String> {
+        let profiled =
+            CompiledProgram::compile(&self.program, &CompileOptions::new(1).profile(true))?;
+        let entry = profiled
+            .get_fn(&self.entry)
+            .ok_or_else(|| format!("{}() was not compiled", self.entry))?;
+        let session = ProfileSession::start(&profiled.code().profiled_functions);
+        let profiled_result = entry.call(args);
+        let report = session.finish();
+        profiled_result?;
+        Ok(report.by_self_cycles().first().map(|f| f.name.clone()))
+    }
+
+    /// Profile one call, pick the hottest function and evolve it on a
+    /// background thread if it can run on its own
+    fn start_evolution(&mut self, args: &[i64], options: EvolveOptions) -> Result<(), String> {
+        let Some(hottest) = self.hottest(args)? else {
+            return Ok(());
+        };
+        let Some(func) = self.program.functions.iter().find(|f| f.name == hottest) else {
+            return Ok(());
+        };
+        if let Err(why) = evolvable(func) {
+            tracing::info!("Not evolving {}(): {}", hottest, why);
+            return Ok(());
+        }
+
+        let mut interpreter = Interpreter::new(&self.program);
+        let mut test_cases = Vec::new();
+        for input in EVOLUTION_INPUTS {
+            let args: &[i64] = if func.args.is_empty() { &[] } else { &[input] };
+            test_cases.push(TestCase::new(input, interpreter.call(&hottest, args)?));
+        }
+        let config = EvolutionConfig {
+            population_size: options.population,
+            seed: options.seed,
+            ..EvolutionConfig::default()
+        };
+        let seed = func.clone();
+        let handle = thread::Builder::new()
+            .name("nf-evolve".to_string())
+            .spawn(move || {
+                let mut engine = EvolutionEngine::new(&seed, test_cases.clone(), config);
+                let result = engine.run(options.generations, None);
+                let verified = Validator::default()
+                    .validate(&result.best_genome, &test_cases)
+                    .is_valid();
+                (verified && result.final_speedup > 1.0).then(|| result.best_genome.to_function())
+            })
+            .map_err(|e| format!("failed to start evolution: {}", e))?;
+        tracing::info!("Evolving {}() in the background", hottest);
+        self.evolution = Some(Evolution {
+            function: hottest,
+            handle,
+        });
+        Ok(())
+    }
+
+    /// Swap `winner` in for `function` if the entry still returns `expected`
+    fn try_swap(
+        &mut self,
+        function: &str,
+        winner: Function,
+        args: &[i64],
+        expected: i64,
+    ) -> Result<(), String> {
+        let mut evolved = self.program.clone();
+        for f in evolved.functions.iter_mut().filter(|f| f.name == function) {
+            *f = winner.clone();
+        }
+        let candidate = CompiledProgram::compile(&evolved, &CompileOptions::new(3))?;
+        let result = candidate
+            .get_fn(&self.entry)
+            .ok_or_else(|| format!("{}() was not compiled", self.entry))?
+            .call(args);
+        if result != Ok(expected) {
+            tracing::warn!(
+                "Evolved {}() changed {}() from {} to {:?}; keeping the optimized tier",
+                function,
+                self.entry,
+                expected,
+                result
+            );
+            return Ok(());
+        }
+        self.evolved = Some(evolved);
+        let decision = self.policy.on_evolved(&self.entry, Instant::now());
+        let reason = format!("evolved {}() re-verified", function);
+        self.apply(decision, Tier::Optimized, reason, args)
+    }
+}
+
+impl Drop for AdaptiveRuntime {
+    fn drop(&mut self) {
+        if let Some(audit) = &mut self.audit {
+            if let Some(event) = audit.pending.take() {
+                audit.log.record(event);
+            }
+        }
+    }
+}
+
+fn tier_name(tier: Tier) -> String {
+    format!("{:?}", tier).to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    fn program() -> Program {
+        Parser::new()
+            .parse(
+                "fn main(n) {
+                    s = 0
+                    i = 0
+                    while i < n {
+                        s = s + i
+                        i = i + 1
+                    }
+                    return s
+                }",
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn test_climbs_the_ladder_and_keeps_results() {
+        let tiering = TieringConfig::default()
+            .start(Tier::Interpreted)
+            .jit_after_calls(5)
+            .cooldown(Duration::ZERO)
+            .promote_share(0.0)
+            .demote_share(0.0);
+        let config = AdaptiveConfig::default()
+            .tiering(tiering)
+            .window(Duration::ZERO);
+        let mut runtime = AdaptiveRuntime::new(program(), "main", config.clone()).unwrap();
+        assert_eq!(runtime.tier(), Tier::Interpreted);
+        for _ in 0..20 {
+            assert_eq!(runtime.call(&[100]), Ok(4950));
+        }
+        assert_eq!(runtime.tier(), Tier::Optimized);
+        let ladder: Vec<_> = runtime
+            .changes()
+            .iter()
+            .map(|c| (c.call, c.from, c.to))
+            .collect();
+        assert_eq!(
+            ladder,
+            [
+                (5, Tier::Interpreted, Tier::Baseline),
+                (6, Tier::Baseline, Tier::Optimized)
+            ]
+        );
+        assert_eq!(runtime.stats()[&Tier::Interpreted].calls, 5);
+        assert_eq!(runtime.stats()[&Tier::Optimized].calls, 14);
+
+        let audit = AuditLog::new();
+        let mut audited = AdaptiveRuntime::new(program(), "main", config)
+            .unwrap()
+            .with_audit(audit.clone(), "sum");
+        for _ in 0..6 {
+            audited.call(&[100]).unwrap();
+        }
+        // The promotion at call 6 is recorded once the optimized tier has
+        // timed a call
+        let events = audit.recent(10).unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].action, AuditAction::Promote);
+        let tiers = (events[0].from.as_deref(), events[0].to.as_str());
+        assert_eq!(tiers, (Some("interpreted"), "baseline"));
+        assert_eq!(events[0].trigger, "called 5 times");
+        assert!(events[0].before.is_some() && events[0].after.is_some(), "{:?}", events);
+        drop(audited);
+        let events = audit.recent(10).unwrap();
+        assert_eq!(events[1].to, "optimized");
+        assert_eq!(events[1].after, None);
+        assert_eq!(runtime.hot_function(), Some("main"));
+        // n = 100 was expected: other values take the generic loop
+        assert_eq!(runtime.call(&[50]), Ok(1225));
+    }
+
+    #[test]
+    fn test_optimizes_only_the_hot_function() {
+        let prog = Parser::new()
+            .parse(
+                "fn main(n) {
+                    s = kernel(n)
+                    t = s + 1
+                    return t
+                }
+                fn kernel(n) {
+                    s = 0
+                    i = 0
+                    while i < n {
+                        s = s + i
+                        i = i + 1
+                    }
+                    return s
+                }",
+            )
+            .unwrap();
+        let tiering = TieringConfig::default()
+            .start(Tier::Baseline)
+            .cooldown(Duration::ZERO)
+            .promote_share(0.0)
+            .demote_share(0.0);
+        let config = AdaptiveConfig::default()
+            .tiering(tiering)
+            .window(Duration::ZERO);
+        let mut runtime = AdaptiveRuntime::new(prog, "main", config).unwrap();
+        for _ in 0..3 {
+            assert_eq!(runtime.call(&[10000]), Ok(49995001));
+        }
+        assert_eq!(runtime.tier(), Tier::Optimized);
+        assert_eq!(runtime.hot_function(), Some("kernel"));
+    }
+
+    #[test]
+    fn test_starts_from_templates() {
+        let tiering = TieringConfig::default()
+            .start(Tier::Template)
+            .cooldown(Duration::ZERO)
+            .promote_share(0.0)
+            .demote_share(0.0);
+        let config = AdaptiveConfig::default()
+            .tiering(tiering)
+            .window(Duration::ZERO);
+        let mut runtime = AdaptiveRuntime::new(program(), "main", config).unwrap();
+        assert_eq!(runtime.tier(), Tier::Template);
+        assert_eq!(runtime.stitched.is_some(), cfg!(target_arch = "x86_64"));
+        for _ in 0..3 {
+            assert_eq!(runtime.call(&[100]), Ok(4950));
+        }
+        let ladder: Vec<_> = runtime.changes().iter().map(|c| (c.from, c.to)).collect();
+        assert_eq!(ladder, [(Tier::Template, Tier::Optimized)]);
+        assert_eq!(runtime.stats()[&Tier::Template].calls, 1);
+        assert!(runtime.stats()[&Tier::Template].compile > Duration::ZERO);
+    }
+
+    #[test]
+    fn test_only_standalone_functions_evolve() {
+        let prog = Parser::new()
+            .parse(
+                "fn main(a, n) {
+                    v = a[n]
+                    return v
+                }",
+            )
+            .unwrap();
+        assert_eq!(
+            evolvable(&prog.functions[0]),
+            Err("takes 2 arguments".to_string())
+        );
+        assert!(AdaptiveRuntime::new(prog, "missing", AdaptiveConfig::default()).is_err());
+    }
+}
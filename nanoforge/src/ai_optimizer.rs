@@ -3,10 +3,13 @@
 //! Implements Thompson Sampling and Contextual Bandits for intelligent
 //! variant selection based on runtime feedback.
 
-use rand::Rng;
+use crate::cost_model;
+use crate::store::{self, Schema};
+use crate::variant_generator::VariantConfig;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::fs;
 use std::path::Path;
 
 /// Size buckets for contextual decision making
@@ -37,6 +40,28 @@ impl SizeBucket {
         }
     }
 
+    /// Smallest input size in the bucket
+    pub fn min_size(&self) -> u64 {
+        match self {
+            SizeBucket::Tiny => 0,
+            SizeBucket::Small => 32,
+            SizeBucket::Medium => 256,
+            SizeBucket::Large => 4096,
+            SizeBucket::Huge => 65536,
+        }
+    }
+
+    /// Typical input size, where priors for the bucket are predicted
+    pub fn representative_size(&self) -> u64 {
+        match self {
+            SizeBucket::Tiny => 16,
+            SizeBucket::Small => 128,
+            SizeBucket::Medium => 1024,
+            SizeBucket::Large => 16384,
+            SizeBucket::Huge => 262144,
+        }
+    }
+
     /// Get all bucket variants for initialization
     pub fn all() -> Vec<SizeBucket> {
         vec![
@@ -131,9 +156,14 @@ pub struct VariantBandit {
     variant_names: Vec<String>,
     /// Total selections per variant
     selections: Vec<u64>,
+    /// Seeded RNG for reproducible runs (`None` = thread RNG)
+    #[serde(skip)]
+    rng: Option<StdRng>,
 }
 
 impl VariantBandit {
+    pub const SCHEMA: Schema = Schema::new("brain", 1);
+
     /// Create a new bandit with uniform priors
     pub fn new(variant_names: Vec<String>) -> Self {
         let n = variant_names.len();
@@ -143,21 +173,45 @@ impl VariantBandit {
             failures: vec![1.0; n],
             variant_names,
             selections: vec![0; n],
+            rng: None,
         }
     }
 
+    /// Create a bandit whose sampling is reproducible for a given seed
+    pub fn with_seed(variant_names: Vec<String>, seed: u64) -> Self {
+        let mut bandit = Self::new(variant_names);
+        bandit.set_seed(seed);
+        bandit
+    }
+
+    /// Switch to a seeded RNG (e.g. after loading saved state)
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = Some(StdRng::seed_from_u64(seed));
+    }
+
+    /// Replace the uniform prior with `strength` pseudo-observations of the
+    /// given rewards (0.0 - 1.0), one per variant
+    pub fn set_prior(&mut self, rewards: &[f64], strength: f64) {
+        for (i, &reward) in rewards.iter().enumerate().take(self.num_variants) {
+            let reward = reward.clamp(0.0, 1.0);
+            self.successes[i] = 1.0 + strength * reward;
+            self.failures[i] = 1.0 + strength * (1.0 - reward);
+        }
+    }
+
+    /// Start from the cost model's predictions for `configs` (in variant order) at `input_size`
+    pub fn warm_start(&mut self, configs: &[VariantConfig], input_size: u64) {
+        self.set_prior(&cost_model::prior_rewards(configs, input_size), PRIOR_STRENGTH);
+    }
+
     /// Select a variant using Thompson Sampling
     /// Returns the index of the selected variant
     pub fn select(&mut self) -> usize {
-        let mut rng = rand::thread_rng();
-
         // Sample from each arm's Beta distribution
-        let samples: Vec<f64> = self
-            .successes
-            .iter()
-            .zip(&self.failures)
-            .map(|(&a, &b)| sample_beta(&mut rng, a, b))
-            .collect();
+        let samples = match self.rng.as_mut() {
+            Some(rng) => sample_arms(rng, &self.successes, &self.failures),
+            None => sample_arms(&mut rand::thread_rng(), &self.successes, &self.failures),
+        };
 
         // Select the arm with highest sample
         let selected = samples
@@ -197,12 +251,7 @@ impl VariantBandit {
             return;
         }
 
-        // Calculate relative performance (0.0 = worst, 1.0 = best)
-        let performance_ratio = if cycles > 0 {
-            best_cycles as f64 / cycles as f64
-        } else {
-            0.0
-        };
+        let performance_ratio = performance_reward(cycles, best_cycles);
 
         // Update Beta parameters proportionally
         self.successes[variant_idx] += performance_ratio;
@@ -256,16 +305,25 @@ impl VariantBandit {
 
     /// Save bandit state to a JSON file
     pub fn save_to_file(&self, path: &Path) -> Result<(), String> {
-        let json = serde_json::to_string_pretty(self)
-            .map_err(|e| format!("Failed to serialize: {}", e))?;
-        fs::write(path, json).map_err(|e| format!("Failed to write file: {}", e))?;
-        Ok(())
+        store::save(path, Self::SCHEMA, self)
     }
 
     /// Load bandit state from a JSON file
     pub fn load_from_file(path: &Path) -> Result<Self, String> {
-        let json = fs::read_to_string(path).map_err(|e| format!("Failed to read file: {}", e))?;
-        serde_json::from_str(&json).map_err(|e| format!("Failed to deserialize: {}", e))
+        store::load(path, Self::SCHEMA)?.ok_or_else(|| format!("{}: not found", path.display()))
+    }
+}
+
+/// Pseudo-observations a cost-model prior is worth: enough to make early
+/// selections sensible, few enough that a handful of measurements override it
+pub const PRIOR_STRENGTH: f64 = 4.0;
+
+/// Relative performance fed to the bandit (0.0 = worst, 1.0 = best)
+pub fn performance_reward(cycles: u64, best_cycles: u64) -> f64 {
+    if cycles > 0 {
+        best_cycles as f64 / cycles as f64
+    } else {
+        0.0
     }
 }
 
@@ -278,6 +336,15 @@ pub struct VariantStats {
     pub confidence: f64,
 }
 
+/// Draw one Thompson sample per arm from its Beta(successes, failures) posterior
+fn sample_arms<R: Rng>(rng: &mut R, successes: &[f64], failures: &[f64]) -> Vec<f64> {
+    successes
+        .iter()
+        .zip(failures)
+        .map(|(&a, &b)| sample_beta(rng, a, b))
+        .collect()
+}
+
 /// Sample from Beta distribution using rejection sampling
 fn sample_beta<R: Rng>(rng: &mut R, alpha: f64, beta: f64) -> f64 {
     // Simple approximation using Gamma distribution
@@ -344,6 +411,8 @@ pub struct ContextualBandit {
 }
 
 impl ContextualBandit {
+    pub const SCHEMA: Schema = Schema::new("context-brain", 1);
+
     /// Create a new contextual bandit
     pub fn new(variant_names: Vec<String>) -> Self {
         let mut bandits = HashMap::new();
@@ -359,6 +428,30 @@ impl ContextualBandit {
         }
     }
 
+    /// Create a contextual bandit whose sampling is reproducible for a given seed
+    pub fn with_seed(variant_names: Vec<String>, seed: u64) -> Self {
+        let mut bandit = Self::new(variant_names);
+        bandit.set_seed(seed);
+        bandit
+    }
+
+    /// Seed every per-bucket bandit (each bucket gets its own derived stream)
+    pub fn set_seed(&mut self, seed: u64) {
+        for (i, bucket) in SizeBucket::all().into_iter().enumerate() {
+            if let Some(bandit) = self.bandits.get_mut(&bucket) {
+                bandit.set_seed(seed.wrapping_add(i as u64));
+            }
+        }
+    }
+
+    /// Start every bucket from the cost model's predictions at its
+    /// representative size; `configs` must be in variant order
+    pub fn warm_start(&mut self, configs: &[VariantConfig]) {
+        for (bucket, bandit) in self.bandits.iter_mut() {
+            bandit.warm_start(configs, bucket.representative_size());
+        }
+    }
+
     /// Select a variant based on context (input size)
     pub fn select(&mut self, context: &OptimizationFeatures) -> usize {
         let bucket = context.size_bucket();
@@ -401,6 +494,16 @@ impl ContextualBandit {
         self.bandits.get(&bucket).map(|b| b.get_best()).unwrap_or(0)
     }
 
+    /// The bandit learning `bucket`
+    pub fn bucket_bandit(&self, bucket: SizeBucket) -> Option<&VariantBandit> {
+        self.bandits.get(&bucket)
+    }
+
+    /// Variant names, in arm order
+    pub fn variant_names(&self) -> &[String] {
+        &self.variant_names
+    }
+
     /// Get the learned decision boundary as a summary
     pub fn get_decision_boundary(&self) -> Vec<(SizeBucket, String, f64)> {
         let mut decisions = Vec::new();
@@ -460,18 +563,15 @@ impl ContextualBandit {
 
     /// Save contextual bandit state to a JSON file
     pub fn save_to_file(&self, path: &Path) -> Result<(), String> {
-        let json = serde_json::to_string_pretty(self)
-            .map_err(|e| format!("Failed to serialize: {}", e))?;
-        fs::write(path, json).map_err(|e| format!("Failed to write file: {}", e))?;
+        store::save(path, Self::SCHEMA, self)?;
         println!("💾 Saved AI knowledge to {:?}", path);
         Ok(())
     }
 
     /// Load contextual bandit state from a JSON file
     pub fn load_from_file(path: &Path) -> Result<Self, String> {
-        let json = fs::read_to_string(path).map_err(|e| format!("Failed to read file: {}", e))?;
-        let bandit =
-            serde_json::from_str(&json).map_err(|e| format!("Failed to deserialize: {}", e))?;
+        let bandit = store::load(path, Self::SCHEMA)?
+            .ok_or_else(|| format!("{}: not found", path.display()))?;
         println!("📂 Loaded AI knowledge from {:?}", path);
         Ok(bandit)
     }
@@ -597,6 +697,56 @@ mod tests {
         assert_eq!(best, 1, "Should converge to AVX2x2");
     }
 
+    #[test]
+    fn test_seeded_bandit_is_reproducible() {
+        let names: Vec<String> = (0..4).map(|i| format!("v{}", i)).collect();
+        let run = |seed| {
+            let mut bandit = VariantBandit::with_seed(names.clone(), seed);
+            (0..50)
+                .map(|_| {
+                    let selected = bandit.select();
+                    bandit.update(selected, selected == 2);
+                    selected
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(run(7), run(7));
+
+        let context = OptimizationFeatures::new(10);
+        let mut a = ContextualBandit::with_seed(names.clone(), 7);
+        let mut b = ContextualBandit::with_seed(names.clone(), 7);
+        for _ in 0..20 {
+            assert_eq!(a.select(&context), b.select(&context));
+        }
+    }
+
+    #[test]
+    fn test_warm_start_follows_cost_model_until_data_disagrees() {
+        use crate::variant_generator::IsaExtension;
+
+        let configs = vec![
+            VariantConfig::new(IsaExtension::Scalar, 4, 2),
+            VariantConfig::new(IsaExtension::Avx2, 4, 3),
+        ];
+        let names: Vec<String> = configs.iter().map(|c| c.name.clone()).collect();
+        let mut bandit = ContextualBandit::with_seed(names, 3);
+        bandit.warm_start(&configs);
+
+        let tiny = OptimizationFeatures::new(8);
+        let huge = OptimizationFeatures::new(1_000_000);
+        assert_eq!(bandit.get_best_for_context(&tiny), 0);
+        assert_eq!(bandit.get_best_for_context(&huge), 1);
+        let early = (0..20).filter(|_| bandit.select(&huge) == 1).count();
+        assert!(early >= 15, "only {} of 20 early picks were AVX2", early);
+
+        // Measurements where scalar wins on huge inputs override the prior
+        for _ in 0..10 {
+            bandit.update_with_performance(&huge, 0, 100, 100);
+            bandit.update_with_performance(&huge, 1, 400, 100);
+        }
+        assert_eq!(bandit.get_best_for_context(&huge), 0);
+    }
+
     #[test]
     fn test_contextual_selector() {
         let names = vec!["Scalar".to_string(), "AVX2".to_string()];
@@ -0,0 +1,33 @@
+//! Aligned Allocation
+//!
+//! The alignment analysis (see `nanoforge_core::alignment`), re-exported,
+//! and the allocator `alloc_aligned` calls in compiled and interpreted code.
+
+pub use nanoforge_core::alignment::*;
+
+/// `alloc_aligned` in compiled code: `size` bytes at a multiple of `align`
+/// (a power of two, at least 8), or null
+pub extern "C" fn nf_alloc_aligned(size: i64, align: i64) -> *mut u8 {
+    let mut ptr = std::ptr::null_mut();
+    let size = size.max(1) as usize;
+    match unsafe { libc::posix_memalign(&mut ptr, align as usize, size) } {
+        0 => ptr as *mut u8,
+        _ => std::ptr::null_mut(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_aligned_allocation() {
+        for align in [8, 64, 4096] {
+            let ptr = nf_alloc_aligned(100, align);
+            assert!(!ptr.is_null());
+            assert_eq!(ptr as usize % align as usize, 0);
+            unsafe { libc::free(ptr as *mut libc::c_void) };
+        }
+        assert!(nf_alloc_aligned(8, 24).is_null());
+    }
+}
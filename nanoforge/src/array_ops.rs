@@ -7,17 +7,19 @@
 //! - JIT code compiled once and cached via OnceLock
 //! - 4x loop unrolling (16 elements per iteration using 8 YMM registers)
 //! - Aggressive prefetching (2 cache lines ahead)
-//! - Non-temporal stores for large arrays (>1MB) to bypass cache
+//! - Non-temporal stores for large arrays to bypass cache (from 1MB, or the
+//!   micro-architecture database's threshold for this CPU)
+//!
+//! Matrix multiply (i64, and int8 into i32) comes in scalar, AVX2 and AMX
+//! tile kernels, with a bandit picking the fastest per matrix size.
 
 use crate::cpu_features::CpuFeatures;
+use crate::dispatch::Dispatcher;
 use crate::jit_memory::DualMappedMemory;
+use crate::uarch_db::UarchDefaults;
 use dynasmrt::{dynasm, x64::Assembler, DynasmApi, DynasmLabelApi};
 use std::sync::OnceLock;
 
-// Threshold for using non-temporal stores (elements)
-// 1MB of i64 = 131072 elements
-const NT_STORE_THRESHOLD: usize = 131072;
-
 /// Cached JIT function for vec_add (regular stores)
 struct CachedVecAdd {
     #[allow(dead_code)]
@@ -53,9 +55,9 @@ pub fn vec_add_i64(a: &[i64], b: &[i64], c: &mut [i64]) {
 
     if features.has_avx2 && n >= 16 {
         // Check if output is 32-byte aligned for NT stores
-        let c_aligned = (c.as_ptr() as usize) % 32 == 0;
+        let c_aligned = (c.as_ptr() as usize).is_multiple_of(32);
 
-        if n >= NT_STORE_THRESHOLD && c_aligned {
+        if n >= UarchDefaults::cached().nt_store_threshold && c_aligned {
             // Large array with aligned output: use non-temporal stores
             let cached = VEC_ADD_AVX2_NT.get_or_init(|| {
                 init_vec_add_avx2_nt().expect("Failed to initialize AVX2 NT vec_add")
@@ -79,14 +81,9 @@ pub fn vec_add_i64(a: &[i64], b: &[i64], c: &mut [i64]) {
 fn init_vec_add_avx2() -> Result<CachedVecAdd, String> {
     let code = generate_vec_add_avx2_regular()?;
 
-    let memory = DualMappedMemory::new(code.len().max(4096))
+    let memory = DualMappedMemory::with_code(&code)
         .map_err(|e| format!("Failed to allocate JIT memory: {}", e))?;
 
-    unsafe {
-        std::ptr::copy_nonoverlapping(code.as_ptr(), memory.rw_ptr, code.len());
-    }
-    memory.flush_icache();
-
     let func: extern "C" fn(*const i64, *const i64, *mut i64, usize) =
         unsafe { std::mem::transmute(memory.rx_ptr) };
 
@@ -97,14 +94,9 @@ fn init_vec_add_avx2() -> Result<CachedVecAdd, String> {
 fn init_vec_add_avx2_nt() -> Result<CachedVecAdd, String> {
     let code = generate_vec_add_avx2_nt()?;
 
-    let memory = DualMappedMemory::new(code.len().max(4096))
+    let memory = DualMappedMemory::with_code(&code)
         .map_err(|e| format!("Failed to allocate JIT memory: {}", e))?;
 
-    unsafe {
-        std::ptr::copy_nonoverlapping(code.as_ptr(), memory.rw_ptr, code.len());
-    }
-    memory.flush_icache();
-
     let func: extern "C" fn(*const i64, *const i64, *mut i64, usize) =
         unsafe { std::mem::transmute(memory.rx_ptr) };
 
@@ -307,14 +299,9 @@ pub fn vec_sum_i64(arr: &[i64]) -> i64 {
 fn init_vec_sum_avx2() -> Result<CachedVecSum, String> {
     let code = generate_vec_sum_avx2_ultra()?;
 
-    let memory = DualMappedMemory::new(code.len().max(4096))
+    let memory = DualMappedMemory::with_code(&code)
         .map_err(|e| format!("Failed to allocate JIT memory: {}", e))?;
 
-    unsafe {
-        std::ptr::copy_nonoverlapping(code.as_ptr(), memory.rw_ptr, code.len());
-    }
-    memory.flush_icache();
-
     let func: extern "C" fn(*const i64, usize) -> i64 =
         unsafe { std::mem::transmute(memory.rx_ptr) };
 
@@ -401,6 +388,600 @@ pub fn vec_scale_i64(arr: &mut [i64], scalar: i64) {
     }
 }
 
+// ============================================================================
+// Matrix Multiply
+// ============================================================================
+//
+// Row-major `C = A · B` with A m×k, B k×n and C m×n, in three kernel
+// families: portable scalar loops, JIT'd AVX2 kernels that keep a strip of
+// C in registers while walking k, and (int8 only) AMX tiles. The public
+// entry points let a per-size-bucket bandit pick among the kernels the CPU
+// supports; the `_with` variants run one kernel explicitly.
+
+/// A matrix multiply implementation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MatmulKernel {
+    /// Plain i-k-j loops
+    Scalar,
+    /// JIT'd AVX2 kernel computing 1×16 (i64) or 1×32 (i8) strips of C
+    Avx2Blocked,
+    /// AMX `tdpbssd` over 16×64 int8 tiles (int8 only)
+    AmxTile,
+}
+
+impl MatmulKernel {
+    pub const ALL: [MatmulKernel; 3] = [
+        MatmulKernel::Scalar,
+        MatmulKernel::Avx2Blocked,
+        MatmulKernel::AmxTile,
+    ];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|kernel| kernel.name() == name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            MatmulKernel::Scalar => "scalar",
+            MatmulKernel::Avx2Blocked => "avx2-blocked",
+            MatmulKernel::AmxTile => "amx-tile",
+        }
+    }
+
+    /// Kernels usable for i64 matrices on this CPU
+    pub fn available_i64() -> Vec<MatmulKernel> {
+        let mut kernels = vec![MatmulKernel::Scalar];
+        if CpuFeatures::detect().has_avx2 {
+            kernels.push(MatmulKernel::Avx2Blocked);
+        }
+        kernels
+    }
+
+    /// Kernels usable for int8 matrices on this CPU
+    pub fn available_i8() -> Vec<MatmulKernel> {
+        let mut kernels = Self::available_i64();
+        let features = CpuFeatures::detect();
+        if features.has_amx_tile && features.has_amx_int8 && amx_permitted() {
+            kernels.push(MatmulKernel::AmxTile);
+        }
+        kernels
+    }
+}
+
+/// Operands of one multiply, as handed to the dispatcher's variants.
+/// Only built by the public entry points after the shapes are checked.
+struct MatmulJob<T, Acc> {
+    a: *const T,
+    b: *const T,
+    c: *mut Acc,
+    m: usize,
+    k: usize,
+    n: usize,
+}
+
+impl<T, Acc> MatmulJob<T, Acc> {
+    fn new(a: &[T], b: &[T], c: &mut [Acc], m: usize, k: usize, n: usize) -> Result<Self, String> {
+        let (am, bk, cm) = (m.checked_mul(k), k.checked_mul(n), m.checked_mul(n));
+        if am != Some(a.len()) || bk != Some(b.len()) || cm != Some(c.len()) {
+            return Err(format!(
+                "Matrix shape mismatch for {}x{} * {}x{}: a={}, b={}, c={}",
+                m,
+                k,
+                k,
+                n,
+                a.len(),
+                b.len(),
+                c.len()
+            ));
+        }
+        Ok(Self {
+            a: a.as_ptr(),
+            b: b.as_ptr(),
+            c: c.as_mut_ptr(),
+            m,
+            k,
+            n,
+        })
+    }
+
+    /// Multiply-adds performed, the size the bandit buckets by
+    fn work(&self) -> u64 {
+        (self.m as u64)
+            .saturating_mul(self.k as u64)
+            .saturating_mul(self.n as u64)
+    }
+
+    fn run(&self, kernel: MatmulFn<T, Acc>) {
+        // SAFETY: `new` checked the lengths against the borrowed slices,
+        // which outlive the job
+        let (a, b, c) = unsafe {
+            (
+                std::slice::from_raw_parts(self.a, self.m * self.k),
+                std::slice::from_raw_parts(self.b, self.k * self.n),
+                std::slice::from_raw_parts_mut(self.c, self.m * self.n),
+            )
+        };
+        kernel(a, b, c, self.m, self.k, self.n)
+    }
+}
+
+type MatmulFn<T, Acc> = fn(&[T], &[T], &mut [Acc], usize, usize, usize);
+
+fn matmul_i64_kernel(kernel: MatmulKernel) -> MatmulFn<i64, i64> {
+    match kernel {
+        MatmulKernel::Avx2Blocked => matmul_i64_avx2,
+        _ => matmul_i64_scalar,
+    }
+}
+
+fn matmul_i8_kernel(kernel: MatmulKernel) -> MatmulFn<i8, i32> {
+    match kernel {
+        MatmulKernel::Scalar => matmul_i8_scalar,
+        MatmulKernel::Avx2Blocked => matmul_i8_avx2,
+        MatmulKernel::AmxTile => matmul_i8_amx,
+    }
+}
+
+static MATMUL_I64: OnceLock<Dispatcher<MatmulJob<i64, i64>, ()>> = OnceLock::new();
+static MATMUL_I8: OnceLock<Dispatcher<MatmulJob<i8, i32>, ()>> = OnceLock::new();
+
+fn matmul_dispatcher<T: 'static, Acc: 'static>(
+    kernels: Vec<MatmulKernel>,
+    lookup: fn(MatmulKernel) -> MatmulFn<T, Acc>,
+) -> Dispatcher<MatmulJob<T, Acc>, ()> {
+    kernels.into_iter().fold(
+        Dispatcher::new(MatmulJob::<T, Acc>::work).trials_per_bucket(16),
+        |dispatcher, kernel| {
+            let f = lookup(kernel);
+            dispatcher.variant(kernel.name(), move |job: &MatmulJob<T, Acc>| job.run(f))
+        },
+    )
+}
+
+/// i64 matrix multiply `C = A · B` (row-major, wrapping), with the kernel
+/// chosen per size by the bandit
+pub fn matmul_i64(
+    a: &[i64],
+    b: &[i64],
+    c: &mut [i64],
+    m: usize,
+    k: usize,
+    n: usize,
+) -> Result<(), String> {
+    let job = MatmulJob::new(a, b, c, m, k, n)?;
+    MATMUL_I64
+        .get_or_init(|| matmul_dispatcher(MatmulKernel::available_i64(), matmul_i64_kernel))
+        .call(&job);
+    Ok(())
+}
+
+/// i64 matrix multiply with an explicit kernel
+pub fn matmul_i64_with(
+    kernel: MatmulKernel,
+    a: &[i64],
+    b: &[i64],
+    c: &mut [i64],
+    m: usize,
+    k: usize,
+    n: usize,
+) -> Result<(), String> {
+    if !MatmulKernel::available_i64().contains(&kernel) {
+        return Err(format!(
+            "Kernel '{}' not available for i64 on this CPU",
+            kernel.name()
+        ));
+    }
+    MatmulJob::new(a, b, c, m, k, n)?.run(matmul_i64_kernel(kernel));
+    Ok(())
+}
+
+/// int8 matrix multiply into i32 `C = A · B`, with the kernel chosen per
+/// size by the bandit
+pub fn matmul_i8(
+    a: &[i8],
+    b: &[i8],
+    c: &mut [i32],
+    m: usize,
+    k: usize,
+    n: usize,
+) -> Result<(), String> {
+    let job = MatmulJob::new(a, b, c, m, k, n)?;
+    MATMUL_I8
+        .get_or_init(|| matmul_dispatcher(MatmulKernel::available_i8(), matmul_i8_kernel))
+        .call(&job);
+    Ok(())
+}
+
+/// int8 matrix multiply with an explicit kernel
+pub fn matmul_i8_with(
+    kernel: MatmulKernel,
+    a: &[i8],
+    b: &[i8],
+    c: &mut [i32],
+    m: usize,
+    k: usize,
+    n: usize,
+) -> Result<(), String> {
+    if !MatmulKernel::available_i8().contains(&kernel) {
+        return Err(format!(
+            "Kernel '{}' not available for int8 on this CPU",
+            kernel.name()
+        ));
+    }
+    MatmulJob::new(a, b, c, m, k, n)?.run(matmul_i8_kernel(kernel));
+    Ok(())
+}
+
+/// Kernel the bandit settled on for i64 matrices of this shape, once decided
+pub fn matmul_i64_winner(m: usize, k: usize, n: usize) -> Option<&'static str> {
+    let size = (m as u64).saturating_mul(k as u64).saturating_mul(n as u64);
+    MATMUL_I64.get()?.winner(size)
+}
+
+/// Kernel the bandit settled on for int8 matrices of this shape, once decided
+pub fn matmul_i8_winner(m: usize, k: usize, n: usize) -> Option<&'static str> {
+    let size = (m as u64).saturating_mul(k as u64).saturating_mul(n as u64);
+    MATMUL_I8.get()?.winner(size)
+}
+
+fn matmul_i64_scalar(a: &[i64], b: &[i64], c: &mut [i64], m: usize, k: usize, n: usize) {
+    c.fill(0);
+    for i in 0..m {
+        for p in 0..k {
+            let x = a[i * k + p];
+            for j in 0..n {
+                c[i * n + j] = c[i * n + j].wrapping_add(x.wrapping_mul(b[p * n + j]));
+            }
+        }
+    }
+}
+
+fn matmul_i8_scalar(a: &[i8], b: &[i8], c: &mut [i32], m: usize, k: usize, n: usize) {
+    c.fill(0);
+    for i in 0..m {
+        for p in 0..k {
+            let x = a[i * k + p] as i32;
+            for j in 0..n {
+                c[i * n + j] = c[i * n + j].wrapping_add(x * b[p * n + j] as i32);
+            }
+        }
+    }
+}
+
+/// JIT'd strip kernel: `c[0..w] = Σp a_row[p] * b[p * stride + 0..w]`.
+/// Args: a_row, b (first column of the strip), c, k, B's row stride in bytes.
+type StripFn = extern "C" fn(*const u8, *const u8, *mut u8, usize, usize);
+
+struct CachedStripKernel {
+    #[allow(dead_code)]
+    memory: DualMappedMemory,
+    func: StripFn,
+}
+
+unsafe impl Send for CachedStripKernel {}
+unsafe impl Sync for CachedStripKernel {}
+
+/// 16- and 4-column i64 strips
+static MATMUL_I64_AVX2: OnceLock<[CachedStripKernel; 2]> = OnceLock::new();
+/// 32- and 8-column int8 strips
+static MATMUL_I8_AVX2: OnceLock<[CachedStripKernel; 2]> = OnceLock::new();
+
+fn load_strip_kernel(code: Vec<u8>) -> Result<CachedStripKernel, String> {
+    let memory = DualMappedMemory::with_code(&code)
+        .map_err(|e| format!("Failed to allocate JIT memory: {}", e))?;
+    let func: StripFn = unsafe { std::mem::transmute(memory.rx_ptr) };
+    Ok(CachedStripKernel { memory, func })
+}
+
+/// Run `strips` (`widths` columns each, widest first) across each row of C,
+/// then fill the leftover columns with `column(a_row, j)`
+fn matmul_strips<T, Acc>(
+    strips: &[CachedStripKernel; 2],
+    widths: [usize; 2],
+    (a, b, c): (&[T], &[T], &mut [Acc]),
+    (m, k, n): (usize, usize, usize),
+    column: impl Fn(&[T], usize) -> Acc,
+) {
+    let elem = std::mem::size_of::<T>();
+    for i in 0..m {
+        let a_row = &a[i * k..(i + 1) * k];
+        let mut j = 0;
+        for (strip, width) in strips.iter().zip(widths) {
+            while j + width <= n {
+                (strip.func)(
+                    a_row.as_ptr() as *const u8,
+                    b[j..].as_ptr() as *const u8,
+                    c[i * n + j..].as_mut_ptr() as *mut u8,
+                    k,
+                    n * elem,
+                );
+                j += width;
+            }
+        }
+        for j in j..n {
+            c[i * n + j] = column(a_row, j);
+        }
+    }
+}
+
+fn matmul_i64_avx2(a: &[i64], b: &[i64], c: &mut [i64], m: usize, k: usize, n: usize) {
+    let strips = MATMUL_I64_AVX2.get_or_init(|| {
+        [4, 1].map(|vectors| {
+            generate_matmul_i64_avx2(vectors)
+                .and_then(load_strip_kernel)
+                .expect("Failed to initialize AVX2 i64 matmul")
+        })
+    });
+    matmul_strips(strips, [16, 4], (a, b, c), (m, k, n), |a_row, j| {
+        (0..k).fold(0i64, |acc, p| {
+            acc.wrapping_add(a_row[p].wrapping_mul(b[p * n + j]))
+        })
+    });
+}
+
+fn matmul_i8_avx2(a: &[i8], b: &[i8], c: &mut [i32], m: usize, k: usize, n: usize) {
+    let strips = MATMUL_I8_AVX2.get_or_init(|| {
+        [4, 1].map(|vectors| {
+            generate_matmul_i8_avx2(vectors)
+                .and_then(load_strip_kernel)
+                .expect("Failed to initialize AVX2 int8 matmul")
+        })
+    });
+    matmul_strips(strips, [32, 8], (a, b, c), (m, k, n), |a_row, j| {
+        (0..k).fold(0i32, |acc, p| {
+            acc.wrapping_add(a_row[p] as i32 * b[p * n + j] as i32)
+        })
+    });
+}
+
+/// i64 strip of `vectors` × 4 columns, accumulated in ymm0..ymm3. AVX2 has
+/// no 64-bit multiply, so each product is built from 32-bit halves:
+/// `lo(a)·lo(b) + ((hi(a)·lo(b) + lo(a)·hi(b)) << 32)`. `vpmuludq` reads
+/// only the low dword of each lane, so `vpshufd 0xF5` stands in for `>> 32`
+/// (dynasm encodes the ymm shift-by-immediate as its xmm form).
+fn generate_matmul_i64_avx2(vectors: u8) -> Result<Vec<u8>, String> {
+    let mut ops = Assembler::new().map_err(|e| e.to_string())?;
+    dynasm!(ops ; .arch x64);
+    for v in 0..vectors {
+        dynasm!(ops ; vpxor Ry(v), Ry(v), Ry(v));
+    }
+    dynasm!(ops
+        ; .arch x64
+        ; test rcx, rcx
+        ; jz ->store
+        ; ->k_loop:
+        ; vpbroadcastq ymm4, [rdi]
+        ; vpshufd ymm5, ymm4, BYTE 0xF5u8 as i8
+    );
+    for v in 0..vectors {
+        let offset = v as i32 * 32;
+        dynasm!(ops
+            ; .arch x64
+            ; vmovdqu ymm6, [rsi + offset]
+            ; vpshufd ymm7, ymm6, BYTE 0xF5u8 as i8
+            ; vpmuludq ymm8, ymm4, ymm6
+            ; vpmuludq ymm9, ymm5, ymm6
+            ; vpmuludq ymm10, ymm4, ymm7
+            ; vpaddq ymm9, ymm9, ymm10
+            ; vpsllq ymm9, ymm9, 32
+            ; vpaddq Ry(v), Ry(v), ymm8
+            ; vpaddq Ry(v), Ry(v), ymm9
+        );
+    }
+    dynasm!(ops
+        ; .arch x64
+        ; add rdi, 8
+        ; add rsi, r8
+        ; dec rcx
+        ; jnz ->k_loop
+        ; ->store:
+    );
+    for v in 0..vectors {
+        let offset = v as i32 * 32;
+        dynasm!(ops ; .arch x64 ; vmovdqu [rdx + offset], Ry(v));
+    }
+    dynasm!(ops
+        ; .arch x64
+        ; vzeroupper
+        ; ret
+    );
+    let buf = ops.finalize().map_err(|e| format!("{:?}", e))?;
+    Ok(buf.to_vec())
+}
+
+/// int8 strip of `vectors` × 8 columns: B is sign-extended to i32 lanes and
+/// multiplied by the broadcast A element, accumulating in ymm0..ymm3
+fn generate_matmul_i8_avx2(vectors: u8) -> Result<Vec<u8>, String> {
+    let mut ops = Assembler::new().map_err(|e| e.to_string())?;
+    dynasm!(ops ; .arch x64);
+    for v in 0..vectors {
+        dynasm!(ops ; vpxor Ry(v), Ry(v), Ry(v));
+    }
+    dynasm!(ops
+        ; .arch x64
+        ; test rcx, rcx
+        ; jz ->store
+        ; ->k_loop:
+        ; movsx eax, BYTE [rdi]
+        ; vmovd xmm4, eax
+        ; vpbroadcastd ymm4, xmm4
+    );
+    for v in 0..vectors {
+        let offset = v as i32 * 8;
+        dynasm!(ops
+            ; .arch x64
+            ; vmovq xmm6, QWORD [rsi + offset]
+            ; vpmovsxbd ymm6, xmm6
+            ; vpmulld ymm6, ymm6, ymm4
+            ; vpaddd Ry(v), Ry(v), ymm6
+        );
+    }
+    dynasm!(ops
+        ; .arch x64
+        ; inc rdi
+        ; add rsi, r8
+        ; dec rcx
+        ; jnz ->k_loop
+        ; ->store:
+    );
+    for v in 0..vectors {
+        let offset = v as i32 * 32;
+        dynasm!(ops ; .arch x64 ; vmovdqu [rdx + offset], Ry(v));
+    }
+    dynasm!(ops
+        ; .arch x64
+        ; vzeroupper
+        ; ret
+    );
+    let buf = ops.finalize().map_err(|e| format!("{:?}", e))?;
+    Ok(buf.to_vec())
+}
+
+// AMX: C tiles are 16×16 i32, A tiles 16 rows × 64 int8 of k, and B tiles
+// 16 rows × 64 bytes in the VNNI layout (4 consecutive k per column).
+const TILE_ROWS: usize = 16;
+const TILE_K: usize = 64;
+const TILE_BYTES: usize = TILE_ROWS * TILE_K;
+
+/// Linux hands out the AMX tile-data state only on request (once per process)
+fn amx_permitted() -> bool {
+    static PERMITTED: OnceLock<bool> = OnceLock::new();
+    *PERMITTED.get_or_init(|| {
+        const ARCH_REQ_XCOMP_PERM: libc::c_long = 0x1023;
+        const XFEATURE_XTILEDATA: libc::c_long = 18;
+        unsafe {
+            libc::syscall(
+                libc::SYS_arch_prctl,
+                ARCH_REQ_XCOMP_PERM,
+                XFEATURE_XTILEDATA,
+            ) == 0
+        }
+    })
+}
+
+/// Tile kernel: `C(16×16 i32) = Σ A panel · B panel` over `k_tiles` tile pairs.
+/// Args: tile config, A panel, B panel, C tile, k_tiles.
+type AmxTileFn = extern "C" fn(*const u8, *const i8, *const i8, *mut i32, usize);
+
+struct CachedAmxKernel {
+    #[allow(dead_code)]
+    memory: DualMappedMemory,
+    func: AmxTileFn,
+}
+
+unsafe impl Send for CachedAmxKernel {}
+unsafe impl Sync for CachedAmxKernel {}
+
+static MATMUL_I8_AMX: OnceLock<CachedAmxKernel> = OnceLock::new();
+
+/// `ldtilecfg` palette 1: tmm0 = C, tmm1 = A, tmm2 = B, all 16 rows × 64 bytes
+#[repr(C, align(64))]
+struct TileConfig([u8; 64]);
+
+impl TileConfig {
+    fn new() -> Self {
+        let mut cfg = [0u8; 64];
+        cfg[0] = 1; // palette
+        for tile in 0..3 {
+            cfg[16 + tile * 2..18 + tile * 2].copy_from_slice(&(TILE_K as u16).to_le_bytes());
+            cfg[48 + tile] = TILE_ROWS as u8;
+        }
+        TileConfig(cfg)
+    }
+}
+
+fn generate_matmul_i8_amx() -> Result<Vec<u8>, String> {
+    // dynasm has no AMX mnemonics; VEX encodings from the SDM
+    const LDTILECFG_RDI: [u8; 5] = [0xC4, 0xE2, 0x78, 0x49, 0x07];
+    const TILEZERO_TMM0: [u8; 5] = [0xC4, 0xE2, 0x7B, 0x49, 0xC0];
+    const TILELOADD_TMM1_RSI_RAX: [u8; 6] = [0xC4, 0xE2, 0x7B, 0x4B, 0x0C, 0x06];
+    const TILELOADD_TMM2_RDX_RAX: [u8; 6] = [0xC4, 0xE2, 0x7B, 0x4B, 0x14, 0x02];
+    const TDPBSSD_TMM0_TMM1_TMM2: [u8; 5] = [0xC4, 0xE2, 0x6B, 0x5E, 0xC1];
+    const TILESTORED_RCX_RAX_TMM0: [u8; 6] = [0xC4, 0xE2, 0x7A, 0x4B, 0x04, 0x01];
+    const TILERELEASE: [u8; 5] = [0xC4, 0xE2, 0x78, 0x49, 0xC0];
+
+    let mut ops = Assembler::new().map_err(|e| e.to_string())?;
+    dynasm!(ops
+        ; .arch x64
+        ; .bytes LDTILECFG_RDI
+        ; mov eax, TILE_K as i32
+        ; .bytes TILEZERO_TMM0
+        ; test r8, r8
+        ; jz ->store
+        ; ->k_loop:
+        ; .bytes TILELOADD_TMM1_RSI_RAX
+        ; .bytes TILELOADD_TMM2_RDX_RAX
+        ; .bytes TDPBSSD_TMM0_TMM1_TMM2
+        ; add rsi, TILE_BYTES as i32
+        ; add rdx, TILE_BYTES as i32
+        ; dec r8
+        ; jnz ->k_loop
+        ; ->store:
+        ; .bytes TILESTORED_RCX_RAX_TMM0
+        ; .bytes TILERELEASE
+        ; ret
+    );
+    let buf = ops.finalize().map_err(|e| format!("{:?}", e))?;
+    Ok(buf.to_vec())
+}
+
+/// Copy A into zero-padded tiles: for each 16-row block, its k tiles in order
+fn pack_a_tiles(a: &[i8], m: usize, k: usize) -> Vec<i8> {
+    let (m_tiles, k_tiles) = (m.div_ceil(TILE_ROWS), k.div_ceil(TILE_K));
+    let mut packed = vec![0i8; m_tiles * k_tiles * TILE_BYTES];
+    for i in 0..m {
+        for p in 0..k {
+            let tile = (i / TILE_ROWS) * k_tiles + p / TILE_K;
+            packed[tile * TILE_BYTES + (i % TILE_ROWS) * TILE_K + p % TILE_K] = a[i * k + p];
+        }
+    }
+    packed
+}
+
+/// Copy B into zero-padded VNNI tiles: for each 16-column block, its k
+/// tiles in order, each row holding 4 consecutive k for every column
+fn pack_b_tiles(b: &[i8], k: usize, n: usize) -> Vec<i8> {
+    let (n_tiles, k_tiles) = (n.div_ceil(TILE_ROWS), k.div_ceil(TILE_K));
+    let mut packed = vec![0i8; n_tiles * k_tiles * TILE_BYTES];
+    for p in 0..k {
+        for j in 0..n {
+            let tile = (j / TILE_ROWS) * k_tiles + p / TILE_K;
+            let (row, lane) = ((p % TILE_K) / 4, (j % TILE_ROWS) * 4 + p % 4);
+            packed[tile * TILE_BYTES + row * TILE_K + lane] = b[p * n + j];
+        }
+    }
+    packed
+}
+
+fn matmul_i8_amx(a: &[i8], b: &[i8], c: &mut [i32], m: usize, k: usize, n: usize) {
+    let kernel = MATMUL_I8_AMX.get_or_init(|| {
+        let code = generate_matmul_i8_amx().expect("Failed to generate AMX matmul");
+        let memory = DualMappedMemory::with_code(&code).expect("Failed to allocate JIT memory");
+        let func: AmxTileFn = unsafe { std::mem::transmute(memory.rx_ptr) };
+        CachedAmxKernel { memory, func }
+    });
+    let (a_tiles, b_tiles) = (pack_a_tiles(a, m, k), pack_b_tiles(b, k, n));
+    let k_tiles = k.div_ceil(TILE_K);
+    let config = TileConfig::new();
+    let mut tile = [0i32; TILE_ROWS * TILE_ROWS];
+    for ib in 0..m.div_ceil(TILE_ROWS) {
+        for jb in 0..n.div_ceil(TILE_ROWS) {
+            (kernel.func)(
+                config.0.as_ptr(),
+                a_tiles[ib * k_tiles * TILE_BYTES..].as_ptr(),
+                b_tiles[jb * k_tiles * TILE_BYTES..].as_ptr(),
+                tile.as_mut_ptr(),
+                k_tiles,
+            );
+            let cols = (n - jb * TILE_ROWS).min(TILE_ROWS);
+            for r in 0..(m - ib * TILE_ROWS).min(TILE_ROWS) {
+                let i = ib * TILE_ROWS + r;
+                c[i * n + jb * TILE_ROWS..][..cols].copy_from_slice(&tile[r * TILE_ROWS..][..cols]);
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -446,7 +1027,7 @@ mod tests {
         // Spot check
         assert_eq!(c[0], 0);
         assert_eq!(c[1000], 1000 + 2000);
-        assert_eq!(c[n as usize - 1], (n - 1) as i64 + (n - 1) as i64 * 2);
+        assert_eq!(c[n as usize - 1], (n - 1) + (n - 1) * 2);
     }
 
     #[test]
@@ -488,4 +1069,110 @@ mod tests {
         vec_scale_i64(&mut arr, 10);
         assert_eq!(arr, vec![10, 20, 30, 40, 50]);
     }
+
+    /// Deterministic matrix with mixed signs and large magnitudes
+    fn matrix<T>(len: usize, seed: i64, cast: impl Fn(i64) -> T) -> Vec<T> {
+        (0..len as i64)
+            .map(|i| cast((i * 2654435761 + seed).wrapping_mul(0x9E3779B97F4A7C15u64 as i64) >> 7))
+            .collect()
+    }
+
+    const SHAPES: &[(usize, usize, usize)] = &[
+        (1, 1, 1),
+        (3, 5, 4),
+        (5, 7, 21),
+        (17, 70, 33),
+        (2, 0, 3),
+        (16, 64, 16),
+    ];
+
+    #[test]
+    fn test_matmul_i64_kernels_agree() {
+        for &(m, k, n) in SHAPES {
+            let (a, b) = (matrix(m * k, 1, |x| x), matrix(k * n, 2, |x| x));
+            let mut expected = vec![7; m * n];
+            matmul_i64_with(MatmulKernel::Scalar, &a, &b, &mut expected, m, k, n).unwrap();
+            for kernel in MatmulKernel::available_i64() {
+                let mut c = vec![7; m * n];
+                matmul_i64_with(kernel, &a, &b, &mut c, m, k, n).unwrap();
+                assert_eq!(c, expected, "{} {}x{}x{}", kernel.name(), m, k, n);
+            }
+            let mut c = vec![7; m * n];
+            matmul_i64(&a, &b, &mut c, m, k, n).unwrap();
+            assert_eq!(c, expected);
+        }
+        assert_eq!(
+            matmul_i64_with(MatmulKernel::AmxTile, &[1], &[1], &mut [0], 1, 1, 1).unwrap_err(),
+            "Kernel 'amx-tile' not available for i64 on this CPU"
+        );
+    }
+
+    #[test]
+    fn test_matmul_i8_kernels_agree() {
+        for &(m, k, n) in SHAPES {
+            let (a, b) = (matrix(m * k, 3, |x| x as i8), matrix(k * n, 4, |x| x as i8));
+            let mut expected = vec![7; m * n];
+            matmul_i8_with(MatmulKernel::Scalar, &a, &b, &mut expected, m, k, n).unwrap();
+            // Spot-check the reference itself
+            if m * k * n > 0 {
+                let first: i32 = (0..k).map(|p| a[p] as i32 * b[p * n] as i32).sum();
+                assert_eq!(expected[0], first);
+            }
+            for kernel in MatmulKernel::available_i8() {
+                let mut c = vec![7; m * n];
+                matmul_i8_with(kernel, &a, &b, &mut c, m, k, n).unwrap();
+                assert_eq!(c, expected, "{} {}x{}x{}", kernel.name(), m, k, n);
+            }
+        }
+    }
+
+    #[test]
+    fn test_matmul_kernel_names() {
+        for kernel in MatmulKernel::ALL {
+            assert_eq!(MatmulKernel::from_name(kernel.name()), Some(kernel));
+        }
+        assert_eq!(MatmulKernel::from_name("gpu"), None);
+    }
+
+    #[test]
+    fn test_matmul_shape_mismatch() {
+        let err = matmul_i64(&[1, 2, 3], &[1, 2], &mut [0; 3], 3, 1, 2).unwrap_err();
+        assert!(err.contains("3x1 * 1x2"), "{}", err);
+        assert!(matmul_i8(&[1; 4], &[1; 4], &mut [0; 3], 2, 2, 2).is_err());
+    }
+
+    #[test]
+    fn test_matmul_bandit_settles_per_size() {
+        let (m, k, n) = (4, 8, 12);
+        let (a, b) = (matrix(m * k, 5, |x| x as i8), matrix(k * n, 6, |x| x as i8));
+        let mut c = vec![0; m * n];
+        for _ in 0..16 {
+            matmul_i8(&a, &b, &mut c, m, k, n).unwrap();
+        }
+        let winner = matmul_i8_winner(m, k, n).unwrap();
+        assert!(MatmulKernel::available_i8()
+            .iter()
+            .any(|kernel| kernel.name() == winner));
+    }
+
+    #[test]
+    fn test_amx_kernel_encoding() {
+        let listing = crate::disasm::disassemble(&generate_matmul_i8_amx().unwrap());
+        for expected in [
+            "ldtilecfg [rdi]",
+            "tilezero tmm0",
+            "tileloadd tmm1, [rsi+rax]",
+            "tileloadd tmm2, [rdx+rax]",
+            "tdpbssd tmm0, tmm1, tmm2",
+            "tilestored [rcx+rax], tmm0",
+            "tilerelease",
+        ] {
+            assert!(
+                listing.contains(expected),
+                "{} missing from\n{}",
+                expected,
+                listing
+            );
+        }
+    }
 }
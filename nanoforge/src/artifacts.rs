@@ -0,0 +1,177 @@
+//! Compilation Artifacts
+//!
+//! Files written next to a compilation on request (`run --emit-bin` and
+//! friends), instead of dumping into the working directory:
+//!
+//! - `bin`: the raw machine code, as loaded at offset 0
+//! - `asm`: its disassembly, with a header at each function's entry and
+//!   data (jump tables, function names) shown as bytes rather than decoded
+//! - `ir`: the optimized IR each function was compiled from, with the
+//!   source line of every instruction
+//! - `report`: the optimization report, as JSON if the path ends in
+//!   `.json` and markdown otherwise
+
+use crate::compiler::CompiledCode;
+use crate::disasm;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+/// Where to write each artifact (none by default)
+#[derive(Debug, Clone, Default)]
+pub struct Artifacts {
+    pub bin: Option<PathBuf>,
+    pub asm: Option<PathBuf>,
+    pub ir: Option<PathBuf>,
+    pub report: Option<PathBuf>,
+}
+
+impl Artifacts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn bin(mut self, path: impl Into<PathBuf>) -> Self {
+        self.bin = Some(path.into());
+        self
+    }
+
+    pub fn asm(mut self, path: impl Into<PathBuf>) -> Self {
+        self.asm = Some(path.into());
+        self
+    }
+
+    pub fn ir(mut self, path: impl Into<PathBuf>) -> Self {
+        self.ir = Some(path.into());
+        self
+    }
+
+    pub fn report(mut self, path: impl Into<PathBuf>) -> Self {
+        self.report = Some(path.into());
+        self
+    }
+
+    /// Write the requested artifacts of `code`
+    pub fn emit(&self, code: &CompiledCode) -> Result<(), String> {
+        if let Some(path) = &self.bin {
+            write(path, &code.code)?;
+        }
+        if let Some(path) = &self.asm {
+            write(path, assembly(code).as_bytes())?;
+        }
+        if let Some(path) = &self.ir {
+            write(path, ir(code).as_bytes())?;
+        }
+        if let Some(path) = &self.report {
+            let text = match path.extension() {
+                Some(ext) if ext == "json" => code.report.to_json(),
+                _ => code.report.to_markdown(),
+            };
+            write(path, text.as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+fn write(path: &Path, contents: &[u8]) -> Result<(), String> {
+    std::fs::write(path, contents).map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+/// Disassembly of `code`, one `offset: instruction` line each
+pub fn assembly(code: &CompiledCode) -> String {
+    let mut data = code.data.clone();
+    data.sort_by_key(|range| range.start);
+    let mut out = String::new();
+    let mut at = 0;
+    // Decode up to each data range, then dump it
+    for range in data.iter().chain(std::iter::once(&(code.code.len()..code.code.len()))) {
+        for (offset, _, text) in disasm::decode(&code.code[at..range.start], at) {
+            if let Some((name, _)) = code.function_offsets.iter().find(|(_, &o)| o == offset) {
+                writeln!(out, "\nfn {}:", name).ok();
+            }
+            writeln!(out, "{:04x}: {}", offset, text).ok();
+        }
+        if !range.is_empty() {
+            let bytes: Vec<String> = code.code[range.clone()].iter().map(|b| format!("{:02x}", b)).collect();
+            writeln!(out, "{:04x}: .bytes {}", range.start, bytes.join(" ")).ok();
+        }
+        at = range.end;
+    }
+    out.trim_start().to_string()
+}
+
+/// The optimized IR of every function, one instruction per line
+pub fn ir(code: &CompiledCode) -> String {
+    let mut out = String::new();
+    let mut function = None;
+    for mapped in &code.source_map {
+        if function != Some(&mapped.function) {
+            function = Some(&mapped.function);
+            writeln!(out, "\nfn {}:", mapped.function).ok();
+        }
+        let line = match mapped.line {
+            0 => String::new(),
+            line => format!("  ; line {}", line),
+        };
+        writeln!(out, "{:4}: {}{}", mapped.index, mapped.instruction, line).ok();
+    }
+    out.trim_start().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::{CompileOptions, Compiler};
+    use crate::parser::Parser;
+
+    const SRC: &str = "fn main(x) {
+    r = 0
+    switch x {
+        0 => r = 5
+        1 => r = 6
+        2 => r = 7
+        3 => r = 8
+        _ => r = 9
+    }
+    return r
+}";
+
+    #[test]
+    fn test_assembly_skips_data_and_marks_functions() {
+        let prog = Parser::new().parse(SRC).unwrap();
+        let code = Compiler::compile_with_options(&prog, &CompileOptions::new(1)).unwrap();
+        assert!(!code.data.is_empty());
+        let text = assembly(&code);
+        assert!(text.starts_with("fn main:\n0000: push rbp\n"), "{}", text);
+        // The jump table is data: dumped, and decoding picks up after it
+        let table = code.data[0].clone();
+        let dumped = format!("{:04x}: .bytes ", table.start);
+        assert!(text.contains(&dumped), "{}", text);
+        if table.end < code.code.len() {
+            assert!(text.contains(&format!("\n{:04x}: ", table.end)), "{}", text);
+        }
+    }
+
+    #[test]
+    fn test_emits_requested_files_only() {
+        let prog = Parser::new().parse(SRC).unwrap();
+        let code = Compiler::compile_with_options(&prog, &CompileOptions::new(1)).unwrap();
+        let dir = std::env::temp_dir().join(format!("nf_artifacts_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let artifacts = Artifacts::new()
+            .bin(dir.join("main.bin"))
+            .ir(dir.join("main.ir"))
+            .report(dir.join("report.json"));
+        artifacts.emit(&code).unwrap();
+
+        assert_eq!(std::fs::read(dir.join("main.bin")).unwrap(), code.code);
+        let ir = std::fs::read_to_string(dir.join("main.ir")).unwrap();
+        assert!(ir.starts_with("fn main:\n   0: LoadArg(0) Reg(10)  ; line 1\n"), "{}", ir);
+        let report = std::fs::read_to_string(dir.join("report.json")).unwrap();
+        assert!(report.trim_start().starts_with('{'), "{}", report);
+        assert!(!dir.join("main.asm").exists());
+
+        let missing = Artifacts::new().asm(dir.join("no/such/dir/main.asm"));
+        assert!(missing.emit(&code).unwrap_err().starts_with("Failed to write "));
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
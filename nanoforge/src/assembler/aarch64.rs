@@ -1,7 +1,7 @@
+use crate::ir::Cond;
 use crate::jit_memory::DualMappedMemory;
 use dynasmrt::{aarch64::Assembler, dynasm, DynamicLabel, DynasmApi, DynasmLabelApi};
 use std::collections::HashMap;
-use std::ptr;
 
 pub struct CodeGenerator;
 
@@ -173,11 +173,9 @@ impl CodeGenerator {
 
     /// Writes the generated code into the DualMappedMemory at the specified offset.
     pub fn emit_to_memory(memory: &DualMappedMemory, code: &[u8], offset: usize) {
-        unsafe {
-            let dest = memory.rw_ptr.add(offset);
-            ptr::copy_nonoverlapping(code.as_ptr(), dest, code.len());
-        }
-        memory.flush_icache();
+        memory
+            .write(offset, code)
+            .expect("generated code fits its JIT memory");
     }
 }
 
@@ -281,6 +279,48 @@ impl JitBuilder {
         dynasm!(ops ; .arch aarch64 ; b.ge =>label);
     }
 
+    /// CSEL dest, src, dest - Branch-free select on the flags of the last CMP
+    pub fn cmov_reg_reg(&mut self, cond: Cond, dest_reg: u8, src_reg: u8) {
+        let mut ops = &mut self.ops;
+        let (d, s) = (dest_reg as u32, src_reg as u32);
+        match cond {
+            Cond::Eq => dynasm!(ops ; .arch aarch64 ; csel X(d), X(s), X(d), eq),
+            Cond::Ne => dynasm!(ops ; .arch aarch64 ; csel X(d), X(s), X(d), ne),
+            Cond::Lt => dynasm!(ops ; .arch aarch64 ; csel X(d), X(s), X(d), lt),
+            Cond::Le => dynasm!(ops ; .arch aarch64 ; csel X(d), X(s), X(d), le),
+            Cond::Gt => dynasm!(ops ; .arch aarch64 ; csel X(d), X(s), X(d), gt),
+            Cond::Ge => dynasm!(ops ; .arch aarch64 ; csel X(d), X(s), X(d), ge),
+        }
+    }
+
+    /// CLZ dest, src (64 for a zero source)
+    pub fn clz_reg_reg(&mut self, dest_reg: u8, src_reg: u8) {
+        let mut ops = &mut self.ops;
+        let (d, s) = (dest_reg as u32, src_reg as u32);
+        dynasm!(ops ; .arch aarch64 ; clz X(d), X(s));
+    }
+
+    /// RBIT + CLZ: count trailing zeros (64 for a zero source)
+    pub fn ctz_reg_reg(&mut self, dest_reg: u8, src_reg: u8) {
+        let mut ops = &mut self.ops;
+        let (d, s) = (dest_reg as u32, src_reg as u32);
+        dynasm!(ops ; .arch aarch64 ; rbit X(d), X(s) ; clz X(d), X(d));
+    }
+
+    /// Population count through NEON (no scalar popcount before FEAT_CSSC):
+    /// count bits per byte in v31, then sum the bytes
+    pub fn popcnt_reg_reg(&mut self, dest_reg: u8, src_reg: u8) {
+        let mut ops = &mut self.ops;
+        let (d, s) = (dest_reg as u32, src_reg as u32);
+        dynasm!(ops
+            ; .arch aarch64
+            ; fmov d31, X(s)
+            ; cnt v31.b8, v31.b8
+            ; addv b31, v31.b8
+            ; fmov W(d), s31
+        );
+    }
+
     pub fn call(&mut self, name: &str) {
         let label = self.get_label(name);
         let mut ops = &mut self.ops;
@@ -34,6 +34,8 @@ pub struct EvexPrefix {
     index: Option<u8>,
     /// Scale (1, 2, 4, 8)
     scale: u8,
+    /// Vector length L'L (0 = 128, 1 = 256, 2 = 512 bits)
+    length: u8,
 }
 
 impl EvexPrefix {
@@ -49,6 +51,7 @@ impl EvexPrefix {
             disp: 0,
             index: None,
             scale: 1,
+            length: 0,
         }
     }
 
@@ -90,6 +93,11 @@ impl EvexPrefix {
         self
     }
 
+    pub fn with_length(mut self, length: u8) -> Self {
+        self.length = length & 0x03;
+        self
+    }
+
     /// Encode the EVEX prefix (4 bytes)
     fn encode_prefix(&self) -> [u8; 4] {
         // Byte 0: EVEX identifier
@@ -112,50 +120,50 @@ impl EvexPrefix {
         let byte2 = w_bit | vvvv_inv | 0x04 | self.pp; // bit 2 is always 1
 
         // Byte 3: zaaa0bVV'
-        // z=0 (no zeroing), aaa=000 (no mask), b=0 (no broadcast), VV'=11 (vvvv[4]=0)
-        let byte3 = 0x00 | 0x08; // VV' bits set for zmm0-15
+        // z=0 (no zeroing), aaa=000 (no mask), b=0 (no broadcast), V'=1 (vvvv[4]=0)
+        let byte3 = (self.length << 5) | 0x08; // V' bit set for zmm0-15
 
         [byte0, byte1, byte2, byte3]
     }
 
-    /// Encode ModR/M byte
+    /// Encode the ModR/M byte of a register-register form
     fn encode_modrm(&self) -> u8 {
-        let reg_field = (self.reg & 0x07) << 3;
-        let rm_field = self.rm & 0x07;
-
-        if self.is_mem {
-            if self.index.is_some() {
-                // SIB follows
-                0x04 | reg_field // mod=00, rm=100 (SIB)
-            } else if self.disp == 0 {
-                0x00 | reg_field | rm_field // mod=00
-            } else if self.disp >= -128 && self.disp <= 127 {
-                0x40 | reg_field | rm_field // mod=01 (disp8)
-            } else {
-                0x80 | reg_field | rm_field // mod=10 (disp32)
-            }
-        } else {
-            0xC0 | reg_field | rm_field // mod=11 (register)
-        }
+        0xC0 | ((self.reg & 0x07) << 3) | (self.rm & 0x07)
     }
 
-    /// Encode SIB byte if needed
-    fn encode_sib(&self) -> Option<u8> {
-        if !self.is_mem || self.index.is_none() {
-            return None;
-        }
-
-        let index = self.index.unwrap() & 0x07;
+    /// Encode ModR/M, SIB and displacement of a memory operand. A disp8 is
+    /// compressed: it counts units of `n` bytes, the size of the access
+    fn encode_mem(&self, n: i32) -> Vec<u8> {
+        let reg_field = (self.reg & 0x07) << 3;
         let base = self.rm & 0x07;
-        let scale_bits = match self.scale {
-            1 => 0x00,
-            2 => 0x40,
-            4 => 0x80,
-            8 => 0xC0,
-            _ => 0x00,
+        let disp8 = Some(self.disp / n).filter(|d| self.disp % n == 0 && (-128..=127).contains(d));
+        // rbp and r13 have no displacement-free form
+        let (mode, disp) = if self.disp == 0 && base != 5 {
+            (0x00, vec![])
+        } else if let Some(d) = disp8 {
+            (0x40, vec![d as u8])
+        } else {
+            (0x80, self.disp.to_le_bytes().to_vec())
         };
 
-        Some(scale_bits | (index << 3) | base)
+        let mut bytes = Vec::with_capacity(6);
+        match self.index {
+            // rsp and r12 as a base need a SIB byte (with no index)
+            None if base != 4 => bytes.push(mode | reg_field | base),
+            index => {
+                let scale_bits = match self.scale {
+                    2 => 0x40,
+                    4 => 0x80,
+                    8 => 0xC0,
+                    _ => 0x00,
+                };
+                let index = index.map_or(0x04, |i| i & 0x07);
+                bytes.push(mode | reg_field | 0x04);
+                bytes.push(scale_bits | (index << 3) | base);
+            }
+        }
+        bytes.extend(disp);
+        bytes
     }
 }
 
@@ -178,63 +186,73 @@ impl Avx512Encoder {
     /// VMOVDQU64 zmm, [base + index*8 + disp] - Load 512 bits
     /// Opcode: EVEX.512.F3.0F.W1 6F /r
     pub fn vmovdqu64_load(&mut self, dest_zmm: u8, base: u8, index: u8, disp: i32) {
-        let prefix = EvexPrefix::new()
-            .with_dest(dest_zmm)
-            .with_mem_base(base)
-            .with_index(index, 8)
-            .with_disp(disp)
-            .with_map(0x01); // 0F map
+        self.vmovdqu64(0x6F, dest_zmm, base, Some(index), disp);
+    }
 
-        // EVEX prefix with pp=10 (F3)
-        let mut evex = prefix.encode_prefix();
-        evex[2] = (evex[2] & 0xFC) | 0x02; // pp=10 for F3
+    /// VMOVDQU64 [base + index*8 + disp], zmm - Store 512 bits
+    /// Opcode: EVEX.512.F3.0F.W1 7F /r
+    pub fn vmovdqu64_store(&mut self, base: u8, index: u8, src_zmm: u8, disp: i32) {
+        self.vmovdqu64(0x7F, src_zmm, base, Some(index), disp);
+    }
 
-        self.buffer.extend_from_slice(&evex);
-        self.buffer.push(0x6F); // opcode
-        self.buffer.push(prefix.encode_modrm());
+    /// VMOVDQA64 zmm, [base + index*8 + disp] - Load 512 bits from a 64-byte aligned address
+    /// Opcode: EVEX.512.66.0F.W1 6F /r
+    pub fn vmovdqa64_load(&mut self, dest_zmm: u8, base: u8, index: u8, disp: i32) {
+        self.vmov_zmm(0x6F, 0x01, true, dest_zmm, base, Some(index), disp);
+    }
 
-        if let Some(sib) = prefix.encode_sib() {
-            self.buffer.push(sib);
-        }
+    /// VMOVDQA64 [base + index*8 + disp], zmm - Store 512 bits to a 64-byte aligned address
+    /// Opcode: EVEX.512.66.0F.W1 7F /r
+    pub fn vmovdqa64_store(&mut self, base: u8, index: u8, src_zmm: u8, disp: i32) {
+        self.vmov_zmm(0x7F, 0x01, true, src_zmm, base, Some(index), disp);
+    }
 
-        // Displacement (64-byte granularity for disp8)
-        if disp != 0 {
-            if disp >= -128 * 64 && disp <= 127 * 64 && disp % 64 == 0 {
-                self.buffer.push((disp / 64) as u8);
-            } else {
-                self.buffer.extend_from_slice(&(disp as i32).to_le_bytes());
-            }
-        }
+    /// VMOVNTDQ [base + index*8 + disp], zmm - Non-temporal store to a 64-byte aligned address
+    /// Opcode: EVEX.512.66.0F.W0 E7 /r
+    pub fn vmovntdq_zmm(&mut self, base: u8, index: u8, src_zmm: u8, disp: i32) {
+        self.vmov_zmm(0xE7, 0x01, false, src_zmm, base, Some(index), disp);
     }
 
-    /// VMOVDQU64 [base + index*8 + disp], zmm - Store 512 bits
-    /// Opcode: EVEX.512.F3.0F.W1 7F /r
-    pub fn vmovdqu64_store(&mut self, base: u8, index: u8, src_zmm: u8, disp: i32) {
-        let prefix = EvexPrefix::new()
-            .with_dest(src_zmm)
-            .with_mem_base(base)
-            .with_index(index, 8)
-            .with_disp(disp)
-            .with_map(0x01);
+    /// VMOVDQU64 zmm, [rsp + disp] - Reload a spilled vector
+    pub fn vmovdqu64_load_stack(&mut self, dest_zmm: u8, disp: i32) {
+        self.vmovdqu64(0x6F, dest_zmm, 4, None, disp);
+    }
 
-        let mut evex = prefix.encode_prefix();
-        evex[2] = (evex[2] & 0xFC) | 0x02; // pp=10 for F3
+    /// VMOVDQU64 [rsp + disp], zmm - Spill a vector
+    pub fn vmovdqu64_store_stack(&mut self, disp: i32, src_zmm: u8) {
+        self.vmovdqu64(0x7F, src_zmm, 4, None, disp);
+    }
 
-        self.buffer.extend_from_slice(&evex);
-        self.buffer.push(0x7F); // opcode (store)
-        self.buffer.push(prefix.encode_modrm());
+    fn vmovdqu64(&mut self, opcode: u8, zmm: u8, base: u8, index: Option<u8>, disp: i32) {
+        self.vmov_zmm(opcode, 0x02, true, zmm, base, index, disp); // F3
+    }
 
-        if let Some(sib) = prefix.encode_sib() {
-            self.buffer.push(sib);
+    #[allow(clippy::too_many_arguments)]
+    fn vmov_zmm(
+        &mut self,
+        opcode: u8,
+        pp: u8,
+        w: bool,
+        zmm: u8,
+        base: u8,
+        index: Option<u8>,
+        disp: i32,
+    ) {
+        let mut prefix = EvexPrefix::new()
+            .with_dest(zmm)
+            .with_mem_base(base)
+            .with_disp(disp)
+            .with_map(0x01) // 0F map
+            .with_length(2);
+        if let Some(index) = index {
+            prefix = prefix.with_index(index, 8);
         }
+        prefix.pp = pp;
+        prefix.w = w;
 
-        if disp != 0 {
-            if disp >= -128 * 64 && disp <= 127 * 64 && disp % 64 == 0 {
-                self.buffer.push((disp / 64) as u8);
-            } else {
-                self.buffer.extend_from_slice(&(disp as i32).to_le_bytes());
-            }
-        }
+        self.buffer.extend_from_slice(&prefix.encode_prefix());
+        self.buffer.push(opcode);
+        self.buffer.extend(prefix.encode_mem(64));
     }
 
     /// VPADDQ zmm, zmm, zmm - Add packed 64-bit integers
@@ -244,7 +262,8 @@ impl Avx512Encoder {
             .with_dest(dest)
             .with_src1(src1)
             .with_src2_reg(src2)
-            .with_map(0x01);
+            .with_map(0x01)
+            .with_length(2);
 
         self.buffer.extend_from_slice(&prefix.encode_prefix());
         self.buffer.push(0xD4); // opcode
@@ -258,13 +277,81 @@ impl Avx512Encoder {
             .with_dest(dest)
             .with_src1(src1)
             .with_src2_reg(src2)
-            .with_map(0x01);
+            .with_map(0x01)
+            .with_length(2);
 
         self.buffer.extend_from_slice(&prefix.encode_prefix());
         self.buffer.push(0xEF); // opcode
         self.buffer.push(prefix.encode_modrm());
     }
 
+    /// VPMINSQ xmm, xmm, xmm - Signed 64-bit minimum (AVX-512VL)
+    /// Opcode: EVEX.128.66.0F38.W1 39 /r
+    pub fn vpminsq_xmm(&mut self, dest: u8, src1: u8, src2: u8) {
+        self.op_0f38(0x39, 0, dest, src1, src2);
+    }
+
+    /// VPMINSQ ymm, ymm, ymm - Signed 64-bit minimum (AVX-512VL)
+    /// Opcode: EVEX.256.66.0F38.W1 39 /r
+    pub fn vpminsq_ymm(&mut self, dest: u8, src1: u8, src2: u8) {
+        self.op_0f38(0x39, 1, dest, src1, src2);
+    }
+
+    /// VPMINSQ zmm, zmm, zmm - Signed 64-bit minimum
+    /// Opcode: EVEX.512.66.0F38.W1 39 /r
+    pub fn vpminsq_zmm(&mut self, dest: u8, src1: u8, src2: u8) {
+        self.op_0f38(0x39, 2, dest, src1, src2);
+    }
+
+    /// VPMAXSQ xmm, xmm, xmm - Signed 64-bit maximum (AVX-512VL)
+    /// Opcode: EVEX.128.66.0F38.W1 3D /r
+    pub fn vpmaxsq_xmm(&mut self, dest: u8, src1: u8, src2: u8) {
+        self.op_0f38(0x3D, 0, dest, src1, src2);
+    }
+
+    /// VPMAXSQ ymm, ymm, ymm - Signed 64-bit maximum (AVX-512VL)
+    /// Opcode: EVEX.256.66.0F38.W1 3D /r
+    pub fn vpmaxsq_ymm(&mut self, dest: u8, src1: u8, src2: u8) {
+        self.op_0f38(0x3D, 1, dest, src1, src2);
+    }
+
+    /// VPMAXSQ zmm, zmm, zmm - Signed 64-bit maximum
+    /// Opcode: EVEX.512.66.0F38.W1 3D /r
+    pub fn vpmaxsq_zmm(&mut self, dest: u8, src1: u8, src2: u8) {
+        self.op_0f38(0x3D, 2, dest, src1, src2);
+    }
+
+    /// VPABSQ xmm, xmm - 64-bit absolute value (AVX-512VL)
+    /// Opcode: EVEX.128.66.0F38.W1 1F /r (vvvv unused)
+    pub fn vpabsq_xmm(&mut self, dest: u8, src: u8) {
+        self.op_0f38(0x1F, 0, dest, 0, src);
+    }
+
+    /// VPABSQ ymm, ymm - 64-bit absolute value (AVX-512VL)
+    /// Opcode: EVEX.256.66.0F38.W1 1F /r (vvvv unused)
+    pub fn vpabsq_ymm(&mut self, dest: u8, src: u8) {
+        self.op_0f38(0x1F, 1, dest, 0, src);
+    }
+
+    /// VPABSQ zmm, zmm - 64-bit absolute value
+    /// Opcode: EVEX.512.66.0F38.W1 1F /r (vvvv unused)
+    pub fn vpabsq_zmm(&mut self, dest: u8, src: u8) {
+        self.op_0f38(0x1F, 2, dest, 0, src);
+    }
+
+    fn op_0f38(&mut self, opcode: u8, length: u8, dest: u8, src1: u8, src2: u8) {
+        let prefix = EvexPrefix::new()
+            .with_dest(dest)
+            .with_src1(src1)
+            .with_src2_reg(src2)
+            .with_map(0x02)
+            .with_length(length);
+
+        self.buffer.extend_from_slice(&prefix.encode_prefix());
+        self.buffer.push(opcode);
+        self.buffer.push(prefix.encode_modrm());
+    }
+
     /// Get the encoded bytes
     pub fn finalize(self) -> Vec<u8> {
         self.buffer
@@ -303,6 +390,19 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_ymm_min_max_abs_encoding() {
+        let mut enc = Avx512Encoder::new();
+        enc.vpminsq_ymm(1, 2, 3);
+        enc.vpmaxsq_ymm(9, 10, 11);
+        enc.vpabsq_ymm(0, 15);
+
+        let listing = crate::disasm::disassemble(&enc.finalize());
+        for insn in ["vpminsq ymm1, ymm2, ymm3", "vpmaxsq ymm9, ymm10, ymm11", "vpabsq ymm0, ymm15"] {
+            assert!(listing.contains(insn), "{}", listing);
+        }
+    }
+
     #[test]
     fn test_vpaddq_zmm() {
         let mut enc = Avx512Encoder::new();
@@ -310,6 +410,35 @@ mod tests {
 
         let bytes = enc.finalize();
         println!("VPADDQ zmm0, zmm1, zmm2: {:02X?}", bytes);
-        assert!(!bytes.is_empty());
+        assert!(crate::disasm::disassemble(&bytes).contains("vpaddq zmm0, zmm1, zmm2"));
+    }
+
+    #[test]
+    fn test_zmm_memory_operands() {
+        let mut enc = Avx512Encoder::new();
+        enc.vmovdqu64_load(1, 3, 9, 0);
+        enc.vmovdqu64_load(2, 5, 1, 64); // rbp base, disp8 counts vectors
+        enc.vmovdqu64_store(13, 0, 12, 8); // not a multiple of 64: disp32
+        enc.vmovdqu64_load_stack(9, -128);
+        enc.vmovdqu64_store_stack(0, 0);
+        enc.vmovdqa64_load(3, 6, 2, -64);
+        enc.vmovntdq_zmm(7, 8, 14, 0);
+        enc.vpminsq_zmm(1, 2, 3);
+        enc.vpabsq_xmm(4, 5);
+
+        let listing = crate::disasm::disassemble(&enc.finalize());
+        for insn in [
+            "vmovdqu64 zmm1, [rbx+r9*8]",
+            "vmovdqu64 zmm2, [rbp+rcx*8+0x40]",
+            "vmovdqu64 [r13+rax*8+8], zmm12",
+            "vmovdqu64 zmm9, [rsp-0x80]",
+            "vmovdqu64 [rsp], zmm0",
+            "vmovdqa64 zmm3, [rsi+rdx*8-0x40]",
+            "vmovntdq [rdi+r8*8], zmm14",
+            "vpminsq zmm1, zmm2, zmm3",
+            "vpabsq xmm4, xmm5",
+        ] {
+            assert!(listing.contains(insn), "{}\n{}", insn, listing);
+        }
     }
 }
@@ -0,0 +1,340 @@
+//! Static Stack-Balance Checker
+//!
+//! `JitBuilder` logs every instruction that moves RSP (pushes, pops, RSP
+//! adjustments) along with labels, jumps, calls and epilogues. `check` replays
+//! that log and reports:
+//!
+//! - a pop whose register doesn't match the push it undoes
+//! - an RSP adjustment that doesn't undo the most recent one
+//! - a label reached with different stack layouts (e.g. fall-through vs jump)
+//! - an epilogue with pushes still outstanding
+//! - a call made with the stack not 16-byte aligned
+//!
+//! Depths are in bytes below RBP; the standard prologue leaves 48.
+
+use std::collections::HashMap;
+
+/// Stack depth right after `JitBuilder::prologue`
+pub const PROLOGUE_DEPTH: i32 = 48;
+
+/// Stack-relevant event in the builder's op stream
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameOp {
+    Prologue {
+        reserved: i32,
+    },
+    Push(u8),
+    Pop(u8),
+    /// `add rsp, n` (negative allocates)
+    AdjustRsp(i32),
+    /// `and rsp, -n`: RSP drops by an unknown amount to an n-byte boundary
+    AlignRsp(i32),
+    /// `lea rsp, [rbp + offset]`
+    ResetRsp(i32),
+    Label(String),
+    Jump {
+        target: String,
+        conditional: bool,
+    },
+    Call,
+    Epilogue,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Slot {
+    Reg(u8),
+    Space(i32),
+    Align(i32),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Frame {
+    slots: Vec<Slot>,
+}
+
+impl Frame {
+    /// Depth in bytes, or None once an alignment made it unknown
+    fn depth(&self) -> Option<i32> {
+        let mut depth = PROLOGUE_DEPTH;
+        for slot in &self.slots {
+            match slot {
+                Slot::Reg(_) => depth += 8,
+                Slot::Space(n) => depth += n,
+                Slot::Align(_) => return None,
+            }
+        }
+        Some(depth)
+    }
+
+    /// Bytes pushed since the last alignment (or the frame base), and that alignment
+    fn since_align(&self) -> (i32, i32) {
+        let mut bytes = PROLOGUE_DEPTH;
+        let mut align = 16;
+        for slot in &self.slots {
+            match slot {
+                Slot::Reg(_) => bytes += 8,
+                Slot::Space(n) => bytes += n,
+                Slot::Align(a) => {
+                    bytes = 0;
+                    align = *a;
+                }
+            }
+        }
+        (bytes, align)
+    }
+
+    /// Drop slots so the depth is exactly `target`
+    fn reset_to(&mut self, target: i32) -> Result<(), String> {
+        let mut depth = PROLOGUE_DEPTH;
+        if depth == target {
+            self.slots.clear();
+            return Ok(());
+        }
+        for i in 0..self.slots.len() {
+            depth += match self.slots[i] {
+                Slot::Reg(_) => 8,
+                Slot::Space(n) => n,
+                Slot::Align(_) => break,
+            };
+            if depth == target {
+                self.slots.truncate(i + 1);
+                return Ok(());
+            }
+        }
+        Err(format!(
+            "rsp reset to depth {} which is not a slot boundary",
+            target
+        ))
+    }
+}
+
+/// Check the op stream; all problems are reported, one per line
+pub fn check(ops: &[FrameOp]) -> Result<(), String> {
+    let mut errors = Vec::new();
+    // None: unreachable (after an epilogue or unconditional jump)
+    let mut state: Option<Frame> = None;
+    let mut at_label: HashMap<&str, Frame> = HashMap::new();
+    let mut bound: HashMap<&str, Frame> = HashMap::new();
+
+    for (idx, op) in ops.iter().enumerate() {
+        match op {
+            FrameOp::Prologue { reserved } => {
+                let mut frame = Frame { slots: Vec::new() };
+                if *reserved > 0 {
+                    frame.slots.push(Slot::Space(*reserved));
+                }
+                state = Some(frame);
+                continue;
+            }
+            FrameOp::Label(name) => {
+                let incoming = at_label.get(name.as_str()).cloned();
+                match (&state, incoming) {
+                    (Some(frame), Some(jumped)) if *frame != jumped => errors.push(format!(
+                        "op {}: '{}' falls through with stack {:?} but is jumped to with {:?}",
+                        idx, name, frame.slots, jumped.slots
+                    )),
+                    (None, Some(jumped)) => state = Some(jumped),
+                    _ => {}
+                }
+                if let Some(frame) = &state {
+                    bound.insert(name.as_str(), frame.clone());
+                }
+                continue;
+            }
+            _ => {}
+        }
+
+        let Some(frame) = state.as_mut() else {
+            continue;
+        };
+
+        match op {
+            FrameOp::Push(r) => frame.slots.push(Slot::Reg(*r)),
+            FrameOp::Pop(r) => match frame.slots.pop() {
+                Some(Slot::Reg(top)) if top == *r => {}
+                Some(Slot::Reg(top)) => errors.push(format!(
+                    "op {}: pop {} but the top of stack was pushed from {}",
+                    idx, r, top
+                )),
+                other => errors.push(format!(
+                    "op {}: pop {} with {:?} on top of stack",
+                    idx, r, other
+                )),
+            },
+            FrameOp::AdjustRsp(n) if *n < 0 => frame.slots.push(Slot::Space(-n)),
+            FrameOp::AdjustRsp(n) => match frame.slots.pop() {
+                Some(Slot::Space(size)) if size == *n => {}
+                other => errors.push(format!(
+                    "op {}: add rsp, {} with {:?} on top of stack",
+                    idx, n, other
+                )),
+            },
+            FrameOp::AlignRsp(a) => frame.slots.push(Slot::Align(*a)),
+            FrameOp::ResetRsp(offset) => {
+                if let Err(e) = frame.reset_to(-offset) {
+                    errors.push(format!("op {}: {}", idx, e));
+                }
+            }
+            FrameOp::Jump {
+                target,
+                conditional,
+            } => {
+                let frame = frame.clone();
+                for known in [&bound, &at_label] {
+                    match known.get(target.as_str()) {
+                        Some(existing) if *existing != frame => errors.push(format!(
+                            "op {}: '{}' reached with stack {:?}, elsewhere {:?}",
+                            idx, target, frame.slots, existing.slots
+                        )),
+                        _ => {}
+                    }
+                }
+                at_label.entry(target.as_str()).or_insert(frame);
+                if !conditional {
+                    state = None;
+                }
+            }
+            FrameOp::Call => {
+                let (bytes, align) = frame.since_align();
+                if align % 16 != 0 || bytes % 16 != 0 {
+                    errors.push(format!(
+                        "op {}: call with stack misaligned by {} bytes",
+                        idx,
+                        bytes.rem_euclid(16)
+                    ));
+                }
+            }
+            FrameOp::Epilogue => {
+                if !frame.slots.is_empty() {
+                    errors.push(format!(
+                        "op {}: epilogue with {:?} still on the stack (depth {:?})",
+                        idx,
+                        frame.slots,
+                        frame.depth()
+                    ));
+                }
+                state = None;
+            }
+            FrameOp::Prologue { .. } | FrameOp::Label(_) => unreachable!(),
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors.join("\n"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use FrameOp::*;
+
+    fn jump(target: &str, conditional: bool) -> FrameOp {
+        Jump {
+            target: target.to_string(),
+            conditional,
+        }
+    }
+
+    #[test]
+    fn test_balanced_frame_passes() {
+        let ops = vec![
+            Label("fn_main".to_string()),
+            Prologue { reserved: 0 },
+            Push(7),
+            Push(8),
+            AdjustRsp(-16),
+            Label("loop".to_string()),
+            jump("fail", true),
+            Push(1),
+            Push(2),
+            Call,
+            Pop(2),
+            Pop(1),
+            jump("loop", false),
+            Label("fail".to_string()),
+            AdjustRsp(16),
+            Pop(8),
+            Pop(7),
+            Epilogue,
+        ];
+        check(&ops).unwrap();
+    }
+
+    #[test]
+    fn test_detects_imbalance() {
+        // Wrong pop order
+        let err = check(&[
+            Prologue { reserved: 0 },
+            Push(7),
+            Push(8),
+            Pop(7),
+            Pop(8),
+            Epilogue,
+        ])
+        .unwrap_err();
+        assert!(
+            err.contains("pop 7 but the top of stack was pushed from 8"),
+            "{}",
+            err
+        );
+
+        // Exit path forgets to release the spill area
+        let err = check(&[
+            Prologue { reserved: 0 },
+            Push(7),
+            AdjustRsp(-8),
+            jump("fail", true),
+            AdjustRsp(8),
+            Pop(7),
+            Epilogue,
+            Label("fail".to_string()),
+            Pop(7),
+            Epilogue,
+        ])
+        .unwrap_err();
+        assert!(err.contains("pop 7 with Some(Space(8))"), "{}", err);
+
+        // Label reached with two different layouts
+        let err = check(&[
+            Prologue { reserved: 0 },
+            Push(1),
+            jump("join", true),
+            Pop(1),
+            Label("join".to_string()),
+        ])
+        .unwrap_err();
+        assert!(err.contains("'join' falls through"), "{}", err);
+
+        // Misaligned call and leftover push
+        let err = check(&[Prologue { reserved: 0 }, Push(1), Call, Epilogue]).unwrap_err();
+        assert!(err.contains("misaligned by 8"), "{}", err);
+        assert!(err.contains("epilogue with [Reg(1)]"), "{}", err);
+    }
+
+    #[test]
+    fn test_reset_after_alignment() {
+        check(&[
+            Prologue { reserved: 0 },
+            Push(7),
+            Push(8),
+            Push(9),
+            Push(10),
+            Push(5),
+            AdjustRsp(-8),
+            AdjustRsp(-64),
+            AlignRsp(32),
+            Call,
+            ResetRsp(-88),
+            Pop(5),
+            Pop(10),
+            Pop(9),
+            Pop(8),
+            Pop(7),
+            Epilogue,
+        ])
+        .unwrap();
+    }
+}
@@ -1,6 +1,5 @@
 #[cfg(test)]
 mod tests {
-    use super::*;
     use crate::assembler::x64::JitBuilder;
     use crate::jit_memory::DualMappedMemory;
     use std::mem;
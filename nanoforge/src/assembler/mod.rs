@@ -22,4 +22,5 @@ pub use self::aarch64::JitBuilder;
 // For now, we assume one of the two.
 #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
 compile_error!("Nanoforge only supports x86_64 and aarch64");
+pub mod frame_check;
 pub mod manual_test;
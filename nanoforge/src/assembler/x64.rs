@@ -1,7 +1,12 @@
-use crate::jit_memory::DualMappedMemory;
+use super::avx512::Avx512Encoder;
+use super::frame_check::FrameOp;
+use crate::cpu_features::VectorWidth;
+use crate::ir::{Access, Cond};
+use crate::jit_memory::{DualMappedMemory, PatchPoint};
+use dynasmrt::relocations::{Relocation, RelocationSize};
+use dynasmrt::x64::X64Relocation;
 use dynasmrt::{dynasm, x64::Assembler, DynamicLabel, DynasmApi, DynasmLabelApi};
 use std::collections::HashMap;
-use std::ptr;
 
 pub struct CodeGenerator;
 
@@ -164,11 +169,9 @@ impl CodeGenerator {
 
     /// Writes the generated code into the DualMappedMemory at the specified offset.
     pub fn emit_to_memory(memory: &DualMappedMemory, code: &[u8], offset: usize) {
-        unsafe {
-            let dest = memory.rw_ptr.add(offset);
-            ptr::copy_nonoverlapping(code.as_ptr(), dest, code.len());
-        }
-        memory.flush_icache();
+        memory
+            .write(offset, code)
+            .expect("generated code fits its JIT memory");
     }
 }
 
@@ -196,6 +199,11 @@ fn get_hw_reg(r: u8) -> u8 {
 pub struct JitBuilder {
     ops: Assembler,
     labels: HashMap<String, DynamicLabel>,
+    /// Stack-relevant ops, replayed by `frame_check::check`
+    frame_ops: Vec<FrameOp>,
+    patch_points: Vec<PatchPoint>,
+    /// (IR instruction index, offset its code starts at), see `mark`
+    marks: Vec<(usize, usize)>,
 }
 
 impl JitBuilder {
@@ -203,6 +211,9 @@ impl JitBuilder {
         Self {
             ops: Assembler::new().unwrap(),
             labels: HashMap::new(),
+            frame_ops: Vec::new(),
+            patch_points: Vec::new(),
+            marks: Vec::new(),
         }
     }
 
@@ -217,6 +228,7 @@ impl JitBuilder {
     }
 
     pub fn bind_label(&mut self, name: &str) {
+        self.frame_ops.push(FrameOp::Label(name.to_string()));
         let label = self.get_label(name);
         let ops = &mut self.ops;
         dynasm!(ops ; =>label);
@@ -226,13 +238,55 @@ impl JitBuilder {
         self.ops.offset().0
     }
 
+    /// Record that the code of IR instruction `index` starts here
+    pub fn mark(&mut self, index: usize) {
+        let offset = self.current_offset();
+        self.marks.push((index, offset));
+    }
+
+    /// The marks recorded since the last call, in emission order
+    pub fn take_marks(&mut self) -> Vec<(usize, usize)> {
+        std::mem::take(&mut self.marks)
+    }
+
+    /// Pad with NOPs up to the next multiple of `alignment` (a power of two)
+    pub fn align(&mut self, alignment: usize) {
+        self.ops.align(alignment, 0x90);
+    }
+
+    /// Stack-relevant ops emitted so far (see `frame_check`)
+    pub fn frame_ops(&self) -> &[FrameOp] {
+        &self.frame_ops
+    }
+
+    fn log_jump(&mut self, name: &str, conditional: bool) {
+        self.frame_ops.push(FrameOp::Jump {
+            target: name.to_string(),
+            conditional,
+        });
+    }
+
+    /// LEA rdi, [label] - Address of a label (e.g. data emitted with `emit_bytes`)
+    pub fn lea_rdi_label(&mut self, name: &str) {
+        let label = self.get_label(name);
+        let ops = &mut self.ops;
+        dynasm!(ops ; .arch x64 ; lea rdi, [=>label]);
+    }
+
+    /// Raw bytes in the code stream (never executed: place after a terminator)
+    pub fn emit_bytes(&mut self, bytes: &[u8]) {
+        self.ops.extend(bytes.iter().copied());
+    }
+
     pub fn jmp(&mut self, name: &str) {
+        self.log_jump(name, false);
         let label = self.get_label(name);
         let ops = &mut self.ops;
         dynasm!(ops ; .arch x64 ; jmp =>label);
     }
 
     pub fn jnz(&mut self, cond_reg: u8, name: &str) {
+        self.log_jump(name, true);
         let label = self.get_label(name);
         let ops = &mut self.ops;
 
@@ -267,42 +321,49 @@ impl JitBuilder {
     }
 
     pub fn je(&mut self, name: &str) {
+        self.log_jump(name, true);
         let label = self.get_label(name);
         let ops = &mut self.ops;
         dynasm!(ops ; .arch x64 ; je =>label);
     }
 
     pub fn jne(&mut self, name: &str) {
+        self.log_jump(name, true);
         let label = self.get_label(name);
         let ops = &mut self.ops;
         dynasm!(ops ; .arch x64 ; jne =>label);
     }
 
     pub fn jl(&mut self, name: &str) {
+        self.log_jump(name, true);
         let label = self.get_label(name);
         let ops = &mut self.ops;
         dynasm!(ops ; .arch x64 ; jl =>label);
     }
 
     pub fn jle(&mut self, name: &str) {
+        self.log_jump(name, true);
         let label = self.get_label(name);
         let ops = &mut self.ops;
         dynasm!(ops ; .arch x64 ; jle =>label);
     }
 
     pub fn jg(&mut self, name: &str) {
+        self.log_jump(name, true);
         let label = self.get_label(name);
         let ops = &mut self.ops;
         dynasm!(ops ; .arch x64 ; jg =>label);
     }
 
     pub fn jge(&mut self, name: &str) {
+        self.log_jump(name, true);
         let label = self.get_label(name);
         let ops = &mut self.ops;
         dynasm!(ops ; .arch x64 ; jge =>label);
     }
 
     pub fn call(&mut self, name: &str) {
+        self.frame_ops.push(FrameOp::Call);
         let label = self.get_label(name);
         let ops = &mut self.ops;
         dynasm!(ops ; .arch x64 ; call =>label);
@@ -326,6 +387,51 @@ impl JitBuilder {
         dynasm!(ops ; .arch x64 ; mov Rd(d), imm);
     }
 
+    /// `mov_reg_imm` whose immediate is recorded as patch point `name`.
+    /// Always the `B8+r imm32` form, NOP-padded so the immediate is 4-byte aligned.
+    pub fn mov_reg_imm_patchable(&mut self, name: &str, dest_reg: u8, imm: i32) {
+        let d = get_hw_reg(dest_reg);
+        let mut bytes = Vec::with_capacity(6);
+        if d >= 8 {
+            bytes.push(0x41);
+        }
+        bytes.push(0xB8 + (d & 7));
+        let pad = (4 - (self.current_offset() + bytes.len()) % 4) % 4;
+        bytes.splice(0..0, std::iter::repeat_n(0x90, pad));
+        bytes.extend_from_slice(&imm.to_le_bytes());
+
+        let ops = &mut self.ops;
+        dynasm!(ops ; .arch x64 ; .bytes bytes.as_slice());
+        self.patch_points.push(PatchPoint {
+            name: name.to_string(),
+            offset: self.current_offset() - 4,
+            width: 4,
+        });
+    }
+
+    /// `mov_reg_imm64` whose immediate is recorded as patch point `name`,
+    /// NOP-padded so the immediate is 8-byte aligned
+    pub fn mov_reg_imm64_patchable(&mut self, name: &str, dest_reg: u8, imm: u64) {
+        let d = get_hw_reg(dest_reg);
+        let mut bytes = vec![0x48 | (d >> 3), 0xB8 + (d & 7)];
+        let pad = (8 - (self.current_offset() + bytes.len()) % 8) % 8;
+        bytes.splice(0..0, std::iter::repeat_n(0x90, pad));
+        bytes.extend_from_slice(&imm.to_le_bytes());
+
+        let ops = &mut self.ops;
+        dynasm!(ops ; .arch x64 ; .bytes bytes.as_slice());
+        self.patch_points.push(PatchPoint {
+            name: name.to_string(),
+            offset: self.current_offset() - 8,
+            width: 8,
+        });
+    }
+
+    /// Immediates recorded by `mov_reg_imm_patchable`, in emission order
+    pub fn patch_points(&self) -> &[PatchPoint] {
+        &self.patch_points
+    }
+
     pub fn mov_reg_imm64(&mut self, dest_reg: u8, imm: u64) {
         let ops = &mut self.ops;
         let imm_val = imm as i64;
@@ -352,6 +458,74 @@ impl JitBuilder {
         dynasm!(ops ; .arch x64 ; mov Rq(d), Rq(s));
     }
 
+    /// CMOVcc dest, src - Branch-free select on the flags of the last CMP
+    pub fn cmov_reg_reg(&mut self, cond: Cond, dest_reg: u8, src_reg: u8) {
+        let ops = &mut self.ops;
+        let d = get_hw_reg(dest_reg);
+        let s = get_hw_reg(src_reg);
+        match cond {
+            Cond::Eq => dynasm!(ops ; .arch x64 ; cmove Rq(d), Rq(s)),
+            Cond::Ne => dynasm!(ops ; .arch x64 ; cmovne Rq(d), Rq(s)),
+            Cond::Lt => dynasm!(ops ; .arch x64 ; cmovl Rq(d), Rq(s)),
+            Cond::Le => dynasm!(ops ; .arch x64 ; cmovle Rq(d), Rq(s)),
+            Cond::Gt => dynasm!(ops ; .arch x64 ; cmovg Rq(d), Rq(s)),
+            Cond::Ge => dynasm!(ops ; .arch x64 ; cmovge Rq(d), Rq(s)),
+        }
+    }
+
+    /// POPCNT dest, src (requires the POPCNT extension)
+    pub fn popcnt_reg_reg(&mut self, dest_reg: u8, src_reg: u8) {
+        let ops = &mut self.ops;
+        let d = get_hw_reg(dest_reg);
+        let s = get_hw_reg(src_reg);
+        dynasm!(ops ; .arch x64 ; popcnt Rq(d), Rq(s));
+    }
+
+    /// TZCNT dest, src (requires BMI1; 64 for a zero source)
+    pub fn tzcnt_reg_reg(&mut self, dest_reg: u8, src_reg: u8) {
+        let ops = &mut self.ops;
+        let d = get_hw_reg(dest_reg);
+        let s = get_hw_reg(src_reg);
+        dynasm!(ops ; .arch x64 ; tzcnt Rq(d), Rq(s));
+    }
+
+    /// LZCNT dest, src (requires LZCNT; 64 for a zero source)
+    pub fn lzcnt_reg_reg(&mut self, dest_reg: u8, src_reg: u8) {
+        let ops = &mut self.ops;
+        let d = get_hw_reg(dest_reg);
+        let s = get_hw_reg(src_reg);
+        dynasm!(ops ; .arch x64 ; lzcnt Rq(d), Rq(s));
+    }
+
+    /// BSF dest, src - index of the lowest set bit; sets ZF for a zero source
+    pub fn bsf_reg_reg(&mut self, dest_reg: u8, src_reg: u8) {
+        let ops = &mut self.ops;
+        let d = get_hw_reg(dest_reg);
+        let s = get_hw_reg(src_reg);
+        dynasm!(ops ; .arch x64 ; bsf Rq(d), Rq(s));
+    }
+
+    /// BSR dest, src - index of the highest set bit; sets ZF for a zero source
+    pub fn bsr_reg_reg(&mut self, dest_reg: u8, src_reg: u8) {
+        let ops = &mut self.ops;
+        let d = get_hw_reg(dest_reg);
+        let s = get_hw_reg(src_reg);
+        dynasm!(ops ; .arch x64 ; bsr Rq(d), Rq(s));
+    }
+
+    pub fn xor_reg_imm(&mut self, dest_reg: u8, imm: i32) {
+        let ops = &mut self.ops;
+        let d = get_hw_reg(dest_reg);
+        dynasm!(ops ; .arch x64 ; xor Rq(d), imm);
+    }
+
+    /// NEG dest - Two's complement negate; flags as for 0 - dest
+    pub fn neg_reg(&mut self, dest_reg: u8) {
+        let ops = &mut self.ops;
+        let d = get_hw_reg(dest_reg);
+        dynasm!(ops ; .arch x64 ; neg Rq(d));
+    }
+
     pub fn add_reg_reg(&mut self, dest_reg: u8, src_reg: u8) {
         let ops = &mut self.ops;
         let d = get_hw_reg(dest_reg);
@@ -433,43 +607,69 @@ impl JitBuilder {
         dynasm!(ops ; .arch x64 ; vpaddq Ry(d), Ry(s1), Ry(s2));
     }
 
-    pub fn mov_reg_index(&mut self, dest_reg: u8, base_reg: u8, index_reg: u8) {
+    pub fn mov_reg_index(&mut self, dest_reg: u8, base_reg: u8, index_reg: u8, offset_bytes: i32) {
         let ops = &mut self.ops;
         let d = get_hw_reg(dest_reg);
         let b = get_hw_reg(base_reg);
         let i = get_hw_reg(index_reg);
-        dynasm!(ops ; .arch x64 ; mov Rq(d), [Rq(b) + Rq(i) * 8]);
+        match offset_bytes {
+            0 => dynasm!(ops ; .arch x64 ; mov Rq(d), [Rq(b) + Rq(i) * 8]),
+            -128..=127 => {
+                let disp = offset_bytes as i8;
+                dynasm!(ops ; .arch x64 ; mov Rq(d), [BYTE Rq(b) + Rq(i) * 8 + disp])
+            }
+            _ => dynasm!(ops ; .arch x64 ; mov Rq(d), [Rq(b) + Rq(i) * 8 + offset_bytes]),
+        }
     }
 
-    pub fn mov_index_reg(&mut self, base_reg: u8, index_reg: u8, src_reg: u8) {
+    pub fn mov_index_reg(&mut self, base_reg: u8, index_reg: u8, src_reg: u8, offset_bytes: i32) {
         let ops = &mut self.ops;
         let b = get_hw_reg(base_reg);
         let i = get_hw_reg(index_reg);
         let s = get_hw_reg(src_reg);
-        dynasm!(ops ; .arch x64 ; mov [Rq(b) + Rq(i) * 8], Rq(s));
+        match offset_bytes {
+            0 => dynasm!(ops ; .arch x64 ; mov [Rq(b) + Rq(i) * 8], Rq(s)),
+            -128..=127 => {
+                let disp = offset_bytes as i8;
+                dynasm!(ops ; .arch x64 ; mov [BYTE Rq(b) + Rq(i) * 8 + disp], Rq(s))
+            }
+            _ => dynasm!(ops ; .arch x64 ; mov [Rq(b) + Rq(i) * 8 + offset_bytes], Rq(s)),
+        }
     }
 
     pub fn call_reg(&mut self, reg: u8) {
+        self.frame_ops.push(FrameOp::Call);
         let ops = &mut self.ops;
         let r = get_hw_reg(reg);
         dynasm!(ops ; .arch x64 ; call Rq(r));
     }
 
+    pub fn jmp_reg(&mut self, reg: u8) {
+        let ops = &mut self.ops;
+        let r = get_hw_reg(reg);
+        dynasm!(ops ; .arch x64 ; jmp Rq(r));
+    }
+
     pub fn push_reg(&mut self, reg: u8) {
+        self.frame_ops.push(FrameOp::Push(reg));
         let ops = &mut self.ops;
         let r = get_hw_reg(reg);
         dynasm!(ops ; .arch x64 ; push Rq(r));
     }
 
     pub fn pop_reg(&mut self, reg: u8) {
+        self.frame_ops.push(FrameOp::Pop(reg));
         let ops = &mut self.ops;
         let r = get_hw_reg(reg);
         dynasm!(ops ; .arch x64 ; pop Rq(r));
     }
 
     pub fn prologue(&mut self, stack_size: i32) {
-        let ops = &mut self.ops;
         let aligned_size = (stack_size + 15) & !15;
+        self.frame_ops.push(FrameOp::Prologue {
+            reserved: aligned_size,
+        });
+        let ops = &mut self.ops;
 
         dynasm!(ops
             ; .arch x64
@@ -498,11 +698,13 @@ impl JitBuilder {
     }
 
     pub fn add_rsp(&mut self, offset: i32) {
+        self.frame_ops.push(FrameOp::AdjustRsp(offset));
         let ops = &mut self.ops;
         dynasm!(ops ; .arch x64 ; add rsp, offset);
     }
 
     pub fn epilogue(&mut self) {
+        self.frame_ops.push(FrameOp::Epilogue);
         let ops = &mut self.ops;
         dynasm!(ops
             ; .arch x64
@@ -545,11 +747,78 @@ impl JitBuilder {
     }
 
     pub fn jz(&mut self, name: &str) {
+        self.log_jump(name, true);
         let label = self.get_label(name);
         let ops = &mut self.ops;
         dynasm!(ops ; .arch x64 ; jz =>label);
     }
 
+    /// Jump if the last add, sub or imul overflowed
+    pub fn jo(&mut self, name: &str) {
+        self.log_jump(name, true);
+        let label = self.get_label(name);
+        let ops = &mut self.ops;
+        dynasm!(ops ; .arch x64 ; jo =>label);
+    }
+
+    /// Jump if below (unsigned)
+    pub fn jb(&mut self, name: &str) {
+        self.log_jump(name, true);
+        let label = self.get_label(name);
+        let ops = &mut self.ops;
+        dynasm!(ops ; .arch x64 ; jb =>label);
+    }
+
+    /// Jump if above or equal (unsigned)
+    pub fn jae(&mut self, name: &str) {
+        self.log_jump(name, true);
+        let label = self.get_label(name);
+        let ops = &mut self.ops;
+        dynasm!(ops ; .arch x64 ; jae =>label);
+    }
+
+    /// Jump to entry `index` of the table at `table` (see
+    /// `jump_table_entries`); clobbers `index` and `tmp`
+    pub fn jmp_table(&mut self, index: u8, tmp: u8, table: &str, targets: &[String]) {
+        for (i, target) in targets.iter().enumerate() {
+            self.log_jump(target, i + 1 < targets.len());
+        }
+        let label = self.get_label(table);
+        let ops = &mut self.ops;
+        let i = get_hw_reg(index);
+        let t = get_hw_reg(tmp);
+        dynasm!(ops
+            ; .arch x64
+            ; lea Rq(t), [=>label]
+            ; lea Rq(t), [Rq(t) + Rq(i) * 4]
+            ; movsxd Rq(i), DWORD [Rq(t)]
+            ; add Rq(t), Rq(i)
+            ; jmp Rq(t)
+        );
+    }
+
+    /// A jump table named `name`: a 32-bit offset per target, each relative
+    /// to its own entry (never executed: place after a terminator)
+    pub fn jump_table_entries(&mut self, name: &str, targets: &[String]) {
+        self.align(4);
+        self.bind_label(name);
+        for target in targets {
+            let label = self.get_label(target);
+            // The 4 bytes just emitted, relative to their own start
+            self.ops.extend([0; 4]);
+            let dword = X64Relocation::from_size(RelocationSize::DWord);
+            self.ops.dynamic_relocation(label, 0, 4, 4, dword);
+        }
+    }
+
+    /// CMP rsp, [fs:offset] - Compare the stack pointer with a word of the
+    /// thread control block
+    pub fn cmp_rsp_fs(&mut self, offset: i32) {
+        // fs prefix, REX.W, CMP r64, r/m64 with an absolute disp32
+        self.emit_bytes(&[0x64, 0x48, 0x3b, 0x24, 0x25]);
+        self.emit_bytes(&offset.to_le_bytes());
+    }
+
     // ========================================================================
     // AVX-512 Instructions (512-bit ZMM registers)
     // ========================================================================
@@ -568,8 +837,6 @@ impl JitBuilder {
     }
 
     /// VMOVDQU ymm, [base + index*8] - Load 256 bits (4 x i64) from memory
-    /// Uses static register selection via match for dynasm compatibility
-    #[allow(dead_code)]
     pub fn vmovdqu_load_ymm(
         &mut self,
         dest_ymm: u8,
@@ -577,26 +844,22 @@ impl JitBuilder {
         index_reg: u8,
         offset_bytes: i32,
     ) {
+        assert!(dest_ymm < 16, "YMM register {} not supported", dest_ymm);
         let ops = &mut self.ops;
         let b = get_hw_reg(base_reg);
         let i = get_hw_reg(index_reg);
+        dynasm!(ops ; .arch x64 ; vmovdqu Ry(dest_ymm), [Rq(b) + Rq(i) * 8 + offset_bytes]);
+    }
 
-        // Use match for static register selection (dynasm limitation)
-        match dest_ymm {
-            0 => dynasm!(ops ; .arch x64 ; vmovdqu ymm0, [Rq(b) + Rq(i) * 8 + offset_bytes]),
-            1 => dynasm!(ops ; .arch x64 ; vmovdqu ymm1, [Rq(b) + Rq(i) * 8 + offset_bytes]),
-            2 => dynasm!(ops ; .arch x64 ; vmovdqu ymm2, [Rq(b) + Rq(i) * 8 + offset_bytes]),
-            3 => dynasm!(ops ; .arch x64 ; vmovdqu ymm3, [Rq(b) + Rq(i) * 8 + offset_bytes]),
-            4 => dynasm!(ops ; .arch x64 ; vmovdqu ymm4, [Rq(b) + Rq(i) * 8 + offset_bytes]),
-            5 => dynasm!(ops ; .arch x64 ; vmovdqu ymm5, [Rq(b) + Rq(i) * 8 + offset_bytes]),
-            6 => dynasm!(ops ; .arch x64 ; vmovdqu ymm6, [Rq(b) + Rq(i) * 8 + offset_bytes]),
-            7 => dynasm!(ops ; .arch x64 ; vmovdqu ymm7, [Rq(b) + Rq(i) * 8 + offset_bytes]),
-            _ => panic!("YMM register {} not supported", dest_ymm),
-        }
+    /// PREFETCHT0 [base + index*8 + offset] - Pull a line into all cache levels
+    pub fn prefetcht0_index(&mut self, base_reg: u8, index_reg: u8, offset_bytes: i32) {
+        let ops = &mut self.ops;
+        let b = get_hw_reg(base_reg);
+        let i = get_hw_reg(index_reg);
+        dynasm!(ops ; .arch x64 ; prefetcht0 [Rq(b) + Rq(i) * 8 + offset_bytes]);
     }
 
     /// VMOVDQU [base + index*8], ymm - Store 256 bits to memory
-    #[allow(dead_code)]
     pub fn vmovdqu_store_ymm(
         &mut self,
         base_reg: u8,
@@ -604,21 +867,49 @@ impl JitBuilder {
         src_ymm: u8,
         offset_bytes: i32,
     ) {
+        assert!(src_ymm < 16, "YMM register {} not supported", src_ymm);
         let ops = &mut self.ops;
         let b = get_hw_reg(base_reg);
         let i = get_hw_reg(index_reg);
+        dynasm!(ops ; .arch x64 ; vmovdqu [Rq(b) + Rq(i) * 8 + offset_bytes], Ry(src_ymm));
+    }
 
-        match src_ymm {
-            0 => dynasm!(ops ; .arch x64 ; vmovdqu [Rq(b) + Rq(i) * 8 + offset_bytes], ymm0),
-            1 => dynasm!(ops ; .arch x64 ; vmovdqu [Rq(b) + Rq(i) * 8 + offset_bytes], ymm1),
-            2 => dynasm!(ops ; .arch x64 ; vmovdqu [Rq(b) + Rq(i) * 8 + offset_bytes], ymm2),
-            3 => dynasm!(ops ; .arch x64 ; vmovdqu [Rq(b) + Rq(i) * 8 + offset_bytes], ymm3),
-            4 => dynasm!(ops ; .arch x64 ; vmovdqu [Rq(b) + Rq(i) * 8 + offset_bytes], ymm4),
-            5 => dynasm!(ops ; .arch x64 ; vmovdqu [Rq(b) + Rq(i) * 8 + offset_bytes], ymm5),
-            6 => dynasm!(ops ; .arch x64 ; vmovdqu [Rq(b) + Rq(i) * 8 + offset_bytes], ymm6),
-            7 => dynasm!(ops ; .arch x64 ; vmovdqu [Rq(b) + Rq(i) * 8 + offset_bytes], ymm7),
-            _ => panic!("YMM register {} not supported", src_ymm),
-        }
+    /// VMOVDQA ymm, [rsp + offset] - Reload a spilled vector (slot must be 32-byte aligned)
+    pub fn vmovdqa_ymm_stack(&mut self, dest_ymm: u8, offset: i32) {
+        let ops = &mut self.ops;
+        dynasm!(ops ; .arch x64 ; vmovdqa Ry(dest_ymm), [rsp + offset]);
+    }
+
+    /// VMOVDQA [rsp + offset], ymm - Spill a vector (slot must be 32-byte aligned)
+    pub fn vmovdqa_stack_ymm(&mut self, offset: i32, src_ymm: u8) {
+        let ops = &mut self.ops;
+        dynasm!(ops ; .arch x64 ; vmovdqa [rsp + offset], Ry(src_ymm));
+    }
+
+    /// VMOVDQA ymm, ymm - Register-to-register vector move
+    pub fn vmovdqa_ymm_ymm(&mut self, dest_ymm: u8, src_ymm: u8) {
+        let ops = &mut self.ops;
+        dynasm!(ops ; .arch x64 ; vmovdqa Ry(dest_ymm), Ry(src_ymm));
+    }
+
+    /// Clear the upper halves of all YMM registers (avoids AVX/SSE transition stalls)
+    pub fn vzeroupper(&mut self) {
+        let ops = &mut self.ops;
+        dynasm!(ops ; .arch x64 ; vzeroupper);
+    }
+
+    /// AND rsp, imm - Align the stack pointer down (imm = -32 for 32-byte alignment)
+    pub fn and_rsp(&mut self, imm: i32) {
+        self.frame_ops.push(FrameOp::AlignRsp(-imm));
+        let ops = &mut self.ops;
+        dynasm!(ops ; .arch x64 ; and rsp, imm);
+    }
+
+    /// LEA rsp, [rbp + offset] - Reset the stack pointer relative to the frame
+    pub fn lea_rsp_rbp(&mut self, offset: i32) {
+        self.frame_ops.push(FrameOp::ResetRsp(offset));
+        let ops = &mut self.ops;
+        dynasm!(ops ; .arch x64 ; lea rsp, [rbp + offset]);
     }
 
     /// VPADDQ ymm_dest, ymm_src1, ymm_src2 - Add packed 64-bit integers (256-bit)
@@ -638,6 +929,270 @@ impl JitBuilder {
         }
     }
 
+    /// VPMINSQ ymm_dest, ymm_src1, ymm_src2 - Packed signed 64-bit min (AVX-512VL)
+    pub fn vpminsq_ymm(&mut self, dest: u8, src1: u8, src2: u8) {
+        let mut enc = Avx512Encoder::new();
+        enc.vpminsq_ymm(dest, src1, src2);
+        self.emit_evex(enc);
+    }
+
+    /// VPMAXSQ ymm_dest, ymm_src1, ymm_src2 - Packed signed 64-bit max (AVX-512VL)
+    pub fn vpmaxsq_ymm(&mut self, dest: u8, src1: u8, src2: u8) {
+        let mut enc = Avx512Encoder::new();
+        enc.vpmaxsq_ymm(dest, src1, src2);
+        self.emit_evex(enc);
+    }
+
+    /// VPABSQ ymm_dest, ymm_src - Packed 64-bit absolute value (AVX-512VL)
+    pub fn vpabsq_ymm(&mut self, dest: u8, src: u8) {
+        let mut enc = Avx512Encoder::new();
+        enc.vpabsq_ymm(dest, src);
+        self.emit_evex(enc);
+    }
+
+    /// dynasm has no EVEX encodings; splice in the encoder's bytes
+    fn emit_evex(&mut self, enc: Avx512Encoder) {
+        let bytes = enc.finalize();
+        let ops = &mut self.ops;
+        dynasm!(ops ; .arch x64 ; .bytes bytes.as_slice());
+    }
+
+    /// VPCMPGTQ ymm_dest, ymm_src1, ymm_src2 - All-ones lanes where src1 > src2 (signed)
+    pub fn vpcmpgtq_ymm(&mut self, dest: u8, src1: u8, src2: u8) {
+        let ops = &mut self.ops;
+        dynasm!(ops ; .arch x64 ; vpcmpgtq Ry(dest), Ry(src1), Ry(src2));
+    }
+
+    /// VBLENDVPD ymm_dest, ymm_src1, ymm_src2, ymm_mask - src2 in lanes whose mask sign bit is set
+    pub fn vblendvpd_ymm(&mut self, dest: u8, src1: u8, src2: u8, mask: u8) {
+        let ops = &mut self.ops;
+        dynasm!(ops ; .arch x64 ; vblendvpd Ry(dest), Ry(src1), Ry(src2), Ry(mask));
+    }
+
+    /// VPXOR ymm_dest, ymm_src1, ymm_src2
+    pub fn vpxor_ymm(&mut self, dest: u8, src1: u8, src2: u8) {
+        let ops = &mut self.ops;
+        dynasm!(ops ; .arch x64 ; vpxor Ry(dest), Ry(src1), Ry(src2));
+    }
+
+    /// VPSUBQ ymm_dest, ymm_src1, ymm_src2 - Subtract packed 64-bit integers
+    pub fn vpsubq_ymm(&mut self, dest: u8, src1: u8, src2: u8) {
+        let ops = &mut self.ops;
+        dynasm!(ops ; .arch x64 ; vpsubq Ry(dest), Ry(src1), Ry(src2));
+    }
+
+    // Vector ops at a `VectorWidth`: xmm and ymm through VEX, zmm through
+    // the EVEX encoder. Register numbers are the same at every width.
+
+    /// VMOVDQU vec, [base + index*8 + offset], or VMOVDQA if the access is
+    /// aligned to the vector size
+    pub fn vec_load(
+        &mut self,
+        width: VectorWidth,
+        access: Access,
+        dest: u8,
+        base_reg: u8,
+        index_reg: u8,
+        offset_bytes: i32,
+    ) {
+        let ops = &mut self.ops;
+        let b = get_hw_reg(base_reg);
+        let i = get_hw_reg(index_reg);
+        let aligned = access != Access::Unaligned;
+        match (width, aligned) {
+            (VectorWidth::Lanes2, false) => {
+                dynasm!(ops ; .arch x64 ; vmovdqu Rx(dest), [Rq(b) + Rq(i) * 8 + offset_bytes])
+            }
+            (VectorWidth::Lanes2, true) => {
+                dynasm!(ops ; .arch x64 ; vmovdqa Rx(dest), [Rq(b) + Rq(i) * 8 + offset_bytes])
+            }
+            (VectorWidth::Lanes4, false) => {
+                self.vmovdqu_load_ymm(dest, base_reg, index_reg, offset_bytes)
+            }
+            (VectorWidth::Lanes4, true) => {
+                dynasm!(ops ; .arch x64 ; vmovdqa Ry(dest), [Rq(b) + Rq(i) * 8 + offset_bytes])
+            }
+            (VectorWidth::Lanes8, _) => {
+                let mut enc = Avx512Encoder::new();
+                if aligned {
+                    enc.vmovdqa64_load(dest, b, i, offset_bytes);
+                } else {
+                    enc.vmovdqu64_load(dest, b, i, offset_bytes);
+                }
+                self.emit_evex(enc);
+            }
+        }
+    }
+
+    /// VMOVDQU [base + index*8 + offset], vec; VMOVDQA or VMOVNTDQ as the
+    /// access allows
+    pub fn vec_store(
+        &mut self,
+        width: VectorWidth,
+        access: Access,
+        base_reg: u8,
+        index_reg: u8,
+        src: u8,
+        offset_bytes: i32,
+    ) {
+        let ops = &mut self.ops;
+        let b = get_hw_reg(base_reg);
+        let i = get_hw_reg(index_reg);
+        match (width, access) {
+            (VectorWidth::Lanes2, Access::Unaligned) => {
+                dynasm!(ops ; .arch x64 ; vmovdqu [Rq(b) + Rq(i) * 8 + offset_bytes], Rx(src))
+            }
+            (VectorWidth::Lanes2, Access::Aligned) => {
+                dynasm!(ops ; .arch x64 ; vmovdqa [Rq(b) + Rq(i) * 8 + offset_bytes], Rx(src))
+            }
+            (VectorWidth::Lanes2, Access::NonTemporal) => {
+                dynasm!(ops ; .arch x64 ; vmovntdq [Rq(b) + Rq(i) * 8 + offset_bytes], Rx(src))
+            }
+            (VectorWidth::Lanes4, Access::Unaligned) => {
+                self.vmovdqu_store_ymm(base_reg, index_reg, src, offset_bytes)
+            }
+            (VectorWidth::Lanes4, Access::Aligned) => {
+                dynasm!(ops ; .arch x64 ; vmovdqa [Rq(b) + Rq(i) * 8 + offset_bytes], Ry(src))
+            }
+            (VectorWidth::Lanes4, Access::NonTemporal) => {
+                dynasm!(ops ; .arch x64 ; vmovntdq [Rq(b) + Rq(i) * 8 + offset_bytes], Ry(src))
+            }
+            (VectorWidth::Lanes8, access) => {
+                let mut enc = Avx512Encoder::new();
+                match access {
+                    Access::Unaligned => enc.vmovdqu64_store(b, i, src, offset_bytes),
+                    Access::Aligned => enc.vmovdqa64_store(b, i, src, offset_bytes),
+                    Access::NonTemporal => enc.vmovntdq_zmm(b, i, src, offset_bytes),
+                }
+                self.emit_evex(enc);
+            }
+        }
+    }
+
+    /// SFENCE - Order non-temporal stores before every later store
+    pub fn sfence(&mut self) {
+        let ops = &mut self.ops;
+        dynasm!(ops ; .arch x64 ; sfence);
+    }
+
+    /// Reload a spilled vector from [rsp + offset] (slot aligned to `width.bytes()`)
+    pub fn vec_reload(&mut self, width: VectorWidth, dest: u8, offset: i32) {
+        let ops = &mut self.ops;
+        match width {
+            VectorWidth::Lanes2 => dynasm!(ops ; .arch x64 ; vmovdqa Rx(dest), [rsp + offset]),
+            VectorWidth::Lanes4 => self.vmovdqa_ymm_stack(dest, offset),
+            VectorWidth::Lanes8 => {
+                let mut enc = Avx512Encoder::new();
+                enc.vmovdqu64_load_stack(dest, offset);
+                self.emit_evex(enc);
+            }
+        }
+    }
+
+    /// Spill a vector to [rsp + offset] (slot aligned to `width.bytes()`)
+    pub fn vec_spill(&mut self, width: VectorWidth, offset: i32, src: u8) {
+        let ops = &mut self.ops;
+        match width {
+            VectorWidth::Lanes2 => dynasm!(ops ; .arch x64 ; vmovdqa [rsp + offset], Rx(src)),
+            VectorWidth::Lanes4 => self.vmovdqa_stack_ymm(offset, src),
+            VectorWidth::Lanes8 => {
+                let mut enc = Avx512Encoder::new();
+                enc.vmovdqu64_store_stack(offset, src);
+                self.emit_evex(enc);
+            }
+        }
+    }
+
+    /// VPADDQ vec_dest, vec_src1, vec_src2
+    pub fn vec_add(&mut self, width: VectorWidth, dest: u8, src1: u8, src2: u8) {
+        let ops = &mut self.ops;
+        match width {
+            VectorWidth::Lanes2 => dynasm!(ops ; .arch x64 ; vpaddq Rx(dest), Rx(src1), Rx(src2)),
+            VectorWidth::Lanes4 => self.vpaddq_ymm(dest, src1, src2),
+            VectorWidth::Lanes8 => {
+                let mut enc = Avx512Encoder::new();
+                enc.vpaddq_zmm(dest, src1, src2);
+                self.emit_evex(enc);
+            }
+        }
+    }
+
+    /// VPMINSQ vec_dest, vec_src1, vec_src2 (EVEX; xmm and ymm need AVX-512VL)
+    pub fn vec_min(&mut self, width: VectorWidth, dest: u8, src1: u8, src2: u8) {
+        let mut enc = Avx512Encoder::new();
+        match width {
+            VectorWidth::Lanes2 => enc.vpminsq_xmm(dest, src1, src2),
+            VectorWidth::Lanes4 => enc.vpminsq_ymm(dest, src1, src2),
+            VectorWidth::Lanes8 => enc.vpminsq_zmm(dest, src1, src2),
+        }
+        self.emit_evex(enc);
+    }
+
+    /// VPMAXSQ vec_dest, vec_src1, vec_src2 (EVEX; xmm and ymm need AVX-512VL)
+    pub fn vec_max(&mut self, width: VectorWidth, dest: u8, src1: u8, src2: u8) {
+        let mut enc = Avx512Encoder::new();
+        match width {
+            VectorWidth::Lanes2 => enc.vpmaxsq_xmm(dest, src1, src2),
+            VectorWidth::Lanes4 => enc.vpmaxsq_ymm(dest, src1, src2),
+            VectorWidth::Lanes8 => enc.vpmaxsq_zmm(dest, src1, src2),
+        }
+        self.emit_evex(enc);
+    }
+
+    /// VPABSQ vec_dest, vec_src (EVEX; xmm and ymm need AVX-512VL)
+    pub fn vec_abs(&mut self, width: VectorWidth, dest: u8, src: u8) {
+        let mut enc = Avx512Encoder::new();
+        match width {
+            VectorWidth::Lanes2 => enc.vpabsq_xmm(dest, src),
+            VectorWidth::Lanes4 => enc.vpabsq_ymm(dest, src),
+            VectorWidth::Lanes8 => enc.vpabsq_zmm(dest, src),
+        }
+        self.emit_evex(enc);
+    }
+
+    // AVX2 fallbacks for min/max/abs without AVX-512VL. AVX-512 compares
+    // write mask registers, so these have no zmm form (zmm has VPMINSQ etc.)
+
+    /// VPCMPGTQ vec_dest, vec_src1, vec_src2 (xmm or ymm)
+    pub fn vec_cmpgt(&mut self, width: VectorWidth, dest: u8, src1: u8, src2: u8) {
+        assert_ne!(width, VectorWidth::Lanes8, "VPCMPGTQ has no VEX zmm form");
+        let ops = &mut self.ops;
+        match width {
+            VectorWidth::Lanes2 => dynasm!(ops ; .arch x64 ; vpcmpgtq Rx(dest), Rx(src1), Rx(src2)),
+            _ => self.vpcmpgtq_ymm(dest, src1, src2),
+        }
+    }
+
+    /// VBLENDVPD vec_dest, vec_src1, vec_src2, vec_mask (xmm or ymm)
+    pub fn vec_blend(&mut self, width: VectorWidth, dest: u8, src1: u8, src2: u8, mask: u8) {
+        assert_ne!(width, VectorWidth::Lanes8, "VBLENDVPD has no zmm form");
+        let ops = &mut self.ops;
+        match width {
+            VectorWidth::Lanes2 => dynasm!(ops ; .arch x64 ; vblendvpd Rx(dest), Rx(src1), Rx(src2), Rx(mask)),
+            _ => self.vblendvpd_ymm(dest, src1, src2, mask),
+        }
+    }
+
+    /// VPXOR vec_dest, vec_src1, vec_src2 (xmm or ymm)
+    pub fn vec_xor(&mut self, width: VectorWidth, dest: u8, src1: u8, src2: u8) {
+        assert_ne!(width, VectorWidth::Lanes8, "VPXOR has no VEX zmm form");
+        let ops = &mut self.ops;
+        match width {
+            VectorWidth::Lanes2 => dynasm!(ops ; .arch x64 ; vpxor Rx(dest), Rx(src1), Rx(src2)),
+            _ => self.vpxor_ymm(dest, src1, src2),
+        }
+    }
+
+    /// VPSUBQ vec_dest, vec_src1, vec_src2 (xmm or ymm)
+    pub fn vec_sub(&mut self, width: VectorWidth, dest: u8, src1: u8, src2: u8) {
+        assert_ne!(width, VectorWidth::Lanes8, "VPSUBQ has no VEX zmm form");
+        let ops = &mut self.ops;
+        match width {
+            VectorWidth::Lanes2 => dynasm!(ops ; .arch x64 ; vpsubq Rx(dest), Rx(src1), Rx(src2)),
+            _ => self.vpsubq_ymm(dest, src1, src2),
+        }
+    }
+
     /// Generate AVX-512 vector sum loop (8 x 64-bit integers per iteration)
     /// This is the "muscle" - processes 64 bytes per loop iteration
     pub fn generate_avx512_sum_loop() -> Result<Vec<u8>, String> {
@@ -0,0 +1,128 @@
+//! Script Assertions
+//!
+//! `assert lhs op rhs` lowers to `Opcode::Assert`. Below -O2 the code
+//! generator compiles it to a compare and a branch, taken when the condition
+//! fails, to a stub that calls `nf_assert_failed` with both values and the
+//! assertion's site id and exits with `Status::AssertFailed`. From -O2 up
+//! assertions are stripped before the optimizer runs, so they cost nothing.
+//! As with overflow traps the failure is kept per thread, for wrappers to
+//! report the source line and values from `CompiledCode::assert_sites`.
+
+use crate::ir::{Function, Opcode};
+use crate::status::{self, Status};
+use serde::Serialize;
+use std::cell::Cell;
+
+/// Lowest optimization level that drops assertions
+pub const STRIP_LEVEL: u8 = 2;
+
+/// An assertion compiled into the code
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AssertSite {
+    pub function: String,
+    /// Source line (0: unknown)
+    pub line: usize,
+    /// The condition as written, e.g. "x == y"
+    pub text: String,
+}
+
+impl AssertSite {
+    pub fn new(function: &str, line: usize, text: &str) -> Self {
+        Self {
+            function: function.to_string(),
+            line,
+            text: text.to_string(),
+        }
+    }
+
+    /// What a failure with these operand values reports
+    pub fn failure(&self, lhs: i64, rhs: i64) -> String {
+        let at = if self.line > 0 {
+            format!(" at line {}", self.line)
+        } else {
+            String::new()
+        };
+        format!("assertion failed{}: {} ({} vs {})", at, self.text, lhs, rhs)
+    }
+}
+
+/// The values a failed assertion compared
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AssertFailure {
+    /// Index into `CompiledCode::assert_sites`
+    pub site: usize,
+    pub lhs: i64,
+    pub rhs: i64,
+}
+
+thread_local! {
+    static FAILURE: Cell<Option<AssertFailure>> = const { Cell::new(None) };
+}
+
+/// Handler called from the stubs of failed assertions
+pub extern "C" fn nf_assert_failed(lhs: i64, rhs: i64, site: i64) {
+    if status::raise(Status::AssertFailed) {
+        let site = site as usize;
+        FAILURE.with(|f| f.set(Some(AssertFailure { site, lhs, rhs })));
+    }
+}
+
+/// Take (and clear) this thread's last assertion failure
+pub fn take_failure() -> Option<AssertFailure> {
+    FAILURE.with(|f| f.take())
+}
+
+/// Remove `func`'s assertions; true if it had any
+pub fn strip(func: &mut Function) -> bool {
+    let before = func.instructions.len();
+    func.instructions.retain(|i| !matches!(i.op, Opcode::Assert(..)));
+    func.instructions.len() < before
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiled_program::CompiledProgram;
+    use crate::compiler::CompileOptions;
+    use crate::parser::Parser;
+
+    const SRC: &str = "fn main(x) {
+        y = x * 2
+        assert y < 10
+        return y
+    }";
+
+    #[test]
+    fn test_failure_is_kept_only_for_the_first_trap() {
+        status::reset();
+        take_failure();
+        nf_assert_failed(1, 2, 0);
+        nf_assert_failed(3, 4, 1);
+        assert_eq!(status::take(), Status::AssertFailed);
+        assert_eq!(take_failure(), Some(AssertFailure { site: 0, lhs: 1, rhs: 2 }));
+        assert_eq!(take_failure(), None);
+    }
+
+    #[test]
+    fn test_checked_below_o2_and_stripped_from_it() {
+        let prog = Parser::new().parse(SRC).unwrap();
+        for level in 0..=3 {
+            let compiled = CompiledProgram::compile(&prog, &CompileOptions::new(level)).unwrap();
+            let main = compiled.get_fn("main").unwrap();
+            assert_eq!(main.call(&[4]), Ok(8));
+            let sites = &compiled.code().assert_sites;
+            if level < STRIP_LEVEL {
+                assert_eq!(sites, &[AssertSite::new("main", 3, "y < 10")]);
+                assert_eq!(
+                    main.call(&[6]),
+                    Err("main(): assertion failed at line 3: y < 10 (12 vs 10)".to_string())
+                );
+            } else {
+                assert!(sites.is_empty());
+                assert_eq!(main.call(&[6]), Ok(12));
+                let passes = &compiled.code().report.functions[0].passes;
+                assert_eq!(passes.get("strip_assertions"), Some(&1));
+            }
+        }
+    }
+}
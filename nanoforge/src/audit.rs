@@ -0,0 +1,247 @@
+//! Tier Change Audit Log
+//!
+//! Every time running code changes under a caller (a promotion or demotion
+//! between tiers, or an evolved implementation swapped in) the runtime
+//! records why and what it bought: the function, the tiers, the metric that
+//! triggered the change and the performance before and after it.
+//!
+//! An `AuditLog` keeps the latest events in memory and, opened on a file,
+//! also appends each as one JSON line (see `store::append_line`). Processes
+//! may share one file; `recent` then reads it back, so the daemon's
+//! introspection endpoint (`GET /audit`) shows the changes of every runtime
+//! writing to it.
+
+use crate::introspection::now_ms;
+use crate::store;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Events kept in memory by default
+pub const DEFAULT_CAPACITY: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditAction {
+    /// Moved to a more optimized tier
+    Promote,
+    /// Moved back to a cheaper tier
+    Demote,
+    /// Same tier, new code (an evolved implementation)
+    Swap,
+}
+
+/// One change of running code
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditEvent {
+    /// Milliseconds since the Unix epoch
+    pub at_ms: u64,
+    pub program: String,
+    pub function: String,
+    pub action: AuditAction,
+    pub from: Option<String>,
+    pub to: String,
+    /// What triggered it, e.g. "busy 34% of the last 120 calls"
+    pub trigger: String,
+    /// Unit of `before` and `after`, e.g. "ns/call"
+    pub metric: String,
+    pub before: Option<f64>,
+    /// Measured on the new code (None if it never ran)
+    pub after: Option<f64>,
+}
+
+impl AuditEvent {
+    pub fn new(program: &str, function: &str, action: AuditAction, to: &str) -> Self {
+        Self {
+            at_ms: now_ms(),
+            program: program.to_string(),
+            function: function.to_string(),
+            action,
+            from: None,
+            to: to.to_string(),
+            trigger: String::new(),
+            metric: String::new(),
+            before: None,
+            after: None,
+        }
+    }
+
+    pub fn from(mut self, from: &str) -> Self {
+        self.from = Some(from.to_string());
+        self
+    }
+
+    pub fn trigger(mut self, trigger: impl Into<String>) -> Self {
+        self.trigger = trigger.into();
+        self
+    }
+
+    /// Performance in `metric` before the change
+    pub fn before(mut self, metric: &str, before: Option<f64>) -> Self {
+        self.metric = metric.to_string();
+        self.before = before;
+        self
+    }
+
+    /// One line for humans
+    pub fn summary(&self) -> String {
+        let perf = |v: Option<f64>| v.map_or("?".to_string(), |v| format!("{:.0}", v));
+        let mut line = format!(
+            "{:?} {}/{}: {} -> {}",
+            self.action,
+            self.program,
+            self.function,
+            self.from.as_deref().unwrap_or("-"),
+            self.to
+        );
+        if !self.trigger.is_empty() {
+            line.push_str(&format!(" ({})", self.trigger));
+        }
+        if self.before.is_some() || self.after.is_some() {
+            line.push_str(&format!(
+                ", {} -> {} {}",
+                perf(self.before),
+                perf(self.after),
+                self.metric
+            ));
+        }
+        line
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    recent: VecDeque<AuditEvent>,
+    capacity: usize,
+    path: Option<PathBuf>,
+}
+
+/// Shared, cheaply cloned audit log
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Default for AuditLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AuditLog {
+    /// Keep events in memory only
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                recent: VecDeque::new(),
+                capacity: DEFAULT_CAPACITY,
+                path: None,
+            })),
+        }
+    }
+
+    /// Also append every event to `path` (created on the first event)
+    pub fn open(path: &Path) -> Result<Self, String> {
+        if path.is_dir() {
+            return Err(format!("{}: is a directory", path.display()));
+        }
+        let log = Self::new();
+        log.inner.lock().unwrap().path = Some(path.to_path_buf());
+        Ok(log)
+    }
+
+    /// Events kept in memory (older ones are dropped)
+    pub fn capacity(self, capacity: usize) -> Self {
+        let mut inner = self.inner.lock().unwrap();
+        inner.capacity = capacity.max(1);
+        while inner.recent.len() > inner.capacity {
+            inner.recent.pop_front();
+        }
+        drop(inner);
+        self
+    }
+
+    pub fn path(&self) -> Option<PathBuf> {
+        self.inner.lock().unwrap().path.clone()
+    }
+
+    /// Record `event`; a failed file write is logged, not returned, so
+    /// auditing never stops a tier change
+    pub fn record(&self, event: AuditEvent) {
+        tracing::info!("Audit: {}", event.summary());
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(path) = &inner.path {
+            if let Err(e) = store::append_line(path, &event) {
+                tracing::warn!("Audit log: {}", e);
+            }
+        }
+        if inner.recent.len() == inner.capacity {
+            inner.recent.pop_front();
+        }
+        inner.recent.push_back(event);
+    }
+
+    /// The last `n` events, oldest first: read from the file if there is
+    /// one (so events other processes appended are included), else from
+    /// memory
+    pub fn recent(&self, n: usize) -> Result<Vec<AuditEvent>, String> {
+        let inner = self.inner.lock().unwrap();
+        let mut events: Vec<AuditEvent> = match &inner.path {
+            Some(path) => store::read_lines(path)?,
+            None => inner.recent.iter().cloned().collect(),
+        };
+        let skip = events.len().saturating_sub(n);
+        events.drain(..skip);
+        Ok(events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(to: &str) -> AuditEvent {
+        AuditEvent::new("kernel", "main", AuditAction::Promote, to)
+            .from("baseline")
+            .trigger("busy 40% of the last 12 calls")
+            .before("ns/call", Some(900.0))
+    }
+
+    #[test]
+    fn test_keeps_the_latest_events_in_memory() {
+        let log = AuditLog::new().capacity(2);
+        for to in ["a", "b", "c"] {
+            log.record(event(to));
+        }
+        let recent = log.recent(10).unwrap();
+        let tiers: Vec<&str> = recent.iter().map(|e| e.to.as_str()).collect();
+        assert_eq!(tiers, ["b", "c"]);
+        assert_eq!(log.recent(1).unwrap()[0].to, "c");
+        assert_eq!(
+            recent[0].summary(),
+            "Promote kernel/main: baseline -> b (busy 40% of the last 12 calls), 900 -> ? ns/call"
+        );
+    }
+
+    #[test]
+    fn test_file_is_shared_json_lines() {
+        let path = std::env::temp_dir().join(format!("nf_audit_{}.jsonl", std::process::id()));
+        std::fs::remove_file(&path).ok();
+        let first = AuditLog::open(&path).unwrap();
+        let second = AuditLog::open(&path).unwrap();
+        first.record(event("optimized"));
+        let mut swap = AuditEvent::new("kernel", "main", AuditAction::Swap, "evolved");
+        swap.after = Some(450.0);
+        second.record(swap.clone());
+
+        // Either handle sees both writers' events
+        let recent = first.recent(10).unwrap();
+        assert_eq!(recent.len(), 2);
+        assert_eq!(recent[1], swap);
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert!(text.lines().next().unwrap().contains("\"action\":\"promote\""), "{}", text);
+        assert!(AuditLog::open(&std::env::temp_dir()).is_err());
+        std::fs::remove_file(&path).ok();
+    }
+}
@@ -1,13 +1,37 @@
-use crate::compiler::Compiler;
-use crate::jit_memory::DualMappedMemory;
+use crate::capture::Replay;
+use crate::compiled_program::CompiledProgram;
+use crate::compiler::CompileOptions;
+use crate::distribution::{InputDistribution, DEFAULT_POINTS};
 use crate::parser::Parser;
+use crate::profiler::PerfCounters;
+use crate::sandbox::Stability;
+use serde::Serialize;
 use std::hint::black_box;
-use std::mem;
 
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 use std::arch::x86_64::_rdtsc;
 
-pub fn run_benchmark(script: &str, iterations: usize, opt_level: u8) -> Result<(), String> {
+/// Timing of one `run_benchmark`
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct BenchmarkSummary {
+    pub iterations: usize,
+    pub total_cycles: u64,
+    pub avg_cycles: f64,
+    /// What every call returned
+    pub result: i64,
+}
+
+/// Compile `script` and time `iterations` calls of its entry point.
+///
+/// With `stats`, hardware counters are collected over the measured loop.
+/// Every call must return the same value; a kernel whose result varies is
+/// reported as an error rather than a timing.
+pub fn run_benchmark(
+    script: &str,
+    iterations: usize,
+    opt_level: u8,
+    stats: bool,
+) -> Result<BenchmarkSummary, String> {
     println!("Benchmarking script ({} iterations)...", iterations);
 
     // 1. Parse
@@ -16,38 +40,55 @@ pub fn run_benchmark(script: &str, iterations: usize, opt_level: u8) -> Result<(
         .parse(script)
         .map_err(|e| format!("Parse error: {}", e))?;
 
-    // 2. Compile
-    let (code, start_offset) = Compiler::compile_program(&program, opt_level)?;
-
-    // 3. JIT Memory
-    let memory =
-        DualMappedMemory::new(code.len() + 4096).map_err(|e| format!("Memory error: {}", e))?;
-
-    // Emit code
-    crate::assembler::CodeGenerator::emit_to_memory(&memory, &code, 0);
+    // 2. Compile into JIT memory
+    let compiled = CompiledProgram::compile(&program, &CompileOptions::new(opt_level))?;
 
-    // 4. Get Function Pointer
-    let func_ptr = unsafe { memory.rx_ptr.add(start_offset) };
-    let func: extern "C" fn() -> i64 = unsafe { mem::transmute(func_ptr) };
+    // 3. Entry point; any parameters receive 0
+    let main: extern "C" fn(i64, i64, i64, i64) -> i64 = compiled
+        .get("main")
+        .ok_or("main() takes too many parameters to benchmark")?;
+    let func = || main(0, 0, 0, 0);
 
-    println!("Code compiled. Size: {} bytes. executing...", code.len());
+    println!(
+        "Code compiled. Size: {} bytes. executing...",
+        compiled.code().code.len()
+    );
 
-    // 5. Warmup
+    // 4. Warmup
     println!("Warming up...");
+    let mut stability = Stability::new(black_box(func()) as u64);
     for _ in 0..100 {
-        black_box(func());
+        stability.record(black_box(func()) as u64);
     }
 
-    // 6. Benchmark
+    // 5. Benchmark
     println!("Running benchmark loop...");
 
-    let start_cycles = unsafe { _rdtsc() };
+    // Counters are opened before timing so setup isn't charged to the loop
+    let counters = if stats {
+        PerfCounters::new()
+            .map_err(|e| tracing::warn!("--stats unavailable: {}", e))
+            .ok()
+    } else {
+        None
+    };
 
-    for _ in 0..iterations {
-        black_box(func());
-    }
+    let mut run_loop = || {
+        let start_cycles = unsafe { _rdtsc() };
+        for _ in 0..iterations {
+            stability.record(black_box(func()) as u64);
+        }
+        let end_cycles = unsafe { _rdtsc() };
+        (start_cycles, end_cycles)
+    };
 
-    let end_cycles = unsafe { _rdtsc() };
+    let ((start_cycles, end_cycles), perf) = match &counters {
+        Some(c) => {
+            let (span, perf) = c.measure(run_loop);
+            (span, Some(perf))
+        }
+        None => (run_loop(), None),
+    };
 
     let total_cycles = end_cycles - start_cycles;
     let avg_cycles = total_cycles as f64 / iterations as f64;
@@ -58,5 +99,222 @@ pub fn run_benchmark(script: &str, iterations: usize, opt_level: u8) -> Result<(
     println!("Avg Cycles/Op: {:.2}", avg_cycles);
     println!("---------------------------------------------------");
 
-    Ok(())
+    if let Some(perf) = perf {
+        print!("{}", perf.summary());
+        println!("---------------------------------------------------");
+    }
+
+    match stability.first_mismatch {
+        None => {
+            println!("Result:       {} (stable)", stability.output as i64);
+            Ok(BenchmarkSummary {
+                iterations,
+                total_cycles,
+                avg_cycles,
+                result: stability.output as i64,
+            })
+        }
+        Some(then) => Err(format!(
+            "Unstable result: main() returned {} and then {} ({} of {} calls differed)",
+            stability.output as i64,
+            then as i64,
+            stability.mismatches,
+            iterations + 101
+        )),
+    }
+}
+
+/// Timing of one `run_replay`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ReplaySummary {
+    pub function: String,
+    /// Recorded calls replayed per iteration
+    pub samples: usize,
+    pub iterations: usize,
+    pub total_cycles: u64,
+    /// Cycles per replayed call
+    pub avg_cycles: f64,
+    /// Input the function got a guarded copy for (`specialize`)
+    pub specialized_on: Option<i32>,
+}
+
+/// Compile `script` and time `iterations` passes over the calls recorded in
+/// `replay`, checking each returns what it returned when recorded.
+///
+/// With `specialize`, an input the replay shows to be nearly constant
+/// becomes a `constant_args` hint: the function is compiled with a copy
+/// for that value behind a guard (`specialize::specialize_constant_args`).
+pub fn run_replay(
+    script: &str,
+    replay: &Replay,
+    iterations: usize,
+    opt_level: u8,
+    specialize: bool,
+) -> Result<ReplaySummary, String> {
+    if replay.samples.is_empty() {
+        return Err("Replay has no recorded calls".to_string());
+    }
+    println!(
+        "Replaying {} recorded calls of {}() ({} iterations)...",
+        replay.samples.len(),
+        replay.function,
+        iterations
+    );
+
+    let mut program = Parser::new()
+        .parse(script)
+        .map_err(|e| format!("Parse error: {}", e))?;
+    let mut specialized_on = None;
+    if specialize {
+        let func = program
+            .functions
+            .iter_mut()
+            .find(|f| f.name == replay.function)
+            .ok_or_else(|| format!("no function named '{}'", replay.function))?;
+        func.hints.constant_args = replay.arg_profile().expected();
+        specialized_on = func.hints.constant_args.get(&0).copied();
+        match specialized_on {
+            Some(value) => println!("Specializing {}() on input {}", replay.function, value),
+            None => println!("No input of {}() is nearly constant", replay.function),
+        }
+    }
+    let compiled = CompiledProgram::compile(&program, &CompileOptions::new(opt_level))?;
+    let func: extern "C" fn(u64) -> u64 = compiled.get(&replay.function).ok_or_else(|| {
+        format!(
+            "{}() is missing or takes more than one parameter",
+            replay.function
+        )
+    })?;
+
+    // Warmup doubles as the check against the recording
+    for sample in &replay.samples {
+        let output = func(sample.input);
+        if output != sample.output {
+            return Err(format!(
+                "{}({}) returned {}, recorded {}",
+                replay.function, sample.input as i64, output as i64, sample.output as i64
+            ));
+        }
+    }
+
+    let start_cycles = unsafe { _rdtsc() };
+    for _ in 0..iterations {
+        for sample in &replay.samples {
+            black_box(func(black_box(sample.input)));
+        }
+    }
+    let end_cycles = unsafe { _rdtsc() };
+
+    let total_cycles = end_cycles - start_cycles;
+    let calls = iterations * replay.samples.len();
+    let avg_cycles = total_cycles as f64 / calls.max(1) as f64;
+
+    println!("---------------------------------------------------");
+    println!("Total Cycles: {}", total_cycles);
+    println!("Calls:        {}", calls);
+    println!("Avg Cycles/Op: {:.2}", avg_cycles);
+    println!("---------------------------------------------------");
+    println!("Outputs:      match the recording");
+
+    Ok(ReplaySummary {
+        function: replay.function.clone(),
+        samples: replay.samples.len(),
+        iterations,
+        total_cycles,
+        avg_cycles,
+        specialized_on,
+    })
+}
+
+/// Timing at one input of a `run_distribution`
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct InputTiming {
+    pub input: u64,
+    pub weight: f64,
+    pub avg_cycles: f64,
+    /// What every call with this input returned
+    pub result: i64,
+}
+
+/// Timing of one `run_distribution`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DistributionSummary {
+    /// The distribution's spec
+    pub inputs: String,
+    pub iterations: usize,
+    pub timings: Vec<InputTiming>,
+    /// Cycles per call averaged over the inputs by weight
+    pub expected_cycles: f64,
+}
+
+/// Compile `script` and time `main(n)` at inputs standing for `inputs`,
+/// `iterations` calls in all, reporting the expected cost under it
+pub fn run_distribution(
+    script: &str,
+    inputs: &InputDistribution,
+    iterations: usize,
+    opt_level: u8,
+) -> Result<DistributionSummary, String> {
+    let points = inputs.points(DEFAULT_POINTS);
+    println!(
+        "Benchmarking script over {} ({} inputs, {} iterations)...",
+        inputs,
+        points.len(),
+        iterations
+    );
+
+    let program = Parser::new()
+        .parse(script)
+        .map_err(|e| format!("Parse error: {}", e))?;
+    let compiled = CompiledProgram::compile(&program, &CompileOptions::new(opt_level))?;
+    let main: extern "C" fn(u64) -> u64 = compiled
+        .get("main")
+        .ok_or("main() must take at most one parameter to benchmark over inputs")?;
+
+    let per_input = (iterations / points.len()).max(1);
+    let mut timings = Vec::with_capacity(points.len());
+    println!("---------------------------------------------------");
+    println!("{:>12} {:>8} {:>14}", "Input", "Weight", "Avg Cycles/Op");
+    for point in &points {
+        let mut stability = Stability::new(black_box(main(point.input)));
+        for _ in 0..100 {
+            stability.record(black_box(main(point.input)));
+        }
+        let start_cycles = unsafe { _rdtsc() };
+        for _ in 0..per_input {
+            stability.record(black_box(main(black_box(point.input))));
+        }
+        let end_cycles = unsafe { _rdtsc() };
+        if let Some(then) = stability.first_mismatch {
+            return Err(format!(
+                "Unstable result: main({}) returned {} and then {}",
+                point.input, stability.output as i64, then as i64
+            ));
+        }
+        let avg_cycles = (end_cycles - start_cycles) as f64 / per_input as f64;
+        println!(
+            "{:>12} {:>7.1}% {:>14.2}",
+            point.input,
+            point.weight * 100.0,
+            avg_cycles
+        );
+        timings.push(InputTiming {
+            input: point.input,
+            weight: point.weight,
+            avg_cycles,
+            result: stability.output as i64,
+        });
+    }
+
+    let expected_cycles = timings.iter().map(|t| t.weight * t.avg_cycles).sum();
+    println!("---------------------------------------------------");
+    println!("Expected Cycles/Op: {:.2}", expected_cycles);
+    println!("---------------------------------------------------");
+
+    Ok(DistributionSummary {
+        inputs: inputs.to_string(),
+        iterations: per_input * points.len(),
+        timings,
+        expected_cycles,
+    })
 }
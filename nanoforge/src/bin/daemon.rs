@@ -1,10 +1,15 @@
 use clap::Parser;
+use nanoforge::audit::AuditLog;
+use nanoforge::introspection::{Introspection, StatusView};
+use nanoforge::jit_memory;
 use nanoforge::profiler::Profiler;
+use nanoforge::shutdown::{self, StateDir};
 use std::fs;
-use std::io::{BufRead, BufReader, Write};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
 use std::os::unix::net::{UnixListener, UnixStream};
 use std::path::Path;
 use std::thread;
+use std::time::Duration;
 use tracing::{error, info, warn};
 
 #[derive(Parser, Debug)]
@@ -13,6 +18,23 @@ struct Args {
     /// Path to the Unix Domain Socket
     #[arg(short, long, default_value = "/tmp/nanoforge.sock")]
     socket_path: String,
+
+    /// Serve JSON status views (programs, tiers, bandits, JIT memory) over HTTP
+    #[arg(long, value_name = "ADDR")]
+    http: Option<String>,
+
+    /// Serve the tier changes runtimes append to this audit log (their
+    /// `--audit-log`) at /audit on the --http endpoint
+    #[arg(long, value_name = "FILE")]
+    audit_log: Option<String>,
+
+    /// Save the final status here when shut down with Ctrl+C or SIGTERM
+    #[arg(long, value_name = "DIR")]
+    state_dir: Option<String>,
+
+    /// Cap live JIT code at this many bytes (shown at /memory)
+    #[arg(long, value_name = "BYTES")]
+    jit_memory_cap: Option<usize>,
 }
 
 fn main() {
@@ -20,6 +42,7 @@ fn main() {
     tracing_subscriber::fmt::init();
 
     let args = Args::parse();
+    jit_memory::set_limit(args.jit_memory_cap);
 
     info!("NanoForge Daemon starting...");
 
@@ -40,19 +63,63 @@ fn main() {
 
     info!("Listening on {}", args.socket_path);
 
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                thread::spawn(|| handle_client(stream));
+    let mut introspection = Introspection::new();
+    if let Some(path) = &args.audit_log {
+        match AuditLog::open(Path::new(path)) {
+            Ok(audit) => introspection = introspection.with_audit(audit),
+            Err(e) => {
+                error!("{}", e);
+                return;
+            }
+        }
+    }
+    if let Some(addr) = &args.http {
+        match introspection.serve(addr) {
+            Ok((local, _)) => info!("Status endpoint on http://{}/status", local),
+            Err(e) => error!("Failed to serve status on {}: {}", addr, e),
+        }
+    }
+
+    // Poll instead of blocking in accept so a shutdown request is noticed
+    if let Err(e) = shutdown::install() {
+        warn!("Graceful shutdown unavailable: {}", e);
+    }
+    if let Err(e) = listener.set_nonblocking(true) {
+        error!("Failed to make the socket non-blocking: {}", e);
+        return;
+    }
+    while !shutdown::requested() {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                if let Err(e) = stream.set_nonblocking(false) {
+                    error!("Failed to configure connection: {}", e);
+                    continue;
+                }
+                let introspection = introspection.clone();
+                thread::spawn(move || handle_client(stream, introspection));
+            }
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(100));
             }
             Err(err) => {
                 error!("Error accepting connection: {}", err);
             }
         }
     }
+
+    info!("Shutting down");
+    if let Some(dir) = &args.state_dir {
+        match StateDir::open(Path::new(dir))
+            .and_then(|state| state.save_json("daemon-status.json", StatusView::SCHEMA, &introspection.status()))
+        {
+            Ok(path) => info!("Saved {}", path.display()),
+            Err(e) => error!("{}", e),
+        }
+    }
+    let _ = fs::remove_file(&args.socket_path);
 }
 
-fn handle_client(mut stream: UnixStream) {
+fn handle_client(mut stream: UnixStream, introspection: Introspection) {
     let stream_clone = match stream.try_clone() {
         Ok(s) => s,
         Err(e) => {
@@ -62,6 +129,7 @@ fn handle_client(mut stream: UnixStream) {
     };
     let mut reader = BufReader::new(stream_clone);
     let mut profiler: Option<Profiler> = None;
+    let mut registered: Option<String> = None;
 
     loop {
         let mut line = String::new();
@@ -91,6 +159,9 @@ fn handle_client(mut stream: UnixStream) {
                             Ok(p) => {
                                 p.enable(); // Start profiling immediately
                                 profiler = Some(p);
+                                let name = format!("pid:{}", pid);
+                                introspection.register_program(&name, &[]);
+                                registered = Some(name);
                                 let _ = stream.write_all(b"OK\n");
                             }
                             Err(e) => {
@@ -122,6 +193,9 @@ fn handle_client(mut stream: UnixStream) {
             }
         }
     }
+    if let Some(name) = registered {
+        introspection.unregister_program(&name);
+    }
 }
 
 fn check_permissions(stream: &UnixStream, target_pid: i32) -> Result<(), String> {
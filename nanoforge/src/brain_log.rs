@@ -0,0 +1,292 @@
+//! Bandit Training Log
+//!
+//! Append-only JSONL record of every bandit update (the selection, its
+//! context, the measured cycles and the reward it produced), written by the
+//! SOAE commands and the Python `Optimizer`. `nanoforge analyze` reads it
+//! back and shows how quickly the bandit converged and what its exploration
+//! cost in each size bucket.
+
+use crate::ai_optimizer::{performance_reward, OptimizationFeatures, SizeBucket};
+use crate::store;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+/// One bandit update
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BanditEvent {
+    /// Who learned: "soae-ai", "soae-context" or "python"
+    pub source: String,
+    /// 1-based update count within the run that logged it
+    pub step: u64,
+    pub input_size: u64,
+    pub bucket: SizeBucket,
+    pub variant_idx: usize,
+    pub variant: String,
+    /// Cycles per op of the selected variant
+    pub cycles: u64,
+    /// Best cycles per op known for this context
+    pub best_cycles: u64,
+    pub reward: f64,
+}
+
+impl BanditEvent {
+    /// Reward lost by not picking the best variant
+    pub fn regret(&self) -> f64 {
+        (1.0 - self.reward).max(0.0)
+    }
+}
+
+/// Appends events for one run to a JSONL file
+pub struct BrainLog {
+    file: File,
+    source: String,
+    step: u64,
+}
+
+impl BrainLog {
+    /// Open `path` for appending, creating it if needed
+    pub fn append(path: &Path, source: &str) -> Result<Self, String> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+        Ok(Self {
+            file,
+            source: source.to_string(),
+            step: 0,
+        })
+    }
+
+    /// Log an update, computing its reward the way the bandit does
+    pub fn record(
+        &mut self,
+        context: &OptimizationFeatures,
+        variant_idx: usize,
+        variant: &str,
+        cycles: u64,
+        best_cycles: u64,
+    ) -> Result<(), String> {
+        self.step += 1;
+        let event = BanditEvent {
+            source: self.source.clone(),
+            step: self.step,
+            input_size: context.input_size,
+            bucket: context.size_bucket(),
+            variant_idx,
+            variant: variant.to_string(),
+            cycles,
+            best_cycles,
+            reward: performance_reward(cycles, best_cycles),
+        };
+        let line =
+            serde_json::to_string(&event).map_err(|e| format!("Failed to serialize: {}", e))?;
+        writeln!(self.file, "{}", line).map_err(|e| format!("Failed to write log: {}", e))
+    }
+}
+
+/// Read every event from a JSONL log (blank lines and a last line torn by
+/// a crash are skipped)
+pub fn read_log(path: &Path) -> Result<Vec<BanditEvent>, String> {
+    if !path.is_file() {
+        return Err(format!("Failed to read {}: not found", path.display()));
+    }
+    store::read_lines(path)
+}
+
+/// One window of the convergence curve
+#[derive(Debug, Clone, PartialEq)]
+pub struct CurvePoint {
+    /// First and last event index (0-based, inclusive)
+    pub events: (usize, usize),
+    pub mean_reward: f64,
+    /// Fraction of selections that picked their bucket's best variant
+    pub best_rate: f64,
+}
+
+/// What the bandit learned, and paid, in one size bucket
+#[derive(Debug, Clone, PartialEq)]
+pub struct BucketRegret {
+    pub bucket: SizeBucket,
+    pub events: usize,
+    /// Variant with the highest mean reward observed in this bucket
+    pub best_variant: String,
+    pub mean_reward: f64,
+    /// Sum of `1 - reward` over the bucket's events
+    pub cumulative_regret: f64,
+    /// Cycles spent beyond the best known, summed
+    pub excess_cycles: u64,
+    /// Fraction of the bucket's later half that picked `best_variant`
+    pub final_best_rate: f64,
+}
+
+/// Convergence curve and per-bucket regret of a training log
+#[derive(Debug, Clone, PartialEq)]
+pub struct BrainAnalysis {
+    pub events: usize,
+    pub curve: Vec<CurvePoint>,
+    pub buckets: Vec<BucketRegret>,
+}
+
+/// Analyze events in log order, splitting the curve into `windows` points
+pub fn analyze(events: &[BanditEvent], windows: usize) -> BrainAnalysis {
+    // Best variant per bucket: highest mean reward among those ever selected
+    let mut sums: HashMap<(SizeBucket, &str), (f64, usize)> = HashMap::new();
+    for e in events {
+        let entry = sums.entry((e.bucket, e.variant.as_str())).or_default();
+        entry.0 += e.reward;
+        entry.1 += 1;
+    }
+    let mut best: HashMap<SizeBucket, (&str, f64)> = HashMap::new();
+    for (&(bucket, variant), &(sum, count)) in &sums {
+        let mean = sum / count as f64;
+        let current = best.entry(bucket).or_insert((variant, mean));
+        // Ties go to the name that sorts first so the result is deterministic
+        if mean > current.1 || (mean == current.1 && variant < current.0) {
+            *current = (variant, mean);
+        }
+    }
+    let picked_best = |e: &BanditEvent| best.get(&e.bucket).is_some_and(|b| b.0 == e.variant);
+
+    let window = events.len().div_ceil(windows.max(1)).max(1);
+    let curve = events
+        .chunks(window)
+        .enumerate()
+        .map(|(i, chunk)| CurvePoint {
+            events: (i * window, i * window + chunk.len() - 1),
+            mean_reward: chunk.iter().map(|e| e.reward).sum::<f64>() / chunk.len() as f64,
+            best_rate: chunk.iter().filter(|e| picked_best(e)).count() as f64 / chunk.len() as f64,
+        })
+        .collect();
+
+    let buckets = SizeBucket::all()
+        .into_iter()
+        .filter_map(|bucket| {
+            let in_bucket: Vec<&BanditEvent> =
+                events.iter().filter(|e| e.bucket == bucket).collect();
+            let (best_variant, _) = *best.get(&bucket)?;
+            let later = &in_bucket[in_bucket.len() / 2..];
+            Some(BucketRegret {
+                bucket,
+                events: in_bucket.len(),
+                best_variant: best_variant.to_string(),
+                mean_reward: in_bucket.iter().map(|e| e.reward).sum::<f64>()
+                    / in_bucket.len() as f64,
+                cumulative_regret: in_bucket.iter().map(|e| e.regret()).sum(),
+                excess_cycles: in_bucket
+                    .iter()
+                    .map(|e| e.cycles.saturating_sub(e.best_cycles))
+                    .sum(),
+                final_best_rate: later.iter().filter(|e| picked_best(e)).count() as f64
+                    / later.len() as f64,
+            })
+        })
+        .collect();
+
+    BrainAnalysis {
+        events: events.len(),
+        curve,
+        buckets,
+    }
+}
+
+impl BrainAnalysis {
+    /// Print the convergence curve and the per-bucket regret table
+    pub fn print(&self) {
+        println!("\n📈 Convergence ({} events):", self.events);
+        println!("┌─────────────────┬────────┬────────────────────────────────────────┐");
+        println!("│ Events          │ Reward │ Picked bucket's best                   │");
+        println!("├─────────────────┼────────┼────────────────────────────────────────┤");
+        for point in &self.curve {
+            let bar = "█".repeat((point.best_rate * 30.0).round() as usize);
+            println!(
+                "│ {:>6} - {:<6} │ {:6.3} │ {:<30} {:>6.1}% │",
+                point.events.0 + 1,
+                point.events.1 + 1,
+                point.mean_reward,
+                bar,
+                point.best_rate * 100.0
+            );
+        }
+        println!("└─────────────────┴────────┴────────────────────────────────────────┘");
+
+        println!("\n🎯 Per-Bucket Regret:");
+        println!("┌──────────────────┬────────┬──────────────────┬────────┬──────────┬──────────────┬──────────┐");
+        println!("│ Bucket           │ Events │ Best Variant     │ Reward │ Regret   │ Excess Cyc   │ Late Hit │");
+        println!("├──────────────────┼────────┼──────────────────┼────────┼──────────┼──────────────┼──────────┤");
+        for b in &self.buckets {
+            println!(
+                "│ {:16} │ {:6} │ {:16} │ {:6.3} │ {:8.2} │ {:12} │ {:7.1}% │",
+                b.bucket.name(),
+                b.events,
+                b.best_variant,
+                b.mean_reward,
+                b.cumulative_regret,
+                b.excess_cycles,
+                b.final_best_rate * 100.0
+            );
+        }
+        println!("└──────────────────┴────────┴──────────────────┴────────┴──────────┴──────────────┴──────────┘");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_round_trip() {
+        let path = std::env::temp_dir().join(format!("nf_brain_{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut log = BrainLog::append(&path, "soae-context").unwrap();
+        log.record(&OptimizationFeatures::new(10), 0, "Scalarx1", 100, 100)
+            .unwrap();
+        log.record(&OptimizationFeatures::new(5000), 2, "AVX2x4", 200, 50)
+            .unwrap();
+        // A second run appends rather than truncating
+        let mut log = BrainLog::append(&path, "python").unwrap();
+        log.record(&OptimizationFeatures::new(5000), 1, "AVX2x2", 50, 50)
+            .unwrap();
+
+        let events = read_log(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[1].bucket, SizeBucket::Large);
+        assert_eq!(events[1].reward, 0.25);
+        assert_eq!((events[2].source.as_str(), events[2].step), ("python", 1));
+    }
+
+    #[test]
+    fn test_analysis_tracks_convergence_and_regret() {
+        let event = |variant: &str, cycles| BanditEvent {
+            source: "test".to_string(),
+            step: 0,
+            input_size: 1000,
+            bucket: SizeBucket::Medium,
+            variant_idx: 0,
+            variant: variant.to_string(),
+            cycles,
+            best_cycles: 100,
+            reward: performance_reward(cycles, 100),
+        };
+        // Explores the slow variant early, then settles on the fast one
+        let mut events = vec![event("slow", 400), event("fast", 100), event("slow", 400)];
+        events.extend((0..5).map(|_| event("fast", 100)));
+
+        let analysis = analyze(&events, 2);
+        assert_eq!(analysis.curve.len(), 2);
+        assert_eq!(analysis.curve[0].best_rate, 0.5);
+        assert_eq!(analysis.curve[1].best_rate, 1.0);
+
+        let medium = &analysis.buckets[0];
+        assert_eq!(analysis.buckets.len(), 1);
+        assert_eq!(medium.best_variant, "fast");
+        assert_eq!(medium.cumulative_regret, 1.5);
+        assert_eq!(medium.excess_cycles, 600);
+        assert_eq!(medium.final_best_rate, 1.0);
+    }
+}
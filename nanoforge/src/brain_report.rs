@@ -0,0 +1,300 @@
+//! Brain Reports
+//!
+//! A contextual brain (the `context-brain-*.json` that `soae-context` saves
+//! to `--state-dir`) is raw bandit state. `BrainReport` makes it reviewable:
+//! the best variant for each size bucket with its expected reward,
+//! confidence and sample count, and the sizes at which the best variant
+//! changes. `to_markdown` is for people, `to_csv` for tools.
+//!
+//! The CSV is also the way back in. A team can move a bucket's `best` mark
+//! to the variant it trusts, read the file with `DispatchTable::from_csv`
+//! and ship it: `Dispatcher::load_table` makes each listed bucket dispatch
+//! straight to its variant instead of learning.
+
+use crate::ai_optimizer::{ContextualBandit, SizeBucket};
+use crate::store;
+use serde::{Deserialize, Serialize};
+use std::fmt::Write;
+use std::path::Path;
+
+/// Columns of `BrainReport::to_csv`, one row per bucket and variant
+pub const CSV_HEADER: &str = "bucket,min_size,variant,selections,expected,confidence,best";
+
+/// One variant's standing in one bucket
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ArmReport {
+    pub variant: String,
+    pub selections: u64,
+    /// Mean of the arm's Beta posterior (0.0 - 1.0)
+    pub expected: f64,
+    /// α + β: pseudo-observations behind `expected`
+    pub confidence: f64,
+}
+
+/// What the bandit of one size bucket learned
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BucketReport {
+    pub bucket: SizeBucket,
+    pub best: String,
+    pub arms: Vec<ArmReport>,
+}
+
+impl BucketReport {
+    /// Measurements the bucket's bandit took
+    pub fn samples(&self) -> u64 {
+        self.arms.iter().map(|a| a.selections).sum()
+    }
+
+    fn best_arm(&self) -> Option<&ArmReport> {
+        self.arms.iter().find(|a| a.variant == self.best)
+    }
+}
+
+/// A size where the best variant changes between neighbouring buckets
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Crossover {
+    /// First size of the bucket the new variant wins
+    pub size: u64,
+    pub from: String,
+    pub to: String,
+}
+
+/// A contextual brain's decisions, bucket by bucket from small to huge
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BrainReport {
+    pub buckets: Vec<BucketReport>,
+}
+
+impl BrainReport {
+    pub fn from_bandit(bandit: &ContextualBandit) -> Self {
+        let buckets = SizeBucket::all()
+            .into_iter()
+            .filter_map(|bucket| {
+                let learner = bandit.bucket_bandit(bucket)?;
+                let arms = learner
+                    .get_stats()
+                    .into_iter()
+                    .map(|s| ArmReport {
+                        variant: s.name,
+                        selections: s.selections,
+                        expected: s.expected_value,
+                        confidence: s.confidence,
+                    })
+                    .collect();
+                let best = bandit.variant_names().get(learner.get_best())?.clone();
+                Some(BucketReport { bucket, best, arms })
+            })
+            .collect();
+        Self { buckets }
+    }
+
+    /// Report on the contextual brain saved at `path`
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let bandit: ContextualBandit = store::load(path, ContextualBandit::SCHEMA)
+            .map_err(|e| format!("not a contextual brain: {}", e))?
+            .ok_or_else(|| format!("{}: not found", path.display()))?;
+        Ok(Self::from_bandit(&bandit))
+    }
+
+    pub fn crossovers(&self) -> Vec<Crossover> {
+        self.buckets
+            .windows(2)
+            .filter(|pair| pair[0].best != pair[1].best)
+            .map(|pair| Crossover {
+                size: pair[1].bucket.min_size(),
+                from: pair[0].best.clone(),
+                to: pair[1].best.clone(),
+            })
+            .collect()
+    }
+
+    /// Best variant per bucket
+    pub fn dispatch_table(&self) -> DispatchTable {
+        DispatchTable {
+            winners: self.buckets.iter().map(|b| (b.bucket, b.best.clone())).collect(),
+        }
+    }
+
+    pub fn to_markdown(&self) -> String {
+        let mut out = String::from("# Learned dispatch decisions\n\n");
+        out.push_str("| Input size | Best variant | Expected | Confidence | Samples |\n|---|---|---|---|---|\n");
+        for b in &self.buckets {
+            let (expected, confidence) = b.best_arm().map_or((0.0, 0.0), |a| (a.expected, a.confidence));
+            let _ = writeln!(
+                out,
+                "| {} | {} | {:.3} | {:.1} | {} |",
+                b.bucket.name(),
+                b.best,
+                expected,
+                confidence,
+                b.samples()
+            );
+        }
+        out.push_str("\n## Crossovers\n\n");
+        let crossovers = self.crossovers();
+        if crossovers.is_empty() {
+            out.push_str("The same variant wins at every size.\n");
+        }
+        for c in crossovers {
+            let _ = writeln!(out, "- From {} inputs on: {} instead of {}", c.size, c.to, c.from);
+        }
+        for b in &self.buckets {
+            let _ = write!(
+                out,
+                "\n## {}\n\n| Variant | Selections | Expected | Confidence |\n|---|---|---|---|\n",
+                b.bucket.name()
+            );
+            for a in &b.arms {
+                let _ = writeln!(
+                    out,
+                    "| {} | {} | {:.3} | {:.1} |",
+                    a.variant, a.selections, a.expected, a.confidence
+                );
+            }
+        }
+        out
+    }
+
+    pub fn to_csv(&self) -> String {
+        let mut out = format!("{}\n", CSV_HEADER);
+        for b in &self.buckets {
+            for a in &b.arms {
+                let _ = writeln!(
+                    out,
+                    "{:?},{},{},{},{:.6},{:.3},{}",
+                    b.bucket,
+                    b.bucket.min_size(),
+                    a.variant,
+                    a.selections,
+                    a.expected,
+                    a.confidence,
+                    a.variant == b.best
+                );
+            }
+        }
+        out
+    }
+}
+
+/// The variant to run for each listed size bucket
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DispatchTable {
+    pub winners: Vec<(SizeBucket, String)>,
+}
+
+impl DispatchTable {
+    /// Read the rows marked `best` from a `BrainReport::to_csv` file
+    pub fn from_csv(csv: &str) -> Result<Self, String> {
+        let mut lines = csv.lines().enumerate().filter(|(_, l)| !l.trim().is_empty());
+        match lines.next() {
+            Some((_, header)) if header.trim() == CSV_HEADER => {}
+            _ => return Err(format!("expected the header line '{}'", CSV_HEADER)),
+        }
+        let mut winners: Vec<(SizeBucket, String)> = Vec::new();
+        for (i, line) in lines {
+            let fail = |msg: String| format!("line {}: {}", i + 1, msg);
+            let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+            if fields.len() != 7 {
+                return Err(fail(format!("expected 7 fields, found {}", fields.len())));
+            }
+            let bucket = SizeBucket::all()
+                .into_iter()
+                .find(|b| format!("{:?}", b) == fields[0])
+                .ok_or_else(|| fail(format!("unknown size bucket '{}'", fields[0])))?;
+            let best: bool = fields[6]
+                .parse()
+                .map_err(|_| fail(format!("best must be true or false, not '{}'", fields[6])))?;
+            if !best {
+                continue;
+            }
+            if winners.iter().any(|(b, _)| *b == bucket) {
+                return Err(fail(format!("{:?} has more than one best variant", bucket)));
+            }
+            winners.push((bucket, fields[2].to_string()));
+        }
+        winners.sort_by_key(|(bucket, _)| bucket.min_size());
+        Ok(Self { winners })
+    }
+
+    pub fn winner(&self, bucket: SizeBucket) -> Option<&str> {
+        self.winners
+            .iter()
+            .find(|(b, _)| *b == bucket)
+            .map(|(_, name)| name.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ai_optimizer::OptimizationFeatures;
+
+    fn trained() -> ContextualBandit {
+        let mut bandit = ContextualBandit::with_seed(vec!["Scalar".to_string(), "AVX2".to_string()], 3);
+        for size in [16, 100, 1000, 10_000, 100_000] {
+            let context = OptimizationFeatures::new(size);
+            let (fast, slow) = if size < 256 { (0, 1) } else { (1, 0) };
+            for _ in 0..10 {
+                bandit.update_with_performance(&context, fast, 100, 100);
+                bandit.update_with_performance(&context, slow, 300, 100);
+            }
+        }
+        bandit
+    }
+
+    #[test]
+    fn test_report_finds_crossovers() {
+        let report = BrainReport::from_bandit(&trained());
+        assert_eq!(report.buckets.len(), 5);
+        assert_eq!(report.buckets[0].best, "Scalar");
+        assert_eq!(report.buckets[4].best, "AVX2");
+        assert_eq!(
+            report.crossovers(),
+            vec![Crossover {
+                size: 256,
+                from: "Scalar".to_string(),
+                to: "AVX2".to_string()
+            }]
+        );
+
+        let md = report.to_markdown();
+        assert!(md.contains("| Tiny (<32) | Scalar | 0.917 | 12.0 | 0 |"), "{}", md);
+        assert!(md.contains("- From 256 inputs on: AVX2 instead of Scalar"), "{}", md);
+        let csv = report.to_csv();
+        assert_eq!(csv.lines().count(), 1 + 5 * 2);
+        assert!(csv.contains("\nMedium,256,AVX2,0,0.916667,12.000,true\n"), "{}", csv);
+    }
+
+    #[test]
+    fn test_csv_round_trips_curated_tables() {
+        let report = BrainReport::from_bandit(&trained());
+        let table = DispatchTable::from_csv(&report.to_csv()).unwrap();
+        assert_eq!(table, report.dispatch_table());
+
+        // A reviewer moves Medium back to Scalar and drops Huge
+        let curated: String = report
+            .to_csv()
+            .lines()
+            .filter(|l| !l.starts_with("Huge"))
+            .map(|l| match l {
+                l if l.starts_with("Medium,256,Scalar") => l.replace("false", "true"),
+                l if l.starts_with("Medium,256,AVX2") => l.replace("true", "false"),
+                l => l.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        let table = DispatchTable::from_csv(&curated).unwrap();
+        assert_eq!(table.winner(SizeBucket::Medium), Some("Scalar"));
+        assert_eq!(table.winner(SizeBucket::Large), Some("AVX2"));
+        assert_eq!(table.winner(SizeBucket::Huge), None);
+
+        assert!(DispatchTable::from_csv("bucket,variant\n").is_err());
+        let twice = format!("{}\nTiny,0,a,0,0.5,2.0,true\nTiny,0,b,0,0.5,2.0,true\n", CSV_HEADER);
+        assert_eq!(
+            DispatchTable::from_csv(&twice).unwrap_err(),
+            "line 3: Tiny has more than one best variant"
+        );
+        let bad = format!("{}\nSmol,0,a,0,0.5,2.0,true\n", CSV_HEADER);
+        assert!(DispatchTable::from_csv(&bad).unwrap_err().contains("unknown size bucket 'Smol'"));
+    }
+}
@@ -0,0 +1,382 @@
+//! Portable Bundles
+//!
+//! `nanoforge bundle file.nf -o kernel.nfb` packs what a deployment needs to
+//! run a script's variants without the compiler: the IR, the machine code of
+//! every variant generated for the build machine, the CPU features each one
+//! needs and the dispatch table a contextual brain learned (`soae-context`).
+//! On the target, `BundleRuntime` maps for each input size the variant the
+//! table picks if this CPU has its features, and otherwise the first one it
+//! can run, mapping each variant once, on first use.
+//!
+//! Compiled code is position-independent except for the absolute addresses
+//! of the runtime stubs it calls (`code_verifier::runtime_stubs`). Bundling
+//! records each one as a relocation by stub name, and mapping patches in
+//! this process's address. The features a variant needs are read off its
+//! decoded instructions, the x86-64 baseline aside, rather than its config:
+//! a scalar variant may still use `popcnt` or `lzcnt` where the build
+//! machine had them.
+//!
+//! A bundle is a `store` document, so a damaged one is refused, and it is
+//! only loaded by the NanoForge version that built it, whose stubs and
+//! stack-guard layout the code was compiled against.
+
+use crate::ai_optimizer::SizeBucket;
+use crate::brain_report::DispatchTable;
+use crate::code_verifier;
+use crate::compiled_program::CompiledProgram;
+use crate::compiler::CompiledCode;
+use crate::cpu_features::CpuFeatures;
+use crate::ir::Program;
+use crate::report::OptimizationReport;
+use crate::store::{self, Schema};
+use crate::variant_generator::{CompiledVariant, VariantConfig, VariantGenerator};
+use iced_x86::{Code, CpuidFeature};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// An absolute address the code needs patched when mapped
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Relocation {
+    /// Offset of the 64-bit immediate in the code
+    pub offset: usize,
+    /// Runtime stub whose address goes there
+    pub symbol: String,
+}
+
+/// One variant's code, ready to map on another machine
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BundledVariant {
+    pub config: VariantConfig,
+    pub code: Vec<u8>,
+    /// Entry offset of every function, by name
+    pub function_offsets: BTreeMap<String, usize>,
+    /// Parameters of every function, by name
+    pub arities: BTreeMap<String, usize>,
+    /// Bytes of `code` that are data, not instructions
+    pub data: Vec<Range<usize>>,
+    pub batch_offset: Option<usize>,
+    /// CPU features beyond x86-64 the code uses, as `CpuFeatures::has`
+    /// names them
+    pub requires: Vec<String>,
+    pub relocations: Vec<Relocation>,
+}
+
+impl BundledVariant {
+    pub fn from_compiled(variant: &CompiledVariant) -> Result<Self, String> {
+        let code = variant.program.code();
+        let stubs: BTreeMap<u64, &str> = code_verifier::runtime_stubs()
+            .into_iter()
+            .map(|(name, address)| (address, name))
+            .collect();
+        let mut requires = BTreeSet::new();
+        let mut relocations = Vec::new();
+        for instr in code_verifier::decode(&code.code, &code.data) {
+            for &feature in instr.cpuid_features() {
+                requires.extend(feature_name(feature)?);
+            }
+            if instr.code() == Code::Mov_r64_imm64 {
+                if let Some(symbol) = stubs.get(&instr.immediate64()) {
+                    relocations.push(Relocation {
+                        offset: instr.next_ip() as usize - 8,
+                        symbol: symbol.to_string(),
+                    });
+                }
+            }
+        }
+        Ok(Self {
+            config: variant.config.clone(),
+            code: code.code.clone(),
+            function_offsets: code.function_offsets.clone(),
+            arities: variant
+                .optimized
+                .functions
+                .iter()
+                .map(|f| (f.name.clone(), f.args.len()))
+                .collect(),
+            data: code.data.clone(),
+            batch_offset: code.batch_offset,
+            requires: requires.into_iter().map(str::to_string).collect(),
+            relocations,
+        })
+    }
+
+    /// Features in `requires` that `features` lacks
+    pub fn missing(&self, features: &CpuFeatures) -> Vec<&str> {
+        let missing = self.requires.iter().filter(|f| !features.has(f));
+        missing.map(String::as_str).collect()
+    }
+
+    pub fn runs_on(&self, features: &CpuFeatures) -> bool {
+        self.missing(features).is_empty()
+    }
+
+    /// Map the code into executable memory, patched for this process
+    pub fn map(&self) -> Result<CompiledProgram, String> {
+        let stubs: BTreeMap<&str, u64> = code_verifier::runtime_stubs().into_iter().collect();
+        let mut bytes = self.code.clone();
+        for relocation in &self.relocations {
+            let address = stubs
+                .get(relocation.symbol.as_str())
+                .ok_or_else(|| format!("unknown runtime stub {}", relocation.symbol))?;
+            bytes
+                .get_mut(relocation.offset..relocation.offset + 8)
+                .ok_or_else(|| format!("relocation at {} is past the code", relocation.offset))?
+                .copy_from_slice(&address.to_le_bytes());
+        }
+        let main_offset = self.function_offsets.get("main").copied().unwrap_or(0);
+        let code = CompiledCode {
+            code: bytes,
+            main_offset,
+            function_offsets: self.function_offsets.clone(),
+            sanitizer_sites: Vec::new(),
+            overflow_sites: Vec::new(),
+            assert_sites: Vec::new(),
+            profiled_functions: Vec::new(),
+            patch_points: Vec::new(),
+            data: self.data.clone(),
+            report: OptimizationReport::default(),
+            source_map: Vec::new(),
+            batch_offset: self.batch_offset,
+        };
+        CompiledProgram::from_code(code, self.arities.clone())
+    }
+}
+
+/// The `CpuFeatures::has` name of `feature`; None for the x86-64 baseline
+fn feature_name(feature: CpuidFeature) -> Result<Option<&'static str>, String> {
+    use CpuidFeature::*;
+    Ok(Some(match feature {
+        INTEL8086 | INTEL186 | INTEL286 | INTEL386 | INTEL486 | X64 | CMOV | CX8 | FPU | SSE
+        | SSE2 | MULTIBYTENOP => return Ok(None),
+        SSE4_1 => "sse4.1",
+        SSE4_2 => "sse4.2",
+        AVX => "avx",
+        AVX2 => "avx2",
+        AVX512F => "avx512f",
+        AVX512VL => "avx512vl",
+        AVX512BW => "avx512bw",
+        AMX_TILE => "amx-tile",
+        AMX_BF16 => "amx-bf16",
+        AMX_INT8 => "amx-int8",
+        POPCNT => "popcnt",
+        BMI1 => "bmi1",
+        LZCNT => "lzcnt",
+        other => return Err(format!("code needs {:?}, which bundles can't check for", other)),
+    }))
+}
+
+/// A script's variants and dispatch table, as saved to a `.nfb` file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Bundle {
+    /// Version of the NanoForge that built it
+    pub nanoforge: String,
+    /// The script's IR, before optimization
+    pub program: Program,
+    /// In the generator's order: scalar first
+    pub variants: Vec<BundledVariant>,
+    pub dispatch: DispatchTable,
+}
+
+impl Bundle {
+    pub const SCHEMA: Schema = Schema::new("bundle", 1);
+
+    /// Bundle the variants `generator` compiles for `program`
+    pub fn build(
+        program: &Program,
+        generator: &VariantGenerator,
+        dispatch: DispatchTable,
+    ) -> Result<Self, String> {
+        let variants = generator.generate_variants(program)?;
+        let bundled = variants.iter().map(BundledVariant::from_compiled).collect::<Result<_, _>>()?;
+        Ok(Self {
+            nanoforge: env!("CARGO_PKG_VERSION").to_string(),
+            program: program.clone(),
+            variants: bundled,
+            dispatch,
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        store::save(path, Self::SCHEMA, self)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let bundle: Self = store::load(path, Self::SCHEMA)?
+            .ok_or_else(|| format!("{}: not found", path.display()))?;
+        let version = env!("CARGO_PKG_VERSION");
+        if bundle.nanoforge != version {
+            return Err(format!(
+                "{} was built by NanoForge {}, this is {}",
+                path.display(),
+                bundle.nanoforge,
+                version
+            ));
+        }
+        Ok(bundle)
+    }
+
+    pub fn variant(&self, name: &str) -> Option<&BundledVariant> {
+        self.variants.iter().find(|v| v.config.name == name)
+    }
+
+    /// The variant to run inputs of `size` with on a CPU with `features`:
+    /// the dispatch table's if it runs there, else the first that does
+    pub fn select(&self, size: u64, features: &CpuFeatures) -> Option<&BundledVariant> {
+        let learned = self.dispatch.winner(SizeBucket::from_size(size));
+        learned
+            .and_then(|name| self.variant(name))
+            .filter(|v| v.runs_on(features))
+            .or_else(|| self.variants.iter().find(|v| v.runs_on(features)))
+    }
+}
+
+/// Runs a bundle's `main(n)`, mapping the variants it selects on demand
+pub struct BundleRuntime {
+    bundle: Bundle,
+    features: CpuFeatures,
+    mapped: Mutex<BTreeMap<String, Arc<CompiledProgram>>>,
+}
+
+impl BundleRuntime {
+    /// A runtime for this CPU; fails if no variant runs on it
+    pub fn new(bundle: Bundle) -> Result<Self, String> {
+        Self::with_features(bundle, CpuFeatures::detect())
+    }
+
+    pub fn with_features(bundle: Bundle, features: CpuFeatures) -> Result<Self, String> {
+        if !bundle.variants.iter().any(|v| v.runs_on(&features)) {
+            let needs: Vec<String> = bundle
+                .variants
+                .iter()
+                .map(|v| format!("{} needs {}", v.config.name, v.missing(&features).join(", ")))
+                .collect();
+            return Err(format!("No variant runs on this CPU: {}", needs.join("; ")));
+        }
+        Ok(Self {
+            bundle,
+            features,
+            mapped: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    pub fn bundle(&self) -> &Bundle {
+        &self.bundle
+    }
+
+    /// Name of the variant inputs of `size` run
+    pub fn variant_for(&self, size: u64) -> &str {
+        // `new` checked at least one variant runs here
+        &self.bundle.select(size, &self.features).unwrap().config.name
+    }
+
+    /// Names of the variants mapped so far
+    pub fn mapped(&self) -> Vec<String> {
+        self.mapped.lock().unwrap().keys().cloned().collect()
+    }
+
+    /// The program inputs of `size` run, mapped on first use
+    pub fn program_for(&self, size: u64) -> Result<Arc<CompiledProgram>, String> {
+        let name = self.variant_for(size);
+        let mut mapped = self.mapped.lock().unwrap();
+        if let Some(program) = mapped.get(name) {
+            return Ok(program.clone());
+        }
+        let variant = self.bundle.variant(name).ok_or("selected variant vanished")?;
+        let program = Arc::new(variant.map()?);
+        mapped.insert(name.to_string(), program.clone());
+        Ok(program)
+    }
+
+    /// `main(n)` on the variant selected for size `n`
+    pub fn call(&self, n: i64) -> Result<i64, String> {
+        let program = self.program_for(n.max(0) as u64)?;
+        let main = program.get_fn("main").ok_or("The bundle has no main()")?;
+        main.call(&[n])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::Interpreter;
+    use crate::parser::Parser;
+
+    const SRC: &str = "fn main(n) {
+        a = alloc(64)
+        a[0] = n
+        s = 0
+        i = 0
+        while i < n {
+            s = s + i
+            i = i + 1
+        }
+        x = a[0]
+        free(a)
+        r = s + x
+        return r
+    }";
+
+    fn bundle(dispatch: DispatchTable) -> Bundle {
+        let program = Parser::new().parse(SRC).unwrap();
+        Bundle::build(&program, &VariantGenerator::new(), dispatch).unwrap()
+    }
+
+    #[test]
+    fn test_round_trip_runs_like_the_interpreter() {
+        let bundle = bundle(DispatchTable::default());
+        let scalar = &bundle.variants[0];
+        let symbols: Vec<&str> = scalar.relocations.iter().map(|r| r.symbol.as_str()).collect();
+        assert!(symbols.contains(&"malloc") && symbols.contains(&"free"), "{:?}", symbols);
+
+        let path = std::env::temp_dir().join(format!("nf-bundle-{}.nfb", std::process::id()));
+        bundle.save(&path).unwrap();
+        let loaded = Bundle::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let code = |b: &Bundle| -> Vec<Vec<u8>> {
+            b.variants.iter().map(|v| v.code.clone()).collect()
+        };
+        assert_eq!(code(&loaded), code(&bundle));
+
+        let runtime = BundleRuntime::new(loaded).unwrap();
+        let program = Parser::new().parse(SRC).unwrap();
+        for n in [0, 1, 10, 1000] {
+            let expected = Interpreter::new(&program).call("main", &[n]).unwrap();
+            assert_eq!(runtime.call(n), Ok(expected), "n = {}", n);
+        }
+        // Without a table everything runs the first variant, mapped once
+        assert_eq!(runtime.mapped(), [bundle.variants[0].config.name.clone()]);
+    }
+
+    #[test]
+    fn test_table_winners_need_their_features() {
+        let names: Vec<String> = bundle(DispatchTable::default())
+            .variants
+            .iter()
+            .map(|v| v.config.name.clone())
+            .collect();
+        let last = names.last().unwrap().clone();
+        let table = DispatchTable {
+            winners: vec![(SizeBucket::Large, last.clone())],
+        };
+        let mut bundle = bundle(table);
+        let runtime = BundleRuntime::new(bundle.clone()).unwrap();
+        assert_eq!(runtime.variant_for(10), names[0]);
+        assert_eq!(runtime.variant_for(5000), last);
+
+        // A CPU without the winner's features falls back
+        bundle.variants.last_mut().unwrap().requires.push("amx-tile".to_string());
+        let plain = CpuFeatures::default();
+        if bundle.variants[0].runs_on(&plain) {
+            let runtime = BundleRuntime::with_features(bundle.clone(), plain).unwrap();
+            assert_eq!(runtime.variant_for(5000), names[0]);
+        }
+        for variant in &mut bundle.variants {
+            variant.requires.push("amx-tile".to_string());
+        }
+        let err = BundleRuntime::with_features(bundle, plain).err().unwrap();
+        assert!(err.starts_with("No variant runs on this CPU"), "{}", err);
+    }
+}
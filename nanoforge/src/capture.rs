@@ -0,0 +1,208 @@
+//! Input Capture and Replay
+//!
+//! A `Reservoir` keeps a uniform sample of the (input, output) pairs a
+//! function has seen, however many calls it serves: the first `capacity`
+//! calls fill it, and the n-th call after that replaces a random slot with
+//! probability capacity / n. `HotFunction` feeds one from its timed calls.
+//!
+//! A sample becomes validator `TestCase`s for evolving against real
+//! workloads, or a `Replay` file the benchmark command can time a script
+//! against (`benchmark --replay`). A replay's `ArgProfile` tells which
+//! inputs were nearly constant, for specializing the function on them
+//! (`benchmark --replay --specialize`).
+
+use crate::specialize::ArgProfile;
+use crate::validator::TestCase;
+use rand::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Samples kept unless set with `Reservoir::new`
+pub const DEFAULT_CAPACITY: usize = 32;
+
+/// One recorded call
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Sample {
+    pub input: u64,
+    pub output: u64,
+}
+
+/// Uniform sample of a stream of calls (reservoir sampling)
+#[derive(Debug, Clone)]
+pub struct Reservoir {
+    capacity: usize,
+    seen: u64,
+    samples: Vec<Sample>,
+    rng: StdRng,
+}
+
+impl Default for Reservoir {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY, 0)
+    }
+}
+
+impl Reservoir {
+    pub fn new(capacity: usize, seed: u64) -> Self {
+        Self {
+            capacity,
+            seen: 0,
+            samples: Vec::with_capacity(capacity),
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    pub fn record(&mut self, sample: Sample) {
+        self.seen += 1;
+        if self.samples.len() < self.capacity {
+            self.samples.push(sample);
+            return;
+        }
+        let slot = self.rng.gen_range(0..self.seen);
+        if let Some(kept) = self.samples.get_mut(slot as usize) {
+            *kept = sample;
+        }
+    }
+
+    /// Calls recorded, including those not kept
+    pub fn seen(&self) -> u64 {
+        self.seen
+    }
+
+    pub fn samples(&self) -> &[Sample] {
+        &self.samples
+    }
+
+    /// The kept samples as test cases, one per distinct input
+    pub fn test_cases(&self) -> Vec<TestCase> {
+        test_cases(&self.samples)
+    }
+
+    /// The kept samples as a replay of `function`
+    pub fn replay(&self, function: &str) -> Replay {
+        Replay {
+            function: function.to_string(),
+            seen: self.seen,
+            samples: self.samples.clone(),
+        }
+    }
+}
+
+/// Recorded calls of one function, as saved to a replay file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Replay {
+    pub function: String,
+    /// Calls the samples were drawn from
+    pub seen: u64,
+    pub samples: Vec<Sample>,
+}
+
+impl Replay {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        serde_json::from_str(&text).map_err(|e| format!("Bad replay file {}: {}", path.display(), e))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let json = serde_json::to_string_pretty(self).map_err(|e| e.to_string())?;
+        std::fs::write(path, json).map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+    }
+
+    /// The samples as test cases, one per distinct input
+    pub fn test_cases(&self) -> Vec<TestCase> {
+        test_cases(&self.samples)
+    }
+
+    /// The recorded inputs as calls with one argument
+    pub fn arg_profile(&self) -> ArgProfile {
+        let mut profile = ArgProfile::new();
+        for sample in &self.samples {
+            profile.record(&[sample.input as i64]);
+        }
+        profile
+    }
+}
+
+fn test_cases(samples: &[Sample]) -> Vec<TestCase> {
+    let mut cases: Vec<TestCase> = samples
+        .iter()
+        .map(|s| TestCase::new(s.input as i64, s.output as i64))
+        .collect();
+    cases.sort_by_key(|c| c.input);
+    cases.dedup_by_key(|c| c.input);
+    cases
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(input: u64) -> Sample {
+        Sample {
+            input,
+            output: input + 1,
+        }
+    }
+
+    #[test]
+    fn test_reservoir_is_uniform() {
+        // Inputs 0..1000 into 10 slots, many times: each input should be kept
+        // about 10 / 1000 of the time
+        let mut kept = vec![0u32; 1000];
+        for seed in 0..2000 {
+            let mut reservoir = Reservoir::new(10, seed);
+            for input in 0..1000 {
+                reservoir.record(sample(input));
+            }
+            assert_eq!(reservoir.seen(), 1000);
+            assert_eq!(reservoir.samples().len(), 10);
+            for s in reservoir.samples() {
+                kept[s.input as usize] += 1;
+            }
+        }
+        // Expected 20 per input; compare the first and last tenth
+        let early: u32 = kept[..100].iter().sum();
+        let late: u32 = kept[900..].iter().sum();
+        for share in [early, late] {
+            assert!((1600..2400).contains(&share), "{} vs {}", early, late);
+        }
+    }
+
+    #[test]
+    fn test_replay_round_trip() {
+        let mut reservoir = Reservoir::new(4, 1);
+        for input in [3, 1, 3, 2] {
+            reservoir.record(sample(input));
+        }
+        let cases: Vec<_> = reservoir
+            .test_cases()
+            .iter()
+            .map(|c| (c.input, c.expected_output))
+            .collect();
+        assert_eq!(cases, [(1, 2), (2, 3), (3, 4)]);
+
+        let path = std::env::temp_dir().join(format!("nf-replay-{}.json", std::process::id()));
+        let replay = reservoir.replay("main");
+        replay.save(&path).unwrap();
+        assert_eq!(Replay::load(&path), Ok(replay));
+        std::fs::remove_file(&path).unwrap();
+        assert!(Replay::load(&path).is_err());
+    }
+
+    #[test]
+    fn test_replay_profiles_nearly_constant_inputs() {
+        let mut reservoir = Reservoir::new(DEFAULT_CAPACITY, 1);
+        for call in 0..100 {
+            reservoir.record(sample(if call % 50 == 7 { 3 } else { 1000 }));
+        }
+        let expected = reservoir.replay("main").arg_profile().expected();
+        assert_eq!(expected.into_iter().collect::<Vec<_>>(), [(0, 1000)]);
+
+        let mut varied = Reservoir::new(DEFAULT_CAPACITY, 1);
+        for input in 0..100 {
+            varied.record(sample(input));
+        }
+        assert!(varied.replay("main").arg_profile().expected().is_empty());
+    }
+}
@@ -0,0 +1,466 @@
+//! Code Verifier
+//!
+//! Defense in depth for services that run untrusted scripts: with
+//! `CompileOptions::verify_code` the compiler decodes its own output before
+//! anything is mapped executable, and fails the compile unless
+//!
+//! - every byte outside the embedded data decodes to a whitelisted
+//!   instruction (no syscalls, interrupts, port I/O or privileged ops)
+//! - only the stack guard's `cmp rsp, fs:[..]` uses a segment override
+//! - every direct jump lands on an instruction of the code, and every
+//!   direct call on a function entry
+//! - every indirect call goes through a register loaded with the address of
+//!   a registered runtime stub (`runtime_stubs`)
+//! - the only indirect jumps are through a jump table, right after the
+//!   bounds check against its length, and every entry lands on an
+//!   instruction
+//!
+//! The IR checks (`pure`, the sanitizer) say what a script may do; this says
+//! what the code generator may emit, so a codegen bug fails the compile
+//! instead of handing the CPU something nobody meant to run.
+
+use crate::alignment;
+use crate::assertion;
+use crate::compiler;
+use crate::function_profiler;
+use crate::overflow;
+use crate::safety;
+use crate::sanitizer;
+use crate::stack_guard;
+use crate::status;
+use iced_x86::{
+    Code, Decoder, DecoderOptions, FlowControl, Formatter, Instruction, InstructionInfoFactory,
+    IntelFormatter, Mnemonic, OpAccess, OpKind, Register,
+};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::Range;
+
+/// Everything the code generator emits
+const ALLOWED: &[Mnemonic] = &[
+    // Scalar
+    Mnemonic::Add,
+    Mnemonic::Sub,
+    Mnemonic::Imul,
+    Mnemonic::Neg,
+    Mnemonic::Xor,
+    Mnemonic::And,
+    Mnemonic::Test,
+    Mnemonic::Cmp,
+    Mnemonic::Dec,
+    Mnemonic::Lea,
+    Mnemonic::Mov,
+    Mnemonic::Movsxd,
+    Mnemonic::Cmove,
+    Mnemonic::Cmovne,
+    Mnemonic::Cmovl,
+    Mnemonic::Cmovle,
+    Mnemonic::Cmovg,
+    Mnemonic::Cmovge,
+    Mnemonic::Popcnt,
+    Mnemonic::Tzcnt,
+    Mnemonic::Lzcnt,
+    Mnemonic::Bsf,
+    Mnemonic::Bsr,
+    Mnemonic::Push,
+    Mnemonic::Pop,
+    Mnemonic::Nop,
+    Mnemonic::Prefetcht0,
+    Mnemonic::Sfence,
+    // Control flow
+    Mnemonic::Jmp,
+    Mnemonic::Je,
+    Mnemonic::Jne,
+    Mnemonic::Jl,
+    Mnemonic::Jle,
+    Mnemonic::Jg,
+    Mnemonic::Jge,
+    Mnemonic::Jb,
+    Mnemonic::Jae,
+    Mnemonic::Jo,
+    Mnemonic::Call,
+    Mnemonic::Ret,
+    // Vector
+    Mnemonic::Vmovdqu,
+    Mnemonic::Vmovdqa,
+    Mnemonic::Vmovdqu64,
+    Mnemonic::Vmovdqa64,
+    Mnemonic::Vmovntdq,
+    Mnemonic::Vpaddq,
+    Mnemonic::Vpsubq,
+    Mnemonic::Vpxor,
+    Mnemonic::Vpxorq,
+    Mnemonic::Vpminsq,
+    Mnemonic::Vpmaxsq,
+    Mnemonic::Vpabsq,
+    Mnemonic::Vpcmpgtq,
+    Mnemonic::Vblendvpd,
+    Mnemonic::Vzeroupper,
+];
+
+/// Host functions compiled code may call, with their addresses
+pub fn runtime_stubs() -> Vec<(&'static str, u64)> {
+    vec![
+        ("nf_trap", status::nf_trap as *const () as usize as u64),
+        ("nf_overflow", overflow::nf_overflow as *const () as usize as u64),
+        ("nf_assert_failed", assertion::nf_assert_failed as *const () as usize as u64),
+        ("nf_san_check", sanitizer::nf_san_check as *const () as usize as u64),
+        ("nf_pure_check", sanitizer::nf_pure_check as *const () as usize as u64),
+        ("nf_san_alloc", sanitizer::nf_san_alloc as *const () as usize as u64),
+        ("nf_san_free", sanitizer::nf_san_free as *const () as usize as u64),
+        ("nf_prof_enter", function_profiler::nf_prof_enter as *const () as usize as u64),
+        ("nf_prof_exit", function_profiler::nf_prof_exit as *const () as usize as u64),
+        ("nf_alloc_aligned", alignment::nf_alloc_aligned as *const () as usize as u64),
+        ("nf_popcount", compiler::nf_popcount as *const () as usize as u64),
+        ("nf_frame_corrupted", safety::nf_frame_corrupted as *const () as usize as u64),
+        ("malloc", libc::malloc as *const () as usize as u64),
+        ("free", libc::free as *const () as usize as u64),
+    ]
+}
+
+/// Check `code`, whose functions start at `entries` and which embeds
+/// non-executable bytes at `data`, among them the jump tables at `tables`
+/// (32-bit offsets, each relative to its own entry); the error lists every
+/// offending instruction
+pub fn verify(
+    code: &[u8],
+    entries: &BTreeMap<String, usize>,
+    data: &[Range<usize>],
+    tables: &[Range<usize>],
+) -> Result<(), String> {
+    let instructions = decode(code, data);
+    let starts: HashSet<u64> = instructions.iter().map(Instruction::ip).collect();
+    let functions: HashSet<u64> = entries.values().map(|&o| o as u64).collect();
+    // (entry, target) of every jump table entry
+    let entries_to: Vec<(usize, u64)> = tables
+        .iter()
+        .flat_map(|table| table.clone().step_by(4))
+        .filter_map(|at| {
+            let offset = i32::from_le_bytes(code.get(at..at + 4)?.try_into().ok()?);
+            Some((at, (at as i64 + offset as i64) as u64))
+        })
+        .collect();
+    let targets: HashSet<u64> = instructions
+        .iter()
+        .filter(|i| is_direct_branch(i))
+        .map(Instruction::near_branch_target)
+        .chain(entries_to.iter().map(|&(_, target)| target))
+        .collect();
+    let stubs: HashMap<u64, &str> = runtime_stubs().into_iter().map(|(name, addr)| (addr, name)).collect();
+
+    let mut formatter = IntelFormatter::new();
+    let mut info = InstructionInfoFactory::new();
+    // 64-bit immediates each register holds on every path to here
+    let mut loaded: HashMap<Register, u64> = HashMap::new();
+    let mut problems: Vec<String> = entries_to
+        .iter()
+        .filter(|(_, target)| !starts.contains(target))
+        .map(|(at, target)| format!("{:#06x}: jump table entry to {:#06x} (jumps outside the code)", at, target))
+        .collect();
+    for (idx, instr) in instructions.iter().enumerate() {
+        if targets.contains(&instr.ip()) {
+            loaded.clear();
+        }
+        let problem = if instr.code() == Code::INVALID {
+            Some("not a valid instruction".to_string())
+        } else if !ALLOWED.contains(&instr.mnemonic()) {
+            Some("not whitelisted".to_string())
+        } else if instr.segment_prefix() != Register::None && !is_stack_guard(instr) {
+            Some("segment override".to_string())
+        } else {
+            match instr.flow_control() {
+                FlowControl::UnconditionalBranch | FlowControl::ConditionalBranch
+                    if !is_direct_branch(instr) || !starts.contains(&instr.near_branch_target()) =>
+                {
+                    Some("jumps outside the code".to_string())
+                }
+                FlowControl::IndirectBranch if !is_table_jump(&instructions, idx, tables, &targets) => {
+                    Some("indirect jump".to_string())
+                }
+                FlowControl::Call if !functions.contains(&instr.near_branch_target()) => {
+                    Some("calls something other than a function entry".to_string())
+                }
+                FlowControl::IndirectCall => {
+                    let target = (instr.op0_kind() == OpKind::Register)
+                        .then(|| loaded.get(&instr.op0_register().full_register()))
+                        .flatten();
+                    match target.and_then(|addr| stubs.get(addr)) {
+                        Some(_) => None,
+                        None => Some("calls something other than a runtime stub".to_string()),
+                    }
+                }
+                _ => None,
+            }
+        };
+        if let Some(problem) = problem {
+            let mut text = String::new();
+            formatter.format(instr, &mut text);
+            problems.push(format!("{:#06x}: {} ({})", instr.ip(), text, problem));
+        }
+
+        // Callees clobber whatever they like
+        if matches!(instr.flow_control(), FlowControl::Call | FlowControl::IndirectCall) {
+            loaded.clear();
+        } else if instr.code() == Code::Mov_r64_imm64 {
+            loaded.insert(instr.op0_register(), instr.immediate64());
+        } else {
+            for used in info.info(instr).used_registers() {
+                if !matches!(used.access(), OpAccess::Read | OpAccess::CondRead | OpAccess::NoMemAccess) {
+                    loaded.remove(&used.register().full_register());
+                }
+            }
+        }
+    }
+
+    if problems.is_empty() {
+        return Ok(());
+    }
+    Err(format!(
+        "code verifier: {} instruction(s) rejected:\n  {}",
+        problems.len(),
+        problems.join("\n  ")
+    ))
+}
+
+/// Every instruction of `code` outside `data`, in address order
+pub fn decode(code: &[u8], data: &[Range<usize>]) -> Vec<Instruction> {
+    let mut skip: Vec<Range<usize>> = data.to_vec();
+    skip.sort_by_key(|r| r.start);
+    // Decode up to the end
+    skip.push(code.len()..code.len());
+    let mut instructions = Vec::new();
+    let mut start = 0;
+    for range in skip {
+        let end = range.start.clamp(start, code.len());
+        let mut decoder = Decoder::with_ip(64, &code[start..end], start as u64, DecoderOptions::NONE);
+        while decoder.can_decode() {
+            instructions.push(decoder.decode());
+        }
+        start = range.end.clamp(start, code.len());
+    }
+    instructions
+}
+
+/// Whether the indirect jump at `instructions[at]` ends the sequence
+/// `JitBuilder::jmp_table` emits after its bounds check, with nothing
+/// jumping into the middle:
+///
+/// ```text
+/// cmp i, n; jae default; lea t, [rip + table]; lea t, [t + i*4]
+/// movsxd i, dword [t]; add t, i; jmp t
+/// ```
+///
+/// where `table` starts one of `tables` and holds n entries
+fn is_table_jump(
+    instructions: &[Instruction],
+    at: usize,
+    tables: &[Range<usize>],
+    targets: &HashSet<u64>,
+) -> bool {
+    let Some(start) = at.checked_sub(6) else {
+        return false;
+    };
+    let [cmp, jae, lea_table, lea_entry, load, add, jmp] = &instructions[start..=at] else {
+        return false;
+    };
+    let (i, t) = (load.op0_register(), jmp.op0_register());
+    let table = tables.iter().find(|table| {
+        lea_table.is_ip_rel_memory_operand() && lea_table.ip_rel_memory_address() == table.start as u64
+    });
+    let plain = |instr: &Instruction, base: Register, index: Register, scale: u32| {
+        instr.op1_kind() == OpKind::Memory
+            && instr.memory_base() == base
+            && instr.memory_index() == index
+            && instr.memory_index_scale() == scale
+            && instr.memory_displacement64() == 0
+    };
+    table.is_some_and(|table| {
+        cmp.mnemonic() == Mnemonic::Cmp
+            && cmp.op0_register() == i
+            && matches!(cmp.op1_kind(), OpKind::Immediate8to64 | OpKind::Immediate32to64)
+            && cmp.immediate(1) == (table.len() / 4) as u64
+    }) && jae.mnemonic() == Mnemonic::Jae
+        && lea_table.mnemonic() == Mnemonic::Lea
+        && lea_table.op0_register() == t
+        && lea_entry.mnemonic() == Mnemonic::Lea
+        && lea_entry.op0_register() == t
+        && plain(lea_entry, t, i, 4)
+        && load.mnemonic() == Mnemonic::Movsxd
+        && plain(load, t, Register::None, 1)
+        && add.mnemonic() == Mnemonic::Add
+        && add.op0_register() == t
+        && add.op1_register() == i
+        && jmp.op0_kind() == OpKind::Register
+        && i.size() == 8
+        && t.size() == 8
+        && i != t
+        && instructions[start + 1..=at].iter().all(|instr| !targets.contains(&instr.ip()))
+}
+
+fn is_direct_branch(instr: &Instruction) -> bool {
+    instr.op0_kind() == OpKind::NearBranch64
+}
+
+/// `cmp rsp, fs:[LIMIT_OFFSET]`
+fn is_stack_guard(instr: &Instruction) -> bool {
+    instr.mnemonic() == Mnemonic::Cmp
+        && instr.op0_register() == Register::RSP
+        && instr.op1_kind() == OpKind::Memory
+        && instr.segment_prefix() == Register::FS
+        && instr.memory_base() == Register::None
+        && instr.memory_index() == Register::None
+        && instr.memory_displacement64() == stack_guard::LIMIT_OFFSET as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::{CompileOptions, Compiler};
+    use crate::cpu_features::{CpuFeatures, VectorWidth};
+    use crate::overflow::OverflowMode;
+    use crate::parser::Parser;
+
+    const CORPUS: [&str; 7] = [
+        include_str!("../fib.nf"),
+        include_str!("../recursion.nf"),
+        include_str!("../tests/codegen/bits.nf"),
+        include_str!("../tests/codegen/minmax.nf"),
+        include_str!("../tests/codegen/mat_add.nf"),
+        include_str!("../tests/codegen/switch.nf"),
+        include_str!("../kernels/vec_add.nf"),
+    ];
+
+    fn mov_rax(addr: u64) -> Vec<u8> {
+        let mut bytes = vec![0x48, 0xb8];
+        bytes.extend(addr.to_le_bytes());
+        bytes
+    }
+
+    fn rejected(code: &[u8]) -> String {
+        let entries = BTreeMap::from([("main".to_string(), 0)]);
+        verify(code, &entries, &[], &[]).unwrap_err()
+    }
+
+    #[test]
+    fn test_everything_the_compiler_emits_passes() {
+        let all = CpuFeatures {
+            has_avx: true,
+            has_avx2: true,
+            has_avx512f: true,
+            has_avx512vl: true,
+            has_popcnt: true,
+            has_bmi1: true,
+            has_lzcnt: true,
+            ..CpuFeatures::default()
+        };
+        for source in CORPUS {
+            let prog = Parser::new().parse(source).unwrap();
+            for level in 0..=3 {
+                for features in [all, CpuFeatures::default()] {
+                    let base = CompileOptions::new(level).cpu_features(features).verify_code(true);
+                    let variants = [
+                        base,
+                        base.sanitize(true).debug(true).profile(true),
+                        base.overflow(OverflowMode::Checked),
+                        base.software_pipeline(true).prefetch_distance(256),
+                    ];
+                    for options in variants {
+                        Compiler::compile_with_options(&prog, &options).unwrap();
+                    }
+                }
+                let wide = CompileOptions::new(level)
+                    .cpu_features(all)
+                    .vector_width(VectorWidth::Lanes8)
+                    .verify_code(true);
+                Compiler::compile_with_options(&prog, &wide).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_rejects_what_the_compiler_never_emits() {
+        // syscall; ret
+        let err = rejected(&[0x0f, 0x05, 0xc3]);
+        assert!(err.contains("0x0000: syscall (not whitelisted)"), "{}", err);
+        // mov rax, fs:[0x28]; ret
+        let err = rejected(&[0x64, 0x48, 0x8b, 0x04, 0x25, 0x28, 0, 0, 0, 0xc3]);
+        assert!(err.contains("(segment override)"), "{}", err);
+        // jmp into the middle of the jmp itself
+        let err = rejected(&[0xeb, 0xff]);
+        assert!(err.contains("(jumps outside the code)"), "{}", err);
+        // jmp rax
+        let err = rejected(&[0xff, 0xe0]);
+        assert!(err.contains("(indirect jump)"), "{}", err);
+        // Bytes that don't decode
+        let err = rejected(&[0x06]);
+        assert!(err.contains("(not a valid instruction)"), "{}", err);
+    }
+
+    #[test]
+    fn test_indirect_calls_need_a_stub_in_the_register() {
+        let stub = runtime_stubs()[0].1;
+        let call_rax = [0xff, 0xd0];
+        let ok = [mov_rax(stub), call_rax.to_vec(), vec![0xc3]].concat();
+        let entries = BTreeMap::from([("main".to_string(), 0)]);
+        assert_eq!(verify(&ok, &entries, &[], &[]), Ok(()));
+
+        let err = rejected(&[mov_rax(0x1234_5678), call_rax.to_vec()].concat());
+        assert!(err.contains("call rax (calls something other than a runtime stub)"), "{}", err);
+        // add rax, 8 in between: rax no longer holds the stub
+        let err = rejected(&[mov_rax(stub), vec![0x48, 0x83, 0xc0, 0x08], call_rax.to_vec()].concat());
+        assert!(err.contains("runtime stub"), "{}", err);
+        // A second call through rax: the first callee may have changed it
+        let err = rejected(&[mov_rax(stub), call_rax.to_vec(), call_rax.to_vec()].concat());
+        assert_eq!(err.matches("runtime stub").count(), 1, "{}", err);
+    }
+
+    #[test]
+    fn test_jump_tables_need_their_bounds_check_and_entries_on_instructions() {
+        let prog = Parser::new().parse(CORPUS[5]).unwrap();
+        let options = CompileOptions::new(0).verify_code(true);
+        let compiled = Compiler::compile_with_options(&prog, &options).unwrap();
+        let (code, entries, tables) = (&compiled.code, &compiled.function_offsets, &compiled.data);
+        assert_eq!(tables.len(), 1);
+        assert_eq!(verify(code, entries, tables, tables), Ok(()));
+
+        // Not declared as a table: an indirect jump like any other
+        let err = verify(code, entries, tables, &[]).unwrap_err();
+        assert!(err.contains("(indirect jump)"), "{}", err);
+
+        // An entry one byte further lands inside an instruction
+        let mut bad = code.clone();
+        let at = tables[0].start;
+        let entry = i32::from_le_bytes(bad[at..at + 4].try_into().unwrap()) + 1;
+        bad[at..at + 4].copy_from_slice(&entry.to_le_bytes());
+        let err = verify(&bad, entries, tables, tables).unwrap_err();
+        assert!(err.contains(&format!("{:#06x}: jump table entry", at)), "{}", err);
+
+        // The bound the index is checked against must be the table's length
+        let n = (tables[0].len() / 4) as u64;
+        let cmp = decode(code, tables)
+            .into_iter()
+            .find(|i| {
+                i.mnemonic() == Mnemonic::Cmp
+                    && matches!(i.op1_kind(), OpKind::Immediate8to64 | OpKind::Immediate32to64)
+                    && i.immediate(1) == n
+            })
+            .expect("cmp i, n");
+        let imm_size = if cmp.op1_kind() == OpKind::Immediate8to64 { 1 } else { 4 };
+        let mut bad = code.clone();
+        bad[cmp.ip() as usize + cmp.len() - imm_size] += 1;
+        let err = verify(&bad, entries, tables, tables).unwrap_err();
+        assert!(err.contains("(indirect jump)"), "{}", err);
+    }
+
+    #[test]
+    fn test_data_is_skipped_but_never_a_target() {
+        // ret; "in al, dx" as data; ret
+        let code = [0xc3, 0xec, 0xc3];
+        let entries = BTreeMap::from([("main".to_string(), 0)]);
+        assert_eq!(verify(&code, &entries, &[Range { start: 1, end: 2 }], &[]), Ok(()));
+        assert!(verify(&code, &entries, &[], &[]).is_err());
+        // jmp +0 lands on the data byte
+        let code = [0xeb, 0x00, 0xec, 0xc3];
+        let err = verify(&code, &entries, &[Range { start: 2, end: 3 }], &[]).unwrap_err();
+        assert!(err.contains("jumps outside the code"), "{}", err);
+    }
+}
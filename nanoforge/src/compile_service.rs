@@ -0,0 +1,259 @@
+//! Compile Service
+//!
+//! Bounded compile queue backed by a worker pool. Every submission gets a
+//! request id that can be cancelled; editors and the daemon use this to drop
+//! stale compiles when the source changes faster than it compiles.
+//!
+//! Cancellation is cooperative: workers check the flag before parsing, before
+//! code generation, and before delivering the result.
+
+use crate::compiler::Compiler;
+use crate::parser::Parser;
+use crossbeam::channel::{self, Receiver, Sender, TrySendError};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+/// Identifier assigned to each submitted compile
+pub type RequestId = u64;
+
+/// Outcome of a compile request
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompileOutcome {
+    /// Machine code and the offset of `main`
+    Done { code: Vec<u8>, main_offset: usize },
+    /// Parse or compile error
+    Failed(String),
+    /// Cancelled before it finished
+    Cancelled,
+}
+
+struct Job {
+    id: RequestId,
+    source: String,
+    opt_level: u8,
+    cancelled: Arc<AtomicBool>,
+    reply: Sender<CompileOutcome>,
+}
+
+/// Handle to a queued compile
+pub struct CompileTicket {
+    pub id: RequestId,
+    result: Receiver<CompileOutcome>,
+}
+
+impl CompileTicket {
+    /// Block until the request completes (or is cancelled)
+    pub fn wait(&self) -> CompileOutcome {
+        self.result.recv().unwrap_or(CompileOutcome::Cancelled)
+    }
+
+    /// Non-blocking poll
+    pub fn try_result(&self) -> Option<CompileOutcome> {
+        self.result.try_recv().ok()
+    }
+}
+
+/// Worker pool that compiles NanoForge sources off the caller's thread
+pub struct CompileService {
+    queue: Option<Sender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+    in_flight: Arc<Mutex<HashMap<RequestId, Arc<AtomicBool>>>>,
+    next_id: AtomicU64,
+}
+
+impl CompileService {
+    /// Start `workers` threads sharing a queue of at most `capacity` pending requests
+    pub fn new(workers: usize, capacity: usize) -> Self {
+        let (tx, rx) = channel::bounded::<Job>(capacity);
+        let in_flight = Arc::new(Mutex::new(HashMap::new()));
+
+        let workers = (0..workers.max(1))
+            .map(|i| {
+                let rx = rx.clone();
+                let in_flight = Arc::clone(&in_flight);
+                thread::Builder::new()
+                    .name(format!("nf-compile-{}", i))
+                    .spawn(move || worker_loop(rx, in_flight))
+                    .expect("Failed to spawn compile worker")
+            })
+            .collect();
+
+        Self {
+            queue: Some(tx),
+            workers,
+            in_flight,
+            next_id: AtomicU64::new(1),
+        }
+    }
+
+    /// Queue a compile. Fails immediately if the queue is full.
+    pub fn submit(&self, source: &str, opt_level: u8) -> Result<CompileTicket, String> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let (reply, result) = channel::bounded(1);
+
+        self.in_flight
+            .lock()
+            .unwrap()
+            .insert(id, Arc::clone(&cancelled));
+
+        let job = Job {
+            id,
+            source: source.to_string(),
+            opt_level,
+            cancelled,
+            reply,
+        };
+
+        let queue = self.queue.as_ref().ok_or("Compile service is shut down")?;
+        match queue.try_send(job) {
+            Ok(()) => Ok(CompileTicket { id, result }),
+            Err(e) => {
+                self.in_flight.lock().unwrap().remove(&id);
+                Err(match e {
+                    TrySendError::Full(_) => "Compile queue is full".to_string(),
+                    TrySendError::Disconnected(_) => "Compile service is shut down".to_string(),
+                })
+            }
+        }
+    }
+
+    /// Cancel a request. Returns false if it already finished (or never existed).
+    pub fn cancel(&self, id: RequestId) -> bool {
+        match self.in_flight.lock().unwrap().get(&id) {
+            Some(flag) => {
+                flag.store(true, Ordering::Release);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Cancel every queued or running request; returns how many were cancelled
+    pub fn cancel_all(&self) -> usize {
+        let in_flight = self.in_flight.lock().unwrap();
+        for flag in in_flight.values() {
+            flag.store(true, Ordering::Release);
+        }
+        in_flight.len()
+    }
+
+    /// Number of requests queued or being compiled
+    pub fn pending(&self) -> usize {
+        self.in_flight.lock().unwrap().len()
+    }
+}
+
+impl Drop for CompileService {
+    fn drop(&mut self) {
+        // Closing the queue lets workers drain what's left and exit
+        self.queue.take();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn worker_loop(rx: Receiver<Job>, in_flight: Arc<Mutex<HashMap<RequestId, Arc<AtomicBool>>>>) {
+    for job in rx {
+        let outcome = compile_job(&job);
+        in_flight.lock().unwrap().remove(&job.id);
+        // The ticket may have been dropped; nobody is waiting then
+        let _ = job.reply.send(outcome);
+    }
+}
+
+fn compile_job(job: &Job) -> CompileOutcome {
+    let is_cancelled = || job.cancelled.load(Ordering::Acquire);
+
+    if is_cancelled() {
+        return CompileOutcome::Cancelled;
+    }
+
+    let program = match Parser::new().parse(&job.source) {
+        Ok(p) => p,
+        Err(e) => return CompileOutcome::Failed(format!("Parse error: {}", e)),
+    };
+
+    if is_cancelled() {
+        return CompileOutcome::Cancelled;
+    }
+
+    // Mutated or malformed IR can panic inside the assembler; keep the worker alive
+    let compiled = std::panic::catch_unwind(|| Compiler::compile_program(&program, job.opt_level));
+
+    if is_cancelled() {
+        return CompileOutcome::Cancelled;
+    }
+
+    match compiled {
+        Ok(Ok((code, main_offset))) => CompileOutcome::Done { code, main_offset },
+        Ok(Err(e)) => CompileOutcome::Failed(e),
+        Err(_) => CompileOutcome::Failed("Compiler panicked".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SRC: &str = "fn main() {\n x = 40\n y = x + 2\n return y\n}";
+
+    #[test]
+    fn test_compile_and_errors() {
+        let service = CompileService::new(2, 8);
+
+        let ok = service.submit(SRC, 2).unwrap();
+        let bad = service.submit("fn main( {", 2).unwrap();
+        assert_ne!(ok.id, bad.id);
+
+        assert!(matches!(ok.wait(), CompileOutcome::Done { ref code, .. } if !code.is_empty()));
+        assert!(matches!(bad.wait(), CompileOutcome::Failed(_)));
+        assert_eq!(service.pending(), 0);
+    }
+
+    #[test]
+    fn test_cancelled_job_is_not_compiled() {
+        let (reply, _result) = channel::bounded(1);
+        let job = Job {
+            id: 1,
+            source: SRC.to_string(),
+            opt_level: 2,
+            cancelled: Arc::new(AtomicBool::new(true)),
+            reply,
+        };
+        assert_eq!(compile_job(&job), CompileOutcome::Cancelled);
+    }
+
+    #[test]
+    fn test_cancel_in_flight() {
+        let service = CompileService::new(1, 16);
+        let tickets: Vec<_> = (0..8).map(|_| service.submit(SRC, 2).unwrap()).collect();
+        service.cancel_all();
+
+        // Each request either finished before the cancel landed or was dropped
+        for t in &tickets {
+            assert!(matches!(
+                t.wait(),
+                CompileOutcome::Done { .. } | CompileOutcome::Cancelled
+            ));
+            assert!(
+                !service.cancel(t.id),
+                "finished requests can't be cancelled"
+            );
+        }
+        assert_eq!(service.pending(), 0);
+    }
+
+    #[test]
+    fn test_queue_full() {
+        let service = CompileService::new(1, 1);
+        let results: Vec<_> = (0..64).map(|_| service.submit(SRC, 2)).collect();
+        assert!(results.iter().any(|r| r.is_err()));
+        for ticket in results.into_iter().flatten() {
+            ticket.wait();
+        }
+    }
+}
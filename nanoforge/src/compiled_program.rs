@@ -0,0 +1,467 @@
+//! Compiled Programs
+//!
+//! `CompiledProgram` owns the executable memory for a whole program along with
+//! where each function starts, so any function can be called by name, not
+//! just `main`: dynamically through `get_fn`, or as a typed function pointer
+//! through `get`, without callers touching raw memory or transmutes.
+
+use crate::assertion;
+use crate::compiler::{self, CompileOptions, CompiledCode, Compiler};
+use crate::host_args::{self, MAX_ARGS};
+use crate::ir::Program;
+use crate::jit_memory::DualMappedMemory;
+use crate::overflow;
+use crate::status::{self, Status};
+use crate::unwind::UnwindInfo;
+use std::collections::BTreeMap;
+
+/// Function pointer types `CompiledProgram::get` can hand out:
+/// `extern "C" fn(..) -> i64` (or `u64`) with up to `MAX_ARGS` parameters
+///
+/// # Safety
+/// Implementors must be `extern "C"` function pointers taking `ARITY`
+/// integer parameters and returning an integer.
+pub unsafe trait JitFn: Copy {
+    const ARITY: usize;
+
+    /// # Safety
+    /// `entry` must be the start of a NanoForge-compiled function.
+    unsafe fn from_entry(entry: *const u8) -> Self;
+}
+
+macro_rules! jit_fn {
+    ($t:ty; $($arg:ident),*) => {
+        unsafe impl JitFn for extern "C" fn($($arg: $t),*) -> $t {
+            const ARITY: usize = {
+                let params: &[&str] = &[$(stringify!($arg)),*];
+                params.len()
+            };
+
+            unsafe fn from_entry(entry: *const u8) -> Self {
+                std::mem::transmute(entry)
+            }
+        }
+    };
+}
+
+jit_fn!(i64;);
+jit_fn!(i64; a);
+jit_fn!(i64; a, b);
+jit_fn!(i64; a, b, c);
+jit_fn!(i64; a, b, c, d);
+jit_fn!(u64;);
+jit_fn!(u64; a);
+jit_fn!(u64; a, b);
+jit_fn!(u64; a, b, c);
+jit_fn!(u64; a, b, c, d);
+
+/// A program compiled into executable memory
+#[derive(Debug)]
+pub struct CompiledProgram {
+    /// Unregistered before `memory` is unmapped
+    _unwind: Option<UnwindInfo>,
+    memory: DualMappedMemory,
+    code: CompiledCode,
+    arities: BTreeMap<String, usize>,
+}
+
+impl CompiledProgram {
+    pub fn compile(prog: &Program, options: &CompileOptions) -> Result<Self, String> {
+        let code = Compiler::compile_with_options(prog, options)?;
+        let arities = prog
+            .functions
+            .iter()
+            .map(|f| (f.name.clone(), f.args.len()))
+            .collect();
+        Self::from_code(code, arities)
+    }
+
+    /// Map code compiled elsewhere (e.g. loaded from a `bundle`), whose
+    /// functions take `arities` parameters
+    pub fn from_code(code: CompiledCode, arities: BTreeMap<String, usize>) -> Result<Self, String> {
+        let memory = DualMappedMemory::with_code(&code.code)?;
+        let unwind = UnwindInfo::register(&code.code, memory.rx_ptr, &code.function_offsets);
+        Ok(Self {
+            _unwind: unwind,
+            memory,
+            code,
+            arities,
+        })
+    }
+
+    /// Machine code and metadata (sanitizer sites, profiled functions, ...)
+    pub fn code(&self) -> &CompiledCode {
+        &self.code
+    }
+
+    /// Where offset 0 of the code is mapped for execution, to map sampled
+    /// instruction pointers back to it
+    pub fn address(&self) -> usize {
+        self.memory.rx_ptr as usize
+    }
+
+    /// Size of the executable region holding the code
+    pub fn mapped_bytes(&self) -> usize {
+        self.memory.size
+    }
+
+    /// Rewrite every 32-bit immediate recorded as patch point `name` in place, e.g.
+    /// to retune a threshold without recompiling. Safe while the code runs:
+    /// each call sees either the old or the new value.
+    pub fn patch(&self, name: &str, value: i32) -> Result<(), String> {
+        let mut found = false;
+        let points = self.code.patch_points.iter();
+        for point in points.filter(|p| p.name == name && p.width == 4) {
+            self.memory.patch_i32(point.offset, value)?;
+            found = true;
+        }
+        if !found {
+            return Err(format!("No patch point named {}", name));
+        }
+        Ok(())
+    }
+
+    /// Current value of patch point `name`
+    pub fn patched_value(&self, name: &str) -> Option<i32> {
+        let point = self
+            .code
+            .patch_points
+            .iter()
+            .find(|p| p.name == name && p.width == 4)?;
+        self.memory.read_i32(point.offset)
+    }
+
+    /// Loop back-edges `function` may take per call before bailing out with
+    /// `Status::FuelExhausted`. Loops proven to stop within the budget it was
+    /// compiled with (`CompileOptions::fuel`) have no fuel check, so a lower
+    /// budget doesn't reach them; compile with it instead to enforce one.
+    pub fn set_fuel(&self, function: &str, fuel: i32) -> Result<(), String> {
+        self.patch(&compiler::fuel_patch_point(function), fuel)
+    }
+
+    pub fn function_names(&self) -> impl Iterator<Item = &str> {
+        self.arities.keys().map(String::as_str)
+    }
+
+    /// `name` as a typed function pointer, or None if the program has no such
+    /// function or it takes more parameters than `F` passes. (Extra
+    /// parameters in `F` are harmless: the callee never reads them.)
+    ///
+    /// The pointer is only valid while this `CompiledProgram` is alive.
+    pub fn get<F: JitFn>(&self, name: &str) -> Option<F> {
+        let f = self.get_fn(name)?;
+        (f.arity <= F::ARITY).then(|| unsafe { F::from_entry(f.entry) })
+    }
+
+    /// The function called `name`, if the program defines it
+    pub fn get_fn(&self, name: &str) -> Option<NanoFn<'_>> {
+        let (name, &arity) = self.arities.get_key_value(name)?;
+        let offset = *self.code.function_offsets.get(name)?;
+        Some(NanoFn {
+            name,
+            arity,
+            entry: unsafe { self.memory.rx_ptr.add(offset) },
+            program: self,
+        })
+    }
+}
+
+/// A function of a `CompiledProgram`, callable with exactly its declared
+/// number of integer arguments
+#[derive(Clone, Copy)]
+pub struct NanoFn<'a> {
+    name: &'a str,
+    arity: usize,
+    entry: *const u8,
+    /// Keeps the code mapped while this handle exists
+    program: &'a CompiledProgram,
+}
+
+impl NanoFn<'_> {
+    pub fn name(&self) -> &str {
+        self.name
+    }
+
+    pub fn arity(&self) -> usize {
+        self.arity
+    }
+
+    /// Call with `args`; a call that didn't return normally (see `status`)
+    /// is an error rather than a result
+    pub fn call(&self, args: &[i64]) -> Result<i64, String> {
+        let (result, status) = self.call_with_status(args)?;
+        self.check(status).map(|()| result)
+    }
+
+    /// Set `outputs[i]` to this function called on `inputs[i]`, from one
+    /// loop in JIT code (the compiler's batch stub) rather than a host call
+    /// per input. A call that didn't return normally fails the batch.
+    pub fn call_batch(&self, inputs: &[i64], outputs: &mut [i64]) -> Result<(), String> {
+        if self.arity > 1 {
+            return Err(format!(
+                "{}() takes {} arguments; a batch passes one",
+                self.name, self.arity
+            ));
+        }
+        if inputs.len() != outputs.len() {
+            return Err(format!(
+                "Batch of {} inputs needs as many outputs, got {}",
+                inputs.len(),
+                outputs.len()
+            ));
+        }
+        let Some(offset) = self.program.code.batch_offset else {
+            // No stub (`verify_code`): one host call per input
+            for (input, output) in inputs.iter().zip(outputs.iter_mut()) {
+                *output = self.call(&[*input][..self.arity])?;
+            }
+            return Ok(());
+        };
+        type Batch = extern "C" fn(*const u8, *const i64, *mut i64, u64) -> u64;
+        let batch: Batch = unsafe { std::mem::transmute(self.program.memory.rx_ptr.add(offset)) };
+        status::reset();
+        batch(self.entry, inputs.as_ptr(), outputs.as_mut_ptr(), inputs.len() as u64);
+        self.check(status::take())
+    }
+
+    /// A call that ended with `status` as a result: an error unless it
+    /// returned normally
+    fn check(&self, status: Status) -> Result<(), String> {
+        let site = overflow::take_site();
+        let failure = assertion::take_failure();
+        match status {
+            Status::Ok => Ok(()),
+            Status::AssertFailed => {
+                let sites = &self.program.code.assert_sites;
+                match failure.and_then(|f| sites.get(f.site).map(|site| (site, f))) {
+                    Some((site, f)) => Err(format!("{}(): {}", self.name, site.failure(f.lhs, f.rhs))),
+                    None => Err(format!("{}(): assertion failed", self.name)),
+                }
+            }
+            Status::Overflow => {
+                let sites = &self.program.code.overflow_sites;
+                match site.and_then(|site| sites.get(site)) {
+                    Some(site) => Err(format!("{}(): integer overflow in {}", self.name, site)),
+                    None => Err(format!("{}(): integer overflow", self.name)),
+                }
+            }
+            status => Err(format!("{}(): {}", self.name, status)),
+        }
+    }
+
+    /// Call with `args`, returning the raw result and how the call ended
+    pub fn call_with_status(&self, args: &[i64]) -> Result<(i64, Status), String> {
+        if args.len() != self.arity {
+            return Err(format!(
+                "{}() takes {} argument(s), got {}",
+                self.name,
+                self.arity,
+                args.len()
+            ));
+        }
+        if args.len() > MAX_ARGS {
+            return Err(format!(
+                "{}() takes {} arguments; at most {} are supported",
+                self.name, self.arity, MAX_ARGS
+            ));
+        }
+        status::reset();
+        let result = unsafe { host_args::call_with_args(self.entry, args) };
+        Ok((result, status::take()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::overflow::OverflowMode;
+    use crate::parser::Parser;
+
+    #[test]
+    fn test_call_any_function_by_name() {
+        let prog = Parser::new()
+            .parse(
+                "fn main() {
+                    x = mul_add(6, 7, 0)
+                    return x
+                }
+                fn mul_add(a, b, c) {
+                    p = a * b
+                    r = p + c
+                    return r
+                }",
+            )
+            .unwrap();
+        let compiled = CompiledProgram::compile(&prog, &CompileOptions::new(2)).unwrap();
+        assert_eq!(
+            compiled.function_names().collect::<Vec<_>>(),
+            ["main", "mul_add"]
+        );
+
+        let mul_add = compiled.get_fn("mul_add").unwrap();
+        assert_eq!(mul_add.arity(), 3);
+        assert_eq!(mul_add.call(&[2, 3, 4]), Ok(10));
+        assert!(mul_add.call(&[2, 3]).unwrap_err().contains("takes 3"));
+        assert_eq!(compiled.get_fn("main").unwrap().call(&[]), Ok(42));
+        assert!(compiled.get_fn("missing").is_none());
+
+        let typed: extern "C" fn(i64, i64, i64) -> i64 = compiled.get("mul_add").unwrap();
+        assert_eq!(typed(5, 5, 1), 26);
+        assert!(compiled
+            .get::<extern "C" fn(i64, i64) -> i64>("mul_add")
+            .is_none());
+        let main: extern "C" fn(u64) -> u64 = compiled.get("main").unwrap();
+        assert_eq!(main(7), 42);
+    }
+
+    #[test]
+    fn test_checked_overflow_traps_and_names_the_instruction() {
+        let prog = Parser::new()
+            .parse(
+                "fn main() {
+                    x = 2147483647
+                    x = x + 1
+                    return x
+                }
+                fn scale(a, b) {
+                    s = a + 1
+                    p = s * b
+                    return p
+                }",
+            )
+            .unwrap();
+        let wrapping = CompiledProgram::compile(&prog, &CompileOptions::new(1)).unwrap();
+        assert_eq!(wrapping.get_fn("main").unwrap().call(&[]), Ok(2147483648));
+        assert_eq!(
+            wrapping.get_fn("scale").unwrap().call(&[i64::MAX / 2, 2]),
+            Ok(i64::MIN)
+        );
+
+        let options = CompileOptions::new(3).overflow(OverflowMode::Checked);
+        let checked = CompiledProgram::compile(&prog, &options).unwrap();
+        assert_eq!(checked.get_fn("main").unwrap().call(&[]), Ok(2147483648));
+        let scale = checked.get_fn("scale").unwrap();
+        assert_eq!(scale.call(&[20, 2]), Ok(42));
+        let err = scale.call(&[i64::MAX / 2, 2]).unwrap_err();
+        assert!(
+            err.starts_with("scale(): integer overflow in scale["),
+            "{}",
+            err
+        );
+        assert!(err.contains("Mul"), "{}", err);
+        let err = scale.call(&[i64::MAX, 1]).unwrap_err();
+        assert!(err.contains("Add"), "{}", err);
+        assert_eq!(scale.call(&[1, 1]), Ok(2));
+    }
+
+    #[test]
+    fn test_patch_fuel_without_recompiling() {
+        let prog = Parser::new()
+            .parse(
+                "fn main(n) {
+                    i = 0
+                    label top
+                    if i == n goto done
+                    i = i + 1
+                    goto top
+                    label done
+                    return i
+                }",
+            )
+            .unwrap();
+        // A loop with a constant trip count would burn no fuel
+        let compiled = CompiledProgram::compile(&prog, &CompileOptions::new(0)).unwrap();
+        let main = compiled.get_fn("main").unwrap();
+        assert_eq!(
+            compiled.patched_value("fuel_main"),
+            Some(compiler::DEFAULT_FUEL)
+        );
+        assert_eq!(main.call(&[100]), Ok(100));
+
+        compiled.set_fuel("main", 10).unwrap();
+        assert_eq!(compiled.patched_value("fuel_main"), Some(10));
+        assert_eq!(main.call(&[100]), Err("main(): fuel exhausted".to_string()));
+
+        compiled.set_fuel("main", 1000).unwrap();
+        assert_eq!(main.call(&[100]), Ok(100));
+
+        // The old sentinel is an ordinary result now
+        let prog = Parser::new()
+            .parse("fn main() {\n x = 0 - 999\n return x\n}")
+            .unwrap();
+        let compiled = CompiledProgram::compile(&prog, &CompileOptions::new(0)).unwrap();
+        assert_eq!(compiled.get_fn("main").unwrap().call(&[]), Ok(-999));
+        assert!(compiled.set_fuel("missing", 1).is_err());
+    }
+
+    #[test]
+    fn test_batch_calls_once_per_input() {
+        let prog = Parser::new()
+            .parse(
+                "fn main(n) {
+                    i = 0
+                    s = 0
+                    label top
+                    if i == n goto done
+                    s = s + i
+                    i = i + 1
+                    goto top
+                    label done
+                    return s
+                }
+                fn two(a, b) {
+                    return a
+                }",
+            )
+            .unwrap();
+        let inputs: Vec<i64> = (0..100).collect();
+        let expected: Vec<i64> = inputs.iter().map(|n| n * (n - 1) / 2).collect();
+        for options in [CompileOptions::new(0), CompileOptions::new(0).verify_code(true)] {
+            let compiled = CompiledProgram::compile(&prog, &options).unwrap();
+            assert_eq!(compiled.code().batch_offset.is_some(), !options.verify_code);
+            let main = compiled.get_fn("main").unwrap();
+            let mut outputs = vec![0; inputs.len()];
+            main.call_batch(&inputs, &mut outputs).unwrap();
+            assert_eq!(outputs, expected);
+
+            // One trapping input fails the batch
+            compiled.set_fuel("main", 50).unwrap();
+            let err = main.call_batch(&inputs, &mut outputs).unwrap_err();
+            assert_eq!(err, "main(): fuel exhausted");
+            compiled.set_fuel("main", 1000).unwrap();
+            assert_eq!(main.call_batch(&inputs[..3], &mut outputs[..3]), Ok(()));
+
+            let err = main.call_batch(&inputs, &mut outputs[..1]).unwrap_err();
+            assert!(err.contains("needs as many outputs"), "{}", err);
+            let two = compiled.get_fn("two").unwrap();
+            assert!(two.call_batch(&[], &mut []).unwrap_err().contains("passes one"));
+        }
+    }
+
+    #[cfg(all(target_arch = "x86_64", target_env = "gnu"))]
+    #[test]
+    fn test_runaway_recursion_is_reported() {
+        let prog = Parser::new()
+            .parse(
+                "fn main(n) {
+                    if n == 0 goto done
+                    m = n - 1
+                    r = main(m)
+                    r = r + 1
+                    return r
+                    label done
+                    return 0
+                }",
+            )
+            .unwrap();
+        let compiled = CompiledProgram::compile(&prog, &CompileOptions::new(1)).unwrap();
+        let main = compiled.get_fn("main").unwrap();
+        assert_eq!(main.call(&[1000]), Ok(1000));
+        assert_eq!(
+            main.call(&[100_000_000]),
+            Err("main(): stack overflow (runaway recursion?)".to_string())
+        );
+        // The guard left the thread usable
+        assert_eq!(main.call(&[10]), Ok(10));
+    }
+}
@@ -1,15 +1,289 @@
-use crate::assembler::JitBuilder;
-use crate::ir::{Function, Opcode, Operand, Program};
-use std::collections::{HashMap, HashSet};
+use crate::alignment;
+use crate::code_verifier;
+use crate::assembler::{frame_check, JitBuilder};
+use crate::assertion::{self, AssertSite};
+use crate::const_eval;
+use crate::constant_time;
+use crate::cpu_features::{CpuFeatures, Microarch, VectorWidth};
+use crate::diagnostics;
+use crate::explain::MappedInstruction;
+use crate::function_profiler;
+use crate::ir::{Access, Cond, Function, Instruction, Opcode, Operand, Program};
+use crate::jit_memory::PatchPoint;
+use crate::loops;
+use crate::overflow::{self, OverflowMode};
+use crate::passes::{Pass, PassSet};
+use crate::pipeliner;
+use crate::pure;
+use crate::ranges;
+use crate::report::OptimizationReport;
+use crate::safety;
+use crate::scheduler;
+use crate::sanitizer::{self, SanitizerSite};
+use crate::stack_guard;
+use crate::status::{self, Status};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::Range;
 
 pub struct Compiler;
 
+/// Code generation settings
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct CompileOptions {
+    pub opt_level: u8,
+    /// Instrument Alloc/Free/Load/Store with the memory sanitizer
+    pub sanitize: bool,
+    /// Guard every frame with a stack canary and statically check push/pop balance
+    pub debug: bool,
+    /// Call the per-function profiling hooks on every entry and return
+    pub profile: bool,
+    /// If-convert data-dependent branches into CMovs and reject programs
+    /// that keep any (see `constant_time`)
+    pub constant_time: bool,
+    /// Leave single-assignment ifs as jumps instead of cmp+cmov selects
+    pub keep_branches: bool,
+    /// Latency table for instruction scheduling at -O2 and up (the detected
+    /// CPU's when unset)
+    pub uarch: Option<Microarch>,
+    /// Software-pipeline small counted loops at -O3 (see `pipeliner`)
+    pub software_pipeline: bool,
+    /// Prefetch this many bytes ahead of each vectorized loop's loads at -O3
+    /// (0 disables; see `Optimizer::insert_prefetches`)
+    pub prefetch_distance: i32,
+    /// ISA extensions the generated code may use (the detected CPU's when
+    /// unset); bit intrinsics fall back to portable sequences without them
+    pub cpu_features: Option<CpuFeatures>,
+    /// Lanes per vectorized loop iteration at -O3, in xmm, ymm or zmm
+    /// registers (4 when unset; `CpuFeatures::vector_width` is the widest
+    /// the CPU runs)
+    pub vector_width: Option<VectorWidth>,
+    /// Call other functions through patchable absolute addresses instead of
+    /// direct calls, so callees can live elsewhere (see `lazy`)
+    pub lazy_calls: bool,
+    /// Trap on signed overflow in scalar Add/Sub/Mul instead of wrapping
+    pub overflow: OverflowMode,
+    /// Reject programs that allocate or call outside themselves, and trap on
+    /// accesses outside the buffers passed in (see `pure` and
+    /// `sanitizer::set_buffers`)
+    pub pure: bool,
+    /// Decode the generated code and reject any instruction, branch or call
+    /// the code generator never emits (see `code_verifier`)
+    pub verify_code: bool,
+    /// Passes to skip, e.g. to measure what one contributes (see `passes`)
+    pub disabled_passes: PassSet,
+    /// Virtual registers to keep on the stack in every function, e.g. to
+    /// measure what a spill costs (see `spill_impact`)
+    pub forced_spills: SpillSet,
+    /// Loop back-edges each function may take per call (`DEFAULT_FUEL` when
+    /// unset); only loops proven to stop within it skip their fuel check
+    pub fuel: Option<i32>,
+}
+
+/// A set of general-purpose virtual registers, by number
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct SpillSet([u64; 4]);
+
+impl SpillSet {
+    pub fn with(mut self, vreg: u8) -> Self {
+        self.0[vreg as usize / 64] |= 1 << (vreg % 64);
+        self
+    }
+
+    pub fn contains(self, vreg: u8) -> bool {
+        self.0[vreg as usize / 64] & (1 << (vreg % 64)) != 0
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == [0; 4]
+    }
+}
+
+impl CompileOptions {
+    pub fn new(opt_level: u8) -> Self {
+        Self {
+            opt_level,
+            ..Default::default()
+        }
+    }
+
+    pub fn sanitize(mut self, enabled: bool) -> Self {
+        self.sanitize = enabled;
+        self
+    }
+
+    pub fn debug(mut self, enabled: bool) -> Self {
+        self.debug = enabled;
+        self
+    }
+
+    pub fn profile(mut self, enabled: bool) -> Self {
+        self.profile = enabled;
+        self
+    }
+
+    pub fn constant_time(mut self, enabled: bool) -> Self {
+        self.constant_time = enabled;
+        self
+    }
+
+    pub fn keep_branches(mut self, enabled: bool) -> Self {
+        self.keep_branches = enabled;
+        self
+    }
+
+    pub fn uarch(mut self, uarch: Microarch) -> Self {
+        self.uarch = Some(uarch);
+        self
+    }
+
+    pub fn software_pipeline(mut self, enabled: bool) -> Self {
+        self.software_pipeline = enabled;
+        self
+    }
+
+    pub fn prefetch_distance(mut self, bytes: i32) -> Self {
+        self.prefetch_distance = bytes;
+        self
+    }
+
+    pub fn cpu_features(mut self, features: CpuFeatures) -> Self {
+        self.cpu_features = Some(features);
+        self
+    }
+
+    pub fn vector_width(mut self, width: VectorWidth) -> Self {
+        self.vector_width = Some(width);
+        self
+    }
+
+    pub fn lazy_calls(mut self, enabled: bool) -> Self {
+        self.lazy_calls = enabled;
+        self
+    }
+
+    pub fn overflow(mut self, mode: OverflowMode) -> Self {
+        self.overflow = mode;
+        self
+    }
+
+    pub fn pure(mut self, enabled: bool) -> Self {
+        self.pure = enabled;
+        self
+    }
+
+    pub fn verify_code(mut self, enabled: bool) -> Self {
+        self.verify_code = enabled;
+        self
+    }
+
+    pub fn disable_pass(mut self, pass: Pass) -> Self {
+        self.disabled_passes = self.disabled_passes.with(pass);
+        self
+    }
+
+    /// Give `rN` a stack slot instead of a machine register; precolored
+    /// registers (r0-r4) can't be
+    pub fn force_spill(mut self, vreg: u8) -> Self {
+        self.forced_spills = self.forced_spills.with(vreg);
+        self
+    }
+
+    pub fn fuel(mut self, budget: i32) -> Self {
+        self.fuel = Some(budget);
+        self
+    }
+}
+
+/// Output of `Compiler::compile_with_options`
+#[derive(Debug, Clone)]
+pub struct CompiledCode {
+    pub code: Vec<u8>,
+    pub main_offset: usize,
+    /// Entry offset of every function, by name
+    pub function_offsets: BTreeMap<String, usize>,
+    /// Instrumented instructions, indexed by the site ids in sanitizer faults
+    pub sanitizer_sites: Vec<SanitizerSite>,
+    /// Checked arithmetic, indexed by the site ids passed to `nf_overflow`
+    pub overflow_sites: Vec<SanitizerSite>,
+    /// Assertions compiled in, indexed by the site ids passed to
+    /// `nf_assert_failed` (none from -O2 up)
+    pub assert_sites: Vec<AssertSite>,
+    /// Function names indexed by profiling hook id (empty unless profiling)
+    pub profiled_functions: Vec<String>,
+    /// Immediates that can be rewritten in place, e.g. each function's fuel
+    pub patch_points: Vec<PatchPoint>,
+    /// Bytes of `code` that are data, not instructions (function names for
+    /// the frame canary's report, jump tables)
+    pub data: Vec<Range<usize>>,
+    /// What the optimizer and register allocator did, per function
+    pub report: OptimizationReport,
+    /// Where the code of each IR instruction lies, in code order
+    pub source_map: Vec<MappedInstruction>,
+    /// Entry of the batch stub (`emit_batch_stub`); none under
+    /// `verify_code`, which only allows calls it can resolve
+    pub batch_offset: Option<usize>,
+}
+
+/// Loop back-edges a function may take per call before it bails out with
+/// `Status::FuelExhausted`, unless `CompileOptions::fuel` says otherwise.
+/// Loops that provably stop soon (`ranges::unfueled_loops`) don't burn any.
+pub const DEFAULT_FUEL: i32 = 1_000_000;
+
+/// Name of the patch point holding `function`'s fuel budget
+pub fn fuel_patch_point(function: &str) -> String {
+    format!("fuel_{}", function)
+}
+
+/// Name of the patch points holding the target of calls to `function`
+/// (64-bit, only with `CompileOptions::lazy_calls`)
+pub fn call_patch_point(function: &str) -> String {
+    format!("call_{}", function)
+}
+
+/// Every call must name a function of `prog`; the error suggests the
+/// closest one for typos
+pub fn check_call_targets(prog: &Program) -> Result<(), String> {
+    let mut known: Vec<&str> = prog.functions.iter().map(|f| f.name.as_str()).collect();
+    known.sort_unstable();
+    for func in &prog.functions {
+        for instr in func.instructions.iter().filter(|i| i.op == Opcode::Call) {
+            if let Some(Operand::Label(target)) = &instr.src1 {
+                if known.binary_search(&target.as_str()).is_err() {
+                    return Err(format!(
+                        "{}(): {}",
+                        func.name,
+                        diagnostics::undefined_function(target, &known)
+                    ));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Argument for a host runtime call
+#[derive(Clone, Copy)]
+enum RuntimeArg {
+    Loc(Location),
+    Imm(i32),
+    /// The value at a location plus a constant
+    Offset(Location, i32),
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Location {
     Register(u8),
-    Spill(i32), // Stack offset relative to RBP
+    Spill(i32), // Stack offset relative to RBP (YMM: relative to the top of the vector area)
 }
 
+/// Bytes between RBP and RSP once the prologue and the register saves are done
+const FRAME_PUSHED: i32 = 88;
+
+/// YMM registers reserved for reloading spilled vectors (cf. scratch1/scratch2),
+/// plus the lane mask of the AVX2 min/max/abs selects
+const YMM_SCRATCH: [u8; 3] = [14, 15, 13];
+
 #[derive(Debug, Clone)]
 struct Interval {
     operand: Operand,
@@ -20,24 +294,157 @@ struct Interval {
 
 impl Compiler {
     pub fn compile_program(prog: &Program, opt_level: u8) -> Result<(Vec<u8>, usize), String> {
+        Self::compile_with_options(prog, &CompileOptions::new(opt_level))
+            .map(|c| (c.code, c.main_offset))
+    }
+
+    pub fn compile_with_options(
+        prog: &Program,
+        options: &CompileOptions,
+    ) -> Result<CompiledCode, String> {
+        // Lazy callees live outside `prog` and are resolved at run time
+        if !options.lazy_calls {
+            check_call_targets(prog)?;
+        }
+        if options.pure {
+            if options.lazy_calls {
+                return Err("pure mode: lazy calls resolve callees outside the program".to_string());
+            }
+            pure::check_program(prog)?;
+        }
+        if options.verify_code && options.lazy_calls {
+            return Err("code verifier: lazy calls go through addresses patched in at run time".to_string());
+        }
         let mut builder = JitBuilder::new();
         let mut main_offset = 0;
+        let mut function_offsets = BTreeMap::new();
+        let mut sanitizer_sites = Vec::new();
+        let mut overflow_sites = Vec::new();
+        let mut assert_sites = Vec::new();
+        let mut profiled_functions = Vec::new();
+        let mut data = Vec::new();
+        let mut tables = Vec::new();
+        let mut source_map = Vec::new();
 
+        let fuel = options.fuel.unwrap_or(DEFAULT_FUEL).max(0);
+        // Pure mode bounds every access by the buffers the host passed in
+        // (`sanitizer::nf_pure_check`)
+        let checks_memory = options.sanitize || options.pure;
         let mut program = prog.clone();
-        crate::optimizer::Optimizer::optimize_program(&mut program, opt_level);
+        let mut report = OptimizationReport::new(&program, options.opt_level);
+        let on = |func: &Function, pass| !options.disabled_passes.union(func.hints.disabled_passes).contains(pass);
+        // Evaluated against the program as written
+        let pure_functions = const_eval::pure_functions(&program);
+        let written = if pure_functions.is_empty() { None } else { Some(program.clone()) };
+        for (func, func_report) in program.functions.iter_mut().zip(&mut report.functions) {
+            let level = func_report.opt_level;
+            if level >= assertion::STRIP_LEVEL {
+                func_report.note("strip_assertions", assertion::strip(func), func);
+            }
+            if let (Some(written), true) = (&written, level >= 1 && on(func, Pass::FoldPureCalls)) {
+                let folded = const_eval::fold_calls(written, &pure_functions, func, fuel as u64, options.overflow);
+                func_report.note("fold_pure_calls", folded > 0, func);
+            }
+            if options.constant_time {
+                func_report.note("if_convert", crate::optimizer::Optimizer::if_convert(func), func);
+            } else if level >= 1 && !options.keep_branches && on(func, Pass::SelectLowering) {
+                func_report.note("select_lowering", crate::optimizer::Optimizer::select_lowering(func), func);
+            }
+            // Before unrolling, which would copy the exit test into the body
+            if options.software_pipeline && level >= 3 && on(func, Pass::SoftwarePipeline) {
+                func_report.note("software_pipeline", pipeliner::pipeline_function(func), func);
+            }
+            // Vectorized loops use packed adds, which wrap without setting OF,
+            // and vector loads and stores, which pure mode can't check
+            if options.overflow == OverflowMode::Checked || options.pure {
+                func.hints.opt_level = Some(level.min(2));
+            }
+        }
+        let width = options.vector_width.unwrap_or_default();
+        crate::optimizer::Optimizer::optimize_program_except(&mut program, options.opt_level, width, options.disabled_passes, &mut report);
+        for (func, func_report) in program.functions.iter_mut().zip(&mut report.functions) {
+            if options.prefetch_distance > 0 && func_report.opt_level >= 3 && on(func, Pass::InsertPrefetches) {
+                let inserted = crate::optimizer::Optimizer::insert_prefetches(func, options.prefetch_distance);
+                func_report.note("insert_prefetches", inserted, func);
+            }
+        }
+        for (func, func_report) in program.functions.iter_mut().zip(&mut report.functions) {
+            // After every pass that matches loops by their header test. The
+            // guard left in front of a rotated loop is no loop's control, so
+            // constant-time builds keep the header shape.
+            let rotate = !options.constant_time && on(func, Pass::RotateLoops);
+            if func_report.opt_level >= 1 && rotate {
+                func_report.note("rotate_loops", loops::rotate(func), func);
+            }
+            if func_report.opt_level >= 2 && on(func, Pass::Schedule) {
+                let uarch = options.uarch.unwrap_or_else(Microarch::cached);
+                func_report.note("schedule", scheduler::schedule_function(func, uarch), func);
+            }
+        }
+        // Every loop header burns fuel
+        loops::annotate_program(&mut program);
+        for (func, written) in program.functions.iter_mut().zip(&prog.functions) {
+            func.carry_lines(written);
+        }
+        if options.constant_time {
+            constant_time::check_program(&program)?;
+        }
+        let features = options.cpu_features.unwrap_or_else(CpuFeatures::detect);
+        if let Some(requested) = options.vector_width {
+            if requested.lanes() > features.vector_width().lanes() {
+                return Err(format!(
+                    "{}-lane vectors need a wider vector unit than this CPU's ({} lanes)",
+                    requested.lanes(),
+                    features.vector_width().lanes()
+                ));
+            }
+        }
 
-        for func in &program.functions {
+        for (func, func_report) in program.functions.iter().zip(&mut report.functions) {
             let label_name = format!("fn_{}", func.name);
             let fail_label = format!("fuel_fail_{}", func.name);
+            let stack_fail_label = format!("stack_fail_{}", func.name);
+            let san_fail_label = format!("san_fail_{}", func.name);
+            let canary_fail_label = format!("canary_fail_{}", func.name);
+            let overflow_fail_label = format!("ovf_fail_{}", func.name);
+            let assert_fail_label = format!("assert_fail_{}", func.name);
+            let mut overflow_stubs = Vec::new();
+            let mut assert_stubs = Vec::new();
+            let profile_id = profiled_functions.len() as i32;
+            if options.profile {
+                profiled_functions.push(func.name.clone());
+            }
             
+            let start = builder.current_offset();
+            if let Some(align) = func.hints.align {
+                builder.align(align as usize);
+            }
             builder.bind_label(&label_name);
             let curr = builder.current_offset();
             if func.name == "main" {
                 main_offset = curr;
             }
+            function_offsets.insert(func.name.clone(), curr);
 
             let intervals = liveness_analysis(func);
 
+            // Checks the value ranges prove can't fire
+            let ranges = ranges::analyze(func);
+            let unfueled = ranges::unfueled_loops(func, &ranges, fuel as u64);
+            // Accesses left unchecked, with the allocation each stays inside
+            let unchecked: BTreeMap<usize, usize> = if checks_memory {
+                (0..func.instructions.len())
+                    .filter_map(|idx| ranges.in_bounds(func, idx).map(|site| (idx, site)))
+                    .collect()
+            } else {
+                BTreeMap::new()
+            };
+            let sanitized = |idx: usize, instr: &Instruction| match instr.op {
+                Opcode::Load(_) | Opcode::Store(_) => !unchecked.contains_key(&idx),
+                Opcode::Alloc | Opcode::Free => true,
+                _ => false,
+            };
+
             let gpr_intervals: Vec<Interval> = intervals
                 .iter()
                 .filter(|i| matches!(i.operand, Operand::Reg(_)))
@@ -56,7 +463,9 @@ impl Compiler {
 
             let callee_saved_size = 40;
 
-            let (gpr_map, stack_slots) = allocate_registers(gpr_intervals, gpr_pool, callee_saved_size)?;
+            let (gpr_map, stack_slots) = allocate_registers(
+                gpr_intervals, gpr_pool, callee_saved_size, 8, options.forced_spills,
+            )?;
             
             let spill_slots = stack_slots;
             let raw_stack_size = spill_slots * 8;
@@ -65,9 +474,67 @@ impl Compiler {
             if stack_size % 16 == 0 {
                 stack_size += 8;
             }
+            if options.debug {
+                // Canary slot at the bottom of the frame, below every spill
+                stack_size += 16;
+            }
+            let canary_offset = -(FRAME_PUSHED + stack_size);
 
-            let ymm_pool = (0..16).collect();
-            let (ymm_map, _) = allocate_registers(ymm_intervals, ymm_pool, 0)?;
+            // Every vector register is caller-saved, so vectors live across a call
+            // go straight to the stack
+            let clobbers: Vec<usize> = func
+                .instructions
+                .iter()
+                .enumerate()
+                .filter(|&(idx, i)| matches!(i.op, Opcode::Call | Opcode::Alloc | Opcode::Free)
+                    || (checks_memory && sanitized(idx, i)))
+                .map(|(idx, _)| idx)
+                .collect();
+            let (ymm_across_calls, ymm_intervals): (Vec<Interval>, Vec<Interval>) = ymm_intervals
+                .into_iter()
+                .partition(|iv| clobbers.iter().any(|&p| iv.start < p && p < iv.end));
+
+            let ymm_pool = (0..16).filter(|r| !YMM_SCRATCH.contains(r)).collect();
+            let (mut ymm_map, mut ymm_slots) = allocate_registers(
+                ymm_intervals, ymm_pool, 0, width.bytes(), SpillSet::default(),
+            )?;
+            for iv in ymm_across_calls {
+                ymm_slots += 1;
+                ymm_map.insert(iv.operand, Location::Spill(-(ymm_slots * width.bytes())));
+            }
+            for map in [&gpr_map, &ymm_map] {
+                let mut spilled: Vec<&Operand> = map
+                    .iter()
+                    .filter(|(_, loc)| matches!(loc, Location::Spill(_)))
+                    .map(|(operand, _)| operand)
+                    .collect();
+                spilled.sort_by_key(|op| match op {
+                    Operand::Reg(r) | Operand::Ymm(r) => *r,
+                    _ => 0,
+                });
+                for operand in spilled {
+                    func_report.spill(operand);
+                }
+            }
+            let mut allocated: Vec<u8> = gpr_map
+                .iter()
+                .filter_map(|(operand, loc)| match (operand, loc) {
+                    (Operand::Reg(r), Location::Register(_)) => Some(*r),
+                    _ => None,
+                })
+                .collect();
+            allocated.sort();
+            func_report.allocated = allocated.iter().map(|r| format!("r{}", r)).collect();
+            // Spill area for vectors, aligned to their size, addressed from RSP
+            let ymm_area = ymm_slots * width.bytes();
+            let uses_ymm = !ymm_map.is_empty();
+            // Non-temporal stores are weakly ordered: fence them before returning
+            let streams = func
+                .instructions
+                .iter()
+                .any(|i| matches!(i.op, Opcode::VStore(_, Access::NonTemporal)));
+            // Upper YMM halves may be dirty: a VEX op ran since the last vzeroupper
+            let mut ymm_dirty = false;
 
             let get_loc = |op: &Option<Operand>| -> Location {
                 match op {
@@ -76,15 +543,39 @@ impl Compiler {
                 }
             };
 
-            let _get_ymm = |op: &Option<Operand>| -> u8 {
-                if let Some(Operand::Ymm(v)) = op {
-                    if let Some(Location::Register(r)) = ymm_map.get(&Operand::Ymm(*v)) {
-                         *r
-                    } else {
-                        0
-                    }
-                } else {
-                    panic!("Expected Ymm operand");
+            let get_ymm = |op: &Option<Operand>| -> Location {
+                match op {
+                    Some(Operand::Ymm(v)) => *ymm_map.get(&Operand::Ymm(*v)).unwrap_or(&Location::Register(0)),
+                    _ => panic!("Expected Ymm operand"),
+                }
+            };
+
+            // Runs on every exit path with the return value already in RAX
+            let profile_exit = |builder: &mut JitBuilder| {
+                if options.profile {
+                    let exit = function_profiler::nf_prof_exit as *const () as usize as u64;
+                    builder.push_reg(0);
+                    builder.add_rsp(-8);
+                    emit_runtime_call(builder, exit, [RuntimeArg::Imm(profile_id), RuntimeArg::Imm(0), RuntimeArg::Imm(0)], None);
+                    builder.add_rsp(8);
+                    builder.pop_reg(0);
+                }
+            };
+
+            let check_canary = |builder: &mut JitBuilder| {
+                if options.debug {
+                    builder.mov_reg_stack(scratch1, canary_offset);
+                    builder.mov_reg_imm64(scratch2, safety::FRAME_CANARY);
+                    builder.cmp_reg_reg(scratch1, scratch2);
+                    builder.jne(&canary_fail_label);
+                }
+            };
+
+            let release_frame = |builder: &mut JitBuilder| {
+                if ymm_area > 0 {
+                    builder.lea_rsp_rbp(-FRAME_PUSHED);
+                } else if stack_size > 0 {
+                    builder.add_rsp(stack_size);
                 }
             };
 
@@ -99,38 +590,30 @@ impl Compiler {
             if stack_size > 0 {
                 builder.add_rsp(-stack_size);
             }
-            
-            builder.mov_reg_imm(5, 1_000_000);
-
-            let mut label_indices = HashMap::new();
-            for (i, instr) in func.instructions.iter().enumerate() {
-                if let Opcode::Label = instr.op {
-                    if let Some(Operand::Label(name)) = &instr.dest {
-                        label_indices.insert(name.clone(), i);
-                    }
-                }
+            if options.debug {
+                builder.mov_reg_imm64(scratch1, safety::FRAME_CANARY);
+                builder.mov_stack_reg(canary_offset, scratch1);
             }
-            let mut loop_headers = HashSet::new();
-            for (i, instr) in func.instructions.iter().enumerate() {
-                let target_label = match instr.op {
-                    Opcode::Jmp | Opcode::Jnz | Opcode::Je | Opcode::Jne | 
-                    Opcode::Jl | Opcode::Jle | Opcode::Jg | Opcode::Jge => {
-                        if let Some(Operand::Label(target)) = &instr.dest {
-                            Some(target)
-                        } else { None }
-                    }
-                    _ => None
-                };
-                if let Some(target) = target_label {
-                    if let Some(&target_idx) = label_indices.get(target) {
-                        if target_idx < i {
-                            loop_headers.insert(target.clone());
-                        }
-                    }
-                }
+            if ymm_area > 0 {
+                builder.add_rsp(-ymm_area);
+                builder.and_rsp(-width.bytes());
+            }
+            if options.profile {
+                let enter = function_profiler::nf_prof_enter as *const () as usize as u64;
+                emit_runtime_call(&mut builder, enter, [RuntimeArg::Imm(profile_id), RuntimeArg::Imm(0), RuntimeArg::Imm(0)], None);
+            }
+            if stack_guard::SUPPORTED {
+                builder.cmp_rsp_fs(stack_guard::LIMIT_OFFSET);
+                builder.jb(&stack_fail_label);
             }
+            
+            builder.mov_reg_imm_patchable(&fuel_patch_point(&func.name), 5, fuel);
+
+            // (label, entries) of each jump table, emitted after the function
+            let mut jump_tables: Vec<(String, Vec<String>)> = Vec::new();
 
             for (idx, instr) in func.instructions.iter().enumerate() {
+                builder.mark(idx);
                 let load_op = |builder: &mut JitBuilder, loc: Location, scratch: u8| -> u8 {
                     match loc {
                         Location::Register(r) => r,
@@ -153,17 +636,120 @@ impl Compiler {
                         }
                     }
                 };
+
+                let load_ymm = |builder: &mut JitBuilder, loc: Location, scratch: u8| -> u8 {
+                    match loc {
+                        Location::Register(r) => r,
+                        Location::Spill(offset) => {
+                            builder.vec_reload(width, scratch, ymm_area + offset);
+                            scratch
+                        }
+                    }
+                };
+
+                let index_op = |builder: &mut JitBuilder, op: &Option<Operand>| -> u8 {
+                    match op {
+                        Some(Operand::Imm(v)) => {
+                            builder.mov_reg_imm(scratch2, *v);
+                            scratch2
+                        }
+                        _ => load_op(builder, get_loc(op), scratch2),
+                    }
+                };
                 
                 if let Some(Operand::Label(name)) = &instr.dest {
                      if instr.op == Opcode::Label {
+                        // A jump may arrive from a path that ran vector code
+                        ymm_dirty |= uses_ymm;
+                        if let Some(align) = func.hints_for_loop(name).align {
+                            builder.align(align as usize);
+                        }
                         builder.bind_label(name);
-                        if loop_headers.contains(name) {
+                        if func.loops.contains_key(name) && !unfueled.contains(name) {
                             builder.dec_reg(5); 
                             builder.jz(&fail_label);
                         }
                      }
                 }
 
+                if checks_memory && sanitized(idx, instr) {
+                    let site = sanitizer_sites.len() as i32;
+                    let mut add_site = || sanitizer_sites.push(SanitizerSite::new(&func.name, idx, instr));
+                    let as_arg = |op: &Option<Operand>| match op {
+                        Some(Operand::Imm(v)) => RuntimeArg::Imm(*v),
+                        _ => RuntimeArg::Loc(get_loc(op)),
+                    };
+
+                    clean_upper(&mut builder, &mut ymm_dirty);
+                    match instr.op {
+                        Opcode::Load(disp) | Opcode::Store(disp) => {
+                            add_site();
+                            let (base, index) = if matches!(instr.op, Opcode::Load(_)) {
+                                (&instr.src1, &instr.src2)
+                            } else {
+                                (&instr.dest, &instr.src1)
+                            };
+                            // The checked element is index + disp
+                            let index = match (index, disp) {
+                                (_, 0) => as_arg(index),
+                                (Some(Operand::Imm(i)), _) => RuntimeArg::Imm(
+                                    i.checked_add(disp).ok_or("Load/Store index out of range")?,
+                                ),
+                                _ => RuntimeArg::Offset(get_loc(index), disp),
+                            };
+                            let check = if options.pure {
+                                sanitizer::nf_pure_check as *const () as usize as u64
+                            } else {
+                                sanitizer::nf_san_check as *const () as usize as u64
+                            };
+                            emit_runtime_call(
+                                &mut builder,
+                                check,
+                                [as_arg(base), index, RuntimeArg::Imm(site)],
+                                Some(&san_fail_label),
+                            );
+                        }
+                        Opcode::Alloc => {
+                            add_site();
+                            let alloc = sanitizer::nf_san_alloc as *const () as usize as u64;
+                            let align = match instr.src2 {
+                                Some(Operand::Imm(align)) => align,
+                                _ => 0,
+                            };
+                            emit_runtime_call(
+                                &mut builder,
+                                alloc,
+                                [
+                                    as_arg(&instr.src1),
+                                    RuntimeArg::Imm(site),
+                                    RuntimeArg::Imm(align),
+                                ],
+                                None,
+                            );
+                            store_op(&mut builder, get_loc(&instr.dest), 0);
+                            // Unchecked accesses rely on the allocation: a failed one
+                            // (over the memory limit) traps here instead of at them
+                            if unchecked.values().any(|&site| site == idx) {
+                                builder.cmp_reg_imm(0, 0);
+                                builder.je(&san_fail_label);
+                            }
+                            continue;
+                        }
+                        Opcode::Free => {
+                            add_site();
+                            let free = sanitizer::nf_san_free as *const () as usize as u64;
+                            emit_runtime_call(
+                                &mut builder,
+                                free,
+                                [as_arg(&instr.src1), RuntimeArg::Imm(site), RuntimeArg::Imm(0)],
+                                None,
+                            );
+                            continue;
+                        }
+                        _ => {}
+                    }
+                }
+
                 match &instr.op {
                     Opcode::Mov => {
                         let dest_loc = get_loc(&instr.dest);
@@ -262,12 +848,172 @@ impl Compiler {
                             builder.cmp_reg_imm(r1, *val);
                         }
                     }
+                    Opcode::Assert(cond, text) => {
+                        // Jump to the stub when the condition fails
+                        let r1 = match instr.src1 {
+                            Some(Operand::Imm(val)) => {
+                                builder.mov_reg_imm(scratch1, val);
+                                scratch1
+                            }
+                            _ => load_op(&mut builder, get_loc(&instr.src1), scratch1),
+                        };
+                        match instr.src2 {
+                            Some(Operand::Imm(val)) => builder.cmp_reg_imm(r1, val),
+                            _ => {
+                                let r2 = load_op(&mut builder, get_loc(&instr.src2), scratch2);
+                                builder.cmp_reg_reg(r1, r2);
+                            }
+                        }
+                        let site = assert_sites.len();
+                        assert_sites.push(AssertSite::new(&func.name, func.line(idx), text));
+                        let stub = format!("assert_{}_{}", func.name, site);
+                        match cond.negate() {
+                            Cond::Eq => builder.je(&stub),
+                            Cond::Ne => builder.jne(&stub),
+                            Cond::Lt => builder.jl(&stub),
+                            Cond::Le => builder.jle(&stub),
+                            Cond::Gt => builder.jg(&stub),
+                            Cond::Ge => builder.jge(&stub),
+                        }
+                        let arg = |op: &Option<Operand>| match op {
+                            Some(Operand::Imm(val)) => RuntimeArg::Imm(*val),
+                            _ => RuntimeArg::Loc(get_loc(op)),
+                        };
+                        assert_stubs.push((stub, site, arg(&instr.src1), arg(&instr.src2)));
+                    }
                     Opcode::Je => { if let Some(Operand::Label(t)) = &instr.dest { builder.je(t); } }
                     Opcode::Jne => { if let Some(Operand::Label(t)) = &instr.dest { builder.jne(t); } }
                     Opcode::Jl => { if let Some(Operand::Label(t)) = &instr.dest { builder.jl(t); } }
                     Opcode::Jle => { if let Some(Operand::Label(t)) = &instr.dest { builder.jle(t); } }
                     Opcode::Jg => { if let Some(Operand::Label(t)) = &instr.dest { builder.jg(t); } }
                     Opcode::Jge => { if let Some(Operand::Label(t)) = &instr.dest { builder.jge(t); } }
+                    Opcode::JumpTable(low, targets) => {
+                        if let Some(Operand::Label(default)) = &instr.dest {
+                            // Slot index - low, compared unsigned: values below
+                            // low wrap around past the end
+                            let index = index_op(&mut builder, &instr.src1);
+                            if index != scratch1 {
+                                builder.mov_reg_reg(scratch1, index);
+                            }
+                            if *low != 0 {
+                                builder.sub_reg_imm(scratch1, *low);
+                            }
+                            builder.cmp_reg_imm(scratch1, targets.len() as i32);
+                            builder.jae(default);
+                            let table = format!("jump_table_{}_{}", func.name, idx);
+                            builder.jmp_table(scratch1, scratch2, &table, targets);
+                            jump_tables.push((table, targets.clone()));
+                        }
+                    }
+                    Opcode::CMov(cond) => {
+                        // Only movs between the Cmp and here: the flags are intact
+                        let dest_loc = get_loc(&instr.dest);
+                        let d_reg = load_op(&mut builder, dest_loc, scratch1);
+                        let s_reg = match instr.src1 {
+                            Some(Operand::Imm(val)) => {
+                                builder.mov_reg_imm(scratch2, val);
+                                scratch2
+                            }
+                            _ => load_op(&mut builder, get_loc(&instr.src1), scratch2),
+                        };
+                        builder.cmov_reg_reg(*cond, d_reg, s_reg);
+                        if let Location::Spill(off) = dest_loc {
+                            builder.mov_stack_reg(off, d_reg);
+                        }
+                    }
+                    Opcode::Min | Opcode::Max => {
+                        // dest takes src when it compares greater (min) or less (max)
+                        let dest_loc = get_loc(&instr.dest);
+                        let d_reg = load_op(&mut builder, dest_loc, scratch1);
+                        let s_reg = match instr.src1 {
+                            Some(Operand::Imm(val)) => {
+                                builder.mov_reg_imm(scratch2, val);
+                                scratch2
+                            }
+                            _ => load_op(&mut builder, get_loc(&instr.src1), scratch2),
+                        };
+                        builder.cmp_reg_reg(d_reg, s_reg);
+                        let cond = if instr.op == Opcode::Min { Cond::Gt } else { Cond::Lt };
+                        builder.cmov_reg_reg(cond, d_reg, s_reg);
+                        if let Location::Spill(off) = dest_loc {
+                            builder.mov_stack_reg(off, d_reg);
+                        }
+                    }
+                    Opcode::Abs => {
+                        let dest_loc = get_loc(&instr.dest);
+                        let d_reg = match dest_loc {
+                            Location::Register(r) => r,
+                            Location::Spill(_) => scratch1,
+                        };
+                        let s_reg = match instr.src1 {
+                            Some(Operand::Imm(val)) => {
+                                builder.mov_reg_imm(scratch2, val);
+                                scratch2
+                            }
+                            _ => load_op(&mut builder, get_loc(&instr.src1), scratch2),
+                        };
+                        // temp = -src; take it when it is >= 0 (src <= 0, or
+                        // i64::MIN, where OF makes GE hold and it wraps to itself)
+                        let temp = if d_reg != 6 && s_reg != 6 { 6 } else { 0 };
+                        builder.mov_reg_reg(temp, s_reg);
+                        builder.neg_reg(temp);
+                        if d_reg != s_reg {
+                            builder.mov_reg_reg(d_reg, s_reg);
+                        }
+                        builder.cmov_reg_reg(Cond::Ge, d_reg, temp);
+                        if let Location::Spill(off) = dest_loc {
+                            builder.mov_stack_reg(off, d_reg);
+                        }
+                    }
+                    Opcode::Popcnt | Opcode::Ctz | Opcode::Clz => {
+                        let dest_loc = get_loc(&instr.dest);
+                        let d_reg = match dest_loc {
+                            Location::Register(r) => r,
+                            Location::Spill(_) => scratch1,
+                        };
+                        let s_reg = match instr.src1 {
+                            Some(Operand::Imm(val)) => {
+                                builder.mov_reg_imm(scratch2, val);
+                                scratch2
+                            }
+                            _ => load_op(&mut builder, get_loc(&instr.src1), scratch2),
+                        };
+                        // rcx and rax sit outside the allocation pool
+                        let temp = if d_reg != 6 && s_reg != 6 { 6 } else { 0 };
+                        match instr.op {
+                            Opcode::Popcnt if features.has_popcnt => builder.popcnt_reg_reg(d_reg, s_reg),
+                            Opcode::Popcnt => {
+                                clean_upper(&mut builder, &mut ymm_dirty);
+                                let popcount = nf_popcount as *const () as usize as u64;
+                                emit_runtime_call(
+                                    &mut builder,
+                                    popcount,
+                                    [RuntimeArg::Loc(Location::Register(s_reg)), RuntimeArg::Imm(0), RuntimeArg::Imm(0)],
+                                    None,
+                                );
+                                builder.mov_reg_reg(d_reg, 0);
+                            }
+                            Opcode::Ctz if features.has_bmi1 => builder.tzcnt_reg_reg(d_reg, s_reg),
+                            Opcode::Ctz => {
+                                // bsf sets ZF and leaves no defined result for 0;
+                                // select rather than branch (constant-time code)
+                                builder.bsf_reg_reg(d_reg, s_reg);
+                                builder.mov_reg_imm(temp, 64);
+                                builder.cmov_reg_reg(Cond::Eq, d_reg, temp);
+                            }
+                            Opcode::Clz if features.has_lzcnt => builder.lzcnt_reg_reg(d_reg, s_reg),
+                            _ => {
+                                // 63 - bsr = bsr ^ 63; a zero source takes 127 ^ 63 = 64
+                                builder.bsr_reg_reg(d_reg, s_reg);
+                                builder.mov_reg_imm(temp, 127);
+                                builder.cmov_reg_reg(Cond::Eq, d_reg, temp);
+                                builder.xor_reg_imm(d_reg, 63);
+                            }
+                        }
+                        if let Location::Spill(off) = dest_loc {
+                            builder.mov_stack_reg(off, d_reg);
+                        }
+                    }
 
                     Opcode::LoadArg(arg_idx) => {
                          let dest_loc = get_loc(&instr.dest);
@@ -324,7 +1070,13 @@ impl Compiler {
                             }
                             if pushed_count % 2 != 0 { builder.add_rsp(-8); }
                             
-                            builder.call(&target_label);
+                            clean_upper(&mut builder, &mut ymm_dirty);
+                            if options.lazy_calls {
+                                builder.mov_reg_imm64_patchable(&call_patch_point(target), 0, 0);
+                                builder.call_reg(0);
+                            } else {
+                                builder.call(&target_label);
+                            }
                             
                             if pushed_count % 2 != 0 { builder.add_rsp(8); }
                              for &reg in to_save.iter().rev() {
@@ -336,9 +1088,16 @@ impl Compiler {
                          }
                     }
                     Opcode::Ret => { 
-                         if stack_size > 0 {
-                             builder.add_rsp(stack_size);
+                         if options.profile {
+                             clean_upper(&mut builder, &mut ymm_dirty);
+                         }
+                         profile_exit(&mut builder);
+                         check_canary(&mut builder);
+                         if streams {
+                             builder.sfence();
                          }
+                         release_frame(&mut builder);
+                         clean_upper(&mut builder, &mut ymm_dirty);
                          builder.pop_reg(5); 
                          builder.pop_reg(10);
                          builder.pop_reg(9);
@@ -347,21 +1106,42 @@ impl Compiler {
                          builder.epilogue();
                     }
                     Opcode::Free => {
-                         let free_addr = libc::free as usize as u64;
+                         clean_upper(&mut builder, &mut ymm_dirty);
+                         let free_addr = libc::free as *const () as usize as u64;
                          builder.mov_reg_imm64(0, free_addr);
+                         // Save before loading RDI: it may hold a live value
+                         builder.push_reg(1); builder.push_reg(2); builder.push_reg(3); builder.push_reg(4);
+                         builder.push_reg(6); builder.push_reg(11); builder.push_reg(12); builder.push_reg(13);
                          if let Some(Operand::Reg(vreg)) = instr.src1 {
                              let src_loc = *gpr_map.get(&Operand::Reg(vreg)).unwrap();
                              let s = load_op(&mut builder, src_loc, scratch1); 
                              builder.mov_rdi_reg(s); 
                          }
-                         builder.push_reg(1); builder.push_reg(2); builder.push_reg(3); builder.push_reg(4);
-                         builder.push_reg(6); builder.push_reg(11); builder.push_reg(12); builder.push_reg(13);
                          builder.call_reg(0);
                          builder.pop_reg(13); builder.pop_reg(12); builder.pop_reg(11); builder.pop_reg(6);
                          builder.pop_reg(4); builder.pop_reg(3); builder.pop_reg(2); builder.pop_reg(1);
                     }
+                    Opcode::Alloc if instr.src2.is_some() => {
+                        clean_upper(&mut builder, &mut ymm_dirty);
+                        let Some(Operand::Imm(align)) = instr.src2 else {
+                            return Err("Alloc needs an immediate alignment".to_string());
+                        };
+                        let size = match instr.src1 {
+                            Some(Operand::Imm(v)) => RuntimeArg::Imm(v),
+                            _ => RuntimeArg::Loc(get_loc(&instr.src1)),
+                        };
+                        let alloc = alignment::nf_alloc_aligned as *const () as usize as u64;
+                        emit_runtime_call(
+                            &mut builder,
+                            alloc,
+                            [size, RuntimeArg::Imm(align), RuntimeArg::Imm(0)],
+                            None,
+                        );
+                        store_op(&mut builder, get_loc(&instr.dest), 0);
+                    }
                     Opcode::Alloc => {
-                        let malloc_addr = libc::malloc as usize as u64;
+                        clean_upper(&mut builder, &mut ymm_dirty);
+                        let malloc_addr = libc::malloc as *const () as usize as u64;
                          builder.mov_reg_imm64(0, malloc_addr);
                          if let Some(Operand::Imm(val)) = instr.src1 {
                              builder.mov_rdi_imm(val);
@@ -379,7 +1159,8 @@ impl Compiler {
                          let dest_loc = get_loc(&instr.dest);
                          store_op(&mut builder, dest_loc, 0);
                     }
-                    Opcode::Load => {
+                    Opcode::Load(disp) => {
+                         let offset = disp_bytes(*disp)?;
                          let dest_loc = get_loc(&instr.dest);
                          let base_loc = get_loc(&instr.src1);
                          let base_reg = load_op(&mut builder, base_loc, scratch1);
@@ -387,7 +1168,7 @@ impl Compiler {
                          if let Some(Operand::Imm(idx)) = instr.src2 {
                              let d_reg = match dest_loc { Location::Register(r) => r, _ => scratch2 };
                              builder.mov_reg_imm(d_reg, idx);
-                             builder.mov_reg_index(d_reg, base_reg, d_reg); 
+                             builder.mov_reg_index(d_reg, base_reg, d_reg, offset);
                              if let Location::Spill(off) = dest_loc {
                                  builder.mov_stack_reg(off, d_reg);
                              }
@@ -396,13 +1177,14 @@ impl Compiler {
                              let idx_reg = load_op(&mut builder, idx_loc, scratch2); 
                              
                              let d_reg = match dest_loc { Location::Register(r) => r, _ => scratch1 }; 
-                             builder.mov_reg_index(d_reg, base_reg, idx_reg);
+                             builder.mov_reg_index(d_reg, base_reg, idx_reg, offset);
                              if let Location::Spill(off) = dest_loc {
                                  builder.mov_stack_reg(off, d_reg);
                              }
                          }
                     }
-                    Opcode::Store => {
+                    Opcode::Store(disp) => {
+                         let offset = disp_bytes(*disp)?;
                          let base_loc = get_loc(&instr.dest);
                          let base_reg = load_op(&mut builder, base_loc, scratch1);
                          let val_reg = if let Some(Operand::Imm(val)) = instr.src2 {
@@ -422,28 +1204,342 @@ impl Compiler {
                                   Location::Spill(off) => { builder.mov_reg_stack(6, off); 6 }
                               }
                          };
-                         builder.mov_index_reg(base_reg, idx_reg, val_reg);
+                         builder.mov_index_reg(base_reg, idx_reg, val_reg, offset);
                     }
-                    _ => {} 
+                    Opcode::VLoad(disp, access) => {
+                         ymm_dirty = true;
+                         let offset = disp_bytes(*disp)?;
+                         let base_reg = load_op(&mut builder, get_loc(&instr.src1), scratch1);
+                         let idx_reg = index_op(&mut builder, &instr.src2);
+                         let dest_loc = get_ymm(&instr.dest);
+                         let y = match dest_loc { Location::Register(r) => r, _ => YMM_SCRATCH[0] };
+                         builder.vec_load(width, *access, y, base_reg, idx_reg, offset);
+                         if let Location::Spill(off) = dest_loc {
+                             builder.vec_spill(width, ymm_area + off, y);
+                         }
+                    }
+                    Opcode::PrefetchT0 => {
+                         // A hint: never faults, so no sanitizer check
+                         let base_reg = load_op(&mut builder, get_loc(&instr.dest), scratch1);
+                         let idx_reg = index_op(&mut builder, &instr.src1);
+                         let Some(Operand::Imm(distance)) = instr.src2 else {
+                             return Err("PrefetchT0 needs an immediate distance".to_string());
+                         };
+                         builder.prefetcht0_index(base_reg, idx_reg, distance);
+                    }
+                    Opcode::VStore(disp, access) => {
+                         ymm_dirty = true;
+                         let offset = disp_bytes(*disp)?;
+                         let base_reg = load_op(&mut builder, get_loc(&instr.dest), scratch1);
+                         let idx_reg = index_op(&mut builder, &instr.src1);
+                         let y = load_ymm(&mut builder, get_ymm(&instr.src2), YMM_SCRATCH[0]);
+                         builder.vec_store(width, *access, base_reg, idx_reg, y, offset);
+                    }
+                    Opcode::VAdd => {
+                         ymm_dirty = true;
+                         let s1 = load_ymm(&mut builder, get_ymm(&instr.src1), YMM_SCRATCH[0]);
+                         let s2 = load_ymm(&mut builder, get_ymm(&instr.src2), YMM_SCRATCH[1]);
+                         let dest_loc = get_ymm(&instr.dest);
+                         let d = match dest_loc { Location::Register(r) => r, _ => YMM_SCRATCH[0] };
+                         builder.vec_add(width, d, s1, s2);
+                         if let Location::Spill(off) = dest_loc {
+                             builder.vec_spill(width, ymm_area + off, d);
+                         }
+                    }
+                    Opcode::VMin | Opcode::VMax => {
+                         ymm_dirty = true;
+                         let s1 = load_ymm(&mut builder, get_ymm(&instr.src1), YMM_SCRATCH[0]);
+                         let s2 = load_ymm(&mut builder, get_ymm(&instr.src2), YMM_SCRATCH[1]);
+                         let dest_loc = get_ymm(&instr.dest);
+                         let d = match dest_loc { Location::Register(r) => r, _ => YMM_SCRATCH[0] };
+                         let is_min = instr.op == Opcode::VMin;
+                         if features.has_avx512vl || width == VectorWidth::Lanes8 {
+                             if is_min { builder.vec_min(width, d, s1, s2) } else { builder.vec_max(width, d, s1, s2) }
+                         } else {
+                             // AVX2 has no 64-bit min/max: select on s1 > s2
+                             let mask = YMM_SCRATCH[2];
+                             builder.vec_cmpgt(width, mask, s1, s2);
+                             if is_min { builder.vec_blend(width, d, s1, s2, mask) } else { builder.vec_blend(width, d, s2, s1, mask) }
+                         }
+                         if let Location::Spill(off) = dest_loc {
+                             builder.vec_spill(width, ymm_area + off, d);
+                         }
+                    }
+                    Opcode::VAbs => {
+                         ymm_dirty = true;
+                         let src = load_ymm(&mut builder, get_ymm(&instr.src1), YMM_SCRATCH[0]);
+                         let dest_loc = get_ymm(&instr.dest);
+                         let d = match dest_loc { Location::Register(r) => r, _ => YMM_SCRATCH[0] };
+                         if features.has_avx512vl || width == VectorWidth::Lanes8 {
+                             builder.vec_abs(width, d, src);
+                         } else {
+                             // sign = 0 > src (all ones when negative); |src| = (src ^ sign) - sign
+                             let sign = YMM_SCRATCH[2];
+                             builder.vec_xor(width, sign, sign, sign);
+                             builder.vec_cmpgt(width, sign, sign, src);
+                             builder.vec_xor(width, d, src, sign);
+                             builder.vec_sub(width, d, d, sign);
+                         }
+                         if let Location::Spill(off) = dest_loc {
+                             builder.vec_spill(width, ymm_area + off, d);
+                         }
+                    }
+                }
+
+                if options.overflow == OverflowMode::Checked
+                    && matches!(instr.op, Opcode::Add | Opcode::Sub | Opcode::Mul)
+                {
+                    let site = overflow_sites.len();
+                    overflow_sites.push(SanitizerSite::new(&func.name, idx, instr));
+                    let stub = format!("ovf_{}_{}", func.name, site);
+                    builder.jo(&stub);
+                    overflow_stubs.push((stub, site));
                 }
             }
 
+            builder.mark(func.instructions.len());
+            for pair in builder.take_marks().windows(2) {
+                let ((idx, start), (_, end)) = (pair[0], pair[1]);
+                let instr = &func.instructions[idx];
+                source_map.push(MappedInstruction::new(&func.name, idx, instr, func.line(idx), start..end));
+            }
+
+            // Fail paths record an out-of-band status (see `status`) and return 0
+            let trap = |builder: &mut JitBuilder, status: Status| {
+                if uses_ymm { builder.vzeroupper(); }
+                let handler = status::nf_trap as *const () as usize as u64;
+                emit_runtime_call(builder, handler, [RuntimeArg::Imm(status.code()), RuntimeArg::Imm(0), RuntimeArg::Imm(0)], None);
+                builder.mov_reg_imm(0, 0);
+            };
+
             builder.bind_label(&fail_label);
-            builder.mov_reg_imm(0, -999);
-            if stack_size > 0 { builder.add_rsp(stack_size); }
+            trap(&mut builder, Status::FuelExhausted);
+            profile_exit(&mut builder);
+            check_canary(&mut builder);
+            release_frame(&mut builder);
             builder.pop_reg(5);
             builder.pop_reg(10);
             builder.pop_reg(9);
             builder.pop_reg(8);
             builder.pop_reg(7);
             builder.epilogue();
+
+            if stack_guard::SUPPORTED {
+                // Taken on entry, before any vector code: no vzeroupper
+                builder.bind_label(&stack_fail_label);
+                let handler = status::nf_trap as *const () as usize as u64;
+                let code = Status::StackOverflow.code();
+                emit_runtime_call(&mut builder, handler, [RuntimeArg::Imm(code), RuntimeArg::Imm(0), RuntimeArg::Imm(0)], None);
+                builder.mov_reg_imm(0, 0);
+                profile_exit(&mut builder);
+                check_canary(&mut builder);
+                release_frame(&mut builder);
+                builder.pop_reg(5);
+                builder.pop_reg(10);
+                builder.pop_reg(9);
+                builder.pop_reg(8);
+                builder.pop_reg(7);
+                builder.epilogue();
+            }
+
+            if checks_memory {
+                builder.bind_label(&san_fail_label);
+                trap(&mut builder, Status::Trapped);
+                profile_exit(&mut builder);
+                check_canary(&mut builder);
+                release_frame(&mut builder);
+                builder.pop_reg(5);
+                builder.pop_reg(10);
+                builder.pop_reg(9);
+                builder.pop_reg(8);
+                builder.pop_reg(7);
+                builder.epilogue();
+            }
+
+            if !overflow_stubs.is_empty() {
+                let handler = overflow::nf_overflow as *const () as usize as u64;
+                for (stub, site) in &overflow_stubs {
+                    builder.bind_label(stub);
+                    if uses_ymm { builder.vzeroupper(); }
+                    emit_runtime_call(&mut builder, handler, [RuntimeArg::Imm(*site as i32), RuntimeArg::Imm(0), RuntimeArg::Imm(0)], None);
+                    builder.jmp(&overflow_fail_label);
+                }
+                builder.bind_label(&overflow_fail_label);
+                builder.mov_reg_imm(0, 0);
+                profile_exit(&mut builder);
+                check_canary(&mut builder);
+                release_frame(&mut builder);
+                builder.pop_reg(5);
+                builder.pop_reg(10);
+                builder.pop_reg(9);
+                builder.pop_reg(8);
+                builder.pop_reg(7);
+                builder.epilogue();
+            }
+
+            if !assert_stubs.is_empty() {
+                let handler = assertion::nf_assert_failed as *const () as usize as u64;
+                for (stub, site, lhs, rhs) in &assert_stubs {
+                    builder.bind_label(stub);
+                    if uses_ymm { builder.vzeroupper(); }
+                    emit_runtime_call(&mut builder, handler, [*lhs, *rhs, RuntimeArg::Imm(*site as i32)], None);
+                    builder.jmp(&assert_fail_label);
+                }
+                builder.bind_label(&assert_fail_label);
+                builder.mov_reg_imm(0, 0);
+                profile_exit(&mut builder);
+                check_canary(&mut builder);
+                release_frame(&mut builder);
+                builder.pop_reg(5);
+                builder.pop_reg(10);
+                builder.pop_reg(9);
+                builder.pop_reg(8);
+                builder.pop_reg(7);
+                builder.epilogue();
+            }
+
+            if options.debug {
+                // Never returns: reports the function name and aborts
+                let name_label = format!("canary_name_{}", func.name);
+                builder.bind_label(&canary_fail_label);
+                builder.lea_rdi_label(&name_label);
+                builder.mov_reg_imm(12, func.name.len() as i32);
+                builder.mov_reg_imm64(0, safety::nf_frame_corrupted as *const () as usize as u64);
+                builder.call_reg(0);
+                builder.bind_label(&name_label);
+                let name_start = builder.current_offset();
+                builder.emit_bytes(func.name.as_bytes());
+                data.push(name_start..builder.current_offset());
+            }
+            for (table, targets) in &jump_tables {
+                builder.jump_table_entries(table, targets);
+                let end = builder.current_offset();
+                tables.push(end - 4 * targets.len()..end);
+                data.push(end - 4 * targets.len()..end);
+            }
+            func_report.code_size = builder.current_offset() - start;
         }
 
-        let buf = builder.finalize();
-        Ok((buf, main_offset))
+        let batch_offset = (!options.verify_code).then(|| emit_batch_stub(&mut builder));
+
+        if options.debug || cfg!(debug_assertions) {
+            frame_check::check(builder.frame_ops())
+                .map_err(|e| format!("Frame check failed:\n{}", e))?;
+        }
+        let patch_points = builder.patch_points().to_vec();
+        let code = builder.finalize();
+        report.code_size = code.len();
+        if options.verify_code {
+            code_verifier::verify(&code, &function_offsets, &data, &tables)?;
+        }
+        Ok(CompiledCode {
+            code,
+            main_offset,
+            function_offsets,
+            sanitizer_sites,
+            overflow_sites,
+            assert_sites,
+            profiled_functions,
+            patch_points,
+            data,
+            report,
+            source_map,
+            batch_offset,
+        })
     }
 }
 
+/// Emit vzeroupper if vector code may have left the upper YMM halves dirty,
+/// so callees using legacy SSE don't pay the AVX/SSE transition penalty
+fn clean_upper(builder: &mut JitBuilder, dirty: &mut bool) {
+    if *dirty {
+        builder.vzeroupper();
+        *dirty = false;
+    }
+}
+
+/// Call a host function with three integer arguments, preserving every
+/// caller-saved register. The first two arguments are staged in the scratch
+/// registers because they may themselves live in rdi/rsi/rdx. With
+/// `fail_label`, a nonzero return jumps there after registers are restored.
+fn emit_runtime_call(builder: &mut JitBuilder, addr: u64, args: [RuntimeArg; 3], fail_label: Option<&str>) {
+    const SAVED: [u8; 8] = [1, 2, 3, 4, 6, 11, 12, 13];
+    for &r in &SAVED {
+        builder.push_reg(r);
+    }
+
+    for (arg, scratch) in args[..2].iter().zip([9, 10]) {
+        match *arg {
+            RuntimeArg::Loc(Location::Register(r)) => builder.mov_reg_reg(scratch, r),
+            RuntimeArg::Loc(Location::Spill(off)) => builder.mov_reg_stack(scratch, off),
+            RuntimeArg::Imm(v) => builder.mov_reg_imm(scratch, v),
+            RuntimeArg::Offset(loc, v) => {
+                match loc {
+                    Location::Register(r) => builder.mov_reg_reg(scratch, r),
+                    Location::Spill(off) => builder.mov_reg_stack(scratch, off),
+                }
+                builder.add_reg_imm(scratch, v);
+            }
+        }
+    }
+    builder.mov_reg_reg(11, 9);
+    builder.mov_reg_reg(12, 10);
+    match args[2] {
+        RuntimeArg::Imm(v) => builder.mov_reg_imm(13, v),
+        _ => unreachable!("third runtime argument must be an immediate"),
+    }
+
+    builder.mov_reg_imm64(0, addr);
+    builder.call_reg(0);
+    if fail_label.is_some() {
+        builder.cmp_reg_imm(0, 0);
+    }
+
+    for &r in SAVED.iter().rev() {
+        builder.pop_reg(r);
+    }
+    if let Some(label) = fail_label {
+        builder.jne(label);
+    }
+}
+
+/// Emit `batch(f, inputs, outputs, n)`, which sets `outputs[i] = f(inputs[i])`
+/// for each of the `n` inputs and returns `n`: one host call for the lot
+/// instead of one per input. Returns its entry offset.
+fn emit_batch_stub(builder: &mut JitBuilder) -> usize {
+    let entry = builder.current_offset();
+    builder.prologue(0);
+    // Callee-saved: f in rbx, inputs in r12, outputs in r13, n in r14, i in r15
+    builder.mov_reg_reg(7, 11);
+    builder.mov_reg_reg(8, 12);
+    builder.mov_reg_reg(9, 13);
+    builder.mov_reg_reg(10, 6);
+    builder.mov_reg_imm(5, 0);
+    builder.bind_label("nf_batch_loop");
+    builder.cmp_reg_reg(5, 10);
+    builder.jge("nf_batch_done");
+    builder.mov_reg_index(11, 8, 5, 0);
+    builder.call_reg(7);
+    builder.mov_index_reg(9, 5, 0, 0);
+    builder.add_reg_imm(5, 1);
+    builder.jmp("nf_batch_loop");
+    builder.bind_label("nf_batch_done");
+    builder.mov_reg_reg(0, 10);
+    builder.epilogue();
+    entry
+}
+
+/// Byte displacement of a Load/Store element displacement
+fn disp_bytes(disp: i32) -> Result<i32, String> {
+    disp.checked_mul(8)
+        .ok_or_else(|| format!("Load/Store displacement {} out of range", disp))
+}
+
+/// Portable popcount for CPUs without POPCNT
+pub(crate) extern "C" fn nf_popcount(x: u64) -> u64 {
+    x.count_ones() as u64
+}
+
 // Helper
 fn is_caller_saved(r: u8) -> bool {
     matches!(r, 0 | 1 | 2 | 3 | 4 | 6 | 11 | 12 | 13)
@@ -453,26 +1549,9 @@ fn liveness_analysis(func: &Function) -> Vec<Interval> {
     let mut starts = HashMap::new();
     let mut ends = HashMap::new();
     let mut ops = HashSet::new();
-    let mut back_edges = Vec::new(); 
-    let mut labels = HashMap::new();
-    for (idx, instr) in func.instructions.iter().enumerate() {
-        if instr.op == Opcode::Label {
-            if let Some(Operand::Label(name)) = &instr.dest {
-                labels.insert(name.clone(), idx);
-            }
-        }
-    }
-    for (idx, instr) in func.instructions.iter().enumerate() {
-        if matches!(instr.op, Opcode::Jmp | Opcode::Jnz | Opcode::Je | Opcode::Jne | Opcode::Jl | Opcode::Jle | Opcode::Jg | Opcode::Jge) {
-            if let Some(Operand::Label(target)) = &instr.dest {
-                if let Some(&target_idx) = labels.get(target) {
-                    if target_idx < idx {
-                        back_edges.push((target_idx, idx));
-                    }
-                }
-            }
-        }
-    }
+    let mut back_edges: Vec<(usize, usize)> =
+        func.loops.values().map(|l| (l.head, l.back_jump)).collect();
+    back_edges.sort_by_key(|&(_, tail)| tail);
     for (idx, instr) in func.instructions.iter().enumerate() {
         for op in [&instr.dest, &instr.src1, &instr.src2].iter().filter_map(|x| x.as_ref()) {
             match op {
@@ -509,21 +1588,42 @@ fn liveness_analysis(func: &Function) -> Vec<Interval> {
         let start = *starts.get(&op).unwrap_or(&0);
         let mut end = *ends.get(&op).unwrap_or(&0);
         for &(loop_head, loop_tail) in &back_edges {
-            if start <= loop_head && end >= loop_head {
-                if end < loop_tail { end = loop_tail; }
+            if start <= loop_head && end >= loop_head && end < loop_tail {
+                end = loop_tail;
             }
         }
         Interval { operand: op.clone(), start, end, assigned_loc: None }
     }).collect();
-    intervals.sort_by_key(|i| i.start);
+    // Break ties on the operand so allocation (and thus codegen) is deterministic
+    intervals.sort_by_key(|i| (i.start, operand_order(&i.operand)));
     intervals
 }
 
-fn allocate_registers(mut intervals: Vec<Interval>, pool: Vec<u8>, offset_start: i32) -> Result<(HashMap<Operand, Location>, i32), String> {
+fn operand_order(op: &Operand) -> (u8, u8) {
+    match op {
+        Operand::Reg(r) => (0, *r),
+        Operand::Ymm(r) => (1, *r),
+        _ => (2, 0),
+    }
+}
+
+fn allocate_registers(
+    mut intervals: Vec<Interval>,
+    pool: Vec<u8>,
+    offset_start: i32,
+    slot_size: i32,
+    forced: SpillSet,
+) -> Result<(HashMap<Operand, Location>, i32), String> {
     let mut active: Vec<Interval> = Vec::new();
     let mut map = HashMap::new();
     let mut stack_slot_count = 0;
 
+    if let Some(r) = (0..5).find(|&r| forced.contains(r)) {
+        if intervals.iter().any(|i| i.operand == Operand::Reg(r)) {
+            return Err(format!("r{} is pinned to a machine register and can't be spilled", r));
+        }
+    }
+
     for iv in &intervals {
          if let Operand::Reg(0) = iv.operand {
              map.insert(iv.operand.clone(), Location::Register(0));
@@ -553,6 +1653,14 @@ fn allocate_registers(mut intervals: Vec<Interval>, pool: Vec<u8>, offset_start:
             continue;
         }
 
+        if matches!(intervals[i].operand, Operand::Reg(r) if forced.contains(r)) {
+            stack_slot_count += 1;
+            let loc = Location::Spill(-(offset_start + stack_slot_count * slot_size));
+            intervals[i].assigned_loc = Some(loc);
+            map.insert(intervals[i].operand.clone(), loc);
+            continue;
+        }
+
         let used_regs: HashSet<u8> = active.iter().filter_map(|iv| match iv.assigned_loc {
             Some(Location::Register(r)) => Some(r),
             _ => None
@@ -591,7 +1699,7 @@ fn allocate_registers(mut intervals: Vec<Interval>, pool: Vec<u8>, offset_start:
                 };
                 
                 stack_slot_count += 1;
-                let offset = -(offset_start + stack_slot_count * 8); 
+                let offset = -(offset_start + stack_slot_count * slot_size); 
                 let spill_loc = Location::Spill(offset);
                 
                 spilled_iv.assigned_loc = Some(spill_loc);
@@ -603,7 +1711,7 @@ fn allocate_registers(mut intervals: Vec<Interval>, pool: Vec<u8>, offset_start:
                 active.push(intervals[i].clone());
             } else {
                  stack_slot_count += 1;
-                let offset = -(offset_start + stack_slot_count * 8);
+                let offset = -(offset_start + stack_slot_count * slot_size);
                 let loc = Location::Spill(offset);
                 intervals[i].assigned_loc = Some(loc);
                 map.insert(intervals[i].operand.clone(), loc);
@@ -613,3 +1721,457 @@ fn allocate_registers(mut intervals: Vec<Interval>, pool: Vec<u8>, offset_start:
 
     Ok((map, stack_slot_count))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::CodeGenerator;
+    use crate::ir::{Instruction, Opcode::*};
+    use crate::jit_memory::DualMappedMemory;
+
+    fn ins(op: Opcode, dest: Option<Operand>, src1: Option<Operand>, src2: Option<Operand>) -> Instruction {
+        Instruction { op, dest, src1, src2 }
+    }
+
+    /// Vector widths this CPU can run
+    fn runnable_widths() -> Vec<VectorWidth> {
+        let widest = CpuFeatures::detect().vector_width().lanes();
+        [VectorWidth::Lanes2, VectorWidth::Lanes4, VectorWidth::Lanes8]
+            .into_iter()
+            .filter(|w| w.lanes() <= widest)
+            .collect()
+    }
+
+    #[test]
+    fn test_vectors_spill_and_survive_calls() {
+        if !is_x86_feature_detected!("avx2") {
+            return;
+        }
+        use Operand::{Imm, Label, Reg, Ymm};
+
+        for width in runnable_widths() {
+            let lanes = width.lanes() as i32;
+            let mut one = Function::new("one", vec![]);
+            one.push(ins(Mov, Some(Reg(0)), Some(Imm(1)), None));
+            one.push(ins(Ret, None, None, None));
+
+            // Vector 0 is live across a call, then 15 more vectors are live
+            // at once (more than the allocatable registers)
+            let mut main = Function::new("main", vec!["a".to_string()]);
+            main.push(ins(LoadArg(0), Some(Reg(20)), None, None));
+            for k in 0..16u8 {
+                main.push(ins(VLoad(0, Access::Unaligned), Some(Ymm(k)), Some(Reg(20)), Some(Imm(lanes * k as i32))));
+                if k == 0 {
+                    main.push(ins(Call, Some(Reg(21)), Some(Label("one".to_string())), None));
+                }
+            }
+            main.push(ins(VAdd, Some(Ymm(100)), Some(Ymm(0)), Some(Ymm(1))));
+            for k in 2..16u8 {
+                main.push(ins(VAdd, Some(Ymm(100)), Some(Ymm(100)), Some(Ymm(k))));
+            }
+            main.push(ins(VStore(0, Access::Unaligned), Some(Reg(20)), Some(Imm(16 * lanes)), Some(Ymm(100))));
+            main.push(ins(Load(0), Some(Reg(0)), Some(Reg(20)), Some(Imm(17 * lanes - 1))));
+            main.push(ins(Ret, None, None, None));
+
+            let mut prog = Program::new();
+            prog.add_function(one);
+            prog.add_function(main);
+
+            let options = CompileOptions::new(0).vector_width(width);
+            let compiled = Compiler::compile_with_options(&prog, &options).unwrap();
+            let memory = DualMappedMemory::new(compiled.code.len() + 4096).unwrap();
+            CodeGenerator::emit_to_memory(&memory, &compiled.code, 0);
+            let f: extern "C" fn(*mut i64) -> i64 =
+                unsafe { std::mem::transmute(memory.rx_ptr.add(compiled.main_offset)) };
+
+            let n = 17 * lanes as i64;
+            let mut data: Vec<i64> = (0..n).collect();
+            let result = f(data.as_mut_ptr());
+
+            // Lane j sums a[lanes * k + j] over k = 0..16
+            let lanes = lanes as i64;
+            let expected: Vec<i64> = (0..lanes).map(|j| (0..16).map(|k| lanes * k + j).sum()).collect();
+            assert_eq!(&data[16 * lanes as usize..], expected.as_slice(), "{:?}", width);
+            assert_eq!(result, expected[lanes as usize - 1], "{:?}", width);
+        }
+    }
+
+    #[test]
+    fn test_vectorizes_at_every_width() {
+        if !is_x86_feature_detected!("avx2") {
+            return;
+        }
+        // 19 elements: every width leaves a scalar tail
+        let program = |op: &str| {
+            let src = format!(
+                "fn main() {{
+                    n = 19
+                    A = alloc(152)
+                    B = alloc(152)
+                    C = alloc(152)
+                    i = 0
+                    label fill
+                    if i == n goto go
+                    x = i * 7
+                    x = x - 60
+                    A[i] = x
+                    y = 30 - i
+                    y = y * 3
+                    B[i] = y
+                    i = i + 1
+                    goto fill
+                    label go
+                    i = 0
+                    label loop
+                    if i == n goto end
+                    a = A[i]
+                    b = B[i]
+                    c = {}
+                    C[i] = c
+                    i = i + 1
+                    goto loop
+                    label end
+                    r = 0
+                    i = 0
+                    label hash
+                    if i == n goto done
+                    c = C[i]
+                    r = r * 3
+                    r = r + c
+                    i = i + 1
+                    goto hash
+                    label done
+                    free(A)
+                    free(B)
+                    free(C)
+                    return r
+                }}",
+                op
+            );
+            crate::parser::Parser::new().parse(&src).unwrap()
+        };
+        let run = |prog: &Program, options: CompileOptions| {
+            let compiled = crate::compiled_program::CompiledProgram::compile(prog, &options).unwrap();
+            let main: extern "C" fn() -> i64 = compiled.get("main").unwrap();
+            main()
+        };
+
+        for op in ["a + b", "min(a, b)", "max(a, b)", "abs(a)"] {
+            let prog = program(op);
+            let expected = run(&prog, CompileOptions::new(0));
+            for width in runnable_widths() {
+                let options = CompileOptions::new(3).vector_width(width).sanitize(true);
+                assert_eq!(run(&prog, options), expected, "{} at {:?}", op, width);
+                // xmm and ymm min/max/abs fall back to AVX2 sequences
+                let no_vl = options.cpu_features(CpuFeatures {
+                    has_avx512vl: false,
+                    ..CpuFeatures::detect()
+                });
+                assert_eq!(run(&prog, no_vl), expected, "{} at {:?} without VL", op, width);
+
+                let compiled = Compiler::compile_with_options(&prog, &options).unwrap();
+                let vectorized = compiled.report.functions[0]
+                    .loops
+                    .iter()
+                    .any(|d| d.transform == crate::report::Transform::Vectorize && d.applied > 0);
+                assert!(vectorized, "{} at {:?}", op, width);
+            }
+        }
+
+        let prog = program("a + b");
+        let sse_only = CpuFeatures {
+            has_sse2: true,
+            ..Default::default()
+        };
+        let options = CompileOptions::new(3)
+            .cpu_features(sse_only)
+            .vector_width(VectorWidth::Lanes8);
+        let err = Compiler::compile_with_options(&prog, &options).unwrap_err();
+        assert!(err.contains("8-lane vectors"), "{}", err);
+    }
+
+    #[test]
+    fn test_vzeroupper_only_after_vector_code() {
+        use Operand::{Imm, Reg, Ymm};
+
+        let mut main = Function::new("main", vec![]);
+        main.push(ins(Alloc, Some(Reg(20)), Some(Imm(64)), None));
+        main.push(ins(VLoad(0, Access::Unaligned), Some(Ymm(0)), Some(Reg(20)), Some(Imm(0))));
+        main.push(ins(VStore(0, Access::Unaligned), Some(Reg(20)), Some(Imm(4)), Some(Ymm(0))));
+        main.push(ins(Free, None, Some(Reg(20)), None));
+        main.push(ins(Mov, Some(Reg(0)), Some(Imm(0)), None));
+        main.push(ins(Ret, None, None, None));
+        let mut prog = Program::new();
+        prog.add_function(main);
+
+        let compiled = Compiler::compile_with_options(&prog, &CompileOptions::new(0)).unwrap();
+        // Up to the batch stub, which calls through a register
+        let asm = crate::disasm::disassemble(&compiled.code[..compiled.batch_offset.unwrap()]);
+        let lines: Vec<&str> = asm.lines().collect();
+        let pos = |pat: &str| lines.iter().position(|l| l.contains(pat)).unwrap();
+        let calls: Vec<usize> = (0..lines.len()).filter(|&i| lines[i].contains("call")).collect();
+        let vzeroupper: Vec<usize> = (0..lines.len()).filter(|&i| lines[i].contains("vzeroupper")).collect();
+
+        // None before malloc (upper state still clean), one before free
+        // (after the vector ops), none at the clean return; the shared fuel
+        // exit keeps a conservative one before its trap call, and the stack
+        // guard's exit, taken on entry, needs none
+        assert_eq!(calls.len(), 3 + stack_guard::SUPPORTED as usize, "{}", asm);
+        assert_eq!(vzeroupper.len(), 2, "{}", asm);
+        assert!(pos("vmovdqu") < vzeroupper[0] && vzeroupper[0] < calls[1], "{}", asm);
+        assert!(vzeroupper[0] > calls[0], "{}", asm);
+    }
+
+    #[test]
+    fn test_debug_mode_guards_frames() {
+        let source = "fn sq(x) {\n y = x * x\n return y\n}\nfn main() {\n s = 0\n i = 0\n while i < 5 {\n v = sq(i)\n s = s + v\n i = i + 1\n }\n return s\n}";
+        let prog = crate::parser::Parser::new().parse(source).unwrap();
+
+        for level in 0..=3 {
+            let options = CompileOptions::new(level).debug(true);
+            let compiled = Compiler::compile_with_options(&prog, &options).unwrap();
+            let memory = DualMappedMemory::new(compiled.code.len() + 4096).unwrap();
+            CodeGenerator::emit_to_memory(&memory, &compiled.code, 0);
+            let f: extern "C" fn() -> i64 =
+                unsafe { std::mem::transmute(memory.rx_ptr.add(compiled.main_offset)) };
+            assert_eq!(f(), 30);
+
+            let plain = Compiler::compile_with_options(&prog, &CompileOptions::new(level)).unwrap();
+            let canary = format!("{:x}", safety::FRAME_CANARY);
+            assert!(crate::disasm::disassemble(&compiled.code).contains(&canary));
+            assert!(!crate::disasm::disassemble(&plain.code).contains(&canary));
+        }
+    }
+
+    #[test]
+    fn test_pragmas_align_and_unroll() {
+        let src = "fn helper() {
+            return 1
+        }
+        #pragma align(64)
+        fn main(n) {
+            sum = 0
+            #pragma unroll(3) align(32)
+            loop:
+            if n == 0 goto done
+            sum = sum + n
+            n = n - 1
+            goto loop
+            done:
+            return sum
+        }";
+        let prog = crate::parser::Parser::new().parse(src).unwrap();
+        for level in 0..=3 {
+            let compiled = Compiler::compile_with_options(&prog, &CompileOptions::new(level)).unwrap();
+            assert_eq!(compiled.main_offset % 64, 0);
+
+            let memory = DualMappedMemory::new(compiled.code.len() + 4096).unwrap();
+            CodeGenerator::emit_to_memory(&memory, &compiled.code, 0);
+            let f: extern "C" fn(i64) -> i64 =
+                unsafe { std::mem::transmute(memory.rx_ptr.add(compiled.main_offset)) };
+            // Trip counts that are and aren't multiples of the unroll factor
+            assert_eq!(f(9), 45, "O{}", level);
+            assert_eq!(f(10), 55, "O{}", level);
+        }
+    }
+
+    #[test]
+    fn test_pragma_opt_overrides_the_program_level() {
+        let body = "(n) {
+            sum = 0
+            loop:
+            if n == 0 goto done
+            sum = sum + n
+            n = n - 1
+            goto loop
+            done:
+            return sum
+        }";
+        // Labels are program-wide
+        let hot = body.replace("loop", "hot_loop").replace("done", "hot_done");
+        let src = format!("fn main{}\n#pragma opt(3)\nfn hot{}", body, hot);
+        let mut prog = crate::parser::Parser::new().parse(&src).unwrap();
+        let compiled = Compiler::compile_with_options(&prog, &CompileOptions::new(1)).unwrap();
+        let main = compiled.report.function("main").unwrap();
+        let hot = compiled.report.function("hot").unwrap();
+        assert_eq!((main.opt_level, hot.opt_level), (1, 3));
+        assert!(!main.passes.contains_key("loop_unrolling"));
+        assert!(hot.passes.contains_key("loop_unrolling"));
+        assert!(compiled.report.to_markdown().contains("## fn hot ("));
+        assert!(compiled.report.to_markdown().contains(" bytes, -O3)"));
+
+        let program = crate::compiled_program::CompiledProgram::compile(&prog, &CompileOptions::new(1)).unwrap();
+        let hot_fn = program.get_fn("hot").unwrap();
+        assert_eq!(hot_fn.call(&[10]), Ok(55));
+
+        prog.set_opt_level("main", 2).unwrap();
+        let compiled = Compiler::compile_with_options(&prog, &CompileOptions::new(0)).unwrap();
+        assert!(compiled.report.function("main").unwrap().passes.contains_key("loop_unrolling"));
+        assert!(prog.set_opt_level("missing", 2).is_err());
+    }
+
+    #[test]
+    fn test_bit_intrinsics_with_and_without_hardware() {
+        let src = "fn main(x) {
+            p = popcnt(x)
+            t = ctz(x)
+            l = clz(x)
+            t = t * 128
+            l = l * 16384
+            r = p + t
+            r = r + l
+            return r
+        }";
+        let prog = crate::parser::Parser::new().parse(src).unwrap();
+        let expected = |x: u64| {
+            (x.count_ones() + x.trailing_zeros() * 128 + x.leading_zeros() * 16384) as u64
+        };
+        // The detected CPU's instructions, then bsf/bsr and the host popcount
+        let fallback = CpuFeatures::default();
+        for features in [CpuFeatures::detect(), fallback] {
+            for level in 0..=3 {
+                let options = CompileOptions::new(level).cpu_features(features);
+                let compiled = crate::compiled_program::CompiledProgram::compile(&prog, &options).unwrap();
+                let f: extern "C" fn(u64) -> u64 = compiled.get("main").unwrap();
+                for x in [0, 1, u64::MAX, 1 << 63, 0x00F0_0000_0000_0100, 12345] {
+                    assert_eq!(f(x), expected(x), "O{} {:?} x={:#x}", level, features, x);
+                }
+            }
+        }
+
+        let asm = |features| {
+            let options = CompileOptions::new(0).cpu_features(features);
+            crate::disasm::disassemble(&Compiler::compile_with_options(&prog, &options).unwrap().code)
+        };
+        let hardware = CpuFeatures {
+            has_popcnt: true,
+            has_bmi1: true,
+            has_lzcnt: true,
+            ..fallback
+        };
+        let (fast, portable) = (asm(hardware), asm(fallback));
+        for (insn, fallback_insn) in [("tzcnt", "bsf"), ("lzcnt", "bsr")] {
+            assert!(fast.contains(insn) && !fast.contains(fallback_insn), "{}", fast);
+            assert!(portable.contains(fallback_insn) && !portable.contains(insn), "{}", portable);
+        }
+        // Both call the fuel trap; only the portable popcount calls the host
+        assert!(fast.contains("popcnt") && !portable.contains("popcnt"), "{}", portable);
+        assert_eq!(portable.matches("call").count(), fast.matches("call").count() + 1, "{}", portable);
+        // Zero inputs are selected, not branched on
+        assert_eq!(portable.matches("cmove").count(), 2, "{}", portable);
+    }
+
+    #[test]
+    fn test_min_max_abs_are_branchless() {
+        let src = "fn main(a, b) {
+            lo = min(a, b)
+            hi = max(a, b)
+            m = abs(a)
+            b = max(a, b)
+            r = lo * 3
+            hi = hi * 5
+            r = r + hi
+            m = m * 7
+            r = r + m
+            b = b * 11
+            r = r + b
+            return r
+        }";
+        let prog = crate::parser::Parser::new().parse(src).unwrap();
+        let expected = |a: i64, b: i64| {
+            let hi = a.max(b);
+            (a.min(b).wrapping_mul(3))
+                .wrapping_add(hi.wrapping_mul(16))
+                .wrapping_add(a.wrapping_abs().wrapping_mul(7))
+        };
+        for level in 0..=3 {
+            let compiled = crate::compiled_program::CompiledProgram::compile(&prog, &CompileOptions::new(level)).unwrap();
+            let f: extern "C" fn(i64, i64) -> i64 = compiled.get("main").unwrap();
+            for (a, b) in [(3, -9), (-9, 3), (0, 0), (-1, -1), (i64::MIN, 7), (7, i64::MIN), (1 << 40, -(1 << 40))] {
+                assert_eq!(f(a, b), expected(a, b), "O{} a={} b={}", level, a, b);
+            }
+        }
+
+        let asm = crate::disasm::disassemble(&Compiler::compile_with_options(&prog, &CompileOptions::new(0)).unwrap().code);
+        for insn in ["cmovg", "cmovl", "cmovge", "neg"] {
+            assert!(asm.contains(insn), "{}", asm);
+        }
+    }
+
+    #[test]
+    fn test_constant_time_mode() {
+        // Clamp each step into [lo, hi]; nested ifs inside a loop
+        let src = "fn main(k) {
+            acc = 0
+            i = 0
+            while i < 16 {
+                x = k * i
+                x = x - 40
+                if x < 0 {
+                    x = 0 - x
+                    if x > 25 {
+                        x = 25
+                    }
+                }
+                if x > 50 goto small
+                acc = acc + x
+                small:
+                i = i + 1
+            }
+            return acc
+        }";
+        let prog = crate::parser::Parser::new().parse(src).unwrap();
+        for level in 0..=3 {
+            let plain = crate::compiled_program::CompiledProgram::compile(&prog, &CompileOptions::new(level)).unwrap();
+            let ct = crate::compiled_program::CompiledProgram::compile(
+                &prog,
+                &CompileOptions::new(level).constant_time(true),
+            )
+            .unwrap();
+            let plain: extern "C" fn(i64) -> i64 = plain.get("main").unwrap();
+            let ct: extern "C" fn(i64) -> i64 = ct.get("main").unwrap();
+            for k in [-7, 0, 3, 9, 100] {
+                assert_eq!(ct(k), plain(k), "O{} k={}", level, k);
+            }
+        }
+
+        // A load can fault when run unconditionally: left as a branch, so rejected
+        let src = "fn main(k) {
+            a = alloc(8)
+            a[0] = 3
+            x = 0
+            if k > 0 {
+                x = a[0]
+            }
+            free(a)
+            return x
+        }";
+        let prog = crate::parser::Parser::new().parse(src).unwrap();
+        let err = Compiler::compile_with_options(&prog, &CompileOptions::new(2).constant_time(true)).unwrap_err();
+        assert!(err.contains("constant-time"), "{}", err);
+        assert!(Compiler::compile_with_options(&prog, &CompileOptions::new(2)).is_ok());
+    }
+
+    #[test]
+    fn test_undefined_call_is_an_error_with_a_suggestion() {
+        let src = "fn main() {
+            x = ad(10, 20)
+            return x
+        }
+        fn add(a, b) {
+            c = a + b
+            return c
+        }";
+        let prog = crate::parser::Parser::new().parse(src).unwrap();
+        let err = Compiler::compile_program(&prog, 2).unwrap_err();
+        assert_eq!(
+            err,
+            "main(): call to undefined function `ad`; did you mean `add`? (defined: add, main)"
+        );
+        // Lazily compiled functions call outside the program they are compiled from
+        let single = Program {
+            functions: vec![prog.functions[0].clone()],
+        };
+        assert!(Compiler::compile_with_options(&single, &CompileOptions::new(2).lazy_calls(true)).is_ok());
+    }
+}
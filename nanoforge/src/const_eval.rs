@@ -0,0 +1,323 @@
+//! Compile-Time Evaluation
+//!
+//! A call to a pure function whose arguments are all constants is run
+//! through the `interpreter` while compiling, and replaced by its result:
+//! table-generation idioms (`t = square(12)`) cost nothing at run time, and
+//! evolved genomes shed helpers that only ever see constants.
+//!
+//! A function is pure if it computes over integers only (no memory access,
+//! allocation or vector op) and calls nothing but pure functions of the
+//! program. Arguments count as constant when the value ranges
+//! (`ranges`) pin them to one value. A call whose evaluation fails, takes
+//! more than `FUEL` instructions (or the caller's fuel budget, if lower)
+//! or returns a value that doesn't fit an immediate is left alone. Under
+//! checked overflow a call that would trap fails, so it still traps at run
+//! time.
+
+use crate::interpreter::Interpreter;
+use crate::ir::{Function, Instruction, Opcode, Operand, Program};
+use crate::overflow::OverflowMode;
+use crate::{loops, ranges};
+use std::collections::BTreeSet;
+
+/// Interpreted instructions one call may take
+pub const FUEL: u64 = 100_000;
+
+/// Names of the functions of `prog` whose result depends only on their
+/// arguments
+pub fn pure_functions(prog: &Program) -> BTreeSet<String> {
+    let mut pure: BTreeSet<String> = prog
+        .functions
+        .iter()
+        .filter(|f| f.instructions.iter().all(computes_only))
+        .map(|f| f.name.clone())
+        .collect();
+    // Drop callers of impure functions until none is left
+    loop {
+        let before = pure.len();
+        let calls_impure = |f: &Function| {
+            f.instructions.iter().any(|i| match (&i.op, &i.src1) {
+                (Opcode::Call, Some(Operand::Label(callee))) => !pure.contains(callee),
+                (Opcode::Call, _) => true,
+                _ => false,
+            })
+        };
+        let impure: Vec<String> = prog
+            .functions
+            .iter()
+            .filter(|f| pure.contains(&f.name) && calls_impure(f))
+            .map(|f| f.name.clone())
+            .collect();
+        for name in impure {
+            pure.remove(&name);
+        }
+        if pure.len() == before {
+            return pure;
+        }
+    }
+}
+
+/// Whether `instr` only computes on integer registers (calls are judged
+/// by their callee)
+fn computes_only(instr: &Instruction) -> bool {
+    matches!(
+        instr.op,
+        Opcode::Mov
+            | Opcode::Add
+            | Opcode::Sub
+            | Opcode::Mul
+            | Opcode::Min
+            | Opcode::Max
+            | Opcode::Abs
+            | Opcode::Popcnt
+            | Opcode::Ctz
+            | Opcode::Clz
+            | Opcode::Cmp
+            | Opcode::CMov(_)
+            | Opcode::Label
+            | Opcode::Jmp
+            | Opcode::Jnz
+            | Opcode::Je
+            | Opcode::Jne
+            | Opcode::Jl
+            | Opcode::Jle
+            | Opcode::Jg
+            | Opcode::Jge
+            | Opcode::JumpTable(..)
+            | Opcode::Ret
+            | Opcode::LoadArg(_)
+            | Opcode::SetArg(_)
+            | Opcode::Call
+    )
+}
+
+/// Replace the calls in `func` to functions in `pure` that take constant
+/// arguments by their results, evaluated against `prog` with at most
+/// `fuel` instructions each and `overflow` arithmetic; returns the number
+/// of calls folded
+pub fn fold_calls(
+    prog: &Program,
+    pure: &BTreeSet<String>,
+    func: &mut Function,
+    fuel: u64,
+    overflow: OverflowMode,
+) -> usize {
+    let mut folded = 0;
+    // A folded result may make the next call's arguments constant
+    loop {
+        let round = fold_round(prog, pure, func, FUEL.min(fuel), overflow);
+        if round == 0 {
+            return folded;
+        }
+        folded += round;
+    }
+}
+
+fn fold_round(
+    prog: &Program,
+    pure: &BTreeSet<String>,
+    func: &mut Function,
+    fuel: u64,
+    overflow: OverflowMode,
+) -> usize {
+    loops::annotate(func);
+    let ranges = ranges::analyze(func);
+    let mut folds = Vec::new();
+    for (at, instr) in func.instructions.iter().enumerate() {
+        let (Opcode::Call, Some(Operand::Label(callee))) = (&instr.op, &instr.src1) else {
+            continue;
+        };
+        let Some(arity) = prog
+            .functions
+            .iter()
+            .find(|f| &f.name == callee && pure.contains(callee))
+            .map(|f| f.args.len())
+        else {
+            continue;
+        };
+        let Some((set_args, args)) = constant_args(func, &ranges, at, arity) else {
+            continue;
+        };
+        let result = Interpreter::new(prog)
+            .with_fuel(fuel)
+            .with_overflow(overflow)
+            .call(callee, &args);
+        if let Some(result) = result.ok().and_then(|r| i32::try_from(r).ok()) {
+            folds.push((at, set_args, result));
+        }
+    }
+
+    let folded = folds.len();
+    let mut dropped = BTreeSet::new();
+    for (at, set_args, result) in folds {
+        let call = &mut func.instructions[at];
+        call.op = Opcode::Mov;
+        call.src1 = Some(Operand::Imm(result));
+        // The argument registers are physical: nothing may write them
+        // without a call to read them
+        dropped.extend(set_args);
+    }
+    let mut idx = 0;
+    func.instructions.retain(|_| {
+        idx += 1;
+        !dropped.contains(&(idx - 1))
+    });
+    loops::annotate(func);
+    folded
+}
+
+/// The `SetArg`s feeding the call at `at` and the constant values they
+/// pass, if every one of its `arity` arguments is a constant
+fn constant_args(
+    func: &Function,
+    ranges: &ranges::Ranges,
+    at: usize,
+    arity: usize,
+) -> Option<(Vec<usize>, Vec<i64>)> {
+    let mut set_args = Vec::new();
+    let mut args: Vec<Option<i64>> = vec![None; arity];
+    for idx in (0..at).rev() {
+        let instr = &func.instructions[idx];
+        let ends_run = matches!(instr.op, Opcode::Label | Opcode::Call | Opcode::Ret);
+        if ends_run || !instr.jump_targets().is_empty() {
+            break;
+        }
+        let Opcode::SetArg(arg) = instr.op else {
+            continue;
+        };
+        set_args.push(idx);
+        let value = ranges.at(idx, instr.src1.as_ref()?)?;
+        match args.get_mut(arg) {
+            Some(slot @ None) if value.lo == value.hi => *slot = Some(value.lo),
+            _ => return None,
+        }
+    }
+    let args = args.into_iter().collect::<Option<Vec<i64>>>()?;
+    Some((set_args, args))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiled_program::CompiledProgram;
+    use crate::compiler::CompileOptions;
+    use crate::parser::Parser;
+
+    fn count(func: &Function, op: Opcode) -> usize {
+        func.instructions.iter().filter(|i| i.op == op).count()
+    }
+
+    #[test]
+    fn test_folds_constant_calls_to_pure_functions() {
+        let src = "fn main(n) {
+            k = 10
+            a = fib(k)
+            b = fib(n)
+            c = first(n)
+            d = huge(3)
+            s = a + b
+            s = s + c
+            s = s + d
+            return s
+        }
+        fn fib(n) {
+            if n < 2 goto base
+            m = n - 1
+            x = fib(m)
+            m = n - 2
+            y = fib(m)
+            r = x + y
+            return r
+            label base
+            return n
+        }
+        fn first(p) {
+            v = p[0]
+            return v
+        }
+        fn huge(x) {
+            y = x * 1000000
+            y = y * 1000000
+            return y
+        }";
+        let prog = Parser::new().parse(src).unwrap();
+        let pure = pure_functions(&prog);
+        // main calls first, which loads
+        assert_eq!(pure, BTreeSet::from(["fib".to_string(), "huge".to_string()]));
+
+        // fib(10) folds; fib(n) isn't constant, and huge(3) doesn't fit
+        let mut main = prog.functions[0].clone();
+        assert_eq!(fold_calls(&prog, &pure, &mut main, FUEL, OverflowMode::Wrapping), 1);
+        assert_eq!(count(&main, Opcode::Call), 3);
+        let fib_10 = Some(Operand::Imm(55));
+        assert!(main.instructions.iter().any(|i| i.op == Opcode::Mov && i.src1 == fib_10));
+        assert_eq!(fold_calls(&prog, &pure, &mut main, FUEL, OverflowMode::Wrapping), 0);
+    }
+
+    #[test]
+    fn test_leaves_calls_that_trap_or_outrun_the_fuel() {
+        let src = "fn main() {
+            k = 3
+            a = big(k)
+            n = 5000
+            b = tri(n)
+            s = a + b
+            return s
+        }
+        fn big(x) {
+            y = x * 65536
+            y = y * 65536
+            y = y * 65536
+            y = y * 65536
+            return y
+        }
+        fn tri(n) {
+            s = 0
+            i = 0
+            while i < n {
+                s = s + i
+                i = i + 1
+            }
+            return s
+        }";
+        let prog = Parser::new().parse(src).unwrap();
+        let pure = pure_functions(&prog);
+        let folds = |fuel, overflow| {
+            let mut main = prog.functions[0].clone();
+            fold_calls(&prog, &pure, &mut main, fuel, overflow)
+        };
+        // big(3) wraps to 0
+        assert_eq!(folds(FUEL, OverflowMode::Wrapping), 2);
+        assert_eq!(folds(FUEL, OverflowMode::Checked), 1);
+        assert_eq!(folds(100, OverflowMode::Wrapping), 1);
+    }
+
+    #[test]
+    fn test_folded_programs_compute_the_same() {
+        let src = "fn main() {
+            s = 0
+            i = 0
+            while i < 4 {
+                t = cube(i)
+                s = s + t
+                i = i + 1
+            }
+            u = cube(5)
+            s = s + u
+            return s
+        }
+        fn cube(x) {
+            y = x * x
+            y = y * x
+            return y
+        }";
+        let prog = Parser::new().parse(src).unwrap();
+        for level in [0, 2] {
+            let compiled = CompiledProgram::compile(&prog, &CompileOptions::new(level)).unwrap();
+            let main: extern "C" fn() -> i64 = compiled.get("main").unwrap();
+            assert_eq!(main(), 1 + 8 + 27 + 125);
+            let folded = compiled.code().report.functions[0].passes.get("fold_pure_calls");
+            assert_eq!(folded.is_some(), level > 0);
+        }
+    }
+}
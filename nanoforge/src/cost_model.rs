@@ -0,0 +1,121 @@
+//! Static Cost Model
+//!
+//! Predicts cycles per call for a variant configuration at an input size,
+//! from lanes per vector, unroll factor and a fixed setup cost per ISA. It
+//! only has to rank variants sensibly before anything has been measured: the
+//! bandits start from it as a prior and let the measurements override it.
+//! Vectors wider than the micro-architecture database prefers for this CPU
+//! are predicted at the preferred width's throughput (frequency licenses,
+//! double pumping) while keeping their own setup cost.
+
+use crate::uarch_db::{UarchDefaults, GENERIC};
+use crate::variant_generator::{IsaExtension, VariantConfig};
+
+/// Cycles per unrolled vector operation
+const OP_CYCLES: f64 = 1.0;
+/// Compare and branch per loop iteration
+const LOOP_OVERHEAD: f64 = 1.0;
+/// Scalar registers left for unrolled accumulators before spilling
+const REGISTER_BUDGET: u8 = 8;
+/// Reload and store per accumulator beyond the budget
+const SPILL_CYCLES: f64 = 2.0;
+
+/// 64-bit elements per operation
+pub fn lanes(isa: IsaExtension) -> u64 {
+    match isa {
+        IsaExtension::Scalar => 1,
+        IsaExtension::Avx2 | IsaExtension::Avx512 => isa.vector_width().lanes() as u64,
+        IsaExtension::Amx => 16,
+    }
+}
+
+/// Fixed cost per call: broadcasts and horizontal reductions for vectors,
+/// the frequency license for AVX-512, tile configuration for AMX
+fn setup_cycles(isa: IsaExtension) -> f64 {
+    match isa {
+        IsaExtension::Scalar => 2.0,
+        IsaExtension::Avx2 => 25.0,
+        IsaExtension::Avx512 => 50.0,
+        IsaExtension::Amx => 200.0,
+    }
+}
+
+/// Predicted cycles for one call over `n` elements
+pub fn predict_cycles(config: &VariantConfig, n: u64) -> f64 {
+    predict_cycles_on(config, n, &GENERIC)
+}
+
+/// `predict_cycles` on a CPU with `uarch` defaults
+pub fn predict_cycles_on(config: &VariantConfig, n: u64, uarch: &UarchDefaults) -> f64 {
+    let unroll = config.unroll_factor.max(1);
+    let lanes = match (config.isa, uarch.vector_width) {
+        (IsaExtension::Avx2 | IsaExtension::Avx512, Some(preferred)) => {
+            lanes(config.isa).min(preferred.lanes() as u64)
+        }
+        (isa, _) => lanes(isa),
+    };
+    let per_iteration = lanes * unroll as u64;
+    let spills = if config.isa == IsaExtension::Scalar {
+        unroll.saturating_sub(REGISTER_BUDGET) as f64 * SPILL_CYCLES
+    } else {
+        0.0
+    };
+    let iteration = unroll as f64 * OP_CYCLES + LOOP_OVERHEAD + spills;
+    // The remainder runs through a scalar tail loop
+    let tail = (n % per_iteration) as f64 * (OP_CYCLES + LOOP_OVERHEAD);
+    setup_cycles(config.isa) + (n / per_iteration) as f64 * iteration + tail
+}
+
+/// Predicted reward of each config at `n` on this CPU, as the bandits
+/// compute it from measurements: best predicted cycles over the config's own
+pub fn prior_rewards(configs: &[VariantConfig], n: u64) -> Vec<f64> {
+    prior_rewards_on(configs, n, UarchDefaults::cached())
+}
+
+/// `prior_rewards` on a CPU with `uarch` defaults
+pub fn prior_rewards_on(configs: &[VariantConfig], n: u64, uarch: &UarchDefaults) -> Vec<f64> {
+    let predicted: Vec<f64> = configs.iter().map(|c| predict_cycles_on(c, n, uarch)).collect();
+    let best = predicted.iter().copied().fold(f64::INFINITY, f64::min);
+    predicted.iter().map(|&p| best / p).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_small_inputs_favor_scalar_and_large_favor_vectors() {
+        let configs = vec![
+            VariantConfig::new(IsaExtension::Scalar, 1, 1),
+            VariantConfig::new(IsaExtension::Scalar, 4, 2),
+            VariantConfig::new(IsaExtension::Scalar, 16, 2),
+            VariantConfig::new(IsaExtension::Avx2, 4, 3),
+            VariantConfig::new(IsaExtension::Avx512, 8, 3),
+            VariantConfig::new(IsaExtension::Amx, 1, 3),
+        ];
+        let best = |n| {
+            let rewards = prior_rewards(&configs, n);
+            let idx = rewards.iter().position(|&r| r == 1.0).unwrap();
+            configs[idx].isa
+        };
+        assert_eq!(best(8), IsaExtension::Scalar);
+        assert_ne!(best(1_000_000), IsaExtension::Scalar);
+        // Spilling makes a x16 scalar unroll worse than x4
+        assert!(predict_cycles(&configs[2], 4096) > predict_cycles(&configs[1], 4096));
+    }
+
+    #[test]
+    fn test_wide_vectors_pay_where_the_cpu_prefers_narrower() {
+        let configs = vec![
+            VariantConfig::new(IsaExtension::Avx2, 8, 3),
+            VariantConfig::new(IsaExtension::Avx512, 4, 3),
+        ];
+        let best = |uarch| {
+            let rewards = prior_rewards_on(&configs, 4096, uarch);
+            configs[rewards.iter().position(|&r| r == 1.0).unwrap()].isa
+        };
+        assert_eq!(best(&GENERIC), IsaExtension::Avx512);
+        let skylake = UarchDefaults::for_x86(b"GenuineIntel", 0x00050654);
+        assert_eq!(best(&skylake), IsaExtension::Avx2);
+    }
+}
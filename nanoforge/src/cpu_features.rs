@@ -2,10 +2,13 @@
 //!
 //! Detects available ISA extensions at runtime to generate appropriate variants.
 
+use serde::Serialize;
 use std::arch::x86_64::__cpuid;
 
+pub use nanoforge_core::target::{Microarch, VectorWidth};
+
 /// Detected CPU features for variant generation
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
 pub struct CpuFeatures {
     pub has_sse2: bool,
     pub has_sse4_1: bool,
@@ -18,6 +21,10 @@ pub struct CpuFeatures {
     pub has_amx_bf16: bool,
     pub has_amx_int8: bool,
     pub has_amx_tile: bool,
+    pub has_popcnt: bool,
+    /// BMI1 (tzcnt)
+    pub has_bmi1: bool,
+    pub has_lzcnt: bool,
 }
 
 impl CpuFeatures {
@@ -25,6 +32,7 @@ impl CpuFeatures {
     pub fn detect() -> Self {
         let mut features = CpuFeatures::default();
 
+        #[allow(unused_unsafe)]
         unsafe {
             // Basic feature flags (CPUID EAX=1)
             let cpuid1 = __cpuid(1);
@@ -32,6 +40,7 @@ impl CpuFeatures {
             features.has_sse4_1 = (cpuid1.ecx & (1 << 19)) != 0;
             features.has_sse4_2 = (cpuid1.ecx & (1 << 20)) != 0;
             features.has_avx = (cpuid1.ecx & (1 << 28)) != 0;
+            features.has_popcnt = (cpuid1.ecx & (1 << 23)) != 0;
 
             // Extended feature flags (CPUID EAX=7, ECX=0)
             let cpuid7 = __cpuid(7);
@@ -39,6 +48,12 @@ impl CpuFeatures {
             features.has_avx512f = (cpuid7.ebx & (1 << 16)) != 0;
             features.has_avx512vl = (cpuid7.ebx & (1 << 31)) != 0;
             features.has_avx512bw = (cpuid7.ebx & (1 << 30)) != 0;
+            features.has_bmi1 = (cpuid7.ebx & (1 << 3)) != 0;
+
+            // LZCNT (CPUID EAX=0x80000001, ECX bit 5)
+            if __cpuid(0x8000_0000).eax >= 0x8000_0001 {
+                features.has_lzcnt = (__cpuid(0x8000_0001).ecx & (1 << 5)) != 0;
+            }
 
             // AMX features (CPUID EAX=7, ECX=0, EDX bits)
             features.has_amx_bf16 = (cpuid7.edx & (1 << 22)) != 0;
@@ -64,6 +79,17 @@ impl CpuFeatures {
         self.has_amx_tile && (self.has_amx_bf16 || self.has_amx_int8)
     }
 
+    /// Widest vector of 64-bit lanes the generated code can use here
+    pub fn vector_width(&self) -> VectorWidth {
+        if self.has_avx512f {
+            VectorWidth::Lanes8
+        } else if self.has_avx2 {
+            VectorWidth::Lanes4
+        } else {
+            VectorWidth::Lanes2
+        }
+    }
+
     /// Get a summary of detected features
     pub fn summary(&self) -> String {
         let mut features = vec![];
@@ -87,6 +113,27 @@ impl CpuFeatures {
         }
         features.join(", ")
     }
+
+    /// Whether this CPU has `feature`, named in lowercase ("avx2", "sse4.1",
+    /// "amx-tile", ...); false for names it doesn't know
+    pub fn has(&self, feature: &str) -> bool {
+        match feature {
+            "sse4.1" => self.has_sse4_1,
+            "sse4.2" => self.has_sse4_2,
+            "avx" => self.has_avx,
+            "avx2" => self.has_avx2,
+            "avx512f" => self.has_avx512f,
+            "avx512vl" => self.has_avx512vl,
+            "avx512bw" => self.has_avx512bw,
+            "amx-tile" => self.has_amx_tile,
+            "amx-bf16" => self.has_amx_bf16,
+            "amx-int8" => self.has_amx_int8,
+            "popcnt" => self.has_popcnt,
+            "bmi1" => self.has_bmi1,
+            "lzcnt" => self.has_lzcnt,
+            _ => false,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -100,4 +147,19 @@ mod tests {
         // At minimum, SSE2 should be available on any x86_64
         assert!(features.has_sse2);
     }
+
+    #[test]
+    fn test_vector_width() {
+        let avx2 = CpuFeatures {
+            has_avx2: true,
+            ..Default::default()
+        };
+        let avx512 = CpuFeatures {
+            has_avx512f: true,
+            ..avx2
+        };
+        assert_eq!(CpuFeatures::default().vector_width(), VectorWidth::Lanes2);
+        assert_eq!(avx2.vector_width(), VectorWidth::Lanes4);
+        assert_eq!(avx512.vector_width().bytes(), 64);
+    }
 }
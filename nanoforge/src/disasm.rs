@@ -0,0 +1,87 @@
+//! Disassembly of generated machine code.
+//!
+//! Used to review codegen output (snapshot tests, debugging). Code is decoded
+//! as if loaded at address 0 so branch targets are plain offsets into the buffer.
+
+use iced_x86::{Code, Decoder, DecoderOptions, Formatter, Instruction, IntelFormatter};
+
+/// Placeholder printed instead of 64-bit absolute immediates (e.g. libc addresses)
+pub const ABS64_PLACEHOLDER: &str = "<abs64>";
+
+/// Disassemble x86-64 code into one `offset: instruction` line per instruction.
+pub fn disassemble(code: &[u8]) -> String {
+    render(code, false)
+}
+
+/// Same as `disassemble`, but masks `mov r64, imm64` immediates.
+///
+/// The compiler embeds host addresses (malloc/free) this way, which change
+/// between runs because of ASLR. Everything else is position-independent.
+pub fn disassemble_normalized(code: &[u8]) -> String {
+    render(code, true)
+}
+
+/// Decode `code`, loaded at `ip`, into (offset, length, text) per instruction
+pub fn decode(code: &[u8], ip: usize) -> Vec<(usize, usize, String)> {
+    decode_with(code, ip, false)
+}
+
+fn render(code: &[u8], mask_abs64: bool) -> String {
+    decode_with(code, 0, mask_abs64)
+        .into_iter()
+        .map(|(offset, _, text)| format!("{:04x}: {}\n", offset, text))
+        .collect()
+}
+
+fn decode_with(code: &[u8], ip: usize, mask_abs64: bool) -> Vec<(usize, usize, String)> {
+    let mut decoder = Decoder::with_ip(64, code, ip as u64, DecoderOptions::NONE);
+    let mut formatter = IntelFormatter::new();
+    formatter.options_mut().set_hex_prefix("0x");
+    formatter.options_mut().set_hex_suffix("");
+    formatter.options_mut().set_uppercase_hex(false);
+    formatter.options_mut().set_space_after_operand_separator(true);
+
+    let mut out = Vec::new();
+    let mut instr = Instruction::default();
+
+    while decoder.can_decode() {
+        decoder.decode_out(&mut instr);
+        let mut text = String::new();
+
+        if mask_abs64 && instr.code() == Code::Mov_r64_imm64 {
+            formatter.format_mnemonic(&instr, &mut text);
+            text.push(' ');
+            formatter.format_operand(&instr, &mut text, 0).ok();
+            text.push_str(", ");
+            text.push_str(ABS64_PLACEHOLDER);
+        } else {
+            formatter.format(&instr, &mut text);
+        }
+
+        out.push((instr.ip() as usize, instr.len(), text));
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disassemble_basic() {
+        // mov rax, rdi ; add rax, 5 ; ret
+        let code = [0x48, 0x89, 0xf8, 0x48, 0x83, 0xc0, 0x05, 0xc3];
+        let text = disassemble(&code);
+        assert_eq!(text, "0000: mov rax, rdi\n0003: add rax, 5\n0007: ret\n");
+    }
+
+    #[test]
+    fn test_normalized_masks_abs64() {
+        // mov rax, 0x1122334455667788 ; ret
+        let code = [0x48, 0xb8, 0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11, 0xc3];
+        let text = disassemble_normalized(&code);
+        assert!(text.contains("<abs64>"), "{}", text);
+        assert!(!text.contains("1122334455667788"), "{}", text);
+    }
+}
@@ -0,0 +1,284 @@
+//! Runtime Dispatcher
+//!
+//! SOAE for native Rust code: register several implementations of one
+//! operation and NanoForge learns which is fastest for each input-size
+//! bucket. While a bucket is learning, each call goes to the variant its
+//! contextual bandit selects and is timed with rdtsc; after
+//! `trials_per_bucket` calls the bucket's best variant is cached and later
+//! calls dispatch straight to it. `load_table` fixes buckets up front from a
+//! reviewed `brain export`.
+//!
+//! ```
+//! use nanoforge::nf_dispatch;
+//!
+//! let sum = nf_dispatch! {
+//!     size: |v: &[i64]| v.len() as u64,
+//!     "iter" => |v: &[i64]| v.iter().sum::<i64>(),
+//!     "chunks" => |v: &[i64]| v.chunks(4).map(|c| c.iter().sum::<i64>()).sum(),
+//! };
+//! assert_eq!(sum.call(&[1, 2, 3, 4, 5]), 15);
+//! ```
+
+use crate::ai_optimizer::{ContextualBandit, OptimizationFeatures, SizeBucket};
+use crate::brain_report::DispatchTable;
+use crate::sandbox::rdtsc;
+use std::collections::HashMap;
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// One implementation of the operation
+pub type Variant<I, O> = Box<dyn Fn(&I) -> O + Send + Sync>;
+
+/// Winner slot value while a bucket is still learning
+const LEARNING: usize = usize::MAX;
+const BUCKETS: usize = 5;
+
+/// Picks among registered variants per input-size bucket at runtime
+pub struct Dispatcher<I: ?Sized, O> {
+    names: Vec<String>,
+    variants: Vec<Variant<I, O>>,
+    size_of: Box<dyn Fn(&I) -> u64 + Send + Sync>,
+    /// Cached variant per bucket (`LEARNING` until decided)
+    winners: [AtomicUsize; BUCKETS],
+    learning: Mutex<Learning>,
+    trials_per_bucket: u32,
+    seed: Option<u64>,
+}
+
+/// Bandit state, created on the first learning call
+#[derive(Default)]
+struct Learning {
+    bandit: Option<ContextualBandit>,
+    trials: HashMap<SizeBucket, u32>,
+    /// Best cycles per 1024 elements seen in each bucket
+    best: HashMap<SizeBucket, u64>,
+}
+
+impl<I: ?Sized, O> Dispatcher<I, O> {
+    /// `size_of` maps an input to the size its bucket is chosen by
+    pub fn new(size_of: impl Fn(&I) -> u64 + Send + Sync + 'static) -> Self {
+        Self {
+            names: Vec::new(),
+            variants: Vec::new(),
+            size_of: Box::new(size_of),
+            winners: std::array::from_fn(|_| AtomicUsize::new(LEARNING)),
+            learning: Mutex::new(Learning::default()),
+            trials_per_bucket: 64,
+            seed: None,
+        }
+    }
+
+    /// Register a variant
+    pub fn variant(mut self, name: &str, f: impl Fn(&I) -> O + Send + Sync + 'static) -> Self {
+        self.names.push(name.to_string());
+        self.variants.push(Box::new(f));
+        self
+    }
+
+    /// Timed calls per bucket before its winner is fixed
+    pub fn trials_per_bucket(mut self, trials: u32) -> Self {
+        self.trials_per_bucket = trials.max(1);
+        self
+    }
+
+    /// Make the bandit's exploration reproducible
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Run the operation on `input`
+    ///
+    /// # Panics
+    /// If no variant has been registered.
+    pub fn call(&self, input: &I) -> O {
+        assert!(!self.variants.is_empty(), "Dispatcher has no variants");
+        let context = OptimizationFeatures::new((self.size_of)(input));
+        let bucket = context.size_bucket();
+        let slot = &self.winners[bucket_index(bucket)];
+        let winner = slot.load(Ordering::Acquire);
+        if winner != LEARNING {
+            return (self.variants[winner])(input);
+        }
+
+        let selected = self.with_bandit(|bandit, _| bandit.select(&context));
+        // The lock isn't held while the variant runs, so variants may recurse
+        let start = rdtsc();
+        let output = black_box((self.variants[selected])(input));
+        let cycles = rdtsc().saturating_sub(start);
+
+        // Normalize so sizes within a bucket compare fairly
+        let scaled = (cycles as u128 * 1024 / context.input_size.max(1) as u128) as u64;
+        self.with_bandit(|bandit, learning| {
+            let best = learning.best.entry(bucket).or_insert(scaled);
+            *best = (*best).min(scaled);
+            bandit.update_with_performance(&context, selected, scaled.max(1), (*best).max(1));
+
+            let trials = learning.trials.entry(bucket).or_insert(0);
+            *trials += 1;
+            if *trials >= self.trials_per_bucket {
+                slot.store(bandit.get_best_for_context(&context), Ordering::Release);
+            }
+        });
+        output
+    }
+
+    fn with_bandit<R>(&self, f: impl FnOnce(&mut ContextualBandit, &mut Learning) -> R) -> R {
+        let mut guard = self.learning.lock().unwrap_or_else(|e| e.into_inner());
+        let learning = &mut *guard;
+        let mut bandit = learning.bandit.take().unwrap_or_else(|| match self.seed {
+            Some(seed) => ContextualBandit::with_seed(self.names.clone(), seed),
+            None => ContextualBandit::new(self.names.clone()),
+        });
+        let result = f(&mut bandit, learning);
+        learning.bandit = Some(bandit);
+        result
+    }
+
+    /// Variant names, in registration order
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    /// The cached variant for inputs of `size`, once that bucket has decided
+    pub fn winner(&self, size: u64) -> Option<&str> {
+        let winner =
+            self.winners[bucket_index(SizeBucket::from_size(size))].load(Ordering::Acquire);
+        self.names.get(winner).map(String::as_str)
+    }
+
+    /// Decided variant per bucket (`None` while still learning)
+    pub fn decisions(&self) -> Vec<(SizeBucket, Option<&str>)> {
+        SizeBucket::all()
+            .into_iter()
+            .map(|bucket| (bucket, self.winner(bucket.representative_size())))
+            .collect()
+    }
+
+    /// Fix each bucket `table` lists to its variant, e.g. a reviewed
+    /// `brain export` shipped with the application; other buckets keep
+    /// learning. Nothing changes if the table names an unknown variant.
+    pub fn load_table(&self, table: &DispatchTable) -> Result<(), String> {
+        let mut winners = Vec::new();
+        for (bucket, name) in &table.winners {
+            let index = self.names.iter().position(|n| n == name).ok_or_else(|| {
+                format!("The table picks {} for {}, but no such variant is registered", name, bucket)
+            })?;
+            winners.push((*bucket, index));
+        }
+        for (bucket, index) in winners {
+            self.winners[bucket_index(bucket)].store(index, Ordering::Release);
+        }
+        Ok(())
+    }
+
+    /// Forget everything learned, e.g. after the machine or workload changed
+    pub fn reset(&self) {
+        let mut learning = self.learning.lock().unwrap_or_else(|e| e.into_inner());
+        *learning = Learning::default();
+        for slot in &self.winners {
+            slot.store(LEARNING, Ordering::Release);
+        }
+    }
+}
+
+fn bucket_index(bucket: SizeBucket) -> usize {
+    match bucket {
+        SizeBucket::Tiny => 0,
+        SizeBucket::Small => 1,
+        SizeBucket::Medium => 2,
+        SizeBucket::Large => 3,
+        SizeBucket::Huge => 4,
+    }
+}
+
+/// Build a [`Dispatcher`](crate::dispatch::Dispatcher) from a size function
+/// and named variants:
+///
+/// ```
+/// # use nanoforge::nf_dispatch;
+/// let square = nf_dispatch! {
+///     size: |n: &u64| *n,
+///     "mul" => |n: &u64| n * n,
+///     "pow" => |n: &u64| n.pow(2),
+/// };
+/// assert_eq!(square.call(&12), 144);
+/// ```
+#[macro_export]
+macro_rules! nf_dispatch {
+    (size: $size:expr, $($name:literal => $variant:expr),+ $(,)?) => {
+        $crate::dispatch::Dispatcher::new($size)$(.variant($name, $variant))+
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Burn roughly `n` loop iterations
+    fn spin(n: u64) -> u64 {
+        (0..n).fold(0u64, |acc, i| black_box(acc.wrapping_add(i)))
+    }
+
+    #[test]
+    fn test_dispatch_learns_per_bucket_winner() {
+        // "linear" costs grow with the input, "fixed" pays a constant overhead
+        let dispatcher = nf_dispatch! {
+            size: |n: &u64| *n,
+            "linear" => |n: &u64| { spin(n * 20); *n + 1 },
+            "fixed" => |n: &u64| { spin(20_000); *n + 1 },
+        }
+        .trials_per_bucket(30)
+        .seed(5);
+
+        for _ in 0..30 {
+            assert_eq!(dispatcher.call(&8), 9);
+            assert_eq!(dispatcher.call(&100_000), 100_001);
+        }
+        assert_eq!(dispatcher.winner(8), Some("linear"));
+        assert_eq!(dispatcher.winner(100_000), Some("fixed"));
+        assert_eq!(dispatcher.winner(1000), None);
+
+        dispatcher.reset();
+        assert_eq!(dispatcher.winner(8), None);
+    }
+
+    #[test]
+    fn test_dispatch_over_slices() {
+        let sum = nf_dispatch! {
+            size: |v: &[i64]| v.len() as u64,
+            "iter" => |v: &[i64]| v.iter().sum::<i64>(),
+            "reversed" => |v: &[i64]| v.iter().rev().sum::<i64>(),
+        }
+        .trials_per_bucket(4);
+        let data: Vec<i64> = (1..=100).collect();
+        for _ in 0..10 {
+            assert_eq!(sum.call(&data), 5050);
+        }
+        assert!(sum.winner(100).is_some());
+        assert_eq!(sum.names(), ["iter", "reversed"]);
+    }
+
+    #[test]
+    fn test_loaded_table_skips_learning() {
+        let dispatcher = nf_dispatch! {
+            size: |n: &u64| *n,
+            "a" => |n: &u64| *n,
+            "b" => |n: &u64| *n + 1,
+        };
+        let table = DispatchTable {
+            winners: vec![(SizeBucket::Tiny, "b".to_string()), (SizeBucket::Huge, "a".to_string())],
+        };
+        dispatcher.load_table(&table).unwrap();
+        assert_eq!(dispatcher.winner(8), Some("b"));
+        assert_eq!(dispatcher.call(&8), 9);
+        assert_eq!(dispatcher.winner(1000), None);
+
+        let unknown = DispatchTable {
+            winners: vec![(SizeBucket::Small, "a".to_string()), (SizeBucket::Large, "c".to_string())],
+        };
+        assert!(dispatcher.load_table(&unknown).unwrap_err().contains("picks c for Large"));
+        assert_eq!(dispatcher.winner(100), None);
+    }
+}
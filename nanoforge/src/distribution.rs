@@ -0,0 +1,229 @@
+//! Input Distributions
+//!
+//! Real callers pass a spread of sizes, not one. An `InputDistribution`
+//! describes that spread and reduces it to a few weighted inputs
+//! (`points`), so a variant can be ranked by its expected cost rather than
+//! its cost at a single input. Specs, as the CLI takes them:
+//!
+//! - `1000`: always 1000
+//! - `uniform:16..65536`: uniformly from 16 up to, not including, 65536
+//! - `file:sizes.txt`: the sizes listed in the file (whitespace-separated,
+//!   `#` starts a comment); a size listed twice weighs twice as much
+
+use std::fmt;
+use std::str::FromStr;
+
+/// Inputs a distribution is reduced to unless asked for more
+pub const DEFAULT_POINTS: usize = 8;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InputDistribution {
+    Point(u64),
+    /// `low..high`, half-open
+    Uniform { low: u64, high: u64 },
+    /// Sizes read from `path`, sorted
+    Empirical { path: String, sizes: Vec<u64> },
+}
+
+/// One input standing in for part of a distribution
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WeightedInput {
+    pub input: u64,
+    /// Share of the distribution; the weights of `points` sum to 1
+    pub weight: f64,
+}
+
+impl InputDistribution {
+    /// Read the sizes of `file:` specs
+    pub fn from_file(path: &str) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path, e))?;
+        let mut sizes = Vec::new();
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or_default();
+            for word in line.split_whitespace() {
+                sizes.push(
+                    word.parse()
+                        .map_err(|_| format!("{}: '{}' is not a size", path, word))?,
+                );
+            }
+        }
+        if sizes.is_empty() {
+            return Err(format!("{} lists no sizes", path));
+        }
+        sizes.sort_unstable();
+        Ok(InputDistribution::Empirical {
+            path: path.to_string(),
+            sizes,
+        })
+    }
+
+    /// At most `n` inputs with weights: the middle of each of `n` equally
+    /// likely strata, with equal inputs merged. An empirical distribution
+    /// with no more than `n` distinct sizes is returned exactly.
+    pub fn points(&self, n: usize) -> Vec<WeightedInput> {
+        let n = n.max(1);
+        let raw: Vec<WeightedInput> = match self {
+            InputDistribution::Point(input) => vec![WeightedInput {
+                input: *input,
+                weight: 1.0,
+            }],
+            InputDistribution::Uniform { low, high } => {
+                let span = (high - low) as u128;
+                (0..n as u128)
+                    .map(|i| WeightedInput {
+                        input: low + ((2 * i + 1) * span / (2 * n as u128)) as u64,
+                        weight: 1.0 / n as f64,
+                    })
+                    .collect()
+            }
+            InputDistribution::Empirical { sizes, .. } => {
+                let mut distinct = sizes.clone();
+                distinct.dedup();
+                let share = 1.0 / sizes.len() as f64;
+                if distinct.len() <= n {
+                    sizes
+                        .iter()
+                        .map(|&input| WeightedInput {
+                            input,
+                            weight: share,
+                        })
+                        .collect()
+                } else {
+                    (0..n)
+                        .map(|i| WeightedInput {
+                            input: sizes[(2 * i + 1) * sizes.len() / (2 * n)],
+                            weight: 1.0 / n as f64,
+                        })
+                        .collect()
+                }
+            }
+        };
+        let mut points: Vec<WeightedInput> = Vec::with_capacity(raw.len());
+        for p in raw {
+            match points.last_mut() {
+                Some(last) if last.input == p.input => last.weight += p.weight,
+                _ => points.push(p),
+            }
+        }
+        points
+    }
+
+    /// The median: the input to show a single result for
+    pub fn median(&self) -> u64 {
+        match self {
+            InputDistribution::Point(input) => *input,
+            InputDistribution::Uniform { low, high } => low + (high - low) / 2,
+            InputDistribution::Empirical { sizes, .. } => sizes[sizes.len() / 2],
+        }
+    }
+}
+
+/// `Σ weight × cost` of `costs` measured at each of `points`
+pub fn expected(points: &[WeightedInput], costs: &[u64]) -> f64 {
+    points
+        .iter()
+        .zip(costs)
+        .map(|(p, &cost)| p.weight * cost as f64)
+        .sum()
+}
+
+impl FromStr for InputDistribution {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        if let Some(range) = s.strip_prefix("uniform:") {
+            let (low, high) = range
+                .split_once("..")
+                .ok_or_else(|| format!("expected uniform:LOW..HIGH, got '{}'", s))?;
+            let bound = |b: &str| {
+                b.trim()
+                    .parse::<u64>()
+                    .map_err(|_| format!("'{}' is not a size", b))
+            };
+            let (low, high) = (bound(low)?, bound(high)?);
+            if low >= high {
+                return Err(format!("uniform:{}..{} is empty", low, high));
+            }
+            return Ok(InputDistribution::Uniform { low, high });
+        }
+        if let Some(path) = s.strip_prefix("file:") {
+            return Self::from_file(path);
+        }
+        s.parse().map(InputDistribution::Point).map_err(|_| {
+            format!(
+                "unknown input spec '{}' (expected N, uniform:LOW..HIGH or file:PATH)",
+                s
+            )
+        })
+    }
+}
+
+impl fmt::Display for InputDistribution {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InputDistribution::Point(input) => write!(f, "{}", input),
+            InputDistribution::Uniform { low, high } => write!(f, "uniform:{}..{}", low, high),
+            InputDistribution::Empirical { path, sizes } => {
+                write!(f, "file:{} ({} sizes)", path, sizes.len())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inputs(points: &[WeightedInput]) -> Vec<(u64, f64)> {
+        points.iter().map(|p| (p.input, p.weight)).collect()
+    }
+
+    #[test]
+    fn test_parses_specs() {
+        assert_eq!("1000".parse(), Ok(InputDistribution::Point(1000)));
+        assert_eq!(
+            "uniform:16..65536".parse(),
+            Ok(InputDistribution::Uniform {
+                low: 16,
+                high: 65536
+            })
+        );
+        for bad in ["uniform:9..9", "uniform:1-5", "normal:3", "file:/nonexistent"] {
+            assert!(bad.parse::<InputDistribution>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_points_stratify_the_distribution() {
+        let uniform = InputDistribution::Uniform { low: 0, high: 400 };
+        assert_eq!(
+            inputs(&uniform.points(4)),
+            [(50, 0.25), (150, 0.25), (250, 0.25), (350, 0.25)]
+        );
+        // Narrower than the number of points: repeats merge
+        let narrow = InputDistribution::Uniform { low: 7, high: 9 };
+        assert_eq!(inputs(&narrow.points(4)), [(7, 0.5), (8, 0.5)]);
+        assert_eq!(narrow.median(), 8);
+
+        let costs = [10, 20, 30, 40];
+        assert_eq!(expected(&uniform.points(4), &costs), 25.0);
+    }
+
+    #[test]
+    fn test_reads_sizes_from_a_file() {
+        let path = std::env::temp_dir().join(format!("nf-sizes-{}.txt", std::process::id()));
+        std::fs::write(&path, "# sizes seen\n64 16\n64   # twice\n1024\n").unwrap();
+        let spec = format!("file:{}", path.display());
+        let dist: InputDistribution = spec.parse().unwrap();
+        assert_eq!(inputs(&dist.points(8)), [(16, 0.25), (64, 0.5), (1024, 0.25)]);
+        // Fewer points than distinct sizes: quantiles
+        assert_eq!(inputs(&dist.points(2)), [(64, 0.5), (1024, 0.5)]);
+        assert_eq!(dist.median(), 64);
+
+        std::fs::write(&path, "64 big\n").unwrap();
+        let err = spec.parse::<InputDistribution>().unwrap_err();
+        assert!(err.contains("'big' is not a size"), "{}", err);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
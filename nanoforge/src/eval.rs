@@ -0,0 +1,341 @@
+//! Single-Shot Evaluation
+//!
+//! `eval` runs an untrusted script from source to result in one call, for
+//! web playgrounds and grading systems. Every `SecurityLimits` field is
+//! enforced, not advisory:
+//!
+//! - `max_script_size`, `max_instructions` and `max_code_size` before anything
+//!   runs
+//! - `max_loop_iterations` as every function's fuel (per call), and the
+//!   stack guard against runaway recursion
+//! - `max_memory` through the sanitizer's allocation budget
+//! - `max_execution_ms` as the deadline of an isolated child process, which
+//!   also keeps crashes and stray stores away from the caller
+//!
+//! Scripts are compiled at `EVAL_OPT_LEVEL` with the memory sanitizer on, and
+//! the code verifier checks the machine code before it is mapped.
+
+use crate::compiled_program::CompiledProgram;
+use crate::compiler::CompileOptions;
+use crate::diagnostics::{self, Severity};
+use crate::error::{NanoForgeError, Result, SecurityLimits};
+use crate::isolated_runner::{self, IsolatedFailure};
+use crate::lowering;
+use crate::parser::Parser;
+use crate::passes::Pass;
+use crate::sanitizer::{self, FaultKind, SanitizerFault};
+use crate::status::Status;
+use std::time::{Duration, Instant};
+
+/// Optimization level for evaluated scripts: no vectorization or scheduling,
+/// so every memory access goes through a sanitizer check
+pub const EVAL_OPT_LEVEL: u8 = 1;
+
+/// What an evaluation produced
+#[derive(Debug, Clone, PartialEq)]
+pub struct EvalOutput {
+    /// `main`'s return value
+    pub result: i64,
+    /// IR instructions in the parsed program
+    pub instructions: usize,
+    /// Bytes of machine code generated
+    pub code_size: usize,
+    /// Wall time of the isolated run
+    pub elapsed: Duration,
+}
+
+/// Words the child reports: the result, the call's `Status` code and the
+/// sanitizer fault (kind, address, site, alloc site, offset, alloc size)
+const REPORT_WORDS: usize = 8;
+
+/// Parse, compile and run `source`'s `main` on `inputs` within `limits`
+pub fn eval(source: &str, inputs: &[i64], limits: &SecurityLimits) -> Result<EvalOutput> {
+    limits.check_script_size(source.len())?;
+    let ast = Parser::new().parse_ast(source).map_err(NanoForgeError::ParseError)?;
+    let found = diagnostics::check(&ast);
+    if diagnostics::has_errors(&found) {
+        let errors: Vec<String> = found
+            .iter()
+            .filter(|d| d.severity == Severity::Error)
+            .map(|d| d.to_string())
+            .collect();
+        return Err(NanoForgeError::ParseError(errors.join("\n")));
+    }
+    let prog = lowering::lower(&ast).map_err(NanoForgeError::ParseError)?;
+    let instructions = prog.functions.iter().map(|f| f.instructions.len()).sum();
+    limits.check_instruction_count(instructions)?;
+
+    // Compiled in rather than patched with `set_fuel`, so loops with a
+    // constant bound above the limit keep their fuel checks. Calls aren't
+    // folded either: that would run them here, outside the limits.
+    let fuel = limits.max_loop_iterations.min(i32::MAX as u64) as i32;
+    let options = CompileOptions::new(EVAL_OPT_LEVEL)
+        .sanitize(true)
+        .verify_code(true)
+        .fuel(fuel)
+        .disable_pass(Pass::FoldPureCalls);
+    let compiled = CompiledProgram::compile(&prog, &options).map_err(NanoForgeError::CompileError)?;
+    let code_size = compiled.code().code.len();
+    limits.check_code_size(code_size)?;
+
+    let main = compiled
+        .get_fn("main")
+        .ok_or_else(|| NanoForgeError::ExecutionError("no main() function".to_string()))?;
+    if main.arity() != inputs.len() {
+        return Err(NanoForgeError::ExecutionError(format!(
+            "main() takes {} argument(s), got {}",
+            main.arity(),
+            inputs.len()
+        )));
+    }
+
+    let guard = sanitizer::fork_guard();
+    let start = Instant::now();
+    let timeout = Duration::from_millis(limits.max_execution_ms);
+    let words = isolated_runner::run_forked(REPORT_WORDS, timeout, |report| {
+        drop(guard);
+        sanitizer::set_memory_limit(Some(limits.max_memory));
+        // The arity was checked above, so the call itself can't fail
+        let (result, status) = main.call_with_status(inputs).unwrap_or((0, Status::Ok));
+        report[0] = result;
+        report[1] = status.code() as i64;
+        report[2] = -1;
+        if let Some(fault) = sanitizer::take_fault() {
+            report[2..].copy_from_slice(&encode_fault(&fault));
+        }
+    });
+    let elapsed = start.elapsed();
+
+    let words = words.map_err(|failure| match failure {
+        IsolatedFailure::Timeout => NanoForgeError::ResourceLimitExceeded(format!(
+            "Execution time exceeds limit {} ms",
+            limits.max_execution_ms
+        )),
+        failure => NanoForgeError::ExecutionError(format!("main() {}", failure)),
+    })?;
+    if let Some(fault) = decode_fault(&words[2..]) {
+        if fault.kind == FaultKind::MemoryLimit {
+            return Err(NanoForgeError::ResourceLimitExceeded(format!(
+                "Allocation of {} bytes exceeds memory limit {} bytes",
+                fault.alloc_size.unwrap_or(0),
+                limits.max_memory
+            )));
+        }
+        let sites = &compiled.code().sanitizer_sites;
+        return Err(NanoForgeError::MemoryError(fault.report(sites)));
+    }
+    match Status::from_code(words[1]) {
+        Status::Ok => Ok(EvalOutput {
+            result: words[0],
+            instructions,
+            code_size,
+            elapsed,
+        }),
+        Status::FuelExhausted => Err(NanoForgeError::ResourceLimitExceeded(format!(
+            "Loop iterations exceed limit {}",
+            fuel
+        ))),
+        Status::StackOverflow => Err(NanoForgeError::ResourceLimitExceeded(
+            "Call depth exceeds the stack".to_string(),
+        )),
+        status => Err(NanoForgeError::ExecutionError(format!("main(): {}", status))),
+    }
+}
+
+fn encode_fault(fault: &SanitizerFault) -> [i64; 6] {
+    let kind = match fault.kind {
+        FaultKind::HeapBufferOverflow => 0,
+        FaultKind::UseAfterFree => 1,
+        FaultKind::DoubleFree => 2,
+        FaultKind::InvalidFree => 3,
+        FaultKind::MemoryLimit => 4,
+        FaultKind::OutsideBuffers => 5,
+    };
+    [
+        kind,
+        fault.address as i64,
+        fault.site as i64,
+        fault.alloc_site.map_or(-1, |s| s as i64),
+        fault.offset.map_or(i64::MIN, |o| o as i64),
+        fault.alloc_size.map_or(-1, |s| s as i64),
+    ]
+}
+
+fn decode_fault(words: &[i64]) -> Option<SanitizerFault> {
+    let kind = match words[0] {
+        0 => FaultKind::HeapBufferOverflow,
+        1 => FaultKind::UseAfterFree,
+        2 => FaultKind::DoubleFree,
+        3 => FaultKind::InvalidFree,
+        4 => FaultKind::MemoryLimit,
+        5 => FaultKind::OutsideBuffers,
+        _ => return None,
+    };
+    Some(SanitizerFault {
+        kind,
+        address: words[1] as usize,
+        site: words[2] as usize,
+        alloc_site: (words[3] >= 0).then_some(words[3] as usize),
+        offset: (words[4] != i64::MIN).then_some(words[4] as isize),
+        alloc_size: (words[5] >= 0).then_some(words[5] as usize),
+    })
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+
+    fn limits() -> SecurityLimits {
+        SecurityLimits::strict()
+    }
+
+    #[test]
+    fn test_evaluates_main_with_inputs() {
+        let src = "fn main(n, k) {
+            sum = 0
+            i = 0
+            while i < n {
+                t = i * k
+                sum = sum + t
+                i = i + 1
+            }
+            return sum
+        }";
+        let output = eval(src, &[10, 3], &limits()).unwrap();
+        assert_eq!(output.result, 135);
+        assert!(output.instructions > 0 && output.code_size > 0);
+
+        let err = eval(src, &[10], &limits()).unwrap_err();
+        assert!(err.to_string().contains("takes 2 argument(s), got 1"), "{}", err);
+        assert!(matches!(eval("fn main( {", &[], &limits()), Err(NanoForgeError::ParseError(_))));
+    }
+
+    #[test]
+    fn test_enforces_static_limits() {
+        let src = "fn main() {\nreturn 1\n}";
+        let tiny = |f: fn(&mut SecurityLimits)| {
+            let mut limits = limits();
+            f(&mut limits);
+            eval(src, &[], &limits).unwrap_err()
+        };
+        for err in [
+            tiny(|l| l.max_script_size = 4),
+            tiny(|l| l.max_instructions = 0),
+            tiny(|l| l.max_code_size = 4),
+        ] {
+            assert!(matches!(err, NanoForgeError::ResourceLimitExceeded(_)), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_enforces_fuel_time_and_memory() {
+        let spin = "fn main() {
+            i = 0
+            while i < 1000000 {
+                i = i + 1
+            }
+            return i
+        }";
+        let err = eval(spin, &[], &limits()).unwrap_err();
+        assert!(err.to_string().contains("Loop iterations exceed limit 100000"), "{}", err);
+
+        // Fuel is per call, so only the deadline stops ~10^10 iterations
+        // spread over many calls
+        let mut fast = limits();
+        fast.max_execution_ms = 200;
+        let nested = "fn main() {
+            sum = 0
+            i = 0
+            while i < 90000 {
+                t = inner(i)
+                sum = sum + t
+                i = i + 1
+            }
+            return sum
+        }
+        fn inner(x) {
+            j = 0
+            while j < 90000 {
+                j = j + 1
+            }
+            r = j + x
+            return r
+        }";
+        let err = eval(nested, &[], &fast).unwrap_err();
+        assert!(err.to_string().contains("Execution time exceeds limit 200 ms"), "{}", err);
+
+        let hog = "fn main() {
+            a = alloc(4096)
+            b = alloc(20000000)
+            i = 0
+            b[i] = 1
+            x = b[i]
+            return x
+        }";
+        let err = eval(hog, &[], &limits()).unwrap_err();
+        assert!(err.to_string().contains("exceeds memory limit 16777216"), "{}", err);
+        let mut roomy = limits();
+        roomy.max_memory = 32 * 1024 * 1024;
+        assert_eq!(eval(hog, &[], &roomy).unwrap().result, 1);
+    }
+
+    #[test]
+    fn test_constant_bound_loops_burn_fuel_above_the_limit() {
+        let src = "fn main() {
+            s = 0
+            i = 0
+            while i < 5000 {
+                s = s + i
+                i = i + 1
+            }
+            return s
+        }";
+        let mut tight = limits();
+        tight.max_loop_iterations = 100;
+        let err = eval(src, &[], &tight).unwrap_err();
+        assert!(matches!(err, NanoForgeError::ResourceLimitExceeded(_)), "{}", err);
+        assert_eq!(eval(src, &[], &limits()).unwrap().result, 12497500);
+
+        // Nor when it runs in a pure function called with a constant
+        let called = "fn main() {
+            n = 5000
+            s = tri(n)
+            return s
+        }
+        fn tri(n) {
+            s = 0
+            i = 0
+            while i < n {
+                s = s + i
+                i = i + 1
+            }
+            return s
+        }";
+        let err = eval(called, &[], &tight).unwrap_err();
+        assert!(matches!(err, NanoForgeError::ResourceLimitExceeded(_)), "{}", err);
+    }
+
+    #[test]
+    fn test_memory_errors_and_crashes_are_contained() {
+        let overflow = "fn main() {
+            a = alloc(2)
+            i = 2
+            a[i] = 7
+            x = a[i]
+            return x
+        }";
+        let err = eval(overflow, &[], &limits()).unwrap_err();
+        assert!(matches!(&err, NanoForgeError::MemoryError(r) if r.starts_with("heap-buffer-overflow")), "{}", err);
+
+        // Memory the sanitizer didn't allocate is unchecked, but a wild store
+        // only takes down the child
+        let wild = "fn main(p) {
+            i = 0
+            p[i] = 1
+            x = p[i]
+            return x
+        }";
+        let err = eval(wild, &[8], &limits()).unwrap_err();
+        assert!(err.to_string().contains("crashed with signal"), "{}", err);
+    }
+}
@@ -2,10 +2,23 @@
 //!
 //! Core engine that evolves code populations through selection,
 //! crossover, and mutation to discover optimal implementations.
+//!
+//! Fitness is cycles measured in the nanosecond sandbox. A genome only counts
+//! as faster than another when the gap exceeds both measurements' confidence
+//! intervals; within the noise, the shorter genome wins.
+//!
+//! Diversity (mean edit distance between genomes) is tracked per generation.
+//! When neither the best fitness nor diversity has improved for
+//! `stagnation_limit` generations, the bottom half of the next population is
+//! replaced: half restart from the seed, half are hypermutated survivors.
 
 use crate::ir::Function;
 use crate::mutator::{Genome, Mutator};
-use crate::validator::{TestCase, Validator, ValidatorConfig};
+use crate::shutdown;
+use crate::store::Schema;
+use crate::validator::{Fitness, TestCase, ValidationResult, Validator, ValidatorConfig};
+use serde::Serialize;
+use std::cmp::Ordering;
 use rand::prelude::*;
 
 /// Configuration for the evolution process
@@ -23,6 +36,10 @@ pub struct EvolutionConfig {
     pub elite_count: usize,
     /// Random seed for reproducibility
     pub seed: u64,
+    /// Stalled generations before a partial restart (0 never restarts)
+    pub stagnation_limit: u32,
+    /// Mutations applied to each genome a restart reseeds or hypermutates
+    pub hypermutation_steps: u32,
 }
 
 impl Default for EvolutionConfig {
@@ -34,18 +51,25 @@ impl Default for EvolutionConfig {
             tournament_size: 5,
             elite_count: 2,
             seed: 42,
+            stagnation_limit: 8,
+            hypermutation_steps: 6,
         }
     }
 }
 
 /// Result of a single generation's evolution
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct GenerationResult {
     pub generation: u32,
+    /// Cycles per call of the best genome
     pub best_fitness: f64,
     pub avg_fitness: f64,
     pub valid_count: usize,
     pub speedup_vs_baseline: f64,
+    /// Mean pairwise edit distance between genomes, normalized to 0..=1
+    pub diversity: f64,
+    /// Whether stagnation triggered a partial restart after this generation
+    pub restarted: bool,
 }
 
 /// Result of the evolution process
@@ -55,6 +79,42 @@ pub struct EvolutionResult {
     pub generations_run: u32,
     pub final_speedup: f64,
     pub history: Vec<GenerationResult>,
+    /// Partial restarts triggered by stagnation
+    pub restarts: u32,
+}
+
+/// What a run found, as flushed to `--state-dir`
+#[derive(Debug, Clone, Serialize)]
+pub struct EvolutionCheckpoint {
+    pub generations_run: u32,
+    pub final_speedup: f64,
+    /// Whether a shutdown request cut the run short
+    pub interrupted: bool,
+    /// The best genome's code, one instruction per line
+    pub best: Vec<String>,
+    pub history: Vec<GenerationResult>,
+}
+
+impl EvolutionCheckpoint {
+    pub const SCHEMA: Schema = Schema::new("evolution-checkpoint", 1);
+}
+
+impl EvolutionResult {
+    pub fn checkpoint(&self) -> EvolutionCheckpoint {
+        EvolutionCheckpoint {
+            generations_run: self.generations_run,
+            final_speedup: self.final_speedup,
+            interrupted: shutdown::requested(),
+            best: self.best_genome.instructions.iter().map(|i| i.to_string()).collect(),
+            history: self.history.clone(),
+        }
+    }
+
+    /// Re-check the winner independently of the fitness measured while
+    /// evolving; anything but `Valid` means it must not replace the seed
+    pub fn verify_winner(&self, test_cases: &[TestCase]) -> ValidationResult {
+        Validator::default().validate(&self.best_genome, test_cases)
+    }
 }
 
 /// The main evolution engine
@@ -64,7 +124,7 @@ pub struct EvolutionEngine {
     /// The best genome ever seen
     best_ever: Option<Genome>,
     /// Baseline fitness (original code's performance)
-    baseline_fitness: f64,
+    baseline_fitness: Option<Fitness>,
     /// Current generation number
     generation: u32,
     /// Configuration
@@ -79,6 +139,13 @@ pub struct EvolutionEngine {
     rng: StdRng,
     /// History of generation results
     history: Vec<GenerationResult>,
+    /// The seed genome, for restarts
+    seed_genome: Genome,
+    /// Generations since the best fitness or diversity last improved
+    stalled_generations: u32,
+    /// Highest diversity since then
+    diversity_peak: f64,
+    restarts: u32,
 }
 
 impl EvolutionEngine {
@@ -94,14 +161,12 @@ impl EvolutionEngine {
         let rng = StdRng::seed_from_u64(config.seed);
 
         // Initialize population with copies of seed (will be mutated)
-        let population: Vec<Genome> = (0..config.population_size)
-            .map(|_| seed_genome.clone())
-            .collect();
+        let population: Vec<Genome> = vec![seed_genome.clone(); config.population_size];
 
         Self {
             population,
             best_ever: None,
-            baseline_fitness: f64::MAX,
+            baseline_fitness: None,
             generation: 0,
             config,
             mutator,
@@ -109,18 +174,28 @@ impl EvolutionEngine {
             test_cases,
             rng,
             history: Vec::new(),
+            seed_genome,
+            stalled_generations: 0,
+            diversity_peak: 0.0,
+            restarts: 0,
         }
     }
 
     /// Establish baseline fitness from the seed genome
-    pub fn establish_baseline(&mut self) -> Option<f64> {
-        if let Some(genome) = self.population.first() {
-            if let Some(fitness) = self.validator.fitness(genome, &self.test_cases) {
-                self.baseline_fitness = fitness;
-                return Some(fitness);
-            }
+    pub fn establish_baseline(&mut self) -> Option<Fitness> {
+        let genome = self.population.first()?;
+        // The first measurement pays for page faults and frequency ramp-up
+        self.validator.fitness(genome, &self.test_cases)?;
+        self.baseline_fitness = self.validator.fitness(genome, &self.test_cases);
+        self.baseline_fitness
+    }
+
+    /// Speedup of `fitness` over the seed, 1.0 unless it is clearly faster
+    fn speedup(&self, fitness: Option<Fitness>) -> f64 {
+        match (self.baseline_fitness, fitness) {
+            (Some(base), Some(f)) if f.beats(&base) && f.cycles > 0.0 => base.cycles / f.cycles,
+            _ => 1.0,
         }
-        None
     }
 
     /// Run one generation of evolution
@@ -138,32 +213,43 @@ impl EvolutionEngine {
             .filter(|g| g.fitness.is_some())
             .cloned()
             .collect();
-        valid_genomes.sort_by(|a, b| a.fitness.unwrap().partial_cmp(&b.fitness.unwrap()).unwrap());
+        valid_genomes.sort_by(|a, b| a.fitness.unwrap().cycles.total_cmp(&b.fitness.unwrap().cycles));
 
-        // 3. Update best ever
+        // 3. Update best ever (only on a win beyond the measurement noise)
+        let mut improved = false;
         if let Some(best) = valid_genomes.first() {
-            if self.best_ever.is_none()
-                || best.fitness.unwrap() < self.best_ever.as_ref().unwrap().fitness.unwrap()
-            {
+            improved = match &self.best_ever {
+                None => true,
+                Some(current) => best.fitness.unwrap().beats(&current.fitness.unwrap()),
+            };
+            if improved {
                 self.best_ever = Some(best.clone());
             }
         }
 
+        // Stagnation: no new best and diversity below its peak since the last progress
+        let diversity = diversity(&self.population);
+        if improved || diversity > self.diversity_peak * (1.0 + DIVERSITY_GAIN) {
+            self.stalled_generations = 0;
+            self.diversity_peak = diversity;
+        } else {
+            self.stalled_generations += 1;
+        }
+        let restart = self.config.stagnation_limit > 0
+            && self.stalled_generations >= self.config.stagnation_limit
+            && !valid_genomes.is_empty();
+
         // 4. Calculate statistics
         let valid_count = valid_genomes.len();
         let (best_fitness, avg_fitness) = if valid_count > 0 {
-            let best = valid_genomes.first().unwrap().fitness.unwrap();
-            let sum: f64 = valid_genomes.iter().map(|g| g.fitness.unwrap()).sum();
+            let best = valid_genomes.first().unwrap().fitness.unwrap().cycles;
+            let sum: f64 = valid_genomes.iter().map(|g| g.fitness.unwrap().cycles).sum();
             (best, sum / valid_count as f64)
         } else {
             (f64::MAX, f64::MAX)
         };
 
-        let speedup = if best_fitness > 0.0 {
-            self.baseline_fitness / best_fitness
-        } else {
-            1.0
-        };
+        let speedup = self.speedup(valid_genomes.first().and_then(|g| g.fitness));
 
         // 5. Create next generation
         let mut next_population = Vec::with_capacity(self.config.population_size);
@@ -173,8 +259,15 @@ impl EvolutionEngine {
             next_population.push(elite.clone());
         }
 
+        // A restart takes the bottom half of the slots
+        let offspring_slots = if restart {
+            self.config.population_size - self.config.population_size / 2
+        } else {
+            self.config.population_size
+        };
+
         // Fill rest with offspring
-        while next_population.len() < self.config.population_size {
+        while next_population.len() < offspring_slots {
             // Tournament selection for parents (using indices to avoid borrow issues)
             let parent1_idx = self.tournament_select_idx(&valid_genomes);
             let parent2_idx = self.tournament_select_idx(&valid_genomes);
@@ -197,6 +290,27 @@ impl EvolutionEngine {
             next_population.push(child);
         }
 
+        if restart {
+            self.stalled_generations = 0;
+            self.diversity_peak = 0.0;
+            self.restarts += 1;
+            // Alternate fresh starts from the seed with hypermutated survivors, worst first
+            let mut worst = valid_genomes.iter().rev();
+            let mut fresh = true;
+            while next_population.len() < self.config.population_size {
+                let mut genome = match worst.next() {
+                    Some(survivor) if !fresh => survivor.clone(),
+                    _ => self.seed_genome.clone(),
+                };
+                self.mutator
+                    .hypermutate(&mut genome, self.config.hypermutation_steps);
+                genome.fitness = None;
+                genome.generation = self.generation;
+                next_population.push(genome);
+                fresh = !fresh;
+            }
+        }
+
         self.population = next_population;
 
         let result = GenerationResult {
@@ -205,6 +319,8 @@ impl EvolutionEngine {
             avg_fitness,
             valid_count,
             speedup_vs_baseline: speedup,
+            diversity,
+            restarted: restart,
         };
 
         self.history.push(result.clone());
@@ -226,25 +342,20 @@ impl EvolutionEngine {
             panic!("No valid candidates for selection");
         }
 
-        let mut best_idx = 0;
-        let mut best_fitness = f64::MAX;
+        let mut best_idx = self.rng.gen_range(0..candidates.len());
 
-        for _ in 0..self.config.tournament_size.min(candidates.len()) {
+        for _ in 1..self.config.tournament_size.min(candidates.len()) {
             let idx = self.rng.gen_range(0..candidates.len());
-            let candidate = &candidates[idx];
-
-            if let Some(fitness) = candidate.fitness {
-                if fitness < best_fitness {
-                    best_fitness = fitness;
-                    best_idx = idx;
-                }
+            if tournament_order(&candidates[idx], &candidates[best_idx]) == Ordering::Less {
+                best_idx = idx;
             }
         }
 
         best_idx
     }
 
-    /// Run evolution until target speedup or max generations
+    /// Run evolution until target speedup, max generations or a shutdown
+    /// request (see `shutdown`)
     pub fn run(&mut self, max_generations: u32, target_speedup: Option<f64>) -> EvolutionResult {
         // Establish baseline
         self.establish_baseline();
@@ -256,8 +367,11 @@ impl EvolutionEngine {
             }
         }
 
-        // Evolution loop
+        // Evolution loop; a requested shutdown keeps what was found so far
         for _ in 0..max_generations {
+            if shutdown::requested() {
+                break;
+            }
             let result = self.evolve_generation();
 
             // Check if target achieved
@@ -273,17 +387,14 @@ impl EvolutionEngine {
             .clone()
             .unwrap_or_else(|| self.population.first().cloned().unwrap());
 
-        let final_speedup = if let Some(fitness) = best_genome.fitness {
-            self.baseline_fitness / fitness
-        } else {
-            1.0
-        };
+        let final_speedup = self.speedup(best_genome.fitness);
 
         EvolutionResult {
             best_genome,
             generations_run: self.generation,
             final_speedup,
             history: self.history.clone(),
+            restarts: self.restarts,
         }
     }
 
@@ -298,6 +409,45 @@ impl EvolutionEngine {
     }
 }
 
+/// Relative diversity increase that still counts as the search progressing
+const DIVERSITY_GAIN: f64 = 0.01;
+
+/// Mean pairwise edit distance, each pair normalized by the longer genome
+pub fn diversity(population: &[Genome]) -> f64 {
+    let mut total = 0.0;
+    let mut pairs = 0;
+    for (i, a) in population.iter().enumerate() {
+        for b in &population[i + 1..] {
+            let longest = a.len().max(b.len());
+            if longest > 0 {
+                total += a.edit_distance(b) as f64 / longest as f64;
+            }
+            pairs += 1;
+        }
+    }
+    if pairs == 0 {
+        0.0
+    } else {
+        total / pairs as f64
+    }
+}
+
+/// Tournament order: measurably fewer cycles wins, and within each other's
+/// noise fewer instructions do. (Not transitive, so never used for sorting.)
+fn tournament_order(a: &Genome, b: &Genome) -> Ordering {
+    match (a.fitness, b.fitness) {
+        (Some(fa), Some(fb)) if fa.beats(&fb) => Ordering::Less,
+        (Some(fa), Some(fb)) if fb.beats(&fa) => Ordering::Greater,
+        (Some(fa), Some(fb)) => a
+            .len()
+            .cmp(&b.len())
+            .then(fa.cycles.total_cmp(&fb.cycles)),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => Ordering::Equal,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -327,6 +477,10 @@ mod tests {
                     src2: None,
                 },
             ],
+            hints: Default::default(),
+            loop_hints: Default::default(),
+            loops: Default::default(),
+            lines: Default::default(),
         }
     }
 
@@ -350,4 +504,78 @@ mod tests {
         assert_eq!(engine.population.len(), 10);
         assert_eq!(engine.current_generation(), 0);
     }
+
+    #[test]
+    fn test_stagnation_triggers_restarts() {
+        let func = create_test_function();
+        let config = EvolutionConfig {
+            population_size: 6,
+            stagnation_limit: 1,
+            ..Default::default()
+        };
+        let mut engine = EvolutionEngine::new(&func, vec![TestCase::new(10, 11)], config);
+        let result = engine.run(8, None);
+
+        assert!(result.restarts > 0);
+        let flagged = result.history.iter().filter(|g| g.restarted).count();
+        assert_eq!(flagged as u32, result.restarts);
+        assert!(result.history.iter().all(|g| (0.0..=1.0).contains(&g.diversity)));
+        assert_eq!(engine.population.len(), 6);
+    }
+
+    #[test]
+    fn test_wrong_winner_fails_verification() {
+        let cases = [TestCase::new(10, 11), TestCase::new(100, 101)];
+        let seed = Genome::from_function(&create_test_function());
+        let mut result = EvolutionResult {
+            best_genome: seed.clone(),
+            generations_run: 1,
+            final_speedup: 1.0,
+            history: vec![],
+            restarts: 0,
+        };
+        assert!(result.verify_winner(&cases).is_valid());
+
+        // x + 2 instead of x + 1
+        result.best_genome.instructions[1].src1 = Some(Operand::Imm(2));
+        assert_eq!(
+            result.verify_winner(&cases),
+            ValidationResult::WrongOutput {
+                expected: 11,
+                actual: 12
+            }
+        );
+    }
+
+    #[test]
+    fn test_diversity() {
+        let seed = Genome::from_function(&create_test_function());
+        assert_eq!(diversity(&[seed.clone(), seed.clone()]), 0.0);
+
+        let mut other = seed.clone();
+        other.instructions[1].src1 = Some(Operand::Imm(2));
+        // One of three instructions differs, in one of three pairs
+        let d = diversity(&[seed.clone(), seed, other]);
+        assert!((d - 2.0 / 9.0).abs() < 1e-9, "{}", d);
+    }
+
+    #[test]
+    fn test_tournament_order_ignores_noise() {
+        let seed = Genome::from_function(&create_test_function());
+        let mut shorter = seed.clone();
+        shorter.instructions.remove(1);
+        let with = |mut g: Genome, cycles: f64| {
+            g.fitness = Some(Fitness { cycles, ci95: 0.05 });
+            g
+        };
+
+        // Within each other's noise: the shorter genome wins either way
+        let (a, b) = (with(seed.clone(), 100.0), with(shorter.clone(), 104.0));
+        assert_eq!(tournament_order(&b, &a), Ordering::Less);
+        // A real gap in cycles beats length
+        let (a, b) = (with(seed, 50.0), with(shorter, 104.0));
+        assert_eq!(tournament_order(&a, &b), Ordering::Less);
+        assert_eq!(tournament_order(&b, &a), Ordering::Greater);
+    }
 }
+
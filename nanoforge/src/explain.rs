@@ -0,0 +1,166 @@
+//! Source ↔ IR ↔ Machine Code View
+//!
+//! The compiler marks where the code of each IR instruction starts
+//! (`JitBuilder::mark`) and keeps the source line each instruction was
+//! lowered from (`Function::carry_lines`). `explain` lays the three side by
+//! side: every source line, the IR it became, and the bytes and assembly of
+//! each IR instruction.
+//!
+//! Lines are carried across the passes by matching instructions, so code
+//! the optimizer made up (unrolled copies, vector loops) shows under the
+//! line it came closest to. Prologues, epilogues and trap stubs belong to
+//! no IR instruction and are left out.
+
+use crate::compiler::CompiledCode;
+use crate::disasm;
+use crate::ir::Instruction;
+use std::fmt::Write;
+use std::ops::Range;
+
+/// Width of the IR column
+const IR_WIDTH: usize = 34;
+
+/// Width of the bytes column (longer encodings overflow it)
+const BYTES_WIDTH: usize = 24;
+
+/// Code of one IR instruction
+#[derive(Debug, Clone, PartialEq)]
+pub struct MappedInstruction {
+    pub function: String,
+    /// Index into the function's (optimized) instruction list
+    pub index: usize,
+    pub instruction: String,
+    /// Source line it was lowered from (0: none)
+    pub line: usize,
+    /// Offsets of its code (empty if it emits none, like most labels)
+    pub code: Range<usize>,
+}
+
+impl MappedInstruction {
+    pub fn new(function: &str, index: usize, instr: &Instruction, line: usize, code: Range<usize>) -> Self {
+        Self {
+            function: function.to_string(),
+            index,
+            instruction: instr.to_string(),
+            line,
+            code,
+        }
+    }
+}
+
+/// Side-by-side view of `code`, compiled from `source`
+pub fn explain(source: &str, code: &CompiledCode) -> String {
+    let lines: Vec<&str> = source.lines().collect();
+    let mut out = String::new();
+    let mut function = None;
+    let mut line = None;
+    for mapped in &code.source_map {
+        if function != Some(&mapped.function) {
+            function = Some(&mapped.function);
+            line = None;
+            let entry = code.function_offsets.get(&mapped.function).copied().unwrap_or(0);
+            writeln!(out, "\nfn {} @ {:04x}", mapped.function, entry).ok();
+        }
+        if line != Some(mapped.line) {
+            line = Some(mapped.line);
+            match lines.get(mapped.line.wrapping_sub(1)) {
+                Some(text) => writeln!(out, "{:>4} | {}", mapped.line, text.trim()).ok(),
+                None => writeln!(out, "   - | (no source line)").ok(),
+            };
+        }
+        let decoded = disasm::decode(&code.code[mapped.code.clone()], mapped.code.start);
+        if decoded.is_empty() {
+            writeln!(out, "       {}", mapped.instruction).ok();
+        }
+        for (i, (offset, len, text)) in decoded.iter().enumerate() {
+            let ir = if i == 0 { mapped.instruction.as_str() } else { "" };
+            let bytes: Vec<String> =
+                code.code[*offset..offset + len].iter().map(|b| format!("{:02x}", b)).collect();
+            writeln!(
+                out,
+                "       {:<ir_width$} {:04x}: {:<bytes_width$} {}",
+                ir,
+                offset,
+                bytes.join(" "),
+                text,
+                ir_width = IR_WIDTH,
+                bytes_width = BYTES_WIDTH,
+            )
+            .ok();
+        }
+    }
+    out.trim_start().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::{CompileOptions, Compiler};
+    use crate::parser::Parser;
+
+    const SRC: &str = "fn main(n) {
+    s = 0
+    i = 0
+    while i < n {
+        s = s + i
+        i = i + 1
+    }
+    return s
+}";
+
+    #[test]
+    fn test_source_map_covers_each_instruction() {
+        let prog = Parser::new().parse(SRC).unwrap();
+        for level in [0, 3] {
+            let code = Compiler::compile_with_options(&prog, &CompileOptions::new(level)).unwrap();
+            let main = &code.source_map;
+            assert!(!main.is_empty());
+            // In order, back to back, inside the code
+            for pair in main.windows(2) {
+                assert_eq!(pair[0].index + 1, pair[1].index);
+                assert_eq!(pair[0].code.end, pair[1].code.start);
+            }
+            assert!(main.last().unwrap().code.end <= code.code.len());
+            assert!(main.iter().all(|m| (1..=9).contains(&m.line)), "{:?}", main);
+        }
+    }
+
+    #[test]
+    fn test_explain_lays_source_ir_and_code_side_by_side() {
+        let prog = Parser::new().parse(SRC).unwrap();
+        let code = Compiler::compile_with_options(&prog, &CompileOptions::new(0)).unwrap();
+        let text = explain(SRC, &code);
+        assert!(text.starts_with("fn main @ 0000\n   1 | fn main(n) {\n"), "{}", text);
+        let body = text.find("   5 | s = s + i\n").expect(&text);
+        let add = &text[body..].lines().nth(1).unwrap();
+        assert!(add.trim_start().starts_with("Add Reg("), "{}", text);
+        assert!(add.contains(" add "), "{}", text);
+        assert!(text.contains("   8 | return s\n"), "{}", text);
+    }
+
+    #[test]
+    fn test_unrolled_copies_keep_their_line() {
+        let src = "fn main() {
+            a = alloc(64)
+            for (i = 0; i < 64; i = i + 1) {
+                a[i] = i
+            }
+            s = 0
+            for (i = 0; i < 64; i = i + 1) {
+                v = a[i]
+                s = s + v
+            }
+            free(a)
+            return s
+        }";
+        let prog = Parser::new().parse(src).unwrap();
+        let code = Compiler::compile_with_options(&prog, &CompileOptions::new(2)).unwrap();
+        let lines_of = |op: &str| -> Vec<usize> {
+            let mapped = code.source_map.iter().filter(|m| m.instruction.starts_with(op));
+            mapped.map(|m| m.line).collect()
+        };
+        let (stores, loads) = (lines_of("Store"), lines_of("Load("));
+        assert!(stores.len() > 1 && stores.iter().all(|&l| l == 4), "{:?}", stores);
+        assert!(loads.len() > 1 && loads.iter().all(|&l| l == 8), "{:?}", loads);
+    }
+}
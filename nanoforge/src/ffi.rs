@@ -6,7 +6,7 @@
 use crate::ai_optimizer::{ContextualBandit, OptimizationFeatures};
 use crate::cpu_features::CpuFeatures;
 use crate::parser::Parser;
-use crate::variant_generator::VariantGenerator;
+use crate::variant_generator::{CompiledVariant, VariantGenerator};
 use std::ffi::{CStr, CString};
 use std::os::raw::c_char;
 use std::path::Path;
@@ -15,7 +15,8 @@ use std::ptr;
 /// Opaque handle to a compiled function
 #[repr(C)]
 pub struct NanoFunction {
-    func_ptr: extern "C" fn(u64) -> u64,
+    /// Owns the code `execute` jumps into
+    variant: CompiledVariant,
 }
 
 /// Opaque handle to the AI optimizer
@@ -53,8 +54,11 @@ pub extern "C" fn nanoforge_init() -> *mut c_char {
 }
 
 /// Free a string returned by NanoForge
+///
+/// # Safety
+/// `s` must be null or a string returned by `nanoforge_init` that was not freed yet.
 #[no_mangle]
-pub extern "C" fn nanoforge_free_string(s: *mut c_char) {
+pub unsafe extern "C" fn nanoforge_free_string(s: *mut c_char) {
     if !s.is_null() {
         unsafe {
             let _ = CString::from_raw(s);
@@ -64,8 +68,11 @@ pub extern "C" fn nanoforge_free_string(s: *mut c_char) {
 
 /// Compile a NanoForge script and return the best function
 /// Returns null on failure
+///
+/// # Safety
+/// `source` must be null or point to a NUL-terminated string.
 #[no_mangle]
-pub extern "C" fn nanoforge_compile(source: *const c_char) -> *mut NanoFunction {
+pub unsafe extern "C" fn nanoforge_compile(source: *const c_char) -> *mut NanoFunction {
     if source.is_null() {
         return ptr::null_mut();
     }
@@ -82,7 +89,7 @@ pub extern "C" fn nanoforge_compile(source: *const c_char) -> *mut NanoFunction
     };
 
     let generator = VariantGenerator::new();
-    let variants = match generator.generate_variants(&program) {
+    let mut variants = match generator.generate_variants(&program) {
         Ok(v) => v,
         Err(_) => return ptr::null_mut(),
     };
@@ -93,25 +100,31 @@ pub extern "C" fn nanoforge_compile(source: *const c_char) -> *mut NanoFunction
     }
 
     let func = Box::new(NanoFunction {
-        func_ptr: variants[0].func_ptr,
+        variant: variants.swap_remove(0),
     });
 
     Box::into_raw(func)
 }
 
 /// Execute a compiled function
+///
+/// # Safety
+/// `func` must be null or a live handle returned by `nanoforge_compile`.
 #[no_mangle]
-pub extern "C" fn nanoforge_execute(func: *const NanoFunction, input: u64) -> u64 {
+pub unsafe extern "C" fn nanoforge_execute(func: *const NanoFunction, input: u64) -> u64 {
     if func.is_null() {
         return 0;
     }
     let f = unsafe { &*func };
-    (f.func_ptr)(input)
+    f.variant.execute(input)
 }
 
 /// Free a compiled function
+///
+/// # Safety
+/// `func` must be null or a handle returned by `nanoforge_compile` that was not freed yet.
 #[no_mangle]
-pub extern "C" fn nanoforge_free_function(func: *mut NanoFunction) {
+pub unsafe extern "C" fn nanoforge_free_function(func: *mut NanoFunction) {
     if !func.is_null() {
         unsafe {
             let _ = Box::from_raw(func);
@@ -140,8 +153,11 @@ pub extern "C" fn nanoforge_optimizer_new() -> *mut NanoOptimizer {
 
 /// Select variant using AI optimizer
 /// Returns the index of the selected variant
+///
+/// # Safety
+/// `opt` must be null or a live optimizer handle not used concurrently.
 #[no_mangle]
-pub extern "C" fn nanoforge_optimizer_select(opt: *mut NanoOptimizer, input_size: u64) -> i32 {
+pub unsafe extern "C" fn nanoforge_optimizer_select(opt: *mut NanoOptimizer, input_size: u64) -> i32 {
     if opt.is_null() {
         return -1;
     }
@@ -151,8 +167,11 @@ pub extern "C" fn nanoforge_optimizer_select(opt: *mut NanoOptimizer, input_size
 }
 
 /// Update AI optimizer with feedback
+///
+/// # Safety
+/// `opt` must be null or a live optimizer handle not used concurrently.
 #[no_mangle]
-pub extern "C" fn nanoforge_optimizer_update(
+pub unsafe extern "C" fn nanoforge_optimizer_update(
     opt: *mut NanoOptimizer,
     input_size: u64,
     variant_idx: i32,
@@ -170,8 +189,12 @@ pub extern "C" fn nanoforge_optimizer_update(
 }
 
 /// Save AI optimizer to file
+///
+/// # Safety
+/// `opt` must be null or a live optimizer handle, and `path` null or a
+/// NUL-terminated string.
 #[no_mangle]
-pub extern "C" fn nanoforge_optimizer_save(
+pub unsafe extern "C" fn nanoforge_optimizer_save(
     opt: *const NanoOptimizer,
     path: *const c_char,
 ) -> NanoResult {
@@ -192,8 +215,11 @@ pub extern "C" fn nanoforge_optimizer_save(
 }
 
 /// Load AI optimizer from file
+///
+/// # Safety
+/// `path` must be null or point to a NUL-terminated string.
 #[no_mangle]
-pub extern "C" fn nanoforge_optimizer_load(path: *const c_char) -> *mut NanoOptimizer {
+pub unsafe extern "C" fn nanoforge_optimizer_load(path: *const c_char) -> *mut NanoOptimizer {
     if path.is_null() {
         return ptr::null_mut();
     }
@@ -221,8 +247,11 @@ pub extern "C" fn nanoforge_optimizer_load(path: *const c_char) -> *mut NanoOpti
 }
 
 /// Free AI optimizer
+///
+/// # Safety
+/// `opt` must be null or an optimizer handle that was not freed yet.
 #[no_mangle]
-pub extern "C" fn nanoforge_optimizer_free(opt: *mut NanoOptimizer) {
+pub unsafe extern "C" fn nanoforge_optimizer_free(opt: *mut NanoOptimizer) {
     if !opt.is_null() {
         unsafe {
             let _ = Box::from_raw(opt);
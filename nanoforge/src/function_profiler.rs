@@ -0,0 +1,293 @@
+//! Per-Function Profiling
+//!
+//! Code compiled with `CompileOptions::profile` calls `nf_prof_enter(id)` right
+//! after each function's prologue and `nf_prof_exit(id)` before every return.
+//! The hooks read a perf counter group (cycles and instructions in one read),
+//! or the TSC when perf is unavailable, and charge the difference to the
+//! function twice: inclusive ("total") and exclusive of callees ("self").
+//!
+//! Hooks are only live while a `ProfileSession` is active on the calling
+//! thread; otherwise they return immediately.
+
+use crate::profiler::{CounterGroup, CounterSample};
+use crate::sandbox::rdtsc;
+use std::cell::RefCell;
+
+/// Where the cycle counts came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CounterSource {
+    /// perf_event group: core cycles and retired instructions
+    Perf,
+    /// Time-stamp counter only (reference cycles, no instruction counts)
+    Tsc,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FunctionStats {
+    pub calls: u64,
+    pub self_cycles: u64,
+    /// Includes callees (recursive calls are counted at every level)
+    pub total_cycles: u64,
+    pub self_instructions: Option<u64>,
+    pub total_instructions: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionProfile {
+    pub name: String,
+    pub stats: FunctionStats,
+}
+
+/// Per-function cycles/instructions for one profiled run
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProfileReport {
+    pub source: CounterSource,
+    pub functions: Vec<FunctionProfile>,
+}
+
+impl ProfileReport {
+    pub fn get(&self, name: &str) -> Option<&FunctionStats> {
+        self.functions
+            .iter()
+            .find(|f| f.name == name)
+            .map(|f| &f.stats)
+    }
+
+    pub fn total_self_cycles(&self) -> u64 {
+        self.functions.iter().map(|f| f.stats.self_cycles).sum()
+    }
+
+    /// Share of all self cycles spent in `name`
+    pub fn self_share(&self, name: &str) -> f64 {
+        let total = self.total_self_cycles();
+        match self.get(name) {
+            Some(s) if total > 0 => s.self_cycles as f64 / total as f64,
+            _ => 0.0,
+        }
+    }
+
+    /// Functions that were called, hottest (most self cycles) first
+    pub fn by_self_cycles(&self) -> Vec<&FunctionProfile> {
+        let mut called: Vec<&FunctionProfile> = self
+            .functions
+            .iter()
+            .filter(|f| f.stats.calls > 0)
+            .collect();
+        called.sort_by_key(|f| std::cmp::Reverse(f.stats.self_cycles));
+        called
+    }
+
+    /// Functions worth promoting to a higher tier: at least `min_share` of self cycles
+    pub fn hot_functions(&self, min_share: f64) -> Vec<&str> {
+        self.by_self_cycles()
+            .into_iter()
+            .filter(|f| self.self_share(&f.name) >= min_share)
+            .map(|f| f.name.as_str())
+            .collect()
+    }
+
+    pub fn summary(&self) -> String {
+        let unit = match self.source {
+            CounterSource::Perf => "cycles",
+            CounterSource::Tsc => "TSC ticks",
+        };
+        let mut out = format!("Per-function profile ({}):\n", unit);
+        out.push_str(&format!(
+            "  {:<16} {:>8} {:>14} {:>14} {:>7} {:>6}\n",
+            "function", "calls", "self", "total", "self%", "IPC"
+        ));
+        for f in self.by_self_cycles() {
+            let ipc = match f.stats.self_instructions {
+                Some(i) if f.stats.self_cycles > 0 => {
+                    format!("{:.2}", i as f64 / f.stats.self_cycles as f64)
+                }
+                _ => "-".to_string(),
+            };
+            out.push_str(&format!(
+                "  {:<16} {:>8} {:>14} {:>14} {:>6.1}% {:>6}\n",
+                f.name,
+                f.stats.calls,
+                f.stats.self_cycles,
+                f.stats.total_cycles,
+                self.self_share(&f.name) * 100.0,
+                ipc
+            ));
+        }
+        out
+    }
+}
+
+struct OpenCall {
+    id: usize,
+    start: CounterSample,
+    /// Inclusive counts of callees that already returned
+    children: CounterSample,
+}
+
+struct Session {
+    counters: Option<CounterGroup>,
+    stats: Vec<FunctionStats>,
+    stack: Vec<OpenCall>,
+}
+
+impl Session {
+    fn sample(&self) -> CounterSample {
+        match &self.counters {
+            Some(group) => group.read(),
+            None => CounterSample {
+                cycles: rdtsc(),
+                instructions: None,
+            },
+        }
+    }
+}
+
+thread_local! {
+    static SESSION: RefCell<Option<Session>> = const { RefCell::new(None) };
+}
+
+/// Collects per-function counts on this thread until `finish`
+pub struct ProfileSession {
+    names: Vec<String>,
+    source: CounterSource,
+}
+
+impl ProfileSession {
+    /// Start profiling; `functions` maps hook ids to names
+    /// (`CompiledCode::profiled_functions`). Replaces any session already active.
+    pub fn start(functions: &[String]) -> Self {
+        let counters = CounterGroup::new().ok();
+        let source = if counters.is_some() {
+            CounterSource::Perf
+        } else {
+            CounterSource::Tsc
+        };
+        SESSION.with(|s| {
+            *s.borrow_mut() = Some(Session {
+                counters,
+                stats: vec![FunctionStats::default(); functions.len()],
+                stack: Vec::new(),
+            })
+        });
+        Self {
+            names: functions.to_vec(),
+            source,
+        }
+    }
+
+    pub fn finish(self) -> ProfileReport {
+        let stats = SESSION
+            .with(|s| s.borrow_mut().take())
+            .map(|s| s.stats)
+            .unwrap_or_default();
+        ProfileReport {
+            source: self.source,
+            functions: self
+                .names
+                .iter()
+                .cloned()
+                .zip(stats)
+                .map(|(name, stats)| FunctionProfile { name, stats })
+                .collect(),
+        }
+    }
+}
+
+/// Function entry hook (called from JIT code)
+pub extern "C" fn nf_prof_enter(id: i64) {
+    SESSION.with(|s| {
+        if let Some(session) = s.borrow_mut().as_mut() {
+            let start = session.sample();
+            session.stack.push(OpenCall {
+                id: id as usize,
+                start,
+                children: CounterSample::default(),
+            });
+        }
+    });
+}
+
+/// Function exit hook (called from JIT code)
+pub extern "C" fn nf_prof_exit(id: i64) {
+    SESSION.with(|s| {
+        let mut slot = s.borrow_mut();
+        let Some(session) = slot.as_mut() else {
+            return;
+        };
+        let now = session.sample();
+        let Some(call) = session.stack.pop() else {
+            return;
+        };
+        debug_assert_eq!(call.id, id as usize);
+
+        let cycles = now.cycles.saturating_sub(call.start.cycles);
+        let instructions = match (now.instructions, call.start.instructions) {
+            (Some(n), Some(s)) => Some(n.saturating_sub(s)),
+            _ => None,
+        };
+        let self_instructions = match (instructions, call.children.instructions) {
+            (Some(i), Some(c)) => Some(i.saturating_sub(c)),
+            (Some(i), None) => Some(i),
+            _ => None,
+        };
+
+        if let Some(stats) = session.stats.get_mut(call.id) {
+            stats.calls += 1;
+            stats.total_cycles += cycles;
+            stats.self_cycles += cycles.saturating_sub(call.children.cycles);
+            if let Some(i) = instructions {
+                *stats.total_instructions.get_or_insert(0) += i;
+            }
+            if let Some(i) = self_instructions {
+                *stats.self_instructions.get_or_insert(0) += i;
+            }
+        }
+
+        if let Some(parent) = session.stack.last_mut() {
+            parent.children.cycles += cycles;
+            if let Some(i) = instructions {
+                *parent.children.instructions.get_or_insert(0) += i;
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spin(n: u64) -> u64 {
+        (0..n).fold(0u64, |a, i| std::hint::black_box(a.wrapping_add(i)))
+    }
+
+    #[test]
+    fn test_self_excludes_callees() {
+        let session = ProfileSession::start(&["outer".to_string(), "inner".to_string()]);
+        nf_prof_enter(0);
+        spin(1000);
+        for _ in 0..3 {
+            nf_prof_enter(1);
+            spin(100_000);
+            nf_prof_exit(1);
+        }
+        nf_prof_exit(0);
+        let report = session.finish();
+
+        let outer = report.get("outer").unwrap();
+        let inner = report.get("inner").unwrap();
+        assert_eq!((outer.calls, inner.calls), (1, 3));
+        assert!(outer.total_cycles >= inner.total_cycles);
+        assert_eq!(outer.self_cycles, outer.total_cycles - inner.total_cycles);
+        assert_eq!(report.hot_functions(0.5), vec!["inner"]);
+        assert!(report.summary().contains("inner"));
+    }
+
+    #[test]
+    fn test_hooks_are_inert_without_session() {
+        nf_prof_enter(0);
+        nf_prof_exit(0);
+        let report = ProfileSession::start(&["main".to_string()]).finish();
+        assert_eq!(report.get("main").unwrap().calls, 0);
+        assert!(report.hot_functions(0.0).is_empty());
+    }
+}
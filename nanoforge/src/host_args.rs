@@ -0,0 +1,299 @@
+//! Host Array Arguments
+//!
+//! Lets the host hand pre-allocated buffers to a script's `main`. Each bound
+//! array occupies two consecutive parameters: the pointer, then the element
+//! count. `fn main(a, n)` bound with `a=data.bin` receives `a = ptr, n = len`.
+//!
+//! Arrays are 64-bit elements (the stride NanoForge `Load`/`Store` use).
+
+use crate::ir::Function;
+use std::fs::File;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::ptr;
+
+/// Maximum number of integer arguments `main` can receive (rdi, rsi, rdx, rcx)
+pub const MAX_ARGS: usize = 4;
+
+enum Backing {
+    Owned(Vec<i64>),
+    Mapped {
+        addr: *mut libc::c_void,
+        bytes: usize,
+    },
+}
+
+/// A host buffer that a script can read and write through a pointer argument
+pub struct HostArray {
+    backing: Backing,
+    len: usize,
+}
+
+impl HostArray {
+    /// Wrap an owned vector
+    pub fn from_vec(data: Vec<i64>) -> Self {
+        let len = data.len();
+        Self {
+            backing: Backing::Owned(data),
+            len,
+        }
+    }
+
+    /// Map a file of little-endian i64 values.
+    ///
+    /// The mapping is private (copy-on-write): the script may write to the
+    /// array, but the file on disk is never modified.
+    pub fn map_file(path: &Path) -> Result<Self, String> {
+        let file =
+            File::open(path).map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+        let bytes = file
+            .metadata()
+            .map_err(|e| format!("Failed to stat {}: {}", path.display(), e))?
+            .len() as usize;
+
+        if !bytes.is_multiple_of(8) {
+            return Err(format!(
+                "{}: size {} is not a multiple of 8 (expected i64 elements)",
+                path.display(),
+                bytes
+            ));
+        }
+        if bytes == 0 {
+            return Ok(Self::from_vec(Vec::new()));
+        }
+
+        let addr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                bytes,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(format!("mmap of {} failed", path.display()));
+        }
+
+        Ok(Self {
+            backing: Backing::Mapped { addr, bytes },
+            len: bytes / 8,
+        })
+    }
+
+    /// Number of i64 elements
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Pointer handed to the script, which may write through it
+    pub fn as_mut_ptr(&mut self) -> *mut i64 {
+        match &mut self.backing {
+            Backing::Owned(v) => v.as_mut_ptr(),
+            Backing::Mapped { addr, .. } => *addr as *mut i64,
+        }
+    }
+
+    /// Current contents (reflects writes made by the script)
+    pub fn as_slice(&self) -> &[i64] {
+        match &self.backing {
+            Backing::Owned(v) => v,
+            Backing::Mapped { addr, .. } => unsafe {
+                std::slice::from_raw_parts(*addr as *const i64, self.len)
+            },
+        }
+    }
+}
+
+impl Drop for HostArray {
+    fn drop(&mut self) {
+        if let Backing::Mapped { addr, bytes } = self.backing {
+            unsafe {
+                libc::munmap(addr, bytes);
+            }
+        }
+    }
+}
+
+/// Parse a CLI binding of the form `name=path`
+pub fn parse_binding(spec: &str) -> Result<(String, HostArray), String> {
+    let (name, path) = spec
+        .split_once('=')
+        .ok_or_else(|| format!("Invalid binding '{}': expected NAME=FILE", spec))?;
+    if name.is_empty() || path.is_empty() {
+        return Err(format!("Invalid binding '{}': expected NAME=FILE", spec));
+    }
+    Ok((name.to_string(), HostArray::map_file(Path::new(path))?))
+}
+
+/// Build the argument list for `func` from array bindings and plain values.
+///
+/// A bound name takes the pointer and the parameter after it takes the
+/// length. Unbound parameters take `values` in order; any left over receive 0.
+pub fn marshal_args(
+    func: &Function,
+    bindings: &mut [(String, HostArray)],
+    values: &[i64],
+) -> Result<Vec<i64>, String> {
+    if func.args.len() > MAX_ARGS {
+        return Err(format!(
+            "{}() takes {} parameters; at most {} are supported",
+            func.name,
+            func.args.len(),
+            MAX_ARGS
+        ));
+    }
+
+    for (name, _) in bindings.iter() {
+        if !func.args.contains(name) {
+            return Err(format!("{}() has no parameter named '{}'", func.name, name));
+        }
+    }
+
+    let mut args = Vec::with_capacity(func.args.len());
+    let mut values = values.iter();
+    let mut i = 0;
+    while i < func.args.len() {
+        let param = &func.args[i];
+        match bindings.iter().position(|(name, _)| name == param) {
+            Some(bound) => {
+                let len_param = func.args.get(i + 1).ok_or_else(|| {
+                    format!(
+                        "Array '{}' must be followed by a length parameter in {}()",
+                        param, func.name
+                    )
+                })?;
+                if bindings.iter().any(|(name, _)| name == len_param) {
+                    return Err(format!(
+                        "Parameter '{}' is bound to an array but must receive the length of '{}'",
+                        len_param, param
+                    ));
+                }
+                let array = &mut bindings[bound].1;
+                args.push(array.as_mut_ptr() as i64);
+                args.push(array.len() as i64);
+                i += 2;
+            }
+            None => {
+                let value = values.next().copied().unwrap_or_else(|| {
+                    tracing::warn!(
+                        "Parameter '{}' of {}() is not bound; passing 0",
+                        param,
+                        func.name
+                    );
+                    0
+                });
+                args.push(value);
+                i += 1;
+            }
+        }
+    }
+
+    let extra = values.len();
+    if extra > 0 {
+        return Err(format!(
+            "{}() got {} more argument value(s) than it has unbound parameters",
+            func.name, extra
+        ));
+    }
+    Ok(args)
+}
+
+/// Call compiled code at `entry` with up to `MAX_ARGS` integer arguments.
+///
+/// # Safety
+/// `entry` must point to a function compiled by NanoForge.
+pub unsafe fn call_with_args(entry: *const u8, args: &[i64]) -> i64 {
+    assert!(args.len() <= MAX_ARGS, "too many arguments");
+    let mut regs = [0i64; MAX_ARGS];
+    regs[..args.len()].copy_from_slice(args);
+
+    // Extra arguments are ignored by the callee (System V passes them in registers)
+    let func: extern "C" fn(i64, i64, i64, i64) -> i64 = std::mem::transmute(entry);
+    func(regs[0], regs[1], regs[2], regs[3])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::CodeGenerator;
+    use crate::compiler::Compiler;
+    use crate::jit_memory::DualMappedMemory;
+    use crate::parser::Parser;
+
+    fn main_of(src: &str) -> (crate::ir::Program, Function) {
+        let prog = Parser::new().parse(src).unwrap();
+        let main = prog
+            .functions
+            .iter()
+            .find(|f| f.name == "main")
+            .unwrap()
+            .clone();
+        (prog, main)
+    }
+
+    #[test]
+    fn test_marshal_args() {
+        let (_, main) = main_of("fn main(a, n) { return n }");
+        assert_eq!(marshal_args(&main, &mut [], &[]).unwrap(), vec![0, 0]);
+        assert_eq!(marshal_args(&main, &mut [], &[7]).unwrap(), vec![7, 0]);
+        assert!(marshal_args(&main, &mut [], &[1, 2, 3])
+            .unwrap_err()
+            .contains("1 more"));
+
+        let mut data = vec![("b".to_string(), HostArray::from_vec(vec![1]))];
+        assert!(marshal_args(&main, &mut data, &[])
+            .unwrap_err()
+            .contains("no parameter"));
+
+        let (_, main) = main_of("fn main(a) { return 0 }");
+        let mut data = vec![("a".to_string(), HostArray::from_vec(vec![1]))];
+        assert!(marshal_args(&main, &mut data, &[]).unwrap_err().contains("length"));
+    }
+
+    #[test]
+    fn test_script_sums_host_array() {
+        let (prog, main) = main_of(
+            "fn main(a, n) {
+                sum = 0
+                i = 0
+                while i < n {
+                    v = a[i]
+                    sum = sum + v
+                    i = i + 1
+                }
+                return sum
+            }",
+        );
+        let mut bindings = vec![("a".to_string(), HostArray::from_vec((1..=10).collect()))];
+        let args = marshal_args(&main, &mut bindings, &[]).unwrap();
+        assert_eq!(args[1], 10);
+
+        let (code, main_offset) = Compiler::compile_program(&prog, 2).unwrap();
+        let memory = DualMappedMemory::new(code.len() + 4096).unwrap();
+        CodeGenerator::emit_to_memory(&memory, &code, 0);
+        let result = unsafe { call_with_args(memory.rx_ptr.add(main_offset), &args) };
+        assert_eq!(result, 55);
+    }
+
+    #[test]
+    fn test_map_file_is_copy_on_write() {
+        let path = std::env::temp_dir().join(format!("nf_host_args_{}.bin", std::process::id()));
+        let bytes: Vec<u8> = [3i64, 4, 5].iter().flat_map(|v| v.to_le_bytes()).collect();
+        std::fs::write(&path, &bytes).unwrap();
+
+        let mut array = HostArray::map_file(&path).unwrap();
+        assert_eq!(array.as_slice(), &[3, 4, 5]);
+        unsafe { *array.as_mut_ptr() = 99 };
+        assert_eq!(array.as_slice()[0], 99);
+        drop(array);
+
+        assert_eq!(std::fs::read(&path).unwrap(), bytes);
+        std::fs::remove_file(&path).ok();
+    }
+}
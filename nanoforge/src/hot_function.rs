@@ -1,7 +1,36 @@
 #![allow(dead_code)]
-use crate::jit_memory::DualMappedMemory;
+use crate::capture::{Reservoir, Sample};
+use crate::jit_memory::{self, DualMappedMemory};
+use crate::sandbox::rdtsc;
+use crate::status::{self, Status};
 use crossbeam::epoch::{self, Atomic, Owned};
-use std::sync::atomic::Ordering;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Calls between latency samples unless set with `with_sample_interval`
+pub const DEFAULT_SAMPLE_INTERVAL: u64 = 64;
+
+/// Each latency sample moves the average 1/8 of the way towards it
+const EWMA_SHIFT: u32 = 3;
+
+/// Call frequency and latency of a `HotFunction`, for tiering decisions
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct CallStats {
+    /// Calls since the function was created
+    pub calls: u64,
+    /// Timed calls since the current implementation was installed
+    pub samples: u64,
+    /// Moving average of the timed calls' cycles (0 before the first sample)
+    pub avg_cycles: u64,
+}
+
+impl CallStats {
+    /// Calls made since an `earlier` snapshot
+    pub fn calls_since(&self, earlier: &CallStats) -> u64 {
+        self.calls.saturating_sub(earlier.calls)
+    }
+}
 
 // A wrapper around the raw function pointer that we can manage with EBR
 pub struct JittedCode {
@@ -18,6 +47,13 @@ pub struct HotFunction {
     // The active implementation.
     // We use crossbeam::epoch::Atomic to manage the lifetime of the pointer.
     current: Atomic<JittedCode>,
+    calls: AtomicU64,
+    samples: AtomicU64,
+    avg_cycles: AtomicU64,
+    // Sample interval - 1; the interval is a power of two
+    sample_mask: u64,
+    // Sample of the timed calls' inputs and outputs
+    capture: Mutex<Reservoir>,
 }
 
 impl HotFunction {
@@ -32,9 +68,41 @@ impl HotFunction {
 
         Self {
             current: Atomic::new(code),
+            calls: AtomicU64::new(0),
+            samples: AtomicU64::new(0),
+            avg_cycles: AtomicU64::new(0),
+            sample_mask: DEFAULT_SAMPLE_INTERVAL - 1,
+            capture: Mutex::new(Reservoir::default()),
+        }
+    }
+
+    /// Time one call in every `interval` (rounded up to a power of two)
+    pub fn with_sample_interval(mut self, interval: u64) -> Self {
+        self.sample_mask = interval.max(1).next_power_of_two() - 1;
+        self
+    }
+
+    /// Call count and latency so far
+    pub fn stats(&self) -> CallStats {
+        CallStats {
+            calls: self.calls.load(Ordering::Relaxed),
+            samples: self.samples.load(Ordering::Relaxed),
+            avg_cycles: self.avg_cycles.load(Ordering::Relaxed),
         }
     }
 
+    /// Keep `capacity` samples of timed calls, drawn with `seed`
+    pub fn with_capture(self, capacity: usize, seed: u64) -> Self {
+        *self.capture.lock().unwrap() = Reservoir::new(capacity, seed);
+        self
+    }
+
+    /// A uniform sample of the timed calls' inputs and outputs: real
+    /// arguments to evolve or benchmark against
+    pub fn capture(&self) -> Reservoir {
+        self.capture.lock().unwrap().clone()
+    }
+
     pub fn call(&self, arg: u64) -> u64 {
         // 1. Enter critical section (pin the epoch)
         let guard = epoch::pin();
@@ -46,7 +114,46 @@ impl HotFunction {
         // Safety: The guard ensures 'shared' remains valid during this call.
         // We must unwrap because we initialized it.
         let code = unsafe { shared.as_ref() }.expect("HotFunction is null!");
-        (code.func_ptr)(arg)
+
+        // Only every Nth call is timed; the rest just bump the counter
+        let n = self.calls.fetch_add(1, Ordering::Relaxed);
+        if n & self.sample_mask != 0 {
+            return (code.func_ptr)(arg);
+        }
+        let start = rdtsc();
+        let result = (code.func_ptr)(arg);
+        self.record_sample(rdtsc().saturating_sub(start));
+        // Skipped rather than waited for while someone reads the sample
+        if let Ok(mut capture) = self.capture.try_lock() {
+            capture.record(Sample {
+                input: arg,
+                output: result,
+            });
+        }
+        result
+    }
+
+    fn record_sample(&self, cycles: u64) {
+        let first = self.samples.fetch_add(1, Ordering::Relaxed) == 0;
+        let _ = self
+            .avg_cycles
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |avg| {
+                Some(if first {
+                    cycles
+                } else {
+                    avg - (avg >> EWMA_SHIFT) + (cycles >> EWMA_SHIFT)
+                })
+            });
+    }
+
+    /// `call`, reporting a call that didn't return normally (see `status`)
+    pub fn call_checked(&self, arg: u64) -> Result<u64, Status> {
+        status::reset();
+        let result = self.call(arg);
+        match status::take() {
+            Status::Ok => Ok(result),
+            status => Err(status),
+        }
     }
 
     pub fn update(&self, new_memory: DualMappedMemory, offset: usize) {
@@ -58,16 +165,21 @@ impl HotFunction {
             func_ptr,
         };
 
-        // 1. Enter critical section
+        // 1. Make sure no caller runs on instructions fetched before the new
+        // code was written (freed code memory can be mapped again at the
+        // same address)
+        jit_memory::sync_cores();
+
+        // 2. Enter critical section
         let guard = epoch::pin();
 
-        // 2. Atomic Swap
+        // 3. Atomic Swap
         // We move 'new_code' into an Owned pointer, then swap it into the Atomic.
         let old = self
             .current
             .swap(Owned::new(new_code), Ordering::Release, &guard);
 
-        // 3. Defer Destruction
+        // 4. Defer Destruction
         // 'old' is a Shared pointer to the previous JittedCode.
         // We need to schedule its destruction only after all threads have left the previous epoch.
         unsafe {
@@ -77,6 +189,120 @@ impl HotFunction {
             guard.defer_destroy(old);
         }
 
+        // The old implementation's latency says nothing about the new one
+        self.samples.store(0, Ordering::Relaxed);
+        self.avg_cycles.store(0, Ordering::Relaxed);
+
         println!("HotFunction: Swapped implementation. Old memory will be freed safely.");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::CodeGenerator;
+    use crate::jit_memory::MappingMode;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+    use std::thread;
+
+    fn sum_loop() -> DualMappedMemory {
+        let code = CodeGenerator::generate_sum_loop().unwrap();
+        let memory = DualMappedMemory::new(4096).unwrap();
+        CodeGenerator::emit_to_memory(&memory, &code, 0);
+        memory
+    }
+
+    #[test]
+    fn test_counts_calls_and_samples_latency() {
+        let hot = HotFunction::new(sum_loop(), 0).with_sample_interval(3);
+        assert_eq!(hot.stats(), CallStats::default());
+
+        let before = hot.stats();
+        for _ in 0..20 {
+            assert_eq!(hot.call(100), 4950);
+        }
+        let stats = hot.stats();
+        assert_eq!(stats.calls, 20);
+        assert_eq!(stats.calls_since(&before), 20);
+        // Interval 3 rounds up to 4: calls 0, 4, 8, 12 and 16 are timed
+        assert_eq!(stats.samples, 5);
+        assert!(stats.avg_cycles > 0);
+
+        // A new implementation starts a fresh latency average
+        hot.update(sum_loop(), 0);
+        let swapped = hot.stats();
+        assert_eq!(
+            (swapped.calls, swapped.samples, swapped.avg_cycles),
+            (20, 0, 0)
+        );
+        hot.call(100);
+        assert_eq!(hot.stats().samples, 1);
+    }
+
+    #[test]
+    fn test_captures_timed_calls() {
+        let hot = HotFunction::new(sum_loop(), 0)
+            .with_sample_interval(2)
+            .with_capture(4, 7);
+        assert_eq!(hot.capture().seen(), 0);
+        for n in 0..3 {
+            hot.call(n);
+        }
+        let capture = hot.capture();
+        assert_eq!(
+            capture.samples(),
+            [
+                Sample { input: 0, output: 0 },
+                Sample { input: 2, output: 1 }
+            ]
+        );
+
+        for n in 3..100 {
+            hot.call(n);
+        }
+        let capture = hot.capture();
+        assert_eq!(capture.seen(), 50);
+        assert_eq!(capture.samples().len(), 4);
+        assert!(capture.samples().iter().all(|s| s.input % 2 == 0));
+        assert!(capture
+            .test_cases()
+            .iter()
+            .all(|c| c.expected_output == c.input * (c.input - 1).max(0) / 2));
+    }
+
+    #[test]
+    fn test_swaps_under_concurrent_callers() {
+        let hot = Arc::new(HotFunction::new(sum_loop(), 0).with_sample_interval(1));
+        let stop = Arc::new(AtomicBool::new(false));
+        let callers: Vec<_> = (0..4)
+            .map(|_| {
+                let (hot, stop) = (hot.clone(), stop.clone());
+                thread::spawn(move || {
+                    let mut calls = 0;
+                    while !stop.load(Ordering::Relaxed) {
+                        assert_eq!(hot.call(101), 5050);
+                        calls += 1;
+                    }
+                    calls
+                })
+            })
+            .collect();
+
+        let simple = CodeGenerator::generate_sum_loop().unwrap();
+        let unrolled = CodeGenerator::generate_sum_loop_unrolled().unwrap();
+        for i in 0..200 {
+            let (code, mode) = match i % 3 {
+                0 => (&unrolled, MappingMode::Dual),
+                1 => (&simple, MappingMode::Toggle),
+                _ => (&simple, MappingMode::Dual),
+            };
+            let memory = DualMappedMemory::with_mode(4096, mode).unwrap();
+            memory.write(0, code).unwrap();
+            hot.update(memory, 0);
+        }
+        stop.store(true, Ordering::Relaxed);
+        let calls: u64 = callers.into_iter().map(|c| c.join().unwrap()).sum();
+        assert_eq!(hot.stats().calls, calls);
+    }
+}
@@ -0,0 +1,428 @@
+//! IR Interpreter
+//!
+//! Executes unoptimized IR directly, with the same semantics as the JIT:
+//! registers are wrapping i64s (or checked ones, `with_overflow`),
+//! `Load`/`Store` address raw memory, and
+//! `Alloc`/`Free` go to libc. It is the first tier of the adaptive runtime
+//! (no compile cost for code that runs a handful of times) and a reference
+//! to check compiled code against.
+//!
+//! Vector opcodes only appear after optimization and are rejected.
+
+use crate::assertion::AssertSite;
+use crate::ir::{Cond, Function, Opcode, Operand, Program};
+use crate::overflow::OverflowMode;
+use std::collections::HashMap;
+
+/// Nested calls allowed before giving up
+pub const MAX_DEPTH: usize = 10_000;
+
+pub struct Interpreter<'a> {
+    functions: HashMap<&'a str, Code<'a>>,
+    calls: HashMap<String, u64>,
+    /// Instructions left before stopping; None runs unbounded
+    fuel: Option<u64>,
+    overflow: OverflowMode,
+    branches: u64,
+}
+
+/// A function and where its labels are
+struct Code<'a> {
+    func: &'a Function,
+    labels: HashMap<&'a str, usize>,
+}
+
+/// One active call
+struct Frame<'a> {
+    code: &'a Code<'a>,
+    regs: Vec<i64>,
+    args: Vec<i64>,
+    /// Arguments set for the next call
+    pending_args: Vec<i64>,
+    /// Operands of the last `Cmp`
+    flags: (i64, i64),
+    pc: usize,
+}
+
+impl<'a> Frame<'a> {
+    fn new(code: &'a Code<'a>, args: Vec<i64>) -> Self {
+        Self {
+            code,
+            regs: vec![0; 256],
+            args,
+            pending_args: Vec::new(),
+            flags: (0, 0),
+            pc: 0,
+        }
+    }
+
+    fn read(&self, op: &Option<Operand>) -> Option<i64> {
+        match op {
+            Some(Operand::Reg(r)) => Some(self.regs[*r as usize]),
+            Some(Operand::Imm(v)) => Some(*v as i64),
+            _ => None,
+        }
+    }
+}
+
+impl<'a> Interpreter<'a> {
+    pub fn new(prog: &'a Program) -> Self {
+        let functions = prog
+            .functions
+            .iter()
+            .map(|func| {
+                let labels = func
+                    .instructions
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(idx, i)| match (&i.op, &i.dest) {
+                        (Opcode::Label, Some(Operand::Label(name))) => Some((name.as_str(), idx)),
+                        _ => None,
+                    })
+                    .collect();
+                (func.name.as_str(), Code { func, labels })
+            })
+            .collect();
+        Self {
+            functions,
+            calls: HashMap::new(),
+            fuel: None,
+            overflow: OverflowMode::Wrapping,
+            branches: 0,
+        }
+    }
+
+    /// Stop with an error after executing `steps` more instructions
+    pub fn with_fuel(mut self, steps: u64) -> Self {
+        self.fuel = Some(steps);
+        self
+    }
+
+    /// What `Add`, `Sub` and `Mul` do on i64 overflow; checked stops with
+    /// an error, as compiled code traps
+    pub fn with_overflow(mut self, mode: OverflowMode) -> Self {
+        self.overflow = mode;
+        self
+    }
+
+    /// Times `name` was called, including calls from other functions
+    pub fn calls(&self, name: &str) -> u64 {
+        self.calls.get(name).copied().unwrap_or(0)
+    }
+
+    /// Jumps executed so far, taken or not
+    pub fn branches(&self) -> u64 {
+        self.branches
+    }
+
+    /// Run `name` with `args` and return its result
+    pub fn call(&mut self, name: &str, args: &[i64]) -> Result<i64, String> {
+        let functions = &self.functions;
+        let calls = &mut self.calls;
+        let mut enter = |name: &str, args: Vec<i64>| {
+            let code = functions
+                .get(name)
+                .ok_or_else(|| format!("call to undefined function '{}'", name))?;
+            *calls.entry(name.to_string()).or_default() += 1;
+            Ok::<_, String>(Frame::new(code, args))
+        };
+
+        let mut stack = vec![enter(name, args.to_vec())?];
+        loop {
+            let depth = stack.len();
+            let frame = stack.last_mut().expect("active frame");
+            let func = frame.code.func;
+            let Some(instr) = func.instructions.get(frame.pc) else {
+                // Falling off the end returns Reg(0), as compiled code does
+                let result = frame.regs[0];
+                stack.pop();
+                match stack.last_mut() {
+                    Some(caller) => {
+                        finish_call(caller, result);
+                        continue;
+                    }
+                    None => return Ok(result),
+                }
+            };
+            if let Some(fuel) = &mut self.fuel {
+                if *fuel == 0 {
+                    return Err(format!("{}(): out of fuel", func.name));
+                }
+                *fuel -= 1;
+            }
+            if matches!(
+                instr.op,
+                Opcode::Jmp
+                    | Opcode::Jnz
+                    | Opcode::Je
+                    | Opcode::Jne
+                    | Opcode::Jl
+                    | Opcode::Jle
+                    | Opcode::Jg
+                    | Opcode::Jge
+                    | Opcode::JumpTable(..)
+            ) {
+                self.branches += 1;
+            }
+            let fail = |msg: &str| format!("{}[{}]: {}: {}", func.name, frame.pc, instr, msg);
+            let operand = |frame: &Frame, op: &Option<Operand>| {
+                frame
+                    .read(op)
+                    .ok_or_else(|| fail("expected a register or immediate"))
+            };
+            let dest = match instr.dest {
+                Some(Operand::Reg(r)) => Ok(r as usize),
+                _ => Err(fail("expected a register destination")),
+            };
+            let jump = |target: &Option<Operand>| match target {
+                Some(Operand::Label(l)) => frame
+                    .code
+                    .labels
+                    .get(l.as_str())
+                    .copied()
+                    .ok_or_else(|| fail("undefined label")),
+                _ => Err(fail("expected a label")),
+            };
+
+            let mut next = frame.pc + 1;
+            match instr.op {
+                Opcode::Label | Opcode::PrefetchT0 => {}
+                Opcode::Mov => frame.regs[dest?] = operand(frame, &instr.src1)?,
+                Opcode::Add | Opcode::Sub | Opcode::Mul | Opcode::Min | Opcode::Max => {
+                    let a = operand(frame, &instr.dest)?;
+                    let b = operand(frame, &instr.src1)?;
+                    let checked = match instr.op {
+                        Opcode::Add => a.checked_add(b),
+                        Opcode::Sub => a.checked_sub(b),
+                        Opcode::Mul => a.checked_mul(b),
+                        Opcode::Min => Some(a.min(b)),
+                        _ => Some(a.max(b)),
+                    };
+                    frame.regs[dest?] = match (checked, self.overflow) {
+                        (Some(v), _) => v,
+                        (None, OverflowMode::Checked) => return Err(fail("integer overflow")),
+                        (None, OverflowMode::Wrapping) => match instr.op {
+                            Opcode::Add => a.wrapping_add(b),
+                            Opcode::Sub => a.wrapping_sub(b),
+                            _ => a.wrapping_mul(b),
+                        },
+                    };
+                }
+                Opcode::Abs | Opcode::Popcnt | Opcode::Ctz | Opcode::Clz => {
+                    let v = operand(frame, &instr.src1)?;
+                    frame.regs[dest?] = match instr.op {
+                        Opcode::Abs => v.wrapping_abs(),
+                        Opcode::Popcnt => v.count_ones() as i64,
+                        Opcode::Ctz => v.trailing_zeros() as i64,
+                        _ => v.leading_zeros() as i64,
+                    };
+                }
+                Opcode::Cmp => {
+                    frame.flags = (operand(frame, &instr.src1)?, operand(frame, &instr.src2)?)
+                }
+                Opcode::CMov(cond) => {
+                    if holds(cond, frame.flags) {
+                        frame.regs[dest?] = operand(frame, &instr.src1)?;
+                    }
+                }
+                Opcode::Assert(cond, ref text) => {
+                    let lhs = operand(frame, &instr.src1)?;
+                    let rhs = operand(frame, &instr.src2)?;
+                    if !holds(cond, (lhs, rhs)) {
+                        let site = AssertSite::new(&func.name, func.line(frame.pc), text);
+                        return Err(format!("{}(): {}", func.name, site.failure(lhs, rhs)));
+                    }
+                }
+                Opcode::Jmp => next = jump(&instr.dest)?,
+                Opcode::Jnz => {
+                    if operand(frame, &instr.src1)? != 0 {
+                        next = jump(&instr.dest)?;
+                    }
+                }
+                Opcode::Je | Opcode::Jne | Opcode::Jl | Opcode::Jle | Opcode::Jg | Opcode::Jge => {
+                    let cond = Cond::of_jump(&instr.op).expect("conditional jump");
+                    if holds(cond, frame.flags) {
+                        next = jump(&instr.dest)?;
+                    }
+                }
+                Opcode::JumpTable(low, ref targets) => {
+                    let slot = operand(frame, &instr.src1)?.wrapping_sub(low as i64);
+                    next = match usize::try_from(slot).ok().and_then(|s| targets.get(s)) {
+                        Some(target) => jump(&Some(Operand::Label(target.clone())))?,
+                        None => jump(&instr.dest)?,
+                    };
+                }
+                Opcode::Load(disp) => {
+                    let index = operand(frame, &instr.src2)?.wrapping_add(disp as i64);
+                    let addr = address(operand(frame, &instr.src1)?, index);
+                    frame.regs[dest?] = unsafe { std::ptr::read_unaligned(addr) };
+                }
+                Opcode::Store(disp) => {
+                    let index = operand(frame, &instr.src1)?.wrapping_add(disp as i64);
+                    let addr = address(operand(frame, &instr.dest)?, index);
+                    let value = operand(frame, &instr.src2)?;
+                    unsafe { std::ptr::write_unaligned(addr, value) };
+                }
+                Opcode::Alloc => {
+                    let size = operand(frame, &instr.src1)?;
+                    frame.regs[dest?] = match instr.src2 {
+                        Some(Operand::Imm(align)) => {
+                            crate::alignment::nf_alloc_aligned(size, align as i64) as i64
+                        }
+                        _ => unsafe { libc::malloc(size.max(0) as usize) as i64 },
+                    };
+                }
+                Opcode::Free => {
+                    let ptr = operand(frame, &instr.src1)?;
+                    unsafe { libc::free(ptr as *mut libc::c_void) };
+                }
+                Opcode::SetArg(i) => {
+                    let value = operand(frame, &instr.src1)?;
+                    if frame.pending_args.len() <= i {
+                        frame.pending_args.resize(i + 1, 0);
+                    }
+                    frame.pending_args[i] = value;
+                    if let Ok(d) = dest {
+                        frame.regs[d] = value;
+                    }
+                }
+                Opcode::LoadArg(i) => frame.regs[dest?] = frame.args.get(i).copied().unwrap_or(0),
+                Opcode::Call => {
+                    let Some(Operand::Label(callee)) = &instr.src1 else {
+                        return Err(fail("call without a function name"));
+                    };
+                    if depth >= MAX_DEPTH {
+                        return Err(format!(
+                            "{}(): calls nested deeper than {}",
+                            callee, MAX_DEPTH
+                        ));
+                    }
+                    let args = std::mem::take(&mut frame.pending_args);
+                    // Resume after the call once the callee returns
+                    frame.pc = next;
+                    let callee = enter(callee, args)?;
+                    stack.push(callee);
+                    continue;
+                }
+                Opcode::Ret => {
+                    let result = frame.read(&instr.dest).unwrap_or(frame.regs[0]);
+                    stack.pop();
+                    match stack.last_mut() {
+                        Some(caller) => {
+                            finish_call(caller, result);
+                            continue;
+                        }
+                        None => return Ok(result),
+                    }
+                }
+                Opcode::VLoad(..)
+                | Opcode::VStore(..)
+                | Opcode::VAdd
+                | Opcode::VMin
+                | Opcode::VMax
+                | Opcode::VAbs => return Err(fail("vector instructions are not interpreted")),
+            }
+            frame.pc = next;
+        }
+    }
+}
+
+/// Store a returned value in the destination of the caller's `Call`
+fn finish_call(caller: &mut Frame, result: i64) {
+    let call = &caller.code.func.instructions[caller.pc - 1];
+    if let Some(Operand::Reg(r)) = call.dest {
+        caller.regs[r as usize] = result;
+    }
+}
+
+/// Whether `cond` holds for the operands of the last `Cmp`
+fn holds(cond: Cond, (a, b): (i64, i64)) -> bool {
+    match cond {
+        Cond::Eq => a == b,
+        Cond::Ne => a != b,
+        Cond::Lt => a < b,
+        Cond::Le => a <= b,
+        Cond::Gt => a > b,
+        Cond::Ge => a >= b,
+    }
+}
+
+/// `base + index * 8`, as the backend addresses memory
+fn address(base: i64, index: i64) -> *mut i64 {
+    base.wrapping_add(index.wrapping_mul(8)) as *mut i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiled_program::CompiledProgram;
+    use crate::compiler::CompileOptions;
+    use crate::parser::Parser;
+
+    #[test]
+    fn test_matches_the_jit() {
+        let prog = Parser::new()
+            .parse(
+                "fn main(n) {
+                    a = alloc(80)
+                    i = 0
+                    while i < 10 {
+                        v = i * n
+                        a[i] = v
+                        i = i + 1
+                    }
+                    s = sum(a, 10)
+                    free(a)
+                    return s
+                }
+                fn sum(a, n) {
+                    s = 0
+                    for (i = 1; i <= n; i = i + 1) {
+                        v = a[i-1]
+                        s = s + v
+                    }
+                    b = clz(s)
+                    r = s + b
+                    return r
+                }",
+            )
+            .unwrap();
+        let compiled = CompiledProgram::compile(&prog, &CompileOptions::new(0)).unwrap();
+        let jit = compiled.get_fn("main").unwrap();
+        let mut interp = Interpreter::new(&prog);
+        for n in [0, 3, -7] {
+            assert_eq!(interp.call("main", &[n]), jit.call(&[n]));
+        }
+        assert_eq!(interp.calls("sum"), 3);
+    }
+
+    #[test]
+    fn test_recursion_and_fuel() {
+        let prog = Parser::new()
+            .parse(
+                "fn fib(n) {
+                    if n < 2 {
+                        return n
+                    }
+                    m = n - 1
+                    a = fib(m)
+                    m = n - 2
+                    b = fib(m)
+                    r = a + b
+                    return r
+                }
+                fn main() {
+                    return 0
+                }",
+            )
+            .unwrap();
+        assert_eq!(Interpreter::new(&prog).call("fib", &[15]), Ok(610));
+        let err = Interpreter::new(&prog)
+            .with_fuel(100)
+            .call("fib", &[15])
+            .unwrap_err();
+        assert!(err.contains("out of fuel"), "{}", err);
+        let err = Interpreter::new(&prog).call("fib", &[20_000]).unwrap_err();
+        assert!(err.contains("nested deeper"), "{}", err);
+    }
+}
@@ -0,0 +1,411 @@
+//! Runtime Introspection
+//!
+//! A small HTTP endpoint with JSON views of what a long-running process has
+//! loaded, so operators can see it without attaching a debugger:
+//!
+//! - `GET /programs`: loaded programs, each function's current tier, call
+//!   stats and recent tier swaps
+//! - `GET /bandits`: the latest snapshot of every registered bandit
+//! - `GET /memory`: live JIT memory (see `jit_memory::usage`)
+//! - `GET /audit?last=N`: the last N tier changes of the attached `AuditLog`
+//!   (default `AUDIT_EVENTS`)
+//! - `GET /` or `/status`: all of the above
+//!
+//! The process records state in an `Introspection` handle as it goes;
+//! `serve` answers requests from a background thread. It is deliberately
+//! read-only and speaks just enough HTTP/1.1 for curl and dashboards. Each
+//! connection gets its own thread, a timeout and a cap on the request size,
+//! so a client that stalls or streams garbage can't hold the endpoint.
+
+use crate::audit::{AuditEvent, AuditLog};
+use crate::hot_function::CallStats;
+use crate::jit_memory::{self, JitMemoryUsage};
+use crate::store::Schema;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Tier swaps kept per function; older ones are dropped
+pub const MAX_SWAPS: usize = 32;
+
+/// Audit events `GET /audit` returns without `?last=N`
+pub const AUDIT_EVENTS: usize = 50;
+
+/// Where the demo serves; loopback only, as the views are unauthenticated
+pub const DEFAULT_ADDR: &str = "127.0.0.1:9001";
+
+/// Bytes of request (line and headers) read before answering anyway
+pub const MAX_REQUEST_BYTES: u64 = 8 * 1024;
+
+/// How long a connection may take to send its request or read the answer
+pub const IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// One change of a function's implementation
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SwapEvent {
+    /// Milliseconds since the Unix epoch
+    pub at_ms: u64,
+    pub from: Option<String>,
+    pub to: String,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct FunctionState {
+    /// Name of the running implementation (None until first set)
+    pub tier: Option<String>,
+    pub calls: Option<CallStats>,
+    /// Most recent last
+    pub swaps: Vec<SwapEvent>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ProgramState {
+    pub loaded_at_ms: u64,
+    pub functions: BTreeMap<String, FunctionState>,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct State {
+    programs: BTreeMap<String, ProgramState>,
+    bandits: BTreeMap<String, serde_json::Value>,
+}
+
+/// Everything `GET /status` reports
+#[derive(Debug, Serialize)]
+pub struct StatusView {
+    pub programs: BTreeMap<String, ProgramState>,
+    pub bandits: BTreeMap<String, serde_json::Value>,
+    pub memory: JitMemoryUsage,
+}
+
+impl StatusView {
+    pub const SCHEMA: Schema = Schema::new("status", 1);
+}
+
+/// Shared, cheaply cloned record of what to report
+#[derive(Debug, Clone, Default)]
+pub struct Introspection {
+    state: Arc<Mutex<State>>,
+    audit: AuditLog,
+}
+
+pub(crate) fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+impl Introspection {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serve `audit`'s events at `/audit` (an empty in-memory log by default)
+    pub fn with_audit(mut self, audit: AuditLog) -> Self {
+        self.audit = audit;
+        self
+    }
+
+    pub fn audit(&self) -> &AuditLog {
+        &self.audit
+    }
+
+    /// Record a loaded program (replacing any of the same name)
+    pub fn register_program(&self, name: &str, functions: &[&str]) {
+        let functions = functions
+            .iter()
+            .map(|f| (f.to_string(), FunctionState::default()))
+            .collect();
+        self.state.lock().unwrap().programs.insert(
+            name.to_string(),
+            ProgramState {
+                loaded_at_ms: now_ms(),
+                functions,
+            },
+        );
+    }
+
+    pub fn unregister_program(&self, name: &str) {
+        self.state.lock().unwrap().programs.remove(name);
+    }
+
+    /// Record that `program`'s `function` now runs the `tier` implementation
+    pub fn set_tier(&self, program: &str, function: &str, tier: &str, reason: &str) {
+        self.with_function(program, function, |f| {
+            if f.swaps.len() == MAX_SWAPS {
+                f.swaps.remove(0);
+            }
+            f.swaps.push(SwapEvent {
+                at_ms: now_ms(),
+                from: f.tier.replace(tier.to_string()),
+                to: tier.to_string(),
+                reason: reason.to_string(),
+            });
+        });
+    }
+
+    /// Latest call stats of `program`'s `function` (see `HotFunction::stats`)
+    pub fn record_calls(&self, program: &str, function: &str, stats: CallStats) {
+        self.with_function(program, function, |f| f.calls = Some(stats));
+    }
+
+    /// Replace the snapshot of the bandit called `name`
+    pub fn record_bandit<T: Serialize>(&self, name: &str, bandit: &T) -> Result<(), String> {
+        let snapshot = serde_json::to_value(bandit).map_err(|e| e.to_string())?;
+        self.state
+            .lock()
+            .unwrap()
+            .bandits
+            .insert(name.to_string(), snapshot);
+        Ok(())
+    }
+
+    // Functions of unregistered programs are added on first use
+    fn with_function(
+        &self,
+        program: &str,
+        function: &str,
+        update: impl FnOnce(&mut FunctionState),
+    ) {
+        let mut state = self.state.lock().unwrap();
+        let program = state
+            .programs
+            .entry(program.to_string())
+            .or_insert_with(|| ProgramState {
+                loaded_at_ms: now_ms(),
+                functions: BTreeMap::new(),
+            });
+        update(program.functions.entry(function.to_string()).or_default());
+    }
+
+    pub fn status(&self) -> StatusView {
+        let state = self.state.lock().unwrap();
+        StatusView {
+            programs: state.programs.clone(),
+            bandits: state.bandits.clone(),
+            memory: jit_memory::usage(),
+        }
+    }
+
+    /// JSON body for `path`, or None if there is no such view
+    pub fn route(&self, path: &str) -> Option<String> {
+        let (path, query) = path.split_once('?').unwrap_or((path, ""));
+        if path.trim_end_matches('/') == "/audit" {
+            return Some(self.audit_view(query));
+        }
+        let status = self.status();
+        let json = match path.trim_end_matches('/') {
+            "" | "/status" => serde_json::to_string_pretty(&status),
+            "/programs" => serde_json::to_string_pretty(&status.programs),
+            "/bandits" => serde_json::to_string_pretty(&status.bandits),
+            "/memory" => serde_json::to_string_pretty(&status.memory),
+            _ => return None,
+        };
+        Some(json.unwrap_or_else(|e| format!("{{\"error\": \"{}\"}}", e)))
+    }
+
+    fn audit_view(&self, query: &str) -> String {
+        let last = query
+            .split('&')
+            .find_map(|p| p.strip_prefix("last="))
+            .and_then(|n| n.parse().ok())
+            .unwrap_or(AUDIT_EVENTS);
+        let json = self
+            .audit
+            .recent(last)
+            .and_then(|events: Vec<AuditEvent>| {
+                serde_json::to_string_pretty(&events).map_err(|e| e.to_string())
+            });
+        json.unwrap_or_else(|e| serde_json::json!({ "error": e }).to_string())
+    }
+
+    /// Answer requests on `addr` (e.g. "127.0.0.1:9100") from a background
+    /// thread; returns the bound address, useful with port 0
+    pub fn serve(&self, addr: &str) -> io::Result<(SocketAddr, JoinHandle<()>)> {
+        let listener = TcpListener::bind(addr)?;
+        let local = listener.local_addr()?;
+        let this = self.clone();
+        let handle = thread::Builder::new()
+            .name("nf-introspection".to_string())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    let stream = match stream {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            tracing::warn!("Introspection accept failed: {}", e);
+                            continue;
+                        }
+                    };
+                    let this = this.clone();
+                    let spawned = thread::Builder::new()
+                        .name("nf-introspection-conn".to_string())
+                        .spawn(move || {
+                            if let Err(e) = this.respond(stream) {
+                                tracing::debug!("Introspection request failed: {}", e);
+                            }
+                        });
+                    if let Err(e) = spawned {
+                        tracing::warn!("Introspection connection dropped: {}", e);
+                    }
+                }
+            })?;
+        Ok((local, handle))
+    }
+
+    fn respond(&self, stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(IO_TIMEOUT))?;
+        stream.set_write_timeout(Some(IO_TIMEOUT))?;
+        let mut reader = BufReader::new(stream.try_clone()?.take(MAX_REQUEST_BYTES));
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        // Drain the headers; requests have no body
+        let mut header = String::new();
+        while reader.read_line(&mut header)? > 2 {
+            header.clear();
+        }
+
+        let error = |message: String| serde_json::json!({ "error": message }).to_string();
+        let mut parts = request_line.split_whitespace();
+        let (status, body) = match (parts.next(), parts.next()) {
+            _ if !request_line.ends_with('\n') => (
+                "400 Bad Request",
+                error("request line too long or incomplete".to_string()),
+            ),
+            (Some("GET"), Some(path)) => match self.route(path) {
+                Some(body) => ("200 OK", body),
+                None => ("404 Not Found", error(format!("no view at {}", path))),
+            },
+            _ => (
+                "405 Method Not Allowed",
+                error("only GET is supported".to_string()),
+            ),
+        };
+        let mut stream = stream;
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ai_optimizer::VariantBandit;
+    use crate::audit::AuditAction;
+    use std::io::Read;
+
+    #[test]
+    fn test_tiers_keep_swap_history() {
+        let intro = Introspection::new();
+        intro.register_program("kernel", &["main", "helper"]);
+        intro.set_tier("kernel", "main", "scalar", "initial");
+        intro.set_tier("kernel", "main", "avx2_unroll4", "hot");
+        intro.record_calls(
+            "kernel",
+            "main",
+            CallStats {
+                calls: 10,
+                samples: 1,
+                avg_cycles: 40,
+            },
+        );
+
+        let main = &intro.status().programs["kernel"].functions["main"];
+        assert_eq!(main.tier.as_deref(), Some("avx2_unroll4"));
+        assert_eq!(main.calls.map(|c| c.calls), Some(10));
+        let swaps: Vec<_> = main
+            .swaps
+            .iter()
+            .map(|s| (s.from.as_deref(), s.to.as_str()))
+            .collect();
+        assert_eq!(swaps, [(None, "scalar"), (Some("scalar"), "avx2_unroll4")]);
+
+        for i in 0..MAX_SWAPS {
+            intro.set_tier("kernel", "helper", &i.to_string(), "churn");
+        }
+        intro.set_tier("kernel", "helper", "last", "churn");
+        let helper = &intro.status().programs["kernel"].functions["helper"];
+        assert_eq!(helper.swaps.len(), MAX_SWAPS);
+        assert_eq!(helper.swaps[0].to, "1");
+
+        assert!(intro.route("/memory").unwrap().contains("\"regions\""));
+        assert_eq!(intro.route("/audit").as_deref(), Some("[]"));
+        assert!(intro.route("/nope").is_none());
+    }
+
+    #[test]
+    fn test_serves_the_last_audit_events() {
+        let audit = AuditLog::new();
+        let intro = Introspection::new().with_audit(audit.clone());
+        for to in ["baseline", "optimized", "evolved"] {
+            audit.record(AuditEvent::new("kernel", "main", AuditAction::Promote, to));
+        }
+        let last: Vec<AuditEvent> =
+            serde_json::from_str(&intro.route("/audit?last=2").unwrap()).unwrap();
+        let tiers: Vec<&str> = last.iter().map(|e| e.to.as_str()).collect();
+        assert_eq!(tiers, ["optimized", "evolved"]);
+        let all: Vec<AuditEvent> = serde_json::from_str(&intro.route("/audit/").unwrap()).unwrap();
+        assert_eq!(all.len(), 3);
+    }
+
+    #[test]
+    fn test_serves_json_over_http() {
+        let intro = Introspection::new();
+        intro.register_program("demo", &["main"]);
+        intro
+            .record_bandit("sum", &VariantBandit::new(vec!["a".into(), "b".into()]))
+            .unwrap();
+        let (addr, _) = intro.serve("127.0.0.1:0").unwrap();
+
+        let get = |request: &str| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(request.as_bytes()).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+        let status = get("GET /status HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert!(status.starts_with("HTTP/1.1 200 OK"), "{}", status);
+        let body = status.split("\r\n\r\n").nth(1).unwrap();
+        let json: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert!(json["programs"]["demo"]["functions"]["main"].is_object());
+        assert!(json["bandits"]["sum"].is_object());
+
+        assert!(get("GET /missing HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404"));
+        assert!(get("POST /status HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 405"));
+
+        // The path is escaped into the error, not pasted into the JSON
+        let missing = get("GET /\"},\"x\":{\" HTTP/1.1\r\n\r\n");
+        let body = missing.split("\r\n\r\n").nth(1).unwrap();
+        let json: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(json["error"], "no view at /\"},\"x\":{\"");
+
+        // An endless request line is cut off at the cap instead of read forever
+        let long = format!("GET /{}", "a".repeat(MAX_REQUEST_BYTES as usize - 5));
+        assert!(get(&long).starts_with("HTTP/1.1 400"));
+    }
+
+    #[test]
+    fn test_silent_client_does_not_block_others() {
+        let intro = Introspection::new();
+        let (addr, _) = intro.serve("127.0.0.1:0").unwrap();
+        // Connects and never sends a byte
+        let _idle = TcpStream::connect(addr).unwrap();
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        stream.write_all(b"GET /memory HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    }
+}
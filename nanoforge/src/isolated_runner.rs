@@ -0,0 +1,203 @@
+//! Isolated Runner
+//!
+//! Runs untrusted machine code in a forked child, so a crash, a hang or a
+//! clobbered callee-saved register can only take down the child. The code is
+//! mapped before forking; the child just calls it, writes the results to a
+//! pipe and `_exit`s. (Nothing that allocates or takes locks: another thread
+//! may have held them at fork time. `run_forked` callers that need a lock in
+//! the child take it before forking.)
+
+use crate::jit_memory::DualMappedMemory;
+use std::time::{Duration, Instant};
+
+/// Why an isolated run produced no results
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IsolatedFailure {
+    /// The child was killed by this signal (SIGSEGV, SIGILL, ...)
+    Crashed(i32),
+    /// Still running at the deadline; the child was killed
+    Timeout,
+    /// Mapping, pipe or fork failed, or the child exited abnormally
+    Setup(String),
+}
+
+impl std::fmt::Display for IsolatedFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IsolatedFailure::Crashed(signal) => write!(f, "crashed with signal {}", signal),
+            IsolatedFailure::Timeout => write!(f, "timed out"),
+            IsolatedFailure::Setup(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Call `code` (an `extern "C" fn(i64) -> i64`) on each input in a child
+/// process, returning the outputs in order
+pub fn run_isolated(
+    code: &[u8],
+    inputs: &[i64],
+    timeout: Duration,
+) -> Result<Vec<i64>, IsolatedFailure> {
+    let memory = DualMappedMemory::with_code(code).map_err(IsolatedFailure::Setup)?;
+    let func: extern "C" fn(i64) -> i64 = unsafe { std::mem::transmute(memory.rx_ptr) };
+
+    run_forked(inputs.len(), timeout, |outputs| {
+        for (output, &input) in outputs.iter_mut().zip(inputs) {
+            *output = func(input);
+        }
+    })
+}
+
+/// Run `child` in a forked child process, returning the `outputs` words it
+/// fills in. `child` is dropped in the parent as soon as the fork is done, so
+/// it may own guards that must be held across the fork.
+pub fn run_forked<F: FnOnce(&mut [i64])>(
+    outputs: usize,
+    timeout: Duration,
+    child: F,
+) -> Result<Vec<i64>, IsolatedFailure> {
+    // Allocated before forking; the child only writes into it
+    let mut words = vec![0i64; outputs];
+
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(IsolatedFailure::Setup(format!(
+            "pipe: {}",
+            std::io::Error::last_os_error()
+        )));
+    }
+    let [read_fd, write_fd] = fds;
+
+    let pid = unsafe { libc::fork() };
+    if pid < 0 {
+        let err = std::io::Error::last_os_error();
+        unsafe {
+            libc::close(read_fd);
+            libc::close(write_fd);
+        }
+        return Err(IsolatedFailure::Setup(format!("fork: {}", err)));
+    }
+    if pid == 0 {
+        unsafe {
+            libc::close(read_fd);
+            // Die by the signal (instead of the crash handler's exit) so the parent sees it
+            for signal in [libc::SIGSEGV, libc::SIGILL, libc::SIGBUS, libc::SIGFPE] {
+                libc::signal(signal, libc::SIG_DFL);
+            }
+            child(&mut words);
+            let len = words.len() * 8;
+            if libc::write(write_fd, words.as_ptr().cast(), len) != len as isize {
+                libc::_exit(2);
+            }
+            libc::_exit(0);
+        }
+    }
+    drop(child);
+
+    unsafe { libc::close(write_fd) };
+    let outcome = collect_output(read_fd, outputs * 8, Instant::now() + timeout);
+    unsafe { libc::close(read_fd) };
+    if outcome.is_err() {
+        unsafe { libc::kill(pid, libc::SIGKILL) };
+    }
+
+    let mut status = 0;
+    unsafe { libc::waitpid(pid, &mut status, 0) };
+    let bytes = outcome?;
+    if libc::WIFSIGNALED(status) {
+        return Err(IsolatedFailure::Crashed(libc::WTERMSIG(status)));
+    }
+    if !libc::WIFEXITED(status) || libc::WEXITSTATUS(status) != 0 || bytes.len() != outputs * 8 {
+        return Err(IsolatedFailure::Setup(format!(
+            "child exited abnormally (status {:#x}, {} of {} results)",
+            status,
+            bytes.len() / 8,
+            outputs
+        )));
+    }
+
+    Ok(bytes
+        .chunks_exact(8)
+        .map(|c| i64::from_ne_bytes(c.try_into().unwrap()))
+        .collect())
+}
+
+/// Read up to `expected` bytes until EOF or the deadline
+fn collect_output(fd: i32, expected: usize, deadline: Instant) -> Result<Vec<u8>, IsolatedFailure> {
+    let mut bytes = Vec::with_capacity(expected);
+    let mut buf = [0u8; 4096];
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(IsolatedFailure::Timeout);
+        }
+        let mut poll_fd = libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let ready = unsafe { libc::poll(&mut poll_fd, 1, remaining.as_millis().max(1) as i32) };
+        if ready == 0 {
+            return Err(IsolatedFailure::Timeout);
+        }
+        if ready < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() == std::io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(IsolatedFailure::Setup(format!("poll: {}", err)));
+        }
+        let n = unsafe { libc::read(fd, buf.as_mut_ptr().cast(), buf.len()) };
+        match n {
+            0 => return Ok(bytes),
+            n if n > 0 => bytes.extend_from_slice(&buf[..n as usize]),
+            _ => {
+                let err = std::io::Error::last_os_error();
+                if err.kind() != std::io::ErrorKind::Interrupted {
+                    return Err(IsolatedFailure::Setup(format!("read: {}", err)));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    #[test]
+    fn test_runs_code_and_returns_outputs() {
+        // lea rax, [rdi + 1]; ret
+        let code = [0x48, 0x8d, 0x47, 0x01, 0xc3];
+        assert_eq!(
+            run_isolated(&code, &[1, -5, 41], TIMEOUT),
+            Ok(vec![2, -4, 42])
+        );
+    }
+
+    #[test]
+    fn test_crashes_and_hangs_stay_in_the_child() {
+        // mov rax, [0]
+        let segfault = [0x48, 0x8b, 0x04, 0x25, 0x00, 0x00, 0x00, 0x00, 0xc3];
+        assert_eq!(
+            run_isolated(&segfault, &[0], TIMEOUT),
+            Err(IsolatedFailure::Crashed(libc::SIGSEGV))
+        );
+
+        // ud2
+        let illegal = [0x0f, 0x0b];
+        assert_eq!(
+            run_isolated(&illegal, &[0], TIMEOUT),
+            Err(IsolatedFailure::Crashed(libc::SIGILL))
+        );
+
+        // jmp $
+        let spin = [0xeb, 0xfe];
+        assert_eq!(
+            run_isolated(&spin, &[0], Duration::from_millis(100)),
+            Err(IsolatedFailure::Timeout)
+        );
+    }
+}
@@ -1,13 +1,162 @@
+use serde::Serialize;
 use std::ffi::CString;
 use std::fmt;
 use std::os::unix::io::RawFd;
 use std::ptr;
+use std::sync::atomic::{AtomicI32, AtomicI64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+static LIVE_REGIONS: AtomicUsize = AtomicUsize::new(0);
+static LIVE_BYTES: AtomicUsize = AtomicUsize::new(0);
+static LIVE_TOGGLED: AtomicUsize = AtomicUsize::new(0);
+/// Cap on `LIVE_BYTES` (0: none)
+static LIMIT: AtomicUsize = AtomicUsize::new(0);
+static RECLAIMED_BYTES: AtomicUsize = AtomicUsize::new(0);
+static RECLAIMERS: Mutex<Vec<Weak<dyn Reclaimer>>> = Mutex::new(Vec::new());
+
+/// Executable memory currently mapped by this process
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct JitMemoryUsage {
+    /// Live `DualMappedMemory` regions
+    pub regions: usize,
+    /// Their total size (dual-mapped regions are mapped twice, RW and RX)
+    pub bytes: usize,
+    /// Regions in `MappingMode::Toggle`
+    pub toggled: usize,
+    /// Cap on `bytes` (see `set_limit`)
+    pub limit: Option<usize>,
+    /// Bytes reclaimers have freed to stay under the cap
+    pub reclaimed: usize,
+}
+
+/// Totals over every live `DualMappedMemory`
+pub fn usage() -> JitMemoryUsage {
+    JitMemoryUsage {
+        regions: LIVE_REGIONS.load(Ordering::Relaxed),
+        bytes: LIVE_BYTES.load(Ordering::Relaxed),
+        toggled: LIVE_TOGGLED.load(Ordering::Relaxed),
+        limit: limit(),
+        reclaimed: RECLAIMED_BYTES.load(Ordering::Relaxed),
+    }
+}
+
+/// Cap the bytes of live regions (None: no cap). A mapping that would go
+/// over it first asks the registered reclaimers to free code nobody runs,
+/// and fails if they can't free enough.
+pub fn set_limit(bytes: Option<usize>) {
+    LIMIT.store(bytes.unwrap_or(0), Ordering::Relaxed);
+    publish();
+}
+
+pub fn limit() -> Option<usize> {
+    Some(LIMIT.load(Ordering::Relaxed)).filter(|&bytes| bytes > 0)
+}
+
+/// Something holding JIT code it can drop and rebuild later, e.g. a
+/// `VariantCache`
+pub trait Reclaimer: Send + Sync {
+    /// Unmap code worth about `bytes` (more or less); returns the bytes
+    /// freed. Called from whatever thread is mapping, so it must not map
+    /// memory itself or wait on a lock held while mapping.
+    fn reclaim(&self, bytes: usize) -> usize;
+}
+
+/// Ask `reclaimer` for memory when the cap is reached, for as long as it
+/// is alive
+pub fn register_reclaimer(reclaimer: Weak<dyn Reclaimer>) {
+    RECLAIMERS.lock().unwrap().push(reclaimer);
+}
+
+/// Ask the live reclaimers, in registration order, to free `bytes`
+fn reclaim(bytes: usize) -> usize {
+    let reclaimers: Vec<Arc<dyn Reclaimer>> = {
+        let mut list = RECLAIMERS.lock().unwrap();
+        list.retain(|r| r.strong_count() > 0);
+        list.iter().filter_map(Weak::upgrade).collect()
+    };
+    let mut freed = 0;
+    for reclaimer in reclaimers {
+        if freed >= bytes {
+            break;
+        }
+        freed += reclaimer.reclaim(bytes - freed);
+    }
+    if freed > 0 {
+        RECLAIMED_BYTES.fetch_add(freed, Ordering::Relaxed);
+        metrics::counter!("nanoforge_jit_memory_reclaimed_bytes", freed as u64);
+        tracing::info!("Reclaimed {} bytes of JIT memory", freed);
+    }
+    freed
+}
+
+/// Make room under the cap for a `size` byte region. Best effort under
+/// concurrent mapping: two threads may both see room for their region.
+fn reserve(size: usize) -> Result<(), String> {
+    let Some(limit) = limit() else {
+        return Ok(());
+    };
+    let live = LIVE_BYTES.load(Ordering::Relaxed);
+    if live + size > limit {
+        reclaim(live + size - limit);
+    }
+    let live = LIVE_BYTES.load(Ordering::Relaxed);
+    if live + size > limit {
+        return Err(format!(
+            "JIT memory cap of {} bytes reached: {} bytes live, {} more requested",
+            limit, live, size
+        ));
+    }
+    Ok(())
+}
+
+/// Export `usage` as Prometheus gauges (a no-op until a recorder is
+/// installed)
+fn publish() {
+    let usage = usage();
+    metrics::gauge!("nanoforge_jit_memory_bytes", usage.bytes as f64);
+    metrics::gauge!("nanoforge_jit_memory_regions", usage.regions as f64);
+    metrics::gauge!("nanoforge_jit_memory_limit_bytes", usage.limit.unwrap_or(0) as f64);
+}
+
+/// How a `DualMappedMemory` keeps its code writable but never writable and
+/// executable at once
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MappingMode {
+    /// One shared-memory file mapped twice, RW and RX
+    Dual,
+    /// One private mapping that is never writable: for hardened systems
+    /// (SELinux, grsecurity, some containers) that deny executable shared
+    /// mappings. `rw_ptr` and `rx_ptr` are the same address, so only `write`
+    /// and the patch methods may store to it. They change a writable copy of
+    /// the pages they touch, make it executable and `mremap` it over the
+    /// originals, so code running there on another thread sees the old page
+    /// or the new one, never a missing or non-executable one.
+    Toggle,
+}
+
+/// An immediate in emitted code that can be rewritten in place
+/// (`DualMappedMemory::patch_i32`/`patch_i64`) instead of recompiling, e.g. a
+/// fuel budget or a call target. The immediate is aligned to its width so the
+/// write is a single atomic store.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PatchPoint {
+    pub name: String,
+    /// Byte offset of the immediate from the start of the code
+    pub offset: usize,
+    /// Immediate size in bytes (4 or 8)
+    pub width: usize,
+}
 
 pub struct DualMappedMemory {
     pub rw_ptr: *mut u8,
     pub rx_ptr: *const u8,
     pub size: usize,
     fd: RawFd,
+    mode: MappingMode,
+    /// Serializes writes in toggle mode, so one writer's copy of a page
+    /// can't swap out another's changes
+    toggle: Mutex<()>,
 }
 
 impl fmt::Debug for DualMappedMemory {
@@ -16,12 +165,46 @@ impl fmt::Debug for DualMappedMemory {
             .field("size", &self.size)
             .field("rw_ptr", &format_args!("{:p}", self.rw_ptr))
             .field("rx_ptr", &format_args!("{:p}", self.rx_ptr))
+            .field("mode", &self.mode)
             .finish()
     }
 }
 
 impl DualMappedMemory {
+    /// `size` bytes of executable memory, dual-mapped if the system allows
+    /// it and in toggle mode otherwise
     pub fn new(size: usize) -> Result<Self, String> {
+        reserve(size)?;
+        Self::map(size, MappingMode::Dual).or_else(|dual| {
+            tracing::debug!("Dual mapping denied ({}), toggling W^X instead", dual);
+            Self::map(size, MappingMode::Toggle)
+                .map_err(|toggle| format!("{}; W^X toggle fallback: {}", dual, toggle))
+        })
+    }
+
+    /// `new` holding `code` at offset 0
+    pub fn with_code(code: &[u8]) -> Result<Self, String> {
+        let memory = Self::new(code.len().max(4096))?;
+        memory.write(0, code)?;
+        Ok(memory)
+    }
+
+    /// `new` without the fallback
+    pub fn with_mode(size: usize, mode: MappingMode) -> Result<Self, String> {
+        reserve(size)?;
+        Self::map(size, mode)
+    }
+
+    fn map(size: usize, mode: MappingMode) -> Result<Self, String> {
+        let memory = match mode {
+            MappingMode::Dual => Self::dual(size),
+            MappingMode::Toggle => Self::toggled(size),
+        };
+        publish();
+        memory
+    }
+
+    fn dual(size: usize) -> Result<Self, String> {
         unsafe {
             // 1. Create an anonymous file in memory
             let name = CString::new("nanoforge_jit").unwrap();
@@ -65,78 +248,250 @@ impl DualMappedMemory {
                 return Err("mmap RX failed".to_string());
             }
 
+            LIVE_REGIONS.fetch_add(1, Ordering::Relaxed);
+            LIVE_BYTES.fetch_add(size, Ordering::Relaxed);
             Ok(DualMappedMemory {
                 rw_ptr: rw_ptr as *mut u8,
                 rx_ptr: rx_ptr as *const u8,
                 size,
                 fd,
+                mode: MappingMode::Dual,
+                toggle: Mutex::new(()),
             })
         }
     }
 
+    fn toggled(size: usize) -> Result<Self, String> {
+        unsafe {
+            // Zeroed and executable until written
+            let ptr = libc::mmap(
+                ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_EXEC,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            if ptr == libc::MAP_FAILED {
+                return Err("mmap RX failed".to_string());
+            }
+
+            LIVE_REGIONS.fetch_add(1, Ordering::Relaxed);
+            LIVE_BYTES.fetch_add(size, Ordering::Relaxed);
+            LIVE_TOGGLED.fetch_add(1, Ordering::Relaxed);
+            Ok(DualMappedMemory {
+                rw_ptr: ptr as *mut u8,
+                rx_ptr: ptr as *const u8,
+                size,
+                fd: -1,
+                mode: MappingMode::Toggle,
+                toggle: Mutex::new(()),
+            })
+        }
+    }
+
+    pub fn mode(&self) -> MappingMode {
+        self.mode
+    }
+
+    /// Copy `bytes` to `offset` and make them visible to instruction fetch
+    pub fn write(&self, offset: usize, bytes: &[u8]) -> Result<(), String> {
+        if offset + bytes.len() > self.size {
+            return Err(format!(
+                "{} bytes at {:#x} overrun {} bytes of JIT memory",
+                bytes.len(),
+                offset,
+                self.size
+            ));
+        }
+        self.writable(offset, bytes.len(), |at| unsafe {
+            ptr::copy_nonoverlapping(bytes.as_ptr(), at, bytes.len());
+        })?;
+        self.flush_icache_range(offset, bytes.len());
+        Ok(())
+    }
+
+    /// Run `store`, which writes `len` bytes at the pointer it is given, to
+    /// write them at `offset`. In toggle mode the pointer is into a copy of
+    /// the pages, swapped in once written (see `MappingMode::Toggle`).
+    fn writable(&self, offset: usize, len: usize, store: impl FnOnce(*mut u8)) -> Result<(), String> {
+        if self.mode == MappingMode::Dual {
+            store(unsafe { self.rw_ptr.add(offset) });
+            return Ok(());
+        }
+        let _lock = self.toggle.lock().unwrap_or_else(|e| e.into_inner());
+        let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) }.max(4096) as usize;
+        let start = offset / page * page;
+        let end = (offset + len).div_ceil(page) * page;
+        unsafe {
+            let copy = libc::mmap(
+                ptr::null_mut(),
+                end - start,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            if copy == libc::MAP_FAILED {
+                return Err("mmap RW failed".to_string());
+            }
+            let copy = copy as *mut u8;
+            ptr::copy_nonoverlapping(self.rx_ptr.add(start), copy, end - start);
+            store(copy.add(offset - start));
+            if libc::mprotect(copy as *mut libc::c_void, end - start, libc::PROT_READ | libc::PROT_EXEC) != 0 {
+                libc::munmap(copy as *mut libc::c_void, end - start);
+                return Err("mprotect RX failed".to_string());
+            }
+            let swapped = libc::mremap(
+                copy as *mut libc::c_void,
+                end - start,
+                end - start,
+                libc::MREMAP_MAYMOVE | libc::MREMAP_FIXED,
+                self.rw_ptr.add(start) as *mut libc::c_void,
+            );
+            if swapped == libc::MAP_FAILED {
+                libc::munmap(copy as *mut libc::c_void, end - start);
+                return Err("mremap over the old pages failed".to_string());
+            }
+        }
+        Ok(())
+    }
+
     /// Flushes the Instruction Cache for the allocated memory.
     /// This ensures that the CPU sees the new instructions we just wrote.
     pub fn flush_icache(&self) {
-        unsafe {
-            // __builtin___clear_cache is a GCC/Clang intrinsic.
-            // In Rust, we can use the unstable std::intrinsics or just call a C function.
-            // However, libc doesn't always expose it.
-            // For x86_64, strictly speaking, hardware handles coherency, but 'clflush' is good practice.
-            // A portable way in Rust is hard without nightly.
-            // We will use a simple assembly block for x86_64 to serialize.
+        self.flush_icache_range(0, self.size);
+    }
+
+    /// Atomically overwrite the aligned 32-bit immediate at `offset` and make
+    /// the new value visible to instruction fetch. Code running concurrently
+    /// sees either the old or the new value, never a mix.
+    pub fn patch_i32(&self, offset: usize, value: i32) -> Result<(), String> {
+        self.check_patch(offset, 4)?;
+        self.writable(offset, 4, |at| unsafe {
+            AtomicI32::from_ptr(at as *mut i32).store(value, Ordering::SeqCst);
+        })?;
+        self.flush_icache_range(offset, 4);
+        Ok(())
+    }
+
+    /// `patch_i32` for an 8-byte aligned 64-bit immediate
+    pub fn patch_i64(&self, offset: usize, value: i64) -> Result<(), String> {
+        self.check_patch(offset, 8)?;
+        self.writable(offset, 8, |at| unsafe {
+            AtomicI64::from_ptr(at as *mut i64).store(value, Ordering::SeqCst);
+        })?;
+        self.flush_icache_range(offset, 8);
+        Ok(())
+    }
+
+    /// The 32-bit immediate at `offset`, as currently seen by executing code
+    pub fn read_i32(&self, offset: usize) -> Option<i32> {
+        self.check_patch(offset, 4).ok()?;
+        Some(unsafe { ptr::read_volatile(self.rx_ptr.add(offset) as *const i32) })
+    }
+
+    /// The 64-bit immediate at `offset`, as currently seen by executing code
+    pub fn read_i64(&self, offset: usize) -> Option<i64> {
+        self.check_patch(offset, 8).ok()?;
+        Some(unsafe { ptr::read_volatile(self.rx_ptr.add(offset) as *const i64) })
+    }
 
+    fn check_patch(&self, offset: usize, width: usize) -> Result<(), String> {
+        if !offset.is_multiple_of(width) || offset + width > self.size {
+            return Err(format!("Invalid {}-byte patch offset {:#x}", width, offset));
+        }
+        Ok(())
+    }
+
+    /// `flush_icache` for `len` bytes at `offset`. On aarch64 this cleans
+    /// the data cache lines written through `rw_ptr` and invalidates the
+    /// instruction cache lines fetched through `rx_ptr`, at the line sizes
+    /// `CTR_EL0` reports, and skips whichever step the CPU makes redundant.
+    /// Only the calling thread is synchronized with the new code: other
+    /// threads that may already be running near it need `sync_cores`.
+    #[cfg_attr(not(target_arch = "aarch64"), allow(unused_variables))]
+    pub fn flush_icache_range(&self, offset: usize, len: usize) {
+        unsafe {
             #[cfg(target_arch = "x86_64")]
             {
-                // mfence is sufficient to drain store buffers.
-                // For full serialization, cpuid is needed, but rbx is reserved by LLVM.
-                // We'll just use mfence for this PoC to avoid complexity.
+                // Instruction fetch snoops stores on x86, so draining the
+                // store buffer is enough for this thread
                 std::arch::asm!("mfence", options(nostack));
             }
 
             #[cfg(target_arch = "aarch64")]
             {
-                // Aarch64 Cache Coherency:
-                // 1. Clean data cache by VA to PoU (Point of Unification)
-                // 2. Invalidate instruction cache by VA to PoU
-                // 3. ISB (Instruction Synchronization Barrier) to ensure fetch pipeline sees it.
-
-                let start = self.rx_ptr as usize;
-                let end = start + self.size;
-                // Cache line size is usually 64 bytes (CTR_EL0), but we'll iterate.
-                // Ideally reading lookup size is better, but step of 64 is safe on modern ARM64.
-                // Or we can rely on system primitives.
-                // For this PoC, we do a loop.
-
-                let stride = 64;
-                let mut addr = start;
-                while addr < end {
-                    // DC CVAU: Data Cache Clean by VA to Point of Unification
-                    std::arch::asm!("dc cvau, {0}", in(reg) addr);
-                    addr += stride;
-                }
-
-                std::arch::asm!("dsb ish"); // Data Synchronization Barrier (Inner Shareable)
+                let ctr: u64;
+                std::arch::asm!("mrs {}, ctr_el0", out(reg) ctr, options(nomem, nostack));
+                // IDC: data cache clean to the point of unification not needed
+                const CTR_IDC: u64 = 1 << 28;
+                // DIC: instruction cache invalidation not needed
+                const CTR_DIC: u64 = 1 << 29;
+                // DminLine and IminLine: log2 of the smallest line in words
+                let dline = 4usize << ((ctr >> 16) & 0xf);
+                let iline = 4usize << (ctr & 0xf);
 
-                addr = start;
-                while addr < end {
-                    // IC IVAU: Instruction Cache Invalidate by VA to Point of Unification
-                    std::arch::asm!("ic ivau, {0}", in(reg) addr);
-                    addr += stride;
+                if ctr & CTR_IDC == 0 {
+                    let start = self.rw_ptr as usize + offset;
+                    let mut addr = start & !(dline - 1);
+                    while addr < start + len {
+                        std::arch::asm!("dc cvau, {}", in(reg) addr, options(nostack));
+                        addr += dline;
+                    }
                 }
+                std::arch::asm!("dsb ish", options(nostack));
 
-                std::arch::asm!("dsb ish"); // Ensure IC invalidation completes
-                std::arch::asm!("isb"); // Instruction Synchronization Barrier (Flush pipeline)
+                if ctr & CTR_DIC == 0 {
+                    let start = self.rx_ptr as usize + offset;
+                    let mut addr = start & !(iline - 1);
+                    while addr < start + len {
+                        std::arch::asm!("ic ivau, {}", in(reg) addr, options(nostack));
+                        addr += iline;
+                    }
+                    std::arch::asm!("dsb ish", options(nostack));
+                }
+                std::arch::asm!("isb", options(nostack));
             }
+        }
+    }
+}
 
-            // Ideally we would use:
-            // extern "C" { fn __clear_cache(start: *mut c_void, end: *mut c_void); }
-            // __clear_cache(self.rx_ptr as *mut _, self.rx_ptr.add(self.size) as *mut _);
+const MEMBARRIER_CMD_PRIVATE_EXPEDITED_SYNC_CORE: libc::c_int = 1 << 5;
+const MEMBARRIER_CMD_REGISTER_PRIVATE_EXPEDITED_SYNC_CORE: libc::c_int = 1 << 6;
+
+/// Make every running thread of this process execute a context
+/// synchronizing event (`isb` on aarch64, a serializing instruction on
+/// x86), so none keeps executing instructions it fetched before code was
+/// rewritten. Call it after writing and flushing new code and before
+/// publishing it. Uses `membarrier(2)`, registering on first use; false if
+/// the kernel doesn't support it, in which case only the usual fence is
+/// issued.
+pub fn sync_cores() -> bool {
+    static REGISTERED: std::sync::OnceLock<bool> = std::sync::OnceLock::new();
+    let registered = *REGISTERED.get_or_init(|| unsafe {
+        libc::syscall(
+            libc::SYS_membarrier,
+            MEMBARRIER_CMD_REGISTER_PRIVATE_EXPEDITED_SYNC_CORE,
+            0,
+            0,
+        ) == 0
+    });
+    if registered {
+        let synced = unsafe {
+            libc::syscall(libc::SYS_membarrier, MEMBARRIER_CMD_PRIVATE_EXPEDITED_SYNC_CORE, 0, 0)
+        };
+        if synced == 0 {
+            return true;
         }
     }
+    std::sync::atomic::fence(Ordering::SeqCst);
+    false
 }
 
 // SAFETY: We are responsible for ensuring no data races occur.
-// The RW view is only used during initialization (before publishing).
+// The RW view is only used during initialization (before publishing) and
+// for atomic patches; in toggle mode those hold `toggle` and write a copy.
 // The RX view is read-only after publishing.
 unsafe impl Send for DualMappedMemory {}
 unsafe impl Sync for DualMappedMemory {}
@@ -145,8 +500,72 @@ impl Drop for DualMappedMemory {
     fn drop(&mut self) {
         unsafe {
             libc::munmap(self.rw_ptr as *mut _, self.size);
-            libc::munmap(self.rx_ptr as *mut _, self.size);
-            libc::close(self.fd);
+            if self.mode == MappingMode::Dual {
+                libc::munmap(self.rx_ptr as *mut _, self.size);
+                libc::close(self.fd);
+            }
+        }
+        LIVE_REGIONS.fetch_sub(1, Ordering::Relaxed);
+        LIVE_BYTES.fetch_sub(self.size, Ordering::Relaxed);
+        if self.mode == MappingMode::Toggle {
+            LIVE_TOGGLED.fetch_sub(1, Ordering::Relaxed);
         }
+        publish();
+    }
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+
+    /// `mov eax, 1; ret`, with the immediate 4-byte aligned at offset 4
+    const RETURN_ONE: [u8; 9] = [0x90, 0x90, 0x90, 0xb8, 1, 0, 0, 0, 0xc3];
+
+    #[test]
+    fn test_both_modes_run_and_patch_code() {
+        for mode in [MappingMode::Dual, MappingMode::Toggle] {
+            let memory = DualMappedMemory::with_mode(4096, mode).unwrap();
+            assert_eq!(memory.mode(), mode);
+            assert_eq!(mode == MappingMode::Toggle, ptr::eq(memory.rw_ptr, memory.rx_ptr));
+            assert!(usage().regions >= 1);
+            memory.write(0, &RETURN_ONE).unwrap();
+            let f: extern "C" fn() -> i32 = unsafe { std::mem::transmute(memory.rx_ptr) };
+            assert_eq!(f(), 1, "{:?}", mode);
+
+            memory.patch_i32(4, 42).unwrap();
+            assert_eq!((f(), memory.read_i32(4)), (42, Some(42)), "{:?}", mode);
+            assert!(memory.write(4090, &RETURN_ONE).is_err());
+        }
+    }
+
+    #[test]
+    fn test_toggle_patches_while_code_runs() {
+        let memory = DualMappedMemory::with_mode(4096, MappingMode::Toggle).unwrap();
+        memory.write(0, &RETURN_ONE).unwrap();
+        let f: extern "C" fn() -> i32 = unsafe { std::mem::transmute(memory.rx_ptr) };
+        let stop = std::sync::atomic::AtomicBool::new(false);
+        std::thread::scope(|scope| {
+            let runner = scope.spawn(|| {
+                let mut calls = 0u64;
+                while !stop.load(Ordering::Relaxed) {
+                    assert!((1..=1000).contains(&f()));
+                    calls += 1;
+                }
+                calls
+            });
+            for value in 1..=1000 {
+                memory.patch_i32(4, value).unwrap();
+            }
+            stop.store(true, Ordering::Relaxed);
+            assert!(runner.join().unwrap() > 0);
+        });
+        assert_eq!(f(), 1000);
+    }
+
+    #[test]
+    fn test_sync_cores_registers_once() {
+        // Kernels before 4.16 (and some sandboxes) lack membarrier SYNC_CORE
+        let supported = sync_cores();
+        assert_eq!(sync_cores(), supported);
     }
 }
@@ -0,0 +1,317 @@
+//! Lazy Compilation
+//!
+//! `LazyProgram` compiles each function the first time it is called. Until
+//! then, calls to it land in a small stub that asks the program to compile
+//! the callee, back-patches every call site already emitted for it (see
+//! `CompileOptions::lazy_calls`) and jumps to the fresh code. Large programs
+//! start as soon as their entry point is compiled, and functions that never
+//! run are never compiled.
+
+use crate::assembler::JitBuilder;
+use crate::compiled_program::JitFn;
+use crate::compiler::{self, CompileOptions, CompiledCode, Compiler};
+use crate::host_args::{self, MAX_ARGS};
+use crate::ir::Program;
+use crate::jit_memory::DualMappedMemory;
+use crate::status::{self, Status};
+use std::sync::Mutex;
+
+/// Patch point in the stubs holding the `LazyState` address
+const CTX_PATCH_POINT: &str = "lazy_ctx";
+
+/// One function, compiled on demand
+struct CompiledFunction {
+    memory: DualMappedMemory,
+    code: CompiledCode,
+    entry: u64,
+}
+
+/// Everything the stubs reach through their context pointer
+struct LazyState {
+    program: Program,
+    options: CompileOptions,
+    stubs: DualMappedMemory,
+    /// Offset of each function's stub, indexed like `program.functions`
+    stub_offsets: Vec<usize>,
+    /// Stub returning 0, used when compilation fails (with `Status::CompileFailed` raised)
+    bail_offset: usize,
+    compiled: Mutex<Vec<Option<CompiledFunction>>>,
+    errors: Mutex<Vec<String>>,
+}
+
+/// A program whose functions are compiled on first call
+pub struct LazyProgram {
+    state: Box<LazyState>,
+}
+
+impl LazyProgram {
+    /// Emit the stubs; no function is compiled yet
+    pub fn new(prog: &Program, options: &CompileOptions) -> Result<Self, String> {
+        compiler::check_call_targets(prog)?;
+        let mut builder = JitBuilder::new();
+        let resolve = nf_lazy_resolve as *const () as usize as u64;
+        let mut stub_offsets = Vec::with_capacity(prog.functions.len());
+        for index in 0..prog.functions.len() {
+            builder.align(16);
+            stub_offsets.push(builder.current_offset());
+            // Keep the callee's arguments (rdi, rsi, rdx, rcx) and 16-byte alignment
+            for reg in [11, 12, 13, 6] {
+                builder.push_reg(reg);
+            }
+            builder.add_rsp(-8);
+            builder.mov_reg_imm64_patchable(CTX_PATCH_POINT, 11, 0);
+            builder.mov_reg_imm(12, index as i32);
+            builder.mov_reg_imm64(0, resolve);
+            builder.call_reg(0);
+            builder.add_rsp(8);
+            for reg in [6, 13, 12, 11] {
+                builder.pop_reg(reg);
+            }
+            builder.jmp_reg(0);
+        }
+        builder.align(16);
+        let bail_offset = builder.current_offset();
+        builder.mov_reg_imm(0, 0);
+        builder.ret();
+
+        let patch_points = builder.patch_points().to_vec();
+        let code = builder.finalize();
+        let stubs = DualMappedMemory::with_code(&code)?;
+
+        let state = Box::new(LazyState {
+            program: prog.clone(),
+            options: options.lazy_calls(true),
+            stubs,
+            stub_offsets,
+            bail_offset,
+            compiled: Mutex::new(prog.functions.iter().map(|_| None).collect()),
+            errors: Mutex::new(Vec::new()),
+        });
+        let ctx = &*state as *const LazyState as i64;
+        for point in &patch_points {
+            state.stubs.patch_i64(point.offset, ctx)?;
+        }
+        state.stubs.flush_icache();
+        Ok(Self { state })
+    }
+
+    fn index_of(&self, name: &str) -> Option<usize> {
+        self.state
+            .program
+            .functions
+            .iter()
+            .position(|f| f.name == name)
+    }
+
+    /// `name` as a typed function pointer (its stub until compiled), like
+    /// `CompiledProgram::get`. Valid while this `LazyProgram` is alive.
+    pub fn get<F: JitFn>(&self, name: &str) -> Option<F> {
+        let index = self.index_of(name)?;
+        let arity = self.state.program.functions[index].args.len();
+        (arity <= F::ARITY).then(|| unsafe { F::from_entry(self.state.entry(index) as *const u8) })
+    }
+
+    /// Call `name`, compiling whatever it reaches on the way
+    pub fn call(&self, name: &str, args: &[i64]) -> Result<i64, String> {
+        let index = self
+            .index_of(name)
+            .ok_or_else(|| format!("No function named {}", name))?;
+        let arity = self.state.program.functions[index].args.len();
+        if args.len() != arity || arity > MAX_ARGS {
+            return Err(format!(
+                "{}() takes {} argument(s), got {}",
+                name,
+                arity,
+                args.len()
+            ));
+        }
+
+        let failures = self.errors().len();
+        status::reset();
+        let result =
+            unsafe { host_args::call_with_args(self.state.entry(index) as *const u8, args) };
+        match status::take() {
+            Status::Ok => Ok(result),
+            Status::CompileFailed => Err(self.errors()[failures].clone()),
+            status => Err(format!("{}(): {}", name, status)),
+        }
+    }
+
+    /// Compile `name` now instead of on its first call
+    pub fn compile(&self, name: &str) -> Result<(), String> {
+        let index = self
+            .index_of(name)
+            .ok_or_else(|| format!("No function named {}", name))?;
+        self.state.compile(index).map(|_| ())
+    }
+
+    /// Names of the functions compiled so far
+    pub fn compiled_functions(&self) -> Vec<String> {
+        let compiled = self.state.compiled.lock().unwrap();
+        self.state
+            .program
+            .functions
+            .iter()
+            .zip(compiled.iter())
+            .filter(|(_, c)| c.is_some())
+            .map(|(f, _)| f.name.clone())
+            .collect()
+    }
+
+    /// Compilation failures hit while running (each raised `Status::CompileFailed`)
+    pub fn errors(&self) -> Vec<String> {
+        self.state.errors.lock().unwrap().clone()
+    }
+}
+
+impl LazyState {
+    /// Address calls to function `index` should use right now
+    fn entry(&self, index: usize) -> u64 {
+        let compiled = self.compiled.lock().unwrap();
+        match &compiled[index] {
+            Some(f) => f.entry,
+            None => self.stubs.rx_ptr as u64 + self.stub_offsets[index] as u64,
+        }
+    }
+
+    /// Compile function `index` if needed and return its entry
+    fn compile(&self, index: usize) -> Result<u64, String> {
+        let mut compiled = self.compiled.lock().unwrap();
+        if let Some(f) = &compiled[index] {
+            return Ok(f.entry);
+        }
+
+        let func = &self.program.functions[index];
+        let single = Program {
+            functions: vec![func.clone()],
+        };
+        let code = Compiler::compile_with_options(&single, &self.options)?;
+        let memory = DualMappedMemory::with_code(&code.code)?;
+        let entry = memory.rx_ptr as u64 + code.function_offsets[&func.name] as u64;
+
+        // Point this function's calls at compiled callees (itself included)
+        // or their stubs
+        for point in code.patch_points.iter().filter(|p| p.width == 8) {
+            let callee = self
+                .program
+                .functions
+                .iter()
+                .position(|f| compiler::call_patch_point(&f.name) == point.name)
+                .ok_or_else(|| format!("{}: call to an undefined function", func.name))?;
+            let target = match &compiled[callee] {
+                _ if callee == index => entry,
+                Some(f) => f.entry,
+                None => self.stubs.rx_ptr as u64 + self.stub_offsets[callee] as u64,
+            };
+            memory.patch_i64(point.offset, target as i64)?;
+        }
+        memory.flush_icache();
+
+        // Back-patch existing callers so they stop going through the stub
+        let name = compiler::call_patch_point(&func.name);
+        for f in compiled.iter().flatten() {
+            for point in f.code.patch_points.iter().filter(|p| p.name == name) {
+                f.memory.patch_i64(point.offset, entry as i64)?;
+            }
+        }
+
+        tracing::debug!("Lazily compiled {} ({} bytes)", func.name, code.code.len());
+        compiled[index] = Some(CompiledFunction {
+            memory,
+            code,
+            entry,
+        });
+        Ok(entry)
+    }
+
+    /// Called by the stubs: compile, or fall back to the bail-out stub
+    fn resolve(&self, index: usize) -> u64 {
+        match self.compile(index) {
+            Ok(entry) => entry,
+            Err(e) => {
+                let name = &self.program.functions[index].name;
+                tracing::error!("Lazy compilation of {} failed: {}", name, e);
+                self.errors
+                    .lock()
+                    .unwrap()
+                    .push(format!("Failed to compile {}: {}", name, e));
+                status::raise(Status::CompileFailed);
+                self.stubs.rx_ptr as u64 + self.bail_offset as u64
+            }
+        }
+    }
+}
+
+/// Entry point of the stubs
+extern "C" fn nf_lazy_resolve(ctx: *const LazyState, index: u64) -> u64 {
+    // SAFETY: the stubs embed the address of the boxed state that owns them
+    let state = unsafe { &*ctx };
+    state.resolve(index as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    #[test]
+    fn test_compiles_functions_on_first_call() {
+        let prog = Parser::new()
+            .parse(
+                "fn main(n) {
+                    x = twice(n)
+                    y = sum(10)
+                    r = x + y
+                    return r
+                }
+                fn twice(a) {
+                    b = a + a
+                    return b
+                }
+                fn sum(k) {
+                    if k == 0 goto zero
+                    k1 = k - 1
+                    s = sum(k1)
+                    r = s + k
+                    return r
+                    label zero
+                    return 0
+                }
+                fn unused(a) {
+                    return a
+                }",
+            )
+            .unwrap();
+        for level in [0, 2, 3] {
+            let lazy = LazyProgram::new(&prog, &CompileOptions::new(level)).unwrap();
+            assert!(lazy.compiled_functions().is_empty());
+
+            assert_eq!(lazy.call("main", &[21]), Ok(42 + 55));
+            assert_eq!(lazy.compiled_functions(), ["main", "twice", "sum"]);
+
+            // main's call sites now point straight at the compiled callees
+            let state = &lazy.state;
+            let compiled = state.compiled.lock().unwrap();
+            let main = compiled[0].as_ref().unwrap();
+            let site = main
+                .code
+                .patch_points
+                .iter()
+                .find(|p| p.name == "call_twice")
+                .unwrap();
+            let twice_entry = compiled[1].as_ref().unwrap().entry;
+            assert_eq!(main.memory.read_i64(site.offset), Some(twice_entry as i64));
+            drop(compiled);
+
+            let main: extern "C" fn(i64) -> i64 = lazy.get("main").unwrap();
+            assert_eq!(main(1), 2 + 55);
+            assert!(lazy.errors().is_empty());
+        }
+
+        let lazy = LazyProgram::new(&prog, &CompileOptions::new(2)).unwrap();
+        lazy.compile("unused").unwrap();
+        assert_eq!(lazy.compiled_functions(), ["unused"]);
+        assert!(lazy.call("main", &[]).unwrap_err().contains("takes 1"));
+        assert!(lazy.call("missing", &[]).is_err());
+    }
+}
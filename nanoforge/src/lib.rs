@@ -1,25 +1,84 @@
+// The front end, IR and optimizer live in nanoforge-core, which builds
+// without the JIT; they are re-exported here under their usual paths
+pub use nanoforge_core::{
+    ast, constant_time, diagnostics, ir, loops, lowering, optimizer, parser, passes, pipeliner,
+    pure, ranges, report, scheduler, specialize, types, verifier,
+};
+
+pub mod adaptive;
 pub mod ai_optimizer;
+pub mod alignment;
 pub mod array_ops;
+pub mod artifacts;
 pub mod assembler;
+pub mod assertion;
+pub mod audit;
 pub mod benchmark;
 pub mod benchmarker;
+pub mod brain_log;
+pub mod brain_report;
+pub mod bundle;
+pub mod capture;
+pub mod code_verifier;
+pub mod compile_service;
+pub mod compiled_program;
 pub mod compiler;
+pub mod const_eval;
+pub mod cost_model;
 pub mod cpu_features;
+pub mod disasm;
+pub mod dispatch;
+pub mod distribution;
 pub mod error;
+pub mod eval;
 pub mod evolution;
+pub mod explain;
 pub mod ffi;
+pub mod function_profiler;
+pub mod host_args;
 pub mod hot_function;
-pub mod ir;
+pub mod interpreter;
+pub mod introspection;
+pub mod isolated_runner;
 pub mod jit_memory;
+pub mod lazy;
+pub mod live_evolution;
+pub mod machine_genome;
+pub mod mem_trace;
 pub mod mutator;
-pub mod optimizer;
-pub mod parser;
+pub mod native;
+pub mod numa;
+pub mod overflow;
+pub mod parallel;
+pub mod pass_impact;
+pub mod pass_schedule;
+pub mod plugin;
 pub mod profiler;
 pub mod protocol;
 #[cfg(feature = "python")]
 pub mod pybindings;
 pub mod safety;
 pub mod sandbox;
+pub mod sanitizer;
+pub mod shutdown;
+pub mod simulation;
+pub mod soae;
+pub mod soak;
+pub mod spill_impact;
+pub mod stack_guard;
+pub mod status;
+pub mod store;
+pub mod template_jit;
+pub mod thermal;
 pub mod thread_safe;
+pub mod tiering;
+pub mod topology;
+pub mod tuning;
+pub mod uarch_db;
+pub mod unwind;
 pub mod validator;
+pub mod variant_cache;
 pub mod variant_generator;
+
+// Single-shot evaluation for playgrounds: `nanoforge::eval(source, inputs, limits)`
+pub use eval::{eval, EvalOutput};
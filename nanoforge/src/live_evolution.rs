@@ -0,0 +1,395 @@
+//! Live Evolution
+//!
+//! Connects evolution to hot swapping. A `LiveEvolver` thread watches a
+//! `HotFunction` that serves a script function, and every `interval`:
+//!
+//! 1. turns the captured inputs of its timed calls (see `capture`) into
+//!    test cases, with the seed function's outputs as ground truth
+//! 2. evolves the implementation currently installed against them
+//! 3. re-validates the best genome and, if it beats the current code by at
+//!    least `min_speedup`, compiles it and swaps it in while callers keep
+//!    calling
+//!
+//! Only functions that can run on their own are evolved (see `evolvable`).
+//! Swaps of an evolver started with `spawn_audited` are audited with the
+//! average cycles per call before the swap and up to the next round.
+
+use crate::audit::{AuditAction, AuditEvent, AuditLog};
+use crate::compiled_program::CompiledProgram;
+use crate::compiler::{CompileOptions, Compiler};
+use crate::evolution::{EvolutionConfig, EvolutionEngine};
+use crate::hot_function::HotFunction;
+use crate::ir::{Function, Opcode, Program};
+use crate::jit_memory::DualMappedMemory;
+use crate::shutdown;
+use crate::validator::{TestCase, Validator};
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Opt level candidates are measured at by the validator, and so swapped in at
+const LIVE_OPT_LEVEL: u8 = 0;
+
+/// How often the evolver thread checks whether it should stop
+const POLL: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LiveConfig {
+    /// Generations per round
+    pub generations: u32,
+    pub population: usize,
+    pub seed: u64,
+    /// Speedup over the installed code a winner needs to be swapped in
+    pub min_speedup: f64,
+    /// Distinct recorded inputs needed before a round starts
+    pub min_inputs: usize,
+    /// Pause between rounds
+    pub interval: Duration,
+}
+
+impl Default for LiveConfig {
+    fn default() -> Self {
+        Self {
+            generations: 5,
+            population: 16,
+            seed: 42,
+            min_speedup: 1.1,
+            min_inputs: 3,
+            interval: Duration::from_millis(200),
+        }
+    }
+}
+
+impl LiveConfig {
+    pub fn generations(mut self, generations: u32) -> Self {
+        self.generations = generations;
+        self
+    }
+
+    pub fn population(mut self, population: usize) -> Self {
+        self.population = population;
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub fn min_speedup(mut self, speedup: f64) -> Self {
+        self.min_speedup = speedup;
+        self
+    }
+
+    pub fn min_inputs(mut self, inputs: usize) -> Self {
+        self.min_inputs = inputs;
+        self
+    }
+
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+}
+
+/// What one round of evolution did
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LiveRound {
+    pub round: u32,
+    /// Distinct recorded inputs evolved against
+    pub inputs: usize,
+    pub generations: u32,
+    /// Speedup of the best genome over the installed code
+    pub speedup: f64,
+    /// Whether the winner was swapped in
+    pub swapped: bool,
+    /// Instructions of the code installed after the round
+    pub instructions: usize,
+}
+
+/// A background thread evolving the code behind a `HotFunction`
+pub struct LiveEvolver {
+    stop: Arc<AtomicBool>,
+    rounds: Arc<Mutex<Vec<LiveRound>>>,
+    handle: JoinHandle<Result<(), String>>,
+}
+
+impl LiveEvolver {
+    /// Start evolving `seed`, which `hot` must currently be running
+    pub fn spawn(
+        hot: Arc<HotFunction>,
+        seed: Function,
+        config: LiveConfig,
+    ) -> Result<Self, String> {
+        Self::start(hot, seed, config, None)
+    }
+
+    /// `spawn`, auditing every swap to `audit` as a change of `program`
+    pub fn spawn_audited(
+        hot: Arc<HotFunction>,
+        seed: Function,
+        config: LiveConfig,
+        audit: AuditLog,
+        program: &str,
+    ) -> Result<Self, String> {
+        Self::start(hot, seed, config, Some((audit, program.to_string())))
+    }
+
+    fn start(
+        hot: Arc<HotFunction>,
+        seed: Function,
+        config: LiveConfig,
+        audit: Option<(AuditLog, String)>,
+    ) -> Result<Self, String> {
+        evolvable(&seed).map_err(|why| format!("cannot evolve {}(): {}", seed.name, why))?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let rounds = Arc::new(Mutex::new(Vec::new()));
+        let handle = {
+            let (stop, rounds) = (stop.clone(), rounds.clone());
+            thread::Builder::new()
+                .name("nf-live-evolve".to_string())
+                .spawn(move || evolve_live(&hot, seed, &config, &stop, &rounds, audit))
+                .map_err(|e| format!("failed to start evolution: {}", e))?
+        };
+        Ok(Self {
+            stop,
+            rounds,
+            handle,
+        })
+    }
+
+    /// Rounds finished so far
+    pub fn rounds(&self) -> Vec<LiveRound> {
+        self.rounds.lock().unwrap().clone()
+    }
+
+    /// Stop after the current round and return every round
+    pub fn stop(self) -> Result<Vec<LiveRound>, String> {
+        self.stop.store(true, Ordering::Relaxed);
+        self.handle
+            .join()
+            .map_err(|_| "evolution thread panicked".to_string())??;
+        Ok(self.rounds.lock().unwrap().clone())
+    }
+}
+
+fn evolve_live(
+    hot: &HotFunction,
+    seed: Function,
+    config: &LiveConfig,
+    stop: &AtomicBool,
+    rounds: &Mutex<Vec<LiveRound>>,
+    audit: Option<(AuditLog, String)>,
+) -> Result<(), String> {
+    let stopping = || stop.load(Ordering::Relaxed) || shutdown::requested();
+    // The last swap, recorded once its code has been timed (or on stopping)
+    let mut pending: Option<AuditEvent> = None;
+    let flush = |pending: &mut Option<AuditEvent>| {
+        let (Some((log, _)), Some(mut event)) = (&audit, pending.take()) else {
+            return;
+        };
+        let stats = hot.stats();
+        event.after = (stats.samples > 0).then_some(stats.avg_cycles as f64);
+        log.record(event);
+    };
+    let mut reference = Program::new();
+    reference.add_function(seed.clone());
+    let reference = CompiledProgram::compile(&reference, &CompileOptions::new(LIVE_OPT_LEVEL))?;
+    let reference = reference
+        .get_fn(&seed.name)
+        .ok_or_else(|| format!("{}() was not compiled", seed.name))?;
+
+    let mut current = seed.clone();
+    let mut round = 0;
+    loop {
+        let mut waited = Duration::ZERO;
+        while waited < config.interval && !stopping() {
+            thread::sleep(POLL);
+            waited += POLL;
+        }
+        flush(&mut pending);
+        if stopping() {
+            return Ok(());
+        }
+
+        let mut inputs: Vec<u64> = hot.capture().samples().iter().map(|s| s.input).collect();
+        inputs.sort_unstable();
+        inputs.dedup();
+        if inputs.len() < config.min_inputs.max(1) {
+            continue;
+        }
+        let mut test_cases = Vec::new();
+        for input in &inputs {
+            let args: &[i64] = if seed.args.is_empty() { &[] } else { &[*input as i64] };
+            test_cases.push(TestCase::new(*input as i64, reference.call(args)?));
+        }
+
+        round += 1;
+        let evolution = EvolutionConfig {
+            population_size: config.population,
+            seed: config.seed.wrapping_add(round as u64),
+            ..EvolutionConfig::default()
+        };
+        let mut engine = EvolutionEngine::new(&current, test_cases.clone(), evolution);
+        let result = engine.run(config.generations, Some(config.min_speedup));
+        let swapped = result.final_speedup >= config.min_speedup
+            && Validator::default()
+                .validate(&result.best_genome, &test_cases)
+                .is_valid();
+        if swapped {
+            let winner = result.best_genome.to_function();
+            let (memory, offset) = compile_standalone(&winner, LIVE_OPT_LEVEL)?;
+            let before = hot.stats();
+            hot.update(memory, offset);
+            if let Some((_, program)) = &audit {
+                let before = (before.samples > 0).then_some(before.avg_cycles as f64);
+                let to = format!("evolved#{}", round);
+                let from = match round_swapped(rounds) {
+                    Some(last) => format!("evolved#{}", last),
+                    None => "seed".to_string(),
+                };
+                let trigger = format!(
+                    "{:.2}x on {} captured inputs",
+                    result.final_speedup,
+                    inputs.len()
+                );
+                let event = AuditEvent::new(program, &winner.name, AuditAction::Swap, &to)
+                    .from(&from)
+                    .trigger(trigger)
+                    .before("cycles/call", before);
+                pending = Some(event);
+            }
+            tracing::info!(
+                "Swapped in evolved {}() ({:.2}x on {} inputs)",
+                winner.name,
+                result.final_speedup,
+                inputs.len()
+            );
+            current = winner;
+        }
+        rounds.lock().unwrap().push(LiveRound {
+            round,
+            inputs: inputs.len(),
+            generations: result.generations_run,
+            speedup: result.final_speedup,
+            swapped,
+            instructions: current.instructions.len(),
+        });
+    }
+}
+
+/// Round whose winner is installed, if any was swapped in
+fn round_swapped(rounds: &Mutex<Vec<LiveRound>>) -> Option<u32> {
+    rounds.lock().unwrap().iter().rev().find(|r| r.swapped).map(|r| r.round)
+}
+
+/// Compile `func` on its own into fresh memory for `HotFunction`: the
+/// memory and the function's offset in it
+pub fn compile_standalone(func: &Function, level: u8) -> Result<(DualMappedMemory, usize), String> {
+    let mut program = Program::new();
+    program.add_function(func.clone());
+    let code = Compiler::compile_with_options(&program, &CompileOptions::new(level))?;
+    let offset = *code
+        .function_offsets
+        .get(&func.name)
+        .ok_or_else(|| format!("{}() was not compiled", func.name))?;
+    let memory = DualMappedMemory::with_code(&code.code)?;
+    Ok((memory, offset))
+}
+
+/// Whether `func` can be evolved on its own: evolution compiles it alone
+/// and calls it with at most one integer
+pub fn evolvable(func: &Function) -> Result<(), String> {
+    if func.args.len() > 1 {
+        return Err(format!("takes {} arguments", func.args.len()));
+    }
+    match func.instructions.iter().find(|i| {
+        matches!(
+            i.op,
+            Opcode::Call | Opcode::Load(_) | Opcode::Store(_) | Opcode::Alloc | Opcode::Free
+        )
+    }) {
+        Some(instr) => Err(format!("uses {:?}", instr.op)),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    fn seed() -> Function {
+        let prog = Parser::new()
+            .parse(
+                "fn main(n) {
+                    s = 0
+                    i = 0
+                    while i < n {
+                        s = s + i
+                        i = i + 1
+                    }
+                    return s
+                }",
+            )
+            .unwrap();
+        prog.functions[0].clone()
+    }
+
+    #[test]
+    fn test_evolves_against_recorded_inputs_while_serving() {
+        let (memory, offset) = compile_standalone(&seed(), LIVE_OPT_LEVEL).unwrap();
+        let hot = Arc::new(HotFunction::new(memory, offset).with_sample_interval(1));
+        let config = LiveConfig::default()
+            .generations(2)
+            .population(4)
+            .interval(Duration::ZERO)
+            // Any verified genome goes in, so a swap happens every round
+            .min_speedup(0.0);
+        let audit = AuditLog::new();
+        let evolver =
+            LiveEvolver::spawn_audited(hot.clone(), seed(), config, audit.clone(), "sum").unwrap();
+
+        let mut n = 0;
+        while evolver.rounds().iter().filter(|r| r.swapped).count() < 2 {
+            // Results stay correct across swaps
+            let input = n % 50;
+            assert_eq!(hot.call(input), input * input.saturating_sub(1) / 2);
+            n += 1;
+        }
+        let rounds = evolver.stop().unwrap();
+        assert!(rounds.iter().all(|r| r.inputs >= 3 && r.generations <= 2));
+
+        // One swap audited per swapping round, each from the code it replaced
+        let swaps = audit.recent(100).unwrap();
+        assert_eq!(swaps.len(), rounds.iter().filter(|r| r.swapped).count());
+        assert_eq!(swaps[0].from.as_deref(), Some("seed"));
+        assert_eq!(swaps[1].from.as_deref(), Some(swaps[0].to.as_str()));
+        assert!(swaps.iter().all(|s| s.action == AuditAction::Swap && s.metric == "cycles/call"));
+    }
+
+    #[test]
+    fn test_rejects_functions_that_need_their_program() {
+        let prog = Parser::new()
+            .parse(
+                "fn main(a, n) {
+                    v = a[n]
+                    return v
+                }",
+            )
+            .unwrap();
+        assert!(evolvable(&seed()).is_ok());
+        assert_eq!(
+            evolvable(&prog.functions[0]),
+            Err("takes 2 arguments".to_string())
+        );
+        let (memory, offset) = compile_standalone(&seed(), LIVE_OPT_LEVEL).unwrap();
+        let hot = Arc::new(HotFunction::new(memory, offset));
+        let err = LiveEvolver::spawn(hot, prog.functions[0].clone(), LiveConfig::default())
+            .err()
+            .unwrap();
+        assert!(err.contains("cannot evolve main()"), "{}", err);
+    }
+}
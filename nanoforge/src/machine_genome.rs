@@ -0,0 +1,797 @@
+//! Machine-Code Genomes (experimental)
+//!
+//! Evolves raw x86-64 instruction sequences instead of IR, so the search can
+//! try scheduling and encoding choices (lea arithmetic, three-operand imul,
+//! xor-zeroing) the IR has no way to express.
+//!
+//! Genomes come from a constrained encoder: straight-line integer ALU
+//! instructions and `lea`, on caller-saved registers only. Every candidate is
+//! re-decoded and checked against a whitelist (the fence) before it runs, then
+//! validated in the isolated subprocess runner. Only code that passed both is
+//! timed in-process. Partial restarts are not implemented for machine genomes;
+//! `stagnation_limit` is ignored.
+
+use crate::evolution::{EvolutionCheckpoint, EvolutionConfig, GenerationResult};
+use crate::ir::{Function, Opcode, Operand};
+use crate::isolated_runner::run_isolated;
+use crate::jit_memory::DualMappedMemory;
+use crate::shutdown;
+use crate::validator::{Fitness, TestCase, Validator, ValidatorConfig};
+use dynasmrt::{dynasm, x64::Assembler, DynasmApi};
+use iced_x86::{Decoder, DecoderOptions, Instruction, Mnemonic, OpKind, Register};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::time::Duration;
+
+const RAX: u8 = 0;
+const RDI: u8 = 7;
+/// Registers a genome may touch: rdi holds the input, rax the result
+pub const REGISTERS: [u8; 9] = [0, 1, 2, 6, 7, 8, 9, 10, 11];
+/// Where the seed translation puts IR virtual registers
+const SCRATCH: [u8; 7] = [1, 2, 6, 8, 9, 10, 11];
+const REGISTER_NAMES: [&str; 12] = [
+    "rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi", "r8", "r9", "r10", "r11",
+];
+
+/// Longest genome mutation or crossover will produce
+pub const MAX_LENGTH: usize = 64;
+/// Deadline for one isolated validation run
+const ISOLATION_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// One whitelisted instruction; registers are x86 register numbers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MachineInstr {
+    MovRR(u8, u8),
+    MovRI(u8, i32),
+    AddRR(u8, u8),
+    AddRI(u8, i32),
+    SubRR(u8, u8),
+    SubRI(u8, i32),
+    ImulRR(u8, u8),
+    /// dest = src * imm
+    ImulRRI(u8, u8, i32),
+    XorRR(u8, u8),
+    /// dest = a + b
+    LeaRR(u8, u8, u8),
+    /// dest = a + imm
+    LeaRI(u8, u8, i32),
+    /// dest = a + b * scale (2, 4 or 8)
+    LeaScaled(u8, u8, u8, u8),
+    Nop,
+}
+
+impl MachineInstr {
+    fn emit(&self, ops: &mut Assembler) {
+        match *self {
+            MachineInstr::MovRR(d, s) => dynasm!(ops ; .arch x64 ; mov Rq(d), Rq(s)),
+            MachineInstr::MovRI(d, imm) => dynasm!(ops ; .arch x64 ; mov Rq(d), imm),
+            MachineInstr::AddRR(d, s) => dynasm!(ops ; .arch x64 ; add Rq(d), Rq(s)),
+            MachineInstr::AddRI(d, imm) => dynasm!(ops ; .arch x64 ; add Rq(d), imm),
+            MachineInstr::SubRR(d, s) => dynasm!(ops ; .arch x64 ; sub Rq(d), Rq(s)),
+            MachineInstr::SubRI(d, imm) => dynasm!(ops ; .arch x64 ; sub Rq(d), imm),
+            MachineInstr::ImulRR(d, s) => dynasm!(ops ; .arch x64 ; imul Rq(d), Rq(s)),
+            MachineInstr::ImulRRI(d, s, imm) => {
+                dynasm!(ops ; .arch x64 ; imul Rq(d), Rq(s), imm)
+            }
+            MachineInstr::XorRR(d, s) => dynasm!(ops ; .arch x64 ; xor Rq(d), Rq(s)),
+            MachineInstr::LeaRR(d, a, b) => dynasm!(ops ; .arch x64 ; lea Rq(d), [Rq(a) + Rq(b)]),
+            MachineInstr::LeaRI(d, a, imm) => dynasm!(ops ; .arch x64 ; lea Rq(d), [Rq(a) + imm]),
+            MachineInstr::LeaScaled(d, a, b, 2) => {
+                dynasm!(ops ; .arch x64 ; lea Rq(d), [Rq(a) + Rq(b) * 2])
+            }
+            MachineInstr::LeaScaled(d, a, b, 4) => {
+                dynasm!(ops ; .arch x64 ; lea Rq(d), [Rq(a) + Rq(b) * 4])
+            }
+            MachineInstr::LeaScaled(d, a, b, _) => {
+                dynasm!(ops ; .arch x64 ; lea Rq(d), [Rq(a) + Rq(b) * 8])
+            }
+            MachineInstr::Nop => dynasm!(ops ; .arch x64 ; nop),
+        }
+    }
+
+    fn registers_mut(&mut self) -> Vec<&mut u8> {
+        match self {
+            MachineInstr::MovRR(d, s)
+            | MachineInstr::AddRR(d, s)
+            | MachineInstr::SubRR(d, s)
+            | MachineInstr::ImulRR(d, s)
+            | MachineInstr::XorRR(d, s)
+            | MachineInstr::ImulRRI(d, s, _)
+            | MachineInstr::LeaRI(d, s, _) => vec![d, s],
+            MachineInstr::MovRI(d, _) | MachineInstr::AddRI(d, _) | MachineInstr::SubRI(d, _) => {
+                vec![d]
+            }
+            MachineInstr::LeaRR(d, a, b) | MachineInstr::LeaScaled(d, a, b, _) => vec![d, a, b],
+            MachineInstr::Nop => vec![],
+        }
+    }
+
+    fn immediate_mut(&mut self) -> Option<&mut i32> {
+        match self {
+            MachineInstr::MovRI(_, imm)
+            | MachineInstr::AddRI(_, imm)
+            | MachineInstr::SubRI(_, imm)
+            | MachineInstr::ImulRRI(_, _, imm)
+            | MachineInstr::LeaRI(_, _, imm) => Some(imm),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for MachineInstr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let r = |reg: u8| REGISTER_NAMES[reg as usize];
+        match *self {
+            MachineInstr::MovRR(d, s) => write!(f, "mov {}, {}", r(d), r(s)),
+            MachineInstr::MovRI(d, imm) => write!(f, "mov {}, {}", r(d), imm),
+            MachineInstr::AddRR(d, s) => write!(f, "add {}, {}", r(d), r(s)),
+            MachineInstr::AddRI(d, imm) => write!(f, "add {}, {}", r(d), imm),
+            MachineInstr::SubRR(d, s) => write!(f, "sub {}, {}", r(d), r(s)),
+            MachineInstr::SubRI(d, imm) => write!(f, "sub {}, {}", r(d), imm),
+            MachineInstr::ImulRR(d, s) => write!(f, "imul {}, {}", r(d), r(s)),
+            MachineInstr::ImulRRI(d, s, imm) => write!(f, "imul {}, {}, {}", r(d), r(s), imm),
+            MachineInstr::XorRR(d, s) => write!(f, "xor {}, {}", r(d), r(s)),
+            MachineInstr::LeaRR(d, a, b) => write!(f, "lea {}, [{} + {}]", r(d), r(a), r(b)),
+            MachineInstr::LeaRI(d, a, imm) => write!(f, "lea {}, [{} + {}]", r(d), r(a), imm),
+            MachineInstr::LeaScaled(d, a, b, scale) => {
+                write!(f, "lea {}, [{} + {}*{}]", r(d), r(a), r(b), scale)
+            }
+            MachineInstr::Nop => write!(f, "nop"),
+        }
+    }
+}
+
+/// A straight-line `fn(i64) -> i64` body: input in rdi, result in rax
+#[derive(Debug, Clone, PartialEq)]
+pub struct MachineGenome {
+    pub instructions: Vec<MachineInstr>,
+    /// Measured cycles per call (lower is better)
+    pub fitness: Option<Fitness>,
+    /// Generation this genome was created
+    pub generation: u32,
+}
+
+impl MachineGenome {
+    pub fn new(instructions: Vec<MachineInstr>) -> Self {
+        Self {
+            instructions,
+            fitness: None,
+            generation: 0,
+        }
+    }
+
+    /// Translate a straight-line IR function of at most one argument
+    pub fn from_function(func: &Function) -> Result<Self, String> {
+        let mut regs: HashMap<u8, u8> = HashMap::from([(0, RAX)]);
+        let mut out = Vec::new();
+        let mut returned = false;
+
+        for (i, instr) in func.instructions.iter().enumerate() {
+            if returned {
+                return Err(format!("instruction {} follows the return", i));
+            }
+            let unsupported = || {
+                format!(
+                    "instruction {} (`{}`) is not straight-line integer code",
+                    i, instr
+                )
+            };
+            let dest = match instr.dest {
+                Some(Operand::Reg(r)) => Some(r),
+                None => None,
+                _ => return Err(unsupported()),
+            };
+
+            match instr.op {
+                Opcode::LoadArg(0) => {
+                    let d = dest.ok_or_else(unsupported)?;
+                    regs.insert(d, RDI);
+                    continue;
+                }
+                Opcode::Ret => {
+                    if let Some(src) = &instr.src1 {
+                        let src = read(&regs, src).ok_or_else(unsupported)?;
+                        out.push(mov(RAX, src));
+                    }
+                    returned = true;
+                    continue;
+                }
+                Opcode::Mov | Opcode::Add | Opcode::Sub | Opcode::Mul => {}
+                _ => return Err(unsupported()),
+            }
+
+            let d = dest.ok_or_else(unsupported)?;
+            let src = read(&regs, instr.src1.as_ref().ok_or_else(unsupported)?)
+                .ok_or_else(|| format!("instruction {} reads an unset register", i))?;
+            if instr.op != Opcode::Mov && !regs.contains_key(&d) {
+                return Err(format!("instruction {} reads an unset register", i));
+            }
+            if !regs.contains_key(&d) {
+                let used: Vec<u8> = regs.values().copied().collect();
+                let free = SCRATCH.iter().find(|r| !used.contains(r)).ok_or_else(|| {
+                    format!("{} needs more than {} registers", func.name, SCRATCH.len())
+                })?;
+                regs.insert(d, *free);
+            }
+            let d = regs[&d];
+
+            out.push(match (&instr.op, src) {
+                (Opcode::Mov, src) => mov(d, src),
+                (Opcode::Add, Source::Reg(s)) => MachineInstr::AddRR(d, s),
+                (Opcode::Add, Source::Imm(imm)) => MachineInstr::AddRI(d, imm),
+                (Opcode::Sub, Source::Reg(s)) => MachineInstr::SubRR(d, s),
+                (Opcode::Sub, Source::Imm(imm)) => MachineInstr::SubRI(d, imm),
+                (_, Source::Reg(s)) => MachineInstr::ImulRR(d, s),
+                (_, Source::Imm(imm)) => MachineInstr::ImulRRI(d, d, imm),
+            });
+        }
+
+        if !returned {
+            return Err(format!("{} does not return", func.name));
+        }
+        Ok(Self::new(out))
+    }
+
+    /// Machine code for the genome, `ret` included
+    pub fn encode(&self) -> Vec<u8> {
+        let mut ops = Assembler::new().unwrap();
+        for instr in &self.instructions {
+            instr.emit(&mut ops);
+        }
+        dynasm!(ops ; .arch x64 ; ret);
+        ops.finalize().unwrap().to_vec()
+    }
+
+    /// Get the number of instructions
+    pub fn len(&self) -> usize {
+        self.instructions.len()
+    }
+
+    /// Check if empty
+    pub fn is_empty(&self) -> bool {
+        self.instructions.is_empty()
+    }
+}
+
+enum Source {
+    Reg(u8),
+    Imm(i32),
+}
+
+fn read(regs: &HashMap<u8, u8>, operand: &Operand) -> Option<Source> {
+    match operand {
+        Operand::Reg(r) => regs.get(r).map(|&r| Source::Reg(r)),
+        Operand::Imm(imm) => Some(Source::Imm(*imm)),
+        _ => None,
+    }
+}
+
+fn mov(dest: u8, src: Source) -> MachineInstr {
+    match src {
+        Source::Reg(s) => MachineInstr::MovRR(dest, s),
+        Source::Imm(imm) => MachineInstr::MovRI(dest, imm),
+    }
+}
+
+/// The safety fence: decode `code` and accept only whitelisted ALU
+/// instructions and `lea` on genome registers, ending in a single `ret`
+pub fn check_fence(code: &[u8]) -> Result<(), String> {
+    let allowed: Vec<Register> = [
+        Register::RAX,
+        Register::RCX,
+        Register::RDX,
+        Register::RSI,
+        Register::RDI,
+        Register::R8,
+        Register::R9,
+        Register::R10,
+        Register::R11,
+    ]
+    .to_vec();
+    let register_ok =
+        |reg: Register| reg == Register::None || allowed.contains(&reg.full_register());
+
+    let mut decoder = Decoder::with_ip(64, code, 0, DecoderOptions::NONE);
+    let mut instr = Instruction::default();
+    while decoder.can_decode() {
+        decoder.decode_out(&mut instr);
+        let at = instr.ip();
+        let last = instr.next_ip() == code.len() as u64;
+        let ok = match instr.mnemonic() {
+            Mnemonic::Ret => last && instr.op_count() == 0,
+            Mnemonic::Mov
+            | Mnemonic::Add
+            | Mnemonic::Sub
+            | Mnemonic::Imul
+            | Mnemonic::Xor
+            | Mnemonic::Lea
+            | Mnemonic::Nop => (0..instr.op_count()).all(|i| match instr.op_kind(i) {
+                OpKind::Register => register_ok(instr.op_register(i)),
+                OpKind::Memory => {
+                    instr.mnemonic() == Mnemonic::Lea
+                        && register_ok(instr.memory_base())
+                        && register_ok(instr.memory_index())
+                }
+                OpKind::Immediate8
+                | OpKind::Immediate32
+                | OpKind::Immediate8to64
+                | OpKind::Immediate32to64 => true,
+                _ => false,
+            }),
+            _ => false,
+        };
+        if !ok {
+            return Err(format!(
+                "fence: `{}` at offset {} is not allowed",
+                instr, at
+            ));
+        }
+        if last {
+            return Ok(());
+        }
+    }
+    Err("fence: code does not end in `ret`".to_string())
+}
+
+/// Mutation and crossover for machine genomes
+pub struct MachineMutator {
+    pub mutation_rate: f64,
+    rng: StdRng,
+}
+
+impl MachineMutator {
+    pub fn new(mutation_rate: f64, seed: u64) -> Self {
+        Self {
+            mutation_rate,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    /// Mutate each instruction with probability `mutation_rate`, occasionally
+    /// inserting or deleting one
+    pub fn mutate(&mut self, genome: &mut MachineGenome) {
+        for i in 0..genome.len() {
+            if self.rng.gen::<f64>() < self.mutation_rate {
+                self.mutate_at(genome, i);
+            }
+        }
+        if self.rng.gen::<f64>() < self.mutation_rate {
+            match self.rng.gen_range(0..3) {
+                0 if genome.len() < MAX_LENGTH => {
+                    let instr = self.random_instruction(genome);
+                    let at = self.rng.gen_range(0..=genome.len());
+                    genome.instructions.insert(at, instr);
+                }
+                1 if !genome.is_empty() => {
+                    let at = self.rng.gen_range(0..genome.len());
+                    genome.instructions.remove(at);
+                }
+                _ if genome.len() >= 2 => {
+                    let at = self.rng.gen_range(0..genome.len() - 1);
+                    genome.instructions.swap(at, at + 1);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn mutate_at(&mut self, genome: &mut MachineGenome, i: usize) {
+        match self.rng.gen_range(0..3) {
+            0 => genome.instructions[i] = self.random_instruction(genome),
+            1 => {
+                let reg = self.register();
+                let mut regs = genome.instructions[i].registers_mut();
+                if !regs.is_empty() {
+                    let at = self.rng.gen_range(0..regs.len());
+                    *regs[at] = reg;
+                }
+            }
+            _ => {
+                let delta = self.rng.gen_range(-2..=2);
+                if let Some(imm) = genome.instructions[i].immediate_mut() {
+                    *imm = imm.wrapping_add(delta);
+                }
+            }
+        }
+    }
+
+    /// A whitelisted instruction, with immediates biased towards the genome's own
+    fn random_instruction(&mut self, genome: &MachineGenome) -> MachineInstr {
+        let mut known: Vec<i32> = genome
+            .instructions
+            .clone()
+            .iter_mut()
+            .filter_map(|i| i.immediate_mut().map(|imm| *imm))
+            .collect();
+        known.extend([0, 1, 2, 3, 4, 8]);
+        let imm = known[self.rng.gen_range(0..known.len())];
+        let (d, a, b) = (self.register(), self.register(), self.register());
+        match self.rng.gen_range(0..13) {
+            0 => MachineInstr::MovRR(d, a),
+            1 => MachineInstr::MovRI(d, imm),
+            2 => MachineInstr::AddRR(d, a),
+            3 => MachineInstr::AddRI(d, imm),
+            4 => MachineInstr::SubRR(d, a),
+            5 => MachineInstr::SubRI(d, imm),
+            6 => MachineInstr::ImulRR(d, a),
+            7 => MachineInstr::ImulRRI(d, a, imm),
+            8 => MachineInstr::XorRR(d, a),
+            9 => MachineInstr::LeaRR(d, a, b),
+            10 => MachineInstr::LeaRI(d, a, imm),
+            11 => MachineInstr::LeaScaled(d, a, b, [2, 4, 8][self.rng.gen_range(0..3)]),
+            _ => MachineInstr::Nop,
+        }
+    }
+
+    fn register(&mut self) -> u8 {
+        REGISTERS[self.rng.gen_range(0..REGISTERS.len())]
+    }
+
+    /// Single-point crossover
+    pub fn crossover(&mut self, parent1: &MachineGenome, parent2: &MachineGenome) -> MachineGenome {
+        let min_len = parent1.len().min(parent2.len());
+        if min_len < 2 {
+            return parent1.clone();
+        }
+        let point = self.rng.gen_range(1..min_len);
+        let mut instructions = parent1.instructions[..point].to_vec();
+        instructions.extend_from_slice(&parent2.instructions[point..]);
+        instructions.truncate(MAX_LENGTH);
+        MachineGenome {
+            instructions,
+            fitness: None,
+            generation: parent1.generation.max(parent2.generation) + 1,
+        }
+    }
+}
+
+/// Result of a machine-code evolution run
+#[derive(Debug, Clone)]
+pub struct MachineEvolutionResult {
+    pub best_genome: MachineGenome,
+    /// Fitness of the translated seed
+    pub baseline: Option<Fitness>,
+    pub generations_run: u32,
+    pub final_speedup: f64,
+    pub history: Vec<GenerationResult>,
+}
+
+impl MachineEvolutionResult {
+    /// `EvolutionResult::checkpoint`, with the best genome disassembled
+    pub fn checkpoint(&self) -> EvolutionCheckpoint {
+        EvolutionCheckpoint {
+            generations_run: self.generations_run,
+            final_speedup: self.final_speedup,
+            interrupted: shutdown::requested(),
+            best: crate::disasm::disassemble(&self.best_genome.encode())
+                .lines()
+                .map(str::to_string)
+                .collect(),
+            history: self.history.clone(),
+        }
+    }
+}
+
+/// Genetic search over machine genomes, seeded from a straight-line function
+pub struct MachineEvolution {
+    population: Vec<MachineGenome>,
+    best_ever: Option<MachineGenome>,
+    baseline_fitness: Option<Fitness>,
+    generation: u32,
+    config: EvolutionConfig,
+    mutator: MachineMutator,
+    validator: Validator,
+    test_cases: Vec<TestCase>,
+    rng: StdRng,
+    history: Vec<GenerationResult>,
+}
+
+impl MachineEvolution {
+    /// Fails when the seed is not straight-line code `from_function` can translate
+    pub fn new(
+        seed_function: &Function,
+        test_cases: Vec<TestCase>,
+        config: EvolutionConfig,
+    ) -> Result<Self, String> {
+        let seed = MachineGenome::from_function(seed_function)?;
+        Ok(Self {
+            population: vec![seed; config.population_size],
+            best_ever: None,
+            baseline_fitness: None,
+            generation: 0,
+            mutator: MachineMutator::new(config.mutation_rate, config.seed),
+            validator: Validator::new(ValidatorConfig::default()),
+            test_cases,
+            rng: StdRng::seed_from_u64(config.seed),
+            history: Vec::new(),
+            config,
+        })
+    }
+
+    /// Fence, isolated validation, then in-process timing; `None` if any fails
+    /// or the output varies between timed calls
+    pub fn evaluate(&self, genome: &MachineGenome) -> Option<Fitness> {
+        let code = genome.encode();
+        check_fence(&code).ok()?;
+        let inputs: Vec<i64> = self.test_cases.iter().map(|tc| tc.input).collect();
+        let outputs = run_isolated(&code, &inputs, ISOLATION_TIMEOUT).ok()?;
+        if self
+            .test_cases
+            .iter()
+            .zip(&outputs)
+            .any(|(tc, &out)| out != tc.expected_output)
+        {
+            return None;
+        }
+
+        // Fenced straight-line code can neither fault nor hang, and it passed in the child
+        let memory = DualMappedMemory::with_code(&code).ok()?;
+        let func: extern "C" fn(u64) -> u64 = unsafe { std::mem::transmute(memory.rx_ptr) };
+        self.validator
+            .measure(func, &self.test_cases)
+            .ok()
+            .map(|(fitness, _)| fitness)
+    }
+
+    /// Speedup of `fitness` over the seed, 1.0 unless it is clearly faster
+    fn speedup(&self, fitness: Option<Fitness>) -> f64 {
+        match (self.baseline_fitness, fitness) {
+            (Some(base), Some(f)) if f.beats(&base) && f.cycles > 0.0 => base.cycles / f.cycles,
+            _ => 1.0,
+        }
+    }
+
+    /// Run one generation of evolution
+    pub fn evolve_generation(&mut self) -> GenerationResult {
+        self.generation += 1;
+
+        for i in 0..self.population.len() {
+            if self.population[i].fitness.is_none() {
+                self.population[i].fitness = self.evaluate(&self.population[i]);
+            }
+        }
+
+        let mut valid: Vec<MachineGenome> = self
+            .population
+            .iter()
+            .filter(|g| g.fitness.is_some())
+            .cloned()
+            .collect();
+        valid.sort_by(|a, b| {
+            a.fitness
+                .unwrap()
+                .cycles
+                .total_cmp(&b.fitness.unwrap().cycles)
+        });
+
+        if let Some(best) = valid.first() {
+            let improved = match &self.best_ever {
+                None => true,
+                Some(current) => best.fitness.unwrap().beats(&current.fitness.unwrap()),
+            };
+            if improved {
+                self.best_ever = Some(best.clone());
+            }
+        }
+
+        let valid_count = valid.len();
+        let (best_fitness, avg_fitness) = if valid_count > 0 {
+            let sum: f64 = valid.iter().map(|g| g.fitness.unwrap().cycles).sum();
+            (valid[0].fitness.unwrap().cycles, sum / valid_count as f64)
+        } else {
+            (f64::MAX, f64::MAX)
+        };
+        let speedup = self.speedup(valid.first().and_then(|g| g.fitness));
+        let diversity = diversity(&self.population);
+
+        // Nothing survived: start over from the best known genome
+        if valid.is_empty() {
+            valid.extend(self.best_ever.clone());
+        }
+
+        let mut next = Vec::with_capacity(self.config.population_size);
+        next.extend(valid.iter().take(self.config.elite_count).cloned());
+        while next.len() < self.config.population_size && !valid.is_empty() {
+            let parent1 = self.tournament_select_idx(&valid);
+            let parent2 = self.tournament_select_idx(&valid);
+            let mut child = if self.rng.gen::<f64>() < self.config.crossover_rate {
+                self.mutator.crossover(&valid[parent1], &valid[parent2])
+            } else {
+                valid[parent1].clone()
+            };
+            self.mutator.mutate(&mut child);
+            child.fitness = None;
+            child.generation = self.generation;
+            next.push(child);
+        }
+        if !next.is_empty() {
+            self.population = next;
+        }
+
+        let result = GenerationResult {
+            generation: self.generation,
+            best_fitness,
+            avg_fitness,
+            valid_count,
+            speedup_vs_baseline: speedup,
+            diversity,
+            restarted: false,
+        };
+        self.history.push(result.clone());
+        result
+    }
+
+    /// Tournament selection: returns index of best from random subset
+    fn tournament_select_idx(&mut self, candidates: &[MachineGenome]) -> usize {
+        let mut best_idx = self.rng.gen_range(0..candidates.len());
+        for _ in 1..self.config.tournament_size.min(candidates.len()) {
+            let idx = self.rng.gen_range(0..candidates.len());
+            if tournament_order(&candidates[idx], &candidates[best_idx]) == Ordering::Less {
+                best_idx = idx;
+            }
+        }
+        best_idx
+    }
+
+    /// Run evolution until target speedup, max generations or a shutdown request
+    pub fn run(
+        &mut self,
+        max_generations: u32,
+        target_speedup: Option<f64>,
+    ) -> MachineEvolutionResult {
+        // The first measurement pays for page faults and frequency ramp-up
+        let seed = self.population[0].clone();
+        self.evaluate(&seed);
+        self.baseline_fitness = self.evaluate(&seed);
+
+        for genome in self.population.iter_mut().skip(1) {
+            for _ in 0..3 {
+                self.mutator.mutate(genome);
+            }
+        }
+
+        for _ in 0..max_generations {
+            if shutdown::requested() {
+                break;
+            }
+            let result = self.evolve_generation();
+            if let Some(target) = target_speedup {
+                if result.speedup_vs_baseline >= target {
+                    break;
+                }
+            }
+        }
+
+        let best_genome = self.best_ever.clone().unwrap_or(seed);
+        MachineEvolutionResult {
+            final_speedup: self.speedup(best_genome.fitness),
+            best_genome,
+            baseline: self.baseline_fitness,
+            generations_run: self.generation,
+            history: self.history.clone(),
+        }
+    }
+}
+
+/// Mean pairwise edit distance, each pair normalized by the longer genome
+fn diversity(population: &[MachineGenome]) -> f64 {
+    let mut total = 0.0;
+    let mut pairs = 0;
+    for (i, a) in population.iter().enumerate() {
+        for b in &population[i + 1..] {
+            let longest = a.len().max(b.len());
+            if longest > 0 {
+                total += edit_distance(&a.instructions, &b.instructions) as f64 / longest as f64;
+            }
+            pairs += 1;
+        }
+    }
+    if pairs == 0 {
+        0.0
+    } else {
+        total / pairs as f64
+    }
+}
+
+/// Levenshtein distance between two instruction streams
+fn edit_distance(a: &[MachineInstr], b: &[MachineInstr]) -> usize {
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    let mut row = vec![0; b.len() + 1];
+    for (i, x) in a.iter().enumerate() {
+        row[0] = i + 1;
+        for (j, y) in b.iter().enumerate() {
+            let substitute = prev[j] + usize::from(x != y);
+            row[j + 1] = substitute.min(prev[j + 1] + 1).min(row[j] + 1);
+        }
+        std::mem::swap(&mut prev, &mut row);
+    }
+    prev[b.len()]
+}
+
+/// Measurably fewer cycles wins, and within each other's noise fewer instructions do
+fn tournament_order(a: &MachineGenome, b: &MachineGenome) -> Ordering {
+    match (a.fitness, b.fitness) {
+        (Some(fa), Some(fb)) if fa.beats(&fb) => Ordering::Less,
+        (Some(fa), Some(fb)) if fb.beats(&fa) => Ordering::Greater,
+        (Some(fa), Some(fb)) => a.len().cmp(&b.len()).then(fa.cycles.total_cmp(&fb.cycles)),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => Ordering::Equal,
+    }
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    fn seed_function() -> Function {
+        let source = "fn main(n) {\n    x = n * 3\n    y = x + 7\n    z = y - n\n    return z\n}\n";
+        Parser::new().parse(source).unwrap().functions.remove(0)
+    }
+
+    fn test_cases() -> Vec<TestCase> {
+        [0, 10, -4, 1000]
+            .iter()
+            .map(|&n| TestCase::new(n, 2 * n + 7))
+            .collect()
+    }
+
+    #[test]
+    fn test_seed_translation_runs_isolated() {
+        let genome = MachineGenome::from_function(&seed_function()).unwrap();
+        let code = genome.encode();
+        check_fence(&code).unwrap();
+        let outputs = run_isolated(&code, &[0, 10, -4], ISOLATION_TIMEOUT).unwrap();
+        assert_eq!(outputs, vec![7, 27, -1]);
+    }
+
+    #[test]
+    fn test_fence_rejects_memory_and_control_flow() {
+        // mov rax, [rdi]; ret
+        assert!(check_fence(&[0x48, 0x8b, 0x07, 0xc3]).is_err());
+        // jmp $; ret
+        assert!(check_fence(&[0xeb, 0xfe, 0xc3]).is_err());
+        // mov rbx, rdi; ret (callee-saved)
+        assert!(check_fence(&[0x48, 0x89, 0xfb, 0xc3]).is_err());
+        // ret; ret
+        assert!(check_fence(&[0xc3, 0xc3]).is_err());
+        // lea rax, [rdi + rdi*2]; ret
+        assert!(check_fence(&[0x48, 0x8d, 0x04, 0x7f, 0xc3]).is_ok());
+    }
+
+    #[test]
+    fn test_mutations_stay_inside_the_fence() {
+        let mut mutator = MachineMutator::new(0.5, 7);
+        let mut genome = MachineGenome::from_function(&seed_function()).unwrap();
+        for _ in 0..500 {
+            mutator.mutate(&mut genome);
+            assert!(genome.len() <= MAX_LENGTH);
+            check_fence(&genome.encode()).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_machine_evolution_keeps_results_correct() {
+        let config = EvolutionConfig {
+            population_size: 8,
+            ..EvolutionConfig::default()
+        };
+        let mut engine = MachineEvolution::new(&seed_function(), test_cases(), config).unwrap();
+        let result = engine.run(3, None);
+        assert!(result.baseline.is_some());
+        let code = result.best_genome.encode();
+        let outputs = run_isolated(&code, &[0, 10, -4, 1000], ISOLATION_TIMEOUT).unwrap();
+        assert_eq!(outputs, vec![7, 27, -1, 2007]);
+    }
+
+    #[test]
+    fn test_branchy_seed_is_rejected() {
+        let source =
+            "fn main(k) {\n    x = k\n    if x > 20 {\n        x = 20\n    }\n    return x\n}\n";
+        let func = Parser::new().parse(source).unwrap().functions.remove(0);
+        assert!(MachineGenome::from_function(&func).is_err());
+    }
+}
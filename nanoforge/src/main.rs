@@ -1,17 +1,47 @@
 use clap::{Parser, Subcommand};
-use nanoforge::ai_optimizer::{ContextualBandit, OptimizationFeatures, SizeBucket, VariantBandit};
+use nanoforge::adaptive::{AdaptiveConfig, AdaptiveRuntime, EvolveOptions};
+use nanoforge::artifacts::Artifacts;
+use nanoforge::ai_optimizer::{ContextualBandit, SizeBucket, VariantBandit};
 use nanoforge::assembler::CodeGenerator;
-use nanoforge::compiler::Compiler;
+use nanoforge::audit::AuditLog;
+use nanoforge::brain_log::BrainLog;
+use nanoforge::brain_report::{BrainReport, DispatchTable};
+use nanoforge::bundle::{Bundle, BundleRuntime};
+use nanoforge::capture::Replay;
+use nanoforge::compiled_program::CompiledProgram;
+use nanoforge::compiler::{CompileOptions, Compiler};
 use nanoforge::cpu_features::CpuFeatures;
+use nanoforge::diagnostics::{self, Diagnostic, Severity};
+use nanoforge::distribution::InputDistribution;
+use nanoforge::function_profiler::ProfileSession;
+use nanoforge::host_args;
+use nanoforge::sanitizer;
 use nanoforge::hot_function::HotFunction;
-use nanoforge::jit_memory::DualMappedMemory;
-use nanoforge::sandbox::{NanosecondSandbox, SandboxConfig};
+use nanoforge::introspection::{Introspection, StatusView};
+use nanoforge::ir::Program;
+use nanoforge::jit_memory::{self, DualMappedMemory};
+use nanoforge::live_evolution::{self, LiveConfig, LiveEvolver};
+use nanoforge::lowering;
+use nanoforge::mem_trace::{self, MemSampler};
+use nanoforge::native;
+use nanoforge::overflow::OverflowMode;
+use nanoforge::pass_impact;
+use nanoforge::pass_schedule::{self, PassSchedule, PassScheduleEntry, PassScheduleStore};
+use nanoforge::plugin::{self, Plugin};
+use nanoforge::shutdown::{self, StateDir};
+use nanoforge::simulation::{self, MeasurementTable, Policy};
+use nanoforge::store::{self, Schema};
+use nanoforge::tiering::{Tier, TieringConfig};
+use nanoforge::soae::{self, SoaeOptions};
+use nanoforge::soak::{self, SoakConfig};
+use nanoforge::spill_impact;
+use nanoforge::tuning::{self, TuningStore};
 use nanoforge::variant_generator::VariantGenerator;
 
 use nanoforge::parser::Parser as NanoParser;
-use nanoforge::profiler::Profiler;
+use nanoforge::profiler::{PerfCounters, Profiler};
 use std::io::{self, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
@@ -38,6 +68,20 @@ struct Args {
     /// Enable verbose logging (Debug level)
     #[arg(short, long)]
     verbose: bool,
+
+    /// Seed every RNG (bandits, evolution, input sampling) for reproducible runs
+    #[arg(long, global = true)]
+    seed: Option<u64>,
+
+    /// Flush bandit brains, evolution checkpoints and benchmark history here,
+    /// including when interrupted with Ctrl+C
+    #[arg(long, global = true, value_name = "DIR")]
+    state_dir: Option<String>,
+
+    /// Cap live JIT code at this many bytes, reclaiming least recently used
+    /// variants that aren't running to stay under it
+    #[arg(long, global = true, value_name = "BYTES")]
+    jit_memory_cap: Option<usize>,
 }
 
 #[derive(Subcommand, Debug)]
@@ -49,11 +93,95 @@ enum Commands {
         file: String,
         #[arg(short, long, default_value_t = 3)]
         level: u8,
+        /// Pass a file of i64 values to main as (pointer, length) parameters
+        #[arg(long = "bind-array", value_name = "NAME=FILE")]
+        bind_array: Vec<String>,
+        /// Print hardware counters (instructions, cycles, IPC, branch misses)
+        #[arg(long)]
+        stats: bool,
+        /// Check every array access against red-zoned allocations
+        #[arg(long)]
+        sanitize: bool,
+        /// Guard every stack frame with a canary and check push/pop balance
+        #[arg(long)]
+        debug: bool,
+        /// Report cycles (and instructions, with perf) spent in each function
+        #[arg(long)]
+        profile: bool,
+        /// Compile ifs into branch-free selects; fail if any data-dependent branch remains
+        #[arg(long)]
+        constant_time: bool,
+        /// What Add/Sub/Mul do on i64 overflow: wrapping, or checked (trap and
+        /// report the instruction)
+        #[arg(long, value_name = "MODE", default_value = "wrapping")]
+        overflow: OverflowMode,
+        /// Reject scripts that allocate, call outside themselves or touch
+        /// memory other than the arrays passed in
+        #[arg(long)]
+        pure: bool,
+        /// Decode the generated code and refuse to run it if it contains
+        /// anything the compiler never emits (syscalls, stray jumps or calls)
+        #[arg(long)]
+        verify_code: bool,
+        /// Function to run
+        #[arg(long, default_value = "main")]
+        entry: String,
+        /// Values for the entry's parameters not bound with --bind-array
+        #[arg(long, value_delimiter = ',', allow_hyphen_values = true)]
+        args: Vec<i64>,
+        /// Print what the optimizer did (passes, loop decisions, spills, code size)
+        #[arg(long, value_name = "FORMAT", value_parser = ["json", "markdown"])]
+        report: Option<String>,
+        /// Rewrite the program with a pass plugin (a shared library; see
+        /// `nanoforge::plugin`) before compiling; repeat to chain plugins
+        #[arg(long, value_name = "PATH")]
+        plugin: Vec<String>,
+        /// Skip the passes `learn-passes` stored for this script in STORE
+        #[arg(long, value_name = "STORE")]
+        pass_schedule: Option<String>,
+        /// Write the machine code to PATH
+        #[arg(long, value_name = "PATH")]
+        emit_bin: Option<String>,
+        /// Write the disassembled machine code to PATH
+        #[arg(long, value_name = "PATH")]
+        emit_asm: Option<String>,
+        /// Write the optimized IR, with source lines, to PATH
+        #[arg(long, value_name = "PATH")]
+        emit_ir: Option<String>,
+        /// Write the optimization report to PATH (JSON if it ends in .json,
+        /// markdown otherwise)
+        #[arg(long, value_name = "PATH")]
+        emit_report: Option<String>,
     },
     /// Check syntax of a script file without executing
     Check {
         file: String,
     },
+    /// Show each source line next to the IR it became and that IR's
+    /// machine code (bytes and assembly)
+    Explain {
+        file: String,
+        #[arg(short, long, default_value_t = 3)]
+        level: u8,
+    },
+    /// Pack the IR, every variant's machine code, the CPU features each
+    /// needs and a learned dispatch table into a bundle (`run-bundle` loads
+    /// it without compiling)
+    Bundle {
+        file: String,
+        #[arg(short, long, value_name = "FILE")]
+        output: String,
+        /// Dispatch table: a context-brain-*.json saved by soae-context
+        /// --state-dir, or a CSV from `brain export --format csv`
+        #[arg(long, value_name = "FILE")]
+        brain: Option<String>,
+    },
+    /// Run main(n) of a bundle on the variant its table picks for this CPU
+    RunBundle {
+        bundle: String,
+        #[arg(required = true)]
+        inputs: Vec<i64>,
+    },
     /// Run the internal demo/benchmark
     Demo,
     /// Benchmark a script file (10k iterations)
@@ -61,17 +189,239 @@ enum Commands {
         file: String,
         #[arg(short, long, default_value_t = 3)]
         level: u8,
+        /// Print hardware counters for the measured loop
+        #[arg(long)]
+        stats: bool,
+        /// Time the calls recorded in this capture file (see `live --capture`)
+        /// instead of main() with no arguments
+        #[arg(long, value_name = "FILE", conflicts_with = "stats")]
+        replay: Option<String>,
+        /// Compile the replayed function with a guarded copy for an input
+        /// the replay shows to be nearly constant
+        #[arg(long, requires = "replay")]
+        specialize: bool,
+        /// Time main(n) over an input distribution (N, uniform:LOW..HIGH or
+        /// file:PATH) and report the expected cost
+        #[arg(long, value_name = "SPEC", conflicts_with_all = ["stats", "replay"])]
+        inputs: Option<InputDistribution>,
     },
     /// Run Adaptive Optimization Demo
-    Adaptive { file: String },
+    Adaptive {
+        file: String,
+        /// Promote a function at this share of self cycles
+        #[arg(long, default_value_t = 0.2)]
+        promote_share: f64,
+        /// Demote a promoted function only below this share (hysteresis)
+        #[arg(long, default_value_t = 0.1)]
+        demote_share: f64,
+        /// Minimum time between two tier changes of one function
+        #[arg(long, value_name = "MS", default_value_t = 100)]
+        cooldown_ms: u64,
+        /// Cap on recompilations in any minute
+        #[arg(long, default_value_t = 10)]
+        max_recompiles_per_minute: usize,
+        /// Interpret main() this many times before compiling it
+        #[arg(long, value_name = "CALLS", default_value_t = 100)]
+        jit_after: u64,
+        /// Start in the template tier (machine code stitched from
+        /// per-opcode templates) instead of interpreting
+        #[arg(long)]
+        template: bool,
+        /// Wall time over which hotness (share of time spent in main()) is measured
+        #[arg(long, value_name = "MS", default_value_t = 100)]
+        window_ms: u64,
+        /// How long to keep calling main()
+        #[arg(long, value_name = "MS", default_value_t = 2000)]
+        duration_ms: u64,
+        /// Evolve the hottest function in the background once optimized and
+        /// swap in the winner
+        #[arg(long)]
+        evolve: bool,
+        /// Generations for --evolve
+        #[arg(long, default_value_t = 10)]
+        generations: u32,
+        /// Population for --evolve
+        #[arg(long, default_value_t = 16)]
+        population: usize,
+        /// Append every tier change (trigger, time per call before and
+        /// after) to this JSONL file
+        #[arg(long, value_name = "FILE")]
+        audit_log: Option<String>,
+    },
+    /// Serve a function from a hot-swappable slot while a background thread
+    /// evolves it against the inputs it is called with
+    Live {
+        file: String,
+        /// Function to serve (at most one parameter)
+        #[arg(long, default_value = "main")]
+        entry: String,
+        /// Inputs the workload cycles through
+        #[arg(long, value_delimiter = ',', default_value = "10,100,1000")]
+        inputs: Vec<u64>,
+        /// How long to keep calling the function
+        #[arg(long, value_name = "MS", default_value_t = 3000)]
+        duration_ms: u64,
+        /// Generations per evolution round
+        #[arg(short, long, default_value_t = 5)]
+        generations: u32,
+        /// Population size
+        #[arg(short, long, default_value_t = 16)]
+        population: usize,
+        /// Swap in a winner only if it is at least this much faster
+        #[arg(long, default_value_t = 1.1)]
+        min_speedup: f64,
+        /// Save a sample of the calls served to this file, for
+        /// `benchmark --replay`
+        #[arg(long, value_name = "FILE")]
+        capture: Option<String>,
+        /// Append every swap (trigger, cycles per call before and after) to
+        /// this JSONL file
+        #[arg(long, value_name = "FILE")]
+        audit_log: Option<String>,
+    },
     /// Run SOAE (Self-Optimizing Assembly Engine) Demo
-    Soae { file: String },
+    Soae {
+        file: String,
+        /// Explain how the top N variants differ from the winner (config, size, IR diff)
+        #[arg(long, value_name = "N")]
+        explain: Option<usize>,
+        /// Drop variants whose machine code is larger than this many bytes
+        #[arg(long, value_name = "BYTES")]
+        max_code_size: Option<usize>,
+        /// Rank variants by expected cost over an input distribution: N,
+        /// uniform:LOW..HIGH or file:PATH (default: input 1000)
+        #[arg(long, value_name = "SPEC")]
+        inputs: Option<InputDistribution>,
+        /// Profile main, tune each hot function on its own and combine the
+        /// winners (rankings grouped by function)
+        #[arg(long, conflicts_with_all = ["explain", "inputs"])]
+        per_function: bool,
+        /// Also time N calls per variant from cold caches and rank those separately
+        #[arg(long, value_name = "N", conflicts_with = "per_function")]
+        cold: Option<u32>,
+        /// Sample the loads and stores of the top N variants and show which
+        /// instructions miss which cache level
+        #[arg(long, value_name = "N", conflicts_with = "per_function")]
+        mem_trace: Option<usize>,
+    },
+    /// Run SOAE over a library of scripts and store each one's best configuration
+    Tune {
+        /// Scripts, or directories of .nf scripts
+        #[arg(required = true)]
+        paths: Vec<String>,
+        /// Tuning store (default: ~/.nanoforge/tuning.json)
+        #[arg(long, value_name = "PATH")]
+        store: Option<String>,
+        /// Input passed to each script's main
+        #[arg(long, default_value_t = 1000)]
+        input: u64,
+        /// Measure scripts again even if the store has an entry for them from this CPU
+        #[arg(long)]
+        retune: bool,
+        /// Drop variants whose machine code is larger than this many bytes
+        #[arg(long, value_name = "BYTES")]
+        max_code_size: Option<usize>,
+    },
+    /// Measure each optimizer pass's contribution by disabling it, script by script
+    PassImpact {
+        /// Scripts, or directories of .nf scripts
+        #[arg(required = true)]
+        paths: Vec<String>,
+        #[arg(short, long, default_value_t = 3)]
+        level: u8,
+        /// Input passed to each script's main
+        #[arg(long, default_value_t = 1000)]
+        input: u64,
+        /// Print the per-script measurements and the summary as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Force virtual registers onto the stack one at a time and report what
+    /// each spill costs, next to what the allocator spilled by itself
+    SpillImpact {
+        file: String,
+        #[arg(short, long, default_value_t = 3)]
+        level: u8,
+        /// Input passed to main
+        #[arg(long, default_value_t = 1000)]
+        input: u64,
+        /// Spill only these (and then all of them together), e.g. r12,r13;
+        /// every vreg kept in a register when unset
+        #[arg(long, value_delimiter = ',', value_parser = spill_impact::parse_vreg)]
+        vreg: Vec<u8>,
+        /// Print the measurements as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Loop SOAE and evolution over a corpus for a while, checking every
+    /// winner against the unoptimized outputs and watching for crashes and
+    /// leaked JIT memory (for nightly stability runs)
+    Soak {
+        /// Scripts, or directories of .nf scripts
+        #[arg(required = true)]
+        paths: Vec<String>,
+        /// Start no new round after this long
+        #[arg(long, value_name = "SECS", default_value_t = 60)]
+        duration_secs: u64,
+        /// Inputs main() is checked at
+        #[arg(long, value_delimiter = ',', default_value = "10,100,1000")]
+        inputs: Vec<i64>,
+        /// Generations per evolution run (0 skips evolution)
+        #[arg(short, long, default_value_t = 3)]
+        generations: u32,
+        /// Population size
+        #[arg(short, long, default_value_t = 8)]
+        population: usize,
+        /// Print the per-script outcomes and the summary as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Learn which optimizer passes each function should skip with a bandit,
+    /// and store the schedule for the script
+    LearnPasses {
+        file: String,
+        #[arg(short, long, default_value_t = 3)]
+        level: u8,
+        /// Bandit rounds per function
+        #[arg(short, long, default_value_t = 20)]
+        iterations: u32,
+        /// Input passed to main
+        #[arg(long, default_value_t = 1000)]
+        input: u64,
+        /// Schedule store (default: ~/.nanoforge/pass_schedules.json)
+        #[arg(long, value_name = "PATH")]
+        store: Option<String>,
+        /// Learn again even if the store has a schedule for the script from
+        /// this CPU at this level
+        #[arg(long)]
+        relearn: bool,
+    },
+    /// Benchmark a kernel's JIT variants against a rustc-compiled version of it
+    CompareNative {
+        file: String,
+        /// Native kernel the script implements: sum, vec_add or dot (default:
+        /// the script's file name)
+        #[arg(long)]
+        kernel: Option<String>,
+        /// Input passed to main
+        #[arg(long, default_value_t = 1000)]
+        input: u64,
+    },
     /// Run SOAE with AI-Powered Variant Selection
     SoaeAi {
         file: String,
         /// Number of learning iterations
         #[arg(short, long, default_value_t = 50)]
         iterations: u32,
+        /// Append every bandit update to this JSONL file (see `analyze`)
+        #[arg(long, value_name = "PATH")]
+        log: Option<String>,
+        /// Append every sandbox measurement to this JSONL file (see `simulate`)
+        #[arg(long, value_name = "PATH")]
+        record: Option<String>,
+        /// Start from uniform priors instead of the static cost model
+        #[arg(long)]
+        cold_start: bool,
     },
     /// Run SOAE with Contextual Bandit (learns decision boundaries)
     SoaeContext {
@@ -79,6 +429,48 @@ enum Commands {
         /// Number of learning iterations
         #[arg(short, long, default_value_t = 100)]
         iterations: u32,
+        /// Append every bandit update to this JSONL file (see `analyze`)
+        #[arg(long, value_name = "PATH")]
+        log: Option<String>,
+        /// Append every sandbox measurement to this JSONL file (see `simulate`)
+        #[arg(long, value_name = "PATH")]
+        record: Option<String>,
+        /// Start from uniform priors instead of the static cost model
+        #[arg(long)]
+        cold_start: bool,
+    },
+    /// Review contextual brains and curated dispatch tables
+    Brain {
+        #[command(subcommand)]
+        action: BrainAction,
+    },
+    /// Inspect or clear saved state (--state-dir, default ~/.nanoforge)
+    State {
+        #[command(subcommand)]
+        action: StateAction,
+    },
+    /// Print convergence curves and per-bucket regret from a bandit training log
+    Analyze {
+        file: String,
+        /// Points on the convergence curve
+        #[arg(short, long, default_value_t = 10)]
+        windows: usize,
+    },
+    /// Replay bandit policies against measurements recorded with --record,
+    /// without benchmarking
+    Simulate {
+        /// A soae-ai or soae-context --record file
+        #[arg(long, value_name = "PATH")]
+        log: String,
+        /// thompson, ucb1[:C], epsilon-greedy[:EPSILON] or uniform; repeat to compare
+        #[arg(long = "policy", value_name = "POLICY", default_value = "thompson")]
+        policies: Vec<Policy>,
+        /// Bandit rounds per run
+        #[arg(short, long, default_value_t = 200)]
+        iterations: u32,
+        /// Runs per policy, seeded --seed, --seed + 1, ...
+        #[arg(long, default_value_t = 10)]
+        runs: usize,
     },
     /// 🧬 EVOLVE: Use genetic algorithms to evolve optimal code
     Evolve {
@@ -92,6 +484,14 @@ enum Commands {
         /// Target speedup to achieve (stops early if reached)
         #[arg(short, long)]
         target: Option<f64>,
+        /// EXPERIMENTAL: evolve raw x86-64 instructions instead of IR
+        /// (straight-line seeds only; candidates run in a subprocess)
+        #[arg(long)]
+        machine_code: bool,
+        /// Write the code kept after re-verification (the winner, or the
+        /// seed if the winner failed) to PATH as IR JSON
+        #[arg(long, value_name = "PATH", conflicts_with = "machine_code")]
+        emit_winner: Option<String>,
     },
 }
 
@@ -111,53 +511,371 @@ fn main() {
 
     // Register Crash Handler
     nanoforge::safety::register_crash_handler();
+    jit_memory::set_limit(args.jit_memory_cap);
+
+    if let Some(file) = args.command.as_ref().and_then(Commands::file) {
+        if !validate_file(file) {
+            std::process::exit(1);
+        }
+    }
+
+    let state = args.state_dir.as_deref().map(|dir| {
+        StateDir::open(Path::new(dir)).unwrap_or_else(|e| {
+            error!("{}", e);
+            std::process::exit(1);
+        })
+    });
 
     match &args.command {
         Some(Commands::Repl) => run_repl(),
-        Some(Commands::Run { file, level }) => {
-            if validate_file(file) {
-                run_file(file, *level);
-            }
-        }
-        Some(Commands::Check { file }) => {
-             if validate_file(file) {
-                 run_check(file);
-             }
-        }
-        Some(Commands::Demo) => run_demo(&args),
-        Some(Commands::Benchmark { file, level }) => {
-            if validate_file(file) {
-                let script = std::fs::read_to_string(file).expect("Failed to read file");
-                // Default level 2 for explicit benchmark
-                if let Err(e) = nanoforge::benchmark::run_benchmark(&script, 10_000, *level) {
-                    error!("Benchmark Error: {}", e);
+        Some(Commands::Run {
+            file,
+            level,
+            bind_array,
+            stats,
+            sanitize,
+            debug,
+            profile,
+            constant_time,
+            overflow,
+            pure,
+            verify_code,
+            entry,
+            args,
+            report,
+            plugin,
+            pass_schedule,
+            emit_bin,
+            emit_asm,
+            emit_ir,
+            emit_report,
+        }) => run_file(
+            file,
+            &RunOptions {
+                compile: CompileOptions::new(*level)
+                    .sanitize(*sanitize)
+                    .debug(*debug)
+                    .profile(*profile)
+                    .constant_time(*constant_time)
+                    .overflow(*overflow)
+                    .pure(*pure)
+                    .verify_code(*verify_code),
+                entry,
+                values: args,
+                bind_specs: bind_array,
+                stats: *stats,
+                report: report.as_deref(),
+                plugin_paths: plugin,
+                schedule_store: pass_schedule.as_deref(),
+                artifacts: Artifacts {
+                    bin: emit_bin.as_ref().map(PathBuf::from),
+                    asm: emit_asm.as_ref().map(PathBuf::from),
+                    ir: emit_ir.as_ref().map(PathBuf::from),
+                    report: emit_report.as_ref().map(PathBuf::from),
+                },
+            },
+        ),
+        Some(Commands::Check { file }) => run_check(file),
+        Some(Commands::Explain { file, level }) => run_explain(file, *level),
+        Some(Commands::Bundle {
+            file,
+            output,
+            brain,
+        }) => run_bundle(file, output, brain.as_deref()),
+        Some(Commands::RunBundle { bundle, inputs }) => run_run_bundle(bundle, inputs),
+        Some(Commands::Demo) => run_demo(&args, state.as_ref()),
+        Some(Commands::Benchmark {
+            file,
+            level,
+            stats,
+            replay,
+            specialize,
+            inputs,
+        }) => {
+            let script = std::fs::read_to_string(file).expect("Failed to read file");
+            let result = match (replay, inputs) {
+                (_, Some(inputs)) => {
+                    nanoforge::benchmark::run_distribution(&script, inputs, 10_000, *level)
+                        .map(|summary| record_benchmark(state.as_ref(), file, *level, &summary))
                 }
+                (Some(replay), None) => Replay::load(Path::new(replay)).and_then(|replay| {
+                    let passes = 10_000usize.div_ceil(replay.samples.len().max(1));
+                    nanoforge::benchmark::run_replay(&script, &replay, passes, *level, *specialize)
+                        .map(|summary| record_benchmark(state.as_ref(), file, *level, &summary))
+                }),
+                (None, None) => nanoforge::benchmark::run_benchmark(&script, 10_000, *level, *stats)
+                    .map(|summary| record_benchmark(state.as_ref(), file, *level, &summary)),
+            };
+            if let Err(e) = result {
+                error!("Benchmark Error: {}", e);
+                std::process::exit(1);
             }
         }
-        Some(Commands::Adaptive { file }) => {
-             if validate_file(file) { run_adaptive(file); }
-        }
-        Some(Commands::Soae { file }) => {
-             if validate_file(file) { run_soae(file); }
-        }
-        Some(Commands::SoaeAi { file, iterations }) => {
-             if validate_file(file) { run_soae_ai(file, *iterations); }
+        Some(Commands::Adaptive {
+            file,
+            promote_share,
+            demote_share,
+            cooldown_ms,
+            max_recompiles_per_minute,
+            jit_after,
+            template,
+            window_ms,
+            duration_ms,
+            evolve,
+            generations,
+            population,
+            audit_log,
+        }) => run_adaptive(
+            file,
+            AdaptiveConfig::default()
+                .tiering(
+                    TieringConfig::default()
+                        .start(if *template { Tier::Template } else { Tier::Interpreted })
+                        .jit_after_calls(*jit_after)
+                        .promote_share(*promote_share)
+                        .demote_share(*demote_share)
+                        .cooldown(Duration::from_millis(*cooldown_ms))
+                        .max_recompiles_per_minute(*max_recompiles_per_minute),
+                )
+                .window(Duration::from_millis(*window_ms))
+                .evolve(evolve.then(|| EvolveOptions {
+                    generations: *generations,
+                    population: *population,
+                    seed: args.seed.unwrap_or(42),
+                })),
+            Duration::from_millis(*duration_ms),
+            audit_log.as_deref(),
+        ),
+        Some(Commands::Live {
+            file,
+            entry,
+            inputs,
+            duration_ms,
+            generations,
+            population,
+            min_speedup,
+            capture,
+            audit_log,
+        }) => run_live(
+            file,
+            entry,
+            inputs,
+            capture.as_deref(),
+            LiveConfig::default()
+                .generations(*generations)
+                .population(*population)
+                .seed(args.seed.unwrap_or(42))
+                .min_speedup(*min_speedup),
+            Duration::from_millis(*duration_ms),
+            audit_log.as_deref(),
+        ),
+        Some(Commands::Soae {
+            file,
+            explain,
+            max_code_size,
+            inputs,
+            per_function,
+            cold,
+            mem_trace,
+        }) => {
+            if *per_function {
+                run_soae_per_function(file, *max_code_size)
+            } else {
+                run_soae(file, *explain, *max_code_size, inputs.as_ref(), *cold, *mem_trace)
+            }
         }
-        Some(Commands::SoaeContext { file, iterations }) => {
-             if validate_file(file) { run_soae_context(file, *iterations); }
+        Some(Commands::Tune {
+            paths,
+            store,
+            input,
+            retune,
+            max_code_size,
+        }) => run_tune(paths, store.as_deref(), *input, *retune, *max_code_size),
+        Some(Commands::PassImpact {
+            paths,
+            level,
+            input,
+            json,
+        }) => run_pass_impact(paths, *level, *input, *json),
+        Some(Commands::SpillImpact {
+            file,
+            level,
+            input,
+            vreg,
+            json,
+        }) => run_spill_impact(file, *level, *input, vreg, *json),
+        Some(Commands::Soak {
+            paths,
+            duration_secs,
+            inputs,
+            generations,
+            population,
+            json,
+        }) => run_soak(
+            paths,
+            SoakConfig::default()
+                .duration(Duration::from_secs(*duration_secs))
+                .inputs(inputs.clone())
+                .generations(*generations)
+                .population(*population)
+                .seed(args.seed.unwrap_or(42)),
+            *json,
+        ),
+        Some(Commands::LearnPasses {
+            file,
+            level,
+            iterations,
+            input,
+            store,
+            relearn,
+        }) => run_learn_passes(file, *level, *iterations, *input, store.as_deref(), *relearn, args.seed),
+        Some(Commands::CompareNative {
+            file,
+            kernel,
+            input,
+        }) => run_compare_native(file, kernel.as_deref(), *input),
+        Some(Commands::SoaeAi {
+            file,
+            iterations,
+            log,
+            record,
+            cold_start,
+        }) => run_soae_ai(
+            file,
+            *iterations,
+            args.seed,
+            log.as_deref(),
+            record.as_deref(),
+            *cold_start,
+            state.as_ref(),
+        ),
+        Some(Commands::SoaeContext {
+            file,
+            iterations,
+            log,
+            record,
+            cold_start,
+        }) => run_soae_context(
+            file,
+            *iterations,
+            args.seed,
+            log.as_deref(),
+            record.as_deref(),
+            *cold_start,
+            state.as_ref(),
+        ),
+        Some(Commands::Brain { action }) => match action {
+            BrainAction::Export { file, format, output } => run_brain_export(file, format, output.as_deref()),
+            BrainAction::Import { file } => run_brain_import(file),
+        },
+        Some(Commands::State { action }) => {
+            let dir = args.state_dir.as_ref().map_or_else(store::default_dir, Into::into);
+            match action {
+                StateAction::Show => run_state_show(&dir),
+                StateAction::Clear { kind } => run_state_clear(&dir, kind.as_deref()),
+            }
         }
+        Some(Commands::Analyze { file, windows }) => run_analyze(file, *windows),
+        Some(Commands::Simulate {
+            log,
+            policies,
+            iterations,
+            runs,
+        }) => run_simulate(log, policies, *iterations, *runs, args.seed.unwrap_or(0)),
         Some(Commands::Evolve {
             file,
             generations,
             population,
             target,
-        }) => {
-             if validate_file(file) { run_evolve(file, *generations, *population, *target); }
-        }
+            machine_code,
+            emit_winner,
+        }) => run_evolve(
+            file,
+            &EvolveRun {
+                generations: *generations,
+                population_size: *population,
+                target: *target,
+                seed: args.seed,
+                machine_code: *machine_code,
+                emit_winner: emit_winner.as_deref(),
+            },
+            state.as_ref(),
+        ),
         None => run_repl(), // Default to REPL if no args
     }
 }
 
+#[derive(Subcommand, Debug)]
+enum BrainAction {
+    /// Report a contextual brain's best variant per size bucket, confidence,
+    /// samples and crossover sizes
+    Export {
+        /// A context-brain-*.json saved by soae-context --state-dir
+        file: String,
+        #[arg(long, default_value = "md", value_parser = ["md", "csv"])]
+        format: String,
+        /// Write the report here instead of to stdout
+        #[arg(short, long, value_name = "PATH")]
+        output: Option<String>,
+    },
+    /// Check an exported (and possibly edited) CSV and show the dispatch
+    /// table it defines
+    Import {
+        file: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum StateAction {
+    /// List each state file with its kind and schema version, flagging
+    /// damaged files and leftovers of interrupted writes
+    Show,
+    /// Delete the state files
+    Clear {
+        /// Only documents of this kind (e.g. brain, context-brain, tuning)
+        #[arg(long)]
+        kind: Option<String>,
+    },
+}
+
+impl Commands {
+    /// Script path for subcommands that take one
+    fn file(&self) -> Option<&str> {
+        match self {
+            Commands::Run { file, .. }
+            | Commands::Check { file }
+            | Commands::Explain { file, .. }
+            | Commands::Bundle { file, .. }
+            | Commands::Benchmark { file, .. }
+            | Commands::Adaptive { file, .. }
+            | Commands::Live { file, .. }
+            | Commands::Soae { file, .. }
+            | Commands::LearnPasses { file, .. }
+            | Commands::SpillImpact { file, .. }
+            | Commands::CompareNative { file, .. }
+            | Commands::SoaeAi { file, .. }
+            | Commands::SoaeContext { file, .. }
+            | Commands::Analyze { file, .. }
+            | Commands::Evolve { file, .. } => Some(file),
+            Commands::Repl
+            | Commands::Demo
+            | Commands::RunBundle { .. }
+            | Commands::Tune { .. }
+            | Commands::PassImpact { .. }
+            | Commands::Soak { .. }
+            | Commands::Brain { .. }
+            | Commands::State { .. }
+            | Commands::Simulate { .. } => None,
+        }
+    }
+}
+
+fn print_seed(seed: Option<u64>) {
+    if let Some(seed) = seed {
+        println!("🎲 Seed: {} (deterministic mode)", seed);
+    }
+}
+
 fn validate_file(path: &str) -> bool {
     let p = Path::new(path);
     if !p.exists() {
@@ -180,10 +898,27 @@ fn run_check(path: &str) {
         }
     };
     
-    let mut parser = NanoParser::new();
-    match parser.parse(&content) {
+    let ast = match NanoParser::new().parse_ast(&content) {
+        Ok(ast) => ast,
+        Err(e) => {
+            error!("Syntax Check Failed: Parse Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let found = diagnostics::check(&ast);
+    log_diagnostics(&found);
+    if diagnostics::has_errors(&found) {
+        std::process::exit(1);
+    }
+    match lowering::lower(&ast) {
         Ok(prog) => {
             info!("Syntax OK: parsed {} functions.", prog.functions.len());
+            if let Err(e) = nanoforge::verifier::verify_program(&prog) {
+                for line in e.lines() {
+                    error!("IR Verification Failed: {}", line);
+                }
+                std::process::exit(1);
+            }
             // Dry-run compilation to check for backend errors
             match Compiler::compile_program(&prog, 2) {
                 Ok(_) => info!("Compilation Check OK."),
@@ -200,6 +935,107 @@ fn run_check(path: &str) {
     }
 }
 
+fn run_explain(path: &str, level: u8) {
+    let explained = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read file: {}", e))
+        .and_then(|script| {
+            let prog = parse_checked(&script)?;
+            let code = Compiler::compile_with_options(&prog, &CompileOptions::new(level))?;
+            Ok(nanoforge::explain::explain(&script, &code))
+        });
+    match explained {
+        Ok(text) => print!("{}", text),
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn run_bundle(path: &str, output: &str, brain: Option<&str>) {
+    let fail = |e: String| -> ! {
+        error!("{}", e);
+        std::process::exit(1);
+    };
+    let dispatch = match brain {
+        Some(brain) if brain.ends_with(".csv") => std::fs::read_to_string(brain)
+            .map_err(|e| format!("{}: {}", brain, e))
+            .and_then(|csv| DispatchTable::from_csv(&csv).map_err(|e| format!("{}: {}", brain, e))),
+        Some(brain) => BrainReport::load(Path::new(brain)).map(|report| report.dispatch_table()),
+        None => Ok(DispatchTable::default()),
+    }
+    .unwrap_or_else(|e| fail(e));
+    let bundle = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read file: {}", e))
+        .and_then(|script| parse_checked(&script))
+        .and_then(|prog| Bundle::build(&prog, &VariantGenerator::new(), dispatch))
+        .unwrap_or_else(|e| fail(e));
+    bundle.save(Path::new(output)).unwrap_or_else(|e| fail(e));
+
+    println!("📦 Bundled {} variants of {} into {}", bundle.variants.len(), path, output);
+    for variant in &bundle.variants {
+        let requires = if variant.requires.is_empty() {
+            "x86-64".to_string()
+        } else {
+            variant.requires.join(", ")
+        };
+        println!(
+            "  {:24} {:>6} bytes  {} relocations  needs {}",
+            variant.config.name,
+            variant.code.len(),
+            variant.relocations.len(),
+            requires
+        );
+    }
+    if bundle.dispatch.winners.is_empty() {
+        println!("No dispatch table: every size runs the first variant the CPU supports");
+    }
+    for (bucket, winner) in &bundle.dispatch.winners {
+        println!("  {:16} → {}", bucket.name(), winner);
+    }
+}
+
+fn run_run_bundle(path: &str, inputs: &[i64]) {
+    let fail = |e: String| -> ! {
+        error!("{}", e);
+        std::process::exit(1);
+    };
+    let runtime = Bundle::load(Path::new(path))
+        .and_then(BundleRuntime::new)
+        .unwrap_or_else(|e| fail(e));
+    for &n in inputs {
+        let result = runtime.call(n).unwrap_or_else(|e| fail(e));
+        println!("main({}) = {}  [{}]", n, result, runtime.variant_for(n.max(0) as u64));
+    }
+}
+
+fn log_diagnostics(found: &[Diagnostic]) {
+    for d in found {
+        match d.severity {
+            Severity::Warning => warn!("{}", d),
+            Severity::Error => error!("{}", d),
+        }
+    }
+}
+
+/// Parse and lower `script`, logging its warnings; diagnosed errors fail it
+fn parse_checked(script: &str) -> Result<Program, String> {
+    let ast = NanoParser::new().parse_ast(script)?;
+    let found = diagnostics::check(&ast);
+    for d in found.iter().filter(|d| d.severity == Severity::Warning) {
+        warn!("{}", d);
+    }
+    if diagnostics::has_errors(&found) {
+        let errors: Vec<String> = found
+            .iter()
+            .filter(|d| d.severity == Severity::Error)
+            .map(|d| d.to_string())
+            .collect();
+        return Err(errors.join("\n"));
+    }
+    lowering::lower(&ast)
+}
+
 fn run_repl() {
     println!("NanoForge REPL v0.1.0");
     println!("Type 'RUN' to execute buffer, 'CLEAR' to reset, 'EXIT' to quit.");
@@ -225,7 +1061,9 @@ fn run_repl() {
             }
             "RUN" => {
                 println!("Compiling...");
-                execute_script(&buffer, 3).unwrap_or_else(|e| println!("Execution Error: {}", e));
+                let options = RunOptions::new(CompileOptions::new(3));
+                execute_script(&buffer, &options, &mut [], &[], None)
+                    .unwrap_or_else(|e| println!("Execution Error: {}", e));
                 buffer.clear();
             }
             _ => {
@@ -235,115 +1073,344 @@ fn run_repl() {
     }
 }
 
-fn run_file(path: &str, level: u8) {
-    let content = std::fs::read_to_string(path).expect("Failed to read file");
-    match execute_script(&content, level) {
-        Ok(_) => {}
-        Err(e) => error!("Runtime Error: {}", e),
+/// How `run` compiles and calls a script, and what it prints or writes
+/// besides the result
+struct RunOptions<'a> {
+    compile: CompileOptions,
+    entry: &'a str,
+    /// Values for the entry's parameters not bound to arrays
+    values: &'a [i64],
+    /// `NAME=FILE` array bindings (see `host_args`)
+    bind_specs: &'a [String],
+    /// Measure the call with hardware performance counters
+    stats: bool,
+    /// Print the optimization report: "json", or markdown for anything else
+    report: Option<&'a str>,
+    plugin_paths: &'a [String],
+    /// Pass schedule store to look the script up in
+    schedule_store: Option<&'a str>,
+    artifacts: Artifacts,
+}
+
+impl RunOptions<'_> {
+    /// Call `main` with no arguments and report nothing but the result
+    fn new(compile: CompileOptions) -> Self {
+        RunOptions {
+            compile,
+            entry: "main",
+            values: &[],
+            bind_specs: &[],
+            stats: false,
+            report: None,
+            plugin_paths: &[],
+            schedule_store: None,
+            artifacts: Artifacts::new(),
+        }
     }
 }
 
-fn execute_script(script: &str, level: u8) -> Result<(), String> {
-    let mut parser = NanoParser::new();
-    match parser.parse(script) {
-        Ok(prog) => {
-            let (code, main_offset) =
-                Compiler::compile_program(&prog, level).map_err(|e| e.to_string())?;
+fn run_file(path: &str, options: &RunOptions) {
+    let content = std::fs::read_to_string(path).expect("Failed to read file");
+
+    let schedule = options.schedule_store.and_then(|store| {
+        let store = PassScheduleStore::open(Path::new(store)).unwrap_or_else(|e| {
+            error!("{}", e);
+            std::process::exit(1);
+        });
+        let entry = store.lookup(&content).cloned();
+        if entry.is_none() {
+            warn!("No pass schedule stored for {}; running every pass", path);
+        }
+        entry.map(|e| e.schedule)
+    });
+
+    let mut bindings = Vec::new();
+    for spec in options.bind_specs {
+        match host_args::parse_binding(spec) {
+            Ok(binding) => bindings.push(binding),
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let mut plugins = Vec::new();
+    for path in options.plugin_paths {
+        match Plugin::load(path) {
+            Ok(plugin) => plugins.push(plugin),
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let schedule = schedule.as_ref();
+    if let Err(e) = execute_script(&content, options, &mut bindings, &plugins, schedule) {
+        error!("Runtime Error: {}", e);
+        std::process::exit(1);
+    }
+}
 
-            // Debug Dump
-            if tracing::enabled!(Level::DEBUG) {
-                 std::fs::write("debug.bin", &code).ok();
-                 info!("Dumped machine code to debug.bin");
+fn execute_script(
+    script: &str,
+    run: &RunOptions,
+    bindings: &mut [(String, host_args::HostArray)],
+    plugins: &[Plugin],
+    schedule: Option<&PassSchedule>,
+) -> Result<(), String> {
+    let (options, entry) = (&run.compile, run.entry);
+    match parse_checked(script) {
+        Ok(mut prog) => {
+            plugin::run_all(plugins, &mut prog, options)?;
+            if let Some(schedule) = schedule {
+                schedule.apply(&mut prog);
+            }
+            let entry_fn = prog
+                .functions
+                .iter()
+                .find(|f| f.name == entry)
+                .ok_or_else(|| format!("Entry point not found: fn {}()", entry))?;
+            let args = host_args::marshal_args(entry_fn, bindings, run.values)?;
+
+            let program = CompiledProgram::compile(&prog, options)?;
+            let compiled = program.code();
+
+            run.artifacts.emit(compiled)?;
+
+            info!("Executing {}()...", entry);
+            let function = program
+                .get_fn(entry)
+                .ok_or_else(|| format!("Entry point not found: fn {}()", entry))?;
+            if options.pure {
+                sanitizer::set_buffers(bindings.iter().map(|(_, array)| array.as_slice()));
+            }
+            let call = || function.call(&args);
+            let session = options
+                .profile
+                .then(|| ProfileSession::start(&compiled.profiled_functions));
+
+            let (result, perf) = match run.stats.then(PerfCounters::new) {
+                Some(Ok(counters)) => {
+                    let (result, perf) = counters.measure(call);
+                    (result, Some(perf))
+                }
+                Some(Err(e)) => {
+                    warn!("--stats unavailable: {}", e);
+                    (call(), None)
+                }
+                None => (call(), None),
+            };
+            let profile = session.map(ProfileSession::finish);
+
+            // A trapped call reports the fault, not just that it trapped
+            if let Some(fault) = sanitizer::take_fault() {
+                return Err(format!(
+                    "Sanitizer: {}",
+                    fault.report(&compiled.sanitizer_sites)
+                ));
             }
+            let result = result?;
 
-            let memory = DualMappedMemory::new(code.len() + 4096).map_err(|e| e.to_string())?;
-            CodeGenerator::emit_to_memory(&memory, &code, 0);
-            let func_ptr: extern "C" fn() -> i64 =
-                unsafe { std::mem::transmute(memory.rx_ptr.add(main_offset)) };
-            
-            info!("Executing script...");
-            let result = func_ptr();
             println!("Result: {}", result);
+            if let Some(perf) = perf {
+                print!("{}", perf.summary());
+            }
+            if let Some(profile) = profile {
+                print!("{}", profile.summary());
+            }
+            match run.report {
+                Some("json") => println!("{}", compiled.report.to_json()),
+                Some(_) => print!("{}", compiled.report.to_markdown()),
+                None => {}
+            }
             Ok(())
         }
         Err(e) => Err(format!("Parsing Error: {}", e)),
     }
 }
 
-fn run_adaptive(path: &str) {
-    println!("=== NanoForge Adaptive Runtime ===");
-    let script = std::fs::read_to_string(path).expect("Failed to read file");
-    let mut parser = NanoParser::new();
-    let prog_ir = parser.parse(&script).expect("Parse failed");
-
-    // Constants for Metric Calculation
-    // Assuming vec_add_stress.nf: 100 * 10,000 = 1,000,000 Ops per Call
-    const OPS_PER_CALL: f64 = 1_000_000.0;
-    const CLOCK_SPEED: f64 = 4_000_000_000.0; // 4.0 GHz reference
-
-    // Phase 1: Tier 1 (Scalar / Level 2)
-    print!("Running Tier 1 (Scalar)... ");
-    io::stdout().flush().unwrap();
-
-    let (code_base, main_offset_base) =
-        Compiler::compile_program(&prog_ir, 2).expect("Compile failed");
-    let mem_base = DualMappedMemory::new(code_base.len() + 4096).unwrap();
-    CodeGenerator::emit_to_memory(&mem_base, &code_base, 0);
+/// The audit log at `path`, exiting if it can't be used
+fn open_audit(path: Option<&str>) -> Option<AuditLog> {
+    let path = path?;
+    match AuditLog::open(Path::new(path)) {
+        Ok(log) => Some(log),
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
 
-    let current_fn: extern "C" fn() -> i64 =
-        unsafe { std::mem::transmute(mem_base.rx_ptr.add(main_offset_base)) };
+/// Name a script's program goes by in the audit log
+fn program_name(path: &str) -> String {
+    Path::new(path).file_stem().map_or(String::new(), |s| s.to_string_lossy().into_owned())
+}
 
-    // Warmup
-    for _ in 0..10 {
-        std::hint::black_box(current_fn());
-    }
+fn run_adaptive(path: &str, config: AdaptiveConfig, duration: Duration, audit_log: Option<&str>) {
+    println!("=== NanoForge Adaptive Runtime ===");
+    let audit = open_audit(audit_log);
+    let script = std::fs::read_to_string(path).expect("Failed to read file");
+    let prog_ir = NanoParser::new().parse(&script).expect("Parse failed");
+    let mut runtime = match AdaptiveRuntime::new(prog_ir, "main", config) {
+        Ok(runtime) => match audit {
+            Some(log) => runtime.with_audit(log, &program_name(path)),
+            None => runtime,
+        },
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
 
-    // Measure Tier 1
-    let iterations = 100;
+    install_shutdown();
     let start = std::time::Instant::now();
-    for _ in 0..iterations {
-        std::hint::black_box(current_fn());
+    let mut result = 0;
+    let mut reported = 0;
+    while start.elapsed() < duration && !shutdown::requested() {
+        result = match runtime.call(&[]) {
+            Ok(result) => result,
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        };
+        for change in &runtime.changes()[reported..] {
+            println!(
+                "[call {}] {:?} -> {:?}: {}",
+                change.call, change.from, change.to, change.reason
+            );
+            if let (Tier::Optimized, Some(hot)) = (change.to, runtime.hot_function()) {
+                println!("         {}() at -O3, the rest at -O1", hot);
+            }
+        }
+        reported = runtime.changes().len();
     }
-    let dur_t1 = start.elapsed();
-    let cyc_op_t1 = (dur_t1.as_secs_f64() * CLOCK_SPEED) / (iterations as f64 * OPS_PER_CALL);
 
-    println!("{:.2} cycles/op", cyc_op_t1);
-
-    // Phase 2: Optimization Trigger
-    println!("\n🔥 HOT SWAP TRIGGERED 🔥\n");
-
-    // Compile Tier 2 (Vector / Level 3)
-    print!("Running Tier 2 (AVX2)... ");
-    io::stdout().flush().unwrap();
-
-    let (code_opt, main_offset_opt) =
-        Compiler::compile_program(&prog_ir, 3).expect("Compile failed");
-    let mem_opt = DualMappedMemory::new(code_opt.len() + 4096).unwrap();
-    CodeGenerator::emit_to_memory(&mem_opt, &code_opt, 0);
-    let fn_opt: extern "C" fn() -> i64 =
-        unsafe { std::mem::transmute(mem_opt.rx_ptr.add(main_offset_opt)) };
+    println!("\nResult: {} after {} calls\n", result, runtime.calls());
+    println!("{:<12} {:>10} {:>12} {:>12}", "Tier", "Calls", "ns/call", "compile µs");
+    let stats = runtime.stats();
+    for (tier, s) in stats {
+        println!(
+            "{:<12} {:>10} {:>12.0} {:>12.1}",
+            format!("{:?}", tier),
+            s.calls,
+            s.avg_ns(),
+            s.compile.as_secs_f64() * 1e6
+        );
+    }
+    if let (Some((first_tier, first)), Some((last_tier, last))) =
+        (stats.iter().next(), stats.iter().next_back())
+    {
+        if last.avg_ns() > 0.0 {
+            println!(
+                "\nSpeedup: {:.2}x ({:?} vs {:?})",
+                first.avg_ns() / last.avg_ns(),
+                last_tier,
+                first_tier
+            );
+        }
+    }
+}
 
-    // Warmup
-    for _ in 0..10 {
-        std::hint::black_box(fn_opt());
+fn run_live(
+    path: &str,
+    entry: &str,
+    inputs: &[u64],
+    capture: Option<&str>,
+    config: LiveConfig,
+    duration: Duration,
+    audit_log: Option<&str>,
+) {
+    println!("=== NanoForge Live Evolution ===");
+    let audit = open_audit(audit_log);
+    let script = std::fs::read_to_string(path).expect("Failed to read file");
+    let program = NanoParser::new().parse(&script).expect("Parse failed");
+    let Some(seed) = program.functions.iter().find(|f| f.name == entry) else {
+        error!("No function named '{}'", entry);
+        return;
+    };
+    if inputs.is_empty() {
+        error!("--inputs needs at least one value");
+        return;
     }
+    let (memory, offset) = match live_evolution::compile_standalone(seed, 0) {
+        Ok(compiled) => compiled,
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    };
+    let hot = Arc::new(HotFunction::new(memory, offset));
+    let evolver = match audit {
+        Some(log) => {
+            LiveEvolver::spawn_audited(hot.clone(), seed.clone(), config, log, &program_name(path))
+        }
+        None => LiveEvolver::spawn(hot.clone(), seed.clone(), config),
+    };
+    let evolver = match evolver {
+        Ok(evolver) => evolver,
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    };
 
-    // Measure Tier 2
+    install_shutdown();
     let start = std::time::Instant::now();
-    for _ in 0..iterations {
-        std::hint::black_box(fn_opt());
+    let mut result = 0;
+    let mut next = 0;
+    while start.elapsed() < duration && !shutdown::requested() {
+        result = hot.call(inputs[next]);
+        next = (next + 1) % inputs.len();
     }
-    let dur_t2 = start.elapsed();
-    let cyc_op_t2 = (dur_t2.as_secs_f64() * CLOCK_SPEED) / (iterations as f64 * OPS_PER_CALL);
-
-    println!("{:.2} cycles/op", cyc_op_t2);
+    let rounds = match evolver.stop() {
+        Ok(rounds) => rounds,
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    };
 
-    // Final Report
-    let speedup = dur_t1.as_secs_f64() / dur_t2.as_secs_f64();
-    println!("\nSpeedup: {:.2}x", speedup);
+    let stats = hot.stats();
+    println!(
+        "\nResult: {} after {} calls ({} cycles/call lately)\n",
+        result, stats.calls, stats.avg_cycles
+    );
+    println!(
+        "{:<6} {:>7} {:>12} {:>9} {:>8} {:>13}",
+        "Round", "Inputs", "Generations", "Speedup", "Swapped", "Instructions"
+    );
+    for r in &rounds {
+        println!(
+            "{:<6} {:>7} {:>12} {:>8.2}x {:>8} {:>13}",
+            r.round,
+            r.inputs,
+            r.generations,
+            r.speedup,
+            if r.swapped { "yes" } else { "no" },
+            r.instructions
+        );
+    }
+    let swaps = rounds.iter().filter(|r| r.swapped).count();
+    println!("\n{} of {} rounds swapped in new code", swaps, rounds.len());
+
+    if let Some(path) = capture {
+        let replay = hot.capture().replay(entry);
+        match replay.save(Path::new(path)) {
+            Ok(()) => println!(
+                "💾 Captured {} of {} timed calls to {}",
+                replay.samples.len(),
+                replay.seen,
+                path
+            ),
+            Err(e) => error!("{}", e),
+        }
+    }
 }
 
-fn run_demo(args: &Args) {
+fn run_demo(args: &Args, state: Option<&StateDir>) {
     // Initialize Metrics (Prometheus) - Only needed for long running demo
     metrics_exporter_prometheus::PrometheusBuilder::new()
         .with_http_listener(([0, 0, 0, 0], 9000))
@@ -365,6 +1432,14 @@ fn run_demo(args: &Args) {
     CodeGenerator::emit_to_memory(&mem_a, &code_a_bytes, 0);
     let hot_func = Arc::new(HotFunction::new(mem_a, 0));
 
+    let introspection = Introspection::new();
+    introspection.register_program("demo", &["sum_loop"]);
+    introspection.set_tier("demo", "sum_loop", "simple_loop", "initial");
+    match introspection.serve(nanoforge::introspection::DEFAULT_ADDR) {
+        Ok((addr, _)) => info!("Status endpoint on http://{}/status", addr),
+        Err(e) => warn!("Status endpoint unavailable: {}", e),
+    }
+
     // --- Step 2: Initialize Profiler ---
     let pid = std::process::id() as i32;
     let profiler: Arc<dyn nanoforge::profiler::ProfileSource> =
@@ -403,9 +1478,14 @@ fn run_demo(args: &Args) {
 
     // --- Step 4: Workload ---
     info!("Starting workload (Summing 0..1000 repeatedly)...");
+    install_shutdown();
     let mut total_result = 0;
 
     for i in 0..100 {
+        if shutdown::requested() {
+            info!("Shutdown requested; stopping after step {}", i);
+            break;
+        }
         let mut batch_sum = 0;
         for _ in 0..10000 {
             batch_sum += hot_func.call(1000);
@@ -413,9 +1493,11 @@ fn run_demo(args: &Args) {
         total_result += batch_sum;
 
         let current_count = profiler.read();
+        let calls = hot_func.stats();
+        introspection.record_calls("demo", "sum_loop", calls);
         info!(
-            "Step {}: Instructions = {}, Last Batch Sum = {}",
-            i, current_count, batch_sum
+            "Step {}: Instructions = {}, Calls = {}, Avg Cycles/Call = {}, Last Batch Sum = {}",
+            i, current_count, calls.calls, calls.avg_cycles, batch_sum
         );
 
         thread::sleep(Duration::from_millis(100));
@@ -423,9 +1505,45 @@ fn run_demo(args: &Args) {
 
     profiler.disable();
     info!("Final Result: {}", total_result);
+    flush_state(state, "demo-status.json", StatusView::SCHEMA, &introspection.status());
     info!("Phase 10 Complete.");
 }
 
+/// Let Ctrl+C stop long-running modes between steps (see `shutdown`)
+fn install_shutdown() {
+    if let Err(e) = shutdown::install() {
+        warn!("Graceful shutdown unavailable: {}", e);
+    }
+}
+
+/// Save `value` as `name` in the `--state-dir`, if one was given
+fn flush_state<T: serde::Serialize>(state: Option<&StateDir>, name: &str, schema: Schema, value: &T) {
+    let Some(state) = state else { return };
+    match state.save_json(name, schema, value) {
+        Ok(path) => println!("💾 Saved {}", path.display()),
+        Err(e) => error!("{}", e),
+    }
+}
+
+/// Append a benchmark to `benchmarks.jsonl` in the `--state-dir`
+fn record_benchmark(
+    state: Option<&StateDir>,
+    script: &str,
+    level: u8,
+    summary: &impl serde::Serialize,
+) {
+    let Some(state) = state else { return };
+    let entry = serde_json::json!({
+        "script": script,
+        "level": level,
+        "cpu": CpuFeatures::detect().summary(),
+        "summary": summary,
+    });
+    if let Err(e) = state.append_jsonl("benchmarks.jsonl", &entry) {
+        error!("{}", e);
+    }
+}
+
 /// Self-Optimizing Assembly Engine (SOAE) Demo
 ///
 /// This demonstrates the core SOAE concept:
@@ -433,29 +1551,39 @@ fn run_demo(args: &Args) {
 /// 2. Benchmark all variants in the nanosecond sandbox
 /// 3. Select the fastest variant
 /// 4. Show comparative performance
-fn run_soae(path: &str) {
+fn run_soae(
+    path: &str,
+    explain: Option<usize>,
+    max_code_size: Option<usize>,
+    inputs: Option<&InputDistribution>,
+    cold: Option<u32>,
+    mem_trace: Option<usize>,
+) {
     println!("\n╔══════════════════════════════════════════════════════════════╗");
     println!("║     🔥 NanoForge SOAE (Self-Optimizing Assembly Engine) 🔥    ║");
     println!("╚══════════════════════════════════════════════════════════════╝\n");
 
-    // Detect CPU features
-    let cpu = CpuFeatures::detect();
-    println!("🖥️  CPU Features: {}\n", cpu.summary());
-
-    // Parse the source file
     let script = std::fs::read_to_string(path).expect("Failed to read file");
-    let mut parser = NanoParser::new();
-    let program = parser.parse(&script).expect("Parse failed");
+    let program = NanoParser::new().parse(&script).expect("Parse failed");
+    let mut options = SoaeOptions::default();
+    if let Some(bytes) = max_code_size {
+        println!("   Code-size budget: {} bytes", bytes);
+        options = options.max_code_size(bytes);
+    }
+    if let Some(inputs) = inputs {
+        options = options.inputs(inputs.clone());
+    }
+    if let Some(calls) = cold {
+        options = options.cold(calls);
+    }
+    let report = soae::run(&program, &options).unwrap_or_else(|e| {
+        eprintln!("Variant generation failed: {}", e);
+        std::process::exit(1);
+    });
+    println!("🖥️  CPU Features: {}\n", report.cpu.summary());
 
-    // Generate variants
-    println!("📦 Generating Code Variants...");
-    let generator = VariantGenerator::new();
-    let variants = generator
-        .generate_variants(&program)
-        .expect("Variant generation failed");
-
-    println!("   Generated {} variants:\n", variants.len());
-    for (i, v) in variants.iter().enumerate() {
+    println!("📦 Generated {} variants:\n", report.variants.len());
+    for (i, v) in report.variants.iter().enumerate() {
         println!(
             "   {}. {} (opt level: {}, {} bytes)",
             i + 1,
@@ -465,65 +1593,559 @@ fn run_soae(path: &str) {
         );
     }
 
-    // Create sandbox and benchmark all variants
-    println!("\n⏱️  Benchmarking in Nanosecond Sandbox...\n");
-    let sandbox = NanosecondSandbox::new(SandboxConfig {
-        warmup_iterations: 50,
-        measurement_iterations: 500,
-        pin_to_core: Some(0),
-    });
-
-    // Use a test input
-    let test_input = 1000u64;
-
-    let rankings = sandbox.benchmark_all(&variants, test_input);
+    match &report.distribution {
+        Some(dist) => {
+            let points: Vec<String> = report
+                .points
+                .iter()
+                .map(|p| format!("{} ({:.0}%)", p.input, p.weight * 100.0))
+                .collect();
+            println!(
+                "\n⏱️  Benchmarked in Nanosecond Sandbox over {}, ranked by expected cost",
+                dist
+            );
+            println!("   Inputs: {}\n", points.join(", "));
+        }
+        None => println!("\n⏱️  Benchmarked in Nanosecond Sandbox (input {})\n", report.input),
+    }
+    if let Some(choice) = &report.pinned {
+        println!("📌 Pinned to {}", choice);
+    }
 
     // Display results
-    println!("┌────┬──────────────────────┬────────────────┬────────────────┐");
-    println!("│ #  │ Variant              │ Cycles/Op      │ Throughput     │");
-    println!("├────┼──────────────────────┼────────────────┼────────────────┤");
+    println!("┌────┬──────────────────────┬────────────────┬────────────────┬──────────┬──────────┐");
+    println!("│ #  │ Variant              │ Cycles/Op      │ Throughput     │ Code     │ L1I MPKI │");
+    println!("├────┼──────────────────────┼────────────────┼────────────────┼──────────┼──────────┤");
 
-    let baseline_cycles = rankings
-        .first()
-        .map(|r| r.result.cycles_per_op)
-        .unwrap_or(1);
-
-    for ranked in &rankings {
+    let baseline_cycles = report.winner().expected_cycles;
+    for ranked in &report.rankings {
         let speedup = if ranked.rank == 0 {
             "🏆 WINNER".to_string()
         } else {
-            let ratio = ranked.result.cycles_per_op as f64 / baseline_cycles as f64;
+            let ratio = ranked.expected_cycles / baseline_cycles;
             format!("{:.2}x slower", ratio)
         };
 
+        let code_size = report.variant(&ranked.variant_name).map_or(0, |v| v.code_size);
         println!(
-            "│ {:2} │ {:20} │ {:>14} │ {:>14} │",
+            "│ {:2} │ {:20} │ {:>14} │ {:>14} │ {:>8} │ {:>8} │",
             ranked.rank + 1,
             &ranked.variant_name,
-            format!("{} cyc", ranked.result.cycles_per_op),
-            speedup
+            match ranked.result.precision {
+                _ if report.distribution.is_some() => format!("{:.0} exp", ranked.expected_cycles),
+                Some(p) => format!("{} ±{:.1}%", ranked.result.cycles_per_op, p * 100.0),
+                None => format!("{} cyc", ranked.result.cycles_per_op),
+            },
+            speedup,
+            format!("{} B", code_size),
+            ranked
+                .result
+                .l1i_mpki
+                .map_or("n/a".to_string(), |m| format!("{:.2}", m))
         );
     }
-    println!("└────┴──────────────────────┴────────────────┴────────────────┘");
+    println!("└────┴──────────────────────┴────────────────┴────────────────┴──────────┴──────────┘");
+    let cold_rankings = report.cold_rankings();
+    if let Some((_, fastest)) = cold_rankings.first() {
+        println!("\n❄️  Cold caches (median of {} call(s), each after evicting the caches):", fastest.calls);
+        println!("┌────┬──────────────────────┬────────────────┬────────────────┬──────────────┐");
+        println!("│ #  │ Variant              │ Cold Cycles    │ Cold Time      │ Cold / Warm  │");
+        println!("├────┼──────────────────────┼────────────────┼────────────────┼──────────────┤");
+        for (i, (ranked, cold)) in cold_rankings.iter().enumerate() {
+            println!(
+                "│ {:2} │ {:20} │ {:>14} │ {:>14} │ {:>11.1}x │",
+                i + 1,
+                &ranked.variant_name,
+                format!("{} cyc", cold.cycles_per_op),
+                format!("{} ns", cold.nanoseconds_per_op),
+                cold.cycles_per_op as f64 / ranked.result.cycles_per_op.max(1) as f64
+            );
+        }
+        println!("└────┴──────────────────────┴────────────────┴────────────────┴──────────────┘");
+        if cold_rankings[0].0.variant_name != report.winner().variant_name {
+            println!("   Cold-start winner differs: {}", cold_rankings[0].0.variant_name);
+        }
+    }
+    let throttled = report.throttled();
+    if !throttled.is_empty() {
+        println!("⚠️  CPU throttled while measuring: {}", throttled.join(", "));
+    }
 
-    // Execute the winning variant
-    if let Some(winner) = rankings.first() {
-        let winner_variant = variants
-            .iter()
-            .find(|v| v.config.name == winner.variant_name)
-            .expect("Winner not found");
+    if let Some(n) = explain {
+        println!("\n🔍 Variant Explanations (vs winner):");
+        for explanation in report.explain(n) {
+            println!("\n{}", explanation);
+        }
+    }
+
+    if let Some(n) = mem_trace {
+        match MemSampler::new(mem_trace::DEFAULT_PERIOD) {
+            Ok(sampler) => {
+                println!("\n🧠 Memory accesses (input {}):", report.input);
+                for ranked in report.rankings.iter().take(n) {
+                    let Some(variant) = report.variant(&ranked.variant_name) else {
+                        continue;
+                    };
+                    let (_, mem) = variant.trace_memory(report.input, &sampler);
+                    println!("\n{}\n{}", ranked.variant_name, mem.summary());
+                }
+            }
+            Err(e) => warn!("--mem-trace unavailable: {}", e),
+        }
+    }
+
+    let winner = report.winner();
+    println!("\n🚀 Executing winner: {}", winner.variant_name);
+    println!("   Result: {}", report.winner_output);
+    println!("   Cycles/Op: {}", winner.result.cycles_per_op);
+    if report.distribution.is_some() {
+        println!("   Expected Cycles/Op: {:.0}", winner.expected_cycles);
+    }
+    println!("   NUMA: {}", winner.result.numa);
+    println!(
+        "   Ops/Second: {:.2e}",
+        winner.result.throughput_ops_per_sec()
+    );
+
+    println!("\n✅ SOAE Demo Complete!\n");
+}
+
+fn run_soae_per_function(path: &str, max_code_size: Option<usize>) {
+    let script = std::fs::read_to_string(path).expect("Failed to read file");
+    let program = NanoParser::new().parse(&script).expect("Parse failed");
+    let mut options = SoaeOptions::default();
+    if let Some(bytes) = max_code_size {
+        println!("   Code-size budget: {} bytes", bytes);
+        options = options.max_code_size(bytes);
+    }
+    let report = soae::run_per_function(&program, &options).unwrap_or_else(|e| {
+        eprintln!("Per-function SOAE failed: {}", e);
+        std::process::exit(1);
+    });
+    println!("🖥️  CPU Features: {}\n", report.cpu.summary());
+    print!("{}", report.profile.summary());
+    println!("\n⏱️  Benchmarked in Nanosecond Sandbox (input {})", report.input);
+    if let Some(choice) = &report.pinned {
+        println!("📌 Pinned to {}", choice);
+    }
+
+    for function in &report.functions {
+        println!("\n🔥 fn {} ({:.1}% of cycles)", function.function, function.share * 100.0);
+        println!("┌────┬──────────────────────┬────────────────┬────────────────┐");
+        println!("│ #  │ Variant              │ Cycles/Op      │ Throughput     │");
+        println!("├────┼──────────────────────┼────────────────┼────────────────┤");
+        let baseline_cycles = function.rankings[0].result.cycles_per_op.max(1);
+        for ranked in &function.rankings {
+            let speedup = if ranked.rank == 0 {
+                "🏆 WINNER".to_string()
+            } else {
+                let ratio = ranked.result.cycles_per_op as f64 / baseline_cycles as f64;
+                format!("{:.2}x slower", ratio)
+            };
+            println!(
+                "│ {:2} │ {:20} │ {:>14} │ {:>14} │",
+                ranked.rank + 1,
+                &ranked.variant_name,
+                match ranked.result.precision {
+                    Some(p) => format!("{} ±{:.1}%", ranked.result.cycles_per_op, p * 100.0),
+                    None => format!("{} cyc", ranked.result.cycles_per_op),
+                },
+                speedup
+            );
+        }
+        println!("└────┴──────────────────────┴────────────────┴────────────────┘");
+    }
 
-        println!("\n🚀 Executing winner: {}", winner.variant_name);
-        let result = winner_variant.execute(test_input);
-        println!("   Result: {}", result);
-        println!("   Cycles/Op: {}", winner.result.cycles_per_op);
+    println!("\n🚀 Executing combined program: {}", report.combined.config.name);
+    println!("   (untuned functions: {})", report.default.name);
+    println!("   Result: {}", report.winner_output);
+    println!("   Cycles/Op: {}", report.combined_result.cycles_per_op);
+    println!("   Code: {} B", report.combined.code_size);
+}
+
+fn run_tune(
+    paths: &[String],
+    store: Option<&str>,
+    input: u64,
+    retune: bool,
+    max_code_size: Option<usize>,
+) {
+    println!("=== NanoForge Tune ===");
+    let cpu = CpuFeatures::detect().summary();
+    println!("CPU: {}", cpu);
+    let store_path = store.map_or_else(TuningStore::default_path, |p| Path::new(p).to_path_buf());
+    println!("Store: {}\n", store_path.display());
+
+    let fail = |e: String| -> ! {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    };
+    let mut db = TuningStore::open(&store_path).unwrap_or_else(|e| fail(e));
+    let scripts = tuning::collect_scripts(paths).unwrap_or_else(|e| fail(e));
+    if scripts.is_empty() {
+        fail("No scripts to tune".to_string());
+    }
+
+    let mut options = SoaeOptions::default().input(input);
+    if let Some(bytes) = max_code_size {
+        options = options.max_code_size(bytes);
+    }
+
+    println!("┌──────────────────────┬──────────────────────┬────────────┬──────────┬──────────┬────────┐");
+    println!("│ Script               │ Best Variant         │ Cycles/Op  │ Speedup  │ Code     │ Status │");
+    println!("├──────────────────────┼──────────────────────┼────────────┼──────────┼──────────┼────────┤");
+    install_shutdown();
+    let mut failures = Vec::new();
+    for path in &scripts {
+        if shutdown::requested() {
+            failures.push("Interrupted; the store keeps the scripts tuned so far".to_string());
+            break;
+        }
+        let name = path.display().to_string();
+        let short = path.file_name().map_or(name.clone(), |f| f.to_string_lossy().into_owned());
+        let source = match std::fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(e) => {
+                failures.push(format!("{}: {}", name, e));
+                continue;
+            }
+        };
+        let cached = db.lookup(&source).filter(|e| !retune && e.cpu == cpu).cloned();
+        let (entry, status) = match cached {
+            Some(entry) => (entry, "cached"),
+            None => match tuning::tune(&name, &source, &options) {
+                Ok(entry) => {
+                    db.record(&source, entry.clone());
+                    (entry, "tuned")
+                }
+                Err(e) => {
+                    println!("│ {:20} │ {:20} │ {:>10} │ {:>8} │ {:>8} │ {:6} │", short, "-", "-", "-", "-", "failed");
+                    failures.push(format!("{}: {}", name, e));
+                    continue;
+                }
+            },
+        };
         println!(
-            "   Ops/Second: {:.2e}",
-            winner.result.throughput_ops_per_sec()
+            "│ {:20} │ {:20} │ {:>10} │ {:>8} │ {:>8} │ {:6} │",
+            short,
+            entry.config.name,
+            entry.cycles_per_op,
+            format!("{:.2}x", entry.speedup()),
+            format!("{} B", entry.code_size),
+            status
         );
     }
+    println!("└──────────────────────┴──────────────────────┴────────────┴──────────┴──────────┴────────┘");
 
-    println!("\n✅ SOAE Demo Complete!\n");
+    db.save_to_file(&store_path).unwrap_or_else(|e| fail(e));
+    if !failures.is_empty() {
+        fail(failures.join("\n"));
+    }
+}
+
+fn run_pass_impact(paths: &[String], level: u8, input: u64, json: bool) {
+    let fail = |e: String| -> ! {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    };
+    let scripts = tuning::collect_scripts(paths).unwrap_or_else(|e| fail(e));
+    if scripts.is_empty() {
+        fail("No scripts to measure".to_string());
+    }
+    let base = CompileOptions::new(level);
+    let options = SoaeOptions::default().input(input);
+
+    install_shutdown();
+    let mut impacts = Vec::new();
+    let mut failures = Vec::new();
+    for path in &scripts {
+        if shutdown::requested() {
+            failures.push("Interrupted; the summary covers the scripts measured so far".to_string());
+            break;
+        }
+        let name = path.display().to_string();
+        let measured = std::fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|source| pass_impact::measure(&name, &source, &base, &options));
+        match measured {
+            Ok(impact) => {
+                if !json {
+                    println!("Measured {} ({} cycles/op)", name, impact.baseline_cycles_per_op);
+                }
+                impacts.push(impact);
+            }
+            Err(e) => failures.push(format!("{}: {}", name, e)),
+        }
+    }
+    let summary = pass_impact::summarize(&impacts);
+
+    if json {
+        let out = serde_json::json!({ "scripts": impacts, "passes": summary });
+        println!("{}", serde_json::to_string_pretty(&out).unwrap());
+    } else {
+        println!("\n=== Pass impact at -O{} over {} script(s), input {} ===", level, impacts.len(), input);
+        println!("┌────────────────────────┬────────┬──────────┬──────────────────────────────┐");
+        println!("│ Pass                   │ Fired  │ Speedup  │ Best                         │");
+        println!("├────────────────────────┼────────┼──────────┼──────────────────────────────┤");
+        for pass in &summary {
+            let best = pass.best.as_ref().map_or("-".to_string(), |(script, speedup)| {
+                let short = Path::new(script).file_name().map_or(script.clone(), |f| f.to_string_lossy().into_owned());
+                format!("{} ({:.2}x)", short, speedup)
+            });
+            println!(
+                "│ {:22} │ {:>6} │ {:>8} │ {:28} │",
+                pass.pass.name(),
+                format!("{}/{}", pass.fired, impacts.len()),
+                format!("{:.2}x", pass.mean_speedup),
+                best
+            );
+        }
+        println!("└────────────────────────┴────────┴──────────┴──────────────────────────────┘");
+        println!("Speedup: geometric mean over the scripts a pass changed of (cycles without it) / (cycles with it)");
+    }
+    if !failures.is_empty() {
+        fail(failures.join("\n"));
+    }
+}
+
+fn run_spill_impact(path: &str, level: u8, input: u64, vregs: &[u8], json: bool) {
+    let options = SoaeOptions::default().input(input);
+    let base = CompileOptions::new(level);
+    let measured = std::fs::read_to_string(path)
+        .map_err(|e| e.to_string())
+        .and_then(|source| spill_impact::measure(path, &source, &base, &options, vregs));
+    let impact = match measured {
+        Ok(impact) => impact,
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            std::process::exit(1);
+        }
+    };
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&impact).unwrap());
+        return;
+    }
+    println!("=== Spill impact of {} at -O{}, input {} ===", path, level, input);
+    println!("Baseline: {} cycles/op", impact.baseline_cycles_per_op);
+    if impact.natural_spills.is_empty() {
+        println!("Spilled by the allocator: none");
+    } else {
+        println!("Spilled by the allocator: {}", impact.natural_spills.join(", "));
+    }
+    println!("┌──────────────────┬──────────────┬──────────┬──────────────────────────┐");
+    println!("│ Forced spill     │ Cycles/op    │ Slowdown │ Functions                │");
+    println!("├──────────────────┼──────────────┼──────────┼──────────────────────────┤");
+    for (m, slowdown) in impact.ranked() {
+        println!(
+            "│ {:16} │ {:>12} │ {:>8} │ {:24} │",
+            m.vregs.join("+"),
+            m.cycles_per_op.unwrap_or(0),
+            format!("{:.2}x", slowdown),
+            m.functions.join(", ")
+        );
+    }
+    println!("└──────────────────┴──────────────┴──────────┴──────────────────────────┘");
+    let unchanged: Vec<String> = impact
+        .spills
+        .iter()
+        .filter(|m| m.cycles_per_op.is_none())
+        .map(|m| m.vregs.join("+"))
+        .collect();
+    if !unchanged.is_empty() {
+        println!("Already spilled or unused (code unchanged): {}", unchanged.join(", "));
+    }
+}
+
+fn run_soak(paths: &[String], config: SoakConfig, json: bool) {
+    let fail = |e: String| -> ! {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    };
+    let scripts = tuning::collect_scripts(paths).unwrap_or_else(|e| fail(e));
+    if scripts.is_empty() {
+        fail("No scripts to soak".to_string());
+    }
+    if !json {
+        println!(
+            "=== NanoForge Soak: {} script(s) for {}s, inputs {:?} ===",
+            scripts.len(),
+            config.duration.as_secs(),
+            config.inputs
+        );
+    }
+
+    install_shutdown();
+    let mut outcomes = Vec::new();
+    let report = soak::run(&scripts, &config, |outcome| {
+        if json {
+            outcomes.push(outcome.clone());
+            return;
+        }
+        let status = if outcome.failures.is_empty() { "ok" } else { "FAILED" };
+        let evolved = outcome.evolved_speedup.map_or("-".to_string(), |s| format!("{:.2}x", s));
+        println!(
+            "round {:3}  {:40} winner {:20} evolved {:>7}  {}",
+            outcome.round,
+            outcome.script,
+            outcome.winner.as_deref().unwrap_or("-"),
+            evolved,
+            status
+        );
+    });
+
+    if json {
+        let out = serde_json::json!({ "scripts": outcomes, "summary": report });
+        println!("{}", serde_json::to_string_pretty(&out).unwrap());
+    } else {
+        println!(
+            "\n{} round(s), {} script run(s) in {:.1}s; JIT memory {} region(s) before, {} after",
+            report.rounds,
+            report.runs,
+            report.elapsed_ms as f64 / 1000.0,
+            report.memory_before.regions,
+            report.memory_after.regions
+        );
+        if shutdown::requested() {
+            println!("⏹️  Interrupted");
+        }
+    }
+    if !report.passed() {
+        let failures: Vec<String> = report.failures.iter().map(|f| f.to_string()).collect();
+        fail(format!("{} failure(s):\n{}", failures.len(), failures.join("\n")));
+    }
+    if !json {
+        println!("✅ Soak passed");
+    }
+}
+
+fn run_learn_passes(
+    path: &str,
+    level: u8,
+    iterations: u32,
+    input: u64,
+    store: Option<&str>,
+    relearn: bool,
+    seed: Option<u64>,
+) {
+    let fail = |e: String| -> ! {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    };
+    println!("=== NanoForge Learn Passes ===");
+    let cpu = CpuFeatures::detect().summary();
+    println!("CPU: {}", cpu);
+    print_seed(seed);
+    let store_path = store.map_or_else(PassScheduleStore::default_path, |p| Path::new(p).to_path_buf());
+    println!("Store: {}\n", store_path.display());
+
+    let mut db = PassScheduleStore::open(&store_path).unwrap_or_else(|e| fail(e));
+    let source = std::fs::read_to_string(path).unwrap_or_else(|e| fail(format!("{}: {}", path, e)));
+    let cached = db
+        .lookup(&source)
+        .filter(|e| !relearn && e.cpu == cpu && e.opt_level == level)
+        .cloned();
+    let entry = match cached {
+        Some(entry) => {
+            println!("Using the schedule stored for this script (--relearn to learn it again)");
+            entry
+        }
+        None => {
+            let program = NanoParser::new()
+                .parse(&source)
+                .unwrap_or_else(|e| fail(format!("Parse error: {}", e)));
+            let options = SoaeOptions::default().input(input).seed(seed);
+            install_shutdown();
+            let report = pass_schedule::learn(&program, &CompileOptions::new(level), iterations, &options)
+                .unwrap_or_else(|e| fail(e));
+            for function in &report.functions {
+                println!("fn {}: {} arm(s)", function.function, function.arms.len());
+                println!("┌──────────────────────────────────┬───────────┬───────────┐");
+                println!("│ Arm                              │ Selections│ Expected  │");
+                println!("├──────────────────────────────────┼───────────┼───────────┤");
+                for (stats, &arm) in function.bandit.get_stats().iter().zip(&function.arms) {
+                    let marker = if arm == function.chosen { " ◀" } else { "" };
+                    println!(
+                        "│ {:32} │ {:9} │ {:9.3} │{}",
+                        stats.name, stats.selections, stats.expected_value, marker
+                    );
+                }
+                println!("└──────────────────────────────────┴───────────┴───────────┘\n");
+            }
+            let entry = PassScheduleEntry {
+                script: path.to_string(),
+                schedule: report.schedule,
+                opt_level: level,
+                cycles_per_op: report.cycles_per_op,
+                baseline_cycles_per_op: report.baseline_cycles_per_op,
+                cpu: cpu.clone(),
+            };
+            db.record(&source, entry.clone());
+            db.save_to_file(&store_path).unwrap_or_else(|e| fail(e));
+            entry
+        }
+    };
+
+    if entry.schedule.disabled.is_empty() {
+        println!("Schedule: every pass in every function");
+    } else {
+        println!("Schedule:");
+        for (function, passes) in &entry.schedule.disabled {
+            let names: Vec<&str> = passes.iter().map(|p| p.name()).collect();
+            println!("  fn {} skips {}", function, names.join(", "));
+        }
+    }
+    println!(
+        "Cycles/op: {} (every pass: {}, {:.2}x)",
+        entry.cycles_per_op,
+        entry.baseline_cycles_per_op,
+        entry.speedup()
+    );
+}
+
+fn run_compare_native(path: &str, kernel: Option<&str>, input: u64) {
+    let fail = |e: String| -> ! {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    };
+    let name = kernel.map_or_else(
+        || Path::new(path).file_stem().map_or(String::new(), |s| s.to_string_lossy().into_owned()),
+        str::to_string,
+    );
+    let Some(kernel) = native::find(&name) else {
+        let known: Vec<&str> = native::KERNELS.iter().map(|k| k.name).collect();
+        fail(format!("No native kernel named '{}'; pick one with --kernel ({})", name, known.join(", ")));
+    };
+
+    println!("=== NanoForge vs rustc: {} ({}) ===", kernel.name, kernel.description);
+    let script = std::fs::read_to_string(path).unwrap_or_else(|e| fail(format!("{}: {}", path, e)));
+    let program = NanoParser::new()
+        .parse(&script)
+        .unwrap_or_else(|e| fail(format!("Parse error: {}", e)));
+    let comparison = native::compare(&program, kernel, &SoaeOptions::default().input(input))
+        .unwrap_or_else(|e| fail(format!("{} does not compute native {}: {}", path, kernel.name, e)));
+    println!("Input: {}   Result: {}\n", comparison.input, comparison.output as i64);
+
+    println!("┌──────────────────────┬────────────────┬────────────┐");
+    println!("│ Implementation       │ Cycles/Op      │ vs rustc   │");
+    println!("├──────────────────────┼────────────────┼────────────┤");
+    println!("│ {:20} │ {:>14} │ {:>10} │", "rustc (native)", comparison.native.cycles_per_op, "1.00x");
+    for ranked in &comparison.rankings {
+        println!(
+            "│ {:20} │ {:>14} │ {:>10} │",
+            ranked.variant_name,
+            ranked.result.cycles_per_op,
+            format!("{:.2}x", comparison.relative_speed(ranked))
+        );
+    }
+    println!("└──────────────────────┴────────────────┴────────────┘");
+    if cfg!(debug_assertions) {
+        println!("⚠️  Debug build: the native kernels are unoptimized; build with --release for -C opt-level=3");
+    }
+    println!(
+        "Best JIT variant runs at {:.0}% of native speed",
+        comparison.best() * 100.0
+    );
 }
 
 /// SOAE with AI-Powered Variant Selection
@@ -533,118 +2155,237 @@ fn run_soae(path: &str) {
 /// 2. Initialize bandit with uniform priors
 /// 3. Each iteration: bandit selects variant → benchmark → update beliefs
 /// 4. Watch as bandit learns which variant is best
-fn run_soae_ai(path: &str, iterations: u32) {
+fn run_soae_ai(
+    path: &str,
+    iterations: u32,
+    seed: Option<u64>,
+    log_path: Option<&str>,
+    record: Option<&str>,
+    cold_start: bool,
+    state: Option<&StateDir>,
+) {
     println!("\n╔══════════════════════════════════════════════════════════════╗");
     println!("║   🧠 NanoForge AI-Powered SOAE with Thompson Sampling 🧠    ║");
     println!("╚══════════════════════════════════════════════════════════════╝\n");
 
-    // Detect CPU features
-    let cpu = CpuFeatures::detect();
-    println!("🖥️  CPU Features: {}", cpu.summary());
-    println!("📊 Learning iterations: {}\n", iterations);
+    println!("📊 Learning iterations: {}", iterations);
+    print_seed(seed);
+    println!();
 
-    // Parse and generate variants
     let script = std::fs::read_to_string(path).expect("Failed to read file");
-    let mut parser = NanoParser::new();
-    let program = parser.parse(&script).expect("Parse failed");
-
-    let generator = VariantGenerator::new();
-    let variants = generator
-        .generate_variants(&program)
-        .expect("Variant generation failed");
-
-    println!("📦 Generated {} variants:", variants.len());
-    let variant_names: Vec<String> = variants.iter().map(|v| v.config.name.clone()).collect();
-    for name in &variant_names {
-        println!("   • {}", name);
+    let program = NanoParser::new().parse(&script).expect("Parse failed");
+    let mut options = SoaeOptions::default()
+        .measurement(20, 100)
+        .seed(seed)
+        .cold_start(cold_start);
+    if let Some(record) = record {
+        println!("📝 Recording measurements to {}", record);
+        options = options.record(record);
     }
-
-    // Create sandbox
-    let sandbox = NanosecondSandbox::new(SandboxConfig {
-        warmup_iterations: 20,
-        measurement_iterations: 100,
-        pin_to_core: Some(0),
+    let mut log = open_brain_log(log_path, "soae-ai");
+    install_shutdown();
+    let report = soae::learn(&program, iterations, &options, &mut log).unwrap_or_else(|e| {
+        eprintln!("Variant generation failed: {}", e);
+        std::process::exit(1);
     });
+    println!("🖥️  CPU Features: {}", report.cpu.summary());
 
-    // Initialize Thompson Sampling bandit
-    let mut bandit = VariantBandit::new(variant_names.clone());
-    let test_input = 1000u64;
-
-    // Pre-benchmark to find true best (for validation)
-    let true_rankings = sandbox.benchmark_all(&variants, test_input);
-    let true_best = true_rankings
-        .first()
-        .map(|r| r.variant_name.clone())
-        .unwrap_or_default();
-    let best_cycles = true_rankings
-        .first()
-        .map(|r| r.result.cycles_per_op)
-        .unwrap_or(1);
-
-    println!("\n🎯 True best variant (ground truth): {}\n", true_best);
-    println!("🎰 Starting Thompson Sampling learning...\n");
-
-    // Learning loop
-    let mut correct_selections = 0u32;
-
-    for i in 1..=iterations {
-        // Bandit selects variant (exploration/exploitation)
-        let selected_idx = bandit.select();
-        let selected_variant = &variants[selected_idx];
-
-        // Benchmark selected variant
-        let result = sandbox.benchmark(selected_variant, test_input);
-
-        // Update bandit with performance reward
-        bandit.update_with_performance(selected_idx, result.cycles_per_op, best_cycles);
+    println!("📦 Generated {} variants:", report.variant_names.len());
+    for name in &report.variant_names {
+        println!("   • {}", name);
+    }
+    if !cold_start {
+        println!("\n🌡️  Priors warm-started from the static cost model");
+    }
 
-        // Track accuracy
-        let is_correct = variant_names[selected_idx] == true_best;
-        if is_correct {
-            correct_selections += 1;
-        }
+    println!("\n🎯 True best variant (ground truth): {}\n", report.true_best);
+    println!("🎰 Thompson Sampling learning...\n");
 
+    let names = &report.variant_names;
+    for (i, step) in report.steps.iter().enumerate() {
+        let i = i as u32 + 1;
         // Progress output (every 10 iterations)
-        if i <= 5 || i % 10 == 0 || i == iterations {
-            let best_guess = bandit.get_best();
-            let accuracy = (correct_selections as f64 / i as f64) * 100.0;
-            let marker = if variant_names[best_guess] == true_best {
+        if i <= 5 || i.is_multiple_of(10) || i == iterations {
+            let marker = if names[step.best_guess] == report.true_best {
                 "✓"
             } else {
                 "✗"
             };
-
             println!(
                 "  Iter {:3}: Selected {:<12} | Best guess: {:<12} {} | Accuracy: {:.1}%",
-                i, &variant_names[selected_idx], &variant_names[best_guess], marker, accuracy
+                i,
+                &names[step.selected],
+                &names[step.best_guess],
+                marker,
+                report.accuracy(i as usize)
             );
         }
     }
 
     // Final results
     println!("\n{}", "═".repeat(64));
-    bandit.print_status();
+    report.bandit.print_status();
 
-    let final_best = bandit.get_best();
-    let converged = variant_names[final_best] == true_best;
-
-    if converged {
-        println!("\n🎉 SUCCESS: Bandit correctly converged to {}!", true_best);
+    if report.converged() {
+        println!("\n🎉 SUCCESS: Bandit correctly converged to {}!", report.true_best);
     } else {
         println!(
             "\n⚠️  Bandit converged to {} (true best: {})",
-            variant_names[final_best], true_best
+            report.final_best(),
+            report.true_best
         );
     }
-
-    // Execute winner
-    let winner_variant = &variants[final_best];
-    let result = winner_variant.execute(test_input);
-    println!("   Result: {}", result);
+    println!("   Result: {}", report.winner_output);
+    report_interrupted(report.steps.len(), iterations);
+    flush_state(
+        state,
+        &format!("brain-{}.json", shutdown::script_key(path, &script)),
+        VariantBandit::SCHEMA,
+        &report.bandit,
+    );
 
     println!("\n✅ AI-Powered SOAE Complete!\n");
 }
 
+/// Note a learning run that a shutdown request cut short
+fn report_interrupted(done: usize, planned: u32) {
+    if done < planned as usize {
+        println!("⏹️  Interrupted after {} of {} iterations", done, planned);
+    }
+}
+
+/// Open the `--log` file, exiting if it can't be created
+fn run_brain_export(path: &str, format: &str, output: Option<&str>) {
+    let fail = |e: String| -> ! {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    };
+    let report = BrainReport::load(Path::new(path)).unwrap_or_else(|e| fail(e));
+    let text = match format {
+        "csv" => report.to_csv(),
+        _ => report.to_markdown(),
+    };
+    match output {
+        Some(out) => {
+            std::fs::write(out, text).unwrap_or_else(|e| fail(format!("{}: {}", out, e)));
+            println!("Wrote {}", out);
+        }
+        None => print!("{}", text),
+    }
+}
+
+fn run_brain_import(path: &str) {
+    let fail = |e: String| -> ! {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    };
+    let csv = std::fs::read_to_string(path).unwrap_or_else(|e| fail(format!("{}: {}", path, e)));
+    let table = DispatchTable::from_csv(&csv).unwrap_or_else(|e| fail(format!("{}: {}", path, e)));
+    println!("Dispatch table from {}:", path);
+    for bucket in SizeBucket::all() {
+        println!("  {:16} → {}", bucket.name(), table.winner(bucket).unwrap_or("(learned at runtime)"));
+    }
+}
+
+fn run_state_show(dir: &Path) {
+    let files = store::list(dir).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    if files.is_empty() {
+        println!("No state in {}", dir.display());
+        return;
+    }
+    println!("State in {}:", dir.display());
+    for file in &files {
+        println!("  {:32} {:>9} B  {}", file.name, file.bytes, file.status);
+    }
+    if files.iter().any(|f| matches!(f.status, store::FileStatus::Damaged(_))) {
+        std::process::exit(1);
+    }
+}
+
+fn run_state_clear(dir: &Path, kind: Option<&str>) {
+    let removed = store::clear(dir, kind).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    for path in &removed {
+        println!("Removed {}", path.display());
+    }
+    println!("Cleared {} file(s) from {}", removed.len(), dir.display());
+}
+
+fn open_brain_log(path: Option<&str>, source: &str) -> Option<BrainLog> {
+    let path = path?;
+    match BrainLog::append(Path::new(path), source) {
+        Ok(log) => {
+            println!("📝 Logging bandit updates to {}", path);
+            Some(log)
+        }
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn run_analyze(path: &str, windows: usize) {
+    let events = match nanoforge::brain_log::read_log(Path::new(path)) {
+        Ok(events) => events,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    if events.is_empty() {
+        println!("No bandit updates in {}", path);
+        return;
+    }
+    nanoforge::brain_log::analyze(&events, windows).print();
+}
+
+fn run_simulate(path: &str, policies: &[Policy], iterations: u32, runs: usize, seed: u64) {
+    let table = MeasurementTable::load(Path::new(path)).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    println!(
+        "🎲 Replaying {} rounds x {} runs over {} variants at {} input size(s) from {}",
+        iterations,
+        runs,
+        table.variant_names.len(),
+        table.samples.len(),
+        path
+    );
+    if table.incomplete_inputs > 0 {
+        warn!("Skipped {} input(s) missing some variant's measurements", table.incomplete_inputs);
+    }
+    let summaries: Vec<_> = policies
+        .iter()
+        .map(|&policy| simulation::compare(&table, policy, iterations, runs, seed))
+        .collect();
+    if let [only] = summaries.as_slice() {
+        only.first_run.print();
+    }
+
+    println!("\n📊 Policies ({} runs each):", runs.max(1));
+    println!("┌──────────────────────────┬────────┬─────────────────────┬──────────┐");
+    println!("│ Policy                   │ Reward │ Regret (mean ± sd)  │ Late Hit │");
+    println!("├──────────────────────────┼────────┼─────────────────────┼──────────┤");
+    for s in &summaries {
+        println!(
+            "│ {:24} │ {:6.3} │ {:9.2} ± {:7.2} │ {:7.1}% │",
+            s.policy.to_string(),
+            s.mean_reward,
+            s.mean_regret,
+            s.regret_std_dev,
+            s.late_best_rate * 100.0
+        );
+    }
+    println!("└──────────────────────────┴────────┴─────────────────────┴──────────┘");
+}
+
 /// SOAE with Contextual Bandit - Learns Decision Boundaries
 ///
 /// This is the KEY DEMO that shows context-aware learning:
@@ -652,112 +2393,83 @@ fn run_soae_ai(path: &str, iterations: u32) {
 /// - Learns that small inputs → Scalar is better
 /// - Learns that large inputs → AVX2 is better
 /// - Displays the learned decision boundary!
-fn run_soae_context(path: &str, iterations: u32) {
-    use rand::Rng;
-
+fn run_soae_context(
+    path: &str,
+    iterations: u32,
+    seed: Option<u64>,
+    log_path: Option<&str>,
+    record: Option<&str>,
+    cold_start: bool,
+    state: Option<&StateDir>,
+) {
     println!("\n╔══════════════════════════════════════════════════════════════╗");
     println!("║  🧠 CONTEXTUAL BANDIT - Learning Decision Boundaries! 🧠   ║");
     println!("╚══════════════════════════════════════════════════════════════╝\n");
 
-    // Detect CPU features
-    let cpu = CpuFeatures::detect();
-    println!("🖥️  CPU Features: {}", cpu.summary());
     println!(
-        "📊 Learning iterations: {} (with variable input sizes)\n",
+        "📊 Learning iterations: {} (with variable input sizes)",
         iterations
     );
+    print_seed(seed);
+    println!();
 
-    // Parse and generate variants
     let script = std::fs::read_to_string(path).expect("Failed to read file");
-    let mut parser = NanoParser::new();
-    let program = parser.parse(&script).expect("Parse failed");
-
-    let generator = VariantGenerator::new();
-    let variants = generator
-        .generate_variants(&program)
-        .expect("Variant generation failed");
-
-    let variant_names: Vec<String> = variants.iter().map(|v| v.config.name.clone()).collect();
-    println!("📦 Generated {} variants:", variants.len());
-    for name in &variant_names {
+    let program = NanoParser::new().parse(&script).expect("Parse failed");
+    let mut options = SoaeOptions::default()
+        .measurement(10, 50)
+        .seed(seed)
+        .cold_start(cold_start);
+    if let Some(record) = record {
+        println!("📝 Recording measurements to {}", record);
+        options = options.record(record);
+    }
+    let mut log = open_brain_log(log_path, "soae-context");
+    install_shutdown();
+    let report = soae::learn_contextual(&program, iterations, &options, &mut log)
+        .unwrap_or_else(|e| {
+            eprintln!("Variant generation failed: {}", e);
+            std::process::exit(1);
+        });
+    println!("🖥️  CPU Features: {}", report.cpu.summary());
+
+    println!("📦 Generated {} variants:", report.variant_names.len());
+    for name in &report.variant_names {
         println!("   • {}", name);
     }
+    if !cold_start {
+        println!("\n🌡️  Priors warm-started from the static cost model");
+    }
 
-    // Create sandbox
-    let sandbox = NanosecondSandbox::new(SandboxConfig {
-        warmup_iterations: 10,
-        measurement_iterations: 50,
-        pin_to_core: Some(0),
-    });
-
-    // Initialize CONTEXTUAL bandit (one per size bucket!)
-    let mut bandit = ContextualBandit::new(variant_names.clone());
-
-    println!("\n🎰 Starting Contextual Learning with Variable Input Sizes...\n");
-    println!("   The AI will see different input sizes and learn which");
+    println!("\n🎰 Contextual Learning with Variable Input Sizes...\n");
+    println!("   The AI saw different input sizes and learned which");
     println!("   variant works best for each size bucket!\n");
 
-    // Test sizes for each bucket
-    let test_sizes: Vec<u64> = vec![
-        10, 20, // Tiny
-        50, 100, 200, // Small
-        500, 1000, 2000, // Medium
-        5000, 10000,  // Large
-        100000, // Huge
-    ];
-
-    let mut rng = rand::thread_rng();
-
-    // Learning loop with varying input sizes
-    for i in 1..=iterations {
-        // Randomly pick an input size
-        let input_size = test_sizes[rng.gen_range(0..test_sizes.len())];
-        let context = OptimizationFeatures::new(input_size);
-        let bucket = context.size_bucket();
-
-        // Contextual bandit selects based on bucket
-        let selected_idx = bandit.select(&context);
-        let selected_variant = &variants[selected_idx];
-
-        // Benchmark this variant with this input size
-        let result = sandbox.benchmark(selected_variant, input_size);
-
-        // Find the actual best for this size (to compute reward)
-        let rankings = sandbox.benchmark_all(&variants, input_size);
-        let best_cycles = rankings
-            .first()
-            .map(|r| r.result.cycles_per_op)
-            .unwrap_or(1);
-
-        // Update bandit with performance in this context
-        bandit.update_with_performance(&context, selected_idx, result.cycles_per_op, best_cycles);
-
-        // Progress output
-        if i <= 10 || i % 20 == 0 || i == iterations {
+    for (i, step) in report.steps.iter().enumerate() {
+        let i = i as u32 + 1;
+        if i <= 10 || i.is_multiple_of(20) || i == iterations {
             println!(
                 "  Iter {:3}: N={:6} ({:12}) → Selected {}",
                 i,
-                input_size,
-                bucket.name(),
-                &variant_names[selected_idx]
+                step.input,
+                step.bucket().name(),
+                &report.variant_names[step.selected]
             );
         }
     }
 
     // Display the learned decision boundary!
     println!("\n{}", "═".repeat(64));
-    bandit.print_decision_boundary();
+    report.bandit.print_decision_boundary();
 
     // Show detailed stats
-    bandit.print_full_status();
+    report.bandit.print_full_status();
 
     // Summary analysis
     println!("\n📋 Analysis:");
-    let decisions = bandit.get_decision_boundary();
     let mut scalar_wins = 0;
     let mut avx_wins = 0;
 
-    for (bucket, variant, _) in &decisions {
+    for (bucket, variant, _) in &report.decision_boundary() {
         let is_scalar = variant.starts_with("Scalar");
         if is_scalar {
             scalar_wins += 1;
@@ -779,10 +2491,32 @@ fn run_soae_context(path: &str, iterations: u32) {
         "\n   Decision Summary: Scalar wins {} buckets, AVX2 wins {} buckets",
         scalar_wins, avx_wins
     );
+    report_interrupted(report.steps.len(), iterations);
+    flush_state(
+        state,
+        &format!("context-brain-{}.json", shutdown::script_key(path, &script)),
+        ContextualBandit::SCHEMA,
+        &report.bandit,
+    );
 
     println!("\n✅ Contextual Bandit Learning Complete!\n");
 }
 
+/// How `evolve` searches, and where it writes the winner
+#[derive(Clone, Copy)]
+struct EvolveRun<'a> {
+    generations: u32,
+    population_size: usize,
+    /// Stop once the best genome reaches this speedup over the seed
+    target: Option<f64>,
+    /// RNG seed (None: a fixed default)
+    seed: Option<u64>,
+    /// Evolve x86 machine code instead of IR
+    machine_code: bool,
+    /// Write the winning program here, as JSON
+    emit_winner: Option<&'a str>,
+}
+
 /// 🧬 EVOLVE: Genetic Algorithm Code Evolution
 ///
 /// This demonstrates self-evolving code:
@@ -791,10 +2525,19 @@ fn run_soae_context(path: &str, iterations: u32) {
 /// 3. Create population of mutated variants
 /// 4. Evolve through selection, crossover, mutation
 /// 5. Watch code get faster while maintaining correctness!
-fn run_evolve(path: &str, generations: u32, population_size: usize, target: Option<f64>) {
-    use nanoforge::evolution::{EvolutionConfig, EvolutionEngine};
+fn run_evolve(path: &str, run: &EvolveRun, state: Option<&StateDir>) {
+    use nanoforge::evolution::{EvolutionCheckpoint, EvolutionConfig, EvolutionEngine};
     use nanoforge::validator::TestCase;
 
+    let EvolveRun {
+        generations,
+        population_size,
+        target,
+        seed,
+        machine_code,
+        emit_winner,
+    } = *run;
+
     println!("\n╔══════════════════════════════════════════════════════════════╗");
     println!("║     🧬 NanoForge Self-Evolving JIT (Genetic Algorithm) 🧬    ║");
     println!("╚══════════════════════════════════════════════════════════════╝\n");
@@ -821,15 +2564,11 @@ fn run_evolve(path: &str, generations: u32, population_size: usize, target: Opti
     println!("🧪 Generating Ground Truth from Seed Code...");
 
     // Compile seed to run it
-    let (code, main_offset) =
-        Compiler::compile_program(&program, 0).expect("Failed to compile seed for ground truth");
-
-    let memory = DualMappedMemory::new(code.len() + 4096).expect("Memory alloc failed");
-    CodeGenerator::emit_to_memory(&memory, &code, 0);
-
-    // Cast to function pointer
-    let func_ptr: extern "C" fn(i64) -> i64 =
-        unsafe { std::mem::transmute(memory.rx_ptr.add(main_offset)) };
+    let compiled = CompiledProgram::compile(&program, &CompileOptions::new(0))
+        .expect("Failed to compile seed for ground truth");
+    let func_ptr: extern "C" fn(i64) -> i64 = compiled
+        .get("main")
+        .expect("main() must take at most one parameter");
 
     // inputs to test
     let inputs = vec![10, 100, 1000];
@@ -850,7 +2589,7 @@ fn run_evolve(path: &str, generations: u32, population_size: usize, target: Opti
             }
         }
     }
-    println!("");
+    println!();
 
     // Configure evolution
     let config = EvolutionConfig {
@@ -859,29 +2598,170 @@ fn run_evolve(path: &str, generations: u32, population_size: usize, target: Opti
         crossover_rate: 0.7,
         tournament_size: 5,
         elite_count: 2,
-        seed: 42,
+        seed: seed.unwrap_or(42),
+        ..EvolutionConfig::default()
     };
 
     println!("⚙️  Evolution Config:");
     println!("   Population: {}", config.population_size);
     println!("   Generations: {}", generations);
     println!("   Mutation rate: {:.0}%", config.mutation_rate * 100.0);
+    println!("   Seed: {}", config.seed);
     println!(
         "   Target speedup: {}",
         target.map_or("None".to_string(), |t| format!("{:.2}x", t))
     );
 
+    install_shutdown();
+    let checkpoint = format!("evolution-{}.json", shutdown::script_key(path, &script));
+    if machine_code {
+        let result = run_machine_evolution(seed_function, test_cases, config, generations, target);
+        flush_state(state, &checkpoint, EvolutionCheckpoint::SCHEMA, &result.checkpoint());
+        return;
+    }
+
     // Create evolution engine
-    let mut engine = EvolutionEngine::new(seed_function, test_cases, config);
+    let mut engine = EvolutionEngine::new(seed_function, test_cases.clone(), config);
+
+    println!("\n🧬 Starting Evolution...\n");
+    println!("┌──────┬────────────────┬────────────────┬────────────────┬────────────────┐");
+    println!("│ Gen  │ Best Fitness   │ Valid/Pop      │ Speedup        │ Diversity      │");
+    println!("├──────┼────────────────┼────────────────┼────────────────┼────────────────┤");
+
+    // Run evolution
+    let result = engine.run(generations, target);
+
+    for gen in &result.history {
+        let fitness = if gen.valid_count > 0 {
+            format!("{:.0} cyc", gen.best_fitness)
+        } else {
+            "-".to_string()
+        };
+        println!(
+            "│ {:4} │ {:>14} │ {:>14} │ {:>14} │ {:>14} │",
+            gen.generation,
+            fitness,
+            format!("{}/{}", gen.valid_count, population_size),
+            format!("{:.2}x", gen.speedup_vs_baseline),
+            format!("{:.2}{}", gen.diversity, if gen.restarted { " ↻" } else { "" })
+        );
+    }
+    println!("└──────┴────────────────┴────────────────┴────────────────┴────────────────┘");
+    if result.restarts > 0 {
+        println!("↻ {} partial restart(s) after stagnation", result.restarts);
+    }
+    if shutdown::requested() {
+        println!("⏹️  Interrupted after generation {}", result.generations_run);
+    }
+    flush_state(state, &checkpoint, EvolutionCheckpoint::SCHEMA, &result.checkpoint());
+
+    // Re-verify the winner independently before reporting it. Fitness was
+    // measured once during evolution; never advertise code that fails now.
+    let verdict = result.verify_winner(&test_cases);
+    let kept = if verdict.is_valid() {
+        println!(
+            "\n🏆 Winner: generation {}, {} instructions, {:.2}x speedup (re-verified on {} test cases)",
+            result.best_genome.generation,
+            result.best_genome.len(),
+            result.final_speedup,
+            test_cases.len()
+        );
+        result.best_genome.to_function()
+    } else {
+        println!(
+            "\n⚠️  Best genome failed re-verification ({:?}); keeping seed code",
+            verdict
+        );
+        seed_function.clone()
+    };
+    if let Some(out) = emit_winner {
+        let mut winner = nanoforge::ir::Program::new();
+        winner.add_function(kept);
+        let json = serde_json::to_string_pretty(&winner).expect("IR serializes");
+        if let Err(e) = std::fs::write(out, json) {
+            eprintln!("❌ Failed to write {}: {}", out, e);
+            std::process::exit(1);
+        }
+        println!("📝 Wrote {}", out);
+    }
+
+    println!("\n✅ Evolution Complete.\n");
+}
+
+fn run_machine_evolution(
+    seed_function: &nanoforge::ir::Function,
+    test_cases: Vec<nanoforge::validator::TestCase>,
+    config: nanoforge::evolution::EvolutionConfig,
+    generations: u32,
+    target: Option<f64>,
+) -> nanoforge::machine_genome::MachineEvolutionResult {
+    use nanoforge::isolated_runner::run_isolated;
+    use nanoforge::machine_genome::{check_fence, MachineEvolution};
+
+    if !cfg!(target_arch = "x86_64") {
+        eprintln!("❌ --machine-code evolves x86-64 instructions and needs an x86-64 host");
+        std::process::exit(1);
+    }
+    println!("\n⚠️  EXPERIMENTAL: evolving raw machine code (fenced, validated in a subprocess)");
+
+    let population_size = config.population_size;
+    let mut engine = match MachineEvolution::new(seed_function, test_cases.clone(), config) {
+        Ok(engine) => engine,
+        Err(e) => {
+            eprintln!("❌ Cannot evolve {} as machine code: {}", seed_function.name, e);
+            std::process::exit(1);
+        }
+    };
 
     println!("\n🧬 Starting Evolution...\n");
     println!("┌──────┬────────────────┬────────────────┬────────────────┐");
     println!("│ Gen  │ Best Fitness   │ Valid/Pop      │ Speedup        │");
     println!("├──────┼────────────────┼────────────────┼────────────────┤");
 
-    // Run evolution
-    engine.run(generations, target);
+    let result = engine.run(generations, target);
 
+    for gen in &result.history {
+        let fitness = if gen.valid_count > 0 {
+            format!("{:.0} cyc", gen.best_fitness)
+        } else {
+            "-".to_string()
+        };
+        println!(
+            "│ {:4} │ {:>14} │ {:>14} │ {:>14} │",
+            gen.generation,
+            fitness,
+            format!("{}/{}", gen.valid_count, population_size),
+            format!("{:.2}x", gen.speedup_vs_baseline)
+        );
+    }
     println!("└──────┴────────────────┴────────────────┴────────────────┘");
+    if shutdown::requested() {
+        println!("⏹️  Interrupted after generation {}", result.generations_run);
+    }
+
+    // Re-verify the winner in a fresh child before reporting it
+    let code = result.best_genome.encode();
+    let inputs: Vec<i64> = test_cases.iter().map(|tc| tc.input).collect();
+    let verified = check_fence(&code).is_ok()
+        && run_isolated(&code, &inputs, std::time::Duration::from_secs(1)).is_ok_and(|outputs| {
+            outputs
+                .iter()
+                .zip(&test_cases)
+                .all(|(&out, tc)| out == tc.expected_output)
+        });
+    if verified {
+        println!(
+            "\n🏆 Winner: generation {}, {} instructions, {:.2}x speedup (re-verified on {} test cases)",
+            result.best_genome.generation,
+            result.best_genome.len() + 1,
+            result.final_speedup,
+            test_cases.len()
+        );
+        print!("{}", nanoforge::disasm::disassemble(&code));
+    } else {
+        println!("\n⚠️  Best genome failed re-verification; keeping seed code");
+    }
+
     println!("\n✅ Evolution Complete.\n");
+    result
 }
@@ -0,0 +1,579 @@
+//! Memory-Access Sampling
+//!
+//! `perf mem` / `perf c2c` for one call into JIT code: a `MemSampler` samples
+//! retired loads and stores while the call runs, and `MemReport` charges each
+//! sample to the IR instruction whose code it hit (`CompiledCode::source_map`)
+//! with the cache level that served it. "AVX2x4 is slower here" becomes
+//! "its load on line 8 goes to RAM for 60% of samples".
+//!
+//! On Intel the samples are precise (PEBS load-latency and store events): the
+//! kernel reports the exact instruction, the level that served the access,
+//! its latency in cycles and whether it hit a line modified by another core
+//! (HITM, the false-sharing signal `perf c2c` looks for). Elsewhere the
+//! sampler falls back to L1D read misses, which skid and carry no level.
+
+use crate::compiled_program::CompiledProgram;
+use crate::compiler::CompiledCode;
+use crate::profiler::{perf_event_open, PerfEventAttr};
+use libc::c_int;
+use nanoforge_core::target::cpu_identity;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{fence, Ordering};
+
+/// Sample one access in this many by default
+pub const DEFAULT_PERIOD: u64 = 1000;
+
+/// Data pages of each sample ring (a power of two)
+const RING_PAGES: usize = 64;
+
+const PERF_TYPE_RAW: u32 = 4;
+const PERF_TYPE_HW_CACHE: u32 = 3;
+// L1D | (read << 8) | (miss << 16)
+const L1D_READ_MISS: u64 = 1 << 16;
+// MEM_TRANS_RETIRED.LOAD_LATENCY and MEM_INST_RETIRED.ALL_STORES, the events
+// behind `perf mem` on Intel
+const INTEL_LOAD_LATENCY: u64 = 0x1cd;
+const INTEL_ALL_STORES: u64 = 0x82d0;
+/// Loads faster than this many cycles are not sampled
+const LOAD_LATENCY_THRESHOLD: u64 = 3;
+
+// attr.sample_type bits
+const SAMPLE_IP: u64 = 1 << 0;
+const SAMPLE_ADDR: u64 = 1 << 3;
+const SAMPLE_WEIGHT: u64 = 1 << 14;
+const SAMPLE_DATA_SRC: u64 = 1 << 15;
+
+// attr.flags bits
+const FLAG_DISABLED: u64 = 1 << 0;
+const FLAG_EXCLUDE_KERNEL: u64 = 1 << 5;
+const FLAG_EXCLUDE_HV: u64 = 1 << 6;
+/// precise_ip = 2: the sampled IP is the instruction that did the access
+const FLAG_PRECISE_IP: u64 = 2 << 15;
+
+const PERF_RECORD_LOST: u32 = 2;
+const PERF_RECORD_SAMPLE: u32 = 9;
+
+// Fields of perf_mem_data_src
+const MEM_OP_STORE: u64 = 0x04;
+const MEM_LVL_SHIFT: u64 = 5;
+const MEM_LVL_L1: u64 = 0x08;
+const MEM_LVL_LFB: u64 = 0x10;
+const MEM_LVL_L2: u64 = 0x20;
+const MEM_LVL_L3: u64 = 0x40;
+/// Local and remote DRAM, remote caches
+const MEM_LVL_BEYOND_L3: u64 = 0xf80;
+const MEM_SNOOP_SHIFT: u64 = 19;
+const MEM_SNOOP_HITM: u64 = 0x10;
+
+/// Where a sampled access was served from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum MemLevel {
+    L1,
+    /// Line fill buffer: a miss to a line already on its way in
+    Lfb,
+    L2,
+    L3,
+    /// DRAM, or a remote socket's cache or memory
+    Ram,
+    /// The PMU didn't say (imprecise fallback)
+    Unknown,
+}
+
+impl MemLevel {
+    pub const ALL: [MemLevel; 6] = [
+        MemLevel::L1,
+        MemLevel::Lfb,
+        MemLevel::L2,
+        MemLevel::L3,
+        MemLevel::Ram,
+        MemLevel::Unknown,
+    ];
+
+    /// Level of a perf_mem_data_src value
+    pub fn from_data_src(data_src: u64) -> Self {
+        let lvl = data_src >> MEM_LVL_SHIFT;
+        if lvl & MEM_LVL_BEYOND_L3 != 0 {
+            MemLevel::Ram
+        } else if lvl & MEM_LVL_L3 != 0 {
+            MemLevel::L3
+        } else if lvl & MEM_LVL_L2 != 0 {
+            MemLevel::L2
+        } else if lvl & MEM_LVL_LFB != 0 {
+            MemLevel::Lfb
+        } else if lvl & MEM_LVL_L1 != 0 {
+            MemLevel::L1
+        } else {
+            MemLevel::Unknown
+        }
+    }
+}
+
+impl fmt::Display for MemLevel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            MemLevel::L1 => "L1",
+            MemLevel::Lfb => "LFB",
+            MemLevel::L2 => "L2",
+            MemLevel::L3 => "L3",
+            MemLevel::Ram => "RAM",
+            MemLevel::Unknown => "?",
+        })
+    }
+}
+
+/// One sampled load or store
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemSample {
+    pub ip: u64,
+    /// Data address (0 if the PMU didn't report one)
+    pub addr: u64,
+    /// Cycles the access took (loads only; 0 if unknown)
+    pub latency: u64,
+    pub level: MemLevel,
+    pub store: bool,
+    /// Hit a line another core had modified
+    pub hitm: bool,
+}
+
+impl MemSample {
+    /// A sample as the kernel reports it
+    pub fn decode(ip: u64, addr: u64, weight: u64, data_src: u64) -> Self {
+        Self {
+            ip,
+            addr,
+            latency: weight,
+            level: MemLevel::from_data_src(data_src),
+            store: data_src & MEM_OP_STORE != 0,
+            hitm: (data_src >> MEM_SNOOP_SHIFT) & MEM_SNOOP_HITM != 0,
+        }
+    }
+}
+
+/// One sampling event and the ring the kernel writes its samples to
+struct SampledEvent {
+    fd: c_int,
+    ring: *mut u8,
+    len: usize,
+}
+
+impl SampledEvent {
+    fn open(
+        type_: u32,
+        config: u64,
+        config1: u64,
+        period: u64,
+        precise: bool,
+    ) -> Result<Self, String> {
+        let mut attr: PerfEventAttr = unsafe { std::mem::zeroed() };
+        attr.type_ = type_;
+        attr.size = std::mem::size_of::<PerfEventAttr>() as u32;
+        attr.config = config;
+        // config1 shares its slot with bp_addr
+        attr.bp_addr = config1;
+        attr.sample_period = period;
+        attr.sample_type = SAMPLE_IP | SAMPLE_ADDR | SAMPLE_WEIGHT | SAMPLE_DATA_SRC;
+        attr.flags = FLAG_DISABLED | FLAG_EXCLUDE_KERNEL | FLAG_EXCLUDE_HV;
+        if precise {
+            attr.flags |= FLAG_PRECISE_IP;
+        }
+        let fd = perf_event_open(&attr, 0, -1)?;
+
+        let len = (RING_PAGES + 1) * page_size();
+        let ring = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                fd,
+                0,
+            )
+        };
+        if ring == libc::MAP_FAILED {
+            unsafe { libc::close(fd) };
+            let error = std::io::Error::last_os_error();
+            return Err(format!("mmap of the sample ring failed: {}", error));
+        }
+        Ok(Self {
+            fd,
+            ring: ring as *mut u8,
+            len,
+        })
+    }
+
+    fn ioctl(&self, request: u64) {
+        unsafe { libc::ioctl(self.fd, request as _, 0) };
+    }
+
+    /// Samples written since the last drain, and the count of samples the
+    /// kernel dropped because the ring was full
+    fn drain(&self, samples: &mut Vec<MemSample>) -> u64 {
+        let page = page_size();
+        let size = (self.len - page) as u64;
+        let data = unsafe { self.ring.add(page) };
+        // perf_event_mmap_page: data_head at 1024, data_tail at 1032
+        let head_ptr = unsafe { self.ring.add(1024) as *const u64 };
+        let tail_ptr = unsafe { self.ring.add(1032) as *mut u64 };
+        let head = unsafe { std::ptr::read_volatile(head_ptr) };
+        fence(Ordering::Acquire);
+        let mut tail = unsafe { std::ptr::read_volatile(tail_ptr) };
+
+        let read = |at: u64, buf: &mut [u8]| {
+            for (i, byte) in buf.iter_mut().enumerate() {
+                *byte = unsafe { *data.add(((at + i as u64) % size) as usize) };
+            }
+        };
+        let mut lost = 0;
+        while tail < head {
+            // perf_event_header: type u32, misc u16, size u16
+            let mut header = [0u8; 8];
+            read(tail, &mut header);
+            let kind = u32::from_ne_bytes(header[0..4].try_into().unwrap());
+            let len = u16::from_ne_bytes(header[6..8].try_into().unwrap()) as u64;
+            if len < 8 {
+                break;
+            }
+            let mut body = vec![0u8; len as usize - 8];
+            read(tail + 8, &mut body);
+            let field = |i: usize| {
+                body.get(i * 8..i * 8 + 8)
+                    .map_or(0, |b| u64::from_ne_bytes(b.try_into().unwrap()))
+            };
+            match kind {
+                // ip, addr, weight, data_src: the order of sample_type bits
+                PERF_RECORD_SAMPLE => {
+                    samples.push(MemSample::decode(field(0), field(1), field(2), field(3)))
+                }
+                // id, lost
+                PERF_RECORD_LOST => lost += field(1),
+                _ => {}
+            }
+            tail += len;
+        }
+        fence(Ordering::Release);
+        unsafe { std::ptr::write_volatile(tail_ptr, tail) };
+        lost
+    }
+}
+
+impl Drop for SampledEvent {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ring as *mut _, self.len);
+            libc::close(self.fd);
+        }
+    }
+}
+
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+/// Samples the loads and stores of the current thread
+pub struct MemSampler {
+    events: Vec<SampledEvent>,
+    precise: bool,
+}
+
+impl MemSampler {
+    /// Sample one access in `period`. Fails if the PMU offers no memory
+    /// event to sample (VMs, perf_event_paranoid > 2).
+    pub fn new(period: u64) -> Result<Self, String> {
+        let intel = cpu_identity().is_some_and(|(vendor, _)| &vendor == b"GenuineIntel");
+        if intel {
+            let loads = SampledEvent::open(
+                PERF_TYPE_RAW,
+                INTEL_LOAD_LATENCY,
+                LOAD_LATENCY_THRESHOLD,
+                period,
+                true,
+            );
+            if let Ok(loads) = loads {
+                let stores = SampledEvent::open(PERF_TYPE_RAW, INTEL_ALL_STORES, 0, period, true);
+                return Ok(Self {
+                    events: std::iter::once(loads).chain(stores.ok()).collect(),
+                    precise: true,
+                });
+            }
+        }
+        let misses = SampledEvent::open(PERF_TYPE_HW_CACHE, L1D_READ_MISS, 0, period, false)?;
+        Ok(Self {
+            events: vec![misses],
+            precise: false,
+        })
+    }
+
+    /// Whether samples name the exact instruction and the level that
+    /// served it
+    pub fn precise(&self) -> bool {
+        self.precise
+    }
+
+    /// Run `f` with sampling on; returns its result, the samples and the
+    /// number of samples lost to a full ring
+    pub fn measure<R>(&self, f: impl FnOnce() -> R) -> (R, Vec<MemSample>, u64) {
+        const PERF_EVENT_IOC_ENABLE: u64 = 0x2400;
+        const PERF_EVENT_IOC_DISABLE: u64 = 0x2401;
+        const PERF_EVENT_IOC_RESET: u64 = 0x2403;
+        let mut samples = Vec::new();
+        for event in &self.events {
+            // Whatever an earlier `measure` left behind
+            event.drain(&mut samples);
+            event.ioctl(PERF_EVENT_IOC_RESET);
+            event.ioctl(PERF_EVENT_IOC_ENABLE);
+        }
+        let result = f();
+        for event in &self.events {
+            event.ioctl(PERF_EVENT_IOC_DISABLE);
+        }
+        samples.clear();
+        let lost = self.events.iter().map(|e| e.drain(&mut samples)).sum();
+        (result, samples, lost)
+    }
+
+    /// `measure` a call into `program`, attributing the samples to its IR
+    pub fn trace<R>(&self, program: &CompiledProgram, f: impl FnOnce() -> R) -> (R, MemReport) {
+        let (result, samples, lost) = self.measure(f);
+        let mut report = MemReport::attribute(program.code(), program.address(), &samples);
+        report.precise = self.precise;
+        report.lost = lost;
+        (result, report)
+    }
+}
+
+/// Sampled accesses of one IR instruction
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccessSite {
+    pub function: String,
+    /// Index into the function's optimized instructions
+    pub index: usize,
+    pub instruction: String,
+    /// Source line (0: none)
+    pub line: usize,
+    pub samples: u64,
+    pub stores: u64,
+    pub hitm: u64,
+    pub levels: BTreeMap<MemLevel, u64>,
+    /// Summed latency of the samples that reported one
+    pub latency: u64,
+}
+
+impl AccessSite {
+    /// Share of this site's samples served by `level`
+    pub fn share(&self, level: MemLevel) -> f64 {
+        let count = self.levels.get(&level).copied().unwrap_or(0);
+        if self.samples == 0 {
+            return 0.0;
+        }
+        count as f64 / self.samples as f64
+    }
+
+    /// Samples served from beyond L1 (everything but L1 hits, when the
+    /// level is known)
+    pub fn misses(&self) -> u64 {
+        let hits = self.levels.get(&MemLevel::L1).copied().unwrap_or(0);
+        self.samples - hits
+    }
+}
+
+/// Sampled accesses of one run, by IR instruction
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MemReport {
+    /// Samples came from precise (PEBS) events
+    pub precise: bool,
+    /// Most sampled first
+    pub sites: Vec<AccessSite>,
+    /// Samples outside the compiled instructions (runtime helpers,
+    /// prologues, host code)
+    pub elsewhere: u64,
+    /// Samples dropped because the ring was full
+    pub lost: u64,
+}
+
+impl MemReport {
+    /// Charge `samples` to the instructions of `code`, mapped at `base`
+    pub fn attribute(code: &CompiledCode, base: usize, samples: &[MemSample]) -> Self {
+        let mut sites: BTreeMap<usize, AccessSite> = BTreeMap::new();
+        let mut elsewhere = 0;
+        for sample in samples {
+            let offset = (sample.ip as usize).wrapping_sub(base);
+            let Some((at, mapped)) = code
+                .source_map
+                .iter()
+                .enumerate()
+                .find(|(_, m)| m.code.contains(&offset))
+            else {
+                elsewhere += 1;
+                continue;
+            };
+            let site = sites.entry(at).or_insert_with(|| AccessSite {
+                function: mapped.function.clone(),
+                index: mapped.index,
+                instruction: mapped.instruction.clone(),
+                line: mapped.line,
+                samples: 0,
+                stores: 0,
+                hitm: 0,
+                levels: BTreeMap::new(),
+                latency: 0,
+            });
+            site.samples += 1;
+            site.stores += sample.store as u64;
+            site.hitm += sample.hitm as u64;
+            *site.levels.entry(sample.level).or_insert(0) += 1;
+            site.latency += sample.latency;
+        }
+        let mut sites: Vec<AccessSite> = sites.into_values().collect();
+        sites.sort_by_key(|s| std::cmp::Reverse(s.samples));
+        Self {
+            precise: false,
+            sites,
+            elsewhere,
+            lost: 0,
+        }
+    }
+
+    pub fn samples(&self) -> u64 {
+        self.sites.iter().map(|s| s.samples).sum::<u64>() + self.elsewhere
+    }
+
+    /// The site with the most samples served beyond L1
+    pub fn worst(&self) -> Option<&AccessSite> {
+        self.sites.iter().filter(|s| s.misses() > 0).max_by_key(|s| s.misses())
+    }
+
+    /// `perf mem report`-style table, one row per sampled instruction
+    pub fn summary(&self) -> String {
+        let kind = if self.precise { "precise" } else { "L1D misses, imprecise" };
+        let mut out = format!("Memory access samples ({}): {}\n", kind, self.samples());
+        out.push_str(&format!("  {:<20} {:>5} {:<28} {:>7}", "instruction", "line", "", "samples"));
+        for level in MemLevel::ALL {
+            out.push_str(&format!(" {:>5}", level.to_string()));
+        }
+        out.push_str(&format!(" {:>6} {:>5}\n", "lat", "HITM"));
+        for site in &self.sites {
+            let at = format!("{}:{}", site.function, site.index);
+            let mut instruction = site.instruction.clone();
+            if instruction.len() > 28 {
+                instruction.truncate(27);
+                instruction.push('~');
+            }
+            out.push_str(&format!(
+                "  {:<20} {:>5} {:<28} {:>7}",
+                at, site.line, instruction, site.samples
+            ));
+            for level in MemLevel::ALL {
+                out.push_str(&format!(" {:>4.0}%", site.share(level) * 100.0));
+            }
+            let latency = site.latency as f64 / site.samples as f64;
+            out.push_str(&format!(" {:>6.1} {:>5}\n", latency, site.hitm));
+        }
+        if self.elsewhere > 0 {
+            out.push_str(&format!("  {} samples outside compiled instructions\n", self.elsewhere));
+        }
+        if self.lost > 0 {
+            out.push_str(&format!("  {} samples lost (ring full)\n", self.lost));
+        }
+        if let Some(worst) = self.worst() {
+            let (level, share) = MemLevel::ALL[1..]
+                .iter()
+                .map(|&l| (l, worst.share(l)))
+                .fold((MemLevel::Unknown, 0.0), |a, b| if b.1 > a.1 { b } else { a });
+            out.push_str(&format!(
+                "Most misses: {}:{} (line {}) {}, {:.0}% served by {}\n",
+                worst.function,
+                worst.index,
+                worst.line,
+                worst.instruction,
+                share * 100.0,
+                level
+            ));
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::CompileOptions;
+    use crate::parser::Parser;
+
+    fn data_src(op: u64, lvl: u64, snoop: u64) -> u64 {
+        op | (lvl << MEM_LVL_SHIFT) | (snoop << MEM_SNOOP_SHIFT)
+    }
+
+    #[test]
+    fn test_decodes_data_source() {
+        // perf_mem_data_src of an L3 load hit that snooped a modified line
+        let load = MemSample::decode(0x1000, 0x2000, 41, data_src(0x02, 0x40 | 0x02, 0x10));
+        assert_eq!(load.level, MemLevel::L3);
+        assert!(!load.store && load.hitm);
+        assert_eq!(load.latency, 41);
+        let store = MemSample::decode(0, 0, 0, data_src(0x04, 0x08 | 0x02, 0x01));
+        assert_eq!((store.level, store.store, store.hitm), (MemLevel::L1, true, false));
+        // Local DRAM, and no source at all (the imprecise fallback)
+        assert_eq!(MemLevel::from_data_src(data_src(0x02, 0x80, 0)), MemLevel::Ram);
+        assert_eq!(MemLevel::from_data_src(0), MemLevel::Unknown);
+    }
+
+    #[test]
+    fn test_attributes_samples_to_ir_instructions() {
+        let src = "fn main(n) {
+            a = alloc(64)
+            s = 0
+            for (i = 0; i < 64; i = i + 1) {
+                v = a[i]
+                s = s + v
+            }
+            free(a)
+            return s
+        }";
+        let prog = Parser::new().parse(src).unwrap();
+        let program = CompiledProgram::compile(&prog, &CompileOptions::new(0)).unwrap();
+        let base = program.address();
+        let load = program
+            .code()
+            .source_map
+            .iter()
+            .find(|m| m.instruction.starts_with("Load("))
+            .unwrap();
+        let ip = (base + load.code.start) as u64;
+        let ram = data_src(0x02, 0x80, 0);
+        let l1 = data_src(0x02, 0x08, 0);
+        let samples = [
+            MemSample::decode(ip, 0, 200, ram),
+            MemSample::decode(ip, 0, 300, ram),
+            MemSample::decode(ip, 0, 4, l1),
+            // Host code
+            MemSample::decode(0x10, 0, 4, l1),
+        ];
+        let report = MemReport::attribute(program.code(), base, &samples);
+        assert_eq!((report.samples(), report.elsewhere), (4, 1));
+        let site = &report.sites[0];
+        assert_eq!((site.line, site.samples, site.misses()), (5, 3, 2));
+        assert_eq!(report.worst(), Some(site));
+
+        let text = report.summary();
+        assert!(text.contains("main:"), "{}", text);
+        assert!(text.contains("67% served by RAM"), "{}", text);
+        assert!(text.contains("1 samples outside"), "{}", text);
+    }
+
+    #[test]
+    fn test_sampler_traces_or_reports_why_not() {
+        let prog = Parser::new().parse("fn main() {\n return 7\n}").unwrap();
+        let program = CompiledProgram::compile(&prog, &CompileOptions::new(0)).unwrap();
+        let main: extern "C" fn() -> i64 = program.get("main").unwrap();
+        // No sampling PMU in most VMs
+        match MemSampler::new(DEFAULT_PERIOD) {
+            Ok(sampler) => {
+                let (result, report) = sampler.trace(&program, || main());
+                assert_eq!(result, 7);
+                assert_eq!(report.precise, sampler.precise());
+            }
+            Err(e) => assert!(e.starts_with("perf_event_open failed"), "{}", e),
+        }
+    }
+}
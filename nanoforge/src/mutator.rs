@@ -4,6 +4,7 @@
 //! to explore the optimization space through genetic algorithms.
 
 use crate::ir::{Function, Instruction, Opcode, Operand};
+use crate::validator::Fitness;
 use rand::prelude::*;
 
 /// Types of mutations that can be applied to code
@@ -51,8 +52,8 @@ pub struct Genome {
     /// Function metadata
     pub name: String,
     pub args: Vec<String>,
-    /// Fitness score (lower is better, measured in nanoseconds)
-    pub fitness: Option<f64>,
+    /// Measured cycles per call (lower is better)
+    pub fitness: Option<Fitness>,
     /// Generation this genome was created
     pub generation: u32,
 }
@@ -71,11 +72,9 @@ impl Genome {
 
     /// Convert back to a function
     pub fn to_function(&self) -> Function {
-        Function {
-            name: self.name.clone(),
-            args: self.args.clone(),
-            instructions: self.instructions.clone(),
-        }
+        let mut func = Function::new(&self.name, self.args.clone());
+        func.instructions = self.instructions.clone();
+        func
     }
 
     /// Get the number of instructions
@@ -87,6 +86,21 @@ impl Genome {
     pub fn is_empty(&self) -> bool {
         self.instructions.is_empty()
     }
+
+    /// Levenshtein distance between the instruction streams
+    pub fn edit_distance(&self, other: &Genome) -> usize {
+        let mut prev: Vec<usize> = (0..=other.len()).collect();
+        let mut row = vec![0; other.len() + 1];
+        for (i, a) in self.instructions.iter().enumerate() {
+            row[0] = i + 1;
+            for (j, b) in other.instructions.iter().enumerate() {
+                let substitute = prev[j] + usize::from(a != b);
+                row[j + 1] = substitute.min(prev[j + 1] + 1).min(row[j] + 1);
+            }
+            std::mem::swap(&mut prev, &mut row);
+        }
+        prev[other.len()]
+    }
 }
 
 /// Mutator that applies random mutations to genomes
@@ -119,6 +133,20 @@ impl Mutator {
         if self.rng.gen::<f64>() > self.mutation_rate {
             return None;
         }
+        self.mutate_now(genome)
+    }
+
+    /// Apply `steps` mutations regardless of the mutation rate
+    pub fn hypermutate(&mut self, genome: &mut Genome, steps: u32) {
+        for _ in 0..steps {
+            self.mutate_now(genome);
+        }
+    }
+
+    fn mutate_now(&mut self, genome: &mut Genome) -> Option<MutationType> {
+        if genome.is_empty() {
+            return None;
+        }
 
         let mutation_type = MutationType::random(&mut self.rng);
 
@@ -168,6 +196,7 @@ impl Mutator {
                         | Opcode::Jle
                         | Opcode::Jg
                         | Opcode::Jge
+                        | Opcode::JumpTable(..)
                         | Opcode::Ret
                         | Opcode::Call
                 ) && !matches!(genome.instructions[idx + 1].op, Opcode::Label | Opcode::Ret);
@@ -244,7 +273,7 @@ impl Mutator {
         let idx = self.rng.gen_range(0..genome.len());
         let can_duplicate = matches!(
             genome.instructions[idx].op,
-            Opcode::Add | Opcode::Sub | Opcode::Mov | Opcode::Load | Opcode::Store
+            Opcode::Add | Opcode::Sub | Opcode::Mov | Opcode::Load(_) | Opcode::Store(_)
         );
 
         if can_duplicate {
@@ -361,6 +390,30 @@ mod tests {
         assert_eq!(child.generation, 1);
     }
 
+    #[test]
+    fn test_edit_distance() {
+        let genome = create_test_genome();
+        assert_eq!(genome.edit_distance(&genome), 0);
+
+        let mut other = genome.clone();
+        other.instructions.remove(0);
+        other.instructions[0].src1 = Some(Operand::Imm(7));
+        assert_eq!(genome.edit_distance(&other), 2);
+        assert_eq!(other.edit_distance(&genome), 2);
+        assert_eq!(genome.edit_distance(&Genome { instructions: vec![], ..genome.clone() }), 3);
+    }
+
+    #[test]
+    fn test_hypermutate_ignores_rate() {
+        let mut mutator = Mutator::new(0.0, 7);
+        let mut genome = create_test_genome();
+        mutator.mutate(&mut genome);
+        assert_eq!(genome.edit_distance(&create_test_genome()), 0);
+
+        mutator.hypermutate(&mut genome, 20);
+        assert!(genome.edit_distance(&create_test_genome()) > 0);
+    }
+
     #[test]
     fn test_mutation_types() {
         assert_eq!(MutationType::all().len(), 6);
@@ -0,0 +1,160 @@
+//! Native Baselines
+//!
+//! Rust versions of well-known kernels, compiled into the binary with the
+//! rest of the crate (so at `-C opt-level=3` in release builds). `compare`
+//! runs a script's JIT variants and the matching native kernel in the same
+//! sandbox, showing how close the JIT gets to what rustc makes of the same
+//! loop. Every kernel follows the SOAE entry contract, `main(n)`; the
+//! reference scripts live in `kernels/`.
+
+use crate::ir::Program;
+use crate::sandbox::{BenchmarkResult, NanosecondSandbox, RankedVariant};
+use crate::soae::SoaeOptions;
+
+/// A kernel with a statically compiled implementation
+#[derive(Debug, Clone, Copy)]
+pub struct NativeKernel {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub func: extern "C" fn(u64) -> u64,
+    /// The same kernel as a NanoForge script
+    pub script: &'static str,
+}
+
+/// Every kernel `compare` knows
+pub const KERNELS: [NativeKernel; 3] = [
+    NativeKernel {
+        name: "sum",
+        description: "sum of 0..n",
+        func: native_sum,
+        script: include_str!("../kernels/sum.nf"),
+    },
+    NativeKernel {
+        name: "vec_add",
+        description: "C = A + B over n elements, then the sum of C",
+        func: native_vec_add,
+        script: include_str!("../kernels/vec_add.nf"),
+    },
+    NativeKernel {
+        name: "dot",
+        description: "dot product of two n-element arrays",
+        func: native_dot,
+        script: include_str!("../kernels/dot.nf"),
+    },
+];
+
+/// The kernel called `name`
+pub fn find(name: &str) -> Option<&'static NativeKernel> {
+    KERNELS.iter().find(|k| k.name == name)
+}
+
+extern "C" fn native_sum(n: u64) -> u64 {
+    (0..n as i64).fold(0i64, |s, i| s.wrapping_add(i)) as u64
+}
+
+extern "C" fn native_vec_add(n: u64) -> u64 {
+    let n = (n as i64).max(0) as usize;
+    let a: Vec<i64> = (0..n as i64).collect();
+    let b: Vec<i64> = (0..n as i64).map(|i| i.wrapping_add(i)).collect();
+    let c: Vec<i64> = a.iter().zip(&b).map(|(a, b)| a.wrapping_add(*b)).collect();
+    c.iter().fold(0i64, |s, c| s.wrapping_add(*c)) as u64
+}
+
+extern "C" fn native_dot(n: u64) -> u64 {
+    let n = (n as i64).max(0) as usize;
+    let a: Vec<i64> = (0..n as i64).collect();
+    let b: Vec<i64> = (0..n as i64).map(|i| i.wrapping_add(1)).collect();
+    a.iter()
+        .zip(&b)
+        .fold(0i64, |s, (a, b)| s.wrapping_add(a.wrapping_mul(*b))) as u64
+}
+
+/// Inputs every variant must agree with the native kernel on, besides the
+/// benchmarked one
+const CHECK_INPUTS: [u64; 4] = [0, 1, 7, 100];
+
+/// Outcome of `compare`
+#[derive(Debug)]
+pub struct Comparison {
+    pub kernel: &'static str,
+    /// Input both sides were measured at
+    pub input: u64,
+    /// What both sides returned for it
+    pub output: u64,
+    pub native: BenchmarkResult,
+    /// JIT variants, fastest first
+    pub rankings: Vec<RankedVariant>,
+}
+
+impl Comparison {
+    /// Native cycles/op over `ranked`'s: 1.0 matches rustc, above beats it
+    pub fn relative_speed(&self, ranked: &RankedVariant) -> f64 {
+        self.native.cycles_per_op.max(1) as f64 / ranked.result.cycles_per_op.max(1) as f64
+    }
+
+    /// Relative speed of the fastest variant
+    pub fn best(&self) -> f64 {
+        self.rankings.first().map_or(0.0, |r| self.relative_speed(r))
+    }
+}
+
+/// Benchmark every variant of `program` and `kernel`'s native version at
+/// `options.input`, after checking they compute the same thing
+pub fn compare(
+    program: &Program,
+    kernel: &NativeKernel,
+    options: &SoaeOptions,
+) -> Result<Comparison, String> {
+    let variants = options.variants(program)?;
+    for variant in &variants {
+        for input in CHECK_INPUTS.into_iter().chain([options.input]) {
+            let (jit, native) = (variant.execute(input), (kernel.func)(input));
+            if jit != native {
+                return Err(format!(
+                    "{}: main({}) returned {}, but native {} returns {}",
+                    variant.config.name, input, jit as i64, kernel.name, native as i64
+                ));
+            }
+        }
+    }
+
+    let sandbox = NanosecondSandbox::new(options.sandbox.clone());
+    let native = sandbox.benchmark_fn(kernel.func, options.input);
+    let rankings = sandbox.benchmark_all(&variants, options.input);
+    Ok(Comparison {
+        kernel: kernel.name,
+        input: options.input,
+        output: native.stability.output,
+        native,
+        rankings,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    #[test]
+    fn test_reference_scripts_match_native_kernels() {
+        let options = SoaeOptions::default().measurement(2, 10).input(50);
+        for kernel in &KERNELS {
+            let program = Parser::new().parse(kernel.script).unwrap();
+            let comparison = compare(&program, kernel, &options).unwrap();
+            assert_eq!(comparison.output, (kernel.func)(50), "{}", kernel.name);
+            assert!(!comparison.rankings.is_empty());
+            assert!(comparison.best() > 0.0);
+        }
+        assert_eq!(native_dot(3), 8);
+        assert_eq!(native_vec_add(3), 9);
+    }
+
+    #[test]
+    fn test_rejects_a_script_for_another_kernel() {
+        let program = Parser::new().parse(KERNELS[0].script).unwrap();
+        let err = compare(&program, find("dot").unwrap(), &SoaeOptions::default())
+            .unwrap_err();
+        assert!(err.contains("main(7) returned 21, but native dot returns 112"), "{}", err);
+        assert!(find("matmul").is_none());
+    }
+}
@@ -0,0 +1,200 @@
+//! NUMA Placement
+//!
+//! On multi-socket machines the node holding a benchmark's memory and the
+//! node of the core running it can change timings drastically. This binds
+//! the measuring thread's memory to a node through libnuma and reports where
+//! pages actually landed. libnuma is loaded at runtime, so machines without
+//! it still build and run; binding there is an error, not a link failure.
+
+use std::ffi::{c_char, c_int, c_uint, c_ulong, c_void, CStr};
+use std::fmt;
+use std::sync::OnceLock;
+
+const LIBNUMA: &CStr = c"libnuma.so.1";
+
+/// NUMA nodes of a measurement: the node of the CPU running it and the node
+/// its memory comes from. In a `SandboxConfig` these are requests (`None`
+/// leaves placement to the OS); in a `BenchmarkResult` they are observations
+/// (`None` when unknown).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NumaPlacement {
+    pub cpu_node: Option<usize>,
+    pub memory_node: Option<usize>,
+}
+
+impl fmt::Display for NumaPlacement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let node = |n: Option<usize>| n.map_or("?".to_string(), |n| n.to_string());
+        write!(
+            f,
+            "cpu node {}, memory node {}",
+            node(self.cpu_node),
+            node(self.memory_node)
+        )
+    }
+}
+
+/// Opaque `struct bitmask` from numa.h
+#[repr(C)]
+struct Bitmask {
+    _private: [u8; 0],
+}
+
+/// The libnuma entry points we use
+struct Libnuma {
+    max_node: unsafe extern "C" fn() -> c_int,
+    allocate_nodemask: unsafe extern "C" fn() -> *mut Bitmask,
+    bitmask_setbit: unsafe extern "C" fn(*mut Bitmask, c_uint) -> *mut Bitmask,
+    bitmask_free: unsafe extern "C" fn(*mut Bitmask),
+    set_membind: unsafe extern "C" fn(*mut Bitmask),
+    set_localalloc: unsafe extern "C" fn(),
+    move_pages: unsafe extern "C" fn(
+        c_int,
+        c_ulong,
+        *mut *mut c_void,
+        *const c_int,
+        *mut c_int,
+        c_int,
+    ) -> c_int,
+}
+
+impl Libnuma {
+    fn load() -> Result<Self, String> {
+        unsafe {
+            let handle = libc::dlopen(LIBNUMA.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL);
+            if handle.is_null() {
+                return Err("libnuma.so.1 not found".to_string());
+            }
+
+            let available: unsafe extern "C" fn() -> c_int = sym(handle, c"numa_available")?;
+            if available() < 0 {
+                return Err("NUMA is not available on this system".to_string());
+            }
+            Ok(Self {
+                max_node: sym(handle, c"numa_max_node")?,
+                allocate_nodemask: sym(handle, c"numa_allocate_nodemask")?,
+                bitmask_setbit: sym(handle, c"numa_bitmask_setbit")?,
+                bitmask_free: sym(handle, c"numa_bitmask_free")?,
+                set_membind: sym(handle, c"numa_set_membind")?,
+                set_localalloc: sym(handle, c"numa_set_localalloc")?,
+                move_pages: sym(handle, c"numa_move_pages")?,
+            })
+        }
+    }
+}
+
+/// Look up `name` in `handle` as a function pointer of type `F`.
+///
+/// # Safety
+/// `F` must be an `extern "C" fn` type matching the symbol's C signature.
+unsafe fn sym<F: Copy>(handle: *mut c_void, name: &CStr) -> Result<F, String> {
+    let ptr = libc::dlsym(handle, name.as_ptr() as *const c_char);
+    if ptr.is_null() {
+        return Err(format!("libnuma is missing {}", name.to_string_lossy()));
+    }
+    Ok(std::mem::transmute_copy::<*mut c_void, F>(&ptr))
+}
+
+/// libnuma, loaded once per process
+fn libnuma() -> Result<&'static Libnuma, String> {
+    static LIB: OnceLock<Result<Libnuma, String>> = OnceLock::new();
+    LIB.get_or_init(Libnuma::load)
+        .as_ref()
+        .map_err(Clone::clone)
+}
+
+/// Whether libnuma is installed and the kernel supports NUMA
+pub fn is_available() -> bool {
+    libnuma().is_ok()
+}
+
+/// Highest NUMA node id on this machine
+pub fn max_node() -> Result<usize, String> {
+    let lib = libnuma()?;
+    Ok(unsafe { (lib.max_node)() }.max(0) as usize)
+}
+
+/// Restrict the current thread's future allocations to `node`
+pub fn bind_memory(node: usize) -> Result<(), String> {
+    let lib = libnuma()?;
+    let max = max_node()?;
+    if node > max {
+        return Err(format!("NUMA node {} does not exist (max {})", node, max));
+    }
+    unsafe {
+        let mask = (lib.allocate_nodemask)();
+        if mask.is_null() {
+            return Err("Failed to allocate NUMA node mask".to_string());
+        }
+        (lib.bitmask_setbit)(mask, node as c_uint);
+        (lib.set_membind)(mask);
+        (lib.bitmask_free)(mask);
+    }
+    Ok(())
+}
+
+/// Return the current thread to the default (local) allocation policy
+pub fn unbind_memory() {
+    if let Ok(lib) = libnuma() {
+        unsafe { (lib.set_localalloc)() }
+    }
+}
+
+/// Node holding the page at `addr` (the page must be resident)
+pub fn node_of(addr: *const u8) -> Option<usize> {
+    let lib = libnuma().ok()?;
+    let mut page = addr as *mut c_void;
+    let mut status: c_int = -1;
+    let ret = unsafe { (lib.move_pages)(0, 1, &mut page, std::ptr::null(), &mut status, 0) };
+    (ret == 0 && status >= 0).then_some(status as usize)
+}
+
+/// Node a freshly touched page lands on under the current thread's policy
+pub fn probe_memory_node() -> Option<usize> {
+    libnuma().ok()?;
+    unsafe {
+        let page = libc::sysconf(libc::_SC_PAGESIZE).max(4096) as usize;
+        let ptr = libc::mmap(
+            std::ptr::null_mut(),
+            page,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        );
+        if ptr == libc::MAP_FAILED {
+            return None;
+        }
+        // First touch places the page
+        std::ptr::write_volatile(ptr as *mut u8, 1);
+        let node = node_of(ptr as *const u8);
+        libc::munmap(ptr, page);
+        node
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bind_memory_places_pages_on_node() {
+        if !is_available() {
+            assert!(bind_memory(0).is_err());
+            assert_eq!(probe_memory_node(), None);
+            return;
+        }
+        let max = max_node().unwrap();
+        assert!(bind_memory(max + 1).is_err());
+
+        // Run on a scratch thread so the policy doesn't leak into other tests
+        std::thread::spawn(|| {
+            let local = probe_memory_node().expect("page node");
+            bind_memory(local).unwrap();
+            assert_eq!(probe_memory_node(), Some(local));
+            unbind_memory();
+        })
+        .join()
+        .unwrap();
+    }
+}
@@ -0,0 +1,93 @@
+//! Checked Integer Arithmetic
+//!
+//! Scripts wrap on i64 overflow by default. With
+//! `CompileOptions::overflow(OverflowMode::Checked)` every scalar `Add`, `Sub`
+//! and `Mul` is followed by a `jo` to a stub that calls `nf_overflow` with the
+//! instruction's site id and exits with `Status::Overflow`. The site is kept
+//! per thread so wrappers can name the offending IR instruction, indexed into
+//! `CompiledCode::overflow_sites`.
+
+use crate::status::{self, Status};
+use serde::Serialize;
+use std::cell::Cell;
+use std::fmt;
+use std::str::FromStr;
+
+/// What `Add`, `Sub` and `Mul` do when the result doesn't fit in an i64
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub enum OverflowMode {
+    /// Two's complement wraparound
+    #[default]
+    Wrapping,
+    /// Trap with `Status::Overflow`; vectorization is disabled since packed
+    /// adds can't report it
+    Checked,
+}
+
+impl FromStr for OverflowMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "wrapping" => Ok(OverflowMode::Wrapping),
+            "checked" => Ok(OverflowMode::Checked),
+            _ => Err(format!(
+                "unknown overflow mode '{}' (expected wrapping or checked)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for OverflowMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            OverflowMode::Wrapping => "wrapping",
+            OverflowMode::Checked => "checked",
+        })
+    }
+}
+
+thread_local! {
+    static SITE: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+/// Overflow handler called from generated stubs
+pub extern "C" fn nf_overflow(site: i64) {
+    if status::raise(Status::Overflow) {
+        SITE.with(|s| s.set(Some(site as usize)));
+    }
+}
+
+/// Take (and clear) the site of this thread's last overflow trap
+pub fn take_site() -> Option<usize> {
+    SITE.with(|s| s.take())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_overflow_site_is_kept_only_for_the_first_trap() {
+        status::reset();
+        take_site();
+        status::nf_trap(Status::FuelExhausted.code() as i64);
+        nf_overflow(3);
+        assert_eq!(take_site(), None);
+        assert_eq!(status::take(), Status::FuelExhausted);
+
+        nf_overflow(5);
+        nf_overflow(7);
+        assert_eq!(status::take(), Status::Overflow);
+        assert_eq!(take_site(), Some(5));
+        assert_eq!(take_site(), None);
+    }
+
+    #[test]
+    fn test_parse_overflow_mode() {
+        assert_eq!("checked".parse(), Ok(OverflowMode::Checked));
+        assert_eq!("wrapping".parse(), Ok(OverflowMode::Wrapping));
+        assert!("saturating".parse::<OverflowMode>().is_err());
+    }
+}
@@ -0,0 +1,203 @@
+//! Parallel Execution
+//!
+//! Worker pool that fans a batch of calls to one compiled function across
+//! cores. The inputs are cut into one chunk per worker and each worker runs
+//! its chunk through the batch stub (`NanoFn::call_batch`), so a call costs
+//! the same as on the caller's thread: compiled code keeps no shared state
+//! (fuel lives in a register, traps and sanitizer faults are per thread).
+//!
+//! `map` blocks until every chunk is done, which is what lets workers write
+//! straight into the caller's output slice.
+
+use crate::compiled_program::NanoFn;
+use crossbeam::channel::{self, Sender};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::OnceLock;
+use std::thread::{self, JoinHandle};
+
+/// Fewest inputs worth handing to a worker: below this the hand-off costs
+/// more than the calls
+pub const MIN_CHUNK: usize = 256;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A pointer that may cross to a worker: `map` waits for every job before
+/// the borrow it came from ends
+struct Shared<T: ?Sized>(*mut T);
+
+unsafe impl<T: ?Sized> Send for Shared<T> {}
+
+impl<T: ?Sized> Shared<T> {
+    /// # Safety
+    /// The pointee must outlive the job and not be used by anyone else
+    /// while it runs.
+    unsafe fn get<'a>(&self) -> &'a mut T {
+        &mut *self.0
+    }
+}
+
+/// Threads that call compiled code
+pub struct ExecutionPool {
+    queue: Option<Sender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl ExecutionPool {
+    /// Start `workers` threads
+    pub fn new(workers: usize) -> Self {
+        let (tx, rx) = channel::unbounded::<Job>();
+        let workers = (0..workers.max(1))
+            .map(|i| {
+                let rx = rx.clone();
+                thread::Builder::new()
+                    .name(format!("nf-exec-{}", i))
+                    .spawn(move || {
+                        for job in rx {
+                            // A panicking job reports through its dropped sender
+                            let _ = panic::catch_unwind(AssertUnwindSafe(job));
+                        }
+                    })
+                    .expect("Failed to spawn execution worker")
+            })
+            .collect();
+        Self {
+            queue: Some(tx),
+            workers,
+        }
+    }
+
+    /// Process-wide pool with a worker per available CPU
+    pub fn global() -> &'static ExecutionPool {
+        static POOL: OnceLock<ExecutionPool> = OnceLock::new();
+        POOL.get_or_init(|| {
+            let cpus = thread::available_parallelism().map_or(1, |n| n.get());
+            ExecutionPool::new(cpus)
+        })
+    }
+
+    pub fn workers(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Set `outputs[i]` to `f` called on `inputs[i]`, chunks of the inputs
+    /// running on the workers at the same time. Fails like
+    /// `NanoFn::call_batch`, with the error of the first chunk that failed.
+    pub fn map(
+        &self,
+        f: &NanoFn<'_>,
+        inputs: &[i64],
+        outputs: &mut [i64],
+    ) -> Result<(), String> {
+        if inputs.len() != outputs.len() {
+            // Let the stub report it
+            return f.call_batch(inputs, outputs);
+        }
+        let chunk = inputs.len().div_ceil(self.workers()).max(MIN_CHUNK);
+        let queue = self.queue.as_ref().ok_or("Execution pool is shut down")?;
+        if inputs.len() <= chunk {
+            return f.call_batch(inputs, outputs);
+        }
+
+        let (done, results) = channel::unbounded();
+        for (inputs, outputs) in inputs.chunks(chunk).zip(outputs.chunks_mut(chunk)) {
+            let f = Shared(f as *const NanoFn<'_> as *mut NanoFn<'_>);
+            let inputs = Shared(inputs as *const [i64] as *mut [i64]);
+            let outputs = Shared(outputs as *mut [i64]);
+            let done = done.clone();
+            let job: Box<dyn FnOnce() + Send + '_> = Box::new(move || {
+                // Chunks don't overlap, and `map` outlives the job
+                let result = unsafe { f.get().call_batch(inputs.get(), outputs.get()) };
+                let _ = done.send(result);
+            });
+            // Only `map`'s frame bounds the borrows, and it waits below
+            let job: Job = unsafe { std::mem::transmute(job) };
+            // Workers only stop once the queue is dropped, so this can't
+            // fail and leave sent jobs running after an early return
+            queue.send(job).expect("Execution workers exited early");
+        }
+        drop(done);
+
+        // Until every job has sent or been dropped (a panic), which is when
+        // they no longer touch the slices
+        let mut outcome = Ok(());
+        let mut finished = 0;
+        for result in results.iter() {
+            finished += 1;
+            if outcome.is_ok() {
+                outcome = result;
+            }
+        }
+        if finished < inputs.len().div_ceil(chunk) && outcome.is_ok() {
+            outcome = Err(format!("{}(): a worker panicked", f.name()));
+        }
+        outcome
+    }
+}
+
+impl Drop for ExecutionPool {
+    fn drop(&mut self) {
+        // Closing the queue lets workers drain what's left and exit
+        self.queue.take();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiled_program::CompiledProgram;
+    use crate::compiler::CompileOptions;
+    use crate::parser::Parser;
+
+    const SRC: &str = "fn main(n) {
+        s = 0
+        i = 0
+        while i < n {
+            s = s + i
+            i = i + 1
+        }
+        return s
+    }";
+
+    #[test]
+    fn test_map_matches_a_single_batch() {
+        let prog = Parser::new().parse(SRC).unwrap();
+        let compiled = CompiledProgram::compile(&prog, &CompileOptions::new(2)).unwrap();
+        let main = compiled.get_fn("main").unwrap();
+        let pool = ExecutionPool::new(4);
+        assert_eq!(pool.workers(), 4);
+
+        // Short batches stay on the caller's thread; long ones are split
+        for n in [0, 10, 4 * MIN_CHUNK + 3] {
+            let inputs: Vec<i64> = (0..n as i64).map(|i| i % 500).collect();
+            let mut outputs = vec![-1; n];
+            pool.map(&main, &inputs, &mut outputs).unwrap();
+            let expected: Vec<i64> = inputs.iter().map(|i| i * (i - 1) / 2).collect();
+            assert_eq!(outputs, expected);
+        }
+        let err = pool.map(&main, &[1, 2], &mut [0]).unwrap_err();
+        assert!(err.contains("needs as many outputs"), "{}", err);
+    }
+
+    #[test]
+    fn test_a_trap_in_any_chunk_fails_the_map() {
+        let prog = Parser::new().parse(SRC).unwrap();
+        let compiled = CompiledProgram::compile(&prog, &CompileOptions::new(0)).unwrap();
+        compiled.set_fuel("main", 100).unwrap();
+        let main = compiled.get_fn("main").unwrap();
+        let mut inputs = vec![1; 8 * MIN_CHUNK];
+        *inputs.last_mut().unwrap() = 1000;
+        let mut outputs = vec![0; inputs.len()];
+        let pool = ExecutionPool::new(4);
+        assert_eq!(
+            pool.map(&main, &inputs, &mut outputs),
+            Err("main(): fuel exhausted".to_string())
+        );
+        // Workers report through their own thread's status: this one is clean
+        inputs.pop();
+        outputs.pop();
+        assert_eq!(pool.map(&main, &inputs, &mut outputs), Ok(()));
+    }
+}
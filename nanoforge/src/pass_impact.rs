@@ -0,0 +1,205 @@
+//! Pass Impact
+//!
+//! `nanoforge pass-impact` measures what each optimizer pass is worth on
+//! this machine. Every script of a corpus is compiled once as usual and once
+//! with each `Pass` disabled, and the sandbox times `main(n)` for each build.
+//! A pass whose removal leaves the machine code unchanged never fired on
+//! that script and isn't timed. Summed over a corpus, this shows which
+//! passes pay for themselves and which deserve work.
+
+use crate::compiled_program::CompiledProgram;
+use crate::compiler::CompileOptions;
+use crate::parser::Parser;
+use crate::passes::Pass;
+use crate::sandbox::NanosecondSandbox;
+use crate::soae::SoaeOptions;
+use serde::Serialize;
+
+/// One script compiled without one pass
+#[derive(Debug, Clone, Serialize)]
+pub struct PassMeasurement {
+    pub pass: Pass,
+    /// Cycles/op without the pass; None if disabling it changed nothing
+    pub cycles_per_op: Option<u64>,
+}
+
+/// Every pass measured on one script
+#[derive(Debug, Clone, Serialize)]
+pub struct ScriptImpact {
+    pub script: String,
+    /// Cycles/op with every pass enabled
+    pub baseline_cycles_per_op: u64,
+    pub passes: Vec<PassMeasurement>,
+}
+
+impl ScriptImpact {
+    /// How many times faster the script is with `pass` than without it
+    /// (None if the pass didn't fire)
+    pub fn speedup(&self, pass: Pass) -> Option<f64> {
+        let without = self.passes.iter().find(|m| m.pass == pass)?.cycles_per_op?;
+        Some(without.max(1) as f64 / self.baseline_cycles_per_op.max(1) as f64)
+    }
+}
+
+/// One pass over a whole corpus
+#[derive(Debug, Clone, Serialize)]
+pub struct PassSummary {
+    pub pass: Pass,
+    /// Scripts the pass changed
+    pub fired: usize,
+    /// Geometric mean of `ScriptImpact::speedup` over those scripts (1.0 if
+    /// it never fired)
+    pub mean_speedup: f64,
+    /// The script it helped most, and by how much
+    pub best: Option<(String, f64)>,
+    /// The script it helped least (or hurt most)
+    pub worst: Option<(String, f64)>,
+}
+
+/// Compile `source` with `base` and with each pass disabled in turn, and
+/// time `main(options.input)` for every build that differs
+pub fn measure(
+    script: &str,
+    source: &str,
+    base: &CompileOptions,
+    options: &SoaeOptions,
+) -> Result<ScriptImpact, String> {
+    let program = Parser::new()
+        .parse(source)
+        .map_err(|e| format!("Parsing Error: {}", e))?;
+    let sandbox = NanosecondSandbox::new(options.sandbox.clone());
+
+    let baseline = CompiledProgram::compile(&program, base)?;
+    let main = entry(&baseline)?;
+    let expected = main(options.input);
+    let baseline_cycles_per_op = sandbox.benchmark_fn(main, options.input).cycles_per_op;
+
+    let mut passes = Vec::new();
+    for pass in Pass::ALL {
+        let without = CompiledProgram::compile(&program, &base.disable_pass(pass))?;
+        if without.code().code == baseline.code().code {
+            passes.push(PassMeasurement {
+                pass,
+                cycles_per_op: None,
+            });
+            continue;
+        }
+        let main = entry(&without)?;
+        let output = main(options.input);
+        if output != expected {
+            return Err(format!(
+                "main({}) returns {} without {} but {} with it",
+                options.input, output as i64, pass, expected as i64
+            ));
+        }
+        passes.push(PassMeasurement {
+            pass,
+            cycles_per_op: Some(sandbox.benchmark_fn(main, options.input).cycles_per_op),
+        });
+    }
+    Ok(ScriptImpact {
+        script: script.to_string(),
+        baseline_cycles_per_op,
+        passes,
+    })
+}
+
+pub(crate) fn entry(compiled: &CompiledProgram) -> Result<extern "C" fn(u64) -> u64, String> {
+    compiled
+        .get("main")
+        .ok_or_else(|| "Scripts need an entry point fn main(n) with at most one parameter".to_string())
+}
+
+/// Per-pass summary of `impacts`, most valuable pass first
+pub fn summarize(impacts: &[ScriptImpact]) -> Vec<PassSummary> {
+    let mut summaries: Vec<PassSummary> = Pass::ALL
+        .into_iter()
+        .map(|pass| {
+            let speedups: Vec<(&str, f64)> = impacts
+                .iter()
+                .filter_map(|i| Some((i.script.as_str(), i.speedup(pass)?)))
+                .collect();
+            let mean_speedup = match speedups.len() {
+                0 => 1.0,
+                n => (speedups.iter().map(|(_, s)| s.ln()).sum::<f64>() / n as f64).exp(),
+            };
+            let ranked = |best: bool| {
+                speedups
+                    .iter()
+                    .copied()
+                    .reduce(|a, b| if (b.1 > a.1) == best { b } else { a })
+                    .map(|(script, s)| (script.to_string(), s))
+            };
+            PassSummary {
+                pass,
+                fired: speedups.len(),
+                mean_speedup,
+                best: ranked(true),
+                worst: ranked(false),
+            }
+        })
+        .collect();
+    summaries.sort_by(|a, b| b.mean_speedup.total_cmp(&a.mean_speedup));
+    summaries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn impact(script: &str, baseline: u64, passes: &[(Pass, Option<u64>)]) -> ScriptImpact {
+        ScriptImpact {
+            script: script.to_string(),
+            baseline_cycles_per_op: baseline,
+            passes: passes
+                .iter()
+                .map(|&(pass, cycles_per_op)| PassMeasurement {
+                    pass,
+                    cycles_per_op,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_summary_ranks_passes_by_geometric_mean() {
+        let impacts = [
+            impact("a.nf", 100, &[(Pass::LoopUnrolling, Some(400)), (Pass::Schedule, Some(90))]),
+            impact("b.nf", 100, &[(Pass::LoopUnrolling, Some(100)), (Pass::Schedule, None)]),
+        ];
+        let summary = summarize(&impacts);
+        assert_eq!(summary[0].pass, Pass::LoopUnrolling);
+        assert_eq!(summary[0].fired, 2);
+        assert!((summary[0].mean_speedup - 2.0).abs() < 1e-9);
+        assert_eq!(summary[0].best, Some(("a.nf".to_string(), 4.0)));
+        assert_eq!(summary[0].worst, Some(("b.nf".to_string(), 1.0)));
+
+        let schedule = summary.iter().find(|s| s.pass == Pass::Schedule).unwrap();
+        assert_eq!(schedule.fired, 1);
+        assert_eq!(summary.last().unwrap().pass, Pass::Schedule);
+        let never = summary.iter().find(|s| s.pass == Pass::ConstantFolding).unwrap();
+        assert_eq!((never.fired, never.mean_speedup, never.best.clone()), (0, 1.0, None));
+    }
+
+    #[test]
+    fn test_measures_only_passes_that_fire() {
+        let source = "fn main(n) {
+            i = 0
+            s = 0
+            label loop
+            if i >= n goto done
+            s = s + i
+            i = i + 1
+            goto loop
+            label done
+            return s
+        }";
+        let options = SoaeOptions::default().measurement(2, 10).input(100);
+        let impact = measure("sum.nf", source, &CompileOptions::new(2), &options).unwrap();
+        assert_eq!(impact.passes.len(), Pass::ALL.len());
+        assert!(impact.speedup(Pass::LoopUnrolling).is_some());
+        // Off unless the options ask for them
+        assert_eq!(impact.speedup(Pass::SoftwarePipeline), None);
+        assert_eq!(impact.speedup(Pass::InsertPrefetches), None);
+    }
+}
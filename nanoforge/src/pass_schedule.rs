@@ -0,0 +1,333 @@
+//! Learned Pass Schedules
+//!
+//! `nanoforge learn-passes` lets a Thompson Sampling bandit pick which
+//! optimizer passes run in each function. The optimizer applies its passes
+//! in a fixed order until nothing changes, so a schedule is a selection:
+//! each arm is a set of passes to skip in one function (the first skips
+//! none), and its reward is the sandbox-measured cycles/op of `main(n)`
+//! against the fastest arm. Arms that compile to the same machine code as
+//! skipping nothing are dropped, as `pass_impact` does. Functions are
+//! learned one at a time in program order, the others keeping the choice
+//! already made for them.
+//!
+//! Schedules are kept in a `PassScheduleStore` keyed by `tuning::script_hash`
+//! and applied through each function's `Hints::disabled_passes`.
+
+use crate::ai_optimizer::VariantBandit;
+use crate::compiled_program::CompiledProgram;
+use crate::compiler::CompileOptions;
+use crate::cpu_features::CpuFeatures;
+use crate::ir::Program;
+use crate::pass_impact;
+use crate::passes::{Pass, PassSet};
+use crate::sandbox::NanosecondSandbox;
+use crate::shutdown;
+use crate::soae::SoaeOptions;
+use crate::store::{self, Schema};
+use crate::tuning::script_hash;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Passes to skip, by function
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PassSchedule {
+    pub disabled: BTreeMap<String, Vec<Pass>>,
+}
+
+impl PassSchedule {
+    /// Passes `function` skips
+    pub fn disabled_in(&self, function: &str) -> PassSet {
+        self.disabled
+            .get(function)
+            .map_or(PassSet::default(), |passes| {
+                passes.iter().fold(PassSet::default(), |set, &p| set.with(p))
+            })
+    }
+
+    pub fn set(&mut self, function: &str, passes: PassSet) {
+        if passes.is_empty() {
+            self.disabled.remove(function);
+        } else {
+            self.disabled.insert(function.to_string(), passes.passes());
+        }
+    }
+
+    /// Set every function's `disabled_passes` hint from the schedule
+    pub fn apply(&self, program: &mut Program) {
+        for func in &mut program.functions {
+            func.hints.disabled_passes = self.disabled_in(&func.name);
+        }
+    }
+}
+
+/// Name of the arm that skips `passes`
+pub fn arm_name(passes: PassSet) -> String {
+    match passes.passes().as_slice() {
+        [] => "all passes".to_string(),
+        skipped => {
+            let names: Vec<&str> = skipped.iter().map(|p| p.name()).collect();
+            format!("no {}", names.join(", no "))
+        }
+    }
+}
+
+/// What the bandit learned for one function
+#[derive(Debug, Clone)]
+pub struct FunctionSchedule {
+    pub function: String,
+    /// Passes each arm skips, in the bandit's arm order
+    pub arms: Vec<PassSet>,
+    pub bandit: VariantBandit,
+    /// The arm the bandit settled on
+    pub chosen: PassSet,
+}
+
+/// Outcome of `learn`
+#[derive(Debug, Clone)]
+pub struct ScheduleReport {
+    pub cpu: CpuFeatures,
+    pub schedule: PassSchedule,
+    /// Functions that had more than one arm, in program order
+    pub functions: Vec<FunctionSchedule>,
+    /// Cycles/op of `main` with the schedule
+    pub cycles_per_op: u64,
+    /// Cycles/op of `main` with every pass
+    pub baseline_cycles_per_op: u64,
+}
+
+/// Learn which passes each function of `program` should skip, spending
+/// `iterations` bandit rounds per function
+pub fn learn(
+    program: &Program,
+    base: &CompileOptions,
+    iterations: u32,
+    options: &SoaeOptions,
+) -> Result<ScheduleReport, String> {
+    let sandbox = NanosecondSandbox::new(options.sandbox.clone());
+    let input = options.input;
+    let baseline = CompiledProgram::compile(program, base)?;
+    let main = pass_impact::entry(&baseline)?;
+    let expected = main(input);
+    let baseline_cycles_per_op = sandbox.benchmark_fn(main, input).cycles_per_op;
+
+    let mut schedule = PassSchedule::default();
+    let mut functions = Vec::new();
+    for (index, func) in program.functions.iter().enumerate() {
+        if shutdown::requested() {
+            break;
+        }
+        let compile = |passes: PassSet| {
+            let mut candidate = schedule.clone();
+            candidate.set(&func.name, passes);
+            let mut program = program.clone();
+            candidate.apply(&mut program);
+            CompiledProgram::compile(&program, base)
+        };
+        let mut arms = vec![(PassSet::default(), compile(PassSet::default())?)];
+        for pass in Pass::ALL.into_iter().filter(|&p| !base.disabled_passes.contains(p)) {
+            let passes = PassSet::default().with(pass);
+            let compiled = compile(passes)?;
+            if compiled.code().code == arms[0].1.code().code {
+                continue;
+            }
+            let output = pass_impact::entry(&compiled)?(input);
+            if output != expected {
+                return Err(format!(
+                    "main({}) returns {} without {} in {} but {} with it",
+                    input, output as i64, pass, func.name, expected as i64
+                ));
+            }
+            arms.push((passes, compiled));
+        }
+        if arms.len() == 1 {
+            continue;
+        }
+
+        let names: Vec<String> = arms.iter().map(|(passes, _)| arm_name(*passes)).collect();
+        let mut bandit = match options.seed {
+            Some(seed) => VariantBandit::with_seed(names, seed.wrapping_add(index as u64)),
+            None => VariantBandit::new(names),
+        };
+        let mut cycles = Vec::with_capacity(arms.len());
+        for (_, compiled) in &arms {
+            cycles.push(sandbox.benchmark_fn(pass_impact::entry(compiled)?, input).cycles_per_op);
+        }
+        let best_cycles = cycles.iter().copied().min().unwrap_or(0);
+        for _ in 0..iterations {
+            if shutdown::requested() {
+                break;
+            }
+            let selected = bandit.select();
+            let main = pass_impact::entry(&arms[selected].1)?;
+            let result = sandbox.benchmark_fn(main, input);
+            bandit.update_with_performance(selected, result.cycles_per_op, best_cycles);
+        }
+
+        let chosen = arms[bandit.get_best()].0;
+        schedule.set(&func.name, chosen);
+        functions.push(FunctionSchedule {
+            function: func.name.clone(),
+            arms: arms.into_iter().map(|(passes, _)| passes).collect(),
+            bandit,
+            chosen,
+        });
+    }
+
+    // An empty schedule is the baseline: don't report noise as a change
+    let cycles_per_op = if schedule.disabled.is_empty() {
+        baseline_cycles_per_op
+    } else {
+        let mut scheduled = program.clone();
+        schedule.apply(&mut scheduled);
+        let compiled = CompiledProgram::compile(&scheduled, base)?;
+        sandbox.benchmark_fn(pass_impact::entry(&compiled)?, input).cycles_per_op
+    };
+    Ok(ScheduleReport {
+        cpu: CpuFeatures::detect(),
+        schedule,
+        functions,
+        cycles_per_op,
+        baseline_cycles_per_op,
+    })
+}
+
+/// Learned schedule for one script
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PassScheduleEntry {
+    /// Path the schedule was last learned from
+    pub script: String,
+    pub schedule: PassSchedule,
+    /// Optimization level it was learned at
+    pub opt_level: u8,
+    pub cycles_per_op: u64,
+    pub baseline_cycles_per_op: u64,
+    /// `CpuFeatures::summary` of the machine that measured it
+    pub cpu: String,
+}
+
+impl PassScheduleEntry {
+    /// How many times faster than with every pass
+    pub fn speedup(&self) -> f64 {
+        self.baseline_cycles_per_op as f64 / self.cycles_per_op.max(1) as f64
+    }
+}
+
+/// Learned schedules by script hash, persisted as JSON
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct PassScheduleStore {
+    pub entries: BTreeMap<String, PassScheduleEntry>,
+}
+
+impl PassScheduleStore {
+    pub const SCHEMA: Schema = Schema::new("pass-schedules", 1);
+
+    /// `$HOME/.nanoforge/pass_schedules.json`
+    pub fn default_path() -> PathBuf {
+        store::default_dir().join("pass_schedules.json")
+    }
+
+    /// Load the store at `path`; a missing file is an empty store
+    pub fn open(path: &Path) -> Result<Self, String> {
+        store::load_or_default(path, Self::SCHEMA)
+    }
+
+    pub fn save_to_file(&self, path: &Path) -> Result<(), String> {
+        store::save(path, Self::SCHEMA, self)
+    }
+
+    /// Entry for this exact `source`, if a schedule was learned for it
+    pub fn lookup(&self, source: &str) -> Option<&PassScheduleEntry> {
+        self.entries.get(&script_hash(source))
+    }
+
+    pub fn record(&mut self, source: &str, entry: PassScheduleEntry) {
+        self.entries.insert(script_hash(source), entry);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    const SOURCE: &str = "fn main(n) {
+        s = sum(n)
+        return s
+    }
+    fn sum(n) {
+        i = 0
+        s = 0
+        label loop
+        if i >= n goto done
+        s = s + i
+        i = i + 1
+        goto loop
+        label done
+        return s
+    }";
+
+    #[test]
+    fn test_schedule_hint_matches_disabling_the_pass() {
+        let program = Parser::new().parse(SOURCE).unwrap();
+        let options = CompileOptions::new(3);
+        let mut schedule = PassSchedule::default();
+        schedule.set("sum", PassSet::default().with(Pass::LoopUnrolling));
+        let mut scheduled = program.clone();
+        schedule.apply(&mut scheduled);
+        assert_eq!(scheduled.functions[1].hints.disabled_passes.passes(), vec![Pass::LoopUnrolling]);
+        assert!(scheduled.functions[0].hints.disabled_passes.is_empty());
+
+        let hinted = CompiledProgram::compile(&scheduled, &options).unwrap();
+        let everywhere = CompiledProgram::compile(&program, &options.disable_pass(Pass::LoopUnrolling)).unwrap();
+        let plain = CompiledProgram::compile(&program, &options).unwrap();
+        let code = |c: &CompiledProgram| c.code().code.clone();
+        assert_ne!(code(&hinted), code(&plain));
+        let main = |c: &CompiledProgram| pass_impact::entry(c).unwrap()(100);
+        assert_eq!((main(&hinted), main(&everywhere)), (4950, 4950));
+
+        assert_eq!(arm_name(PassSet::default()), "all passes");
+        let two = PassSet::default().with(Pass::Schedule).with(Pass::LoopUnrolling);
+        assert_eq!(arm_name(two), "no loop_unrolling, no schedule");
+        schedule.set("sum", PassSet::default());
+        assert_eq!(schedule, PassSchedule::default());
+    }
+
+    #[test]
+    fn test_learn_and_persist() {
+        let program = Parser::new().parse(SOURCE).unwrap();
+        let options = SoaeOptions::default().measurement(2, 10).input(100).seed(Some(7));
+        let report = learn(&program, &CompileOptions::new(3), 5, &options).unwrap();
+        let sum = report.functions.iter().find(|f| f.function == "sum").unwrap();
+        assert!(sum.arms.len() > 1 && sum.arms[0].is_empty());
+        assert!(sum.arms.contains(&sum.chosen));
+        assert_eq!(sum.bandit.get_stats().iter().map(|s| s.selections).sum::<u64>(), 5);
+        assert_eq!(report.schedule.disabled_in("sum"), sum.chosen);
+
+        let mut scheduled = program.clone();
+        report.schedule.apply(&mut scheduled);
+        let compiled = CompiledProgram::compile(&scheduled, &CompileOptions::new(3)).unwrap();
+        assert_eq!(pass_impact::entry(&compiled).unwrap()(100), 4950);
+
+        let path = std::env::temp_dir()
+            .join(format!("nf_pass_schedules_{}", std::process::id()))
+            .join("pass_schedules.json");
+        let mut store = PassScheduleStore::open(&path).unwrap();
+        assert!(store.lookup(SOURCE).is_none());
+        store.record(
+            SOURCE,
+            PassScheduleEntry {
+                script: "sum.nf".to_string(),
+                schedule: report.schedule.clone(),
+                opt_level: 3,
+                cycles_per_op: report.cycles_per_op,
+                baseline_cycles_per_op: report.baseline_cycles_per_op,
+                cpu: report.cpu.summary(),
+            },
+        );
+        store.save_to_file(&path).unwrap();
+        let reopened = PassScheduleStore::open(&path).unwrap();
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+        assert_eq!(reopened.lookup(SOURCE).unwrap().schedule, report.schedule);
+    }
+}
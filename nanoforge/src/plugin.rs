@@ -0,0 +1,194 @@
+//! Optimization Pass Plugins
+//!
+//! A plugin is a shared library that rewrites a whole program before it is
+//! compiled, so new passes can be tried without forking the crate. The IR
+//! crosses the boundary as JSON (the serde form of `ir::Program`), so a
+//! plugin may be written in any language and needn't match this build's
+//! Rust compiler. It exports two C functions:
+//!
+//! ```c
+//! // `program` is the IR and `options` the `CompileOptions`, both JSON.
+//! // Returns the rewritten program as JSON, or {"error": "..."}.
+//! char *nanoforge_plugin_run(const char *program, const char *options);
+//! // Frees a string returned by nanoforge_plugin_run
+//! void nanoforge_plugin_free(char *result);
+//! ```
+//!
+//! A returned program must pass `verifier::verify_program`; a plugin can't
+//! hand the backend IR it would miscompile.
+
+use crate::compiler::CompileOptions;
+use crate::ir::Program;
+use crate::verifier;
+use std::ffi::{c_char, c_void, CStr, CString};
+
+type RunFn = unsafe extern "C" fn(*const c_char, *const c_char) -> *mut c_char;
+type FreeFn = unsafe extern "C" fn(*mut c_char);
+
+/// A loaded plugin library; unloaded on drop
+pub struct Plugin {
+    path: String,
+    handle: *mut c_void,
+    run: RunFn,
+    free: FreeFn,
+}
+
+impl Plugin {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let c_path = CString::new(path).map_err(|_| format!("bad plugin path {:?}", path))?;
+        unsafe {
+            let handle = libc::dlopen(c_path.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL);
+            if handle.is_null() {
+                return Err(format!("failed to load plugin {}: {}", path, dl_error()));
+            }
+            let symbols = sym(handle, c"nanoforge_plugin_run")
+                .and_then(|run| Ok((run, sym(handle, c"nanoforge_plugin_free")?)));
+            match symbols {
+                Ok((run, free)) => Ok(Self {
+                    path: path.to_string(),
+                    handle,
+                    run,
+                    free,
+                }),
+                Err(missing) => {
+                    libc::dlclose(handle);
+                    Err(format!("plugin {} does not export {}", path, missing))
+                }
+            }
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Run the plugin over `prog`, replacing it with the plugin's output
+    pub fn run(&self, prog: &mut Program, options: &CompileOptions) -> Result<(), String> {
+        let to_c = |json: String| CString::new(json).map_err(|e| e.to_string());
+        let program = to_c(serde_json::to_string(prog).map_err(|e| e.to_string())?)?;
+        let options = to_c(serde_json::to_string(options).map_err(|e| e.to_string())?)?;
+
+        let output = unsafe {
+            let raw = (self.run)(program.as_ptr(), options.as_ptr());
+            if raw.is_null() {
+                return Err(format!("plugin {} returned nothing", self.path));
+            }
+            let output = CStr::from_ptr(raw).to_string_lossy().into_owned();
+            (self.free)(raw);
+            output
+        };
+
+        let value: serde_json::Value = serde_json::from_str(&output)
+            .map_err(|e| format!("plugin {} returned malformed JSON: {}", self.path, e))?;
+        if let Some(error) = value.get("error") {
+            let error = error.as_str().map_or_else(|| error.to_string(), str::to_string);
+            return Err(format!("plugin {}: {}", self.path, error));
+        }
+        let rewritten: Program = serde_json::from_value(value)
+            .map_err(|e| format!("plugin {} returned an invalid program: {}", self.path, e))?;
+        verifier::verify_program(&rewritten)
+            .map_err(|e| format!("plugin {} produced IR that fails verification:\n{}", self.path, e))?;
+        *prog = rewritten;
+        Ok(())
+    }
+}
+
+impl Drop for Plugin {
+    fn drop(&mut self) {
+        unsafe { libc::dlclose(self.handle) };
+    }
+}
+
+/// Run every plugin over `prog`, in order
+pub fn run_all(plugins: &[Plugin], prog: &mut Program, options: &CompileOptions) -> Result<(), String> {
+    for plugin in plugins {
+        plugin.run(prog, options)?;
+        tracing::info!("Applied plugin {}", plugin.path());
+    }
+    Ok(())
+}
+
+/// Look up `name` in `handle` as a function pointer of type `F`, or the
+/// missing name.
+///
+/// # Safety
+/// `F` must be an `extern "C" fn` type matching the symbol's C signature.
+unsafe fn sym<F: Copy>(handle: *mut c_void, name: &CStr) -> Result<F, String> {
+    let ptr = libc::dlsym(handle, name.as_ptr());
+    if ptr.is_null() {
+        return Err(name.to_string_lossy().into_owned());
+    }
+    Ok(std::mem::transmute_copy::<*mut c_void, F>(&ptr))
+}
+
+fn dl_error() -> String {
+    let err = unsafe { libc::dlerror() };
+    if err.is_null() {
+        "unknown error".to_string()
+    } else {
+        unsafe { CStr::from_ptr(err) }.to_string_lossy().into_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiled_program::CompiledProgram;
+    use crate::parser::Parser;
+    use std::path::PathBuf;
+    use std::process::Command;
+
+    /// Build tests/plugins/`name`.c into a shared library
+    fn build(name: &str) -> PathBuf {
+        let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/plugins");
+        let out = std::env::temp_dir().join(format!("nf-{}-{}.so", name, std::process::id()));
+        let status = Command::new("cc")
+            .args(["-shared", "-fPIC", "-o"])
+            .arg(&out)
+            .arg(dir.join(format!("{}.c", name)))
+            .status()
+            .expect("cc is needed to build test plugins");
+        assert!(status.success());
+        out
+    }
+
+    #[test]
+    fn test_plugin_rewrites_program() {
+        let path = build("add_to_sub");
+        let plugin = Plugin::load(path.to_str().unwrap()).unwrap();
+        let src = "fn main() {
+            a = 50
+            b = 8
+            c = a + b
+            return c
+        }";
+        let mut prog = Parser::new().parse(src).unwrap();
+        let options = CompileOptions::new(0);
+        run_all(std::slice::from_ref(&plugin), &mut prog, &options).unwrap();
+        let compiled = CompiledProgram::compile(&prog, &options).unwrap();
+        let main: extern "C" fn() -> i64 = compiled.get("main").unwrap();
+        assert_eq!(main(), 42);
+
+        // The plugin sees the options, and its errors come back
+        let err = plugin.run(&mut prog, &options.sanitize(true)).unwrap_err();
+        assert!(err.ends_with(": sanitized builds are not supported"), "{}", err);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_rejects_broken_plugins() {
+        assert!(Plugin::load("/nonexistent/plugin.so")
+            .err()
+            .unwrap()
+            .starts_with("failed to load plugin"));
+
+        let path = build("bad_jump");
+        let plugin = Plugin::load(path.to_str().unwrap()).unwrap();
+        let mut prog = Parser::new().parse("fn main() {\nreturn 1\n}").unwrap();
+        let before = prog.functions[0].instructions.clone();
+        let err = plugin.run(&mut prog, &CompileOptions::new(0)).unwrap_err();
+        assert!(err.contains("fails verification"), "{}", err);
+        assert_eq!(prog.functions[0].instructions, before);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -31,7 +31,27 @@ pub struct PerfEventAttr {
 }
 
 const PERF_TYPE_HARDWARE: u32 = 0;
+const PERF_COUNT_HW_CPU_CYCLES: u64 = 0;
 const PERF_COUNT_HW_INSTRUCTIONS: u64 = 1;
+const PERF_COUNT_HW_BRANCH_INSTRUCTIONS: u64 = 4;
+const PERF_COUNT_HW_BRANCH_MISSES: u64 = 5;
+
+// PERF_TYPE_HW_CACHE config: id | (op << 8) | (result << 16)
+const PERF_TYPE_HW_CACHE: u32 = 3;
+const PERF_COUNT_HW_CACHE_L1I: u64 = 1;
+const PERF_COUNT_HW_CACHE_OP_READ: u64 = 0;
+const PERF_COUNT_HW_CACHE_RESULT_MISS: u64 = 1;
+const L1I_READ_MISS: u64 = PERF_COUNT_HW_CACHE_L1I
+    | (PERF_COUNT_HW_CACHE_OP_READ << 8)
+    | (PERF_COUNT_HW_CACHE_RESULT_MISS << 16);
+
+// attr.read_format bits
+const PERF_FORMAT_GROUP: u64 = 1 << 3;
+
+// attr.flags bits
+const FLAG_DISABLED: u64 = 1 << 0;
+const FLAG_EXCLUDE_KERNEL: u64 = 1 << 5;
+const FLAG_EXCLUDE_HV: u64 = 1 << 6;
 
 extern "C" {
     fn syscall(number: c_long, ...) -> c_long;
@@ -39,53 +59,76 @@ extern "C" {
 
 const SYS_PERF_EVENT_OPEN: c_long = 298; // x86_64
 
+/// Open the event `attr` describes for `pid` (0: this thread) on any CPU
+pub(crate) fn perf_event_open(
+    attr: &PerfEventAttr,
+    pid: i32,
+    group_fd: c_int,
+) -> Result<c_int, String> {
+    // cpu = -1 (any cpu), flags = 0
+    let fd = unsafe {
+        syscall(
+            SYS_PERF_EVENT_OPEN,
+            attr as *const PerfEventAttr,
+            pid,
+            -1,
+            group_fd,
+            0,
+        )
+    };
+    if fd < 0 {
+        return Err(format!(
+            "perf_event_open failed: {}",
+            Error::last_os_error()
+        ));
+    }
+    Ok(fd as c_int)
+}
+
 pub struct Profiler {
     fd: c_int,
 }
 
 impl Profiler {
     pub fn new_instruction_counter(pid: i32) -> Result<Self, String> {
-        Self::new(PERF_TYPE_HARDWARE, PERF_COUNT_HW_INSTRUCTIONS, pid)
+        Self::new(PERF_TYPE_HARDWARE, PERF_COUNT_HW_INSTRUCTIONS, pid, FLAG_DISABLED)
     }
 
-    fn new(type_: u32, config: u64, pid: i32) -> Result<Self, String> {
+    /// User-space-only hardware counter; works with perf_event_paranoid <= 2
+    fn new_user_counter(config: u64) -> Result<Self, String> {
+        Self::new_user_event(PERF_TYPE_HARDWARE, config)
+    }
+
+    fn new_user_event(type_: u32, config: u64) -> Result<Self, String> {
+        Self::new(
+            type_,
+            config,
+            0,
+            FLAG_DISABLED | FLAG_EXCLUDE_KERNEL | FLAG_EXCLUDE_HV,
+        )
+    }
+
+    fn new(type_: u32, config: u64, pid: i32, flags: u64) -> Result<Self, String> {
+        Self::open(type_, config, pid, flags, 0, -1)
+    }
+
+    fn open(
+        type_: u32,
+        config: u64,
+        pid: i32,
+        flags: u64,
+        read_format: u64,
+        group_fd: c_int,
+    ) -> Result<Self, String> {
         let mut attr: PerfEventAttr = unsafe { mem::zeroed() };
         attr.type_ = type_;
+        attr.read_format = read_format;
         attr.size = mem::size_of::<PerfEventAttr>() as u32;
         attr.config = config;
-        attr.flags = 1; // disabled = 1 (start disabled)
-                        // We also want exclude_kernel = 1, exclude_hv = 1.
-                        // Bit 1: disabled
-                        // Bit 2: inherit
-                        // Bit 3: pinned
-                        // Bit 4: exclusive
-                        // Bit 5: exclude_user
-                        // Bit 6: exclude_kernel
-                        // Bit 7: exclude_hv
-                        // ...
-                        // 1 | (1 << 5) | (1 << 6) is messy.
-                        // Let's just set disabled=1 for now.
-
-        // pid = 0 (current process), cpu = -1 (any cpu), group_fd = -1, flags = 0
-        let fd = unsafe {
-            syscall(
-                SYS_PERF_EVENT_OPEN,
-                &attr as *const PerfEventAttr,
-                pid,
-                -1,
-                -1,
-                0,
-            )
-        };
-
-        if fd < 0 {
-            return Err(format!(
-                "perf_event_open failed: {}",
-                Error::last_os_error()
-            ));
-        }
+        attr.flags = flags; // FLAG_* bits, e.g. start disabled and count user space only
 
-        Ok(Profiler { fd: fd as c_int })
+        let fd = perf_event_open(&attr, pid, group_fd)?;
+        Ok(Profiler { fd })
     }
 
     pub fn enable(&self) {
@@ -99,6 +142,11 @@ impl Profiler {
         unsafe { libc::ioctl(self.fd, PERF_EVENT_IOC_DISABLE as _, 0) };
     }
 
+    pub fn reset(&self) {
+        const PERF_EVENT_IOC_RESET: c_long = 0x2403;
+        unsafe { libc::ioctl(self.fd, PERF_EVENT_IOC_RESET as _, 0) };
+    }
+
     pub fn read(&self) -> u64 {
         let mut count: u64 = 0;
         let ret = unsafe {
@@ -121,6 +169,248 @@ impl Drop for Profiler {
     }
 }
 
+/// Hardware counters collected around a single run (`--stats`)
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PerfStats {
+    pub instructions: Option<u64>,
+    pub cycles: Option<u64>,
+    pub branches: Option<u64>,
+    pub branch_misses: Option<u64>,
+}
+
+impl PerfStats {
+    /// Instructions per cycle
+    pub fn ipc(&self) -> Option<f64> {
+        match (self.instructions, self.cycles) {
+            (Some(i), Some(c)) if c > 0 => Some(i as f64 / c as f64),
+            _ => None,
+        }
+    }
+
+    /// Fraction of branches that were mispredicted
+    pub fn branch_miss_rate(&self) -> Option<f64> {
+        match (self.branch_misses, self.branches) {
+            (Some(m), Some(b)) if b > 0 => Some(m as f64 / b as f64),
+            _ => None,
+        }
+    }
+
+    /// `perf stat`-style summary; counters the kernel refused show as "not supported"
+    pub fn summary(&self) -> String {
+        fn count(v: Option<u64>) -> String {
+            v.map_or("not supported".to_string(), |v| v.to_string())
+        }
+
+        let mut out = String::new();
+        out.push_str("Performance counter stats:\n");
+        out.push_str(&format!("  {:>16}  instructions\n", count(self.instructions)));
+        out.push_str(&format!("  {:>16}  cycles\n", count(self.cycles)));
+        match self.ipc() {
+            Some(ipc) => out.push_str(&format!("  {:>16.2}  insn per cycle\n", ipc)),
+            None => out.push_str(&format!("  {:>16}  insn per cycle\n", "-")),
+        }
+        out.push_str(&format!("  {:>16}  branches\n", count(self.branches)));
+        match self.branch_miss_rate() {
+            Some(rate) => out.push_str(&format!(
+                "  {:>16}  branch-misses ({:.2}% of all branches)\n",
+                count(self.branch_misses),
+                rate * 100.0
+            )),
+            None => out.push_str(&format!(
+                "  {:>16}  branch-misses\n",
+                count(self.branch_misses)
+            )),
+        }
+        out
+    }
+}
+
+/// Instructions, cycles, branches and branch misses for the current thread.
+///
+/// Each counter is opened independently, so a PMU that lacks one event
+/// (common in VMs) still reports the rest.
+pub struct PerfCounters {
+    instructions: Option<Profiler>,
+    cycles: Option<Profiler>,
+    branches: Option<Profiler>,
+    branch_misses: Option<Profiler>,
+}
+
+impl PerfCounters {
+    /// Open the counters. Fails only if none of them are available.
+    pub fn new() -> Result<Self, String> {
+        let open = |config| Profiler::new_user_counter(config);
+        let (instructions, error) = match open(PERF_COUNT_HW_INSTRUCTIONS) {
+            Ok(p) => (Some(p), None),
+            Err(e) => (None, Some(e)),
+        };
+        let counters = PerfCounters {
+            instructions,
+            cycles: open(PERF_COUNT_HW_CPU_CYCLES).ok(),
+            branches: open(PERF_COUNT_HW_BRANCH_INSTRUCTIONS).ok(),
+            branch_misses: open(PERF_COUNT_HW_BRANCH_MISSES).ok(),
+        };
+
+        match error {
+            Some(e) if counters.all().next().is_none() => Err(e),
+            _ => Ok(counters),
+        }
+    }
+
+    fn all(&self) -> impl Iterator<Item = &Profiler> {
+        [
+            &self.instructions,
+            &self.cycles,
+            &self.branches,
+            &self.branch_misses,
+        ]
+        .into_iter()
+        .flatten()
+    }
+
+    /// Run `f` with the counters enabled
+    pub fn measure<R>(&self, f: impl FnOnce() -> R) -> (R, PerfStats) {
+        for p in self.all() {
+            p.enable();
+        }
+        let result = f();
+        for p in self.all() {
+            p.disable();
+        }
+        (result, self.stats())
+    }
+
+    fn stats(&self) -> PerfStats {
+        PerfStats {
+            instructions: self.instructions.as_ref().map(|p| p.read()),
+            cycles: self.cycles.as_ref().map(|p| p.read()),
+            branches: self.branches.as_ref().map(|p| p.read()),
+            branch_misses: self.branch_misses.as_ref().map(|p| p.read()),
+        }
+    }
+}
+
+/// L1 instruction-cache misses over a measured block
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ICacheStats {
+    pub misses: u64,
+    pub instructions: Option<u64>,
+}
+
+impl ICacheStats {
+    /// L1I misses per thousand instructions
+    pub fn mpki(&self) -> Option<f64> {
+        match self.instructions {
+            Some(i) if i > 0 => Some(self.misses as f64 * 1000.0 / i as f64),
+            _ => None,
+        }
+    }
+}
+
+/// L1I read misses (and instructions, to turn them into a rate) for the
+/// current thread. Unrolled variants that win on cycles can still lose in a
+/// real program once their code stops fitting the instruction cache.
+pub struct ICacheCounters {
+    misses: Profiler,
+    instructions: Option<Profiler>,
+}
+
+impl ICacheCounters {
+    /// Fails if the PMU has no L1I miss event (common in VMs)
+    pub fn new() -> Result<Self, String> {
+        Ok(Self {
+            misses: Profiler::new_user_event(PERF_TYPE_HW_CACHE, L1I_READ_MISS)?,
+            instructions: Profiler::new_user_counter(PERF_COUNT_HW_INSTRUCTIONS).ok(),
+        })
+    }
+
+    fn all(&self) -> impl Iterator<Item = &Profiler> {
+        std::iter::once(&self.misses).chain(&self.instructions)
+    }
+
+    /// Zero and start counting
+    pub fn start(&self) {
+        for p in self.all() {
+            p.reset();
+            p.enable();
+        }
+    }
+
+    /// Stop counting and read the totals since `start`
+    pub fn stop(&self) -> ICacheStats {
+        for p in self.all() {
+            p.disable();
+        }
+        ICacheStats {
+            misses: self.misses.read(),
+            instructions: self.instructions.as_ref().map(|p| p.read()),
+        }
+    }
+}
+
+/// One reading of a `CounterGroup`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CounterSample {
+    pub cycles: u64,
+    pub instructions: Option<u64>,
+}
+
+/// Cycles and instructions opened as one perf event group, so a single
+/// `read` returns both counts for the same interval (PERF_FORMAT_GROUP).
+///
+/// Counting starts as soon as the group is opened. If the PMU has no
+/// instructions event the group carries cycles alone.
+pub struct CounterGroup {
+    leader: Profiler,
+    instructions: Option<Profiler>,
+}
+
+impl CounterGroup {
+    pub fn new() -> Result<Self, String> {
+        let flags = FLAG_EXCLUDE_KERNEL | FLAG_EXCLUDE_HV;
+        let leader = Profiler::open(
+            PERF_TYPE_HARDWARE,
+            PERF_COUNT_HW_CPU_CYCLES,
+            0,
+            flags,
+            PERF_FORMAT_GROUP,
+            -1,
+        )?;
+        let instructions = Profiler::open(
+            PERF_TYPE_HARDWARE,
+            PERF_COUNT_HW_INSTRUCTIONS,
+            0,
+            flags,
+            PERF_FORMAT_GROUP,
+            leader.fd,
+        )
+        .ok();
+        Ok(Self {
+            leader,
+            instructions,
+        })
+    }
+
+    pub fn read(&self) -> CounterSample {
+        // Group layout: { nr, values[nr] }
+        let mut buf = [0u64; 3];
+        let len = unsafe {
+            libc::read(
+                self.leader.fd,
+                buf.as_mut_ptr() as *mut c_void,
+                mem::size_of_val(&buf),
+            )
+        };
+        if len < 2 * mem::size_of::<u64>() as isize {
+            return CounterSample::default();
+        }
+        CounterSample {
+            cycles: buf[1],
+            instructions: (self.instructions.is_some() && buf[0] >= 2).then_some(buf[2]),
+        }
+    }
+}
+
 pub trait ProfileSource: Send + Sync {
     fn read(&self) -> u64;
     fn enable(&self);
@@ -201,3 +491,54 @@ impl ProfileSource for RemoteProfiler {
         // Daemon cleans up on connection close
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_perf_stats_summary() {
+        let stats = PerfStats {
+            instructions: Some(2000),
+            cycles: Some(1000),
+            branches: Some(400),
+            branch_misses: Some(4),
+        };
+        assert_eq!(stats.ipc(), Some(2.0));
+        assert_eq!(stats.branch_miss_rate(), Some(0.01));
+        let text = stats.summary();
+        assert!(text.contains("2.00  insn per cycle"), "{}", text);
+        assert!(text.contains("1.00% of all branches"), "{}", text);
+
+        // Missing events (e.g. no PMU in a VM) are reported, not faked
+        let partial = PerfStats {
+            instructions: Some(10),
+            ..Default::default()
+        };
+        assert_eq!(partial.ipc(), None);
+        assert!(partial.summary().contains("not supported"));
+    }
+
+    #[test]
+    fn test_icache_counters() {
+        let stats = ICacheStats {
+            misses: 30,
+            instructions: Some(20_000),
+        };
+        assert_eq!(stats.mpki(), Some(1.5));
+        assert_eq!(ICacheStats::default().mpki(), None);
+
+        // Without PMU access (VMs, paranoid kernels) the counters just fail to open
+        if let Ok(counters) = ICacheCounters::new() {
+            counters.start();
+            let mut sum = 0u64;
+            for i in 0..10_000u64 {
+                sum = std::hint::black_box(sum.wrapping_add(i));
+            }
+            let stats = counters.stop();
+            if let Some(instructions) = stats.instructions {
+                assert!(instructions >= 10_000, "{:?}", stats);
+            }
+        }
+    }
+}
@@ -12,9 +12,13 @@
 //!
 //! # Create AI optimizer
 //! opt = nanoforge.Optimizer()
+//! opt.log_to("brain.log")  # optional: `nanoforge analyze brain.log`
 //! variant = opt.select(input_size=10000)
 //! opt.update(input_size=10000, variant_idx=variant, cycles=1000, best_cycles=800)
 //! opt.save("brain.json")
+//!
+//! # Rank the variants of a script: [(variant, cycles/op, code bytes), ...]
+//! print(nanoforge.soae(open("kernel.nf").read(), input=1000))
 //! ```
 
 #![cfg(feature = "python")]
@@ -25,18 +29,35 @@ use std::path::Path;
 
 use crate::ai_optimizer::{ContextualBandit, OptimizationFeatures, SizeBucket};
 use crate::array_ops;
+use crate::brain_log::BrainLog;
 use crate::cpu_features::CpuFeatures;
+use crate::parallel::ExecutionPool;
 use crate::parser::Parser;
-use crate::variant_generator::VariantGenerator;
+use crate::soae::SoaeOptions;
+use crate::variant_generator::{IsaExtension, VariantConfig, VariantGenerator};
 
-use numpy::{PyArray1, PyReadonlyArray1, PyReadwriteArray1};
+use numpy::{PyArray1, PyArray2, PyReadonlyArray1, PyReadonlyArray2, PyReadwriteArray1};
 use std::time::Instant;
 
+/// Variants the Python optimizer chooses between, in index order
+fn optimizer_configs() -> Vec<VariantConfig> {
+    vec![
+        VariantConfig::new(IsaExtension::Scalar, 1, 1),
+        VariantConfig::new(IsaExtension::Scalar, 2, 2),
+        VariantConfig::new(IsaExtension::Scalar, 4, 2),
+        VariantConfig::new(IsaExtension::Avx2, 2, 3),
+        VariantConfig::new(IsaExtension::Avx2, 4, 3),
+        VariantConfig::new(IsaExtension::Avx2, 8, 3),
+    ]
+}
+
 /// Python-exposed AI Optimizer using Contextual Bandit
 #[pyclass]
 pub struct Optimizer {
     bandit: ContextualBandit,
     variant_names: Vec<String>,
+    /// Training log enabled by `log_to`
+    log: Option<BrainLog>,
 }
 
 #[pymethods]
@@ -44,36 +65,32 @@ impl Optimizer {
     /// Create a new optimizer
     #[new]
     pub fn new() -> Self {
-        let variant_names = vec![
-            "Scalarx1".to_string(),
-            "Scalarx2".to_string(),
-            "Scalarx4".to_string(),
-            "AVX2x2".to_string(),
-            "AVX2x4".to_string(),
-            "AVX2x8".to_string(),
-        ];
-        let bandit = ContextualBandit::new(variant_names.clone());
+        let configs = optimizer_configs();
+        let variant_names: Vec<String> = configs.iter().map(|c| c.name.clone()).collect();
+        let mut bandit = ContextualBandit::new(variant_names.clone());
+        bandit.warm_start(&configs);
         Self {
             bandit,
             variant_names,
+            log: None,
         }
     }
 
     /// Load optimizer from file (or create new if not exists)
     #[staticmethod]
     pub fn load(path: &str) -> PyResult<Self> {
-        let variant_names = vec![
-            "Scalarx1".to_string(),
-            "Scalarx2".to_string(),
-            "Scalarx4".to_string(),
-            "AVX2x2".to_string(),
-            "AVX2x4".to_string(),
-            "AVX2x8".to_string(),
-        ];
-        let bandit = ContextualBandit::load_or_new(Path::new(path), variant_names.clone());
+        let configs = optimizer_configs();
+        let variant_names: Vec<String> = configs.iter().map(|c| c.name.clone()).collect();
+        let fresh = !Path::new(path).exists();
+        let mut bandit = ContextualBandit::load_or_new(Path::new(path), variant_names.clone());
+        // Saved knowledge already reflects measurements; only a new bandit needs the prior
+        if fresh {
+            bandit.warm_start(&configs);
+        }
         Ok(Self {
             bandit,
             variant_names,
+            log: None,
         })
     }
 
@@ -84,10 +101,28 @@ impl Optimizer {
     }
 
     /// Update optimizer with performance feedback
-    pub fn update(&mut self, input_size: u64, variant_idx: usize, cycles: u64, best_cycles: u64) {
+    pub fn update(
+        &mut self,
+        input_size: u64,
+        variant_idx: usize,
+        cycles: u64,
+        best_cycles: u64,
+    ) -> PyResult<()> {
         let features = OptimizationFeatures::new(input_size);
         self.bandit
             .update_with_performance(&features, variant_idx, cycles, best_cycles);
+        if let Some(log) = self.log.as_mut() {
+            let variant = self.variant_names.get(variant_idx).cloned().unwrap_or_default();
+            log.record(&features, variant_idx, &variant, cycles, best_cycles)
+                .map_err(PyValueError::new_err)?;
+        }
+        Ok(())
+    }
+
+    /// Append every update to a JSONL training log (read it with `nanoforge analyze`)
+    pub fn log_to(&mut self, path: &str) -> PyResult<()> {
+        self.log = Some(BrainLog::append(Path::new(path), "python").map_err(PyValueError::new_err)?);
+        Ok(())
     }
 
     /// Save optimizer state to file
@@ -138,6 +173,29 @@ impl CompiledFunction {
         self.execute(input)
     }
 
+    /// Execute the function on every element of an int64 array, looping in
+    /// JIT code instead of crossing into it once per input
+    ///
+    /// Example:
+    /// ```python
+    /// f = nanoforge.compile(open("kernel.nf").read())
+    /// out = f.execute_batch(np.arange(1000, dtype=np.int64))
+    /// ```
+    pub fn execute_batch<'py>(
+        &self,
+        py: Python<'py>,
+        inputs: PyReadonlyArray1<'py, i64>,
+    ) -> PyResult<&'py PyArray1<i64>> {
+        let inputs = inputs
+            .as_slice()
+            .map_err(|e| PyValueError::new_err(format!("Array not contiguous: {}", e)))?;
+        let mut outputs = vec![0; inputs.len()];
+        self.variant
+            .execute_batch(inputs, &mut outputs)
+            .map_err(PyValueError::new_err)?;
+        Ok(PyArray1::from_vec(py, outputs))
+    }
+
     /// Get the variant name
     pub fn name(&self) -> String {
         self.variant.config.name.clone()
@@ -162,6 +220,10 @@ pub fn cpu_info() -> std::collections::HashMap<String, bool> {
     map.insert("avx2".to_string(), features.has_avx2);
     map.insert("avx512f".to_string(), features.has_avx512f);
     map.insert("amx_tile".to_string(), features.has_amx_tile);
+    map.insert("amx_int8".to_string(), features.has_amx_int8);
+    map.insert("popcnt".to_string(), features.has_popcnt);
+    map.insert("bmi1".to_string(), features.has_bmi1);
+    map.insert("lzcnt".to_string(), features.has_lzcnt);
     map
 }
 
@@ -261,6 +323,101 @@ pub fn vec_scale(mut arr: PyReadwriteArray1<i64>, scalar: i64) -> PyResult<()> {
     Ok(())
 }
 
+/// Shapes (m, k, n) of `a @ b` into `c`, checked against each other
+fn matmul_shape(a: &[usize], b: &[usize], c: &[usize]) -> PyResult<(usize, usize, usize)> {
+    let (m, k, n) = (a[0], a[1], b[1]);
+    if b[0] != k || c != [m, n] {
+        return Err(PyValueError::new_err(format!(
+            "Matrix shape mismatch: a={:?}, b={:?}, c={:?}",
+            a, b, c
+        )));
+    }
+    Ok((m, k, n))
+}
+
+fn matmul_kernel(name: &str) -> PyResult<array_ops::MatmulKernel> {
+    array_ops::MatmulKernel::from_name(name)
+        .ok_or_else(|| PyValueError::new_err(format!("Unknown matmul kernel '{}'", name)))
+}
+
+/// Matrix multiply C = A @ B for int64 matrices
+///
+/// The kernel (scalar or AVX2) is learned per matrix size unless one is
+/// named explicitly.
+///
+/// Example:
+/// ```python
+/// import numpy as np
+/// import nanoforge
+/// a = np.arange(6, dtype=np.int64).reshape(2, 3)
+/// b = np.ones((3, 4), dtype=np.int64)
+/// c = np.zeros((2, 4), dtype=np.int64)
+/// nanoforge.matmul(a, b, c)
+/// nanoforge.matmul(a, b, c, kernel="scalar")
+/// ```
+#[pyfunction]
+#[pyo3(signature = (a, b, c, kernel=None))]
+pub fn matmul<'py>(
+    a: PyReadonlyArray2<'py, i64>,
+    b: PyReadonlyArray2<'py, i64>,
+    c: &PyArray2<i64>,
+    kernel: Option<&str>,
+) -> PyResult<()> {
+    let (m, k, n) = matmul_shape(a.shape(), b.shape(), c.shape())?;
+    let a_slice = a
+        .as_slice()
+        .map_err(|e| PyValueError::new_err(format!("Array a not contiguous: {}", e)))?;
+    let b_slice = b
+        .as_slice()
+        .map_err(|e| PyValueError::new_err(format!("Array b not contiguous: {}", e)))?;
+    let c_slice = unsafe { c.as_slice_mut() }
+        .map_err(|e| PyValueError::new_err(format!("Array c not contiguous: {}", e)))?;
+
+    match kernel {
+        Some(name) => array_ops::matmul_i64_with(matmul_kernel(name)?, a_slice, b_slice, c_slice, m, k, n),
+        None => array_ops::matmul_i64(a_slice, b_slice, c_slice, m, k, n),
+    }
+    .map_err(PyValueError::new_err)
+}
+
+/// Matrix multiply C = A @ B for int8 matrices, accumulating into int32
+///
+/// Uses AMX tiles when the CPU has them (and they win for the size).
+#[pyfunction]
+#[pyo3(signature = (a, b, c, kernel=None))]
+pub fn matmul_i8<'py>(
+    a: PyReadonlyArray2<'py, i8>,
+    b: PyReadonlyArray2<'py, i8>,
+    c: &PyArray2<i32>,
+    kernel: Option<&str>,
+) -> PyResult<()> {
+    let (m, k, n) = matmul_shape(a.shape(), b.shape(), c.shape())?;
+    let a_slice = a
+        .as_slice()
+        .map_err(|e| PyValueError::new_err(format!("Array a not contiguous: {}", e)))?;
+    let b_slice = b
+        .as_slice()
+        .map_err(|e| PyValueError::new_err(format!("Array b not contiguous: {}", e)))?;
+    let c_slice = unsafe { c.as_slice_mut() }
+        .map_err(|e| PyValueError::new_err(format!("Array c not contiguous: {}", e)))?;
+
+    match kernel {
+        Some(name) => array_ops::matmul_i8_with(matmul_kernel(name)?, a_slice, b_slice, c_slice, m, k, n),
+        None => array_ops::matmul_i8(a_slice, b_slice, c_slice, m, k, n),
+    }
+    .map_err(PyValueError::new_err)
+}
+
+/// Matmul kernels available on this CPU: {"int64": [...], "int8": [...]}
+#[pyfunction]
+pub fn matmul_kernels() -> std::collections::HashMap<String, Vec<String>> {
+    let names = |kernels: Vec<array_ops::MatmulKernel>| kernels.iter().map(|k| k.name().to_string()).collect();
+    let mut map = std::collections::HashMap::new();
+    map.insert("int64".to_string(), names(array_ops::MatmulKernel::available_i64()));
+    map.insert("int8".to_string(), names(array_ops::MatmulKernel::available_i8()));
+    map
+}
+
 /// Benchmark vec_add: returns (nanoforge_ns, numpy_estimated_ns)
 /// This runs NanoForge vec_add and estimates NumPy time based on memory bandwidth
 #[pyfunction]
@@ -316,10 +473,9 @@ pub fn version() -> &'static str {
 
 #[pyfunction]
 pub fn evolve(script: String, generations: u32, population: usize) -> PyResult<(String, f64)> {
-    use crate::assembler::CodeGenerator;
-    use crate::compiler::Compiler;
+    use crate::compiled_program::CompiledProgram;
+    use crate::compiler::CompileOptions;
     use crate::evolution::{EvolutionConfig, EvolutionEngine};
-    use crate::jit_memory::DualMappedMemory;
     use crate::validator::TestCase;
 
     let mut parser = Parser::new();
@@ -338,16 +494,11 @@ pub fn evolve(script: String, generations: u32, population: usize) -> PyResult<(
     println!("🧪 Generating Ground Truth from Seed Code...");
 
     // Compile seed to run it
-    let (code, main_offset) = Compiler::compile_program(&program, 0)
+    let compiled = CompiledProgram::compile(&program, &CompileOptions::new(0))
         .map_err(|e| PyValueError::new_err(format!("Compile error: {}", e)))?;
-
-    let memory = DualMappedMemory::new(code.len() + 4096)
-        .map_err(|_| PyValueError::new_err("Memory alloc failed"))?;
-    CodeGenerator::emit_to_memory(&memory, &code, 0);
-
-    // Cast to function pointer
-    let func_ptr: extern "C" fn(i64) -> i64 =
-        unsafe { std::mem::transmute(memory.rx_ptr.add(main_offset)) };
+    let func_ptr: extern "C" fn(i64) -> i64 = compiled
+        .get("main")
+        .ok_or_else(|| PyValueError::new_err("main() must take at most one parameter"))?;
 
     // inputs to test
     let inputs = vec![10, 100, 1000];
@@ -378,6 +529,7 @@ pub fn evolve(script: String, generations: u32, population: usize) -> PyResult<(
         tournament_size: 5,
         elite_count: 2,
         seed: 42,
+        ..EvolutionConfig::default()
     };
 
     // Create evolution engine
@@ -396,6 +548,77 @@ pub fn evolve(script: String, generations: u32, population: usize) -> PyResult<(
     Ok((best_code, result.final_speedup))
 }
 
+/// Benchmark every variant of a script's `main` at `input`; returns
+/// (variant, cycles/op, code bytes), fastest first
+#[pyfunction]
+#[pyo3(signature = (script, input=1000))]
+pub fn soae(script: &str, input: u64) -> PyResult<Vec<(String, u64, usize)>> {
+    let program = Parser::new()
+        .parse(script)
+        .map_err(|e| PyValueError::new_err(format!("Parse error: {}", e)))?;
+    let report = crate::soae::run(&program, &SoaeOptions::default().input(input))
+        .map_err(PyValueError::new_err)?;
+    Ok(report
+        .rankings
+        .iter()
+        .map(|r| {
+            let code_size = report.variant(&r.variant_name).map_or(0, |v| v.code_size);
+            (r.variant_name.clone(), r.result.cycles_per_op, code_size)
+        })
+        .collect())
+}
+
+/// Let a Thompson Sampling bandit learn the fastest variant of a script;
+/// returns (its final choice, the measured best, the variant tried each round)
+#[pyfunction]
+#[pyo3(signature = (script, iterations=50, input=1000, seed=None))]
+pub fn soae_learn(
+    script: &str,
+    iterations: u32,
+    input: u64,
+    seed: Option<u64>,
+) -> PyResult<(String, String, Vec<String>)> {
+    let program = Parser::new()
+        .parse(script)
+        .map_err(|e| PyValueError::new_err(format!("Parse error: {}", e)))?;
+    let options = SoaeOptions::default()
+        .input(input)
+        .measurement(20, 100)
+        .seed(seed);
+    let report = crate::soae::learn(&program, iterations, &options, &mut None)
+        .map_err(PyValueError::new_err)?;
+    let selections = report
+        .steps
+        .iter()
+        .map(|s| report.variant_names[s.selected].clone())
+        .collect();
+    Ok((report.final_best().to_string(), report.true_best.clone(), selections))
+}
+
+/// Call a compiled function on every element of an int64 array, the
+/// array split across a pool of threads with the GIL released
+///
+/// Example:
+/// ```python
+/// f = nanoforge.compile(open("kernel.nf").read())
+/// out = nanoforge.parallel_map(f, np.arange(1_000_000, dtype=np.int64))
+/// ```
+#[pyfunction]
+pub fn parallel_map<'py>(
+    py: Python<'py>,
+    compiled_fn: &CompiledFunction,
+    inputs: PyReadonlyArray1<'py, i64>,
+) -> PyResult<&'py PyArray1<i64>> {
+    let inputs = inputs
+        .as_slice()
+        .map_err(|e| PyValueError::new_err(format!("Array not contiguous: {}", e)))?;
+    let mut outputs = vec![0; inputs.len()];
+    let variant = &compiled_fn.variant;
+    py.allow_threads(|| variant.execute_parallel(ExecutionPool::global(), inputs, &mut outputs))
+        .map_err(PyValueError::new_err)?;
+    Ok(PyArray1::from_vec(py, outputs))
+}
+
 /// Python module definition
 #[pymodule]
 fn nanoforge(_py: Python, m: &PyModule) -> PyResult<()> {
@@ -404,12 +627,19 @@ fn nanoforge(_py: Python, m: &PyModule) -> PyResult<()> {
     m.add_function(wrap_pyfunction!(cpu_features, m)?)?;
     m.add_function(wrap_pyfunction!(cpu_info, m)?)?;
     m.add_function(wrap_pyfunction!(compile, m)?)?;
+    m.add_function(wrap_pyfunction!(parallel_map, m)?)?;
     m.add_function(wrap_pyfunction!(version, m)?)?;
     // NumPy array operations
     m.add_function(wrap_pyfunction!(vec_add, m)?)?;
     m.add_function(wrap_pyfunction!(vec_sum, m)?)?;
     m.add_function(wrap_pyfunction!(vec_scale, m)?)?;
+    m.add_function(wrap_pyfunction!(matmul, m)?)?;
+    m.add_function(wrap_pyfunction!(matmul_i8, m)?)?;
+    m.add_function(wrap_pyfunction!(matmul_kernels, m)?)?;
     m.add_function(wrap_pyfunction!(benchmark_vec_add, m)?)?;
+    // SOAE
+    m.add_function(wrap_pyfunction!(soae, m)?)?;
+    m.add_function(wrap_pyfunction!(soae_learn, m)?)?;
     // Evolution
     m.add_function(wrap_pyfunction!(evolve, m)?)?;
     Ok(())
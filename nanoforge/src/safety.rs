@@ -1,12 +1,16 @@
+use crate::unwind;
 use std::process;
 use std::sync::Once;
 
 static REGISTER_ONCE: Once = Once::new();
 
+/// Value debug-mode JIT code keeps below each frame (see `CompileOptions::debug`)
+pub const FRAME_CANARY: u64 = 0x4E46_C0DE_CA4A_F00D;
+
 pub fn register_crash_handler() {
     REGISTER_ONCE.call_once(|| unsafe {
         let mut sa: libc::sigaction = std::mem::zeroed();
-        sa.sa_sigaction = handler as usize;
+        sa.sa_sigaction = handler as *const () as usize;
         sa.sa_flags = libc::SA_SIGINFO;
         libc::sigemptyset(&mut sa.sa_mask);
 
@@ -24,9 +28,31 @@ extern "C" fn handler(sig: libc::c_int, info: *mut libc::siginfo_t, _ctx: *mut l
     eprintln!("\n\n!!! CRITICAL FAILURE !!!");
     eprintln!("Caught signal {}: Crash at address {:?}", sig, addr);
     eprintln!("This likely means the JIT-compiled code was invalid or memory was corrupted.");
+    eprintln!("Backtrace:");
+    for (i, frame) in unwind::backtrace().iter().enumerate() {
+        match &frame.jit {
+            Some((name, offset)) => eprintln!("  #{:<2} {:#x}  fn {}+{:#x} (JIT)", i, frame.ip, name, offset),
+            None => eprintln!("  #{:<2} {:#x}", i, frame.ip),
+        }
+    }
     eprintln!("NanoForge is shutting down safely to prevent further damage.\n");
 
     // In a real system, we might try to longjmp out, but that's unsafe in Rust.
     // We just exit with a special code.
     process::exit(139); // Standard exit code for SIGSEGV
 }
+
+/// Called by debug-mode JIT code whose stack canary was overwritten
+///
+/// # Safety
+/// `name` must point to `len` readable bytes (the function name emitted after the check).
+pub unsafe extern "C" fn nf_frame_corrupted(name: *const u8, len: usize) -> ! {
+    let name = unsafe { std::slice::from_raw_parts(name, len) };
+    eprintln!("\n\n!!! STACK CANARY CORRUPTED !!!");
+    eprintln!(
+        "The frame of fn '{}' was overwritten before it returned.",
+        String::from_utf8_lossy(name)
+    );
+    eprintln!("A push/pop mismatch or an out-of-bounds store hit the stack.\n");
+    process::abort();
+}
@@ -2,12 +2,26 @@
 //!
 //! Provides cycle-accurate benchmarking for JIT-compiled code variants.
 //! Uses perf_event counters and RDTSC for precise measurements.
+//!
+//! Measurements are warm: the caches and branch predictors have seen the
+//! kernel many times. With `SandboxConfig::cold_iterations` the sandbox also
+//! times single calls made after evicting the caches (flushing the variant's
+//! code with `clflush` and reading a buffer twice the size of the last-level
+//! cache), and reports them as `BenchmarkResult::cold` next to the warm numbers.
 
 #![allow(dead_code)]
-use crate::profiler::Profiler;
+use crate::distribution::{self, WeightedInput};
+use crate::numa::{self, NumaPlacement};
+use crate::profiler::{ICacheCounters, Profiler};
+use crate::thermal::{ThermalMonitor, ThrottleReport, DEFAULT_FREQ_DROP_THRESHOLD};
+use crate::topology::{self, CpuTopology, PinChoice};
 use crate::variant_generator::CompiledVariant;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
 use std::hint::black_box;
 use std::mem;
+use std::sync::OnceLock;
 use std::time::Instant;
 
 /// Result of benchmarking a single variant
@@ -17,6 +31,24 @@ pub struct BenchmarkResult {
     pub nanoseconds_per_op: u64,
     pub instructions: u64,
     pub iterations: u64,
+    /// CPU the measurement ran on, if pinning succeeded
+    pub cpu: Option<usize>,
+    /// Frequency/temperature around the measured block
+    pub thermal: ThrottleReport,
+    /// Measurement attempts made (more than 1 if throttled runs were retried)
+    pub attempts: u32,
+    /// Achieved relative half-width of the 95% confidence interval of
+    /// cycles_per_op (None with a single batch)
+    pub precision: Option<f64>,
+    /// NUMA node of the CPU and of memory touched during the measurement
+    pub numa: NumaPlacement,
+    /// L1 instruction-cache misses per thousand instructions (None without
+    /// PMU access)
+    pub l1i_mpki: Option<f64>,
+    /// Whether every call returned the same value
+    pub stability: Stability,
+    /// Calls timed from cold caches (None unless `cold_iterations` is set)
+    pub cold: Option<ColdResult>,
 }
 
 impl BenchmarkResult {
@@ -28,12 +60,61 @@ impl BenchmarkResult {
     }
 }
 
+/// Single calls timed right after evicting the caches
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ColdResult {
+    /// Median over the calls
+    pub cycles_per_op: u64,
+    pub nanoseconds_per_op: u64,
+    pub calls: u32,
+}
+
+/// Return values seen while benchmarking. A racy or UB kernel can return
+/// different values for the same input, and must not win on speed alone.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stability {
+    /// What the first call returned
+    pub output: u64,
+    /// Later calls that returned something else
+    pub mismatches: u64,
+    /// The first value that differed from `output`
+    pub first_mismatch: Option<u64>,
+}
+
+impl Stability {
+    pub fn new(output: u64) -> Self {
+        Self {
+            output,
+            ..Default::default()
+        }
+    }
+
+    /// Compare one more call's return value against the first
+    #[inline(always)]
+    pub fn record(&mut self, value: u64) {
+        if value != self.output {
+            self.mismatches += 1;
+            self.first_mismatch.get_or_insert(value);
+        }
+    }
+
+    pub fn is_stable(&self) -> bool {
+        self.mismatches == 0
+    }
+}
+
 /// A ranked variant with benchmark results
 #[derive(Debug)]
 pub struct RankedVariant {
     pub rank: usize,
     pub variant_name: String,
+    /// Measurement at the median of the inputs ranked on (the only one for
+    /// `benchmark_all`)
     pub result: BenchmarkResult,
+    /// Cycles/op averaged over the inputs ranked on, by weight
+    pub expected_cycles: f64,
+    /// Cycles/op at each input ranked on, in order
+    pub cycles_by_input: Vec<u64>,
 }
 
 /// Configuration for the nanosecond sandbox
@@ -41,7 +122,25 @@ pub struct RankedVariant {
 pub struct SandboxConfig {
     pub warmup_iterations: u32,
     pub measurement_iterations: u32,
+    /// CPU to pin to; the default is picked from the detected topology
     pub pin_to_core: Option<usize>,
+    /// Relative frequency drop that marks a measurement as throttled
+    pub throttle_threshold: f64,
+    /// Times to re-run a throttled measurement before accepting it
+    pub throttle_retries: u32,
+    /// Keep measuring in batches of `measurement_iterations` until the 95%
+    /// confidence interval of cycles/op is narrower than this fraction of
+    /// the mean. `None` measures exactly one batch.
+    pub target_precision: Option<f64>,
+    /// Hard cap on measured iterations when `target_precision` is set
+    pub max_measurement_iterations: u32,
+    /// Run on a CPU of `cpu_node` (overriding `pin_to_core`) and bind the
+    /// measuring thread's memory to `memory_node` via libnuma
+    pub numa: NumaPlacement,
+    /// Also time this many calls, each after evicting the caches (0: warm only)
+    pub cold_iterations: u32,
+    /// Seed for the order variants are measured in (`None` = thread RNG)
+    pub seed: Option<u64>,
 }
 
 impl Default for SandboxConfig {
@@ -49,7 +148,14 @@ impl Default for SandboxConfig {
         Self {
             warmup_iterations: 100,
             measurement_iterations: 1000,
-            pin_to_core: Some(0),
+            pin_to_core: Some(topology::default_benchmark_cpu().map_or(0, |c| c.cpu)),
+            throttle_threshold: DEFAULT_FREQ_DROP_THRESHOLD,
+            throttle_retries: 0,
+            target_precision: Some(0.02),
+            max_measurement_iterations: 50_000,
+            numa: NumaPlacement::default(),
+            cold_iterations: 0,
+            seed: None,
         }
     }
 }
@@ -66,45 +172,181 @@ impl NanosecondSandbox {
 
     /// Pin the current thread to a specific CPU core for consistent measurements
     pub fn pin_thread(&self) -> Result<(), String> {
-        if let Some(core_id) = self.config.pin_to_core {
+        if let Some(core_id) = self.target_cpu() {
             pin_thread_to_core(core_id)?;
         }
         Ok(())
     }
 
+    /// The configured CPU and what kind of core it is
+    pub fn pin_choice(&self) -> Option<PinChoice> {
+        self.target_cpu().map(|cpu| CpuTopology::cached().describe(cpu))
+    }
+
+    /// CPU to pin to: the best one on the requested NUMA node, else `pin_to_core`
+    fn target_cpu(&self) -> Option<usize> {
+        let Some(node) = self.config.numa.cpu_node else {
+            return self.config.pin_to_core;
+        };
+        match CpuTopology::cached().benchmark_cpu_on_node(node, &topology::allowed_cpus()) {
+            Some(choice) => Some(choice.cpu),
+            None => {
+                tracing::warn!("No usable CPU on NUMA node {}; using the default CPU", node);
+                self.config.pin_to_core
+            }
+        }
+    }
+
+    /// Pin, returning the CPU actually pinned to
+    fn pin_for_measurement(&self) -> Option<usize> {
+        self.pin_thread().ok().and(self.target_cpu())
+    }
+
+    /// Apply the configured memory binding and report the resulting placement
+    fn place_memory(&self, cpu: Option<usize>) -> NumaPlacement {
+        if let Some(node) = self.config.numa.memory_node {
+            if let Err(e) = numa::bind_memory(node) {
+                tracing::warn!("NUMA memory binding failed: {}", e);
+            }
+        }
+        NumaPlacement {
+            cpu_node: cpu.and_then(|c| CpuTopology::cached().get(c)).map(|c| c.numa_node),
+            memory_node: numa::probe_memory_node(),
+        }
+    }
+
+    /// Undo `place_memory`
+    fn release_memory(&self) {
+        if self.config.numa.memory_node.is_some() {
+            numa::unbind_memory();
+        }
+    }
+
     /// Benchmark a compiled variant with the given input
     pub fn benchmark(&self, variant: &CompiledVariant, input: u64) -> BenchmarkResult {
+        let code = variant.program.code();
+        let start = (variant.func_ptr as usize).wrapping_sub(code.main_offset) as *const u8;
+        self.measure(variant.func_ptr, input, Some((start, code.code.len())))
+    }
+
+    /// `benchmark` for any `main(n)`, e.g. one compiled with non-default `CompileOptions`
+    pub fn benchmark_fn(&self, func: extern "C" fn(u64) -> u64, input: u64) -> BenchmarkResult {
+        self.measure(func, input, None)
+    }
+
+    /// Warm measurement, then cold calls if configured. `code` is the
+    /// executable range to flush before each cold call, when known.
+    fn measure(
+        &self,
+        func: extern "C" fn(u64) -> u64,
+        input: u64,
+        code: Option<(*const u8, usize)>,
+    ) -> BenchmarkResult {
         // Pin thread for consistent results
-        let _ = self.pin_thread();
+        let cpu = self.pin_for_measurement();
+        let placement = self.place_memory(cpu);
+        let icache = ICacheCounters::new().ok();
 
         // Warmup phase - fill caches, stabilize branch predictors
+        let mut stability = Stability::new(black_box(func(input)));
         for _ in 0..self.config.warmup_iterations {
-            black_box(variant.execute(input));
+            stability.record(black_box(func(input)));
         }
 
-        // Memory fence before measurement
-        std::sync::atomic::fence(std::sync::atomic::Ordering::SeqCst);
-
-        // Measure with RDTSC
-        let start_cycles = rdtsc();
-        let start_time = Instant::now();
-
-        for _ in 0..self.config.measurement_iterations {
-            black_box(variant.execute(input));
-        }
+        let mut result = self.monitored(cpu, || {
+            // Memory fence before measurement
+            std::sync::atomic::fence(std::sync::atomic::Ordering::SeqCst);
 
-        let end_cycles = rdtsc();
-        let elapsed = start_time.elapsed();
+            let batch_size = self.config.measurement_iterations.max(1) as u64;
+            let mut batch_means = Vec::new();
+            let mut total_cycles = 0u64;
+            let mut total_nanos = 0u128;
+            let mut iterations = 0u64;
 
-        let total_cycles = end_cycles.saturating_sub(start_cycles);
-        let iterations = self.config.measurement_iterations as u64;
+            if let Some(c) = &icache {
+                c.start();
+            }
+            let precision = loop {
+                // Measure with RDTSC
+                let start_cycles = rdtsc();
+                let start_time = Instant::now();
+
+                for _ in 0..batch_size {
+                    stability.record(black_box(func(input)));
+                }
+
+                let end_cycles = rdtsc();
+                let elapsed = start_time.elapsed();
+
+                let cycles = end_cycles.saturating_sub(start_cycles);
+                total_cycles += cycles;
+                total_nanos += elapsed.as_nanos();
+                iterations += batch_size;
+                batch_means.push(cycles as f64 / batch_size as f64);
+
+                let precision = relative_ci95(&batch_means);
+                let Some(target) = self.config.target_precision else {
+                    break precision;
+                };
+                let precise = batch_means.len() >= MIN_BATCHES
+                    && precision.is_some_and(|p| p <= target);
+                let capped = iterations + batch_size > self.config.max_measurement_iterations as u64;
+                if precise || capped {
+                    break precision;
+                }
+            };
+            let l1i = icache.as_ref().map(|c| c.stop());
+
+            BenchmarkResult {
+                cycles_per_op: total_cycles / iterations,
+                nanoseconds_per_op: (total_nanos / iterations as u128) as u64,
+                instructions: l1i.and_then(|s| s.instructions).map_or(0, |i| i / iterations),
+                iterations,
+                cpu,
+                thermal: ThrottleReport::default(),
+                attempts: 1,
+                precision,
+                numa: placement,
+                l1i_mpki: l1i.and_then(|s| s.mpki()),
+                stability: Stability::default(),
+                cold: None,
+            }
+        });
+        result.cold = self.measure_cold(func, input, code, &mut stability);
+        result.stability = stability;
+        self.release_memory();
+        result
+    }
 
-        BenchmarkResult {
-            cycles_per_op: total_cycles / iterations,
-            nanoseconds_per_op: elapsed.as_nanos() as u64 / iterations,
-            instructions: 0, // Would need perf counter
-            iterations,
+    fn measure_cold(
+        &self,
+        func: extern "C" fn(u64) -> u64,
+        input: u64,
+        code: Option<(*const u8, usize)>,
+        stability: &mut Stability,
+    ) -> Option<ColdResult> {
+        let calls = self.config.cold_iterations;
+        if calls == 0 {
+            return None;
+        }
+        let mut cycles = Vec::with_capacity(calls as usize);
+        let mut nanos = Vec::with_capacity(calls as usize);
+        for _ in 0..calls {
+            evict_caches(code);
+            std::sync::atomic::fence(std::sync::atomic::Ordering::SeqCst);
+            let start_cycles = rdtsc();
+            let start_time = Instant::now();
+            stability.record(black_box(func(input)));
+            cycles.push(rdtsc().saturating_sub(start_cycles));
+            nanos.push(start_time.elapsed().as_nanos() as u64);
         }
+        cycles.sort_unstable();
+        nanos.sort_unstable();
+        Some(ColdResult {
+            cycles_per_op: cycles[cycles.len() / 2],
+            nanoseconds_per_op: nanos[nanos.len() / 2],
+            calls,
+        })
     }
 
     /// Benchmark with perf counters for detailed metrics
@@ -114,86 +356,189 @@ impl NanosecondSandbox {
         input: u64,
     ) -> Result<BenchmarkResult, String> {
         // Pin thread
-        let _ = self.pin_thread();
+        let cpu = self.pin_for_measurement();
 
         // Try to create profiler (may fail without CAP_PERFMON)
         let profiler = Profiler::new_instruction_counter(0)?;
+        let icache = ICacheCounters::new().ok();
+        let placement = self.place_memory(cpu);
 
         // Warmup
+        let mut stability = Stability::new(black_box(variant.execute(input)));
         for _ in 0..self.config.warmup_iterations {
-            black_box(variant.execute(input));
+            stability.record(black_box(variant.execute(input)));
         }
 
-        // Measurement with perf
-        profiler.enable();
-        let start_cycles = rdtsc();
-        let start_time = Instant::now();
+        let mut result = self.monitored(cpu, || {
+            // Measurement with perf
+            if let Some(c) = &icache {
+                c.start();
+            }
+            profiler.enable();
+            let start_cycles = rdtsc();
+            let start_time = Instant::now();
 
-        for _ in 0..self.config.measurement_iterations {
-            black_box(variant.execute(input));
-        }
+            for _ in 0..self.config.measurement_iterations {
+                stability.record(black_box(variant.execute(input)));
+            }
 
-        let end_cycles = rdtsc();
-        let elapsed = start_time.elapsed();
-        profiler.disable();
+            let end_cycles = rdtsc();
+            let elapsed = start_time.elapsed();
+            profiler.disable();
+            let l1i = icache.as_ref().map(|c| c.stop());
+
+            let instructions = profiler.read();
+            let iterations = self.config.measurement_iterations as u64;
+
+            BenchmarkResult {
+                cycles_per_op: (end_cycles.saturating_sub(start_cycles)) / iterations,
+                nanoseconds_per_op: elapsed.as_nanos() as u64 / iterations,
+                instructions: instructions / iterations,
+                iterations,
+                cpu,
+                thermal: ThrottleReport::default(),
+                attempts: 1,
+                precision: None,
+                numa: placement,
+                l1i_mpki: l1i.and_then(|s| s.mpki()),
+                stability: Stability::default(),
+                cold: None,
+            }
+        });
+        result.stability = stability;
+        self.release_memory();
+        Ok(result)
+    }
 
-        let instructions = profiler.read();
-        let iterations = self.config.measurement_iterations as u64;
+    /// Run a measurement block between thermal samples, retrying throttled runs
+    fn monitored(&self, cpu: Option<usize>, mut measure: impl FnMut() -> BenchmarkResult) -> BenchmarkResult {
+        let monitor = ThermalMonitor::new(cpu.unwrap_or(0), self.config.throttle_threshold);
+        let mut attempt = 1;
+        loop {
+            let before = monitor.sample();
+            let mut result = measure();
+            let report = monitor.compare(before, monitor.sample());
+            result.thermal = report;
+            result.attempts = attempt;
+
+            if !report.throttled || attempt > self.config.throttle_retries {
+                if report.throttled {
+                    tracing::warn!(
+                        "Measurement throttled (frequency drop {:.1}%); results may be skewed",
+                        report.freq_drop.unwrap_or(0.0) * 100.0
+                    );
+                }
+                return result;
+            }
+            tracing::debug!("Throttled measurement, retrying (attempt {})", attempt);
+            attempt += 1;
+        }
+    }
 
-        Ok(BenchmarkResult {
-            cycles_per_op: (end_cycles.saturating_sub(start_cycles)) / iterations,
-            nanoseconds_per_op: elapsed.as_nanos() as u64 / iterations,
-            instructions: instructions / iterations,
-            iterations,
-        })
+    /// A permutation of `0..n`, reproducible when `SandboxConfig::seed` is set
+    fn measurement_order(&self, n: usize) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..n).collect();
+        match self.config.seed {
+            Some(seed) => order.shuffle(&mut StdRng::seed_from_u64(seed)),
+            None => order.shuffle(&mut rand::thread_rng()),
+        }
+        order
     }
 
     /// Benchmark all variants and return ranked results
     pub fn benchmark_all(&self, variants: &[CompiledVariant], input: u64) -> Vec<RankedVariant> {
-        let mut results: Vec<_> = variants
+        self.benchmark_distribution(variants, &[WeightedInput { input, weight: 1.0 }])
+    }
+
+    /// Benchmark all variants at each of `points` and rank them by expected
+    /// cycles/op under the weights
+    pub fn benchmark_distribution(
+        &self,
+        variants: &[CompiledVariant],
+        points: &[WeightedInput],
+    ) -> Vec<RankedVariant> {
+        // Where the cumulative weight reaches one half
+        let mut below = 0.0;
+        let median = points
             .iter()
-            .map(|v| {
-                let result = self.benchmark(v, input);
-                (v.config.name.clone(), result)
+            .position(|p| {
+                below += p.weight;
+                below >= 0.5
+            })
+            .unwrap_or(0);
+
+        // Measure in shuffled order so slow drift (clocks, thermals) doesn't
+        // always land on the same variant, then put results back in variant
+        // order for the ranking
+        let mut results: Vec<_> = self
+            .measurement_order(variants.len())
+            .into_iter()
+            .map(|i| {
+                let v = &variants[i];
+                let mut measured: Vec<BenchmarkResult> = points
+                    .iter()
+                    .map(|p| {
+                        let result = self.benchmark(v, p.input);
+                        if !result.stability.is_stable() {
+                            tracing::warn!(
+                                "{} returned {} different value(s) for input {} (first {}, then {})",
+                                v.config.name,
+                                result.stability.mismatches,
+                                p.input,
+                                result.stability.output,
+                                result.stability.first_mismatch.unwrap_or_default()
+                            );
+                        }
+                        result
+                    })
+                    .collect();
+                let stable = measured.iter().all(|r| r.stability.is_stable());
+                let cycles_by_input: Vec<u64> = measured.iter().map(|r| r.cycles_per_op).collect();
+                let expected_cycles = distribution::expected(points, &cycles_by_input);
+                let result = measured.swap_remove(median);
+                (i, (stable, v.config.name.clone(), result, expected_cycles, cycles_by_input))
             })
             .collect();
+        results.sort_by_key(|(i, _)| *i);
+        let mut results: Vec<_> = results.into_iter().map(|(_, r)| r).collect();
 
-        // Sort by cycles per op (lower is better)
-        results.sort_by_key(|(_, r)| r.cycles_per_op);
+        // Sort by expected cycles per op (lower is better), unstable variants
+        // last. The sort is stable, so ties keep variant order and rankings
+        // don't depend on anything but timings.
+        results.sort_by(|a, b| (!a.0).cmp(&!b.0).then(a.3.total_cmp(&b.3)));
 
         results
             .into_iter()
             .enumerate()
-            .map(|(rank, (name, result))| RankedVariant {
-                rank,
-                variant_name: name,
-                result,
-            })
+            .map(
+                |(rank, (_, name, result, expected_cycles, cycles_by_input))| RankedVariant {
+                    rank,
+                    variant_name: name,
+                    result,
+                    expected_cycles,
+                    cycles_by_input,
+                },
+            )
             .collect()
     }
 
-    /// Find the fastest variant
+    /// Find the fastest variant whose result is stable (None if there is none)
     pub fn find_fastest<'a>(
         &self,
         variants: &'a [CompiledVariant],
         input: u64,
     ) -> Option<(&'a CompiledVariant, BenchmarkResult)> {
-        if variants.is_empty() {
-            return None;
-        }
-
-        let mut best_idx = 0;
-        let mut best_result = self.benchmark(&variants[0], input);
-
-        for (idx, variant) in variants.iter().enumerate().skip(1) {
+        let mut best: Option<(&'a CompiledVariant, BenchmarkResult)> = None;
+        for variant in variants {
             let result = self.benchmark(variant, input);
-            if result.cycles_per_op < best_result.cycles_per_op {
-                best_idx = idx;
-                best_result = result;
+            if !result.stability.is_stable() {
+                continue;
+            }
+            if best.as_ref().is_none_or(|(_, b)| result.cycles_per_op < b.cycles_per_op) {
+                best = Some((variant, result));
             }
         }
-
-        Some((&variants[best_idx], best_result))
+        best
     }
 }
 
@@ -203,6 +548,69 @@ impl Default for NanosecondSandbox {
     }
 }
 
+/// Bytes read to push everything else out of the caches: twice the
+/// last-level cache, or 64 MiB when sysfs doesn't say
+fn eviction_buffer() -> &'static [u8] {
+    static BUFFER: OnceLock<Vec<u8>> = OnceLock::new();
+    BUFFER.get_or_init(|| {
+        let llc = CpuTopology::cached().cpus.iter().filter_map(|c| c.llc_bytes).max();
+        let bytes = llc.map_or(64 << 20, |b| (b * 2).clamp(8 << 20, 1 << 30)) as usize;
+        // Written, so its pages are real memory and not the shared zero page
+        vec![1; bytes]
+    })
+}
+
+/// Evict `code` (start, length) and, by reading `eviction_buffer`, data
+/// from every cache level
+fn evict_caches(code: Option<(*const u8, usize)>) {
+    #[cfg(target_arch = "x86_64")]
+    if let Some((start, len)) = code {
+        for offset in (0..len).step_by(64) {
+            // SAFETY: the range is mapped readable for as long as the
+            // variant lives, and clflush doesn't modify memory
+            unsafe { std::arch::x86_64::_mm_clflush(start.add(offset)) };
+        }
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    let _ = code;
+    let buffer = eviction_buffer();
+    let mut sum = 0u8;
+    for line in buffer.chunks(64) {
+        sum = sum.wrapping_add(line[0]);
+    }
+    black_box(sum);
+}
+
+/// Batches measured before the confidence interval is trusted
+const MIN_BATCHES: usize = 3;
+
+/// Half-width of the 95% confidence interval of the mean, relative to the mean
+pub fn relative_ci95(samples: &[f64]) -> Option<f64> {
+    let n = samples.len();
+    if n < 2 {
+        return None;
+    }
+    let mean = samples.iter().sum::<f64>() / n as f64;
+    if mean <= 0.0 {
+        return None;
+    }
+    let variance = samples.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (n - 1) as f64;
+    let half_width = t_critical_95(n - 1) * (variance / n as f64).sqrt();
+    Some(half_width / mean)
+}
+
+/// Two-sided 95% Student's t critical value
+fn t_critical_95(df: usize) -> f64 {
+    const TABLE: [f64; 10] = [12.706, 4.303, 3.182, 2.776, 2.571, 2.447, 2.365, 2.306, 2.262, 2.228];
+    match df {
+        0 => f64::INFINITY,
+        1..=10 => TABLE[df - 1],
+        11..=20 => 2.086,
+        21..=30 => 2.042,
+        _ => 1.96,
+    }
+}
+
 /// Read the Time Stamp Counter (TSC) for cycle-accurate timing
 #[inline(always)]
 pub fn rdtsc() -> u64 {
@@ -270,6 +678,160 @@ mod tests {
         println!("RDTSC delta: {} cycles", t2 - t1);
     }
 
+    #[test]
+    fn test_seeded_measurement_order_is_reproducible() {
+        let seeded = |seed| {
+            NanosecondSandbox::new(SandboxConfig {
+                seed: Some(seed),
+                ..SandboxConfig::default()
+            })
+            .measurement_order(16)
+        };
+        assert_eq!(seeded(7), seeded(7));
+        assert_ne!(seeded(7), seeded(8));
+
+        let mut order = seeded(7);
+        order.sort();
+        assert_eq!(order, (0..16).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_relative_ci95() {
+        assert_eq!(relative_ci95(&[10.0]), None);
+        assert_eq!(relative_ci95(&[10.0, 10.0, 10.0]), Some(0.0));
+
+        let noisy = relative_ci95(&[8.0, 12.0, 9.0, 11.0]).unwrap();
+        let steady = relative_ci95(&[9.9, 10.1, 10.0, 10.0]).unwrap();
+        assert!(noisy > 0.1 && steady < 0.02, "{} {}", noisy, steady);
+    }
+
+    #[test]
+    fn test_adaptive_iterations_respect_cap() {
+        let program = crate::parser::Parser::new()
+            .parse("fn main(n) {\n x = n + 1\n return x\n}")
+            .unwrap();
+        let variant = crate::variant_generator::VariantGenerator::new()
+            .generate_variants(&program)
+            .unwrap()
+            .remove(0);
+
+        let fixed = NanosecondSandbox::new(SandboxConfig {
+            warmup_iterations: 10,
+            measurement_iterations: 100,
+            target_precision: None,
+            ..SandboxConfig::default()
+        })
+        .benchmark(&variant, 1);
+        assert_eq!(fixed.iterations, 100);
+        assert_eq!(fixed.precision, None);
+
+        // An unreachable target stops at the cap
+        let capped = NanosecondSandbox::new(SandboxConfig {
+            warmup_iterations: 10,
+            measurement_iterations: 100,
+            target_precision: Some(0.0),
+            max_measurement_iterations: 1_000,
+            ..SandboxConfig::default()
+        })
+        .benchmark(&variant, 1);
+        assert_eq!(capped.iterations, 1_000);
+        assert!(capped.precision.is_some());
+    }
+
+    #[test]
+    fn test_cold_calls_are_reported_separately() {
+        let program = crate::parser::Parser::new()
+            .parse("fn main(n) {\n x = n + 1\n return x\n}")
+            .unwrap();
+        let variant = crate::variant_generator::VariantGenerator::new()
+            .generate_variants(&program)
+            .unwrap()
+            .remove(0);
+        let config = SandboxConfig {
+            warmup_iterations: 10,
+            measurement_iterations: 100,
+            target_precision: None,
+            ..SandboxConfig::default()
+        };
+        let warm = NanosecondSandbox::new(config.clone()).benchmark(&variant, 1);
+        assert_eq!(warm.cold, None);
+
+        let sandbox = NanosecondSandbox::new(SandboxConfig {
+            cold_iterations: 5,
+            ..config
+        });
+        let result = sandbox.benchmark(&variant, 1);
+        let cold = result.cold.unwrap();
+        assert_eq!(cold.calls, 5);
+        assert_eq!(result.iterations, 100);
+        assert!(result.stability.is_stable());
+        // Fetching the code from memory alone outweighs a warm call
+        assert!(cold.cycles_per_op > result.cycles_per_op, "{:?} vs {}", cold, result.cycles_per_op);
+    }
+
+    #[test]
+    fn test_records_numa_placement() {
+        let program = crate::parser::Parser::new()
+            .parse("fn main(n) {\n x = n + 1\n return x\n}")
+            .unwrap();
+        let variant = crate::variant_generator::VariantGenerator::new()
+            .generate_variants(&program)
+            .unwrap()
+            .remove(0);
+        let node = CpuTopology::cached().cpus.first().map_or(0, |c| c.numa_node);
+
+        // On a scratch thread: pinning and the memory policy are per thread
+        let sandbox = NanosecondSandbox::new(SandboxConfig {
+            warmup_iterations: 10,
+            measurement_iterations: 100,
+            target_precision: None,
+            numa: NumaPlacement {
+                cpu_node: Some(node),
+                memory_node: Some(node),
+            },
+            ..SandboxConfig::default()
+        });
+        let result = std::thread::spawn(move || sandbox.benchmark(&variant, 1))
+            .join()
+            .unwrap();
+
+        if result.cpu.is_some() && !CpuTopology::cached().cpus.is_empty() {
+            assert_eq!(result.numa.cpu_node, Some(node));
+        }
+        if numa::is_available() {
+            assert_eq!(result.numa.memory_node, Some(node));
+        }
+    }
+
+    #[test]
+    fn test_flags_unstable_results() {
+        use std::sync::atomic::{AtomicU64, Ordering};
+        static CALLS: AtomicU64 = AtomicU64::new(0);
+        // Returns its input, except every 50th call
+        extern "C" fn flaky(n: u64) -> u64 {
+            let calls = CALLS.fetch_add(1, Ordering::Relaxed) + 1;
+            if calls.is_multiple_of(50) { n + 1 } else { n }
+        }
+        extern "C" fn steady(n: u64) -> u64 {
+            n
+        }
+
+        let sandbox = NanosecondSandbox::new(SandboxConfig {
+            warmup_iterations: 10,
+            measurement_iterations: 100,
+            target_precision: None,
+            ..SandboxConfig::default()
+        });
+        let stable = sandbox.benchmark_fn(steady, 7).stability;
+        assert!(stable.is_stable());
+        assert_eq!(stable.output, 7);
+
+        let unstable = sandbox.benchmark_fn(flaky, 7).stability;
+        assert!(!unstable.is_stable());
+        assert_eq!((unstable.output, unstable.first_mismatch), (7, Some(8)));
+        assert_eq!(unstable.mismatches, 2);
+    }
+
     #[test]
     fn test_pin_thread() {
         // This may fail without permissions, which is OK
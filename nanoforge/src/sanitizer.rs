@@ -0,0 +1,506 @@
+//! Memory Sanitizer Runtime
+//!
+//! Support code for `CompileOptions::sanitize`. In sanitized builds `Alloc`
+//! and `Free` go through this module instead of libc, and every `Load`/`Store`
+//! calls `nf_san_check` first:
+//!
+//! - each allocation is surrounded by poisoned red zones; an access that lands
+//!   in one is a heap buffer overflow
+//! - freed blocks are poisoned and quarantined rather than returned to libc,
+//!   so later accesses are reported as use-after-free
+//! - memory the sanitizer didn't allocate (host arrays, stack) is not checked
+//! - with `set_memory_limit`, an `Alloc` that would take the thread's live
+//!   allocations past the limit fails with a `MemoryLimit` fault
+//!
+//! Pure-mode code (`CompileOptions::pure`) checks every `Load`/`Store` with
+//! `nf_pure_check` instead: the access must lie inside one of the buffers
+//! the host handed the thread with `set_buffers`, or it faults with
+//! `OutsideBuffers`.
+//!
+//! A failed check records a `SanitizerFault` for the current thread and the
+//! JIT code exits with `Status::Trapped`. Once a fault is pending every further
+//! check fails too, so callers unwind without touching memory again.
+
+use crate::ir::Instruction;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::ops::Range;
+use std::sync::Mutex;
+
+/// Bytes of poison on each side of an allocation
+pub const REDZONE: usize = 32;
+
+/// Fill byte for red zones and freed memory
+const POISON: u8 = 0xFA;
+
+/// Freed blocks kept poisoned before being released to libc
+const QUARANTINE: usize = 256;
+
+/// IR instruction instrumented by the sanitizer, indexed by site id
+#[derive(Debug, Clone, PartialEq)]
+pub struct SanitizerSite {
+    pub function: String,
+    /// Index into the function's (optimized) instruction list
+    pub index: usize,
+    pub instruction: String,
+}
+
+impl SanitizerSite {
+    pub fn new(function: &str, index: usize, instr: &Instruction) -> Self {
+        Self {
+            function: function.to_string(),
+            index,
+            instruction: instr.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for SanitizerSite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}[{}]: {}", self.function, self.index, self.instruction)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultKind {
+    HeapBufferOverflow,
+    UseAfterFree,
+    DoubleFree,
+    InvalidFree,
+    /// An allocation over the `set_memory_limit` budget
+    MemoryLimit,
+    /// A pure-mode access outside every buffer passed to `set_buffers`
+    OutsideBuffers,
+}
+
+impl fmt::Display for FaultKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            FaultKind::HeapBufferOverflow => "heap-buffer-overflow",
+            FaultKind::UseAfterFree => "heap-use-after-free",
+            FaultKind::DoubleFree => "double-free",
+            FaultKind::InvalidFree => "invalid-free",
+            FaultKind::MemoryLimit => "memory-limit-exceeded",
+            FaultKind::OutsideBuffers => "access-outside-buffers",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A memory error caught in sanitized code
+#[derive(Debug, Clone, PartialEq)]
+pub struct SanitizerFault {
+    pub kind: FaultKind,
+    pub address: usize,
+    /// Site of the faulting access
+    pub site: usize,
+    /// Site of the Alloc that owns the memory, if known
+    pub alloc_site: Option<usize>,
+    /// Byte offset of the access from the start of that allocation
+    pub offset: Option<isize>,
+    /// Size of that allocation in bytes
+    pub alloc_size: Option<usize>,
+}
+
+impl SanitizerFault {
+    /// Human-readable report, resolving site ids against `sites`
+    pub fn report(&self, sites: &[SanitizerSite]) -> String {
+        let site = |id: usize| {
+            sites
+                .get(id)
+                .map_or_else(|| format!("site #{}", id), |s| s.to_string())
+        };
+
+        let mut out = format!(
+            "{} at 0x{:x}\n  in {}",
+            self.kind,
+            self.address,
+            site(self.site)
+        );
+        if let (Some(offset), Some(size)) = (self.offset, self.alloc_size) {
+            out.push_str(&format!(
+                "\n  {} bytes {} a {}-byte region",
+                offset.unsigned_abs(),
+                if offset < 0 {
+                    "before"
+                } else {
+                    "from the start of"
+                },
+                size
+            ));
+        }
+        if let Some(alloc) = self.alloc_site {
+            out.push_str(&format!("\n  allocated by {}", site(alloc)));
+        }
+        out
+    }
+}
+
+struct Allocation {
+    size: usize,
+    /// Bytes from the start of the block to the user start: the red zone,
+    /// rounded up to the allocation's alignment
+    lead: usize,
+    site: usize,
+    freed: bool,
+}
+
+#[derive(Default)]
+struct Heap {
+    /// Keyed by user start address (after the leading red zone)
+    live: BTreeMap<usize, Allocation>,
+    quarantine: VecDeque<usize>,
+}
+
+static HEAP: Mutex<Heap> = Mutex::new(Heap {
+    live: BTreeMap::new(),
+    quarantine: VecDeque::new(),
+});
+
+thread_local! {
+    static FAULT: RefCell<Option<SanitizerFault>> = const { RefCell::new(None) };
+    /// Bytes this thread may still allocate, if limited
+    static BUDGET: Cell<Option<usize>> = const { Cell::new(None) };
+    /// Address ranges pure-mode code on this thread may access
+    static BUFFERS: RefCell<Vec<Range<usize>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Holds the sanitizer heap's lock across a `fork`, so the child can't
+/// inherit it locked by a thread that no longer exists there
+pub struct ForkGuard {
+    _heap: std::sync::MutexGuard<'static, Heap>,
+}
+
+/// Lock the heap until the returned guard is dropped (in the parent and in
+/// the child, after forking)
+pub fn fork_guard() -> ForkGuard {
+    ForkGuard { _heap: heap() }
+}
+
+/// Limit the bytes this thread's sanitized code may have allocated at once
+/// (None removes the limit)
+pub fn set_memory_limit(bytes: Option<usize>) {
+    BUDGET.with(|b| b.set(bytes));
+}
+
+/// Let pure-mode code on this thread access `buffers` and no other memory,
+/// replacing the buffers set before
+pub fn set_buffers<'a>(buffers: impl IntoIterator<Item = &'a [i64]>) {
+    let ranges = buffers
+        .into_iter()
+        .map(|b| {
+            let range = b.as_ptr_range();
+            range.start as usize..range.end as usize
+        })
+        .collect();
+    BUFFERS.with(|b| *b.borrow_mut() = ranges);
+}
+
+fn heap() -> std::sync::MutexGuard<'static, Heap> {
+    // Called from JIT code: never panic across the FFI boundary
+    HEAP.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn fault_pending() -> bool {
+    FAULT.with(|f| f.borrow().is_some())
+}
+
+fn record(fault: SanitizerFault) {
+    FAULT.with(|f| {
+        let mut slot = f.borrow_mut();
+        // Keep the first fault; later ones are fallout from unwinding
+        if slot.is_none() {
+            *slot = Some(fault);
+        }
+    });
+}
+
+/// Take (and clear) the fault recorded on this thread, if any
+pub fn take_fault() -> Option<SanitizerFault> {
+    FAULT.with(|f| f.borrow_mut().take())
+}
+
+/// Release all quarantined memory (allocations still live are left alone)
+pub fn flush_quarantine() {
+    let mut heap = heap();
+    while let Some(start) = heap.quarantine.pop_front() {
+        if let Some(a) = heap.live.remove(&start) {
+            unsafe { libc::free((start - a.lead) as *mut libc::c_void) };
+        }
+    }
+}
+
+/// `Alloc` in sanitized code; `align` is 0 for plain malloc alignment
+pub extern "C" fn nf_san_alloc(size: i64, site: i64, align: i64) -> *mut u8 {
+    let size = size.max(0) as usize;
+    let within_budget = BUDGET.with(|b| match b.get() {
+        Some(left) if size > left => false,
+        Some(left) => {
+            b.set(Some(left - size));
+            true
+        }
+        None => true,
+    });
+    if !within_budget {
+        record(SanitizerFault {
+            kind: FaultKind::MemoryLimit,
+            address: 0,
+            site: site as usize,
+            alloc_site: None,
+            offset: None,
+            alloc_size: Some(size),
+        });
+        return std::ptr::null_mut();
+    }
+    let lead = REDZONE.next_multiple_of(align.max(1) as usize);
+    let total = lead + size + REDZONE;
+    let raw = if align > 0 {
+        crate::alignment::nf_alloc_aligned(total as i64, align)
+    } else {
+        unsafe { libc::malloc(total) as *mut u8 }
+    };
+    if raw.is_null() {
+        return raw;
+    }
+    unsafe {
+        std::ptr::write_bytes(raw, POISON, lead);
+        std::ptr::write_bytes(raw.add(lead + size), POISON, REDZONE);
+    }
+
+    let start = raw as usize + lead;
+    heap().live.insert(
+        start,
+        Allocation {
+            size,
+            lead,
+            site: site as usize,
+            freed: false,
+        },
+    );
+    start as *mut u8
+}
+
+/// `Free` in sanitized code
+pub extern "C" fn nf_san_free(ptr: i64, site: i64) {
+    let start = ptr as usize;
+    if start == 0 || fault_pending() {
+        return;
+    }
+
+    let mut heap = heap();
+    let fault = |kind, alloc_site| SanitizerFault {
+        kind,
+        address: start,
+        site: site as usize,
+        alloc_site,
+        offset: None,
+        alloc_size: None,
+    };
+
+    match heap.live.get_mut(&start) {
+        Some(a) if a.freed => record(fault(FaultKind::DoubleFree, Some(a.site))),
+        Some(a) => {
+            a.freed = true;
+            let size = a.size;
+            BUDGET.with(|b| b.set(b.get().map(|left| left + size)));
+            unsafe { std::ptr::write_bytes(start as *mut u8, POISON, a.size) };
+            heap.quarantine.push_back(start);
+            if heap.quarantine.len() > QUARANTINE {
+                let oldest = heap.quarantine.pop_front().unwrap();
+                if let Some(a) = heap.live.remove(&oldest) {
+                    unsafe { libc::free((oldest - a.lead) as *mut libc::c_void) };
+                }
+            }
+        }
+        None => record(fault(FaultKind::InvalidFree, None)),
+    }
+}
+
+/// Check an 8-byte access at `base + index * 8`. Returns 0 if it may proceed.
+pub extern "C" fn nf_san_check(base: i64, index: i64, site: i64) -> i64 {
+    if fault_pending() {
+        return 1;
+    }
+
+    let address = (base as usize).wrapping_add((index as usize).wrapping_mul(8));
+    let heap = heap();
+
+    // The only allocation whose padded block can contain `address` is the
+    // last one starting at or below address + REDZONE
+    let Some((&start, alloc)) = heap
+        .live
+        .range(..=address.saturating_add(REDZONE))
+        .next_back()
+    else {
+        return 0;
+    };
+    let block_start = start - REDZONE;
+    let block_end = start + alloc.size + REDZONE;
+    if address < block_start || address >= block_end {
+        return 0; // Not sanitizer-owned memory
+    }
+
+    let in_bounds = address >= start && address + 8 <= start + alloc.size;
+    let kind = if alloc.freed {
+        FaultKind::UseAfterFree
+    } else if in_bounds {
+        return 0;
+    } else {
+        FaultKind::HeapBufferOverflow
+    };
+
+    record(SanitizerFault {
+        kind,
+        address,
+        site: site as usize,
+        alloc_site: Some(alloc.site),
+        offset: Some(address as isize - start as isize),
+        alloc_size: Some(alloc.size),
+    });
+    1
+}
+
+/// Check an 8-byte access at `base + index * 8` in pure-mode code against
+/// the thread's `set_buffers`. Returns 0 if it may proceed.
+pub extern "C" fn nf_pure_check(base: i64, index: i64, site: i64) -> i64 {
+    if fault_pending() {
+        return 1;
+    }
+    let address = (base as usize).wrapping_add((index as usize).wrapping_mul(8));
+    let inside = BUFFERS.with(|b| {
+        b.borrow()
+            .iter()
+            .any(|r| r.start <= address && address.checked_add(8).is_some_and(|end| end <= r.end))
+    });
+    if inside {
+        return 0;
+    }
+    record(SanitizerFault {
+        kind: FaultKind::OutsideBuffers,
+        address,
+        site: site as usize,
+        alloc_site: None,
+        offset: None,
+        alloc_size: None,
+    });
+    1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_overflow_and_use_after_free() {
+        let p = nf_san_alloc(16, 7, 0) as i64;
+        assert_eq!(nf_san_check(p, 0, 1), 0);
+        assert_eq!(nf_san_check(p, 1, 1), 0);
+
+        assert_eq!(nf_san_check(p, 2, 2), 1);
+        let fault = take_fault().unwrap();
+        assert_eq!(fault.kind, FaultKind::HeapBufferOverflow);
+        assert_eq!(
+            (fault.site, fault.alloc_site, fault.offset),
+            (2, Some(7), Some(16))
+        );
+
+        assert_eq!(nf_san_check(p, -1, 3), 1);
+        assert_eq!(take_fault().unwrap().offset, Some(-8));
+
+        nf_san_free(p, 4);
+        assert_eq!(nf_san_check(p, 0, 5), 1);
+        assert_eq!(take_fault().unwrap().kind, FaultKind::UseAfterFree);
+
+        nf_san_free(p, 6);
+        assert_eq!(take_fault().unwrap().kind, FaultKind::DoubleFree);
+    }
+
+    #[test]
+    fn test_aligned_allocation_keeps_red_zones() {
+        let p = nf_san_alloc(24, 1, 128) as i64;
+        assert_eq!(p % 128, 0);
+        assert_eq!(nf_san_check(p, 2, 2), 0);
+        assert_eq!(nf_san_check(p, 3, 2), 1);
+        assert_eq!(take_fault().unwrap().kind, FaultKind::HeapBufferOverflow);
+        assert_eq!(nf_san_check(p, -1, 3), 1);
+        assert_eq!(take_fault().unwrap().offset, Some(-8));
+        nf_san_free(p, 4);
+        assert!(take_fault().is_none());
+    }
+
+    #[test]
+    fn test_memory_limit_counts_live_allocations() {
+        set_memory_limit(Some(100));
+        let p = nf_san_alloc(64, 1, 0) as i64;
+        assert!(nf_san_alloc(64, 2, 0).is_null());
+        let fault = take_fault().unwrap();
+        assert_eq!((fault.kind, fault.site, fault.alloc_size), (FaultKind::MemoryLimit, 2, Some(64)));
+
+        // Freeing returns the bytes to the budget
+        nf_san_free(p, 3);
+        let q = nf_san_alloc(64, 4, 0) as i64;
+        assert_ne!(q, 0);
+        nf_san_free(q, 5);
+        set_memory_limit(None);
+        assert!(take_fault().is_none());
+    }
+
+    #[test]
+    fn test_foreign_memory_is_unchecked() {
+        let host = [0i64; 4];
+        assert_eq!(nf_san_check(host.as_ptr() as i64, 3, 0), 0);
+        assert!(take_fault().is_none());
+
+        nf_san_free(host.as_ptr() as i64, 9);
+        assert_eq!(take_fault().unwrap().kind, FaultKind::InvalidFree);
+    }
+
+    #[test]
+    fn test_pure_checks_stay_inside_the_buffers() {
+        // Two buffers with unregistered memory on either side
+        let memory = [0i64; 10];
+        let (a, b) = (&memory[1..5], &memory[7..9]);
+        set_buffers([a, b]);
+        let base = a.as_ptr() as i64;
+        assert_eq!(nf_pure_check(base, 3, 0), 0);
+        assert_eq!(nf_pure_check(b.as_ptr() as i64, 1, 0), 0);
+
+        assert_eq!(nf_pure_check(base, 4, 1), 1);
+        let fault = take_fault().unwrap();
+        assert_eq!((fault.kind, fault.site), (FaultKind::OutsideBuffers, 1));
+        assert_eq!(nf_pure_check(base, -1, 2), 1);
+        assert_eq!(take_fault().unwrap().kind, FaultKind::OutsideBuffers);
+        assert_eq!(nf_pure_check(base, i64::MAX / 8, 3), 1);
+        assert!(take_fault().is_some());
+
+        set_buffers([]);
+        assert_eq!(nf_pure_check(base, 0, 4), 1);
+        assert!(take_fault().is_some());
+    }
+
+    #[test]
+    fn test_report_names_sites() {
+        let sites = vec![SanitizerSite {
+            function: "main".to_string(),
+            index: 3,
+            instruction: "Store Reg(10), Imm(4), Imm(1)".to_string(),
+        }];
+        let fault = SanitizerFault {
+            kind: FaultKind::HeapBufferOverflow,
+            address: 0x1000,
+            site: 0,
+            alloc_site: Some(0),
+            offset: Some(32),
+            alloc_size: Some(32),
+        };
+        let report = fault.report(&sites);
+        assert!(report.starts_with("heap-buffer-overflow"), "{}", report);
+        assert!(report.contains("main[3]: Store"), "{}", report);
+        assert!(
+            report.contains("32 bytes from the start of a 32-byte region"),
+            "{}",
+            report
+        );
+    }
+}
@@ -0,0 +1,155 @@
+//! Graceful Shutdown
+//!
+//! Long-running modes (demo, daemon, evolution, bandit learning, tuning) call
+//! `install` so the first SIGINT or SIGTERM only sets a flag: loops check
+//! `requested` between steps, stop, and flush what they learned to a
+//! `StateDir` before exiting. A second signal exits immediately.
+
+use crate::store::{self, Schema};
+use crate::tuning;
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+const NOTICE: &[u8] = b"\nShutting down after the current step (interrupt again to force)\n";
+
+extern "C" fn on_signal(_: libc::c_int) {
+    // Only async-signal-safe calls in here
+    if REQUESTED.swap(true, Ordering::SeqCst) {
+        unsafe { libc::_exit(130) };
+    }
+    unsafe {
+        libc::write(2, NOTICE.as_ptr() as *const libc::c_void, NOTICE.len());
+    }
+}
+
+/// Route SIGINT and SIGTERM to `request`
+pub fn install() -> Result<(), String> {
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = on_signal as *const () as usize;
+        libc::sigemptyset(&mut action.sa_mask);
+        for signal in [libc::SIGINT, libc::SIGTERM] {
+            if libc::sigaction(signal, &action, std::ptr::null_mut()) != 0 {
+                return Err(format!(
+                    "sigaction({}) failed: {}",
+                    signal,
+                    std::io::Error::last_os_error()
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether a shutdown was requested; loops stop at their next step
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::Relaxed)
+}
+
+/// Ask long-running loops to stop, as a signal would
+pub fn request() {
+    REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Where long-running modes flush state (`--state-dir`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateDir {
+    root: PathBuf,
+}
+
+impl StateDir {
+    /// Use `root`, creating it if needed
+    pub fn open(root: &Path) -> Result<Self, String> {
+        fs::create_dir_all(root)
+            .map_err(|e| format!("{}: failed to create: {}", root.display(), e))?;
+        Ok(Self {
+            root: root.to_path_buf(),
+        })
+    }
+
+    /// Path of the file `name` in this directory
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.root.join(name)
+    }
+
+    /// Save `value` to `name` as a `schema` document, replacing it
+    /// atomically so an interrupted flush never leaves a truncated file
+    pub fn save_json<T: Serialize>(&self, name: &str, schema: Schema, value: &T) -> Result<PathBuf, String> {
+        let path = self.path(name);
+        store::save(&path, schema, value)?;
+        Ok(path)
+    }
+
+    /// Append `value` as one JSON line to `name`
+    pub fn append_jsonl<T: Serialize>(&self, name: &str, value: &T) -> Result<PathBuf, String> {
+        let path = self.path(name);
+        store::append_line(&path, value)?;
+        Ok(path)
+    }
+}
+
+/// File name for a script's per-script state: its file-name-safe stem, to
+/// find it by eye, and `tuning::script_hash` of its source, so scripts with
+/// the same name in different directories don't share state
+pub fn script_key(path: &str, source: &str) -> String {
+    let stem: String = Path::new(path)
+        .file_stem()
+        .map_or_else(
+            || "script".to_string(),
+            |s| s.to_string_lossy().into_owned(),
+        )
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("{}-{}", stem, tuning::script_hash(source))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state_dir_writes_json_and_history() {
+        let root = std::env::temp_dir().join(format!("nf_state_{}", std::process::id()));
+        let dir = StateDir::open(&root.join("nested")).unwrap();
+
+        let schema = Schema::new("brain", 1);
+        let path = dir.save_json("brain.json", schema, &vec![1, 2, 3]).unwrap();
+        dir.save_json("brain.json", schema, &vec![4]).unwrap();
+        assert_eq!(store::load(&path, schema), Ok(Some(vec![4])));
+        assert_eq!(fs::read_dir(root.join("nested")).unwrap().count(), 1);
+
+        dir.append_jsonl("history.jsonl", &1).unwrap();
+        dir.append_jsonl("history.jsonl", &2).unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.path("history.jsonl")).unwrap(),
+            "1\n2\n"
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_script_key() {
+        let hash = tuning::script_hash("fn main() { return 0 }");
+        assert_eq!(
+            script_key("kernels/sum array.nf", "fn main() { return 0 }"),
+            format!("sum_array-{}", hash)
+        );
+        assert_eq!(script_key("/", ""), format!("script-{}", tuning::script_hash("")));
+        // Same name, different scripts
+        assert_ne!(
+            script_key("a/loop.nf", "fn main() { return 1 }"),
+            script_key("b/loop.nf", "fn main() { return 2 }")
+        );
+    }
+}
@@ -0,0 +1,411 @@
+//! Offline Bandit Simulation
+//!
+//! `soae-ai --record` and `soae-context --record` append every sandbox
+//! measurement they take (variant, input size, cycles/op) to a JSONL file.
+//! Both measure every variant at each input they try, so the file holds a
+//! sample of every arm's cycles at every recorded input. `simulate` replays
+//! a bandit policy against those samples instead of the hardware: each round
+//! draws an input from the recorded ones, lets the policy of its size bucket
+//! pick a variant, and rewards it with a recorded measurement of that
+//! variant at that input, scaled as `performance_reward` does against the
+//! fastest median there.
+//!
+//! Runs are deterministic for a seed, so exploration parameters can be
+//! compared on the same data. Simulated updates are `BanditEvent`s, which
+//! `brain_log::analyze` turns into convergence curves and regret.
+
+use crate::ai_optimizer::{performance_reward, OptimizationFeatures, SizeBucket, VariantBandit};
+use crate::brain_log::{analyze, BanditEvent, BrainAnalysis};
+use crate::store;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+/// One sandbox benchmark
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Measurement {
+    pub variant_idx: usize,
+    pub variant: String,
+    pub input: u64,
+    pub cycles: u64,
+}
+
+/// Append `measurement` to the recording at `path`; a failed write stops
+/// the recording rather than the run
+pub(crate) fn record(path: &mut Option<&Path>, measurement: &Measurement) {
+    if let Some(file) = *path {
+        if let Err(e) = store::append_line(file, measurement) {
+            tracing::warn!("{}; recording disabled", e);
+            *path = None;
+        }
+    }
+}
+
+/// Recorded measurements, by input size and variant
+#[derive(Debug, Clone, PartialEq)]
+pub struct MeasurementTable {
+    pub variant_names: Vec<String>,
+    /// Cycles/op samples of each variant (in variant order) per input
+    pub samples: BTreeMap<u64, Vec<Vec<u64>>>,
+    /// Inputs in recording order, repeated as often as they were measured,
+    /// so drawing from it follows the recorded workload
+    inputs: Vec<u64>,
+    /// Inputs dropped because some variant was never measured there
+    pub incomplete_inputs: usize,
+}
+
+impl MeasurementTable {
+    /// Read a `--record` file
+    pub fn load(path: &Path) -> Result<Self, String> {
+        if !path.is_file() {
+            return Err(format!("{}: not found", path.display()));
+        }
+        Self::from_measurements(&store::read_lines(path)?).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn from_measurements(measurements: &[Measurement]) -> Result<Self, String> {
+        let mut variant_names: Vec<String> = Vec::new();
+        for m in measurements {
+            if variant_names.len() <= m.variant_idx {
+                variant_names.resize(m.variant_idx + 1, String::new());
+            }
+            let name = &mut variant_names[m.variant_idx];
+            if name.is_empty() {
+                *name = m.variant.clone();
+            } else if *name != m.variant {
+                return Err(format!(
+                    "variant {} is both {} and {} (recordings of different scripts?)",
+                    m.variant_idx, name, m.variant
+                ));
+            }
+        }
+        if let Some(missing) = variant_names.iter().position(String::is_empty) {
+            return Err(format!("variant {} was never measured", missing));
+        }
+
+        let mut samples: BTreeMap<u64, Vec<Vec<u64>>> = BTreeMap::new();
+        for m in measurements {
+            samples
+                .entry(m.input)
+                .or_insert_with(|| vec![Vec::new(); variant_names.len()])[m.variant_idx]
+                .push(m.cycles);
+        }
+        let before = samples.len();
+        samples.retain(|_, arms| arms.iter().all(|s| !s.is_empty()));
+        let incomplete_inputs = before - samples.len();
+        if samples.is_empty() {
+            return Err("no input has measurements of every variant".to_string());
+        }
+        let inputs = measurements
+            .iter()
+            .map(|m| m.input)
+            .filter(|input| samples.contains_key(input))
+            .collect();
+        Ok(Self {
+            variant_names,
+            samples,
+            inputs,
+            incomplete_inputs,
+        })
+    }
+
+    /// Median cycles/op of each variant at `input`
+    pub fn medians(&self, input: u64) -> Vec<u64> {
+        self.samples[&input]
+            .iter()
+            .map(|s| {
+                let mut sorted = s.clone();
+                sorted.sort_unstable();
+                sorted[sorted.len() / 2]
+            })
+            .collect()
+    }
+
+    /// Fastest median at `input`: the reward scale
+    pub fn best_cycles(&self, input: u64) -> u64 {
+        self.medians(input).into_iter().min().unwrap_or(1)
+    }
+}
+
+/// How a simulated bandit explores
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Policy {
+    /// Beta posteriors, as `VariantBandit` (cold-started)
+    Thompson,
+    /// Mean reward plus `c * sqrt(ln t / pulls)`
+    Ucb1 { c: f64 },
+    /// Best mean reward, or a uniformly random variant with probability `epsilon`
+    EpsilonGreedy { epsilon: f64 },
+    /// Uniformly random: the no-learning baseline
+    Uniform,
+}
+
+impl FromStr for Policy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let (name, param) = match s.split_once(':') {
+            Some((name, param)) => {
+                let value: f64 = param
+                    .parse()
+                    .ok()
+                    .filter(|v: &f64| v.is_finite() && *v >= 0.0)
+                    .ok_or_else(|| format!("'{}' is not a non-negative number", param))?;
+                (name, Some(value))
+            }
+            None => (s, None),
+        };
+        match (name, param) {
+            ("thompson", None) => Ok(Policy::Thompson),
+            ("uniform", None) => Ok(Policy::Uniform),
+            ("ucb1", c) => Ok(Policy::Ucb1 {
+                c: c.unwrap_or(std::f64::consts::SQRT_2),
+            }),
+            ("epsilon-greedy", epsilon) => match epsilon.unwrap_or(0.1) {
+                epsilon if epsilon <= 1.0 => Ok(Policy::EpsilonGreedy { epsilon }),
+                epsilon => Err(format!("epsilon must be at most 1, not {}", epsilon)),
+            },
+            ("thompson" | "uniform", Some(_)) => Err(format!("{} takes no parameter", name)),
+            _ => Err(format!(
+                "unknown policy '{}' (expected thompson, ucb1[:C], epsilon-greedy[:EPSILON] or uniform)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Parameters to 3 decimals, so the default √2 stays readable
+        let short = |x: f64| (x * 1000.0).round() / 1000.0;
+        match self {
+            Policy::Thompson => write!(f, "thompson"),
+            Policy::Ucb1 { c } => write!(f, "ucb1:{}", short(*c)),
+            Policy::EpsilonGreedy { epsilon } => write!(f, "epsilon-greedy:{}", short(*epsilon)),
+            Policy::Uniform => write!(f, "uniform"),
+        }
+    }
+}
+
+/// One size bucket's simulated bandit
+enum Learner {
+    Thompson(Box<VariantBandit>),
+    /// Pulls and reward sums per variant
+    Counting { pulls: Vec<u64>, rewards: Vec<f64> },
+}
+
+impl Learner {
+    fn new(policy: Policy, names: &[String], seed: u64) -> Self {
+        match policy {
+            Policy::Thompson => Learner::Thompson(Box::new(VariantBandit::with_seed(names.to_vec(), seed))),
+            _ => Learner::Counting {
+                pulls: vec![0; names.len()],
+                rewards: vec![0.0; names.len()],
+            },
+        }
+    }
+
+    fn select(&mut self, policy: Policy, rng: &mut StdRng) -> usize {
+        let (pulls, rewards) = match self {
+            Learner::Thompson(bandit) => return bandit.select(),
+            Learner::Counting { pulls, rewards } => (pulls, rewards),
+        };
+        let arms = pulls.len();
+        let mean = |i: usize| rewards[i] / pulls[i].max(1) as f64;
+        let argmax = |score: &dyn Fn(usize) -> f64| {
+            (0..arms)
+                .max_by(|&a, &b| score(a).total_cmp(&score(b)).then(b.cmp(&a)))
+                .unwrap_or(0)
+        };
+        match policy {
+            Policy::Uniform => rng.gen_range(0..arms),
+            Policy::EpsilonGreedy { epsilon } if rng.gen::<f64>() < epsilon => rng.gen_range(0..arms),
+            Policy::EpsilonGreedy { .. } => argmax(&mean),
+            Policy::Ucb1 { c } => match pulls.iter().position(|&p| p == 0) {
+                // Every arm once before the bound means anything
+                Some(untried) => untried,
+                None => {
+                    let ln_t = (pulls.iter().sum::<u64>() as f64).ln();
+                    argmax(&|i| mean(i) + c * (ln_t / pulls[i] as f64).sqrt())
+                }
+            },
+            Policy::Thompson => unreachable!("Thompson sampling has its own learner"),
+        }
+    }
+
+    fn update(&mut self, arm: usize, cycles: u64, best_cycles: u64) {
+        match self {
+            Learner::Thompson(bandit) => bandit.update_with_performance(arm, cycles, best_cycles),
+            Learner::Counting { pulls, rewards } => {
+                pulls[arm] += 1;
+                rewards[arm] += performance_reward(cycles, best_cycles);
+            }
+        }
+    }
+}
+
+/// Replay `policy` for `iterations` rounds against `table`
+pub fn simulate(table: &MeasurementTable, policy: Policy, iterations: u32, seed: u64) -> Vec<BanditEvent> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut learners: HashMap<SizeBucket, Learner> = HashMap::new();
+    let best: BTreeMap<u64, u64> = table.samples.keys().map(|&i| (i, table.best_cycles(i))).collect();
+    let source = format!("simulate:{}", policy);
+    (1..=iterations as u64)
+        .map(|step| {
+            let input = table.inputs[rng.gen_range(0..table.inputs.len())];
+            let context = OptimizationFeatures::new(input);
+            let bucket = context.size_bucket();
+            let learner = learners.entry(bucket).or_insert_with(|| {
+                Learner::new(policy, &table.variant_names, seed.wrapping_add(bucket as u64))
+            });
+            let selected = learner.select(policy, &mut rng);
+            let samples = &table.samples[&input][selected];
+            let cycles = samples[rng.gen_range(0..samples.len())];
+            learner.update(selected, cycles, best[&input]);
+            BanditEvent {
+                source: source.clone(),
+                step,
+                input_size: input,
+                bucket,
+                variant_idx: selected,
+                variant: table.variant_names[selected].clone(),
+                cycles,
+                best_cycles: best[&input],
+                reward: performance_reward(cycles, best[&input]),
+            }
+        })
+        .collect()
+}
+
+/// How a policy did over several seeded runs
+#[derive(Debug, Clone, PartialEq)]
+pub struct PolicySummary {
+    pub policy: Policy,
+    pub runs: usize,
+    pub mean_reward: f64,
+    /// Mean and standard deviation across runs of the summed `1 - reward`
+    pub mean_regret: f64,
+    pub regret_std_dev: f64,
+    /// Share of each run's later half that picked its bucket's best variant
+    pub late_best_rate: f64,
+    /// Analysis of the first run
+    pub first_run: BrainAnalysis,
+}
+
+/// Simulate `runs` runs of `policy`, seeded `seed`, `seed + 1`, ...
+pub fn compare(table: &MeasurementTable, policy: Policy, iterations: u32, runs: usize, seed: u64) -> PolicySummary {
+    let analyses: Vec<BrainAnalysis> = (0..runs.max(1) as u64)
+        .map(|run| analyze(&simulate(table, policy, iterations, seed.wrapping_add(run)), 10))
+        .collect();
+    let per_run = |f: &dyn Fn(&BrainAnalysis) -> f64| -> Vec<f64> { analyses.iter().map(f).collect() };
+    let mean = |values: &[f64]| values.iter().sum::<f64>() / values.len() as f64;
+
+    let regrets = per_run(&|a| a.buckets.iter().map(|b| b.cumulative_regret).sum());
+    let mean_regret = mean(&regrets);
+    let variance = regrets.iter().map(|r| (r - mean_regret).powi(2)).sum::<f64>() / regrets.len() as f64;
+    let events = |a: &BrainAnalysis| a.events.max(1) as f64;
+    let rewards = per_run(&|a| a.buckets.iter().map(|b| b.mean_reward * b.events as f64).sum::<f64>() / events(a));
+    let late = per_run(&|a| {
+        a.buckets.iter().map(|b| b.final_best_rate * b.events as f64).sum::<f64>() / events(a)
+    });
+    PolicySummary {
+        policy,
+        runs: analyses.len(),
+        mean_reward: mean(&rewards),
+        mean_regret,
+        regret_std_dev: variance.sqrt(),
+        late_best_rate: mean(&late),
+        first_run: analyses.into_iter().next().expect("at least one run"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// "slow" takes twice the cycles of "fast" at 1000; they tie at 10
+    fn table() -> MeasurementTable {
+        let mut measurements = Vec::new();
+        for (input, fast, slow) in [(10, 100, 100), (1000, 100, 200)] {
+            for jitter in 0..5 {
+                for (idx, name, cycles) in [(0, "slow", slow), (1, "fast", fast)] {
+                    measurements.push(Measurement {
+                        variant_idx: idx,
+                        variant: name.to_string(),
+                        input,
+                        cycles: cycles + jitter,
+                    });
+                }
+            }
+        }
+        MeasurementTable::from_measurements(&measurements).unwrap()
+    }
+
+    #[test]
+    fn test_table_groups_samples_by_input() {
+        let table = table();
+        assert_eq!(table.variant_names, ["slow", "fast"]);
+        assert_eq!(table.medians(1000), vec![202, 102]);
+        assert_eq!(table.best_cycles(1000), 102);
+        assert_eq!(table.incomplete_inputs, 0);
+
+        let lonely = Measurement {
+            variant_idx: 0,
+            variant: "slow".to_string(),
+            input: 5,
+            cycles: 1,
+        };
+        let mut measurements = vec![lonely.clone()];
+        measurements.extend([(1000, 0, "slow"), (1000, 1, "fast")].map(|(input, variant_idx, name)| Measurement {
+            variant_idx,
+            variant: name.to_string(),
+            input,
+            cycles: 10,
+        }));
+        let partial = MeasurementTable::from_measurements(&measurements).unwrap();
+        assert_eq!(partial.incomplete_inputs, 1);
+        assert_eq!(partial.inputs, vec![1000, 1000]);
+
+        let renamed = Measurement {
+            variant: "other".to_string(),
+            ..lonely.clone()
+        };
+        assert!(MeasurementTable::from_measurements(&[lonely, renamed])
+            .unwrap_err()
+            .contains("both slow and other"));
+    }
+
+    #[test]
+    fn test_policies_parse() {
+        assert_eq!("thompson".parse(), Ok(Policy::Thompson));
+        assert_eq!("ucb1:0.5".parse(), Ok(Policy::Ucb1 { c: 0.5 }));
+        assert_eq!("epsilon-greedy".parse(), Ok(Policy::EpsilonGreedy { epsilon: 0.1 }));
+        assert_eq!(Policy::Ucb1 { c: 2.0 }.to_string().parse(), Ok(Policy::Ucb1 { c: 2.0 }));
+        assert_eq!("ucb1".parse::<Policy>().unwrap().to_string(), "ucb1:1.414");
+        assert!("epsilon-greedy:1.5".parse::<Policy>().is_err());
+        assert!("uniform:3".parse::<Policy>().is_err());
+        assert!("ucb1:-1".parse::<Policy>().is_err());
+        assert!("softmax".parse::<Policy>().unwrap_err().contains("unknown policy"));
+    }
+
+    #[test]
+    fn test_learning_policies_beat_uniform_reproducibly() {
+        let table = table();
+        let events = simulate(&table, Policy::Thompson, 200, 3);
+        assert_eq!(events.len(), 200);
+        assert_eq!(events, simulate(&table, Policy::Thompson, 200, 3));
+        assert!(events.iter().all(|e| e.source == "simulate:thompson"));
+
+        let uniform = compare(&table, Policy::Uniform, 200, 4, 1);
+        for policy in [Policy::Thompson, Policy::Ucb1 { c: 0.5 }, Policy::EpsilonGreedy { epsilon: 0.1 }] {
+            let summary = compare(&table, policy, 200, 4, 1);
+            assert_eq!(summary.runs, 4);
+            assert!(summary.mean_regret < uniform.mean_regret, "{}: {:?}", policy, summary);
+            assert!(summary.mean_reward > uniform.mean_reward, "{}: {:?}", policy, summary);
+        }
+    }
+}
@@ -0,0 +1,710 @@
+//! SOAE (Self-Optimizing Assembly Engine) Sessions
+//!
+//! The SOAE flows as library calls: `run` benchmarks every variant of a
+//! program once, `run_per_function` tunes each hot function on its own,
+//! `learn` lets a Thompson Sampling bandit find the fastest one, and
+//! `learn_contextual` learns the fastest per input-size bucket.
+//! Each returns a structured report; presenting it is up to the caller
+//! (the CLI prints tables, Python gets tuples).
+
+use crate::ai_optimizer::{ContextualBandit, OptimizationFeatures, SizeBucket, VariantBandit};
+use crate::brain_log::BrainLog;
+use crate::compiled_program::CompiledProgram;
+use crate::compiler::CompileOptions;
+use crate::cpu_features::CpuFeatures;
+use crate::distribution::{InputDistribution, WeightedInput, DEFAULT_POINTS};
+use crate::function_profiler::{ProfileReport, ProfileSession};
+use crate::ir::Program;
+use crate::sandbox::{BenchmarkResult, ColdResult, NanosecondSandbox, RankedVariant, SandboxConfig};
+use crate::shutdown;
+use crate::simulation::{self, Measurement};
+use crate::topology::PinChoice;
+use crate::variant_cache::VariantCache;
+use crate::variant_generator::{CompiledVariant, VariantConfig, VariantGenerator};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Input sizes `learn_contextual` draws from, covering every size bucket
+pub const CONTEXT_SIZES: [u64; 11] = [
+    10, 20, // Tiny
+    50, 100, 200, // Small
+    500, 1000, 2000, // Medium
+    5000, 10000,  // Large
+    100000, // Huge
+];
+
+/// Settings shared by the SOAE flows
+#[derive(Debug, Clone)]
+pub struct SoaeOptions {
+    /// Input passed to `main` (`run` and `learn`)
+    pub input: u64,
+    /// Rank `run`'s variants by expected cost under this instead of at `input`
+    pub inputs: Option<InputDistribution>,
+    pub sandbox: SandboxConfig,
+    /// Drop variants larger than this (see `VariantGenerator::code_size_budget`)
+    pub max_code_size: Option<usize>,
+    /// Seed for the bandits and `learn_contextual`'s input sizes
+    pub seed: Option<u64>,
+    /// Start the bandits from uniform priors instead of the static cost model
+    pub cold_start: bool,
+    /// Share of the profiled self cycles a function needs for
+    /// `run_per_function` to tune it
+    pub hot_share: f64,
+    /// Append every measurement `learn` and `learn_contextual` take here
+    /// (see `simulation`)
+    pub record: Option<PathBuf>,
+}
+
+impl Default for SoaeOptions {
+    fn default() -> Self {
+        Self {
+            input: 1000,
+            inputs: None,
+            sandbox: SandboxConfig {
+                warmup_iterations: 50,
+                measurement_iterations: 500,
+                ..SandboxConfig::default()
+            },
+            max_code_size: None,
+            seed: None,
+            cold_start: false,
+            hot_share: 0.1,
+            record: None,
+        }
+    }
+}
+
+impl SoaeOptions {
+    pub fn input(mut self, input: u64) -> Self {
+        self.input = input;
+        self
+    }
+
+    pub fn inputs(mut self, distribution: InputDistribution) -> Self {
+        self.inputs = Some(distribution);
+        self
+    }
+
+    /// Warmup and measured iterations per benchmark
+    pub fn measurement(mut self, warmup: u32, iterations: u32) -> Self {
+        self.sandbox.warmup_iterations = warmup;
+        self.sandbox.measurement_iterations = iterations;
+        self
+    }
+
+    pub fn max_code_size(mut self, bytes: usize) -> Self {
+        self.max_code_size = Some(bytes);
+        self
+    }
+
+    pub fn seed(mut self, seed: Option<u64>) -> Self {
+        self.seed = seed;
+        self.sandbox.seed = seed;
+        self
+    }
+
+    pub fn cold_start(mut self, enabled: bool) -> Self {
+        self.cold_start = enabled;
+        self
+    }
+
+    pub fn hot_share(mut self, share: f64) -> Self {
+        self.hot_share = share;
+        self
+    }
+
+    /// Also time `calls` calls per measurement from cold caches
+    pub fn cold(mut self, calls: u32) -> Self {
+        self.sandbox.cold_iterations = calls;
+        self
+    }
+
+    pub fn record(mut self, path: impl Into<PathBuf>) -> Self {
+        self.record = Some(path.into());
+        self
+    }
+
+    fn generator(&self) -> VariantGenerator {
+        let generator = VariantGenerator::new();
+        match self.max_code_size {
+            Some(bytes) => generator.code_size_budget(bytes),
+            None => generator,
+        }
+    }
+
+    pub(crate) fn variants(&self, program: &Program) -> Result<Vec<CompiledVariant>, String> {
+        self.generator().generate_variants(program)
+    }
+}
+
+/// Outcome of `run`
+#[derive(Debug)]
+pub struct SoaeReport {
+    pub cpu: CpuFeatures,
+    /// Where the sandbox pinned the measurements, if it could
+    pub pinned: Option<PinChoice>,
+    /// The input the winner was run with: the median of the distribution
+    /// ranked on, if any
+    pub input: u64,
+    pub distribution: Option<InputDistribution>,
+    /// Inputs the variants were measured at, with their weights
+    pub points: Vec<WeightedInput>,
+    /// Every variant, in generation order
+    pub variants: Vec<CompiledVariant>,
+    /// Measurements, fastest first
+    pub rankings: Vec<RankedVariant>,
+    /// What the winner returned for `input`
+    pub winner_output: u64,
+}
+
+impl SoaeReport {
+    pub fn variant(&self, name: &str) -> Option<&CompiledVariant> {
+        self.variants.iter().find(|v| v.config.name == name)
+    }
+
+    pub fn winner(&self) -> &RankedVariant {
+        &self.rankings[0]
+    }
+
+    /// Variants measured from cold caches, fastest first (empty unless
+    /// `SoaeOptions::cold` was set)
+    pub fn cold_rankings(&self) -> Vec<(&RankedVariant, ColdResult)> {
+        let mut cold: Vec<_> = self
+            .rankings
+            .iter()
+            .filter(|r| r.result.stability.is_stable())
+            .filter_map(|r| Some((r, r.result.cold?)))
+            .collect();
+        cold.sort_by_key(|(_, c)| c.cycles_per_op);
+        cold
+    }
+
+    /// Variants measured while the CPU was throttling
+    pub fn throttled(&self) -> Vec<&str> {
+        self.rankings
+            .iter()
+            .filter(|r| r.result.thermal.throttled)
+            .map(|r| r.variant_name.as_str())
+            .collect()
+    }
+
+    /// How the `n` fastest variants differ from the winner, fastest first
+    pub fn explain(&self, n: usize) -> Vec<String> {
+        let winner = self.variant(&self.winner().variant_name);
+        self.rankings
+            .iter()
+            .take(n)
+            .filter_map(|ranked| {
+                let v = self.variant(&ranked.variant_name)?;
+                let base = winner.filter(|w| w.config.name != v.config.name);
+                Some(format!("#{} {}", ranked.rank + 1, v.describe(base)))
+            })
+            .collect()
+    }
+}
+
+/// Benchmark every variant of `program` and run the fastest
+pub fn run(program: &Program, options: &SoaeOptions) -> Result<SoaeReport, String> {
+    let variants = options.variants(program)?;
+    let sandbox = NanosecondSandbox::new(options.sandbox.clone());
+    let distribution = options
+        .inputs
+        .clone()
+        .unwrap_or(InputDistribution::Point(options.input));
+    let points = distribution.points(DEFAULT_POINTS);
+    let input = distribution.median();
+    let rankings = sandbox.benchmark_distribution(&variants, &points);
+    let winner = rankings.first().ok_or("No variant was measured")?;
+    let winner_output = variants
+        .iter()
+        .find(|v| v.config.name == winner.variant_name)
+        .ok_or("Winner is not among the variants")?
+        .execute(input);
+    Ok(SoaeReport {
+        cpu: CpuFeatures::detect(),
+        pinned: sandbox.pin_choice(),
+        input,
+        distribution: options.inputs.clone(),
+        points,
+        variants,
+        rankings,
+        winner_output,
+    })
+}
+
+/// One hot function's variants, each measured through `main` with every
+/// other function fixed
+#[derive(Debug)]
+pub struct FunctionRankings {
+    pub function: String,
+    /// Share of the profiled run's self cycles
+    pub share: f64,
+    /// Measurements, fastest first, named after the function's config
+    pub rankings: Vec<RankedVariant>,
+    pub winner: VariantConfig,
+}
+
+/// Outcome of `run_per_function`
+#[derive(Debug)]
+pub struct PerFunctionReport {
+    pub cpu: CpuFeatures,
+    /// Where the sandbox pinned the measurements, if it could
+    pub pinned: Option<PinChoice>,
+    pub input: u64,
+    /// Per-function cycles of one profiled call of `main(input)`
+    pub profile: ProfileReport,
+    /// Hot functions in the order they were tuned, hottest first
+    pub functions: Vec<FunctionRankings>,
+    /// Config of the functions that weren't hot enough to tune
+    pub default: VariantConfig,
+    /// Every hot function under its winner, the rest under `default`
+    pub combined: CompiledVariant,
+    pub combined_result: BenchmarkResult,
+    /// What the combined program returned for `input`
+    pub winner_output: u64,
+}
+
+/// Profile one call of `main(input)`, then tune each function with at
+/// least `options.hot_share` of the cycles (at least the hottest), hottest
+/// first: its variants are benchmarked through `main` with the functions
+/// tuned so far under their winners and the rest under the cheapest
+/// config. The winners are combined into one program.
+pub fn run_per_function(program: &Program, options: &SoaeOptions) -> Result<PerFunctionReport, String> {
+    let generator = options.generator();
+    let sandbox = NanosecondSandbox::new(options.sandbox.clone());
+    let input = options.input;
+    let profile = profile_main(program, input)?;
+    let mut hot = profile.hot_functions(options.hot_share);
+    if hot.is_empty() {
+        hot.extend(profile.by_self_cycles().first().map(|f| f.name.as_str()));
+    }
+    if hot.is_empty() {
+        return Err("Profiling main() recorded no calls".to_string());
+    }
+    let default = generator
+        .configs_for(program)
+        .into_iter()
+        .next()
+        .ok_or("No variant configuration for this CPU")?;
+
+    let mut fixed = BTreeMap::new();
+    let mut functions = Vec::new();
+    for function in hot {
+        let variants = generator.generate_function_variants(program, function, &fixed, &default)?;
+        let rankings = sandbox.benchmark_all(&variants, input);
+        let best = rankings.first().ok_or("No variant was measured")?;
+        let winner = variants
+            .iter()
+            .find(|v| v.config.name == best.variant_name)
+            .ok_or("Winner is not among the variants")?
+            .config
+            .clone();
+        fixed.insert(function.to_string(), winner.clone());
+        functions.push(FunctionRankings {
+            function: function.to_string(),
+            share: profile.self_share(function),
+            rankings,
+            winner,
+        });
+    }
+
+    let combined = generator.compile_per_function(program, &fixed, &default)?;
+    let combined_result = sandbox.benchmark(&combined, input);
+    let winner_output = combined.execute(input);
+    Ok(PerFunctionReport {
+        cpu: CpuFeatures::detect(),
+        pinned: sandbox.pin_choice(),
+        input,
+        profile,
+        functions,
+        default,
+        combined,
+        combined_result,
+        winner_output,
+    })
+}
+
+/// Per-function cycles of one warm call of `main(input)`
+fn profile_main(program: &Program, input: u64) -> Result<ProfileReport, String> {
+    let compiled = CompiledProgram::compile(program, &CompileOptions::new(1).profile(true))?;
+    let main: extern "C" fn(u64) -> u64 = compiled
+        .get("main")
+        .ok_or("Scripts need an entry point fn main(n) with at most one parameter")?;
+    // A cold first call charges cache misses to whoever runs first
+    main(input);
+    let session = ProfileSession::start(&compiled.code().profiled_functions);
+    main(input);
+    Ok(session.finish())
+}
+
+/// One bandit decision of `learn`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LearningStep {
+    /// Index of the variant the bandit tried
+    pub selected: usize,
+    /// Index of the bandit's best guess after the update
+    pub best_guess: usize,
+    pub cycles_per_op: u64,
+}
+
+/// Outcome of `learn`
+#[derive(Debug)]
+pub struct LearningReport {
+    pub cpu: CpuFeatures,
+    pub variant_names: Vec<String>,
+    /// Fastest variant by exhaustive measurement (the ground truth)
+    pub true_best: String,
+    /// One per iteration
+    pub steps: Vec<LearningStep>,
+    pub bandit: VariantBandit,
+    /// What the bandit's final choice returned for the input
+    pub winner_output: u64,
+}
+
+impl LearningReport {
+    /// The bandit's final choice
+    pub fn final_best(&self) -> &str {
+        &self.variant_names[self.bandit.get_best()]
+    }
+
+    pub fn converged(&self) -> bool {
+        self.final_best() == self.true_best
+    }
+
+    /// Percentage of the first `iterations` selections that picked the true best
+    pub fn accuracy(&self, iterations: usize) -> f64 {
+        let correct = self.steps[..iterations]
+            .iter()
+            .filter(|s| self.variant_names[s.selected] == self.true_best)
+            .count();
+        correct as f64 / iterations.max(1) as f64 * 100.0
+    }
+}
+
+/// Let a Thompson Sampling bandit pick variants of `program` for
+/// `iterations` rounds (fewer if a shutdown is requested), rewarding each by
+/// its measured speed. Every update is appended to `log`; a failed write
+/// disables logging, not the run.
+pub fn learn(
+    program: &Program,
+    iterations: u32,
+    options: &SoaeOptions,
+    log: &mut Option<BrainLog>,
+) -> Result<LearningReport, String> {
+    let variants = options.variants(program)?;
+    let variant_names: Vec<String> = variants.iter().map(|v| v.config.name.clone()).collect();
+    let sandbox = NanosecondSandbox::new(options.sandbox.clone());
+    let input = options.input;
+
+    let mut bandit = match options.seed {
+        Some(seed) => VariantBandit::with_seed(variant_names.clone(), seed),
+        None => VariantBandit::new(variant_names.clone()),
+    };
+    if !options.cold_start {
+        bandit.warm_start(&configs_of(&variants), input);
+    }
+
+    // Measure everything once to know the true best (for validation)
+    let mut recording = options.record.as_deref();
+    let true_rankings = sandbox.benchmark_all(&variants, input);
+    record_rankings(&mut recording, &variant_names, input, &true_rankings);
+    let best = true_rankings.first().ok_or("No variant was measured")?;
+    let (true_best, best_cycles) = (best.variant_name.clone(), best.result.cycles_per_op);
+
+    // From here on only the bandit's pick runs: the others may be reclaimed
+    // under the JIT memory cap and recompiled when picked again
+    let cache = VariantCache::new(program, options.generator());
+    for variant in variants {
+        cache.insert(variant);
+    }
+
+    let context = OptimizationFeatures::new(input);
+    let mut steps = Vec::with_capacity(iterations as usize);
+    for _ in 0..iterations {
+        if shutdown::requested() {
+            break;
+        }
+        let selected = bandit.select();
+        let result = sandbox.benchmark(&*cache.get(&variant_names[selected])?, input);
+        record_measurement(&mut recording, &variant_names, selected, input, result.cycles_per_op);
+        bandit.update_with_performance(selected, result.cycles_per_op, best_cycles);
+        record(
+            log,
+            &context,
+            selected,
+            &variant_names[selected],
+            result.cycles_per_op,
+            best_cycles,
+        );
+        cache.activate(&variant_names[bandit.get_best()]);
+        steps.push(LearningStep {
+            selected,
+            best_guess: bandit.get_best(),
+            cycles_per_op: result.cycles_per_op,
+        });
+    }
+
+    let winner_output = cache.get(&variant_names[bandit.get_best()])?.execute(input);
+    Ok(LearningReport {
+        cpu: CpuFeatures::detect(),
+        variant_names,
+        true_best,
+        steps,
+        bandit,
+        winner_output,
+    })
+}
+
+/// One bandit decision of `learn_contextual`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContextStep {
+    /// Input size drawn for this round
+    pub input: u64,
+    pub selected: usize,
+    pub cycles_per_op: u64,
+    /// Cycles/op of the fastest variant at this input size
+    pub best_cycles: u64,
+}
+
+impl ContextStep {
+    pub fn bucket(&self) -> SizeBucket {
+        SizeBucket::from_size(self.input)
+    }
+}
+
+/// Outcome of `learn_contextual`
+#[derive(Debug)]
+pub struct ContextReport {
+    pub cpu: CpuFeatures,
+    pub variant_names: Vec<String>,
+    /// One per iteration
+    pub steps: Vec<ContextStep>,
+    pub bandit: ContextualBandit,
+}
+
+impl ContextReport {
+    /// Learned best variant and its confidence per size bucket
+    pub fn decision_boundary(&self) -> Vec<(SizeBucket, String, f64)> {
+        self.bandit.get_decision_boundary()
+    }
+}
+
+/// `learn` with a contextual bandit and input sizes drawn from
+/// `CONTEXT_SIZES`, so it learns which variant wins in each size bucket
+pub fn learn_contextual(
+    program: &Program,
+    iterations: u32,
+    options: &SoaeOptions,
+    log: &mut Option<BrainLog>,
+) -> Result<ContextReport, String> {
+    let variants = options.variants(program)?;
+    let variant_names: Vec<String> = variants.iter().map(|v| v.config.name.clone()).collect();
+    let sandbox = NanosecondSandbox::new(options.sandbox.clone());
+
+    let mut bandit = match options.seed {
+        Some(seed) => ContextualBandit::with_seed(variant_names.clone(), seed),
+        None => ContextualBandit::new(variant_names.clone()),
+    };
+    if !options.cold_start {
+        bandit.warm_start(&configs_of(&variants));
+    }
+    // Input sizes get their own stream so they don't shift with bandit sampling
+    let mut rng = match options.seed {
+        Some(seed) => StdRng::seed_from_u64(seed ^ 0x5eed_5eed),
+        None => StdRng::from_entropy(),
+    };
+
+    let mut recording = options.record.as_deref();
+    let mut steps = Vec::with_capacity(iterations as usize);
+    for _ in 0..iterations {
+        if shutdown::requested() {
+            break;
+        }
+        let input = CONTEXT_SIZES[rng.gen_range(0..CONTEXT_SIZES.len())];
+        let context = OptimizationFeatures::new(input);
+        let selected = bandit.select(&context);
+        let result = sandbox.benchmark(&variants[selected], input);
+        record_measurement(&mut recording, &variant_names, selected, input, result.cycles_per_op);
+
+        // The fastest variant at this size sets the reward scale
+        let rankings = sandbox.benchmark_all(&variants, input);
+        record_rankings(&mut recording, &variant_names, input, &rankings);
+        let best_cycles = rankings.first().map_or(1, |r| r.result.cycles_per_op);
+
+        bandit.update_with_performance(&context, selected, result.cycles_per_op, best_cycles);
+        record(
+            log,
+            &context,
+            selected,
+            &variant_names[selected],
+            result.cycles_per_op,
+            best_cycles,
+        );
+        steps.push(ContextStep {
+            input,
+            selected,
+            cycles_per_op: result.cycles_per_op,
+            best_cycles,
+        });
+    }
+
+    Ok(ContextReport {
+        cpu: CpuFeatures::detect(),
+        variant_names,
+        steps,
+        bandit,
+    })
+}
+
+fn configs_of(variants: &[CompiledVariant]) -> Vec<VariantConfig> {
+    variants.iter().map(|v| v.config.clone()).collect()
+}
+
+fn record_measurement(recording: &mut Option<&Path>, names: &[String], variant_idx: usize, input: u64, cycles: u64) {
+    let measurement = Measurement {
+        variant_idx,
+        variant: names[variant_idx].clone(),
+        input,
+        cycles,
+    };
+    simulation::record(recording, &measurement);
+}
+
+fn record_rankings(recording: &mut Option<&Path>, names: &[String], input: u64, rankings: &[RankedVariant]) {
+    for ranked in rankings {
+        if let Some(idx) = names.iter().position(|n| *n == ranked.variant_name) {
+            record_measurement(recording, names, idx, input, ranked.result.cycles_per_op);
+        }
+    }
+}
+
+/// Log an update; a failed write disables logging rather than the run
+fn record(
+    log: &mut Option<BrainLog>,
+    context: &OptimizationFeatures,
+    variant_idx: usize,
+    variant: &str,
+    cycles: u64,
+    best_cycles: u64,
+) {
+    if let Some(writer) = log {
+        if let Err(e) = writer.record(context, variant_idx, variant, cycles, best_cycles) {
+            tracing::warn!("{}; logging disabled", e);
+            *log = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    const INC: &str = "fn main(n) {
+        r = n + 1
+        return r
+    }";
+
+    /// `sum` does nearly all the work
+    const CALLS: &str = "fn main(n) {
+        s = sum(n)
+        t = twice(n)
+        r = s + t
+        return r
+    }
+    fn sum(n) {
+        i = 0
+        s = 0
+        label loop
+        if i >= n goto done
+        s = s + i
+        i = i + 1
+        goto loop
+        label done
+        return s
+    }
+    fn twice(n) {
+        r = n + n
+        return r
+    }";
+
+    fn quick() -> SoaeOptions {
+        SoaeOptions::default().measurement(2, 10).seed(Some(7))
+    }
+
+    #[test]
+    fn test_run_ranks_every_variant() {
+        let program = Parser::new().parse(INC).unwrap();
+        let report = run(&program, &quick().input(41)).unwrap();
+        assert_eq!(report.rankings.len(), report.variants.len());
+        assert_eq!(report.winner().rank, 0);
+        assert!(report
+            .rankings
+            .windows(2)
+            .all(|w| w[0].result.cycles_per_op <= w[1].result.cycles_per_op));
+        assert_eq!(report.winner_output, 42);
+
+        let explained = report.explain(2);
+        assert_eq!(explained.len(), 2);
+        assert!(explained[0].starts_with("#1 "), "{}", explained[0]);
+
+        let dist = InputDistribution::Uniform { low: 10, high: 50 };
+        let report = run(&program, &quick().inputs(dist)).unwrap();
+        assert_eq!(report.points.len(), DEFAULT_POINTS);
+        assert_eq!((report.input, report.winner_output), (30, 31));
+        assert!(report.rankings.iter().all(|r| {
+            r.cycles_by_input.len() == DEFAULT_POINTS
+                && r.expected_cycles >= *r.cycles_by_input.iter().min().unwrap() as f64
+                && r.expected_cycles <= *r.cycles_by_input.iter().max().unwrap() as f64
+        }));
+        assert!(report
+            .rankings
+            .windows(2)
+            .all(|w| w[0].expected_cycles <= w[1].expected_cycles));
+
+        let err = run(&program, &quick().max_code_size(1)).unwrap_err();
+        assert!(err.contains("code-size budget"), "{}", err);
+    }
+
+    #[test]
+    fn test_tunes_the_hot_function_on_its_own() {
+        let program = Parser::new().parse(CALLS).unwrap();
+        let report = run_per_function(&program, &quick().input(100_000).hot_share(0.5)).unwrap();
+        assert_eq!(report.functions.len(), 1);
+        let sum = &report.functions[0];
+        assert_eq!(sum.function, "sum");
+        assert!(sum.share >= 0.5);
+        assert_eq!(sum.rankings[0].variant_name, sum.winner.name);
+        assert_eq!(report.winner_output, 4_999_950_000 + 200_000);
+
+        // Every function but sum keeps the default config
+        let name = &report.combined.config.name;
+        assert!(name.contains(&format!("sum={}", sum.winner.name)), "{}", name);
+        assert!(name.contains(&format!("twice={}", report.default.name)), "{}", name);
+        assert!(report.combined_result.cycles_per_op > 0);
+    }
+
+    #[test]
+    fn test_learning_reports_every_step() {
+        let program = Parser::new().parse(INC).unwrap();
+        let report = learn(&program, 6, &quick(), &mut None).unwrap();
+        assert_eq!(report.steps.len(), 6);
+        assert!(report.variant_names.contains(&report.true_best));
+        assert_eq!(
+            report.final_best(),
+            report.variant_names[report.steps[5].best_guess]
+        );
+        assert!((0.0..=100.0).contains(&report.accuracy(6)));
+        assert_eq!(report.winner_output, 1001);
+
+        let report = learn_contextual(&program, 4, &quick(), &mut None).unwrap();
+        assert_eq!(report.steps.len(), 4);
+        assert!(report
+            .steps
+            .iter()
+            .all(|s| CONTEXT_SIZES.contains(&s.input)));
+        assert!(!report.decision_boundary().is_empty());
+    }
+}
@@ -0,0 +1,409 @@
+//! Soak Testing
+//!
+//! `nanoforge soak` runs SOAE and evolution over a corpus of scripts again
+//! and again for a set time, the way a nightly stability job would. Every
+//! round checks each script's SOAE winner and evolved winner against the
+//! outputs of its unoptimized build (the golden outputs), turns panics into
+//! failures instead of aborting the soak, and watches the JIT region
+//! registry (`jit_memory::usage`): once a round's variants are dropped, the
+//! process must map no more executable memory than before the soak.
+
+use crate::compiled_program::CompiledProgram;
+use crate::compiler::CompileOptions;
+use crate::evolution::{EvolutionConfig, EvolutionEngine};
+use crate::ir::Program;
+use crate::jit_memory::{self, JitMemoryUsage};
+use crate::parser::Parser;
+use crate::shutdown;
+use crate::soae::{self, SoaeOptions};
+use crate::validator::{TestCase, Validator};
+use serde::Serialize;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+/// Settings of a soak
+#[derive(Debug, Clone)]
+pub struct SoakConfig {
+    /// Start no new round after this long (the first always runs)
+    pub duration: Duration,
+    /// Inputs `main` is checked at
+    pub inputs: Vec<i64>,
+    pub soae: SoaeOptions,
+    /// Generations per evolution run (0 skips evolution)
+    pub generations: u32,
+    pub population: usize,
+    /// Evolution seed of the first round; each round adds one
+    pub seed: u64,
+}
+
+impl Default for SoakConfig {
+    fn default() -> Self {
+        Self {
+            duration: Duration::from_secs(60),
+            inputs: vec![10, 100, 1000],
+            // Soaking checks outputs; rankings needn't be precise
+            soae: SoaeOptions::default().measurement(10, 50),
+            generations: 3,
+            population: 8,
+            seed: 42,
+        }
+    }
+}
+
+impl SoakConfig {
+    pub fn duration(mut self, duration: Duration) -> Self {
+        self.duration = duration;
+        self
+    }
+
+    pub fn inputs(mut self, inputs: Vec<i64>) -> Self {
+        self.inputs = inputs;
+        self
+    }
+
+    pub fn soae(mut self, options: SoaeOptions) -> Self {
+        self.soae = options;
+        self
+    }
+
+    pub fn generations(mut self, generations: u32) -> Self {
+        self.generations = generations;
+        self
+    }
+
+    pub fn population(mut self, population: usize) -> Self {
+        self.population = population;
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+}
+
+/// Where a soak failure happened
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SoakStage {
+    /// Reading, parsing or running the unoptimized build
+    Baseline,
+    Soae,
+    Evolution,
+    /// JIT memory still mapped after a round
+    Memory,
+}
+
+impl fmt::Display for SoakStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SoakStage::Baseline => "baseline",
+            SoakStage::Soae => "soae",
+            SoakStage::Evolution => "evolution",
+            SoakStage::Memory => "memory",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SoakFailure {
+    pub round: u32,
+    /// Script path ("-" for the memory check, which covers the round)
+    pub script: String,
+    pub stage: SoakStage,
+    pub message: String,
+}
+
+impl fmt::Display for SoakFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "round {} {} [{}]: {}", self.round, self.script, self.stage, self.message)
+    }
+}
+
+/// One script in one round
+#[derive(Debug, Clone, Serialize)]
+pub struct ScriptSoak {
+    pub round: u32,
+    pub script: String,
+    /// The SOAE winner's variant name, if SOAE ran
+    pub winner: Option<String>,
+    /// The evolved winner's speedup over the seed, if evolution ran
+    pub evolved_speedup: Option<f64>,
+    pub failures: Vec<SoakFailure>,
+}
+
+impl ScriptSoak {
+    fn new(round: u32, script: &str) -> Self {
+        Self {
+            round,
+            script: script.to_string(),
+            winner: None,
+            evolved_speedup: None,
+            failures: Vec::new(),
+        }
+    }
+
+    fn fail(&mut self, stage: SoakStage, message: impl Into<String>) {
+        self.failures.push(SoakFailure {
+            round: self.round,
+            script: self.script.clone(),
+            stage,
+            message: message.into(),
+        });
+    }
+}
+
+/// Outcome of `run`
+#[derive(Debug, Clone, Serialize)]
+pub struct SoakReport {
+    pub rounds: u32,
+    /// Scripts soaked, over every round
+    pub runs: usize,
+    pub elapsed_ms: u64,
+    pub memory_before: JitMemoryUsage,
+    pub memory_after: JitMemoryUsage,
+    pub failures: Vec<SoakFailure>,
+}
+
+impl SoakReport {
+    pub fn passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/// A failure message if `after` maps more JIT memory than `before`
+pub fn leak(before: JitMemoryUsage, after: JitMemoryUsage) -> Option<String> {
+    (after.regions > before.regions || after.bytes > before.bytes).then(|| {
+        format!(
+            "{} JIT region(s), {} bytes still mapped; {} region(s), {} bytes before the soak",
+            after.regions, after.bytes, before.regions, before.bytes
+        )
+    })
+}
+
+/// What `main` returns for each input in its unoptimized build
+pub fn golden_outputs(program: &Program, inputs: &[i64]) -> Result<Vec<TestCase>, String> {
+    let compiled = CompiledProgram::compile(program, &CompileOptions::new(0))?;
+    let main = compiled.get_fn("main").ok_or("No main() to soak")?;
+    if main.arity() > 1 {
+        return Err("main() must take at most one parameter".to_string());
+    }
+    inputs
+        .iter()
+        .map(|&input| Ok(TestCase::new(input, main.call(&[input][..main.arity()])?)))
+        .collect()
+}
+
+/// Run `f`, turning a panic into an error
+fn guarded<T>(f: impl FnOnce() -> Result<T, String>) -> Result<T, String> {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|payload| {
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown payload".to_string());
+        Err(format!("panicked: {}", message))
+    })
+}
+
+/// Soak `source` once: SOAE, then evolution if it is a single function
+pub fn soak_script(script: &str, source: &str, config: &SoakConfig, round: u32) -> ScriptSoak {
+    let mut soak = ScriptSoak::new(round, script);
+    let golden = guarded(|| {
+        let program = Parser::new().parse(source)?;
+        let cases = golden_outputs(&program, &config.inputs)?;
+        Ok((program, cases))
+    });
+    let (program, cases) = match golden {
+        Ok(golden) => golden,
+        Err(e) => {
+            soak.fail(SoakStage::Baseline, e);
+            return soak;
+        }
+    };
+
+    let soae = guarded(|| {
+        let report = soae::run(&program, &config.soae)?;
+        let name = report.winner().variant_name.clone();
+        let winner = report.variant(&name).ok_or("Winner is not among the variants")?;
+        let main = winner.program.get_fn("main").ok_or("Winner has no main()")?;
+        let mismatches: Vec<String> = cases
+            .iter()
+            .filter_map(|case| {
+                let got = main.call(&[case.input][..main.arity()]);
+                (got != Ok(case.expected_output)).then(|| {
+                    let got = got.map_or_else(|e| e, |v| v.to_string());
+                    format!("main({}) = {}, expected {}", case.input, got, case.expected_output)
+                })
+            })
+            .collect();
+        Ok((name, mismatches))
+    });
+    match soae {
+        Ok((name, mismatches)) => {
+            if !mismatches.is_empty() {
+                soak.fail(SoakStage::Soae, format!("winner {}: {}", name, mismatches.join("; ")));
+            }
+            soak.winner = Some(name);
+        }
+        Err(e) => soak.fail(SoakStage::Soae, e),
+    }
+
+    // Genomes are single functions: scripts with calls can't evolve
+    if config.generations > 0 && program.functions.len() == 1 {
+        let evolved = guarded(|| {
+            let evolution = EvolutionConfig {
+                population_size: config.population,
+                seed: config.seed.wrapping_add(round as u64),
+                ..EvolutionConfig::default()
+            };
+            let mut engine = EvolutionEngine::new(&program.functions[0], cases.clone(), evolution);
+            let result = engine.run(config.generations, None);
+            let verdict = Validator::default().validate(&result.best_genome, &cases);
+            if verdict.is_valid() {
+                Ok(result.final_speedup)
+            } else {
+                Err(format!(
+                    "winner of generation {} failed re-verification: {:?}",
+                    result.best_genome.generation, verdict
+                ))
+            }
+        });
+        match evolved {
+            Ok(speedup) => soak.evolved_speedup = Some(speedup),
+            Err(e) => soak.fail(SoakStage::Evolution, e),
+        }
+    }
+    soak
+}
+
+/// Soak `scripts` round after round until `config.duration` is up or a
+/// shutdown is requested, passing each script's outcome to `on_script`
+pub fn run(
+    scripts: &[PathBuf],
+    config: &SoakConfig,
+    mut on_script: impl FnMut(&ScriptSoak),
+) -> SoakReport {
+    let start = Instant::now();
+    let memory_before = jit_memory::usage();
+    let mut report = SoakReport {
+        rounds: 0,
+        runs: 0,
+        elapsed_ms: 0,
+        memory_before,
+        memory_after: memory_before,
+        failures: Vec::new(),
+    };
+    // Report a leak once, then again only if it grows
+    let mut leaked = memory_before;
+    while report.rounds == 0 || (start.elapsed() < config.duration && !shutdown::requested()) {
+        report.rounds += 1;
+        for path in scripts {
+            if shutdown::requested() {
+                break;
+            }
+            let name = path.display().to_string();
+            let soak = match std::fs::read_to_string(path) {
+                Ok(source) => soak_script(&name, &source, config, report.rounds),
+                Err(e) => {
+                    let mut soak = ScriptSoak::new(report.rounds, &name);
+                    soak.fail(SoakStage::Baseline, e.to_string());
+                    soak
+                }
+            };
+            on_script(&soak);
+            report.runs += 1;
+            report.failures.extend(soak.failures);
+        }
+        let usage = jit_memory::usage();
+        let grew = usage.regions > leaked.regions || usage.bytes > leaked.bytes;
+        if let Some(message) = leak(memory_before, usage).filter(|_| grew) {
+            report.failures.push(SoakFailure {
+                round: report.rounds,
+                script: "-".to_string(),
+                stage: SoakStage::Memory,
+                message,
+            });
+            leaked = usage;
+        }
+        report.memory_after = usage;
+    }
+    report.elapsed_ms = start.elapsed().as_millis() as u64;
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SUM: &str = "fn main(n) {
+        s = 0
+        i = 0
+        while i < n {
+            s = s + i
+            i = i + 1
+        }
+        return s
+    }";
+
+    fn quick() -> SoakConfig {
+        SoakConfig::default()
+            .inputs(vec![0, 7, 100])
+            .soae(SoaeOptions::default().measurement(2, 10))
+            .generations(1)
+            .population(4)
+    }
+
+    #[test]
+    fn test_winners_match_the_golden_outputs() {
+        let program = Parser::new().parse(SUM).unwrap();
+        let golden = golden_outputs(&program, &[0, 7, 100]).unwrap();
+        let outputs: Vec<i64> = golden.iter().map(|c| c.expected_output).collect();
+        assert_eq!(outputs, [0, 21, 4950]);
+
+        let soak = soak_script("sum.nf", SUM, &quick(), 1);
+        assert_eq!(soak.failures, []);
+        assert!(soak.winner.is_some());
+        assert!(soak.evolved_speedup.is_some());
+    }
+
+    #[test]
+    fn test_failures_name_the_stage() {
+        let soak = soak_script("bad.nf", "fn main( {", &quick(), 2);
+        assert_eq!(soak.failures.len(), 1);
+        assert_eq!(soak.failures[0].stage, SoakStage::Baseline);
+        assert!(soak.failures[0].to_string().starts_with("round 2 bad.nf [baseline]: "));
+
+        let two = "fn main(a, b) {\nreturn a\n}";
+        let soak = soak_script("two.nf", two, &quick(), 1);
+        assert_eq!(soak.failures[0].message, "main() must take at most one parameter");
+
+        // A failing assertion in the unoptimized build
+        let asserted = "fn main(n) {\nassert n < 50\nreturn n\n}";
+        let soak = soak_script("assert.nf", asserted, &quick(), 1);
+        assert_eq!(
+            soak.failures[0].message,
+            "main(): assertion failed at line 2: n < 50 (100 vs 50)"
+        );
+    }
+
+    #[test]
+    fn test_leak_compares_the_registry() {
+        let before = JitMemoryUsage {
+            regions: 2,
+            bytes: 8192,
+            ..JitMemoryUsage::default()
+        };
+        assert_eq!(leak(before, before), None);
+        let after = JitMemoryUsage {
+            regions: 3,
+            ..before
+        };
+        assert_eq!(
+            leak(before, after),
+            Some("3 JIT region(s), 8192 bytes still mapped; 2 region(s), 8192 bytes before the soak".to_string())
+        );
+    }
+}
@@ -0,0 +1,215 @@
+//! Spill Impact
+//!
+//! `nanoforge spill-impact` measures what a spill costs on this machine. A
+//! script is compiled once with the register allocator's own choices and
+//! once per virtual register with that register forced onto the stack
+//! (`CompileOptions::force_spill`), and the sandbox times `main(n)` for each
+//! build. Under pressure the linear scan spills whichever live interval ends
+//! furthest away; ranking every vreg by what spilling it costs shows whether
+//! that was the cheap one to give up.
+
+use crate::compiled_program::CompiledProgram;
+use crate::compiler::CompileOptions;
+use crate::parser::Parser;
+use crate::pass_impact::entry;
+use crate::sandbox::NanosecondSandbox;
+use crate::soae::SoaeOptions;
+use serde::Serialize;
+
+/// One build with some vregs forced onto the stack
+#[derive(Debug, Clone, Serialize)]
+pub struct SpillMeasurement {
+    /// The vregs forced, e.g. ["r12"]
+    pub vregs: Vec<String>,
+    /// Functions that kept one of them in a machine register
+    pub functions: Vec<String>,
+    /// Cycles/op with them spilled; None if that changed nothing
+    pub cycles_per_op: Option<u64>,
+}
+
+/// Every spill measured on one script
+#[derive(Debug, Clone, Serialize)]
+pub struct SpillImpact {
+    pub script: String,
+    /// Cycles/op with the allocator's own choices
+    pub baseline_cycles_per_op: u64,
+    /// What the allocator spilled by itself, as "function:vreg"
+    pub natural_spills: Vec<String>,
+    pub spills: Vec<SpillMeasurement>,
+}
+
+impl SpillImpact {
+    /// How many times slower `m` is than the baseline (None if forcing its
+    /// spills changed nothing)
+    pub fn slowdown(&self, m: &SpillMeasurement) -> Option<f64> {
+        Some(m.cycles_per_op?.max(1) as f64 / self.baseline_cycles_per_op.max(1) as f64)
+    }
+
+    /// The measurements that changed the code, most expensive spill first
+    pub fn ranked(&self) -> Vec<(&SpillMeasurement, f64)> {
+        let mut ranked: Vec<_> = self
+            .spills
+            .iter()
+            .filter_map(|m| Some((m, self.slowdown(m)?)))
+            .collect();
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+        ranked
+    }
+}
+
+/// Parse a general-purpose vreg: "r12" or "12"
+pub fn parse_vreg(s: &str) -> Result<u8, String> {
+    let digits = s.strip_prefix('r').unwrap_or(s);
+    digits
+        .parse()
+        .map_err(|_| format!("'{}' is not a virtual register like r12", s))
+}
+
+/// Compile `source` with `base`, then with each of `vregs` forced onto the
+/// stack in turn (and with all of them at once if there are several), and
+/// time `main(options.input)` for every build that differs. With no `vregs`,
+/// every vreg the allocator kept in a register is tried.
+pub fn measure(
+    script: &str,
+    source: &str,
+    base: &CompileOptions,
+    options: &SoaeOptions,
+    vregs: &[u8],
+) -> Result<SpillImpact, String> {
+    let program = Parser::new()
+        .parse(source)
+        .map_err(|e| format!("Parsing Error: {}", e))?;
+    let sandbox = NanosecondSandbox::new(options.sandbox.clone());
+
+    let baseline = CompiledProgram::compile(&program, base)?;
+    let main = entry(&baseline)?;
+    let expected = main(options.input);
+    let baseline_cycles_per_op = sandbox.benchmark_fn(main, options.input).cycles_per_op;
+
+    let report = &baseline.code().report;
+    let natural_spills = report
+        .functions
+        .iter()
+        .flat_map(|f| f.spilled.iter().map(move |vreg| format!("{}:{}", f.name, vreg)))
+        .collect();
+    // Precolored registers (call arguments and results) can't be spilled
+    let mut candidates: Vec<u8> = report
+        .functions
+        .iter()
+        .flat_map(|f| f.allocated.iter().filter_map(|vreg| parse_vreg(vreg).ok()))
+        .filter(|&r| r >= 5)
+        .collect();
+    candidates.sort();
+    candidates.dedup();
+
+    let mut sets: Vec<Vec<u8>> = if vregs.is_empty() {
+        candidates.iter().map(|&r| vec![r]).collect()
+    } else {
+        vregs.iter().map(|&r| vec![r]).collect()
+    };
+    if vregs.len() > 1 {
+        sets.push(vregs.to_vec());
+    }
+
+    let mut spills = Vec::new();
+    for set in sets {
+        let names: Vec<String> = set.iter().map(|r| format!("r{}", r)).collect();
+        let functions = report
+            .functions
+            .iter()
+            .filter(|f| f.allocated.iter().any(|vreg| names.contains(vreg)))
+            .map(|f| f.name.clone())
+            .collect();
+        let forced = set.iter().fold(*base, |o, &r| o.force_spill(r));
+        let spilled = CompiledProgram::compile(&program, &forced)?;
+        if spilled.code().code == baseline.code().code {
+            spills.push(SpillMeasurement {
+                vregs: names,
+                functions,
+                cycles_per_op: None,
+            });
+            continue;
+        }
+        let main = entry(&spilled)?;
+        let output = main(options.input);
+        if output != expected {
+            return Err(format!(
+                "main({}) returns {} with {} spilled but {} without",
+                options.input,
+                output as i64,
+                names.join("+"),
+                expected as i64
+            ));
+        }
+        spills.push(SpillMeasurement {
+            vregs: names,
+            functions,
+            cycles_per_op: Some(sandbox.benchmark_fn(main, options.input).cycles_per_op),
+        });
+    }
+    Ok(SpillImpact {
+        script: script.to_string(),
+        baseline_cycles_per_op,
+        natural_spills,
+        spills,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SUM: &str = "fn main(n) {
+        i = 0
+        s = 0
+        label loop
+        if i >= n goto done
+        s = s + i
+        i = i + 1
+        goto loop
+        label done
+        return s
+    }";
+
+    #[test]
+    fn test_forced_spills_keep_results_and_show_in_the_report() {
+        let program = Parser::new().parse(SUM).unwrap();
+        let natural = CompiledProgram::compile(&program, &CompileOptions::new(2)).unwrap();
+        let main = &natural.code().report.functions[0];
+        assert!(main.spilled.is_empty(), "{:?}", main.spilled);
+        let vreg = main
+            .allocated
+            .iter()
+            .filter_map(|v| parse_vreg(v).ok())
+            .find(|&r| r >= 5)
+            .unwrap();
+
+        let options = CompileOptions::new(2).force_spill(vreg);
+        let forced = CompiledProgram::compile(&program, &options).unwrap();
+        assert_eq!(forced.code().report.functions[0].spilled, [format!("r{}", vreg)]);
+        assert_eq!(entry(&forced).unwrap()(100), entry(&natural).unwrap()(100));
+
+        let err = CompiledProgram::compile(&program, &CompileOptions::new(2).force_spill(0)).err();
+        assert!(err.unwrap().contains("r0 is pinned"));
+    }
+
+    #[test]
+    fn test_measures_every_allocated_vreg() {
+        let options = SoaeOptions::default().measurement(2, 10).input(100);
+        let impact = measure("sum.nf", SUM, &CompileOptions::new(2), &options, &[]).unwrap();
+        assert!(impact.natural_spills.is_empty());
+        assert!(!impact.spills.is_empty());
+        assert!(impact.spills.iter().all(|m| m.vregs.len() == 1 && m.functions == ["main"]));
+        assert_eq!(impact.ranked().len(), impact.spills.len());
+
+        let chosen = [impact.spills[0].vregs[0].as_str(), "r250"].map(|v| parse_vreg(v).unwrap());
+        let impact = measure("sum.nf", SUM, &CompileOptions::new(2), &options, &chosen).unwrap();
+        let vregs: Vec<_> = impact.spills.iter().map(|m| m.vregs.join("+")).collect();
+        let first = format!("r{}", chosen[0]);
+        assert_eq!(vregs, [first.clone(), "r250".to_string(), format!("{}+r250", first)]);
+        // Not in the program, so nothing to spill
+        assert_eq!(impact.spills[1].cycles_per_op, None);
+        assert!(impact.spills[2].cycles_per_op.is_some());
+        assert!(parse_vreg("x1").is_err());
+    }
+}
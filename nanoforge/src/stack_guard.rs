@@ -0,0 +1,144 @@
+//! Stack Guard
+//!
+//! A recursive script with a bad base case would run off the end of its
+//! thread's stack and die with SIGSEGV long before any loop ran out of fuel.
+//! Every compiled function compares RSP against a per-thread limit on entry
+//! and, below it, traps with `Status::StackOverflow` and returns 0 like the
+//! other fail paths.
+//!
+//! The limit lives where GCC's split stacks keep theirs, in the thread
+//! control block at `fs:[0x70]` (`__private_ss`, unused by Rust), so the
+//! check is a single `cmp`. `arm` sets it `HEADROOM` bytes above the bottom
+//! of the calling thread's stack, which `status::reset` does before every
+//! checked call. On a thread that never armed the limit is 0 and never
+//! trips (or, if glibc handed it a cached stack, that stack's limit).
+//! x86-64 glibc only; elsewhere nothing is checked.
+//!
+//! Split-stack code (`-fsplit-stack`) in the same process is not supported:
+//! it keeps its own limit in that slot. `arm` finds a limit it didn't set
+//! there, leaves it alone and warns, and `foreign_limit` reports it; the
+//! guard then only trips below that limit.
+
+use std::cell::Cell;
+
+/// Whether generated code checks the stack on this target
+pub const SUPPORTED: bool = cfg!(all(target_arch = "x86_64", target_env = "gnu"));
+
+/// Offset of the limit in the thread control block (`tcbhead_t::__private_ss`)
+pub const LIMIT_OFFSET: i32 = 0x70;
+
+/// Stack left for the runtime calls a trapping function still makes
+pub const HEADROOM: usize = 64 * 1024;
+
+thread_local! {
+    static ARMED: Cell<bool> = const { Cell::new(false) };
+    static FOREIGN: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Set this thread's limit from its stack bounds, once, unless the slot
+/// already holds a limit `arm` didn't set
+pub fn arm() {
+    if !SUPPORTED || ARMED.with(|a| a.replace(true)) {
+        return;
+    }
+    let Some(bottom) = stack_bottom() else {
+        return;
+    };
+    let ours = bottom + HEADROOM;
+    match limit() {
+        0 => set_limit(ours),
+        // A cached stack, armed by the thread that had it before
+        current if current == ours => {}
+        current => {
+            FOREIGN.with(|f| f.set(true));
+            tracing::warn!(
+                "Stack limit slot fs:[{:#x}] already holds {:#x} (split-stack code?); \
+                 leaving it alone",
+                LIMIT_OFFSET,
+                current
+            );
+        }
+    }
+}
+
+/// Whether `arm` found a limit it didn't set on this thread, such as
+/// split-stack code's, and left the guard to that
+pub fn foreign_limit() -> bool {
+    FOREIGN.with(Cell::get)
+}
+
+/// Lowest address of the calling thread's stack
+fn stack_bottom() -> Option<usize> {
+    unsafe {
+        let mut attr: libc::pthread_attr_t = std::mem::zeroed();
+        if libc::pthread_getattr_np(libc::pthread_self(), &mut attr) != 0 {
+            return None;
+        }
+        let mut addr = std::ptr::null_mut();
+        let mut size = 0;
+        let found = libc::pthread_attr_getstack(&attr, &mut addr, &mut size) == 0;
+        libc::pthread_attr_destroy(&mut attr);
+        found.then_some(addr as usize)
+    }
+}
+
+/// Trap functions entered with RSP below `limit` on this thread (0 disables)
+pub fn set_limit(limit: usize) {
+    #[cfg(all(target_arch = "x86_64", target_env = "gnu"))]
+    unsafe {
+        std::arch::asm!("mov qword ptr fs:[0x70], {}", in(reg) limit, options(nostack));
+    }
+    #[cfg(not(all(target_arch = "x86_64", target_env = "gnu")))]
+    let _ = limit;
+}
+
+/// This thread's limit
+pub fn limit() -> usize {
+    #[cfg(all(target_arch = "x86_64", target_env = "gnu"))]
+    unsafe {
+        let limit: usize;
+        std::arch::asm!("mov {}, qword ptr fs:[0x70]", out(reg) limit, options(nostack, readonly));
+        limit
+    }
+    #[cfg(not(all(target_arch = "x86_64", target_env = "gnu")))]
+    0
+}
+
+#[cfg(all(test, target_arch = "x86_64", target_env = "gnu"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limit_is_per_thread() {
+        arm();
+        let limit = limit();
+        assert!(limit > 0);
+        let local = 0u8;
+        assert!(limit < &local as *const u8 as usize);
+        // Another thread starts unarmed, or with the limit of an earlier
+        // thread whose stack glibc reused
+        std::thread::spawn(|| {
+            let local = 0u8;
+            assert!(super::limit() < &local as *const u8 as usize);
+        })
+        .join()
+        .unwrap();
+        assert_eq!(super::limit(), limit);
+        assert!(!foreign_limit());
+    }
+
+    #[test]
+    fn test_leaves_a_split_stack_limit_alone() {
+        std::thread::spawn(|| {
+            // As split-stack code would have set it
+            set_limit(0x1000);
+            arm();
+            assert_eq!(limit(), 0x1000);
+            assert!(foreign_limit());
+            // Don't hand the value on with this thread's cached stack
+            set_limit(0);
+        })
+        .join()
+        .unwrap();
+    }
+}
@@ -0,0 +1,128 @@
+//! Call Status Channel
+//!
+//! JIT code reports abnormal exits out of band instead of through its return
+//! value, so a function that legitimately returns any integer can't be
+//! mistaken for one that ran out of fuel. Fail paths call `nf_trap`, which
+//! records a `Status` in a per-thread slot, and then return 0. The slot is
+//! sticky: the first trap wins and callers up the JIT stack keep running to
+//! their normal return. Wrappers (`NanoFn::call`, `HotFunction::call_checked`,
+//! `LazyProgram::call`) `reset` the slot before a call and `take` it after.
+//! `reset` also arms the thread's `stack_guard`.
+
+use crate::stack_guard;
+use std::cell::Cell;
+use std::fmt;
+
+/// How the most recent call into JIT code ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Status {
+    /// Returned normally; the result is meaningful
+    #[default]
+    Ok,
+    /// A loop ran out of fuel (`CompiledProgram::set_fuel`)
+    FuelExhausted,
+    /// The sanitizer caught a memory error (see `sanitizer::take_fault`)
+    Trapped,
+    /// A lazily compiled callee failed to compile (see `LazyProgram::errors`)
+    CompileFailed,
+    /// Checked arithmetic overflowed (see `overflow::take_site`)
+    Overflow,
+    /// A function was entered too close to the end of the stack (see
+    /// `stack_guard`)
+    StackOverflow,
+    /// A script `assert` failed (see `assertion::take_failure`)
+    AssertFailed,
+}
+
+impl Status {
+    /// Value passed to `nf_trap` by generated code
+    pub fn code(self) -> i32 {
+        match self {
+            Status::Ok => 0,
+            Status::FuelExhausted => 1,
+            Status::Trapped => 2,
+            Status::CompileFailed => 3,
+            Status::Overflow => 4,
+            Status::StackOverflow => 5,
+            Status::AssertFailed => 6,
+        }
+    }
+
+    /// Inverse of `code`
+    pub fn from_code(code: i64) -> Self {
+        match code {
+            0 => Status::Ok,
+            1 => Status::FuelExhausted,
+            2 => Status::Trapped,
+            4 => Status::Overflow,
+            5 => Status::StackOverflow,
+            6 => Status::AssertFailed,
+            _ => Status::CompileFailed,
+        }
+    }
+
+    pub fn is_ok(self) -> bool {
+        self == Status::Ok
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Status::Ok => "ok",
+            Status::FuelExhausted => "fuel exhausted",
+            Status::Trapped => "trapped by the sanitizer",
+            Status::CompileFailed => "lazy compilation failed",
+            Status::Overflow => "integer overflow",
+            Status::StackOverflow => "stack overflow (runaway recursion?)",
+            Status::AssertFailed => "assertion failed",
+        })
+    }
+}
+
+thread_local! {
+    static STATUS: Cell<Status> = const { Cell::new(Status::Ok) };
+}
+
+/// Clear this thread's status before calling into JIT code (and arm its
+/// stack guard)
+pub fn reset() {
+    stack_guard::arm();
+    STATUS.with(|s| s.set(Status::Ok));
+}
+
+/// Take (and clear) this thread's status after a call
+pub fn take() -> Status {
+    STATUS.with(|s| s.replace(Status::Ok))
+}
+
+/// Record `status` unless an earlier trap already did; true if it was recorded
+pub fn raise(status: Status) -> bool {
+    STATUS.with(|s| {
+        let first = s.get().is_ok();
+        if first {
+            s.set(status);
+        }
+        first
+    })
+}
+
+/// Trap handler called from generated fail paths
+pub extern "C" fn nf_trap(code: i64) {
+    raise(Status::from_code(code));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_first_trap_wins_until_taken() {
+        reset();
+        assert_eq!(take(), Status::Ok);
+        nf_trap(Status::FuelExhausted.code() as i64);
+        nf_trap(Status::Trapped.code() as i64);
+        assert_eq!(take(), Status::FuelExhausted);
+        assert_eq!(take(), Status::Ok);
+    }
+}
@@ -0,0 +1,397 @@
+//! Persistent State
+//!
+//! Everything NanoForge keeps on disk goes through here: bandit brains, the
+//! tuning and pass-schedule stores, evolution checkpoints, status snapshots
+//! and benchmark histories.
+//!
+//! Documents are JSON wrapped in an envelope that names their `Schema`
+//! (kind and version) and carries an FNV-1a checksum of the payload. They
+//! are replaced by writing a temporary file next to them, syncing it and
+//! renaming it over the old one, so a crash leaves the old version or the
+//! new one, never a mix. A damaged file is reported, not deserialized, and
+//! a file from a newer NanoForge is refused rather than misread. Files
+//! written before the envelope existed still load, as version 0.
+//!
+//! Histories are JSON lines, each appended with a single write. A crash can
+//! only tear the last line, which readers skip.
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// `format` of every envelope
+const FORMAT: &str = "nanoforge-state";
+
+/// What a document holds, and the newest layout of it this build writes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Schema {
+    pub kind: &'static str,
+    pub version: u32,
+}
+
+impl Schema {
+    pub const fn new(kind: &'static str, version: u32) -> Self {
+        Self { kind, version }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    format: String,
+    kind: String,
+    version: u32,
+    checksum: String,
+    payload: Value,
+}
+
+/// 64-bit FNV-1a of `bytes`, hex
+fn checksum(bytes: &[u8]) -> String {
+    let hash = bytes.iter().fold(0xcbf2_9ce4_8422_2325u64, |h, &b| {
+        (h ^ b as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    });
+    format!("{:016x}", hash)
+}
+
+/// Write `value` to `path` as a `schema` document, atomically
+pub fn save<T: Serialize>(path: &Path, schema: Schema, value: &T) -> Result<(), String> {
+    let payload = serde_json::to_value(value)
+        .map_err(|e| format!("{}: failed to serialize: {}", path.display(), e))?;
+    let envelope = Envelope {
+        format: FORMAT.to_string(),
+        kind: schema.kind.to_string(),
+        version: schema.version,
+        checksum: checksum(payload.to_string().as_bytes()),
+        payload,
+    };
+    let json = serde_json::to_string_pretty(&envelope)
+        .map_err(|e| format!("{}: failed to serialize: {}", path.display(), e))?;
+    write_atomic(path, json.as_bytes())
+}
+
+/// Replace `path` with `bytes` so readers (and a crash) see all or nothing
+pub fn write_atomic(path: &Path, bytes: &[u8]) -> Result<(), String> {
+    let fail = |e: std::io::Error| format!("{}: failed to write: {}", path.display(), e);
+    let dir = path.parent().filter(|d| !d.as_os_str().is_empty());
+    if let Some(dir) = dir {
+        fs::create_dir_all(dir).map_err(|e| format!("{}: failed to create: {}", dir.display(), e))?;
+    }
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(format!(".{}.tmp", std::process::id()));
+    let tmp = path.with_file_name(tmp_name);
+    let written = File::create(&tmp)
+        .and_then(|mut f| f.write_all(bytes).and_then(|_| f.sync_all()))
+        .and_then(|_| fs::rename(&tmp, path));
+    if let Err(e) = written {
+        let _ = fs::remove_file(&tmp);
+        return Err(fail(e));
+    }
+    // Make the rename itself durable
+    if let Ok(dir) = File::open(dir.unwrap_or(Path::new("."))) {
+        let _ = dir.sync_all();
+    }
+    Ok(())
+}
+
+/// The `schema` document at `path` (None if there is no file). Older
+/// versions load as long as `T` still reads them, e.g. through
+/// `#[serde(default)]` on fields added since.
+pub fn load<T: DeserializeOwned>(path: &Path, schema: Schema) -> Result<Option<T>, String> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("{}: failed to read: {}", path.display(), e)),
+    };
+    let (version, payload) = open_document(&text).map_err(|e| format!("{}: {}", path.display(), e))?;
+    if let Some((kind, version)) = version {
+        if kind != schema.kind {
+            return Err(format!("{}: holds a {}, not a {}", path.display(), kind, schema.kind));
+        }
+        if version > schema.version {
+            return Err(format!(
+                "{}: {} version {} is newer than this build reads (up to {})",
+                path.display(),
+                kind,
+                version,
+                schema.version
+            ));
+        }
+    }
+    serde_json::from_value(payload)
+        .map(Some)
+        .map_err(|e| format!("{}: failed to deserialize {}: {}", path.display(), schema.kind, e))
+}
+
+/// `load`, with a missing file meaning `T::default()`
+pub fn load_or_default<T: DeserializeOwned + Default>(path: &Path, schema: Schema) -> Result<T, String> {
+    Ok(load(path, schema)?.unwrap_or_default())
+}
+
+/// Kind and version (None for a pre-envelope file) and payload of a
+/// document, after checking it isn't damaged
+fn open_document(text: &str) -> Result<(Option<(String, u32)>, Value), String> {
+    let value: Value = serde_json::from_str(text).map_err(|e| format!("damaged: {}", e))?;
+    if value.get("format").and_then(Value::as_str) != Some(FORMAT) {
+        return Ok((None, value));
+    }
+    let envelope: Envelope =
+        serde_json::from_value(value).map_err(|e| format!("damaged envelope: {}", e))?;
+    if checksum(envelope.payload.to_string().as_bytes()) != envelope.checksum {
+        return Err("damaged: checksum mismatch".to_string());
+    }
+    Ok((Some((envelope.kind, envelope.version)), envelope.payload))
+}
+
+/// Append `value` to the history at `path` as one JSON line
+pub fn append_line<T: Serialize>(path: &Path, value: &T) -> Result<(), String> {
+    let mut line = serde_json::to_string(value)
+        .map_err(|e| format!("{}: failed to serialize: {}", path.display(), e))?;
+    line.push('\n');
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut f| f.write_all(line.as_bytes()).and_then(|_| f.sync_data()))
+        .map_err(|e| format!("{}: failed to append: {}", path.display(), e))
+}
+
+/// Every entry of the history at `path` (empty if there is none). A torn
+/// last line is skipped; damage anywhere else is an error.
+pub fn read_lines<T: DeserializeOwned>(path: &Path) -> Result<Vec<T>, String> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("{}: failed to read: {}", path.display(), e)),
+    };
+    let (entries, _) = parse_lines(&text).map_err(|e| format!("{}:{}", path.display(), e))?;
+    Ok(entries)
+}
+
+/// Entries, and whether the last line was torn
+fn parse_lines<T: DeserializeOwned>(text: &str) -> Result<(Vec<T>, bool), String> {
+    let complete = text.ends_with('\n');
+    let lines: Vec<&str> = text.lines().collect();
+    let mut entries = Vec::with_capacity(lines.len());
+    for (i, line) in lines.iter().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(line) {
+            Ok(entry) => entries.push(entry),
+            Err(_) if i + 1 == lines.len() && !complete => return Ok((entries, true)),
+            Err(e) => return Err(format!("{}: damaged: {}", i + 1, e)),
+        }
+    }
+    Ok((entries, false))
+}
+
+/// What `inspect` found in one file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileStatus {
+    Document { kind: String, version: u32 },
+    /// JSON written before the envelope existed
+    Legacy,
+    History { entries: usize, torn: bool },
+    /// A temporary file a crash left behind
+    Leftover,
+    Damaged(String),
+}
+
+impl std::fmt::Display for FileStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            FileStatus::Document { kind, version } => write!(f, "{} v{}", kind, version),
+            FileStatus::Legacy => write!(f, "unversioned JSON"),
+            FileStatus::History { entries, torn } => {
+                write!(f, "history, {} entries", entries)?;
+                if *torn {
+                    write!(f, " (last line torn)")?;
+                }
+                Ok(())
+            }
+            FileStatus::Leftover => write!(f, "leftover of an interrupted write"),
+            FileStatus::Damaged(why) => write!(f, "DAMAGED: {}", why),
+        }
+    }
+}
+
+/// One file of a state directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateFile {
+    pub name: String,
+    pub bytes: u64,
+    pub status: FileStatus,
+}
+
+/// What the file at `path` holds, judged by its name: `.jsonl` histories,
+/// `.tmp` leftovers, and documents otherwise
+pub fn inspect(path: &Path) -> Result<FileStatus, String> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    if name.ends_with(".tmp") {
+        return Ok(FileStatus::Leftover);
+    }
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: failed to read: {}", path.display(), e))?;
+    Ok(if name.ends_with(".jsonl") {
+        match parse_lines::<Value>(&text) {
+            Ok((entries, torn)) => FileStatus::History {
+                entries: entries.len(),
+                torn,
+            },
+            Err(e) => FileStatus::Damaged(format!("line {}", e)),
+        }
+    } else {
+        match open_document(&text) {
+            Ok((Some((kind, version)), _)) => FileStatus::Document { kind, version },
+            Ok((None, _)) => FileStatus::Legacy,
+            Err(e) => FileStatus::Damaged(e),
+        }
+    })
+}
+
+/// The state files in `dir` (`.json`, `.jsonl` and leftover `.tmp`), by name
+pub fn list(dir: &Path) -> Result<Vec<StateFile>, String> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("{}: {}", dir.display(), e)),
+    };
+    let mut files = Vec::new();
+    for path in entries.filter_map(|e| e.ok().map(|e| e.path())) {
+        let is_state = path
+            .extension()
+            .is_some_and(|x| x == "json" || x == "jsonl" || x == "tmp");
+        if !path.is_file() || !is_state {
+            continue;
+        }
+        files.push(StateFile {
+            name: path.file_name().unwrap_or_default().to_string_lossy().into_owned(),
+            bytes: fs::metadata(&path).map_or(0, |m| m.len()),
+            status: inspect(&path)?,
+        });
+    }
+    files.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(files)
+}
+
+/// Delete the state files in `dir`, or only the documents of `kind`;
+/// returns the paths removed
+pub fn clear(dir: &Path, kind: Option<&str>) -> Result<Vec<PathBuf>, String> {
+    let mut removed = Vec::new();
+    for file in list(dir)? {
+        let matches = match (kind, &file.status) {
+            (None, _) => true,
+            (Some(kind), FileStatus::Document { kind: k, .. }) => k == kind,
+            (Some(_), _) => false,
+        };
+        if matches {
+            let path = dir.join(&file.name);
+            fs::remove_file(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+            removed.push(path);
+        }
+    }
+    Ok(removed)
+}
+
+/// `$HOME/.nanoforge`, where stores live unless told otherwise
+pub fn default_dir() -> PathBuf {
+    let home = std::env::var_os("HOME").map_or_else(|| PathBuf::from("."), PathBuf::from);
+    home.join(".nanoforge")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COUNTS: Schema = Schema::new("counts", 2);
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("nf_store_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_documents_round_trip_and_detect_damage() {
+        let dir = scratch("docs");
+        let path = dir.join("counts.json");
+        assert_eq!(load::<Vec<u32>>(&path, COUNTS), Ok(None));
+        save(&path, COUNTS, &vec![1, 2, 3]).unwrap();
+        save(&path, COUNTS, &vec![4, 5]).unwrap();
+        assert_eq!(load(&path, COUNTS), Ok(Some(vec![4u32, 5])));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        // Floats must read back bit for bit, or their checksum would not match
+        let floats = dir.join("floats.json");
+        let values = vec![0.1 + 0.2, 1.0 / 3.0, 12.000000000000002];
+        save(&floats, COUNTS, &values).unwrap();
+        assert_eq!(load(&floats, COUNTS), Ok(Some(values)));
+        fs::remove_file(&floats).unwrap();
+
+        // A flipped digit in the payload
+        let text = fs::read_to_string(&path).unwrap();
+        fs::write(&path, text.replacen("5", "6", 1)).unwrap();
+        let err = load::<Vec<u32>>(&path, COUNTS).unwrap_err();
+        assert!(err.ends_with("damaged: checksum mismatch"), "{}", err);
+        fs::write(&path, &text[..text.len() / 2]).unwrap();
+        assert!(load::<Vec<u32>>(&path, COUNTS).unwrap_err().contains("damaged"));
+
+        // Other kinds and newer versions are refused; older ones and
+        // pre-envelope files load
+        save(&path, Schema::new("brain", 1), &vec![1]).unwrap();
+        assert!(load::<Vec<u32>>(&path, COUNTS).unwrap_err().contains("holds a brain, not a counts"));
+        save(&path, Schema::new("counts", 3), &vec![1]).unwrap();
+        assert!(load::<Vec<u32>>(&path, COUNTS).unwrap_err().contains("version 3 is newer"));
+        save(&path, Schema::new("counts", 1), &vec![7]).unwrap();
+        assert_eq!(load(&path, COUNTS), Ok(Some(vec![7u32])));
+        fs::write(&path, "[8, 9]").unwrap();
+        assert_eq!(load(&path, COUNTS), Ok(Some(vec![8u32, 9])));
+        assert_eq!(inspect(&path), Ok(FileStatus::Legacy));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_histories_skip_a_torn_last_line() {
+        let dir = scratch("history");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("runs.jsonl");
+        assert_eq!(read_lines::<u32>(&path), Ok(vec![]));
+        append_line(&path, &1).unwrap();
+        append_line(&path, &2).unwrap();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"trunc").unwrap();
+        assert_eq!(read_lines(&path), Ok(vec![1u32, 2]));
+        assert_eq!(inspect(&path), Ok(FileStatus::History { entries: 2, torn: true }));
+
+        fs::write(&path, "1\nnope\n3\n").unwrap();
+        assert!(read_lines::<u32>(&path).unwrap_err().contains(":2: damaged"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_list_and_clear() {
+        let dir = scratch("list");
+        save(&dir.join("a.json"), COUNTS, &vec![1]).unwrap();
+        save(&dir.join("b.json"), Schema::new("brain", 1), &vec![1]).unwrap();
+        append_line(&dir.join("c.jsonl"), &1).unwrap();
+        fs::write(dir.join("d.json.123.tmp"), "{").unwrap();
+        fs::write(dir.join("notes.txt"), "kept").unwrap();
+
+        let files = list(&dir).unwrap();
+        let names: Vec<&str> = files.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, ["a.json", "b.json", "c.jsonl", "d.json.123.tmp"]);
+        assert_eq!(
+            files[0].status,
+            FileStatus::Document {
+                kind: "counts".to_string(),
+                version: 2
+            }
+        );
+        assert_eq!(files[3].status, FileStatus::Leftover);
+
+        assert_eq!(clear(&dir, Some("brain")).unwrap(), vec![dir.join("b.json")]);
+        assert_eq!(clear(&dir, None).unwrap().len(), 3);
+        assert!(dir.join("notes.txt").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,772 @@
+//! Template JIT (Copy-and-Patch Tier)
+//!
+//! The fastest way to get native code: no optimizer, no register
+//! allocation, no assembler. Every IR opcode maps to machine-code templates
+//! assembled ahead of time; compiling copies them one after another and
+//! patches their holes (stack slot offsets, immediates, jump and call
+//! targets, helper addresses). Compiling a function is one linear pass over
+//! its instructions, in microseconds, which makes it the tier that starts
+//! running a program instead of the interpreter (`Tier::Template`).
+//!
+//! Every virtual register lives in its own stack slot, zeroed on entry as
+//! the interpreter's are, and each template loads its operands into
+//! rax/rcx/rdx, works on them and stores the result back. `Cmp` saves its
+//! operands, so a later jump or `CMov` compares them again whatever ran in
+//! between. Bit intrinsics, `Alloc` and `Free` call host helpers. Entry is
+//! checked against the stack guard and failed assertions report like
+//! compiled code's; arithmetic wraps and loops take no fuel. Vector
+//! opcodes never appear unoptimized and are rejected. x86-64 only.
+
+use crate::alignment;
+use crate::assertion::{self, AssertSite};
+use crate::host_args::{self, MAX_ARGS};
+use crate::ir::{Cond, Function, Opcode, Operand, Program};
+use crate::jit_memory::DualMappedMemory;
+use crate::stack_guard;
+use crate::status::{self, Status};
+use std::collections::{BTreeMap, HashMap};
+
+/// Arguments passed in registers between template functions
+const REG_ARGS: usize = 6;
+
+/// Machine code assembled ahead of time, with holes patched in order when
+/// it is copied: 4 bytes each, except 8 for the `abs64` ones
+struct Template {
+    code: &'static [u8],
+    holes: &'static [usize],
+    abs64: &'static [usize],
+}
+
+macro_rules! template {
+    ($name:ident: [$($byte:expr),* $(,)?], holes: [$($hole:expr),*]) => {
+        template!($name: [$($byte),*], holes: [$($hole),*], abs64: []);
+    };
+    ($name:ident: [$($byte:expr),* $(,)?], holes: [$($hole:expr),*], abs64: [$($wide:expr),*]) => {
+        const $name: Template = Template {
+            code: &[$($byte),*],
+            holes: &[$($hole),*],
+            abs64: &[$($wide),*],
+        };
+    };
+}
+
+// Operands in and out of stack slots (`[rbp + disp32]`)
+template!(LOAD_RAX: [0x48, 0x8b, 0x85, 0, 0, 0, 0], holes: [3]);
+template!(LOAD_RCX: [0x48, 0x8b, 0x8d, 0, 0, 0, 0], holes: [3]);
+template!(LOAD_RDX: [0x48, 0x8b, 0x95, 0, 0, 0, 0], holes: [3]);
+template!(IMM_RAX: [0x48, 0xc7, 0xc0, 0, 0, 0, 0], holes: [3]);
+template!(IMM_RCX: [0x48, 0xc7, 0xc1, 0, 0, 0, 0], holes: [3]);
+template!(IMM_RDX: [0x48, 0xc7, 0xc2, 0, 0, 0, 0], holes: [3]);
+template!(STORE_RAX: [0x48, 0x89, 0x85, 0, 0, 0, 0], holes: [3]);
+
+// rax op= rcx
+template!(ADD: [0x48, 0x01, 0xc8], holes: []);
+template!(SUB: [0x48, 0x29, 0xc8], holes: []);
+template!(MUL: [0x48, 0x0f, 0xaf, 0xc1], holes: []);
+// cmp rax, rcx; cmovg/cmovl rax, rcx
+template!(MIN: [0x48, 0x39, 0xc8, 0x48, 0x0f, 0x4f, 0xc1], holes: []);
+template!(MAX: [0x48, 0x39, 0xc8, 0x48, 0x0f, 0x4c, 0xc1], holes: []);
+// mov rcx, rax; neg rax; cmovl rax, rcx
+template!(ABS: [0x48, 0x89, 0xc1, 0x48, 0xf7, 0xd8, 0x48, 0x0f, 0x4c, 0xc1], holes: []);
+
+// mov rdx, [saved lhs]; cmp rdx, [saved rhs]: the flags of the last Cmp
+template!(FLAGS: [0x48, 0x8b, 0x95, 0, 0, 0, 0, 0x48, 0x3b, 0x95, 0, 0, 0, 0], holes: [3, 10]);
+template!(JMP: [0xe9, 0, 0, 0, 0], holes: [1]);
+// jcc rel32, the condition or-ed into the opcode
+template!(JCC: [0x0f, 0x80, 0, 0, 0, 0], holes: [2]);
+const JCC_CC: usize = 1;
+// cmovcc rax, rcx
+template!(CMOV: [0x48, 0x0f, 0x40, 0xc1], holes: []);
+const CMOV_CC: usize = 2;
+// test rax, rax; jnz
+template!(JNZ: [0x48, 0x85, 0xc0, 0x0f, 0x85, 0, 0, 0, 0], holes: [5]);
+
+// rax = [rax + rcx * 8 + disp32]; [rax + rcx * 8 + disp32] = rdx
+template!(LOAD_MEM: [0x48, 0x8b, 0x84, 0xc8, 0, 0, 0, 0], holes: [4]);
+template!(STORE_MEM: [0x48, 0x89, 0x94, 0xc8, 0, 0, 0, 0], holes: [4]);
+
+// rax = helper(rax, rcx), through an absolute address
+template!(CALL_HOST: [
+    0x48, 0x89, 0xc7, // mov rdi, rax
+    0x48, 0x89, 0xce, // mov rsi, rcx
+    0x48, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, // mov rax, abs64
+    0xff, 0xd0, // call rax
+], holes: [], abs64: [8]);
+
+// The outgoing argument slots into rdi, rsi, rdx, rcx, r8 and r9, then call rel32
+template!(CALL: [
+    0x48, 0x8b, 0xbd, 0, 0, 0, 0,
+    0x48, 0x8b, 0xb5, 0, 0, 0, 0,
+    0x48, 0x8b, 0x95, 0, 0, 0, 0,
+    0x48, 0x8b, 0x8d, 0, 0, 0, 0,
+    0x4c, 0x8b, 0x85, 0, 0, 0, 0,
+    0x4c, 0x8b, 0x8d, 0, 0, 0, 0,
+    0xe8, 0, 0, 0, 0,
+], holes: [3, 10, 17, 24, 31, 38, 43]);
+
+// push rbp; mov rbp, rsp; sub rsp, imm32; then the argument registers to
+// their slots
+template!(PROLOGUE: [
+    0x55, 0x48, 0x89, 0xe5, 0x48, 0x81, 0xec, 0, 0, 0, 0,
+    0x48, 0x89, 0xbd, 0, 0, 0, 0,
+    0x48, 0x89, 0xb5, 0, 0, 0, 0,
+    0x48, 0x89, 0x95, 0, 0, 0, 0,
+    0x48, 0x89, 0x8d, 0, 0, 0, 0,
+    0x4c, 0x89, 0x85, 0, 0, 0, 0,
+    0x4c, 0x89, 0x8d, 0, 0, 0, 0,
+    0x31, 0xc0, // xor eax, eax: stored to every register slot
+], holes: [7, 14, 21, 28, 35, 42, 49]);
+// cmp rsp, fs:[disp32]; jb rel32
+template!(STACK_CHECK: [
+    0x64, 0x48, 0x3b, 0x24, 0x25, 0, 0, 0, 0,
+    0x0f, 0x82, 0, 0, 0, 0,
+], holes: [5, 11]);
+// leave; ret
+template!(EPILOGUE: [0xc9, 0xc3], holes: []);
+// xor eax, eax; leave; ret
+template!(RETURN_ZERO: [0x31, 0xc0, 0xc9, 0xc3], holes: []);
+
+template!(JUMP_TABLE: [
+    0x48, 0x2d, 0, 0, 0, 0, // sub rax, low
+    0x48, 0x3d, 0, 0, 0, 0, // cmp rax, entries
+    0x0f, 0x83, 0, 0, 0, 0, // jae default
+    0x48, 0x8d, 0x0d, 9, 0, 0, 0, // lea rcx, [rip + 9]: the table after this template
+    0x48, 0x63, 0x04, 0x81, // movsxd rax, [rcx + rax * 4]
+    0x48, 0x01, 0xc8, // add rax, rcx
+    0xff, 0xe0, // jmp rax
+], holes: [2, 8, 14]);
+
+// cmp rax, rcx; skip the failure if it holds, else report it and return 0
+template!(ASSERT: [
+    0x48, 0x39, 0xc8,
+    0x0f, 0x80, 27, 0, 0, 0, // jcc over the 27 bytes below
+    0x48, 0x89, 0xc7, // mov rdi, rax
+    0x48, 0x89, 0xce, // mov rsi, rcx
+    0xba, 0, 0, 0, 0, // mov edx, site
+    0x48, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, // mov rax, nf_assert_failed
+    0xff, 0xd0, // call rax
+    0x31, 0xc0, 0xc9, 0xc3, // xor eax, eax; leave; ret
+], holes: [16], abs64: [22]);
+const ASSERT_CC: usize = 4;
+
+/// Condition code of `jcc` (0x0f 0x8?) and `cmovcc` (0x0f 0x4?)
+fn cc(cond: Cond) -> u8 {
+    match cond {
+        Cond::Eq => 0x4,
+        Cond::Ne => 0x5,
+        Cond::Lt => 0xc,
+        Cond::Ge => 0xd,
+        Cond::Le => 0xe,
+        Cond::Gt => 0xf,
+    }
+}
+
+extern "C" fn tpl_popcnt(v: i64) -> i64 {
+    v.count_ones() as i64
+}
+
+extern "C" fn tpl_ctz(v: i64) -> i64 {
+    v.trailing_zeros() as i64
+}
+
+extern "C" fn tpl_clz(v: i64) -> i64 {
+    v.leading_zeros() as i64
+}
+
+/// `Alloc` as the interpreter does it: `malloc`, or aligned if `align` > 0
+extern "C" fn tpl_alloc(size: i64, align: i64) -> i64 {
+    if align > 0 {
+        alignment::nf_alloc_aligned(size, align) as i64
+    } else {
+        unsafe { libc::malloc(size.max(0) as usize) as i64 }
+    }
+}
+
+extern "C" fn tpl_free(ptr: i64) {
+    unsafe { libc::free(ptr as *mut libc::c_void) };
+}
+
+/// Where a rel32 hole must point once every function is laid out
+enum Target {
+    /// A label of the function being stitched
+    Label(String),
+    Function(String),
+}
+
+struct Fixup {
+    /// Offset of the 4-byte hole
+    at: usize,
+    target: Target,
+    /// The hole holds `target - base`
+    base: usize,
+}
+
+/// A template function's stack frame: a slot per virtual register, the
+/// operands of the last `Cmp`, then the incoming and outgoing arguments
+struct Frame {
+    registers: usize,
+}
+
+impl Frame {
+    fn of(func: &Function) -> Self {
+        let highest = func
+            .instructions
+            .iter()
+            .flat_map(|i| [&i.dest, &i.src1, &i.src2])
+            .filter_map(|op| match op {
+                Some(Operand::Reg(r)) => Some(*r as usize),
+                _ => None,
+            })
+            .max();
+        // Falling off the end returns Reg(0), so it always has a slot
+        Self {
+            registers: highest.map_or(1, |r| r + 1),
+        }
+    }
+
+    fn slot(index: usize) -> i64 {
+        -8 * (index as i64 + 1)
+    }
+
+    fn register(&self, r: u8) -> i64 {
+        Self::slot(r as usize)
+    }
+
+    /// Saved operands of the last `Cmp`
+    fn flags(&self) -> [i64; 2] {
+        [Self::slot(self.registers), Self::slot(self.registers + 1)]
+    }
+
+    fn arg(&self, i: usize) -> i64 {
+        Self::slot(self.registers + 2 + i)
+    }
+
+    fn outgoing(&self, i: usize) -> i64 {
+        Self::slot(self.registers + 2 + REG_ARGS + i)
+    }
+
+    /// Bytes below rbp, a multiple of 16 so calls stay aligned
+    fn size(&self) -> i64 {
+        let bytes = 8 * (self.registers + 2 + 2 * REG_ARGS) as i64;
+        (bytes + 15) & !15
+    }
+}
+
+/// An operand register and the templates that load it
+#[derive(Clone, Copy)]
+enum Scratch {
+    Rax,
+    Rcx,
+    Rdx,
+}
+
+impl Scratch {
+    fn templates(self) -> (&'static Template, &'static Template) {
+        match self {
+            Scratch::Rax => (&LOAD_RAX, &IMM_RAX),
+            Scratch::Rcx => (&LOAD_RCX, &IMM_RCX),
+            Scratch::Rdx => (&LOAD_RDX, &IMM_RDX),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Stitcher {
+    code: Vec<u8>,
+    fixups: Vec<Fixup>,
+    assert_sites: Vec<AssertSite>,
+}
+
+impl Stitcher {
+    /// Copy `template` and patch its holes with `values`: the 4-byte holes
+    /// first, then the `abs64` ones. Holes without a value are left for a
+    /// fixup.
+    fn copy(&mut self, template: &Template, values: &[i64]) -> usize {
+        let start = self.code.len();
+        self.code.extend_from_slice(template.code);
+        let (narrow, wide) = values.split_at(template.holes.len().min(values.len()));
+        for (&hole, &value) in template.holes.iter().zip(narrow) {
+            let at = start + hole;
+            self.code[at..at + 4].copy_from_slice(&(value as i32).to_le_bytes());
+        }
+        for (&hole, &value) in template.abs64.iter().zip(wide) {
+            self.code[start + hole..start + hole + 8].copy_from_slice(&value.to_le_bytes());
+        }
+        start
+    }
+
+    /// Point the rel32 hole at `at` to `target`
+    fn fixup(&mut self, at: usize, target: Target) {
+        self.fixups.push(Fixup { at, target, base: at + 4 });
+    }
+
+    /// Call `helper(rax, rcx)`, result in rax
+    fn helper(&mut self, helper: *const ()) {
+        self.copy(&CALL_HOST, &[helper as usize as i64]);
+    }
+
+    fn load(
+        &mut self,
+        to: Scratch,
+        frame: &Frame,
+        op: &Option<Operand>,
+    ) -> Result<(), &'static str> {
+        let (slot, imm) = to.templates();
+        match op {
+            Some(Operand::Reg(r)) => self.copy(slot, &[frame.register(*r)]),
+            Some(Operand::Imm(v)) => self.copy(imm, &[*v as i64]),
+            _ => return Err("expected a register or immediate"),
+        };
+        Ok(())
+    }
+
+    fn store(&mut self, frame: &Frame, op: &Option<Operand>) -> Result<(), &'static str> {
+        match op {
+            Some(Operand::Reg(r)) => self.copy(&STORE_RAX, &[frame.register(*r)]),
+            _ => return Err("expected a register destination"),
+        };
+        Ok(())
+    }
+
+    /// Stitch `func`; its label and call fixups are left for `link`
+    fn function(
+        &mut self,
+        program: &Program,
+        func: &Function,
+    ) -> Result<HashMap<String, usize>, String> {
+        if func.args.len() > REG_ARGS {
+            return Err(format!(
+                "{}() takes {} parameters; templates pass at most {}",
+                func.name,
+                func.args.len(),
+                REG_ARGS
+            ));
+        }
+        let frame = Frame::of(func);
+        let args: Vec<i64> = (0..REG_ARGS).map(|i| frame.arg(i)).collect();
+        self.copy(&PROLOGUE, &[&[frame.size()][..], &args].concat());
+        for slot in 0..frame.registers + 2 {
+            self.copy(&STORE_RAX, &[Frame::slot(slot)]);
+        }
+        let stack_fail = stack_guard::SUPPORTED.then(|| {
+            let start = self.copy(&STACK_CHECK, &[stack_guard::LIMIT_OFFSET as i64]);
+            start + STACK_CHECK.holes[1]
+        });
+
+        let mut labels = HashMap::new();
+        for (at, instr) in func.instructions.iter().enumerate() {
+            let fail = |msg: &str| format!("{}[{}]: {}: {}", func.name, at, instr, msg);
+            let label = |op: &Option<Operand>| match op {
+                Some(Operand::Label(label)) => Ok(label.clone()),
+                _ => Err(fail("expected a label")),
+            };
+            let (dest, src1, src2) = (&instr.dest, &instr.src1, &instr.src2);
+            match &instr.op {
+                Opcode::Label => {
+                    labels.insert(label(dest)?, self.code.len());
+                }
+                Opcode::PrefetchT0 => {}
+                Opcode::Mov => {
+                    self.load(Scratch::Rax, &frame, src1).map_err(fail)?;
+                    self.store(&frame, dest).map_err(fail)?;
+                }
+                Opcode::Add | Opcode::Sub | Opcode::Mul | Opcode::Min | Opcode::Max => {
+                    self.load(Scratch::Rax, &frame, dest).map_err(fail)?;
+                    self.load(Scratch::Rcx, &frame, src1).map_err(fail)?;
+                    let op = match instr.op {
+                        Opcode::Add => &ADD,
+                        Opcode::Sub => &SUB,
+                        Opcode::Mul => &MUL,
+                        Opcode::Min => &MIN,
+                        _ => &MAX,
+                    };
+                    self.copy(op, &[]);
+                    self.store(&frame, dest).map_err(fail)?;
+                }
+                Opcode::Abs | Opcode::Popcnt | Opcode::Ctz | Opcode::Clz => {
+                    self.load(Scratch::Rax, &frame, src1).map_err(fail)?;
+                    match instr.op {
+                        Opcode::Abs => _ = self.copy(&ABS, &[]),
+                        Opcode::Popcnt => self.helper(tpl_popcnt as *const ()),
+                        Opcode::Ctz => self.helper(tpl_ctz as *const ()),
+                        _ => self.helper(tpl_clz as *const ()),
+                    }
+                    self.store(&frame, dest).map_err(fail)?;
+                }
+                Opcode::Cmp => {
+                    let [lhs, rhs] = frame.flags();
+                    self.load(Scratch::Rax, &frame, src1).map_err(fail)?;
+                    self.copy(&STORE_RAX, &[lhs]);
+                    self.load(Scratch::Rax, &frame, src2).map_err(fail)?;
+                    self.copy(&STORE_RAX, &[rhs]);
+                }
+                Opcode::CMov(cond) => {
+                    self.load(Scratch::Rax, &frame, dest).map_err(fail)?;
+                    self.load(Scratch::Rcx, &frame, src1).map_err(fail)?;
+                    self.copy(&FLAGS, &frame.flags());
+                    let start = self.copy(&CMOV, &[]);
+                    self.code[start + CMOV_CC] |= cc(*cond);
+                    self.store(&frame, dest).map_err(fail)?;
+                }
+                Opcode::Je | Opcode::Jne | Opcode::Jl | Opcode::Jle | Opcode::Jg | Opcode::Jge => {
+                    let cond = Cond::of_jump(&instr.op).expect("conditional jump");
+                    self.copy(&FLAGS, &frame.flags());
+                    let start = self.copy(&JCC, &[]);
+                    self.code[start + JCC_CC] |= cc(cond);
+                    self.fixup(start + JCC.holes[0], Target::Label(label(dest)?));
+                }
+                Opcode::Jmp => {
+                    let start = self.copy(&JMP, &[]);
+                    self.fixup(start + JMP.holes[0], Target::Label(label(dest)?));
+                }
+                Opcode::Jnz => {
+                    self.load(Scratch::Rax, &frame, src1).map_err(fail)?;
+                    let start = self.copy(&JNZ, &[]);
+                    self.fixup(start + JNZ.holes[0], Target::Label(label(dest)?));
+                }
+                Opcode::JumpTable(low, targets) => {
+                    self.load(Scratch::Rax, &frame, src1).map_err(fail)?;
+                    let start = self.copy(&JUMP_TABLE, &[*low as i64, targets.len() as i64]);
+                    self.fixup(start + JUMP_TABLE.holes[2], Target::Label(label(dest)?));
+                    // Entries are relative to the table, which follows
+                    let table = self.code.len();
+                    for target in targets {
+                        let at = self.code.len();
+                        self.code.extend_from_slice(&[0; 4]);
+                        let target = Target::Label(target.clone());
+                        self.fixups.push(Fixup { at, target, base: table });
+                    }
+                }
+                Opcode::Load(disp) => {
+                    self.load(Scratch::Rax, &frame, src1).map_err(fail)?;
+                    self.load(Scratch::Rcx, &frame, src2).map_err(fail)?;
+                    self.copy(&LOAD_MEM, &[*disp as i64 * 8]);
+                    self.store(&frame, dest).map_err(fail)?;
+                }
+                Opcode::Store(disp) => {
+                    self.load(Scratch::Rax, &frame, dest).map_err(fail)?;
+                    self.load(Scratch::Rcx, &frame, src1).map_err(fail)?;
+                    self.load(Scratch::Rdx, &frame, src2).map_err(fail)?;
+                    self.copy(&STORE_MEM, &[*disp as i64 * 8]);
+                }
+                Opcode::Alloc => {
+                    self.load(Scratch::Rax, &frame, src1).map_err(fail)?;
+                    let align = match src2 {
+                        Some(Operand::Imm(align)) => *align as i64,
+                        _ => 0,
+                    };
+                    self.copy(&IMM_RCX, &[align]);
+                    self.helper(tpl_alloc as *const ());
+                    self.store(&frame, dest).map_err(fail)?;
+                }
+                Opcode::Free => {
+                    self.load(Scratch::Rax, &frame, src1).map_err(fail)?;
+                    self.helper(tpl_free as *const ());
+                }
+                Opcode::SetArg(i) => {
+                    if *i >= REG_ARGS {
+                        return Err(fail(&format!("templates pass at most {} arguments", REG_ARGS)));
+                    }
+                    self.load(Scratch::Rax, &frame, src1).map_err(fail)?;
+                    self.copy(&STORE_RAX, &[frame.outgoing(*i)]);
+                    if let Some(Operand::Reg(r)) = dest {
+                        self.copy(&STORE_RAX, &[frame.register(*r)]);
+                    }
+                }
+                Opcode::LoadArg(i) => {
+                    if *i < REG_ARGS {
+                        self.copy(&LOAD_RAX, &[frame.arg(*i)]);
+                    } else {
+                        self.copy(&IMM_RAX, &[0]);
+                    }
+                    self.store(&frame, dest).map_err(fail)?;
+                }
+                Opcode::Call => {
+                    let callee = label(src1)?;
+                    if !program.functions.iter().any(|f| f.name == callee) {
+                        return Err(format!("call to undefined function '{}'", callee));
+                    }
+                    let outgoing: Vec<i64> = (0..REG_ARGS).map(|i| frame.outgoing(i)).collect();
+                    let start = self.copy(&CALL, &outgoing);
+                    self.fixup(start + CALL.holes[REG_ARGS], Target::Function(callee));
+                    if let Some(Operand::Reg(r)) = dest {
+                        self.copy(&STORE_RAX, &[frame.register(*r)]);
+                    }
+                }
+                Opcode::Ret => {
+                    match dest {
+                        Some(_) => self.load(Scratch::Rax, &frame, dest).map_err(fail)?,
+                        None => _ = self.copy(&LOAD_RAX, &[frame.register(0)]),
+                    }
+                    self.copy(&EPILOGUE, &[]);
+                }
+                Opcode::Assert(cond, text) => {
+                    self.load(Scratch::Rax, &frame, src1).map_err(fail)?;
+                    self.load(Scratch::Rcx, &frame, src2).map_err(fail)?;
+                    let site = self.assert_sites.len() as i64;
+                    self.assert_sites.push(AssertSite::new(&func.name, func.line(at), text));
+                    let handler = assertion::nf_assert_failed as *const () as usize as i64;
+                    let start = self.copy(&ASSERT, &[site, handler]);
+                    self.code[start + ASSERT_CC] |= cc(*cond);
+                }
+                Opcode::VLoad(..)
+                | Opcode::VStore(..)
+                | Opcode::VAdd
+                | Opcode::VMin
+                | Opcode::VMax
+                | Opcode::VAbs => return Err(fail("vector instructions have no templates")),
+            }
+        }
+
+        // Falling off the end returns Reg(0)
+        self.copy(&LOAD_RAX, &[frame.register(0)]);
+        self.copy(&EPILOGUE, &[]);
+        if let Some(hole) = stack_fail {
+            let rel = (self.code.len() - (hole + 4)) as i32;
+            self.code[hole..hole + 4].copy_from_slice(&rel.to_le_bytes());
+            self.copy(&IMM_RAX, &[Status::StackOverflow.code().into()]);
+            self.helper(status::nf_trap as *const ());
+            self.copy(&RETURN_ZERO, &[]);
+        }
+        Ok(labels)
+    }
+
+    /// Patch the jumps of the function stitched last, whose labels are
+    /// `labels`, and leave its calls for `link`
+    fn resolve_labels(
+        &mut self,
+        func: &str,
+        labels: &HashMap<String, usize>,
+    ) -> Result<(), String> {
+        let mut calls = Vec::new();
+        for fixup in self.fixups.drain(..) {
+            match &fixup.target {
+                Target::Label(label) => {
+                    let target = labels
+                        .get(label)
+                        .ok_or_else(|| format!("{}(): jump to undefined label '{}'", func, label))?;
+                    let rel = (*target as i64 - fixup.base as i64) as i32;
+                    self.code[fixup.at..fixup.at + 4].copy_from_slice(&rel.to_le_bytes());
+                }
+                Target::Function(_) => calls.push(fixup),
+            }
+        }
+        self.fixups = calls;
+        Ok(())
+    }
+
+    /// Patch every call once all functions have entries
+    fn link(&mut self, entries: &BTreeMap<String, (usize, usize)>) {
+        for fixup in &self.fixups {
+            if let Target::Function(callee) = &fixup.target {
+                let (entry, _) = entries[callee];
+                let rel = (entry as i64 - fixup.base as i64) as i32;
+                self.code[fixup.at..fixup.at + 4].copy_from_slice(&rel.to_le_bytes());
+            }
+        }
+    }
+}
+
+/// A program stitched from templates into executable memory
+#[derive(Debug)]
+pub struct TemplateProgram {
+    memory: DualMappedMemory,
+    /// Entry offset and arity of each function
+    entries: BTreeMap<String, (usize, usize)>,
+    code_size: usize,
+    assert_sites: Vec<AssertSite>,
+}
+
+impl TemplateProgram {
+    /// Stitch every function of `program`; fails on opcodes without
+    /// templates (vector code) or on targets other than x86-64, for callers
+    /// to fall back to the compiler
+    pub fn compile(program: &Program) -> Result<Self, String> {
+        if !cfg!(target_arch = "x86_64") {
+            return Err("the template tier only targets x86-64".to_string());
+        }
+        let mut stitcher = Stitcher::default();
+        let mut entries = BTreeMap::new();
+        for func in &program.functions {
+            let entry = stitcher.code.len();
+            let labels = stitcher.function(program, func)?;
+            stitcher.resolve_labels(&func.name, &labels)?;
+            entries.insert(func.name.clone(), (entry, func.args.len()));
+        }
+        stitcher.link(&entries);
+
+        let memory = DualMappedMemory::with_code(&stitcher.code)?;
+        Ok(Self {
+            memory,
+            entries,
+            code_size: stitcher.code.len(),
+            assert_sites: stitcher.assert_sites,
+        })
+    }
+
+    pub fn code_size(&self) -> usize {
+        self.code_size
+    }
+
+    pub fn function_names(&self) -> impl Iterator<Item = &str> {
+        self.entries.keys().map(String::as_str)
+    }
+
+    /// Call `name` with `args`, one per parameter; a call that didn't
+    /// return normally (see `status`) is an error rather than a result
+    pub fn call(&self, name: &str, args: &[i64]) -> Result<i64, String> {
+        let &(offset, arity) = self
+            .entries
+            .get(name)
+            .ok_or_else(|| format!("no function named '{}'", name))?;
+        if args.len() != arity {
+            return Err(format!("{}() takes {} argument(s), got {}", name, arity, args.len()));
+        }
+        if args.len() > MAX_ARGS {
+            return Err(format!(
+                "{}() takes {} arguments; at most {} are supported",
+                name, arity, MAX_ARGS
+            ));
+        }
+        status::reset();
+        assertion::take_failure();
+        let entry = unsafe { self.memory.rx_ptr.add(offset) };
+        let result = unsafe { host_args::call_with_args(entry, args) };
+        match status::take() {
+            Status::Ok => Ok(result),
+            Status::AssertFailed => {
+                let failure = assertion::take_failure();
+                match failure.and_then(|f| self.assert_sites.get(f.site).map(|site| (site, f))) {
+                    Some((site, f)) => Err(format!("{}(): {}", name, site.failure(f.lhs, f.rhs))),
+                    None => Err(format!("{}(): assertion failed", name)),
+                }
+            }
+            status => Err(format!("{}(): {}", name, status)),
+        }
+    }
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+    use crate::compiled_program::CompiledProgram;
+    use crate::compiler::CompileOptions;
+    use crate::interpreter::Interpreter;
+    use crate::parser::Parser;
+
+    fn both(src: &str) -> (Program, TemplateProgram) {
+        let program = Parser::new().parse(src).unwrap();
+        let stitched = TemplateProgram::compile(&program).unwrap();
+        (program, stitched)
+    }
+
+    #[test]
+    fn test_matches_the_interpreter() {
+        let (program, stitched) = both(
+            "fn main(n) {
+                a = alloc(80)
+                i = 0
+                while i < 10 {
+                    v = i * n
+                    a[i] = v
+                    i = i + 1
+                }
+                s = sum(a, 10)
+                free(a)
+                m = min(s, 1000)
+                k = 0 - 1000
+                x = max(m, k)
+                y = abs(x)
+                p = popcnt(y)
+                r = y + p
+                return r
+            }
+            fn sum(a, n) {
+                s = 0
+                for (i = 1; i <= n; i = i + 1) {
+                    v = a[i-1]
+                    s = s + v
+                }
+                b = clz(s)
+                c = ctz(s)
+                r = s + b
+                r = r - c
+                return r
+            }",
+        );
+        let mut interpreter = Interpreter::new(&program);
+        for n in [0, 3, -7, 200] {
+            assert_eq!(stitched.call("main", &[n]), interpreter.call("main", &[n]), "n = {}", n);
+        }
+        assert!(stitched.call("main", &[]).unwrap_err().contains("takes 1"));
+        assert!(stitched.call("missing", &[]).is_err());
+    }
+
+    #[test]
+    fn test_recursion_switch_and_selects() {
+        let (program, stitched) = both(
+            "fn fib(n) {
+                if n < 2 {
+                    return n
+                }
+                m = n - 1
+                a = fib(m)
+                m = n - 2
+                b = fib(m)
+                r = a + b
+                return r
+            }
+            fn pick(x) {
+                r = 0
+                switch x {
+                    1 => r = 10
+                    2 => r = 20
+                    4 => r = 40
+                    5 => r = 50
+                    _ => r = 9
+                }
+                return r
+            }
+            fn main(x, y) {
+                r = x
+                if y > x {
+                    r = y
+                }
+                return r
+            }",
+        );
+        let compiled = CompiledProgram::compile(&program, &CompileOptions::new(1)).unwrap();
+        assert_eq!(stitched.call("fib", &[20]), Ok(6765));
+        for x in -1..7 {
+            let expected = compiled.get_fn("pick").unwrap().call(&[x]);
+            assert_eq!(stitched.call("pick", &[x]), expected, "x = {}", x);
+        }
+        assert_eq!(stitched.call("main", &[3, 8]), Ok(8));
+        assert_eq!(stitched.call("main", &[9, 8]), Ok(9));
+    }
+
+    #[test]
+    fn test_traps_report_like_compiled_code() {
+        let (_, stitched) = both(
+            "fn main(x) {
+                y = x * 2
+                assert y < 10
+                return y
+            }
+            fn down(n) {
+                m = n + 1
+                r = down(m)
+                return r
+            }",
+        );
+        assert_eq!(stitched.call("main", &[4]), Ok(8));
+        assert_eq!(
+            stitched.call("main", &[6]),
+            Err("main(): assertion failed at line 3: y < 10 (12 vs 10)".to_string())
+        );
+        if stack_guard::SUPPORTED {
+            let err = stitched.call("down", &[0]).unwrap_err();
+            assert!(err.contains("stack overflow"), "{}", err);
+        }
+    }
+}
@@ -0,0 +1,217 @@
+//! Thermal and Frequency Monitoring
+//!
+//! Samples CPU frequency, package temperature, RAPL energy and the kernel's
+//! thermal-throttle counters from sysfs. The sandbox takes a sample before
+//! and after each measurement block; a block during which the clock dropped
+//! (or the throttle counter moved) is flagged and can be retried, since its
+//! cycle counts don't reflect the code alone.
+//!
+//! Every source is optional: VMs and containers often expose none of them,
+//! in which case no run is ever flagged.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Default relative frequency drop that marks a run as throttled
+pub const DEFAULT_FREQ_DROP_THRESHOLD: f64 = 0.10;
+
+/// One reading of the thermal/frequency sources
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ThermalSample {
+    pub freq_khz: Option<u64>,
+    /// Package temperature in millidegrees Celsius
+    pub temp_millic: Option<i64>,
+    /// Cumulative RAPL package energy in microjoules
+    pub energy_uj: Option<u64>,
+    /// Kernel count of thermal throttling events on this core
+    pub throttle_count: Option<u64>,
+}
+
+/// Comparison of the samples around a measurement block
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ThrottleReport {
+    pub before: ThermalSample,
+    pub after: ThermalSample,
+    /// Relative frequency drop (0.2 = 20% slower at the end)
+    pub freq_drop: Option<f64>,
+    pub throttled: bool,
+}
+
+impl ThrottleReport {
+    /// Energy used during the block, if RAPL is readable (None if the counter wrapped)
+    pub fn energy_uj(&self) -> Option<u64> {
+        self.after.energy_uj?.checked_sub(self.before.energy_uj?)
+    }
+
+    pub fn temp_rise_millic(&self) -> Option<i64> {
+        Some(self.after.temp_millic? - self.before.temp_millic?)
+    }
+}
+
+/// Reads thermal state for one CPU
+#[derive(Debug, Clone)]
+pub struct ThermalMonitor {
+    root: PathBuf,
+    cpu: usize,
+    threshold: f64,
+    temp_zone: Option<PathBuf>,
+}
+
+impl ThermalMonitor {
+    pub fn new(cpu: usize, threshold: f64) -> Self {
+        Self::with_root(Path::new("/sys"), cpu, threshold)
+    }
+
+    /// Monitor against a sysfs tree rooted at `root` (normally `/sys`)
+    pub fn with_root(root: &Path, cpu: usize, threshold: f64) -> Self {
+        Self {
+            temp_zone: package_temp_zone(root),
+            root: root.to_path_buf(),
+            cpu,
+            threshold,
+        }
+    }
+
+    pub fn sample(&self) -> ThermalSample {
+        let cpu_dir = self
+            .root
+            .join(format!("devices/system/cpu/cpu{}", self.cpu));
+        ThermalSample {
+            freq_khz: read_num(&cpu_dir.join("cpufreq/scaling_cur_freq")),
+            temp_millic: self
+                .temp_zone
+                .as_ref()
+                .and_then(|z| read_num(&z.join("temp"))),
+            energy_uj: read_num(&self.root.join("class/powercap/intel-rapl:0/energy_uj")),
+            throttle_count: read_num(&cpu_dir.join("thermal_throttle/core_throttle_count")),
+        }
+    }
+
+    pub fn compare(&self, before: ThermalSample, after: ThermalSample) -> ThrottleReport {
+        let freq_drop = match (before.freq_khz, after.freq_khz) {
+            (Some(b), Some(a)) if b > 0 => Some((b as f64 - a as f64) / b as f64),
+            _ => None,
+        };
+        let throttle_events = match (before.throttle_count, after.throttle_count) {
+            (Some(b), Some(a)) => a > b,
+            _ => false,
+        };
+
+        ThrottleReport {
+            before,
+            after,
+            freq_drop,
+            throttled: throttle_events || freq_drop.is_some_and(|d| d > self.threshold),
+        }
+    }
+}
+
+/// Prefer the package sensor; fall back to the first zone that reads
+fn package_temp_zone(root: &Path) -> Option<PathBuf> {
+    let mut zones: Vec<PathBuf> = fs::read_dir(root.join("class/thermal"))
+        .ok()?
+        .flatten()
+        .map(|e| e.path())
+        .filter(|p| {
+            p.file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| n.starts_with("thermal_zone"))
+        })
+        .collect();
+    zones.sort();
+
+    let kind = |z: &PathBuf| fs::read_to_string(z.join("type")).unwrap_or_default();
+    zones
+        .iter()
+        .find(|z| kind(z).trim() == "x86_pkg_temp")
+        .or_else(|| {
+            zones
+                .iter()
+                .find(|z| read_num::<i64>(&z.join("temp")).is_some())
+        })
+        .cloned()
+}
+
+fn read_num<T: std::str::FromStr>(path: &Path) -> Option<T> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(root: &Path, rel: &str, contents: &str) {
+        let path = root.join(rel);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    #[test]
+    fn test_detects_frequency_drop_and_throttle_events() {
+        let root = std::env::temp_dir().join(format!("nf_thermal_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let cpu = "devices/system/cpu/cpu2";
+        write(
+            &root,
+            &format!("{}/cpufreq/scaling_cur_freq", cpu),
+            "4000000\n",
+        );
+        write(
+            &root,
+            &format!("{}/thermal_throttle/core_throttle_count", cpu),
+            "5\n",
+        );
+        write(&root, "class/thermal/thermal_zone0/type", "acpitz\n");
+        write(&root, "class/thermal/thermal_zone0/temp", "40000\n");
+        write(&root, "class/thermal/thermal_zone1/type", "x86_pkg_temp\n");
+        write(&root, "class/thermal/thermal_zone1/temp", "65000\n");
+
+        let monitor = ThermalMonitor::with_root(&root, 2, 0.10);
+        let before = monitor.sample();
+        assert_eq!(before.freq_khz, Some(4_000_000));
+        assert_eq!(before.temp_millic, Some(65_000));
+        assert_eq!(before.energy_uj, None);
+
+        // 5% drop is within tolerance
+        write(
+            &root,
+            &format!("{}/cpufreq/scaling_cur_freq", cpu),
+            "3800000\n",
+        );
+        let report = monitor.compare(before, monitor.sample());
+        assert!(!report.throttled);
+
+        // 25% drop is throttling
+        write(
+            &root,
+            &format!("{}/cpufreq/scaling_cur_freq", cpu),
+            "3000000\n",
+        );
+        let report = monitor.compare(before, monitor.sample());
+        assert!(report.throttled);
+        assert!((report.freq_drop.unwrap() - 0.25).abs() < 1e-9);
+
+        // So is a throttle event even at full clock
+        write(
+            &root,
+            &format!("{}/cpufreq/scaling_cur_freq", cpu),
+            "4000000\n",
+        );
+        write(
+            &root,
+            &format!("{}/thermal_throttle/core_throttle_count", cpu),
+            "6\n",
+        );
+        assert!(monitor.compare(before, monitor.sample()).throttled);
+
+        fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn test_missing_sources_never_flag() {
+        let monitor = ThermalMonitor::with_root(Path::new("/nonexistent"), 0, 0.10);
+        let s = monitor.sample();
+        assert_eq!(s, ThermalSample::default());
+        assert!(!monitor.compare(s, s).throttled);
+    }
+}
@@ -4,7 +4,7 @@
 //! using Mutex for safe concurrent access.
 
 use std::path::Path;
-use std::sync::{Arc, Mutex, RwLock};
+use std::sync::{Arc, RwLock};
 
 use crate::ai_optimizer::{ContextualBandit, OptimizationFeatures, SizeBucket};
 use crate::error::{NanoForgeError, Result};
@@ -73,7 +73,7 @@ impl ThreadSafeOptimizer {
             .map_err(|e| NanoForgeError::OptimizerError(format!("Lock poisoned: {}", e)))?;
         guard
             .save_to_file(path)
-            .map_err(|e| NanoForgeError::IoError(e))
+            .map_err(NanoForgeError::IoError)
     }
 
     /// Get decision boundary (read lock)
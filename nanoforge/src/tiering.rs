@@ -0,0 +1,343 @@
+//! Tiering Policy
+//!
+//! Decides when a function moves between tiers: from the interpreter to the
+//! baseline JIT after `jit_after_calls` calls, from the template or baseline
+//! tier to the optimized one (and back to baseline) from its share of time
+//! in successive profiles, and to an evolved implementation once one is
+//! found. Every change goes through
+//! guard rails against thrashing when a workload hovers near a threshold:
+//!
+//! - hysteresis: promote at `promote_share`, demote only below the lower
+//!   `demote_share`
+//! - a per-function cool-down after every tier change
+//! - a cap on recompilations per minute across all functions
+//!
+//! Decisions held back by the cool-down or the cap are reported as deferred
+//! and retried on the next observation.
+
+use crate::function_profiler::ProfileReport;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+const MINUTE: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TieringConfig {
+    /// Share of self cycles at or above which a baseline function is promoted
+    pub promote_share: f64,
+    /// Share below which an optimized function is demoted
+    pub demote_share: f64,
+    /// Minimum time between two tier changes of the same function
+    pub cooldown: Duration,
+    /// Recompilations allowed in any 60 s window
+    pub max_recompiles_per_minute: usize,
+    /// Calls an interpreted function makes before it is compiled
+    pub jit_after_calls: u64,
+    /// Tier functions start in
+    pub start: Tier,
+}
+
+impl Default for TieringConfig {
+    fn default() -> Self {
+        Self {
+            promote_share: 0.2,
+            demote_share: 0.1,
+            cooldown: Duration::from_secs(5),
+            max_recompiles_per_minute: 10,
+            jit_after_calls: 100,
+            start: Tier::Baseline,
+        }
+    }
+}
+
+impl TieringConfig {
+    pub fn promote_share(mut self, share: f64) -> Self {
+        self.promote_share = share;
+        self
+    }
+
+    pub fn demote_share(mut self, share: f64) -> Self {
+        self.demote_share = share;
+        self
+    }
+
+    pub fn cooldown(mut self, cooldown: Duration) -> Self {
+        self.cooldown = cooldown;
+        self
+    }
+
+    pub fn max_recompiles_per_minute(mut self, max: usize) -> Self {
+        self.max_recompiles_per_minute = max;
+        self
+    }
+
+    pub fn jit_after_calls(mut self, calls: u64) -> Self {
+        self.jit_after_calls = calls;
+        self
+    }
+
+    pub fn start(mut self, tier: Tier) -> Self {
+        self.start = tier;
+        self
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let share = 0.0..=1.0;
+        if !share.contains(&self.promote_share) || !share.contains(&self.demote_share) {
+            return Err("tiering: shares must be between 0 and 1".to_string());
+        }
+        if self.demote_share > self.promote_share {
+            return Err(format!(
+                "tiering: demote share {} is above promote share {}; \
+                 hysteresis needs demote <= promote",
+                self.demote_share, self.promote_share
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Tier {
+    /// Run by the IR interpreter
+    Interpreted,
+    /// Stitched from machine-code templates (see `template_jit`)
+    Template,
+    /// Compiled at level 1
+    #[default]
+    Baseline,
+    /// Compiled at level 3
+    Optimized,
+    /// Replaced by a re-verified winner of evolution
+    Evolved,
+}
+
+/// Why a wanted tier change was held back
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Deferral {
+    /// The function changed tier too recently
+    CoolingDown { remaining: Duration },
+    /// The per-minute recompilation budget is spent
+    RateLimited,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TierDecision {
+    Stay,
+    /// Recompile into this tier now
+    Change(Tier),
+    /// Would change to this tier, but not yet
+    Deferred(Tier, Deferral),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct FunctionTier {
+    tier: Tier,
+    changed_at: Option<Instant>,
+}
+
+/// Per-function tiers and the guard rails' history
+#[derive(Debug, Clone)]
+pub struct TieringPolicy {
+    config: TieringConfig,
+    functions: HashMap<String, FunctionTier>,
+    /// When each recompilation of the last minute happened, oldest first
+    recompiles: VecDeque<Instant>,
+}
+
+impl TieringPolicy {
+    pub fn new(config: TieringConfig) -> Result<Self, String> {
+        config.validate()?;
+        Ok(Self {
+            config,
+            functions: HashMap::new(),
+            recompiles: VecDeque::new(),
+        })
+    }
+
+    pub fn config(&self) -> &TieringConfig {
+        &self.config
+    }
+
+    /// Current tier of `name` (functions start in `TieringConfig::start`)
+    pub fn tier(&self, name: &str) -> Tier {
+        self.functions
+            .get(name)
+            .map_or(self.config.start, |f| f.tier)
+    }
+
+    /// Decide for one function given its `share` of self cycles at `now`.
+    /// A `Change` is taken to be carried out: it updates the tier and counts
+    /// against the cool-down and the rate limit.
+    pub fn decide(&mut self, name: &str, share: f64, now: Instant) -> TierDecision {
+        let wanted = match self.tier(name) {
+            Tier::Template | Tier::Baseline if share >= self.config.promote_share => {
+                Tier::Optimized
+            }
+            Tier::Optimized | Tier::Evolved if share < self.config.demote_share => Tier::Baseline,
+            _ => return TierDecision::Stay,
+        };
+        self.change(name, wanted, now)
+    }
+
+    /// Decide whether an interpreted function that has been called `calls`
+    /// times should be compiled
+    pub fn on_calls(&mut self, name: &str, calls: u64, now: Instant) -> TierDecision {
+        if self.tier(name) == Tier::Interpreted && calls >= self.config.jit_after_calls {
+            self.change(name, Tier::Baseline, now)
+        } else {
+            TierDecision::Stay
+        }
+    }
+
+    /// Decide whether to swap in an evolved implementation of an optimized
+    /// function
+    pub fn on_evolved(&mut self, name: &str, now: Instant) -> TierDecision {
+        if self.tier(name) == Tier::Optimized {
+            self.change(name, Tier::Evolved, now)
+        } else {
+            TierDecision::Stay
+        }
+    }
+
+    /// Move `name` to `wanted` unless the guard rails hold it back
+    fn change(&mut self, name: &str, wanted: Tier, now: Instant) -> TierDecision {
+        let start = self.config.start;
+        let current = self
+            .functions
+            .entry(name.to_string())
+            .or_insert(FunctionTier {
+                tier: start,
+                changed_at: None,
+            });
+        if let Some(changed_at) = current.changed_at {
+            let elapsed = now.saturating_duration_since(changed_at);
+            if elapsed < self.config.cooldown {
+                let remaining = self.config.cooldown - elapsed;
+                return TierDecision::Deferred(wanted, Deferral::CoolingDown { remaining });
+            }
+        }
+        while self
+            .recompiles
+            .front()
+            .is_some_and(|&t| now.saturating_duration_since(t) >= MINUTE)
+        {
+            self.recompiles.pop_front();
+        }
+        if self.recompiles.len() >= self.config.max_recompiles_per_minute {
+            return TierDecision::Deferred(wanted, Deferral::RateLimited);
+        }
+
+        self.recompiles.push_back(now);
+        current.tier = wanted;
+        current.changed_at = Some(now);
+        TierDecision::Change(wanted)
+    }
+
+    /// Decide for every function in `report`, hottest first so the budget
+    /// goes where it matters most
+    pub fn observe(&mut self, report: &ProfileReport, now: Instant) -> Vec<(String, TierDecision)> {
+        let mut names: Vec<&str> = report.functions.iter().map(|f| f.name.as_str()).collect();
+        names.sort_by(|a, b| report.self_share(b).total_cmp(&report.self_share(a)));
+        names
+            .into_iter()
+            .map(|name| {
+                let decision = self.decide(name, report.self_share(name), now);
+                (name.to_string(), decision)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(config: TieringConfig) -> TieringPolicy {
+        TieringPolicy::new(config).unwrap()
+    }
+
+    #[test]
+    fn test_hysteresis_keeps_functions_near_the_threshold_put() {
+        let mut p = policy(TieringConfig::default().cooldown(Duration::ZERO));
+        let t = Instant::now();
+        assert_eq!(p.decide("f", 0.19, t), TierDecision::Stay);
+        assert_eq!(p.decide("f", 0.2, t), TierDecision::Change(Tier::Optimized));
+        // Hovering between the thresholds doesn't demote
+        for share in [0.15, 0.19, 0.1, 0.21] {
+            assert_eq!(p.decide("f", share, t), TierDecision::Stay);
+        }
+        assert_eq!(p.decide("f", 0.05, t), TierDecision::Change(Tier::Baseline));
+        assert_eq!(p.tier("f"), Tier::Baseline);
+    }
+
+    #[test]
+    fn test_cooldown_and_rate_limit_defer_changes() {
+        let config = TieringConfig::default()
+            .cooldown(Duration::from_secs(10))
+            .max_recompiles_per_minute(2);
+        let mut p = policy(config);
+        let t = Instant::now();
+        assert_eq!(p.decide("f", 0.9, t), TierDecision::Change(Tier::Optimized));
+        assert_eq!(
+            p.decide("f", 0.0, t + Duration::from_secs(4)),
+            TierDecision::Deferred(
+                Tier::Baseline,
+                Deferral::CoolingDown {
+                    remaining: Duration::from_secs(6)
+                }
+            )
+        );
+        assert_eq!(p.tier("f"), Tier::Optimized);
+
+        assert_eq!(p.decide("g", 0.9, t), TierDecision::Change(Tier::Optimized));
+        assert_eq!(
+            p.decide("h", 0.9, t),
+            TierDecision::Deferred(Tier::Optimized, Deferral::RateLimited)
+        );
+        // The budget frees up a minute after the first recompilation
+        assert_eq!(
+            p.decide("h", 0.9, t + MINUTE),
+            TierDecision::Change(Tier::Optimized)
+        );
+    }
+
+    #[test]
+    fn test_ladder_from_interpreter_to_evolved() {
+        let config = TieringConfig::default()
+            .start(Tier::Interpreted)
+            .jit_after_calls(3)
+            .cooldown(Duration::ZERO);
+        let mut p = policy(config);
+        let t = Instant::now();
+        assert_eq!(p.tier("f"), Tier::Interpreted);
+        // Profiles of interpreted code don't promote it
+        assert_eq!(p.decide("f", 1.0, t), TierDecision::Stay);
+        assert_eq!(p.on_evolved("f", t), TierDecision::Stay);
+        assert_eq!(p.on_calls("f", 2, t), TierDecision::Stay);
+        assert_eq!(p.on_calls("f", 3, t), TierDecision::Change(Tier::Baseline));
+        assert_eq!(p.decide("f", 0.5, t), TierDecision::Change(Tier::Optimized));
+        assert_eq!(p.on_evolved("f", t), TierDecision::Change(Tier::Evolved));
+        assert_eq!(p.decide("f", 0.15, t), TierDecision::Stay);
+        assert_eq!(p.decide("f", 0.0, t), TierDecision::Change(Tier::Baseline));
+    }
+
+    #[test]
+    fn test_template_tier_promotes_to_optimized_and_demotes_to_baseline() {
+        let mut p = policy(TieringConfig::default().start(Tier::Template).cooldown(Duration::ZERO));
+        let t = Instant::now();
+        assert_eq!(p.on_calls("f", 1000, t), TierDecision::Stay);
+        assert_eq!(p.decide("f", 0.1, t), TierDecision::Stay);
+        assert_eq!(p.decide("f", 0.3, t), TierDecision::Change(Tier::Optimized));
+        assert_eq!(p.decide("f", 0.0, t), TierDecision::Change(Tier::Baseline));
+    }
+
+    #[test]
+    fn test_rejects_inverted_thresholds() {
+        let config = TieringConfig::default()
+            .promote_share(0.1)
+            .demote_share(0.3);
+        assert!(TieringPolicy::new(config).is_err());
+        assert!(TieringPolicy::new(TieringConfig::default().promote_share(1.5)).is_err());
+    }
+}
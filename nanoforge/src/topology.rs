@@ -0,0 +1,442 @@
+//! CPU Topology Detection
+//!
+//! Reads the Linux sysfs CPU tree to find logical CPUs, their SMT siblings,
+//! NUMA nodes and (on hybrid parts) whether each is a performance or
+//! efficiency core. The sandbox uses this to pick a benchmark CPU instead of
+//! blindly pinning to CPU 0, which on hybrid Intel chips may be an E-core
+//! and on every system is the one servicing most interrupts.
+
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::sync::OnceLock;
+
+const SYSFS_ROOT: &str = "/sys/devices";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoreKind {
+    Performance,
+    Efficiency,
+    /// Not a hybrid part (or the kernel doesn't say)
+    Unknown,
+}
+
+/// One logical CPU (hardware thread)
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogicalCpu {
+    pub id: usize,
+    pub core_id: usize,
+    pub package_id: usize,
+    pub numa_node: usize,
+    pub kind: CoreKind,
+    /// Other hardware threads on the same physical core
+    pub smt_siblings: Vec<usize>,
+    /// Listed in the kernel's `isolcpus` set
+    pub isolated: bool,
+    pub max_freq_khz: Option<u64>,
+    /// Size of the highest-level cache this CPU uses
+    pub llc_bytes: Option<u64>,
+}
+
+/// Why a CPU was picked for benchmarking
+#[derive(Debug, Clone, PartialEq)]
+pub struct PinChoice {
+    pub cpu: usize,
+    pub kind: CoreKind,
+    pub isolated: bool,
+    pub numa_node: usize,
+}
+
+impl fmt::Display for PinChoice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            CoreKind::Performance => "P-core",
+            CoreKind::Efficiency => "E-core",
+            CoreKind::Unknown => "core",
+        };
+        write!(f, "CPU {} ({}, node {}", self.cpu, kind, self.numa_node)?;
+        if self.isolated {
+            write!(f, ", isolated")?;
+        }
+        write!(f, ")")
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CpuTopology {
+    pub cpus: Vec<LogicalCpu>,
+}
+
+impl CpuTopology {
+    /// Detect the topology of this machine (empty if sysfs is unavailable)
+    pub fn detect() -> Self {
+        Self::detect_from(Path::new(SYSFS_ROOT))
+    }
+
+    /// Topology detected once per process
+    pub fn cached() -> &'static CpuTopology {
+        static TOPOLOGY: OnceLock<CpuTopology> = OnceLock::new();
+        TOPOLOGY.get_or_init(Self::detect)
+    }
+
+    /// Detect from a sysfs tree rooted at `root` (normally `/sys/devices`)
+    pub fn detect_from(root: &Path) -> Self {
+        let cpu_dir = root.join("system/cpu");
+        let online = read_list(&cpu_dir.join("online")).unwrap_or_default();
+        let isolated = read_list(&cpu_dir.join("isolated")).unwrap_or_default();
+
+        // Hybrid Intel exposes one PMU per core type
+        let p_cores = read_list(&root.join("cpu_core/cpus"));
+        let e_cores = read_list(&root.join("cpu_atom/cpus"));
+        let nodes = numa_nodes(&root.join("system/node"));
+
+        let mut cpus: Vec<LogicalCpu> = online
+            .iter()
+            .map(|&id| {
+                let dir = cpu_dir.join(format!("cpu{}", id));
+                let topo = dir.join("topology");
+                let kind = match (&p_cores, &e_cores) {
+                    (Some(p), _) if p.contains(&id) => CoreKind::Performance,
+                    (_, Some(e)) if e.contains(&id) => CoreKind::Efficiency,
+                    _ => CoreKind::Unknown,
+                };
+                LogicalCpu {
+                    id,
+                    core_id: read_num(&topo.join("core_id")).unwrap_or(id as u64) as usize,
+                    package_id: read_num(&topo.join("physical_package_id")).unwrap_or(0) as usize,
+                    numa_node: nodes
+                        .iter()
+                        .find(|(_, cpus)| cpus.contains(&id))
+                        .map_or(0, |(node, _)| *node),
+                    kind,
+                    smt_siblings: read_list(&topo.join("thread_siblings_list"))
+                        .unwrap_or_default()
+                        .into_iter()
+                        .filter(|&s| s != id)
+                        .collect(),
+                    isolated: isolated.contains(&id),
+                    max_freq_khz: read_num(&dir.join("cpufreq/cpuinfo_max_freq")),
+                    llc_bytes: last_level_cache(&dir.join("cache")),
+                }
+            })
+            .collect();
+
+        // No hybrid PMU info: fall back to frequency classes, where the
+        // lower-clocked cores of a two-speed system are the efficiency ones
+        if p_cores.is_none() && e_cores.is_none() {
+            classify_by_frequency(&mut cpus);
+        }
+
+        CpuTopology { cpus }
+    }
+
+    pub fn is_hybrid(&self) -> bool {
+        self.cpus.iter().any(|c| c.kind == CoreKind::Efficiency)
+    }
+
+    /// Number of distinct physical cores
+    pub fn physical_cores(&self) -> usize {
+        let mut cores: Vec<_> = self
+            .cpus
+            .iter()
+            .map(|c| (c.package_id, c.core_id))
+            .collect();
+        cores.sort_unstable();
+        cores.dedup();
+        cores.len()
+    }
+
+    pub fn get(&self, cpu: usize) -> Option<&LogicalCpu> {
+        self.cpus.iter().find(|c| c.id == cpu)
+    }
+
+    /// Describe `cpu` for result metadata
+    pub fn describe(&self, cpu: usize) -> PinChoice {
+        match self.get(cpu) {
+            Some(c) => PinChoice {
+                cpu,
+                kind: c.kind,
+                isolated: c.isolated,
+                numa_node: c.numa_node,
+            },
+            None => PinChoice {
+                cpu,
+                kind: CoreKind::Unknown,
+                isolated: false,
+                numa_node: 0,
+            },
+        }
+    }
+
+    /// Pick the quietest fast CPU this thread may run on.
+    ///
+    /// Preference order: isolated CPUs, P-cores over E-cores, anything but
+    /// CPU 0 and its SMT siblings (interrupt and housekeeping load), then the
+    /// highest max frequency. Ties go to the lowest CPU id.
+    pub fn benchmark_cpu(&self, allowed: &[usize]) -> Option<PinChoice> {
+        self.cpus
+            .iter()
+            .filter(|c| allowed.is_empty() || allowed.contains(&c.id))
+            .max_by_key(|c| {
+                let shares_cpu0 = c.id == 0 || c.smt_siblings.contains(&0);
+                (
+                    c.isolated,
+                    c.kind != CoreKind::Efficiency,
+                    !shares_cpu0,
+                    c.max_freq_khz.unwrap_or(0),
+                    std::cmp::Reverse(c.id),
+                )
+            })
+            .map(|c| self.describe(c.id))
+    }
+
+    /// `benchmark_cpu` restricted to the CPUs of one NUMA node
+    pub fn benchmark_cpu_on_node(&self, node: usize, allowed: &[usize]) -> Option<PinChoice> {
+        let on_node: Vec<usize> = self
+            .cpus
+            .iter()
+            .filter(|c| c.numa_node == node && (allowed.is_empty() || allowed.contains(&c.id)))
+            .map(|c| c.id)
+            .collect();
+        if on_node.is_empty() {
+            return None;
+        }
+        self.benchmark_cpu(&on_node)
+    }
+}
+
+/// CPUs the current thread is allowed to run on
+pub fn allowed_cpus() -> Vec<usize> {
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        if libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set) != 0 {
+            return Vec::new();
+        }
+        (0..libc::CPU_SETSIZE as usize)
+            .filter(|&cpu| libc::CPU_ISSET(cpu, &set))
+            .collect()
+    }
+}
+
+/// Default benchmark CPU for this process
+pub fn default_benchmark_cpu() -> Option<PinChoice> {
+    CpuTopology::cached().benchmark_cpu(&allowed_cpus())
+}
+
+fn classify_by_frequency(cpus: &mut [LogicalCpu]) {
+    let freqs: Vec<u64> = cpus.iter().filter_map(|c| c.max_freq_khz).collect();
+    let (Some(&min), Some(&max)) = (freqs.iter().min(), freqs.iter().max()) else {
+        return;
+    };
+    // Turbo bins differ by a few percent between identical cores
+    if min as f64 > max as f64 * 0.85 {
+        return;
+    }
+    for cpu in cpus.iter_mut() {
+        cpu.kind = match cpu.max_freq_khz {
+            Some(f) if f == min => CoreKind::Efficiency,
+            Some(_) => CoreKind::Performance,
+            None => CoreKind::Unknown,
+        };
+    }
+}
+
+fn numa_nodes(node_dir: &Path) -> Vec<(usize, Vec<usize>)> {
+    let Ok(entries) = fs::read_dir(node_dir) else {
+        return Vec::new();
+    };
+    let mut nodes: Vec<(usize, Vec<usize>)> = entries
+        .flatten()
+        .filter_map(|e| {
+            let name = e.file_name().into_string().ok()?;
+            let node = name.strip_prefix("node")?.parse().ok()?;
+            let cpus = read_list(&e.path().join("cpulist"))?;
+            Some((node, cpus))
+        })
+        .collect();
+    nodes.sort();
+    nodes
+}
+
+/// Size of the highest-level cache listed under a CPU's `cache` directory
+fn last_level_cache(cache_dir: &Path) -> Option<u64> {
+    fs::read_dir(cache_dir)
+        .ok()?
+        .flatten()
+        .filter(|e| e.file_name().to_string_lossy().starts_with("index"))
+        .filter_map(|e| {
+            let level = read_num(&e.path().join("level"))?;
+            let size = parse_cache_size(&fs::read_to_string(e.path().join("size")).ok()?)?;
+            Some((level, size))
+        })
+        .max()
+        .map(|(_, bytes)| bytes)
+}
+
+/// Parse a sysfs cache size such as `48K` or `107520K`
+pub fn parse_cache_size(text: &str) -> Option<u64> {
+    let text = text.trim();
+    let (digits, unit) = match text.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => text.split_at(i),
+        None => (text, ""),
+    };
+    let scale = match unit {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        _ => return None,
+    };
+    digits.parse::<u64>().ok().map(|n| n * scale)
+}
+
+fn read_num(path: &Path) -> Option<u64> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+fn read_list(path: &Path) -> Option<Vec<usize>> {
+    parse_cpu_list(&fs::read_to_string(path).ok()?)
+}
+
+/// Parse a kernel CPU list such as `0-3,8,10-11`
+pub fn parse_cpu_list(text: &str) -> Option<Vec<usize>> {
+    let mut cpus = Vec::new();
+    for part in text.trim().split(',').filter(|p| !p.is_empty()) {
+        match part.split_once('-') {
+            Some((lo, hi)) => {
+                let (lo, hi): (usize, usize) = (lo.parse().ok()?, hi.parse().ok()?);
+                cpus.extend(lo..=hi);
+            }
+            None => cpus.push(part.parse().ok()?),
+        }
+    }
+    Some(cpus)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn write(root: &Path, rel: &str, contents: &str) {
+        let path = root.join(rel);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    /// 2 P-cores with SMT (cpus 0-3) and 2 E-cores (cpus 4-5)
+    fn fake_hybrid_sysfs() -> PathBuf {
+        let root = std::env::temp_dir().join(format!("nf_topology_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        write(&root, "system/cpu/online", "0-5\n");
+        write(&root, "system/cpu/isolated", "\n");
+        write(&root, "cpu_core/cpus", "0-3\n");
+        write(&root, "cpu_atom/cpus", "4-5\n");
+        write(&root, "system/node/node0/cpulist", "0-5\n");
+        let siblings = ["0-1", "0-1", "2-3", "2-3", "4", "5"];
+        for (cpu, sib) in siblings.iter().enumerate() {
+            let topo = format!("system/cpu/cpu{}/topology", cpu);
+            write(
+                &root,
+                &format!("{}/core_id", topo),
+                &format!("{}\n", cpu / 2),
+            );
+            write(&root, &format!("{}/physical_package_id", topo), "0\n");
+            write(&root, &format!("{}/thread_siblings_list", topo), sib);
+            let cache = format!("system/cpu/cpu{}/cache", cpu);
+            for (index, level, size) in [(0, "1", "48K"), (2, "3", "24576K"), (1, "2", "1280K")] {
+                write(&root, &format!("{}/index{}/level", cache, index), level);
+                write(&root, &format!("{}/index{}/size", cache, index), size);
+            }
+        }
+        root
+    }
+
+    #[test]
+    fn test_parse_cpu_list() {
+        assert_eq!(
+            parse_cpu_list("0-3,8,10-11\n"),
+            Some(vec![0, 1, 2, 3, 8, 10, 11])
+        );
+        assert_eq!(parse_cpu_list(""), Some(vec![]));
+        assert_eq!(parse_cpu_list("x"), None);
+    }
+
+    #[test]
+    fn test_parse_cache_size() {
+        assert_eq!(parse_cache_size("48K\n"), Some(48 << 10));
+        assert_eq!(parse_cache_size("32M"), Some(32 << 20));
+        assert_eq!(parse_cache_size("512"), Some(512));
+        assert_eq!(parse_cache_size("2Q"), None);
+    }
+
+    #[test]
+    fn test_hybrid_topology_prefers_p_core_away_from_cpu0() {
+        let root = fake_hybrid_sysfs();
+        let topo = CpuTopology::detect_from(&root);
+
+        assert_eq!(topo.cpus.len(), 6);
+        assert!(topo.is_hybrid());
+        assert_eq!(topo.physical_cores(), 3);
+        assert_eq!(topo.get(1).unwrap().smt_siblings, vec![0]);
+        assert_eq!(topo.get(5).unwrap().kind, CoreKind::Efficiency);
+        assert_eq!(topo.get(0).unwrap().llc_bytes, Some(24 << 20));
+
+        // CPU 0 and its sibling are avoided; E-cores lose to P-cores
+        let choice = topo.benchmark_cpu(&[]).unwrap();
+        assert_eq!((choice.cpu, choice.kind), (2, CoreKind::Performance));
+
+        // Only E-cores allowed
+        assert_eq!(topo.benchmark_cpu(&[4, 5]).unwrap().cpu, 4);
+
+        // An isolated CPU wins over everything
+        write(&root, "system/cpu/isolated", "3\n");
+        let topo = CpuTopology::detect_from(&root);
+        let choice = topo.benchmark_cpu(&[]).unwrap();
+        assert_eq!(choice.cpu, 3);
+        assert!(choice.to_string().contains("isolated"));
+
+        fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn test_benchmark_cpu_on_node() {
+        let root = std::env::temp_dir().join(format!("nf_topology_numa_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        write(&root, "system/cpu/online", "0-3\n");
+        write(&root, "system/node/node0/cpulist", "0-1\n");
+        write(&root, "system/node/node1/cpulist", "2-3\n");
+        for cpu in 0..4 {
+            let topo = format!("system/cpu/cpu{}/topology", cpu);
+            write(&root, &format!("{}/core_id", topo), &format!("{}\n", cpu));
+            write(
+                &root,
+                &format!("{}/physical_package_id", topo),
+                &format!("{}\n", cpu / 2),
+            );
+            write(
+                &root,
+                &format!("{}/thread_siblings_list", topo),
+                &cpu.to_string(),
+            );
+        }
+        let topo = CpuTopology::detect_from(&root);
+
+        let choice = topo.benchmark_cpu_on_node(1, &[]).unwrap();
+        assert_eq!((choice.cpu, choice.numa_node), (2, 1));
+        // CPU 0 is avoided even when restricted to its node
+        assert_eq!(topo.benchmark_cpu_on_node(0, &[]).unwrap().cpu, 1);
+        assert_eq!(topo.benchmark_cpu_on_node(1, &[0, 1]), None);
+        assert_eq!(topo.benchmark_cpu_on_node(7, &[]), None);
+
+        fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn test_detect_this_machine() {
+        let topo = CpuTopology::detect();
+        if !topo.cpus.is_empty() {
+            assert!(default_benchmark_cpu().is_some());
+        }
+    }
+}
@@ -0,0 +1,177 @@
+//! Corpus Tuning
+//!
+//! `nanoforge tune` runs SOAE over every script of a kernel library and
+//! remembers each one's winning configuration in a `TuningStore`. Entries are
+//! keyed by a hash of the script's source, so renaming a file keeps its entry
+//! and editing it invalidates it; they also record the CPU that measured
+//! them, since a winner on one machine says little about another.
+
+use crate::parser::Parser;
+use crate::soae::{self, SoaeOptions};
+use crate::store::{self, Schema};
+use crate::variant_generator::VariantConfig;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Best configuration found for one script
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TunedEntry {
+    /// Path the script was last tuned from
+    pub script: String,
+    pub config: VariantConfig,
+    pub cycles_per_op: u64,
+    /// Cycles/op of the first (scalar) variant, for comparison
+    pub baseline_cycles_per_op: u64,
+    pub code_size: usize,
+    /// `CpuFeatures::summary` of the machine that measured it
+    pub cpu: String,
+}
+
+impl TunedEntry {
+    /// How many times faster than the scalar baseline
+    pub fn speedup(&self) -> f64 {
+        self.baseline_cycles_per_op as f64 / self.cycles_per_op.max(1) as f64
+    }
+}
+
+/// Tuned configurations by script hash, persisted as JSON
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct TuningStore {
+    pub entries: BTreeMap<String, TunedEntry>,
+}
+
+impl TuningStore {
+    pub const SCHEMA: Schema = Schema::new("tuning", 1);
+
+    /// `$HOME/.nanoforge/tuning.json`
+    pub fn default_path() -> PathBuf {
+        store::default_dir().join("tuning.json")
+    }
+
+    /// Load the store at `path`; a missing file is an empty store
+    pub fn open(path: &Path) -> Result<Self, String> {
+        store::load_or_default(path, Self::SCHEMA)
+    }
+
+    /// Write the store to `path`, replacing the old file only once the new
+    /// one is complete
+    pub fn save_to_file(&self, path: &Path) -> Result<(), String> {
+        store::save(path, Self::SCHEMA, self)
+    }
+
+    /// Entry for this exact `source`, if it was tuned
+    pub fn lookup(&self, source: &str) -> Option<&TunedEntry> {
+        self.entries.get(&script_hash(source))
+    }
+
+    pub fn record(&mut self, source: &str, entry: TunedEntry) {
+        self.entries.insert(script_hash(source), entry);
+    }
+}
+
+/// Stable hash of a script's source (64-bit FNV-1a, hex)
+pub fn script_hash(source: &str) -> String {
+    let hash = source.bytes().fold(0xcbf2_9ce4_8422_2325u64, |h, b| {
+        (h ^ b as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    });
+    format!("{:016x}", hash)
+}
+
+/// Scripts to tune: files as given, and the `.nf` files of directories
+/// (sorted, not recursive)
+pub fn collect_scripts(paths: &[String]) -> Result<Vec<PathBuf>, String> {
+    let mut scripts = Vec::new();
+    for path in paths.iter().map(PathBuf::from) {
+        if path.is_dir() {
+            let entries = fs::read_dir(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+            let mut found: Vec<PathBuf> = entries
+                .filter_map(|e| e.ok().map(|e| e.path()))
+                .filter(|p| p.is_file() && p.extension().is_some_and(|x| x == "nf"))
+                .collect();
+            found.sort();
+            scripts.extend(found);
+        } else {
+            scripts.push(path);
+        }
+    }
+    Ok(scripts)
+}
+
+/// Benchmark every variant of `source` and return the fastest
+pub fn tune(script: &str, source: &str, options: &SoaeOptions) -> Result<TunedEntry, String> {
+    let program = Parser::new()
+        .parse(source)
+        .map_err(|e| format!("Parsing Error: {}", e))?;
+    let report = soae::run(&program, options)?;
+    let best = report.winner();
+    let winner = report
+        .variant(&best.variant_name)
+        .ok_or("Winner is not among the variants")?;
+    let baseline = &report.variants[0].config.name;
+    Ok(TunedEntry {
+        script: script.to_string(),
+        config: winner.config.clone(),
+        cycles_per_op: best.result.cycles_per_op,
+        baseline_cycles_per_op: report
+            .rankings
+            .iter()
+            .find(|r| &r.variant_name == baseline)
+            .map_or(0, |r| r.result.cycles_per_op),
+        code_size: winner.code_size,
+        cpu: report.cpu.summary(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::variant_generator::VariantGenerator;
+
+    #[test]
+    fn test_tune_and_persist() {
+        let source = "fn main(n) {
+            i = 0
+            s = 0
+            label loop
+            if i >= n goto done
+            s = s + i
+            i = i + 1
+            goto loop
+            label done
+            return s
+        }";
+        let options = SoaeOptions::default().measurement(2, 10).input(100);
+        let entry = tune("sum.nf", source, &options).unwrap();
+        assert!(VariantGenerator::new()
+            .configs_for(&Parser::new().parse(source).unwrap())
+            .iter()
+            .any(|c| c.name == entry.config.name));
+        assert!(entry.cycles_per_op <= entry.baseline_cycles_per_op);
+
+        let path = std::env::temp_dir()
+            .join(format!("nf_tuning_{}", std::process::id()))
+            .join("tuning.json");
+        let mut store = TuningStore::open(&path).unwrap();
+        assert!(store.lookup(source).is_none());
+        store.record(source, entry.clone());
+        store.save_to_file(&path).unwrap();
+
+        let reopened = TuningStore::open(&path).unwrap();
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+        let saved = reopened.lookup(source).unwrap();
+        assert_eq!(saved.config.name, entry.config.name);
+        assert_eq!(saved.script, "sum.nf");
+        // Edits invalidate the entry
+        assert!(reopened.lookup(&source.replace("i + 1", "i + 2")).is_none());
+
+        assert!(tune("bad.nf", "fn main( {", &options).is_err());
+    }
+
+    #[test]
+    fn test_script_hash_is_stable() {
+        assert_eq!(script_hash(""), "cbf29ce484222325");
+        assert_eq!(script_hash("a"), "af63dc4c8601ec8c");
+    }
+}
@@ -0,0 +1,192 @@
+//! Micro-architecture Database
+//!
+//! Starting points that are known to suit a CPU model before anything has
+//! been measured on it: the unroll factor, how far ahead to prefetch, the
+//! array length from which stores should bypass the caches, and the vector
+//! width to prefer. `VariantGenerator` offers a variant built from them,
+//! the cost model's priors discount vectors wider than the preferred width,
+//! and `array_ops` switches to non-temporal stores at the threshold. Learned
+//! state (a saved brain, a tuning store) overrides all of it.
+//!
+//! Models are matched on the CPUID vendor, family and model on x86-64, and
+//! on the MIDR implementer on Apple silicon. Anything else gets `GENERIC`,
+//! which leaves the generator and cost model as they were.
+
+use crate::cpu_features::VectorWidth;
+use nanoforge_core::target::{cpu_identity, family_model};
+use std::sync::OnceLock;
+
+/// Per-model defaults
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UarchDefaults {
+    pub name: &'static str,
+    /// Copies of a loop body per iteration
+    pub unroll: u8,
+    /// Bytes to prefetch ahead of vector loads
+    pub prefetch_distance: i32,
+    /// Array length (i64 elements) from which stores bypass the caches
+    pub nt_store_threshold: usize,
+    /// Widest vectors worth using (None: no preference)
+    pub vector_width: Option<VectorWidth>,
+}
+
+/// Unknown CPUs
+pub const GENERIC: UarchDefaults = UarchDefaults {
+    name: "generic",
+    unroll: 4,
+    prefetch_distance: 0,
+    nt_store_threshold: 131_072,
+    vector_width: None,
+};
+
+/// Which CPUs a table row covers
+enum Models {
+    /// CPUID vendor, display family and inclusive model ranges
+    X86(&'static [u8; 12], u32, &'static [(u32, u32)]),
+    /// MIDR implementer 0x61
+    AppleSilicon,
+}
+
+const TABLE: &[(Models, UarchDefaults)] = &[
+    (
+        Models::X86(b"AuthenticAMD", 0x19, &[(0x00, 0x0F), (0x20, 0x5F)]),
+        UarchDefaults {
+            name: "Zen 3",
+            unroll: 4,
+            prefetch_distance: 128,
+            nt_store_threshold: 2_097_152,
+            vector_width: Some(VectorWidth::Lanes4),
+        },
+    ),
+    (
+        // Its AVX-512 is double-pumped but doesn't downclock. 0xA0-0xAF
+        // are the dense Zen 4c cores, same pipeline with less cache.
+        Models::X86(b"AuthenticAMD", 0x19, &[(0x10, 0x1F), (0x60, 0x7F), (0xA0, 0xAF)]),
+        UarchDefaults {
+            name: "Zen 4",
+            unroll: 4,
+            prefetch_distance: 256,
+            nt_store_threshold: 2_097_152,
+            vector_width: Some(VectorWidth::Lanes8),
+        },
+    ),
+    (
+        // AVX-512 costs a frequency license on Skylake-SP
+        Models::X86(b"GenuineIntel", 6, &[(0x4E, 0x4E), (0x55, 0x55), (0x5E, 0x5E), (0x8E, 0x8E), (0x9E, 0x9E)]),
+        UarchDefaults {
+            name: "Skylake",
+            unroll: 4,
+            prefetch_distance: 128,
+            nt_store_threshold: 131_072,
+            vector_width: Some(VectorWidth::Lanes4),
+        },
+    ),
+    (
+        Models::X86(b"GenuineIntel", 6, &[(0x6A, 0x6A), (0x6C, 0x6C), (0x7D, 0x7E)]),
+        UarchDefaults {
+            name: "Ice Lake",
+            unroll: 4,
+            prefetch_distance: 256,
+            nt_store_threshold: 262_144,
+            vector_width: Some(VectorWidth::Lanes8),
+        },
+    ),
+    (
+        Models::X86(b"GenuineIntel", 6, &[(0xBE, 0xBE)]),
+        UarchDefaults {
+            name: "Gracemont",
+            unroll: 2,
+            prefetch_distance: 64,
+            nt_store_threshold: 65_536,
+            vector_width: Some(VectorWidth::Lanes4),
+        },
+    ),
+    (
+        // 128-bit NEON, but very wide integer issue
+        Models::AppleSilicon,
+        UarchDefaults {
+            name: "Apple M-series",
+            unroll: 8,
+            prefetch_distance: 256,
+            nt_store_threshold: 1_048_576,
+            vector_width: Some(VectorWidth::Lanes2),
+        },
+    ),
+];
+
+impl UarchDefaults {
+    /// Defaults for an x86 CPU by its 12-byte vendor string and CPUID leaf 1
+    /// EAX signature
+    pub fn for_x86(vendor: &[u8], signature: u32) -> Self {
+        let (family, model) = family_model(signature);
+        TABLE
+            .iter()
+            .find(|(models, _)| match models {
+                Models::X86(v, f, ranges) => {
+                    v.as_slice() == vendor
+                        && *f == family
+                        && ranges.iter().any(|&(lo, hi)| (lo..=hi).contains(&model))
+                }
+                Models::AppleSilicon => false,
+            })
+            .map_or(GENERIC, |(_, defaults)| *defaults)
+    }
+
+    /// Defaults for an ARM CPU by the implementer field of MIDR_EL1
+    pub fn for_arm(implementer: u8) -> Self {
+        TABLE
+            .iter()
+            .find(|(models, _)| matches!(models, Models::AppleSilicon) && implementer == 0x61)
+            .map_or(GENERIC, |(_, defaults)| *defaults)
+    }
+
+    pub fn detect() -> Self {
+        if let Some((vendor, signature)) = cpu_identity() {
+            return Self::for_x86(&vendor, signature);
+        }
+        if cfg!(all(target_arch = "aarch64", target_os = "macos")) {
+            return Self::for_arm(0x61);
+        }
+        // Linux exposes MIDR_EL1 of each core
+        std::fs::read_to_string("/sys/devices/system/cpu/cpu0/regs/identification/midr_el1")
+            .ok()
+            .and_then(|midr| u64::from_str_radix(midr.trim().trim_start_matches("0x"), 16).ok())
+            .map_or(GENERIC, |midr| Self::for_arm((midr >> 24) as u8))
+    }
+
+    /// Detected once per process
+    pub fn cached() -> &'static Self {
+        static DEFAULTS: OnceLock<UarchDefaults> = OnceLock::new();
+        DEFAULTS.get_or_init(Self::detect)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_models_map_to_their_defaults() {
+        let name = |vendor: &[u8], signature| UarchDefaults::for_x86(vendor, signature).name;
+        // Vermeer, Milan, Raphael, Genoa, Bergamo (Zen 4c)
+        assert_eq!(name(b"AuthenticAMD", 0x00A20F10), "Zen 3");
+        assert_eq!(name(b"AuthenticAMD", 0x00A00F11), "Zen 3");
+        assert_eq!(name(b"AuthenticAMD", 0x00A60F12), "Zen 4");
+        assert_eq!(name(b"AuthenticAMD", 0x00A10F11), "Zen 4");
+        assert_eq!(name(b"AuthenticAMD", 0x00AA0F01), "Zen 4");
+        // Skylake client and server, Ice Lake client and server, Alder Lake-N
+        assert_eq!(name(b"GenuineIntel", 0x000506E3), "Skylake");
+        assert_eq!(name(b"GenuineIntel", 0x00050654), "Skylake");
+        assert_eq!(name(b"GenuineIntel", 0x000706E5), "Ice Lake");
+        assert_eq!(name(b"GenuineIntel", 0x000606A6), "Ice Lake");
+        assert_eq!(name(b"GenuineIntel", 0x000B06E0), "Gracemont");
+        // Zen 2, Sapphire Rapids and unknown vendors aren't in the table
+        assert_eq!(UarchDefaults::for_x86(b"AuthenticAMD", 0x00830F10), GENERIC);
+        assert_eq!(UarchDefaults::for_x86(b"GenuineIntel", 0x000806F8), GENERIC);
+        assert_eq!(UarchDefaults::for_x86(b"SomeVendorXX", 0x000506E3), GENERIC);
+
+        assert_eq!(UarchDefaults::for_arm(0x61).name, "Apple M-series");
+        assert_eq!(UarchDefaults::for_arm(0x41), GENERIC);
+        assert_eq!(UarchDefaults::cached(), UarchDefaults::cached());
+    }
+}
@@ -0,0 +1,285 @@
+//! JIT Unwind Info
+//!
+//! Every compiled function opens the same RBP frame (`JitBuilder::prologue`),
+//! so one short CFI program describes them all. `UnwindInfo::register` hands
+//! the unwinder (`__register_frame`) an `.eh_frame` table with one FDE per
+//! function of a program, so Rust backtraces and panics walk through JIT
+//! frames instead of stopping at them, and `backtrace` names the JIT frames
+//! it passes (the crash handler prints it).
+//!
+//! The CFI is exact everywhere except on the final `ret` of each exit, after
+//! `pop rbp`. x86-64 only; elsewhere `register` returns None.
+
+use std::collections::BTreeMap;
+use std::ffi::{c_int, c_void};
+use std::sync::Mutex;
+
+/// `push rbp; mov rbp, rsp; push r15; push rbx; push r12; push r13; push r14`
+const PROLOGUE: [u8; 13] = [
+    0x55, 0x48, 0x89, 0xe5, 0x41, 0x57, 0x53, 0x41, 0x54, 0x41, 0x55, 0x41, 0x56,
+];
+
+// DWARF call frame instructions and x86-64 register numbers
+const DW_CFA_ADVANCE_LOC: u8 = 0x40;
+const DW_CFA_OFFSET: u8 = 0x80;
+const DW_CFA_DEF_CFA: u8 = 0x0c;
+const DW_CFA_DEF_CFA_REGISTER: u8 = 0x0d;
+const DW_CFA_DEF_CFA_OFFSET: u8 = 0x0e;
+const RBX: u8 = 3;
+const RBP: u8 = 6;
+const RSP: u8 = 7;
+const R12: u8 = 12;
+const R13: u8 = 13;
+const R14: u8 = 14;
+const R15: u8 = 15;
+const RETURN_ADDRESS: u8 = 16;
+
+/// Compiled functions registered with the unwinder: start, end, name
+static JIT_FUNCTIONS: Mutex<Vec<(usize, usize, String)>> = Mutex::new(Vec::new());
+
+extern "C" {
+    fn __register_frame(table: *const u8);
+    fn __deregister_frame(table: *const u8);
+    fn _Unwind_Backtrace(
+        trace: extern "C" fn(*mut c_void, *mut c_void) -> c_int,
+        data: *mut c_void,
+    ) -> c_int;
+    fn _Unwind_GetIP(context: *mut c_void) -> usize;
+}
+
+/// An `.eh_frame` table registered with the unwinder until dropped
+#[derive(Debug)]
+pub struct UnwindInfo {
+    table: Box<[u8]>,
+}
+
+impl UnwindInfo {
+    /// Register the functions at `offsets` in `code`, which is mapped at
+    /// `base` for execution. Functions that don't start with the standard
+    /// prologue are left out; None if none do.
+    pub fn register(code: &[u8], base: *const u8, offsets: &BTreeMap<String, usize>) -> Option<Self> {
+        if !cfg!(target_arch = "x86_64") {
+            return None;
+        }
+        let mut starts: Vec<(usize, &str)> = offsets.iter().map(|(n, &o)| (o, n.as_str())).collect();
+        starts.sort();
+        let functions: Vec<(usize, usize, &str)> = starts
+            .iter()
+            .enumerate()
+            .map(|(i, &(start, name))| {
+                let end = starts.get(i + 1).map_or(code.len(), |&(next, _)| next);
+                (start, end, name)
+            })
+            .filter(|&(start, _, _)| code.get(start..start + PROLOGUE.len()) == Some(&PROLOGUE[..]))
+            .collect();
+        if functions.is_empty() {
+            return None;
+        }
+
+        let base = base as usize;
+        let ranges: Vec<(usize, usize)> = functions.iter().map(|&(s, e, _)| (base + s, e - s)).collect();
+        let table = eh_frame(&ranges).into_boxed_slice();
+        unsafe { __register_frame(table.as_ptr()) };
+        let mut registry = JIT_FUNCTIONS.lock().unwrap_or_else(|e| e.into_inner());
+        registry.extend(functions.iter().map(|&(s, e, name)| (base + s, base + e, name.to_string())));
+        Some(Self { table })
+    }
+}
+
+impl Drop for UnwindInfo {
+    fn drop(&mut self) {
+        unsafe { __deregister_frame(self.table.as_ptr()) };
+        let starts = fde_starts(&self.table);
+        let mut registry = JIT_FUNCTIONS.lock().unwrap_or_else(|e| e.into_inner());
+        registry.retain(|(start, _, _)| !starts.contains(start));
+    }
+}
+
+/// `pc_begin` of every FDE in `table`
+fn fde_starts(table: &[u8]) -> Vec<usize> {
+    let mut starts = Vec::new();
+    let mut pos = 0;
+    while let Some(len) = table.get(pos..pos + 4) {
+        let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+        if len == 0 {
+            break;
+        }
+        let id = u32::from_le_bytes(table[pos + 4..pos + 8].try_into().unwrap());
+        if id != 0 {
+            starts.push(u64::from_le_bytes(table[pos + 8..pos + 16].try_into().unwrap()) as usize);
+        }
+        pos += 4 + len;
+    }
+    starts
+}
+
+/// An `.eh_frame` table: one CIE, then an FDE for each `(address, length)`
+/// of a function with the standard prologue, then the zero terminator
+pub fn eh_frame(functions: &[(usize, usize)]) -> Vec<u8> {
+    let mut table = Vec::new();
+
+    // CIE: "zR" with absolute FDE addresses; on entry CFA = RSP + 8 and the
+    // return address is at CFA - 8
+    let mut cie = vec![0, 0, 0, 0, 1, b'z', b'R', 0, 1, 0x78, RETURN_ADDRESS, 1, 0];
+    cie.extend([DW_CFA_DEF_CFA, RSP, 8, DW_CFA_OFFSET | RETURN_ADDRESS, 1]);
+    push_entry(&mut table, cie);
+
+    for &(address, length) in functions {
+        let mut fde = Vec::new();
+        // Distance back to the CIE, from this field
+        fde.extend((table.len() as u32 + 4).to_le_bytes());
+        fde.extend((address as u64).to_le_bytes());
+        fde.extend((length as u64).to_le_bytes());
+        fde.push(0); // No augmentation data
+        fde.extend([
+            // push rbp
+            DW_CFA_ADVANCE_LOC | 1,
+            DW_CFA_DEF_CFA_OFFSET,
+            16,
+            DW_CFA_OFFSET | RBP,
+            2,
+            // mov rbp, rsp
+            DW_CFA_ADVANCE_LOC | 3,
+            DW_CFA_DEF_CFA_REGISTER,
+            RBP,
+            // push r15; push rbx; push r12; push r13; push r14
+            DW_CFA_ADVANCE_LOC | 9,
+            DW_CFA_OFFSET | R15,
+            3,
+            DW_CFA_OFFSET | RBX,
+            4,
+            DW_CFA_OFFSET | R12,
+            5,
+            DW_CFA_OFFSET | R13,
+            6,
+            DW_CFA_OFFSET | R14,
+            7,
+        ]);
+        push_entry(&mut table, fde);
+    }
+    table.extend([0; 4]);
+    table
+}
+
+/// Append `body` with its length, padded to 8 bytes with `DW_CFA_nop`s
+fn push_entry(table: &mut Vec<u8>, mut body: Vec<u8>) {
+    while !(body.len() + 4).is_multiple_of(8) {
+        body.push(0);
+    }
+    table.extend((body.len() as u32).to_le_bytes());
+    table.extend(body);
+}
+
+/// A return address on the stack
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub ip: usize,
+    /// The compiled function it lies in, and the offset into it
+    pub jit: Option<(String, usize)>,
+}
+
+/// The calling thread's stack, innermost frame first
+pub fn backtrace() -> Vec<Frame> {
+    extern "C" fn trace(context: *mut c_void, data: *mut c_void) -> c_int {
+        let ips = unsafe { &mut *(data as *mut Vec<usize>) };
+        ips.push(unsafe { _Unwind_GetIP(context) });
+        0 // _URC_NO_REASON: keep going
+    }
+    let mut ips: Vec<usize> = Vec::new();
+    unsafe { _Unwind_Backtrace(trace, &mut ips as *mut Vec<usize> as *mut c_void) };
+
+    // Never block: this runs in the crash handler
+    let registry = JIT_FUNCTIONS.try_lock().ok();
+    ips.into_iter()
+        .map(|ip| Frame {
+            ip,
+            jit: registry.as_ref().and_then(|r| {
+                r.iter()
+                    .find(|(start, end, _)| (*start..*end).contains(&ip))
+                    .map(|(start, _, name)| (name.clone(), ip - start))
+            }),
+        })
+        .collect()
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+    use crate::assembler::JitBuilder;
+    use crate::jit_memory::DualMappedMemory;
+    use std::cell::RefCell;
+
+    thread_local! {
+        static SEEN: RefCell<Vec<Frame>> = const { RefCell::new(Vec::new()) };
+    }
+
+    extern "C" fn record_backtrace() -> u64 {
+        SEEN.with(|seen| *seen.borrow_mut() = backtrace());
+        7
+    }
+
+    #[test]
+    fn test_table_layout() {
+        let table = eh_frame(&[(0x1000, 0x40), (0x1040, 0x20)]);
+        assert_eq!(table.len() % 8, 4);
+        assert_eq!(&table[table.len() - 4..], [0; 4]);
+        assert_eq!(fde_starts(&table), [0x1000, 0x1040]);
+        // The CIE comes first, with id 0 and version 1
+        assert_eq!(&table[4..9], [0, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn test_backtraces_walk_through_jit_frames() {
+        let mut builder = JitBuilder::new();
+        builder.prologue(0);
+        builder.mov_reg_imm64(0, record_backtrace as *const () as usize as u64);
+        builder.call_reg(0);
+        builder.epilogue();
+        let code = builder.finalize();
+        assert_eq!(code[..PROLOGUE.len()], PROLOGUE);
+        let memory = DualMappedMemory::with_code(&code).unwrap();
+        let probe: extern "C" fn() -> u64 = unsafe { std::mem::transmute(memory.rx_ptr) };
+        let offsets = BTreeMap::from([("probe".to_string(), 0)]);
+
+        let start = memory.rx_ptr as usize;
+        let walk = || {
+            assert_eq!(probe(), 7);
+            let frames = SEEN.with(|seen| seen.take());
+            let jit = frames
+                .iter()
+                .position(|f| (start..start + code.len()).contains(&f.ip))
+                .expect("the JIT frame is on the stack");
+            (frames, jit)
+        };
+
+        let info = UnwindInfo::register(&code, memory.rx_ptr, &offsets).unwrap();
+        let (frames, jit) = walk();
+        assert!(frames.len() > jit + 1, "stopped at the JIT frame");
+        let (name, offset) = frames[jit].jit.clone().unwrap();
+        assert_eq!(name, "probe");
+        assert!(offset > PROLOGUE.len() && offset <= code.len());
+
+        // Without the table the unwinder gives up at the JIT frame
+        drop(info);
+        let (frames, jit) = walk();
+        assert_eq!((frames.len(), &frames[jit].jit), (jit + 1, &None));
+    }
+
+    #[test]
+    fn test_compiled_programs_register_every_function() {
+        let prog = crate::parser::Parser::new()
+            .parse("fn main() {\nx = helper()\nreturn x\n}\nfn helper() {\nreturn 1\n}")
+            .unwrap();
+        let options = crate::compiler::CompileOptions::new(2).debug(true);
+        let compiled = crate::compiled_program::CompiledProgram::compile(&prog, &options).unwrap();
+        let helper: extern "C" fn() -> i64 = compiled.get("helper").unwrap();
+        let entry = helper as usize;
+        let registered = |name: &str| {
+            let registry = JIT_FUNCTIONS.lock().unwrap();
+            registry.iter().any(|(start, _, n)| n == name && *start == entry)
+        };
+        assert!(registered("helper"));
+        drop(compiled);
+        assert!(!registered("helper"));
+    }
+}
@@ -1,19 +1,26 @@
 //! Validator for Evolved Code
 //!
 //! Ensures that mutated/evolved code produces correct results
-//! and doesn't crash or hang.
+//! and doesn't crash or hang, then measures it in the nanosecond sandbox:
+//! wall-clock timing of a few runs is noisier than most improvements, so
+//! fitness is rdtsc cycles with a confidence interval.
 
-use crate::compiler::Compiler;
+use crate::compiled_program::CompiledProgram;
+use crate::compiler::CompileOptions;
 use crate::ir::Program;
-use crate::jit_memory::DualMappedMemory;
 use crate::mutator::Genome;
+use crate::sandbox::{NanosecondSandbox, SandboxConfig};
 use std::time::{Duration, Instant};
 
 /// Result of validation
 #[derive(Debug, Clone, PartialEq)]
 pub enum ValidationResult {
     /// Code is valid and produces correct output
-    Valid { output: i64, execution_time_ns: u64 },
+    Valid {
+        output: i64,
+        execution_time_ns: u64,
+        fitness: Fitness,
+    },
     /// Code produces wrong output
     WrongOutput { expected: i64, actual: i64 },
     /// Code took too long (timeout)
@@ -22,6 +29,8 @@ pub enum ValidationResult {
     CompileError(String),
     /// Code crashed during execution
     Crashed,
+    /// Code returned different values for the same input while measured
+    Unstable { input: i64, first: i64, then: i64 },
 }
 
 impl ValidationResult {
@@ -30,6 +39,21 @@ impl ValidationResult {
     }
 }
 
+/// Cycles per call, averaged over the test cases (lower is better)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Fitness {
+    pub cycles: f64,
+    /// Half-width of the 95% confidence interval, relative to `cycles`
+    pub ci95: f64,
+}
+
+impl Fitness {
+    /// Faster than `other` by more than both measurements' uncertainty
+    pub fn beats(&self, other: &Fitness) -> bool {
+        self.cycles * (1.0 + self.ci95) < other.cycles * (1.0 - other.ci95)
+    }
+}
+
 /// Test case for validation
 #[derive(Debug, Clone)]
 pub struct TestCase {
@@ -50,18 +74,21 @@ impl TestCase {
 pub struct ValidatorConfig {
     /// Maximum execution time per test case
     pub timeout: Duration,
-    /// Number of warmup runs before timing
-    pub warmup_runs: u32,
-    /// Number of timing runs for averaging
-    pub timing_runs: u32,
+    /// How each test case is timed once its output checks out
+    pub sandbox: SandboxConfig,
 }
 
 impl Default for ValidatorConfig {
     fn default() -> Self {
         Self {
             timeout: Duration::from_millis(100),
-            warmup_runs: 2,
-            timing_runs: 5,
+            sandbox: SandboxConfig {
+                warmup_iterations: 50,
+                measurement_iterations: 200,
+                target_precision: Some(0.02),
+                max_measurement_iterations: 20_000,
+                ..SandboxConfig::default()
+            },
         }
     }
 }
@@ -69,17 +96,20 @@ impl Default for ValidatorConfig {
 /// Validator for evolved genomes
 pub struct Validator {
     config: ValidatorConfig,
+    sandbox: NanosecondSandbox,
 }
 
 impl Validator {
     pub fn new(config: ValidatorConfig) -> Self {
-        Self { config }
+        let sandbox = NanosecondSandbox::new(config.sandbox.clone());
+        Self { config, sandbox }
     }
 
     /// Validate a genome against test cases
     pub fn validate(&self, genome: &Genome, test_cases: &[TestCase]) -> ValidationResult {
         // Convert genome to function
         let func = genome.to_function();
+        let name = func.name.clone();
 
         // Create program with single function
         let mut program = Program::new();
@@ -88,10 +118,10 @@ impl Validator {
         // Compile to machine code - wrapped in catch_unwind because
         // mutated genomes might cause panics in the assembler (e.g., missing labels)
         let compile_result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
-            Compiler::compile_program(&program, 0)
+            CompiledProgram::compile(&program, &CompileOptions::new(0))
         }));
 
-        let (code, _) = match compile_result {
+        let compiled = match compile_result {
             Ok(Ok(result)) => result,
             Ok(Err(e)) => return ValidationResult::CompileError(e),
             Err(_) => {
@@ -101,103 +131,94 @@ impl Validator {
             }
         };
 
-        // Allocate executable memory
-        let memory = match DualMappedMemory::new(code.len().max(4096)) {
-            Ok(m) => m,
-            Err(e) => {
-                return ValidationResult::CompileError(format!("Memory allocation failed: {}", e))
+        // u64 as the sandbox calls it; outputs are compared as i64
+        let func_ptr: extern "C" fn(u64) -> u64 = match compiled.get(&name) {
+            Some(f) => f,
+            None => {
+                return ValidationResult::CompileError(format!(
+                    "{}() must take at most one parameter",
+                    name
+                ))
             }
         };
 
-        // Copy code to memory
-        unsafe {
-            std::ptr::copy_nonoverlapping(code.as_ptr(), memory.rw_ptr, code.len());
-        }
-        memory.flush_icache();
-
-        // Create function pointer
-        let func_ptr: extern "C" fn(i64) -> i64 = unsafe { std::mem::transmute(memory.rx_ptr) };
-
-        // Run test cases
-        let mut total_time_ns: u64 = 0;
-        let mut test_count = 0;
-
+        // Check every output before spending time measuring
         for test_case in test_cases {
-            // Execute with timeout protection
             match self.execute_with_timeout(func_ptr, test_case.input) {
-                ExecutionResult::Success(output, time_ns) => {
+                ExecutionResult::Success(output) => {
                     if output != test_case.expected_output {
                         return ValidationResult::WrongOutput {
                             expected: test_case.expected_output,
                             actual: output,
                         };
                     }
-                    total_time_ns += time_ns;
-                    test_count += 1;
                 }
                 ExecutionResult::Timeout => return ValidationResult::Timeout,
                 ExecutionResult::Crashed => return ValidationResult::Crashed,
             }
         }
 
-        let avg_time_ns = if test_count > 0 {
-            total_time_ns / test_count as u64
-        } else {
-            0
+        let (fitness, execution_time_ns) = match self.measure(func_ptr, test_cases) {
+            Ok(measured) => measured,
+            Err(unstable) => return unstable,
         };
-
         ValidationResult::Valid {
             output: test_cases.last().map(|tc| tc.expected_output).unwrap_or(0),
-            execution_time_ns: avg_time_ns,
+            execution_time_ns,
+            fitness,
         }
     }
 
-    /// Execute function with timeout protection
-    fn execute_with_timeout(&self, func: extern "C" fn(i64) -> i64, input: i64) -> ExecutionResult {
-        // Warmup runs (no timing)
-        for _ in 0..self.config.warmup_runs {
-            // TODO: Add actual timeout using signals/threads for production
-            // For now, just execute directly (assumes code won't infinite loop)
-            let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| func(input)));
+    /// Time an already-checked function on every test case: the averaged
+    /// fitness and nanoseconds per call, or `Unstable` if any call returned
+    /// something else than the others for its input
+    pub fn measure(
+        &self,
+        func: extern "C" fn(u64) -> u64,
+        test_cases: &[TestCase],
+    ) -> Result<(Fitness, u64), ValidationResult> {
+        let (mut cycles, mut nanos, mut variance) = (0.0, 0u64, 0.0);
+        for test_case in test_cases {
+            let result = self.sandbox.benchmark_fn(func, test_case.input as u64);
+            if let Some(then) = result.stability.first_mismatch {
+                return Err(ValidationResult::Unstable {
+                    input: test_case.input,
+                    first: result.stability.output as i64,
+                    then: then as i64,
+                });
+            }
+            let case_cycles = result.cycles_per_op as f64;
+            // Independent means: absolute half-widths add in quadrature
+            variance += (result.precision.unwrap_or(0.0) * case_cycles).powi(2);
+            cycles += case_cycles;
+            nanos += result.nanoseconds_per_op;
         }
 
-        // Timed runs
-        let mut total_ns: u64 = 0;
-        let mut last_output: i64 = 0;
-
-        for _ in 0..self.config.timing_runs {
-            let start = Instant::now();
-
-            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| func(input)));
-
-            let elapsed = start.elapsed();
-
-            match result {
-                Ok(output) => {
-                    last_output = output;
-                    total_ns += elapsed.as_nanos() as u64;
-                }
-                Err(_) => {
-                    return ExecutionResult::Crashed;
-                }
-            }
+        let count = test_cases.len().max(1);
+        let fitness = Fitness {
+            cycles: cycles / count as f64,
+            ci95: if cycles > 0.0 { variance.sqrt() / cycles } else { 0.0 },
+        };
+        Ok((fitness, nanos / count as u64))
+    }
 
-            // Check timeout
-            if elapsed > self.config.timeout {
-                return ExecutionResult::Timeout;
-            }
+    /// Run once to check the output, with crash and timeout protection
+    fn execute_with_timeout(&self, func: extern "C" fn(u64) -> u64, input: i64) -> ExecutionResult {
+        // TODO: Add actual timeout using signals/threads for production
+        // For now, just execute directly (the fuel check bounds loops)
+        let start = Instant::now();
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| func(input as u64) as i64));
+        match result {
+            Err(_) => ExecutionResult::Crashed,
+            Ok(_) if start.elapsed() > self.config.timeout => ExecutionResult::Timeout,
+            Ok(output) => ExecutionResult::Success(output),
         }
-
-        let avg_ns = total_ns / self.config.timing_runs as u64;
-        ExecutionResult::Success(last_output, avg_ns)
     }
 
-    /// Validate and return fitness score (lower is better)
-    pub fn fitness(&self, genome: &Genome, test_cases: &[TestCase]) -> Option<f64> {
+    /// Validate and return fitness (lower cycles are better)
+    pub fn fitness(&self, genome: &Genome, test_cases: &[TestCase]) -> Option<Fitness> {
         match self.validate(genome, test_cases) {
-            ValidationResult::Valid {
-                execution_time_ns, ..
-            } => Some(execution_time_ns as f64),
+            ValidationResult::Valid { fitness, .. } => Some(fitness),
             _ => None, // Invalid genomes have no fitness
         }
     }
@@ -205,7 +226,7 @@ impl Validator {
 
 /// Result of a single execution attempt
 enum ExecutionResult {
-    Success(i64, u64), // (output, time_ns)
+    Success(i64),
     Timeout,
     Crashed,
 }
@@ -256,6 +277,10 @@ mod tests {
         let valid = ValidationResult::Valid {
             output: 42,
             execution_time_ns: 1000,
+            fitness: Fitness {
+                cycles: 3000.0,
+                ci95: 0.01,
+            },
         };
         assert!(valid.is_valid());
 
@@ -266,6 +291,61 @@ mod tests {
         assert!(!wrong.is_valid());
     }
 
+    #[test]
+    fn test_validate_simple_genome() {
+        let validator = Validator::default();
+        let genome = create_simple_genome();
+        let result = validator.validate(&genome, &[TestCase::new(10, 11), TestCase::new(-1, 0)]);
+        assert!(result.is_valid(), "unexpected result: {:?}", result);
+        let fitness = validator.fitness(&genome, &[TestCase::new(10, 11)]).unwrap();
+        assert!(fitness.cycles > 0.0 && fitness.ci95.is_finite(), "{:?}", fitness);
+    }
+
+    #[test]
+    fn test_unstable_output_is_rejected() {
+        use std::sync::atomic::{AtomicU64, Ordering};
+        static CALLS: AtomicU64 = AtomicU64::new(0);
+        // Correct the first time it runs, then drifts
+        extern "C" fn drifting(n: u64) -> u64 {
+            n + CALLS.fetch_add(1, Ordering::Relaxed).min(1)
+        }
+
+        let result = Validator::default().measure(drifting, &[TestCase::new(3, 3)]);
+        assert_eq!(
+            result.unwrap_err(),
+            ValidationResult::Unstable {
+                input: 3,
+                first: 3,
+                then: 4
+            }
+        );
+    }
+
+    #[test]
+    fn test_fitness_comparison_respects_noise() {
+        let fast = Fitness {
+            cycles: 100.0,
+            ci95: 0.02,
+        };
+        let slow = Fitness {
+            cycles: 120.0,
+            ci95: 0.02,
+        };
+        assert!(fast.beats(&slow));
+        assert!(!slow.beats(&fast));
+
+        // 5% apart, but each measurement is only good to 5%: a tie
+        let a = Fitness {
+            cycles: 100.0,
+            ci95: 0.05,
+        };
+        let b = Fitness {
+            cycles: 105.0,
+            ci95: 0.05,
+        };
+        assert!(!a.beats(&b) && !b.beats(&a));
+    }
+
     #[test]
     fn test_test_case() {
         let tc = TestCase::new(10, 11);
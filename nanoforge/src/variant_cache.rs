@@ -0,0 +1,227 @@
+//! Least-Recently-Used Variant Cache
+//!
+//! A learner keeps every variant it may still pick but runs one at a time.
+//! `VariantCache` holds them by name, stamped with when each was last used,
+//! and registers itself as a `jit_memory::Reclaimer`: when mapping more code
+//! would go over the JIT memory cap (`jit_memory::set_limit`) it unmaps the
+//! least recently used variants, never the active one or one a caller still
+//! holds, and keeps their configs so `get` can recompile them on demand.
+
+use crate::ir::Program;
+use crate::jit_memory::{self, Reclaimer};
+use crate::variant_generator::{CompiledVariant, VariantConfig, VariantGenerator};
+use std::sync::{Arc, Mutex, Weak};
+
+struct Entry {
+    config: VariantConfig,
+    /// None once reclaimed
+    variant: Option<Arc<CompiledVariant>>,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct State {
+    entries: Vec<Entry>,
+    active: Option<String>,
+    /// Bumped on every use
+    clock: u64,
+    recompiled: usize,
+}
+
+struct Inner {
+    program: Program,
+    generator: VariantGenerator,
+    state: Mutex<State>,
+}
+
+/// Compiled variants of one program, reclaimable under the JIT memory cap
+pub struct VariantCache {
+    inner: Arc<Inner>,
+}
+
+impl VariantCache {
+    /// An empty cache recompiling `program`'s variants with `generator`
+    pub fn new(program: &Program, generator: VariantGenerator) -> Self {
+        let inner = Arc::new(Inner {
+            program: program.clone(),
+            generator,
+            state: Mutex::new(State::default()),
+        });
+        let weak: Weak<Inner> = Arc::downgrade(&inner);
+        jit_memory::register_reclaimer(weak);
+        Self { inner }
+    }
+
+    /// Add (or replace) the variant named after its config
+    pub fn insert(&self, variant: CompiledVariant) {
+        let mut state = self.inner.state.lock().unwrap();
+        state.clock += 1;
+        let entry = Entry {
+            config: variant.config.clone(),
+            variant: Some(Arc::new(variant)),
+            last_used: state.clock,
+        };
+        match state.entries.iter_mut().find(|e| e.config.name == entry.config.name) {
+            Some(old) => *old = entry,
+            None => state.entries.push(entry),
+        }
+    }
+
+    /// The variant named `name`, recompiled if it was reclaimed
+    pub fn get(&self, name: &str) -> Result<Arc<CompiledVariant>, String> {
+        let config = {
+            let mut state = self.inner.state.lock().unwrap();
+            state.clock += 1;
+            let clock = state.clock;
+            let entry = state
+                .entries
+                .iter_mut()
+                .find(|e| e.config.name == name)
+                .ok_or_else(|| format!("No variant named {}", name))?;
+            entry.last_used = clock;
+            if let Some(variant) = &entry.variant {
+                return Ok(variant.clone());
+            }
+            entry.config.clone()
+        };
+
+        // Compiled unlocked: mapping it may reclaim from this cache
+        let variant = Arc::new(self.inner.generator.compile_variant(&self.inner.program, &config)?);
+        let mut state = self.inner.state.lock().unwrap();
+        state.recompiled += 1;
+        let entry = state
+            .entries
+            .iter_mut()
+            .find(|e| e.config.name == name)
+            .ok_or_else(|| format!("No variant named {}", name))?;
+        Ok(entry.variant.get_or_insert(variant).clone())
+    }
+
+    /// Never reclaim `name` (the one being run), until another is activated
+    pub fn activate(&self, name: &str) {
+        self.inner.state.lock().unwrap().active = Some(name.to_string());
+    }
+
+    pub fn active(&self) -> Option<String> {
+        self.inner.state.lock().unwrap().active.clone()
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        let state = self.inner.state.lock().unwrap();
+        state.entries.iter().map(|e| e.config.name.clone()).collect()
+    }
+
+    /// Names of the variants currently compiled
+    pub fn resident(&self) -> Vec<String> {
+        let state = self.inner.state.lock().unwrap();
+        let resident = state.entries.iter().filter(|e| e.variant.is_some());
+        resident.map(|e| e.config.name.clone()).collect()
+    }
+
+    /// Reclaimed variants `get` had to compile again
+    pub fn recompiled(&self) -> usize {
+        self.inner.state.lock().unwrap().recompiled
+    }
+
+    /// `Reclaimer::reclaim` on demand, without a cap
+    pub fn reclaim(&self, bytes: usize) -> usize {
+        self.inner.reclaim(bytes)
+    }
+}
+
+impl Reclaimer for Inner {
+    fn reclaim(&self, bytes: usize) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let active = state.active.clone();
+        let mut idle: Vec<&mut Entry> = state
+            .entries
+            .iter_mut()
+            .filter(|e| Some(&e.config.name) != active.as_ref())
+            .filter(|e| e.variant.as_ref().is_some_and(|v| Arc::strong_count(v) == 1))
+            .collect();
+        idle.sort_by_key(|e| e.last_used);
+
+        let mut freed = 0;
+        for entry in idle {
+            if freed >= bytes {
+                break;
+            }
+            if let Some(variant) = entry.variant.take() {
+                tracing::debug!("Reclaiming variant {}", entry.config.name);
+                freed += variant.program.mapped_bytes();
+            }
+        }
+        freed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+    use crate::variant_generator::IsaExtension;
+
+    fn cache() -> (VariantCache, Vec<String>) {
+        let src = "fn main(n) {
+            i = 0
+            s = 0
+            label loop
+            if i >= n goto done
+            s = s + i
+            i = i + 1
+            goto loop
+            label done
+            return s
+        }";
+        let program = Parser::new().parse(src).unwrap();
+        let generator = VariantGenerator::new();
+        // Scalar variants compile on any CPU
+        let variants: Vec<CompiledVariant> = [1, 2, 4]
+            .into_iter()
+            .map(|unroll| VariantConfig::new(IsaExtension::Scalar, unroll, 2))
+            .map(|config| generator.compile_variant(&program, &config).unwrap())
+            .collect();
+        let cache = VariantCache::new(&program, generator);
+        let names: Vec<String> = variants.iter().map(|v| v.config.name.clone()).collect();
+        for variant in variants {
+            cache.insert(variant);
+        }
+        assert_eq!(cache.names(), names);
+        assert_eq!(names.len(), 3);
+        (cache, names)
+    }
+
+    #[test]
+    fn test_reclaims_least_recently_used_first() {
+        let (cache, names) = cache();
+        cache.activate(&names[0]);
+        cache.get(&names[1]).unwrap();
+
+        // The oldest idle variant goes first; the active one never does
+        let freed = cache.reclaim(1);
+        assert!(freed >= 4096, "{}", freed);
+        let resident = cache.resident();
+        assert!(!resident.contains(&names[2]), "{:?}", resident);
+        assert!(resident.contains(&names[1]));
+
+        cache.reclaim(usize::MAX);
+        assert_eq!(cache.resident(), [names[0].clone()]);
+        assert_eq!(cache.reclaim(usize::MAX), 0);
+    }
+
+    #[test]
+    fn test_reclaimed_variants_are_recompiled_on_use() {
+        let (cache, names) = cache();
+        let held = cache.get(&names[0]).unwrap();
+        let expected = held.execute(10);
+        assert!(cache.reclaim(usize::MAX) > 0);
+        // A variant someone still runs is not reclaimed
+        assert!(cache.resident().contains(&names[0]));
+
+        let last = names.last().unwrap();
+        let before = cache.recompiled();
+        assert_eq!(cache.get(last).unwrap().execute(10), expected);
+        assert_eq!(cache.recompiled(), before + 1);
+        assert!(cache.get("missing").is_err());
+    }
+}
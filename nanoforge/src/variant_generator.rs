@@ -3,15 +3,25 @@
 //! Generates multiple optimized variants of the same function using different
 //! ISA extensions and optimization strategies. Each variant is benchmarked
 //! and the AI optimizer selects the best one for the current workload.
-
-use crate::compiler::Compiler;
-use crate::cpu_features::CpuFeatures;
-use crate::ir::Program;
-use crate::jit_memory::DualMappedMemory;
+//!
+//! Variants apply one config to the whole program by default;
+//! `compile_per_function` gives each function its own, which is how
+//! `soae::run_per_function` tunes hot functions one at a time.
+
+use crate::compiled_program::CompiledProgram;
+use crate::compiler::CompileOptions;
+use crate::cpu_features::{CpuFeatures, VectorWidth};
+use crate::ir::{Function, Program};
+use crate::mem_trace::{MemReport, MemSampler};
 use crate::optimizer::Optimizer;
+use crate::parallel::ExecutionPool;
+use crate::pipeliner;
+use crate::uarch_db::{UarchDefaults, GENERIC};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
 /// ISA extension level for code generation
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum IsaExtension {
     Scalar,
     Avx2,
@@ -30,12 +40,31 @@ impl std::fmt::Display for IsaExtension {
     }
 }
 
+impl IsaExtension {
+    /// Width the vectorizer and backend use for this ISA. Scalar variants
+    /// don't vectorize; vector loops of AMX variants run on its AVX-512 unit
+    pub fn vector_width(&self) -> VectorWidth {
+        match self {
+            IsaExtension::Scalar => VectorWidth::Lanes2,
+            IsaExtension::Avx2 => VectorWidth::Lanes4,
+            IsaExtension::Avx512 | IsaExtension::Amx => VectorWidth::Lanes8,
+        }
+    }
+}
+
+/// Prefetch distances in bytes the generator offers (0: no prefetching)
+pub const PREFETCH_DISTANCES: [i32; 4] = [0, 64, 128, 256];
+
 /// Configuration for a specific variant
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct VariantConfig {
     pub isa: IsaExtension,
     pub unroll_factor: u8,
     pub optimization_level: u8,
+    /// Software-pipeline counted loops (only applied at -O3)
+    pub pipelined: bool,
+    /// Bytes to prefetch ahead of vectorized loads (0: none; only applied at -O3)
+    pub prefetch_distance: i32,
     pub name: String,
 }
 
@@ -46,19 +75,59 @@ impl VariantConfig {
             isa,
             unroll_factor,
             optimization_level: opt_level,
+            pipelined: false,
+            prefetch_distance: 0,
             name,
         }
     }
+
+    /// The same variant with software pipelining
+    pub fn pipelined(mut self) -> Self {
+        self.pipelined = true;
+        self.name.push_str("+SWP");
+        self
+    }
+
+    /// The same variant prefetching `bytes` ahead of its vector loads
+    pub fn prefetch(mut self, bytes: i32) -> Self {
+        self.prefetch_distance = bytes;
+        if bytes > 0 {
+            self.name.push_str(&format!("+PF{}", bytes));
+        }
+        self
+    }
+
+    /// Optimization level the variant compiles at: vector ISAs force 3
+    /// (vectorization), scalar ones stop at 2
+    pub fn effective_opt_level(&self) -> u8 {
+        match self.isa {
+            IsaExtension::Scalar => self.optimization_level.min(2),
+            IsaExtension::Avx2 | IsaExtension::Avx512 | IsaExtension::Amx => 3,
+        }
+    }
+
+    /// Whether functions compiled under `self` and `other` can share a
+    /// program: the vector width is program-wide, and scalar variants don't
+    /// vectorize
+    pub fn compatible(&self, other: &VariantConfig) -> bool {
+        self.isa == IsaExtension::Scalar
+            || other.isa == IsaExtension::Scalar
+            || self.isa.vector_width() == other.isa.vector_width()
+    }
 }
 
 /// A compiled variant ready for execution and benchmarking
 #[derive(Debug)]
 pub struct CompiledVariant {
     pub config: VariantConfig,
-    pub memory: DualMappedMemory,
+    pub program: CompiledProgram,
     pub code_size: usize,
-    pub entry_offset: usize,
+    /// `main`, valid as long as `program` is
     pub func_ptr: extern "C" fn(u64) -> u64,
+    /// Optimization level actually applied (AVX variants force 3)
+    pub effective_opt_level: u8,
+    /// IR after optimization, as compiled
+    pub optimized: Program,
 }
 
 impl CompiledVariant {
@@ -66,43 +135,239 @@ impl CompiledVariant {
     pub fn execute(&self, input: u64) -> u64 {
         (self.func_ptr)(input)
     }
+
+    /// `execute` on each of `inputs`, in one host call (see
+    /// `NanoFn::call_batch`)
+    pub fn execute_batch(&self, inputs: &[i64], outputs: &mut [i64]) -> Result<(), String> {
+        let main = self.program.get_fn("main").ok_or("No main() to batch")?;
+        main.call_batch(inputs, outputs)
+    }
+
+    /// `execute` with `sampler` recording the memory accesses of the call
+    pub fn trace_memory(&self, input: u64, sampler: &MemSampler) -> (u64, MemReport) {
+        sampler.trace(&self.program, || self.execute(input))
+    }
+
+    /// `execute_batch` with the inputs split across the workers of `pool`
+    pub fn execute_parallel(
+        &self,
+        pool: &ExecutionPool,
+        inputs: &[i64],
+        outputs: &mut [i64],
+    ) -> Result<(), String> {
+        let main = self.program.get_fn("main").ok_or("No main() to batch")?;
+        pool.map(&main, inputs, outputs)
+    }
+
+    /// Explain this variant: its config and code size, and with a `base`
+    /// to compare against, the config deltas and a diff of the optimized IR
+    pub fn describe(&self, base: Option<&CompiledVariant>) -> String {
+        let mut out = format!(
+            "{}: isa={} unroll={} opt={} ({} bytes)",
+            self.config.name,
+            self.config.isa,
+            self.config.unroll_factor,
+            self.effective_opt_level,
+            self.code_size
+        );
+        let Some(base) = base else {
+            return out;
+        };
+
+        out.push_str(&format!("\n  vs {}:", base.config.name));
+        let mut deltas = Vec::new();
+        if self.config.isa != base.config.isa {
+            deltas.push(format!("isa {} -> {}", base.config.isa, self.config.isa));
+        }
+        if self.config.unroll_factor != base.config.unroll_factor {
+            deltas.push(format!(
+                "unroll {} -> {}",
+                base.config.unroll_factor, self.config.unroll_factor
+            ));
+        }
+        if self.config.pipelined != base.config.pipelined {
+            let state = |on| if on { "on" } else { "off" };
+            deltas.push(format!(
+                "pipelining {} -> {}",
+                state(base.config.pipelined),
+                state(self.config.pipelined)
+            ));
+        }
+        if self.config.prefetch_distance != base.config.prefetch_distance {
+            deltas.push(format!(
+                "prefetch {} -> {} bytes",
+                base.config.prefetch_distance, self.config.prefetch_distance
+            ));
+        }
+        if self.effective_opt_level != base.effective_opt_level {
+            deltas.push(format!(
+                "opt {} -> {}",
+                base.effective_opt_level, self.effective_opt_level
+            ));
+        }
+        if self.code_size != base.code_size {
+            deltas.push(format!(
+                "code {:+} bytes",
+                self.code_size as i64 - base.code_size as i64
+            ));
+        }
+        if deltas.is_empty() {
+            out.push_str(" same config and size");
+        } else {
+            out.push(' ');
+            out.push_str(&deltas.join(", "));
+        }
+
+        let diff = ir_diff(&base.optimized, &self.optimized);
+        if diff.is_empty() {
+            out.push_str("\n  optimized IR is identical");
+        } else {
+            for line in diff {
+                out.push_str("\n    ");
+                out.push_str(&line);
+            }
+        }
+        out
+    }
+}
+
+/// Whether `config` is scalar or could vectorize a loop of `functions`:
+/// one not under `#pragma novectorize`, whose constant trip count (if all
+/// of them have one) fills at least a vector
+fn may_vectorize(config: &VariantConfig, functions: &[&Function]) -> bool {
+    if config.isa == IsaExtension::Scalar {
+        return true;
+    }
+    let vectorizable: Vec<&Function> =
+        functions.iter().copied().filter(|f| !f.hints.no_vectorize).collect();
+    // None unless every loop has a constant trip count
+    let longest = vectorizable
+        .iter()
+        .flat_map(|f| f.loops.values())
+        .map(|l| l.induction.as_ref().and_then(|i| i.trip_count))
+        .try_fold(None, |longest: Option<u64>, trips| Some(longest.max(Some(trips?))))
+        .flatten();
+    let lanes = config.isa.vector_width().lanes() as u64;
+    !vectorizable.is_empty() && longest.is_none_or(|n| lanes <= n)
+}
+
+/// Line diff of two optimized programs, function by function ("-" old, "+" new)
+pub fn ir_diff(old: &Program, new: &Program) -> Vec<String> {
+    let mut out = Vec::new();
+    let render = |prog: &Program, name: &str| -> Vec<String> {
+        prog.functions
+            .iter()
+            .find(|f| f.name == name)
+            .map(|f| f.instructions.iter().map(|i| i.to_string()).collect())
+            .unwrap_or_default()
+    };
+
+    let mut names: Vec<&str> = old.functions.iter().map(|f| f.name.as_str()).collect();
+    for f in &new.functions {
+        if !names.contains(&f.name.as_str()) {
+            names.push(&f.name);
+        }
+    }
+
+    for name in names {
+        let (a, b) = (render(old, name), render(new, name));
+        if a == b {
+            continue;
+        }
+        out.push(format!("@@ fn {} ({} -> {} instrs)", name, a.len(), b.len()));
+        out.extend(line_diff(&a, &b));
+    }
+    out
+}
+
+/// Minimal line diff via longest common subsequence; unchanged lines are dropped
+fn line_diff(a: &[String], b: &[String]) -> Vec<String> {
+    let (n, m) = (a.len(), b.len());
+    let mut lcs = vec![vec![0u32; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i][j] = if a[i] == b[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut out = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && a[i] == b[j] {
+            i += 1;
+            j += 1;
+        } else if j < m && (i == n || lcs[i][j + 1] >= lcs[i + 1][j]) {
+            out.push(format!("+ {}", b[j]));
+            j += 1;
+        } else {
+            out.push(format!("- {}", a[i]));
+            i += 1;
+        }
+    }
+    out
 }
 
 /// Generates multiple code variants for a function
 pub struct VariantGenerator {
     cpu_features: CpuFeatures,
+    /// Largest machine code (bytes) a variant may compile to
+    max_code_size: Option<usize>,
+    uarch: UarchDefaults,
 }
 
 impl VariantGenerator {
     pub fn new() -> Self {
-        Self {
-            cpu_features: CpuFeatures::detect(),
-        }
+        Self::with_features(CpuFeatures::detect()).uarch(*UarchDefaults::cached())
     }
 
+    /// Variants for a CPU with `features` (and `uarch_db::GENERIC` defaults)
     pub fn with_features(features: CpuFeatures) -> Self {
         Self {
             cpu_features: features,
+            max_code_size: None,
+            uarch: GENERIC,
         }
     }
 
+    /// Also offer the variant `defaults` suggest (see `seed_config`)
+    pub fn uarch(mut self, defaults: UarchDefaults) -> Self {
+        self.uarch = defaults;
+        self
+    }
+
+    /// Drop variants whose code is larger than `bytes`. Heavy unrolling can
+    /// win a microbenchmark yet thrash the instruction cache of a real program.
+    pub fn code_size_budget(mut self, bytes: usize) -> Self {
+        self.max_code_size = Some(bytes);
+        self
+    }
+
     /// Generate all viable variant configurations for the current CPU
     pub fn get_variant_configs(&self) -> Vec<VariantConfig> {
-        let mut configs = vec![];
-
         // Always include scalar baseline
-        configs.push(VariantConfig::new(IsaExtension::Scalar, 1, 1));
-        configs.push(VariantConfig::new(IsaExtension::Scalar, 2, 2));
-        configs.push(VariantConfig::new(IsaExtension::Scalar, 4, 2));
-        // High Register Pressure Stress Test
-        configs.push(VariantConfig::new(IsaExtension::Scalar, 8, 2));
-        configs.push(VariantConfig::new(IsaExtension::Scalar, 16, 2));
+        let mut configs = vec![
+            VariantConfig::new(IsaExtension::Scalar, 1, 1),
+            VariantConfig::new(IsaExtension::Scalar, 2, 2),
+            VariantConfig::new(IsaExtension::Scalar, 4, 2),
+            // High Register Pressure Stress Test
+            VariantConfig::new(IsaExtension::Scalar, 8, 2),
+            VariantConfig::new(IsaExtension::Scalar, 16, 2),
+        ];
 
         // AVX2 variants (if supported)
         if self.cpu_features.has_avx2() {
             configs.push(VariantConfig::new(IsaExtension::Avx2, 2, 3));
             configs.push(VariantConfig::new(IsaExtension::Avx2, 4, 3));
             configs.push(VariantConfig::new(IsaExtension::Avx2, 8, 3));
+            configs.push(VariantConfig::new(IsaExtension::Avx2, 4, 3).pipelined());
+            // How far ahead to prefetch depends on the memory system: measure
+            for &bytes in &PREFETCH_DISTANCES[1..] {
+                configs.push(VariantConfig::new(IsaExtension::Avx2, 4, 3).prefetch(bytes));
+            }
         }
 
         // AVX-512 variants (if supported)
@@ -117,17 +382,100 @@ impl VariantGenerator {
             configs.push(VariantConfig::new(IsaExtension::Amx, 1, 3));
         }
 
+        if let Some(seed) = self.seed_config() {
+            if configs.iter().all(|c| c.name != seed.name) {
+                configs.push(seed);
+            }
+        }
         configs
     }
 
+    /// The variant the micro-architecture database suggests for this CPU:
+    /// the widest supported ISA up to its preferred vector width, with its
+    /// unroll factor and prefetch distance (None without a preference)
+    pub fn seed_config(&self) -> Option<VariantConfig> {
+        let lanes = self.uarch.vector_width?.lanes();
+        let isa = if lanes >= 8 && self.cpu_features.has_avx512() {
+            IsaExtension::Avx512
+        } else if lanes >= 4 && self.cpu_features.has_avx2() {
+            IsaExtension::Avx2
+        } else {
+            IsaExtension::Scalar
+        };
+        Some(match isa {
+            IsaExtension::Scalar => VariantConfig::new(isa, self.uarch.unroll, 2),
+            _ => VariantConfig::new(isa, self.uarch.unroll, 3).prefetch(self.uarch.prefetch_distance),
+        })
+    }
+
     /// Generate all viable variants for a program
     pub fn generate_variants(&self, program: &Program) -> Result<Vec<CompiledVariant>, String> {
-        let configs = self.get_variant_configs();
-        let mut variants = Vec::with_capacity(configs.len());
+        let configs = self.configs_for(program);
+        self.within_budget(configs.into_iter().map(|config| {
+            let variant = self.compile_variant(program, &config);
+            (config, variant)
+        }))
+    }
 
-        for config in configs {
-            match self.compile_variant(program, &config) {
-                Ok(variant) => variants.push(variant),
+    /// Variants of `function` alone: each config `function` can take, with
+    /// the functions in `fixed` under theirs and the rest under `default`.
+    /// Every variant is named after `function`'s config.
+    pub fn generate_function_variants(
+        &self,
+        program: &Program,
+        function: &str,
+        fixed: &BTreeMap<String, VariantConfig>,
+        default: &VariantConfig,
+    ) -> Result<Vec<CompiledVariant>, String> {
+        let func = program
+            .functions
+            .iter()
+            .find(|f| f.name == function)
+            .ok_or_else(|| format!("No function named {}", function))?;
+        let configs: Vec<VariantConfig> = self
+            .get_variant_configs()
+            .into_iter()
+            .filter(|c| may_vectorize(c, &[func]))
+            .filter(|c| fixed.iter().all(|(name, other)| name == function || c.compatible(other)))
+            .collect();
+        self.within_budget(configs.into_iter().map(|config| {
+            let mut configs = fixed.clone();
+            configs.insert(function.to_string(), config.clone());
+            let variant = self.compile_per_function(program, &configs, default).map(|mut v| {
+                v.config = config.clone();
+                v
+            });
+            (config, variant)
+        }))
+    }
+
+    /// The compiled variants that fit the code-size budget
+    fn within_budget(
+        &self,
+        compiled: impl Iterator<Item = (VariantConfig, Result<CompiledVariant, String>)>,
+    ) -> Result<Vec<CompiledVariant>, String> {
+        let mut variants = Vec::new();
+
+        let mut smallest_over_budget = None;
+
+        for (config, compiled) in compiled {
+            match compiled {
+                Ok(variant) => match self.max_code_size {
+                    Some(budget) if variant.code_size > budget => {
+                        tracing::info!(
+                            "Dropping variant {}: {} bytes exceeds the {} byte code-size budget",
+                            config.name,
+                            variant.code_size,
+                            budget
+                        );
+                        smallest_over_budget = Some(
+                            smallest_over_budget.map_or(variant.code_size, |s: usize| {
+                                s.min(variant.code_size)
+                            }),
+                        );
+                    }
+                    _ => variants.push(variant),
+                },
                 Err(e) => {
                     // Log but continue - some variants may fail
                     tracing::warn!("Failed to compile variant {}: {}", config.name, e);
@@ -136,14 +484,33 @@ impl VariantGenerator {
         }
 
         if variants.is_empty() {
+            if let (Some(budget), Some(smallest)) = (self.max_code_size, smallest_over_budget) {
+                return Err(format!(
+                    "No variant fits the {} byte code-size budget (smallest is {} bytes)",
+                    budget, smallest
+                ));
+            }
             return Err("Failed to compile any variants".to_string());
         }
 
         Ok(variants)
     }
 
+    /// Variant configurations worth trying for `program`: vector ISAs only
+    /// differ from scalar by vectorizing, so they are dropped when every
+    /// function is `#pragma novectorize`, and so are the ISAs whose vectors
+    /// are wider than every loop's constant trip count (their vector loops
+    /// would never run)
+    pub fn configs_for(&self, program: &Program) -> Vec<VariantConfig> {
+        let functions: Vec<&Function> = program.functions.iter().collect();
+        self.get_variant_configs()
+            .into_iter()
+            .filter(|c| may_vectorize(c, &functions))
+            .collect()
+    }
+
     /// Compile a specific variant
-    fn compile_variant(
+    pub fn compile_variant(
         &self,
         program: &Program,
         config: &VariantConfig,
@@ -152,38 +519,105 @@ impl VariantGenerator {
         let mut prog = program.clone();
 
         // Apply optimization based on config
-        let opt_level = match config.isa {
-            IsaExtension::Scalar => config.optimization_level.min(2),
-            IsaExtension::Avx2 => 3, // Force vectorization
-            IsaExtension::Avx512 => 3,
-            IsaExtension::Amx => 3,
-        };
+        let opt_level = config.effective_opt_level();
+
+        let width = config.isa.vector_width();
+        let options = CompileOptions::new(opt_level)
+            .software_pipeline(config.pipelined)
+            .prefetch_distance(config.prefetch_distance)
+            .vector_width(width);
+        if options.software_pipeline && opt_level >= 3 {
+            pipeliner::pipeline_program(&mut prog);
+        }
+        Optimizer::optimize_program_for(&mut prog, opt_level, width);
 
-        Optimizer::optimize_program(&mut prog, opt_level);
+        // Compile into executable memory
+        let program = CompiledProgram::compile(&prog, &options)?;
+        let code_size = program.code().code.len();
+        let func_ptr = program
+            .get("main")
+            .ok_or("Variants need an entry point fn main(n) with at most one parameter")?;
 
-        // Compile to machine code
-        let (code, entry_offset) = Compiler::compile_program(&prog, opt_level)?;
-        let code_size = code.len();
+        Ok(CompiledVariant {
+            config: config.clone(),
+            program,
+            code_size,
+            func_ptr,
+            effective_opt_level: opt_level,
+            optimized: prog,
+        })
+    }
 
-        // Allocate executable memory
-        let memory = DualMappedMemory::new(code_size.max(4096))?;
+    /// Compile `program` with each function named in `configs` under its
+    /// config and the others under `default`. Vector configs must agree on
+    /// the vector width (see `VariantConfig::compatible`). The variant's
+    /// config names every function's.
+    pub fn compile_per_function(
+        &self,
+        program: &Program,
+        configs: &BTreeMap<String, VariantConfig>,
+        default: &VariantConfig,
+    ) -> Result<CompiledVariant, String> {
+        let config_of = |name: &str| configs.get(name).unwrap_or(default);
+        let used: Vec<&VariantConfig> = program.functions.iter().map(|f| config_of(&f.name)).collect();
+        let vector = used.iter().find(|c| c.isa != IsaExtension::Scalar).copied();
+        if let Some(vector) = vector {
+            if let Some(other) = used.iter().find(|c| !c.compatible(vector)) {
+                return Err(format!(
+                    "{} and {} vectorize at different widths and can't share a program",
+                    vector.name, other.name
+                ));
+            }
+        }
+        let isa = vector.map_or(IsaExtension::Scalar, |c| c.isa);
+        let width = isa.vector_width();
 
-        // Copy code to memory
-        unsafe {
-            std::ptr::copy_nonoverlapping(code.as_ptr(), memory.rw_ptr, code_size);
+        // Per-function levels, then pipelining and prefetching where the
+        // compiler would apply them program-wide
+        let mut prog = program.clone();
+        for func in &mut prog.functions {
+            let config = config_of(&func.name);
+            func.hints.opt_level = Some(config.effective_opt_level());
+            if config.pipelined && config.effective_opt_level() >= 3 {
+                pipeliner::pipeline_function(func);
+            }
+        }
+        let opt_level = used.iter().map(|c| c.effective_opt_level()).max().unwrap_or(0);
+        Optimizer::optimize_program_for(&mut prog, opt_level, width);
+        for func in &mut prog.functions {
+            let config = config_of(&func.name);
+            if config.prefetch_distance > 0 && config.effective_opt_level() >= 3 {
+                Optimizer::insert_prefetches(func, config.prefetch_distance);
+            }
         }
-        memory.flush_icache();
 
-        // Create function pointer
-        let func_ptr: extern "C" fn(u64) -> u64 =
-            unsafe { std::mem::transmute(memory.rx_ptr.add(entry_offset)) };
+        let options = CompileOptions::new(opt_level).vector_width(width);
+        let program = CompiledProgram::compile(&prog, &options)?;
+        let code_size = program.code().code.len();
+        let func_ptr = program
+            .get("main")
+            .ok_or("Variants need an entry point fn main(n) with at most one parameter")?;
+        let names: Vec<String> = prog
+            .functions
+            .iter()
+            .map(|f| format!("{}={}", f.name, config_of(&f.name).name))
+            .collect();
+        let config = VariantConfig {
+            isa,
+            unroll_factor: used.iter().map(|c| c.unroll_factor).max().unwrap_or(1),
+            optimization_level: opt_level,
+            pipelined: used.iter().any(|c| c.pipelined),
+            prefetch_distance: used.iter().map(|c| c.prefetch_distance).max().unwrap_or(0),
+            name: names.join(" "),
+        };
 
         Ok(CompiledVariant {
-            config: config.clone(),
-            memory,
+            config,
+            program,
             code_size,
-            entry_offset,
             func_ptr,
+            effective_opt_level: opt_level,
+            optimized: prog,
         })
     }
 
@@ -215,7 +649,7 @@ mod tests {
         "#;
 
         let mut parser = Parser::new();
-        let program = parser.parse(source).expect("Parse failed");
+        let _program = parser.parse(source).expect("Parse failed");
 
         let generator = VariantGenerator::new();
         let configs = generator.get_variant_configs();
@@ -228,4 +662,226 @@ mod tests {
 
         assert!(!configs.is_empty());
     }
+
+    #[test]
+    fn test_novectorize_drops_vector_configs() {
+        let generator = VariantGenerator::with_features(CpuFeatures {
+            has_avx2: true,
+            ..CpuFeatures::default()
+        });
+        let source = "fn main(n) {\n r = n + 1\n return r\n}";
+        let plain = Parser::new().parse(source).unwrap();
+        assert!(generator.configs_for(&plain).iter().any(|c| c.isa == IsaExtension::Avx2));
+
+        let hinted = Parser::new()
+            .parse(&format!("#pragma novectorize\n{}", source))
+            .unwrap();
+        let configs = generator.configs_for(&hinted);
+        assert!(!configs.is_empty());
+        assert!(configs.iter().all(|c| c.isa == IsaExtension::Scalar));
+    }
+
+    #[test]
+    fn test_short_constant_loops_drop_wide_vector_configs() {
+        let generator = VariantGenerator::with_features(CpuFeatures {
+            has_avx2: true,
+            has_avx512f: true,
+            ..CpuFeatures::default()
+        });
+        let isas = |trips: usize| {
+            let source = format!(
+                "fn main(n) {{\n s = 0\n i = 0\n while i < {} {{\n s = s + i\n i = i + 1\n }}\n return s\n}}",
+                trips
+            );
+            let program = Parser::new().parse(&source).unwrap();
+            let configs = generator.configs_for(&program);
+            let mut isas: Vec<IsaExtension> = configs.iter().map(|c| c.isa).collect();
+            isas.dedup();
+            isas
+        };
+        assert_eq!(isas(3), [IsaExtension::Scalar]);
+        assert_eq!(isas(4), [IsaExtension::Scalar, IsaExtension::Avx2]);
+        assert!(isas(8).contains(&IsaExtension::Avx512));
+    }
+
+    #[test]
+    fn test_uarch_defaults_seed_a_variant() {
+        let avx2 = CpuFeatures {
+            has_avx2: true,
+            ..CpuFeatures::default()
+        };
+        let generic = VariantGenerator::with_features(avx2);
+        assert!(generic.seed_config().is_none());
+
+        let gracemont = crate::uarch_db::UarchDefaults::for_x86(b"GenuineIntel", 0x000B06E0);
+        let generator = VariantGenerator::with_features(avx2).uarch(gracemont);
+        assert_eq!(generator.seed_config().unwrap().name, "AVX2x2+PF64");
+        let configs = generator.get_variant_configs();
+        assert_eq!(configs.len(), generic.get_variant_configs().len() + 1);
+        assert_eq!(configs.last().unwrap().name, "AVX2x2+PF64");
+
+        // Already offered: nothing is added
+        let zen4 = crate::uarch_db::UarchDefaults::for_x86(b"AuthenticAMD", 0x00A60F12);
+        let configs = VariantGenerator::with_features(avx2).uarch(zen4).get_variant_configs();
+        assert_eq!(configs.iter().filter(|c| c.name == "AVX2x4+PF256").count(), 1);
+        assert_eq!(configs.len(), generic.get_variant_configs().len());
+    }
+
+    #[test]
+    fn test_describe_diffs_optimized_ir() {
+        // The scratch array is only removed at level 1+ (dead store elimination)
+        let program = Parser::new()
+            .parse(
+                "fn main() {
+                    t = alloc(16)
+                    t[0] = 7
+                    free(t)
+                    return 3
+                }",
+            )
+            .unwrap();
+        let generator = VariantGenerator::new();
+        let compile = |unroll, level| {
+            generator
+                .compile_variant(
+                    &program,
+                    &VariantConfig::new(IsaExtension::Scalar, unroll, level),
+                )
+                .unwrap()
+        };
+        let base = compile(1, 0);
+
+        assert!(compile(1, 0)
+            .describe(Some(&base))
+            .contains("same config and size"));
+
+        let text = compile(2, 1).describe(Some(&base));
+        assert!(text.contains("unroll 1 -> 2"), "{}", text);
+        assert!(text.contains("opt 0 -> 1"), "{}", text);
+        assert!(text.contains("@@ fn main"), "{}", text);
+        assert!(text.contains("- Alloc"), "{}", text);
+        assert!(text.contains("- Store"), "{}", text);
+    }
+
+    #[test]
+    fn test_prefetch_distance_is_a_variant_dimension() {
+        let generator = VariantGenerator::with_features(CpuFeatures {
+            has_avx2: true,
+            ..CpuFeatures::default()
+        });
+        let names: Vec<String> = generator.get_variant_configs().into_iter().map(|c| c.name).collect();
+        for bytes in &PREFETCH_DISTANCES[1..] {
+            assert!(names.contains(&format!("AVX2x4+PF{}", bytes)), "{:?}", names);
+        }
+
+        let program = Parser::new()
+            .parse(&std::fs::read_to_string("tests/codegen/vec_add.nf").unwrap())
+            .unwrap();
+        let compile = |config| generator.compile_variant(&program, &config).unwrap();
+        let base = compile(VariantConfig::new(IsaExtension::Avx2, 4, 3));
+        let prefetching = compile(VariantConfig::new(IsaExtension::Avx2, 4, 3).prefetch(128));
+        assert_eq!(prefetching.execute(0), base.execute(0));
+        let text = prefetching.describe(Some(&base));
+        assert!(text.contains("prefetch 0 -> 128 bytes"), "{}", text);
+    }
+
+    #[test]
+    fn test_code_size_budget_drops_large_variants() {
+        let program = Parser::new()
+            .parse(&std::fs::read_to_string("tests/codegen/vec_add.nf").unwrap())
+            .unwrap();
+        let sizes: Vec<usize> = VariantGenerator::new()
+            .generate_variants(&program)
+            .unwrap()
+            .iter()
+            .map(|v| v.code_size)
+            .collect();
+        let smallest = *sizes.iter().min().unwrap();
+
+        let fitting = VariantGenerator::new()
+            .code_size_budget(smallest)
+            .generate_variants(&program)
+            .unwrap();
+        assert!(fitting.iter().all(|v| v.code_size <= smallest));
+        assert_eq!(fitting.len(), sizes.iter().filter(|&&s| s == smallest).count());
+
+        let err = VariantGenerator::new()
+            .code_size_budget(smallest - 1)
+            .generate_variants(&program)
+            .unwrap_err();
+        assert!(err.contains("code-size budget"), "{}", err);
+    }
+
+    #[test]
+    fn test_functions_take_their_own_config() {
+        let program = Parser::new()
+            .parse("fn main(n) {\n r = helper(n)\n return r\n}\nfn helper(n) {\n r = n + 1\n return r\n}")
+            .unwrap();
+        let generator = VariantGenerator::with_features(CpuFeatures {
+            has_avx2: true,
+            has_avx512f: true,
+            ..CpuFeatures::default()
+        });
+        let scalar = VariantConfig::new(IsaExtension::Scalar, 1, 1);
+        let whole = generator.compile_variant(&program, &scalar).unwrap();
+        let configs = BTreeMap::from([("main".to_string(), scalar.clone())]);
+        let per_function = generator.compile_per_function(&program, &configs, &scalar).unwrap();
+        assert_eq!(per_function.execute(41), whole.execute(41));
+        let mut batched = [0; 3];
+        per_function.execute_batch(&[1, 41, 99], &mut batched).unwrap();
+        assert_eq!(batched.map(|r| r as u64), [1, 41, 99].map(|n| whole.execute(n)));
+        let mut parallel = [0; 3];
+        let pool = ExecutionPool::new(2);
+        per_function.execute_parallel(&pool, &[1, 41, 99], &mut parallel).unwrap();
+        assert_eq!(parallel, batched);
+        assert_eq!(per_function.config.name, "main=Scalarx1 helper=Scalarx1");
+        assert_eq!(per_function.effective_opt_level, 1);
+
+        // Widths are program-wide
+        let avx2 = VariantConfig::new(IsaExtension::Avx2, 4, 3);
+        let avx512 = VariantConfig::new(IsaExtension::Avx512, 8, 3);
+        assert!(avx2.compatible(&scalar) && !avx2.compatible(&avx512));
+        let configs = BTreeMap::from([("main".to_string(), avx512.clone())]);
+        let err = generator.compile_per_function(&program, &configs, &avx2).unwrap_err();
+        assert!(err.contains("different widths"), "{}", err);
+    }
+
+    #[test]
+    fn test_function_variants_respect_fixed_widths() {
+        let program = Parser::new()
+            .parse("fn main(n) {\n r = helper(n)\n return r\n}\nfn helper(n) {\n r = n + 1\n return r\n}")
+            .unwrap();
+        let generator = VariantGenerator::with_features(CpuFeatures {
+            has_avx2: true,
+            has_avx512f: true,
+            ..CpuFeatures::default()
+        });
+        let default = VariantConfig::new(IsaExtension::Scalar, 1, 1);
+        let fixed = BTreeMap::from([("main".to_string(), VariantConfig::new(IsaExtension::Avx2, 4, 3))]);
+        let configs: Vec<VariantConfig> = generator
+            .get_variant_configs()
+            .into_iter()
+            .filter(|c| c.compatible(&fixed["main"]))
+            .collect();
+        assert!(configs.iter().all(|c| c.isa != IsaExtension::Avx512));
+        // Only compile what this CPU runs
+        if !CpuFeatures::detect().has_avx2() {
+            return;
+        }
+        let variants = generator
+            .generate_function_variants(&program, "helper", &fixed, &default)
+            .unwrap();
+        assert!(variants.iter().all(|v| v.config.isa != IsaExtension::Avx512));
+        assert!(variants.iter().all(|v| v.execute(41) == 42));
+        assert!(generator.generate_function_variants(&program, "nope", &fixed, &default).is_err());
+    }
+
+    #[test]
+    fn test_line_diff() {
+        let lines = |s: &str| s.split(' ').map(String::from).collect::<Vec<_>>();
+        assert_eq!(
+            line_diff(&lines("a b c d"), &lines("a x c d e")),
+            vec!["+ x", "- b", "+ e"]
+        );
+    }
 }
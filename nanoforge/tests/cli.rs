@@ -0,0 +1,928 @@
+//! End-to-end tests driving the `nanoforge` binary on the scripts in tests/cli.
+#![cfg(target_arch = "x86_64")]
+
+use assert_cmd::Command;
+use predicates::prelude::*;
+
+fn nanoforge() -> Command {
+    Command::cargo_bin("nanoforge").unwrap()
+}
+
+/// Name of the state file `kind` saves for `script`
+fn state_file(kind: &str, script: &str) -> String {
+    let source = std::fs::read_to_string(script).unwrap();
+    format!("{}-{}.json", kind, nanoforge::shutdown::script_key(script, &source))
+}
+
+fn stdout_of(args: &[&str]) -> String {
+    let output = nanoforge().args(args).assert().success().get_output().clone();
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+#[test]
+fn run_reports_result() {
+    nanoforge()
+        .args(["run", "tests/cli/sum_to_ten.nf"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Result: 55"));
+
+    for level in ["0", "1", "2", "3"] {
+        nanoforge()
+            .args(["run", "tests/cli/calls.nf", "--level", level])
+            .assert()
+            .success()
+            .stdout(predicate::str::contains("Result: 48"));
+    }
+
+    // Canary-guarded frames behave the same
+    nanoforge()
+        .args(["run", "tests/cli/calls.nf", "--debug"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Result: 48"));
+}
+
+#[test]
+fn run_emits_only_the_requested_artifacts() {
+    // Run from an empty directory: nothing may land in it unasked
+    let dir = std::env::temp_dir().join(format!("nf_cli_emit_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let script = std::fs::canonicalize("tests/cli/calls.nf").unwrap();
+    nanoforge()
+        .current_dir(&dir)
+        .args(["--verbose", "run", script.to_str().unwrap(), "--debug"])
+        .assert()
+        .success();
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+
+    let bin = dir.join("out").join("main.bin");
+    let asm = dir.join("main.asm");
+    let report = dir.join("report.md");
+    nanoforge()
+        .args(["run", "tests/cli/calls.nf", "--emit-bin", bin.to_str().unwrap()])
+        .assert()
+        .code(1)
+        .stdout(predicate::str::contains("Failed to write"));
+    nanoforge()
+        .args(["run", "tests/cli/calls.nf", "--emit-asm", asm.to_str().unwrap()])
+        .args(["--emit-report", report.to_str().unwrap()])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Result: 48"));
+    let asm = std::fs::read_to_string(&asm).unwrap();
+    assert!(asm.starts_with("fn main:\n0000: push rbp\n") && asm.contains("\nfn mul_add:\n"));
+    let report = std::fs::read_to_string(&report).unwrap();
+    assert!(report.starts_with("# Optimization report (-O3)"), "{}", report);
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn run_entry_with_args() {
+    nanoforge()
+        .args(["run", "tests/cli/calls.nf", "--entry", "mul_add", "--args", "-2,3,4"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Result: -2"));
+    nanoforge()
+        .args(["run", "tests/cli/calls.nf", "--entry", "nope"])
+        .assert()
+        .code(1);
+    nanoforge()
+        .args(["run", "tests/cli/calls.nf", "--args", "1"])
+        .assert()
+        .code(1);
+}
+
+#[test]
+fn run_constant_time() {
+    nanoforge()
+        .args(["run", "tests/cli/clamp.nf", "--constant-time", "--args", "9"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Result: 20"));
+    nanoforge()
+        .args(["run", "tests/cli/secret_load.nf", "--constant-time", "--args", "1"])
+        .assert()
+        .code(1)
+        .stdout(predicate::str::contains("could not be made branchless"));
+}
+
+#[test]
+fn missing_file_fails() {
+    nanoforge()
+        .args(["run", "tests/cli/does_not_exist.nf"])
+        .assert()
+        .code(1);
+}
+
+#[test]
+fn check_accepts_valid_and_rejects_invalid() {
+    nanoforge()
+        .args(["check", "tests/cli/calls.nf"])
+        .assert()
+        .success();
+    nanoforge()
+        .args(["check", "tests/cli/bad_syntax.nf"])
+        .assert()
+        .code(1);
+    nanoforge()
+        .args(["check", "tests/cli/int_deref.nf"])
+        .assert()
+        .code(1)
+        .stdout(predicate::str::contains("through an integer"));
+}
+
+#[test]
+fn explain_lines_up_source_ir_and_assembly() {
+    let out = stdout_of(&["explain", "tests/cli/calls.nf", "--level", "0"]);
+    assert!(out.starts_with("fn main @ 0000\n   2 | x = mul_add(6, 7, 0)\n"), "{}", out);
+    let call = out.lines().find(|l| l.contains("Call Reg(")).expect(&out);
+    // The call's own bytes and assembly sit on its line
+    assert!(call.contains("Label(\"mul_add\")") && call.contains(": e8 "), "{}", out);
+    assert!(call.contains(" call 0x"), "{}", out);
+    assert!(out.contains("\nfn mul_add @ "), "{}", out);
+    assert!(out.contains("   9 | r = p + c\n"), "{}", out);
+    nanoforge()
+        .args(["explain", "tests/cli/bad_syntax.nf"])
+        .assert()
+        .code(1);
+}
+
+#[test]
+fn diagnostics_warn_and_reject_undefined_calls() {
+    let out = stdout_of(&["check", "tests/cli/warnings.nf"]);
+    for warning in [
+        "warning[unused-variable]: line 2:5 in main(): `t` is assigned but never read",
+        "warning[constant-condition]: line 3:5 in main(): condition `0 < 0` is always false",
+        "warning[unreachable-code]: line 7:5",
+    ] {
+        assert!(out.contains(warning), "{}", out);
+    }
+    assert!(stdout_of(&["run", "tests/cli/warnings.nf"]).contains("Result: 3"));
+
+    // Fails cleanly instead of panicking in the backend
+    for command in ["check", "run"] {
+        nanoforge()
+            .args([command, "tests/cli/undefined_call.nf"])
+            .assert()
+            .code(1)
+            .stdout(predicate::str::contains(
+                "call to undefined function `helper`; did you mean `helpers`?",
+            ));
+    }
+}
+
+#[test]
+fn checked_overflow_reports_the_instruction() {
+    nanoforge()
+        .args(["run", "tests/cli/overflow.nf", "--args", "3", "--overflow=checked"])
+        .assert()
+        .code(1)
+        .stdout(predicate::str::contains("main(): integer overflow in main["))
+        .stdout(predicate::str::contains("Mul"));
+
+    // Wrapping is the default; 1 never overflows either way
+    assert!(!stdout_of(&["run", "tests/cli/overflow.nf", "--args", "3"]).contains("overflow"));
+    assert!(stdout_of(&["run", "tests/cli/overflow.nf", "--args", "1", "--overflow=checked"])
+        .contains("Result: 1"));
+}
+
+#[test]
+fn checked_overflow_traps_in_folded_calls() {
+    for level in ["0", "2"] {
+        nanoforge()
+            .args(["run", "tests/cli/overflow_call.nf", "--overflow=checked", "--level", level])
+            .assert()
+            .code(1)
+            .stdout(predicate::str::contains("integer overflow in big["));
+    }
+    assert!(stdout_of(&["run", "tests/cli/overflow_call.nf", "--level", "2"]).contains("Result: 0"));
+}
+
+#[test]
+fn assertions_report_line_and_values_below_o2() {
+    nanoforge()
+        .args(["run", "tests/cli/assert.nf", "--args", "10", "--level", "1"])
+        .assert()
+        .code(1)
+        .stdout(predicate::str::contains(
+            "main(): assertion failed at line 13: s < 50 (55 vs 50)",
+        ));
+    assert!(stdout_of(&["run", "tests/cli/assert.nf", "--args", "9", "--level", "0"])
+        .contains("Result: 45"));
+
+    // Compiled out from -O2 up
+    assert!(stdout_of(&["run", "tests/cli/assert.nf", "--args", "10"]).contains("Result: 55"));
+}
+
+#[test]
+fn pure_mode_rejects_allocation() {
+    nanoforge()
+        .args(["run", "tests/cli/oob_store.nf", "--pure"])
+        .assert()
+        .code(1)
+        .stdout(predicate::str::contains("pure mode: 4 instruction(s)"))
+        .stdout(predicate::str::contains("main[0]: Alloc Reg(10), Imm(32): allocates (malloc)"));
+    assert!(stdout_of(&["run", "tests/cli/calls.nf", "--pure"]).contains("Result: 48"));
+}
+
+#[test]
+fn verified_code_still_runs() {
+    let out = stdout_of(&["run", "tests/cli/calls.nf", "--verify-code", "--debug", "--sanitize"]);
+    assert!(out.contains("Result: 48"), "{}", out);
+}
+
+#[test]
+fn run_applies_pass_plugins() {
+    let plugin = std::env::temp_dir().join(format!("nf-cli-plugin-{}.so", std::process::id()));
+    let built = std::process::Command::new("cc")
+        .args(["-shared", "-fPIC", "-o"])
+        .arg(&plugin)
+        .arg("tests/plugins/add_to_sub.c")
+        .status()
+        .unwrap();
+    assert!(built.success());
+    let plugin = plugin.to_str().unwrap();
+
+    // sum = sum + i becomes sum = sum - i
+    assert!(stdout_of(&["run", "tests/cli/sum_to_ten.nf", "--plugin", plugin]).contains("Result: -55"));
+    nanoforge()
+        .args(["run", "tests/cli/sum_to_ten.nf", "--plugin", plugin, "--sanitize"])
+        .assert()
+        .code(1)
+        .stdout(predicate::str::contains("sanitized builds are not supported"));
+    nanoforge()
+        .args(["run", "tests/cli/sum_to_ten.nf", "--plugin", "/nonexistent.so"])
+        .assert()
+        .code(1)
+        .stdout(predicate::str::contains("failed to load plugin /nonexistent.so"));
+    std::fs::remove_file(plugin).unwrap();
+}
+
+#[test]
+fn benchmark_runs() {
+    nanoforge()
+        .args(["benchmark", "tests/cli/sum_to_ten.nf", "--level", "2"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Avg Cycles/Op"))
+        .stdout(predicate::str::contains("Result:       55 (stable)"));
+}
+
+#[test]
+fn adaptive_runs() {
+    nanoforge()
+        .args(["adaptive", "tests/cli/sum_to_ten.nf", "--duration-ms", "500"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Interpreted -> Baseline: called 100 times"))
+        .stdout(predicate::str::contains("Result: 55"))
+        .stdout(predicate::str::contains("Speedup:"));
+
+    nanoforge()
+        .args(["adaptive", "tests/cli/sum_to_ten.nf", "--demote-share", "0.5"])
+        .assert()
+        .failure()
+        .stdout(predicate::str::contains("hysteresis needs demote <= promote"));
+}
+
+#[test]
+fn adaptive_starts_from_templates() {
+    let out = stdout_of(&["adaptive", "tests/cli/sum_to_ten.nf", "--duration-ms", "300", "--template"]);
+    assert!(out.contains("Result: 55"), "{}", out);
+    assert!(!out.contains("Interpreted"), "{}", out);
+    assert!(out.contains("compile µs"), "{}", out);
+    assert!(out.lines().any(|l| l.starts_with("Template ")), "{}", out);
+}
+
+#[test]
+fn adaptive_audits_tier_changes() {
+    let path = std::env::temp_dir().join(format!("nf_cli_audit_{}.jsonl", std::process::id()));
+    std::fs::remove_file(&path).ok();
+    let audit = path.to_str().unwrap();
+    let args = ["adaptive", "tests/cli/sum_to_ten.nf", "--duration-ms", "300"];
+    nanoforge().args(args).args(["--audit-log", audit]).assert().success();
+
+    let text = std::fs::read_to_string(&path).unwrap();
+    let first: serde_json::Value = serde_json::from_str(text.lines().next().unwrap()).unwrap();
+    assert_eq!(first["program"], "sum_to_ten");
+    assert_eq!(first["action"], "promote");
+    assert_eq!((&first["from"], &first["to"]), (&"interpreted".into(), &"baseline".into()));
+    assert_eq!(first["trigger"], "called 100 times");
+    assert!(first["before"].is_number() && first["after"].is_number(), "{}", text);
+    std::fs::remove_file(&path).ok();
+}
+
+#[test]
+fn live_evolves_in_the_background() {
+    let out = stdout_of(&[
+        "live",
+        "tests/cli/inc.nf",
+        "--inputs",
+        "1,2,3",
+        "--duration-ms",
+        "1000",
+        "--generations",
+        "1",
+        "--population",
+        "4",
+    ]);
+    assert!(out.contains("Round"), "{}", out);
+    assert!(out.contains("rounds swapped in new code"), "{}", out);
+
+    let out = stdout_of(&["live", "tests/cli/sum_array.nf"]);
+    assert!(out.contains("cannot evolve main(): takes 2 arguments"), "{}", out);
+}
+
+#[test]
+fn benchmark_replays_live_capture() {
+    let path = std::env::temp_dir().join(format!("nf_cli_capture_{}.json", std::process::id()));
+    let capture = path.to_str().unwrap();
+    let out = stdout_of(&[
+        "live",
+        "tests/cli/inc.nf",
+        "--inputs",
+        "5,6,7",
+        "--duration-ms",
+        "300",
+        "--capture",
+        capture,
+    ]);
+    assert!(out.contains("Captured"), "{}", out);
+
+    let out = stdout_of(&["benchmark", "tests/cli/inc.nf", "--replay", capture]);
+    assert!(out.contains("Replaying"), "{}", out);
+    assert!(out.contains("match the recording"), "{}", out);
+
+    // A script that computes something else fails the replay
+    let out = nanoforge()
+        .args(["benchmark", "tests/cli/clamp.nf", "--replay", capture])
+        .assert()
+        .failure()
+        .get_output()
+        .clone();
+    let _ = std::fs::remove_file(&path);
+    assert!(
+        String::from_utf8_lossy(&out.stdout).contains("recorded"),
+        "{:?}",
+        out
+    );
+}
+
+#[test]
+fn benchmark_specializes_on_captured_inputs() {
+    let path = std::env::temp_dir().join(format!("nf_cli_constant_{}.json", std::process::id()));
+    let capture = path.to_str().unwrap();
+    let script = "tests/cli/sum_squares.nf";
+    let out = stdout_of(&[
+        "live", script, "--inputs", "1000", "--duration-ms", "300", "--capture", capture,
+    ]);
+    assert!(out.contains("Captured"), "{}", out);
+
+    let out = stdout_of(&["benchmark", script, "--replay", capture, "--specialize"]);
+    let _ = std::fs::remove_file(&path);
+    assert!(out.contains("Specializing main() on input 1000"), "{}", out);
+    assert!(out.contains("match the recording"), "{}", out);
+
+    // Only with a replay to profile
+    nanoforge().args(["benchmark", script, "--specialize"]).assert().failure();
+}
+
+#[test]
+fn bundles_run_without_compiling() {
+    let path = std::env::temp_dir().join(format!("nf_cli_bundle_{}.nfb", std::process::id()));
+    let bundle = path.to_str().unwrap();
+    let out = stdout_of(&["bundle", "tests/cli/sum_squares.nf", "-o", bundle]);
+    assert!(out.contains("Bundled"), "{}", out);
+    assert!(out.contains("Scalarx1"), "{}", out);
+
+    let out = stdout_of(&["run-bundle", bundle, "10", "1000"]);
+    assert!(out.contains("main(10) = 285  [Scalarx1]"), "{}", out);
+    assert!(out.contains("main(1000) = 332833500"), "{}", out);
+
+    // A damaged bundle is refused
+    let mut bytes = std::fs::read(&path).unwrap();
+    let at = bytes.len() / 2;
+    bytes[at] ^= 1;
+    std::fs::write(&path, bytes).unwrap();
+    nanoforge().args(["run-bundle", bundle, "10"]).assert().failure();
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn soae_winner_computes_correct_result() {
+    let out = stdout_of(&["soae", "tests/cli/inc.nf"]);
+    assert!(out.contains("Executing winner"), "{}", out);
+    assert!(out.contains("Result: 1001"), "{}", out);
+    assert!(out.contains("L1I MPKI"), "{}", out);
+}
+
+#[test]
+fn soae_and_benchmark_take_input_distributions() {
+    let out = stdout_of(&["soae", "tests/cli/inc.nf", "--inputs", "uniform:10..50"]);
+    assert!(out.contains("over uniform:10..50, ranked by expected cost"), "{}", out);
+    assert!(out.contains("Result: 31"), "{}", out);
+
+    let out = stdout_of(&["benchmark", "tests/cli/inc.nf", "--inputs", "uniform:10..50"]);
+    assert!(out.contains("Expected Cycles/Op"), "{}", out);
+
+    nanoforge()
+        .args(["soae", "tests/cli/inc.nf", "--inputs", "normal:3"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("unknown input spec"));
+}
+
+#[test]
+fn soae_tunes_hot_functions_separately() {
+    let out = stdout_of(&["soae", "tests/cli/hot_sum.nf", "--per-function"]);
+    assert!(out.contains("🔥 fn "), "{}", out);
+    assert!(out.contains("Executing combined program: main="), "{}", out);
+    assert!(out.contains("Result: 501500"), "{}", out);
+}
+
+#[test]
+fn soae_reports_cold_cache_timings_separately() {
+    let out = stdout_of(&["soae", "tests/cli/inc.nf", "--cold", "1"]);
+    assert!(out.contains("Cold caches (median of 1 call(s)"), "{}", out);
+    assert!(out.contains("│ Cold Cycles "), "{}", out);
+    assert!(out.contains("Result: 1001"), "{}", out);
+    assert!(!stdout_of(&["soae", "tests/cli/inc.nf"]).contains("Cold caches"));
+}
+
+#[test]
+fn soae_mem_trace_samples_top_variants_or_says_why_not() {
+    // Sampling needs a PMU (rarely in VMs); either way the run completes
+    let out = stdout_of(&["soae", "tests/cli/inc.nf", "--mem-trace", "2"]);
+    assert!(
+        out.contains("Memory access samples") || out.contains("--mem-trace unavailable"),
+        "{}",
+        out
+    );
+    assert!(out.contains("Result: 1001"), "{}", out);
+}
+
+#[test]
+fn soae_enforces_code_size_budget() {
+    nanoforge()
+        .args(["soae", "tests/cli/inc.nf", "--max-code-size", "1"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("code-size budget"));
+}
+
+#[test]
+fn pass_impact_measures_every_pass() {
+    let out = stdout_of(&["pass-impact", "kernels/sum.nf", "tests/codegen/select.nf", "--json"]);
+    let report: serde_json::Value = serde_json::from_str(&out).unwrap();
+    assert_eq!(report["scripts"].as_array().unwrap().len(), 2);
+    let passes = report["passes"].as_array().unwrap();
+    assert_eq!(passes.len(), 16);
+    let select = passes.iter().find(|p| p["pass"] == "select_lowering").unwrap();
+    assert_eq!(select["fired"], 1);
+
+    let out = stdout_of(&["pass-impact", "tests/codegen/select.nf"]);
+    assert!(out.contains("│ select_lowering        │    1/1 │"), "{}", out);
+}
+
+#[test]
+fn spill_impact_forces_chosen_vregs_onto_the_stack() {
+    let out = stdout_of(&["spill-impact", "kernels/sum.nf", "--input", "100", "--json"]);
+    let report: serde_json::Value = serde_json::from_str(&out).unwrap();
+    let spills = report["spills"].as_array().unwrap();
+    assert!(!spills.is_empty(), "{}", out);
+    assert!(spills.iter().all(|m| m["functions"][0] == "main"), "{}", out);
+
+    let out = stdout_of(&["spill-impact", "kernels/sum.nf", "--input", "100", "--vreg", "r200"]);
+    assert!(out.contains("Already spilled or unused (code unchanged): r200"), "{}", out);
+    nanoforge()
+        .args(["spill-impact", "kernels/sum.nf", "--vreg", "r0"])
+        .assert()
+        .code(1)
+        .stderr(predicate::str::contains("r0 is pinned to a machine register"));
+}
+
+#[test]
+fn soak_checks_winners_against_golden_outputs() {
+    let out = stdout_of(&[
+        "soak", "kernels/sum.nf", "tests/cli/calls.nf", "--duration-secs", "0", "-g", "1", "-p", "4",
+        "--json",
+    ]);
+    let report: serde_json::Value = serde_json::from_str(&out).unwrap();
+    assert_eq!(report["summary"]["rounds"], 1);
+    assert_eq!(report["scripts"].as_array().unwrap().len(), 2);
+    assert_eq!(report["summary"]["failures"].as_array().unwrap().len(), 0);
+    // Evolution only takes single-function scripts
+    assert!(report["scripts"][0]["evolved_speedup"].is_number(), "{}", out);
+    assert!(report["scripts"][1]["evolved_speedup"].is_null(), "{}", out);
+
+    nanoforge()
+        .args(["soak", "tests/cli/assert.nf", "--duration-secs", "0", "-g", "0"])
+        .assert()
+        .code(1)
+        .stderr(predicate::str::contains(
+            "round 1 tests/cli/assert.nf [baseline]: main(): assertion failed at line 13",
+        ));
+}
+
+#[test]
+fn compare_native_checks_and_ranks_against_rustc() {
+    let out = stdout_of(&["compare-native", "kernels/vec_add.nf", "--input", "100"]);
+    assert!(out.contains("Result: 14850"), "{}", out);
+    assert!(out.contains("rustc (native)"), "{}", out);
+    assert!(out.contains("% of native speed"), "{}", out);
+
+    nanoforge()
+        .args(["compare-native", "kernels/sum.nf", "--kernel", "dot"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("does not compute native dot"));
+    nanoforge()
+        .args(["compare-native", "tests/cli/inc.nf"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("No native kernel named 'inc'"));
+}
+
+#[test]
+fn tune_stores_best_config_per_script() {
+    let dir = std::env::temp_dir().join(format!("nf_cli_tune_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::copy("tests/cli/inc.nf", dir.join("inc.nf")).unwrap();
+    std::fs::copy("tests/cli/sum_to_ten.nf", dir.join("sum_to_ten.nf")).unwrap();
+    let store = dir.join("store").join("tuning.json");
+    let args = ["tune", dir.to_str().unwrap(), "--store", store.to_str().unwrap()];
+
+    let out = stdout_of(&args);
+    assert_eq!(out.matches("│ tuned  │").count(), 2, "{}", out);
+    let saved: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&store).unwrap()).unwrap();
+    assert_eq!(saved["kind"], "tuning");
+    assert_eq!(saved["payload"]["entries"].as_object().unwrap().len(), 2);
+
+    // Unchanged scripts come from the store
+    let out = stdout_of(&args);
+    assert_eq!(out.matches("│ cached │").count(), 2, "{}", out);
+
+    std::fs::copy("tests/cli/bad_syntax.nf", dir.join("bad_syntax.nf")).unwrap();
+    nanoforge()
+        .args(args)
+        .assert()
+        .code(1)
+        .stdout(predicate::str::contains("│ failed │"))
+        .stderr(predicate::str::contains("bad_syntax.nf"));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn learn_passes_stores_a_schedule_run_can_use() {
+    let dir = std::env::temp_dir().join(format!("nf_cli_learn_passes_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let store = dir.join("pass_schedules.json");
+    let args = ["learn-passes", "tests/cli/hot_sum.nf", "-i", "5", "--input", "100", "--store", store.to_str().unwrap()];
+
+    let out = stdout_of(&args);
+    assert!(out.contains("fn sum: "), "{}", out);
+    assert!(out.contains("│ all passes "), "{}", out);
+    let saved: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&store).unwrap()).unwrap();
+    let entry = saved["payload"]["entries"].as_object().unwrap().values().next().unwrap();
+    assert_eq!(entry["script"], "tests/cli/hot_sum.nf");
+
+    let out = stdout_of(&args);
+    assert!(out.contains("Using the schedule stored"), "{}", out);
+    let out = stdout_of(&["run", "tests/cli/hot_sum.nf", "--args", "100", "--pass-schedule", store.to_str().unwrap()]);
+    assert!(out.contains("Result: 5150"), "{}", out);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn brain_export_round_trips_through_import() {
+    let dir = std::env::temp_dir().join(format!("nf_cli_brain_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let state = dir.to_str().unwrap();
+    stdout_of(&["soae-context", "tests/cli/inc.nf", "--iterations", "5", "--state-dir", state]);
+    let brain = dir.join(state_file("context-brain", "tests/cli/inc.nf"));
+
+    let md = stdout_of(&["brain", "export", brain.to_str().unwrap()]);
+    assert!(md.contains("| Input size | Best variant | Expected | Confidence | Samples |"), "{}", md);
+    assert!(md.contains("## Crossovers"), "{}", md);
+
+    let csv = dir.join("table.csv");
+    stdout_of(&["brain", "export", brain.to_str().unwrap(), "--format", "csv", "-o", csv.to_str().unwrap()]);
+    let exported = std::fs::read_to_string(&csv).unwrap();
+    assert!(exported.starts_with("bucket,min_size,variant,"), "{}", exported);
+    assert_eq!(exported.lines().filter(|l| l.ends_with(",true")).count(), 5);
+    let out = stdout_of(&["brain", "import", csv.to_str().unwrap()]);
+    assert!(out.contains("Tiny (<32)       → "), "{}", out);
+    assert!(!out.contains("learned at runtime"), "{}", out);
+
+    nanoforge()
+        .args(["brain", "export", "tests/cli/inc.nf"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("not a contextual brain"));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn soae_ai_and_context_run() {
+    let out = stdout_of(&["soae-ai", "tests/cli/inc.nf", "--iterations", "5"]);
+    assert!(out.contains("Result: 1001"), "{}", out);
+
+    nanoforge()
+        .args(["soae-context", "tests/cli/inc.nf", "--iterations", "5"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Contextual Bandit Learning Complete"));
+}
+
+#[test]
+fn jit_memory_cap_refuses_code_past_it() {
+    let args = ["soae-ai", "tests/cli/inc.nf", "--iterations", "5", "--jit-memory-cap"];
+    let out = stdout_of(&[&args[..], &["1000000"]].concat());
+    assert!(out.contains("Result: 1001"), "{}", out);
+
+    nanoforge()
+        .args([&args[..], &["1"]].concat())
+        .assert()
+        .failure()
+        .stdout(predicate::str::contains("JIT memory cap of 1 bytes reached"));
+}
+
+#[test]
+fn state_dir_keeps_history_and_interrupted_progress() {
+    let dir = std::env::temp_dir().join(format!("nf_cli_state_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let state = dir.to_str().unwrap();
+
+    for _ in 0..2 {
+        stdout_of(&["benchmark", "tests/cli/sum_to_ten.nf", "--state-dir", state]);
+    }
+    let history = std::fs::read_to_string(dir.join("benchmarks.jsonl")).unwrap();
+    assert_eq!(history.lines().count(), 2);
+    assert!(history.contains("\"result\":55"), "{}", history);
+
+    stdout_of(&["soae-ai", "tests/cli/inc.nf", "--iterations", "3", "--state-dir", state]);
+    assert!(dir.join(state_file("brain", "tests/cli/inc.nf")).exists());
+
+    // Ctrl+C stops evolution between generations and still saves a checkpoint
+    let mut evolve = std::process::Command::new(assert_cmd::cargo::cargo_bin("nanoforge"))
+        .args(["evolve", "tests/cli/sum_to_ten.nf", "-g", "1000000", "--state-dir", state])
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(1500));
+    unsafe { libc::kill(evolve.id() as i32, libc::SIGINT) };
+    assert!(evolve.wait().unwrap().success());
+    let checkpoint: serde_json::Value = serde_json::from_str(
+        &std::fs::read_to_string(dir.join(state_file("evolution", "tests/cli/sum_to_ten.nf"))).unwrap(),
+    )
+    .unwrap();
+    assert_eq!(checkpoint["kind"], "evolution-checkpoint");
+    assert_eq!(checkpoint["payload"]["interrupted"], true);
+    assert!(!checkpoint["payload"]["best"].as_array().unwrap().is_empty());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn state_show_and_clear() {
+    let dir = std::env::temp_dir().join(format!("nf_cli_store_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let state = dir.to_str().unwrap();
+    stdout_of(&["benchmark", "tests/cli/sum_to_ten.nf", "--state-dir", state]);
+    stdout_of(&["soae-ai", "tests/cli/inc.nf", "--iterations", "3", "--state-dir", state]);
+    stdout_of(&["soae-context", "tests/cli/inc.nf", "--iterations", "3", "--state-dir", state]);
+
+    let out = stdout_of(&["state", "show", "--state-dir", state]);
+    assert!(out.contains("benchmarks.jsonl"), "{}", out);
+    assert!(out.contains("history, 1 entries"), "{}", out);
+    assert!(out.contains("brain v1"), "{}", out);
+    assert!(out.contains("context-brain v1"), "{}", out);
+
+    // A brain cut short is flagged rather than silently loaded
+    let brain = dir.join(state_file("brain", "tests/cli/inc.nf"));
+    let text = std::fs::read_to_string(&brain).unwrap();
+    std::fs::write(&brain, &text[..text.len() / 2]).unwrap();
+    nanoforge()
+        .args(["state", "show", "--state-dir", state])
+        .assert()
+        .failure()
+        .stdout(predicate::str::contains("DAMAGED"));
+
+    let out = stdout_of(&["state", "clear", "--kind", "brain", "--state-dir", state]);
+    assert!(out.contains("Cleared 0 file(s)"), "{}", out);
+    let out = stdout_of(&["state", "clear", "--kind", "context-brain", "--state-dir", state]);
+    assert!(out.contains("Cleared 1 file(s)"), "{}", out);
+    assert!(!dir.join(state_file("context-brain", "tests/cli/inc.nf")).exists());
+    let out = stdout_of(&["state", "clear", "--state-dir", state]);
+    assert!(out.contains("Cleared 2 file(s)"), "{}", out);
+    let out = stdout_of(&["state", "show", "--state-dir", state]);
+    assert!(out.contains("No state in"), "{}", out);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn soae_log_feeds_analyze() {
+    let path = std::env::temp_dir().join(format!("nf_cli_brain_{}.log", std::process::id()));
+    let log = path.to_str().unwrap();
+    let _ = std::fs::remove_file(&path);
+    stdout_of(&["soae-ai", "tests/cli/inc.nf", "--iterations", "4", "--log", log]);
+    stdout_of(&["soae-context", "tests/cli/inc.nf", "--iterations", "6", "--log", log]);
+
+    let lines = std::fs::read_to_string(&path).unwrap().lines().count();
+    let out = stdout_of(&["analyze", log, "--windows", "5"]);
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(lines, 10);
+    assert!(out.contains("Convergence (10 events)"), "{}", out);
+    assert!(out.contains("Per-Bucket Regret"), "{}", out);
+    assert!(out.contains("Medium (256-4K)"), "{}", out);
+}
+
+#[test]
+fn recorded_measurements_replay_offline() {
+    let dir = std::env::temp_dir().join(format!("nf_cli_simulate_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let record = dir.join("run.jsonl");
+    let record = record.to_str().unwrap();
+    stdout_of(&["soae-ai", "tests/cli/inc.nf", "--iterations", "4", "--record", record]);
+    let lines = std::fs::read_to_string(record).unwrap().lines().count();
+    assert!(lines > 4, "{}", lines);
+
+    let args = ["simulate", "--log", record, "--policy", "ucb1:0.5", "--policy", "uniform", "-i", "50", "--runs", "3", "--seed", "9"];
+    let out = stdout_of(&args);
+    assert!(out.contains("at 1 input size(s)"), "{}", out);
+    assert!(out.contains("│ ucb1:0.5 "), "{}", out);
+    assert!(out.contains("│ uniform "), "{}", out);
+    assert_eq!(stdout_of(&args), out);
+
+    let out = stdout_of(&["simulate", "--log", record, "-i", "20", "--runs", "1"]);
+    assert!(out.contains("Per-Bucket Regret"), "{}", out);
+    nanoforge()
+        .args(["simulate", "--log", record, "--policy", "softmax"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("unknown policy 'softmax'"));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn evolve_winner_is_verified() {
+    use nanoforge::compiled_program::CompiledProgram;
+    use nanoforge::compiler::CompileOptions;
+    use nanoforge::ir::Program;
+    use nanoforge::parser::Parser;
+
+    let path = std::env::temp_dir().join(format!("nf_cli_winner_{}.json", std::process::id()));
+    let out = stdout_of(&[
+        "evolve",
+        "tests/cli/inc.nf",
+        "--generations",
+        "5",
+        "--population",
+        "8",
+        "--emit-winner",
+        path.to_str().unwrap(),
+    ]);
+    assert!(out.contains("Evolution Complete"), "{}", out);
+    for line in out.lines().filter(|l| l.contains("Winner")) {
+        assert!(line.contains("re-verified"), "unverified winner: {}", line);
+    }
+
+    let source = std::fs::read_to_string("tests/cli/inc.nf").unwrap();
+    let reference = Parser::new().parse(&source).unwrap();
+    let reference = CompiledProgram::compile(&reference, &CompileOptions::new(0)).unwrap();
+    let reference: extern "C" fn(i64) -> i64 = reference.get("main").unwrap();
+
+    let winner: Program = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+    std::fs::remove_file(&path).ok();
+    let winner = CompiledProgram::compile(&winner, &CompileOptions::new(0)).unwrap();
+    let winner: extern "C" fn(i64) -> i64 = winner.get("main").unwrap();
+    // The inputs evolve uses as test cases
+    for input in [10, 100, 1000] {
+        assert_eq!(winner(input), reference(input), "main({})", input);
+    }
+}
+
+#[test]
+fn evolve_machine_code() {
+    let out = stdout_of(&[
+        "evolve",
+        "tests/cli/inc.nf",
+        "--generations",
+        "3",
+        "--population",
+        "8",
+        "--machine-code",
+    ]);
+    assert!(out.contains("EXPERIMENTAL"), "{}", out);
+    assert!(out.contains("re-verified"), "{}", out);
+    assert!(out.contains("ret"), "{}", out);
+}
+
+#[test]
+fn run_with_bound_host_array() {
+    let path = std::env::temp_dir().join(format!("nf_cli_bind_{}.bin", std::process::id()));
+    let bytes: Vec<u8> = (1..=100i64).flat_map(|v| v.to_le_bytes()).collect();
+    std::fs::write(&path, bytes).unwrap();
+
+    let binding = format!("a={}", path.display());
+    nanoforge()
+        .args(["run", "tests/cli/sum_array.nf", "--bind-array", &binding])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Result: 5050"));
+    nanoforge()
+        .args(["run", "tests/cli/sum_array.nf", "--bind-array", &binding, "--pure"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Result: 5050"));
+    // Pure mode bounds accesses by the bound arrays
+    nanoforge()
+        .args(["run", "tests/cli/read_past.nf", "--bind-array", &binding, "--pure"])
+        .assert()
+        .code(1)
+        .stdout(predicate::str::contains("access-outside-buffers"));
+
+    nanoforge()
+        .args(["run", "tests/cli/sum_array.nf", "--bind-array", "a"])
+        .assert()
+        .code(1);
+
+    std::fs::remove_file(&path).ok();
+}
+
+#[test]
+fn run_and_benchmark_accept_stats() {
+    // Counters may be unavailable (VMs, perf_event_paranoid); the run must still succeed
+    nanoforge()
+        .args(["run", "tests/cli/sum_to_ten.nf", "--stats"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Result: 55"));
+    nanoforge()
+        .args(["benchmark", "tests/cli/sum_to_ten.nf", "--stats"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Avg Cycles/Op"));
+}
+
+#[test]
+fn run_profile_reports_each_function() {
+    // -O0: compile-time evaluation would fold both constant calls away
+    let out = stdout_of(&["run", "tests/cli/calls.nf", "--profile", "--level", "0"]);
+    assert!(out.contains("Result: 48"), "{}", out);
+    assert!(out.contains("Per-function profile"), "{}", out);
+    let mul_add = out
+        .lines()
+        .find(|l| l.trim_start().starts_with("mul_add"))
+        .unwrap_or_else(|| panic!("{}", out));
+    assert_eq!(mul_add.split_whitespace().nth(1), Some("2"), "{}", out);
+}
+
+#[test]
+fn run_report_explains_codegen() {
+    let out = stdout_of(&["run", "tests/cli/sum_to_ten.nf", "--report", "markdown"]);
+    assert!(out.contains("Result: 55"), "{}", out);
+    assert!(out.contains("## fn main ("), "{}", out);
+    assert!(out.contains("| vectorize | no | no array load in the body |"), "{}", out);
+    assert!(out.contains("| 10 | constant | yes | fully unrolled |"), "{}", out);
+    assert!(out.contains("Spilled: "), "{}", out);
+
+    let out = stdout_of(&["run", "tests/cli/calls.nf", "--report", "json"]);
+    let json = &out[out.find('{').unwrap_or_else(|| panic!("{}", out))..];
+    let report: serde_json::Value = serde_json::from_str(json).unwrap();
+    assert_eq!(report["opt_level"], 3);
+    let functions = report["functions"].as_array().unwrap();
+    assert!(functions.iter().any(|f| f["name"] == "mul_add"), "{}", out);
+    let total: u64 = functions.iter().map(|f| f["code_size"].as_u64().unwrap()).sum();
+    assert!(total > 0 && total <= report["code_size"].as_u64().unwrap());
+
+    nanoforge()
+        .args(["run", "tests/cli/sum_to_ten.nf", "--report", "yaml"])
+        .assert()
+        .failure();
+}
+
+#[test]
+fn sanitize_reports_out_of_bounds_store() {
+    nanoforge()
+        .args(["run", "tests/cli/oob_store.nf", "--sanitize"])
+        .assert()
+        .code(1)
+        .stdout(predicate::str::contains("heap-buffer-overflow"))
+        .stdout(predicate::str::contains("in main["))
+        .stdout(predicate::str::contains("Store"));
+
+    // In-bounds programs are unaffected
+    nanoforge()
+        .args(["run", "tests/cli/sum_to_ten.nf", "--sanitize"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Result: 55"));
+}
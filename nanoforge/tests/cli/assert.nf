@@ -0,0 +1,15 @@
+# Sums 1..n, checking the closed form along the way
+fn main(n) {
+    s = 0
+    i = 1
+    while i <= n {
+        s = s + i
+        i = i + 1
+    }
+    t = n * n
+    t = t + n
+    half = s * 2
+    assert half == t
+    assert s < 50
+    return s
+}
@@ -0,0 +1,3 @@
+fn main() {
+    x = 1
+    return x
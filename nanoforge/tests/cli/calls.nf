@@ -0,0 +1,11 @@
+fn main() {
+    x = mul_add(6, 7, 0)
+    y = mul_add(2, 3, x)
+    return y
+}
+
+fn mul_add(a, b, c) {
+    p = a * b
+    r = p + c
+    return r
+}
@@ -0,0 +1,7 @@
+fn main(k) {
+    x = k * 3
+    if x > 20 {
+        x = 20
+    }
+    return x
+}
@@ -0,0 +1,21 @@
+fn main(n) {
+    s = sum(n)
+    t = twice(n)
+    r = s + t
+    return r
+}
+fn sum(n) {
+    i = 0
+    s = 0
+    label loop
+    if i >= n goto done
+    s = s + i
+    i = i + 1
+    goto loop
+    label done
+    return s
+}
+fn twice(n) {
+    r = n + n
+    return r
+}
@@ -0,0 +1,5 @@
+# main(n) = n + 1; used by the variant/evolution commands (input 1000)
+fn main(n) {
+    r = n + 1
+    return r
+}
@@ -0,0 +1,6 @@
+# Dereferences an integer: rejected by the IR verifier
+fn main() {
+    x = 5
+    x[0] = 1
+    return 0
+}
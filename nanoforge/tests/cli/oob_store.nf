@@ -0,0 +1,12 @@
+# Writes one element past the end of a 4-element array
+fn main() {
+    a = alloc(32)
+    i = 0
+    while i < 5 {
+        a[i] = i
+        i = i + 1
+    }
+    v = a[0]
+    free(a)
+    return v
+}
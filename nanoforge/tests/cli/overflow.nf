@@ -0,0 +1,9 @@
+# Squares n until it no longer fits in an i64
+fn main(n) {
+    i = 0
+    while i < 8 {
+        n = n * n
+        i = i + 1
+    }
+    return n
+}
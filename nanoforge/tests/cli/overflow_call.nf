@@ -0,0 +1,14 @@
+# big(3) is a pure call with a constant argument, folded from -O1 up
+fn main() {
+    k = 3
+    r = big(k)
+    return r
+}
+
+fn big(x) {
+    y = x * 65536
+    y = y * 65536
+    y = y * 65536
+    y = y * 65536
+    return y
+}
@@ -0,0 +1,5 @@
+# Reads the element just past a host array bound with --bind-array a=FILE
+fn main(a, n) {
+    v = a[n]
+    return v
+}
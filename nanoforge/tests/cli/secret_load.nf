@@ -0,0 +1,10 @@
+fn main(k) {
+    a = alloc(8)
+    a[0] = 3
+    x = 0
+    if k > 0 {
+        x = a[0]
+    }
+    free(a)
+    return x
+}
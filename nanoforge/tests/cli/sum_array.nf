@@ -0,0 +1,11 @@
+# Sums a host array bound with --bind-array a=FILE
+fn main(a, n) {
+    sum = 0
+    i = 0
+    while i < n {
+        v = a[i]
+        sum = sum + v
+        i = i + 1
+    }
+    return sum
+}
@@ -0,0 +1,11 @@
+# main(n) = 0*0 + 1*1 + ... + (n-1)*(n-1); replayed with --specialize
+fn main(n) {
+    s = 0
+    i = 0
+    while i < n {
+        x = i * i
+        s = s + x
+        i = i + 1
+    }
+    return s
+}
@@ -0,0 +1,9 @@
+fn main() {
+    sum = 0
+    i = 10
+    while i > 0 {
+        sum = sum + i
+        i = i - 1
+    }
+    return sum
+}
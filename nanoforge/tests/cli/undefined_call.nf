@@ -0,0 +1,8 @@
+fn main() {
+    x = helper(1)
+    return x
+}
+
+fn helpers(a) {
+    return a
+}
@@ -0,0 +1,8 @@
+fn main() {
+    t = 5
+    while 0 < 0 {
+        t = 1
+    }
+    return 3
+    x = 2
+}
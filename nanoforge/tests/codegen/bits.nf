@@ -0,0 +1,35 @@
+fn main() {
+    n = 8
+    A = alloc(64)
+    i = 0
+    v = 12
+    label fill
+    if i == n goto count
+    A[i] = v
+    v = v * 7
+    i = i + 1
+    goto fill
+
+    label count
+    bits = 0
+    low = 0
+    i = 0
+    label scan
+    if i == n goto done
+    x = A[i]
+    p = popcnt(x)
+    bits = bits + p
+    t = ctz(x)
+    low = low + t
+    i = i + 1
+    goto scan
+
+    label done
+    free(A)
+    z = clz(bits)
+    r = bits * 10000
+    low = low * 100
+    r = r + low
+    r = r + z
+    return r
+}
@@ -0,0 +1,6 @@
+fn main() {
+    x = 2
+    x = x + 3
+    y = x * 2
+    return y
+}
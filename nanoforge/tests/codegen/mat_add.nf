@@ -0,0 +1,46 @@
+fn main() {
+    rows = 3
+    cols = 6
+    A = alloc2d(3, 6)
+    B = alloc2d(3, 6)
+    C = alloc2d(3, 6)
+
+    r = 0
+    label fill
+    if r == rows goto fill_done
+    c = 0
+    label fill_row
+    if c == cols goto fill_next
+    v = r + c
+    A[r, c] = v
+    B[r, c] = r
+    c = c + 1
+    goto fill_row
+    label fill_next
+    r = r + 1
+    goto fill
+
+    label fill_done
+    r = 0
+    label row_loop
+    if r == rows goto sum_rows
+    c = 0
+    label col_loop
+    if c == cols goto next_row
+    v1 = A[r, c]
+    v2 = B[r, c]
+    sum = v1 + v2
+    C[r, c] = sum
+    c = c + 1
+    goto col_loop
+    label next_row
+    r = r + 1
+    goto row_loop
+
+    label sum_rows
+    res = C[2, 5]
+    free(A)
+    free(B)
+    free(C)
+    return res
+}
@@ -0,0 +1,52 @@
+fn main() {
+    n = 10
+    A = alloc(80)
+    B = alloc(80)
+    C = alloc(80)
+
+    i = 0
+    label init
+    if i == n goto init_done
+    x = i - 5
+    A[i] = x
+    y = 2 - i
+    B[i] = y
+    i = i + 1
+    goto init
+
+    label init_done
+    i = 0
+    label loop
+    if i == n goto end
+    v1 = A[i]
+    v2 = B[i]
+    m = max(v1, v2)
+    C[i] = m
+    i = i + 1
+    goto loop
+
+    label end
+    sc = 0
+    sa = 0
+    i = 0
+    label total
+    if i == n goto done
+    c = C[i]
+    sc = sc + c
+    a = abs(c)
+    sa = sa + a
+    i = i + 1
+    goto total
+
+    label done
+    lo = C[3]
+    free(A)
+    free(B)
+    free(C)
+    lo = min(lo, sc)
+    r = sc * 1000
+    sa = sa * 10
+    r = r + sa
+    r = r + lo
+    return r
+}
@@ -0,0 +1,11 @@
+# Single-assignment ifs: cmp+cmov from O1 up, jumps at O0
+fn main(n) {
+    x = n * 3
+    if x > 100 {
+        x = 100
+    }
+    if x >= 0 goto done
+    x = 0
+    done:
+    return x
+}
@@ -0,0 +1,41 @@
+# Dense cases become a jump table, sparse ones a compare chain
+fn dense(x) {
+    r = 0
+    switch x {
+        -1 => r = 7
+        0 => r = 10
+        1 => r = 11
+        2 => {
+            r = x * 100
+            r = r + 1
+        }
+        4 => r = 14
+        _ => r = 99
+    }
+    return r
+}
+
+fn sparse(x) {
+    r = 1
+    switch x {
+        3 => r = 30
+        100 => r = 1000
+        -50 => r = 5
+    }
+    return r
+}
+
+fn main() {
+    s = 0
+    i = 0 - 60
+    while i < 105 {
+        a = dense(i)
+        w = i + 61
+        a = a * w
+        s = s + a
+        b = sparse(i)
+        s = s + b
+        i = i + 1
+    }
+    return s
+}
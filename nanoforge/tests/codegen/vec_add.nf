@@ -0,0 +1,32 @@
+fn main() {
+    n = 16
+    A = alloc(128)
+    B = alloc(128)
+    C = alloc(128)
+
+    i = 0
+    label init
+    if i == n goto init_done
+    A[i] = i
+    B[i] = i
+    i = i + 1
+    goto init
+
+    label init_done
+    i = 0
+    label loop
+    if i == n goto end
+    v1 = A[i]
+    v2 = B[i]
+    sum = v1 + v2
+    C[i] = sum
+    i = i + 1
+    goto loop
+
+    label end
+    res = C[10]
+    free(A)
+    free(B)
+    free(C)
+    return res
+}
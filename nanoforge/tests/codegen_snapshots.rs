@@ -0,0 +1,79 @@
+//! Disassembly snapshots of generated code at every optimization level.
+//!
+//! Any codegen change shows up as a snapshot diff. Review it, then accept with
+//! `cargo insta review` (or `INSTA_UPDATE=always cargo test --test codegen_snapshots`).
+#![cfg(target_arch = "x86_64")]
+
+use nanoforge::compiler::{CompileOptions, CompiledCode, Compiler};
+use nanoforge::cpu_features::{CpuFeatures, Microarch};
+use nanoforge::disasm;
+use nanoforge::explain;
+use nanoforge::parser::Parser as NanoParser;
+use std::fs;
+use std::path::Path;
+
+const CORPUS: &[&str] = &[
+    "tests/programs/test_control.nf",
+    "tests/programs/test_ops.nf",
+    "tests/codegen/bits.nf",
+    "tests/codegen/select.nf",
+    "tests/codegen/switch.nf",
+    "tests/codegen/mat_add.nf",
+    "tests/codegen/minmax.nf",
+    "tests/codegen/vec_add.nf",
+    "fib.nf",
+    "iter_fib.nf",
+    "recursion.nf",
+    "simple_add.nf",
+];
+
+fn compile(path: &str, level: u8) -> (String, CompiledCode) {
+    let source = fs::read_to_string(path).unwrap_or_else(|e| panic!("{}: {}", path, e));
+    let mut parser = NanoParser::new();
+    let prog = parser
+        .parse(&source)
+        .unwrap_or_else(|e| panic!("{}: parse error: {}", path, e));
+    // Pin the scheduling model and ISA so snapshots don't depend on the host CPU
+    let features = CpuFeatures {
+        has_popcnt: true,
+        has_bmi1: true,
+        has_lzcnt: true,
+        ..CpuFeatures::default()
+    };
+    let options = CompileOptions::new(level)
+        .uarch(Microarch::Generic)
+        .cpu_features(features);
+    let compiled = Compiler::compile_with_options(&prog, &options)
+        .unwrap_or_else(|e| panic!("{}: compile error: {}", path, e));
+    (source, compiled)
+}
+
+fn render(path: &str, level: u8) -> String {
+    let (_, compiled) = compile(path, level);
+    let (code, main_offset) = (compiled.code, compiled.main_offset);
+
+    format!(
+        "; {} -O{} ({} bytes, main at {:#06x})\n{}",
+        path,
+        level,
+        code.len(),
+        main_offset,
+        disasm::disassemble_normalized(&code)
+    )
+}
+
+#[test]
+fn codegen_snapshots() {
+    for path in CORPUS {
+        let stem = Path::new(path).file_stem().unwrap().to_str().unwrap();
+        for level in 0..=3 {
+            insta::assert_snapshot!(format!("{}_O{}", stem, level), render(path, level));
+        }
+    }
+}
+
+#[test]
+fn explain_keeps_folded_constants_on_their_line() {
+    let (source, compiled) = compile("tests/codegen/fold.nf", 1);
+    insta::assert_snapshot!("explain_fold_O1", explain::explain(&source, &compiled));
+}
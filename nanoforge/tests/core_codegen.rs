@@ -0,0 +1,908 @@
+//! Tests of nanoforge-core's front end and passes that check the code they
+//! lead to: each module's IR-only tests live next to it in nanoforge-core,
+//! which can't compile or run anything.
+#![cfg(target_arch = "x86_64")]
+
+mod parser {
+    use nanoforge::assembler::CodeGenerator;
+    use nanoforge::compiler::Compiler;
+    use nanoforge::ir::Opcode;
+    use nanoforge::jit_memory::DualMappedMemory;
+    use nanoforge::parser::Parser;
+
+    #[test]
+    fn test_parse_and_run() {
+        let script = "
+            fn main() {
+                x = 10
+                y = 32
+                z = x + y
+                return z
+            }
+        ";
+        let mut parser = Parser::new();
+        let prog = parser.parse(script).expect("Parsing failed");
+        let (code, main_offset) = Compiler::compile_program(&prog, 0).expect("Compilation failed");
+
+        let memory = DualMappedMemory::new(4096).unwrap();
+        CodeGenerator::emit_to_memory(&memory, &code, 0);
+        let func_ptr: extern "C" fn() -> i64 =
+            unsafe { std::mem::transmute(memory.rx_ptr.add(main_offset)) };
+        assert_eq!(func_ptr(), 42);
+    }
+
+    #[test]
+    fn test_loop_sum() {
+        // Updated to use while loop sugar
+        let script = "
+            fn main() {
+                sum = 0
+                i = 10
+                while i > 0 {
+                    sum = sum + i
+                    i = i - 1
+                }
+                return sum
+            }
+        ";
+        let mut parser = Parser::new();
+        let prog = parser.parse(script).expect("Parsing failed");
+        let code = Compiler::compile_program(&prog, 0).expect("Compilation failed");
+        let memory = DualMappedMemory::new(4096).unwrap();
+        CodeGenerator::emit_to_memory(&memory, &code.0, 0);
+        let func_ptr: extern "C" fn() -> i64 = unsafe { std::mem::transmute(memory.rx_ptr) };
+        assert_eq!(func_ptr(), 55);
+    }
+
+    #[test]
+    fn test_function_call() {
+        let script = "
+            fn main() {
+                x = add(10, 20)
+                return x
+            }
+            fn add(a, b) {
+                c = a + b
+                return c
+            }
+        ";
+        let mut parser = Parser::new();
+        let prog = parser.parse(script).expect("Parsing failed");
+        let code = Compiler::compile_program(&prog, 0).expect("Compilation failed");
+        let memory = DualMappedMemory::new(4096).unwrap();
+        CodeGenerator::emit_to_memory(&memory, &code.0, 0);
+        let func_ptr: extern "C" fn() -> i64 = unsafe { std::mem::transmute(memory.rx_ptr) };
+        assert_eq!(func_ptr(), 30);
+    }
+
+    #[test]
+    fn test_2d_index_uses_row_stride() {
+        // Runtime stride declared for an array allocated elsewhere
+        let script = "
+            fn main(cols) {
+                m = alloc2d(4, cols)
+                i = 3
+                j = 2
+                m[i, j] = 40
+                p = m
+                stride(p, cols)
+                x = p[3, j]
+                y = m[0, 1]
+                free(m)
+                return x
+            }
+        ";
+        let prog = Parser::new().parse(script).expect("Parsing failed");
+        let main = &prog.functions[0];
+        let text: Vec<String> = main.instructions.iter().map(|i| i.to_string()).collect();
+        // Size and row offsets are scaled by the column count, then by 8
+        assert_eq!(text.iter().filter(|t| t.starts_with("Mul") && t.ends_with("Imm(8)")).count(), 4);
+        assert_eq!(main.instructions.iter().filter(|i| i.op == Opcode::Store(0)).count(), 1);
+
+        let (code, main_offset) = Compiler::compile_program(&prog, 0).expect("Compilation failed");
+        let memory = DualMappedMemory::new(4096).unwrap();
+        CodeGenerator::emit_to_memory(&memory, &code, 0);
+        let func_ptr: extern "C" fn(i64) -> i64 =
+            unsafe { std::mem::transmute(memory.rx_ptr.add(main_offset)) };
+        assert_eq!(func_ptr(5), 40);
+    }
+
+    #[test]
+    fn test_index_offsets_become_displacements() {
+        let script = "
+            fn main(i) {
+                m = alloc2d(3, 4)
+                a = alloc(64)
+                a[i+1] = 7
+                a[i - 1] = 5
+                m[2, i+2] = 9
+                x = a[i+1]
+                y = a[i-1]
+                z = m[2, i + 2]
+                x = x * y
+                x = x * z
+                free(a)
+                free(m)
+                return x
+            }
+        ";
+        let prog = Parser::new().parse(script).expect("Parsing failed");
+        let ops: Vec<&Opcode> = prog.functions[0]
+            .instructions
+            .iter()
+            .map(|i| &i.op)
+            .filter(|op| op.displacement().is_some())
+            .collect();
+        use Opcode::{Load, Store};
+        assert_eq!(ops, [&Store(1), &Store(-1), &Store(2), &Load(1), &Load(-1), &Load(2)]);
+
+        let (code, main_offset) = Compiler::compile_program(&prog, 0).expect("Compilation failed");
+        let memory = DualMappedMemory::new(4096).unwrap();
+        CodeGenerator::emit_to_memory(&memory, &code, 0);
+        let func_ptr: extern "C" fn(i64) -> i64 =
+            unsafe { std::mem::transmute(memory.rx_ptr.add(main_offset)) };
+        assert_eq!(func_ptr(1), 315);
+
+        for (bad, expected) in [
+            ("x = a[i+j]", "Index offset must be a constant"),
+            ("x = a[i+268435456]", "Index offset must be a constant"),
+            ("x = a[i+1, 2]", "Only the column of a 2D index can have an offset"),
+        ] {
+            let err = Parser::new()
+                .parse(&format!("fn main(a, i, j) {{\n{}\nreturn x\n}}", bad))
+                .unwrap_err();
+            assert!(err.contains(expected) && err.contains("line 2"), "{}", err);
+        }
+    }
+}
+
+mod lowering {
+    use nanoforge::parser::Parser;
+
+    #[test]
+    fn test_blocks_scope_and_shadow_variables() {
+        use nanoforge::compiled_program::CompiledProgram;
+        use nanoforge::compiler::CompileOptions;
+
+        let src = "fn main(n) {
+            s = 0
+            t = 100
+            while n > 0 {
+                let t = n * 2
+                s = s + t
+                n = n - 1
+            }
+            if s > 0 {
+                u = 7
+                s = s + u
+            }
+            v = 5
+            s = s + v
+            s = s + t
+            return s
+        }";
+        let prog = Parser::new().parse(src).unwrap();
+        let text: Vec<String> = prog.functions[0]
+            .instructions
+            .iter()
+            .map(|i| i.to_string())
+            .collect();
+        // n=10, s=11, t=12; the inner t is 13 and u, v reuse it after its block
+        assert!(
+            text.contains(&"Mul Reg(13), Imm(2)".to_string()),
+            "{:?}",
+            text
+        );
+        assert!(
+            text.contains(&"Mov Reg(13), Imm(7)".to_string()),
+            "{:?}",
+            text
+        );
+        assert!(
+            text.contains(&"Mov Reg(13), Imm(5)".to_string()),
+            "{:?}",
+            text
+        );
+
+        for level in [0, 2] {
+            let compiled = CompiledProgram::compile(&prog, &CompileOptions::new(level)).unwrap();
+            let main = compiled.get_fn("main").unwrap();
+            // 2 * (1 + 2 + 3) + 7 + 5 + 100
+            assert_eq!(main.call(&[3]), Ok(124));
+        }
+
+        // A 2D array's stride ends with its scope
+        let err = Parser::new()
+            .parse("fn main() {\nif 1 == 1 {\nlet m = alloc2d(2, 2)\n}\nm = alloc(32)\nx = m[1, 1]\nreturn x\n}")
+            .unwrap_err();
+        assert!(err.contains("line 6"), "{}", err);
+    }
+
+    #[test]
+    fn test_switch_jump_tables_and_compare_chains_run() {
+        use nanoforge::compiled_program::CompiledProgram;
+        use nanoforge::compiler::CompileOptions;
+        use nanoforge::interpreter::Interpreter;
+
+        let prog = Parser::new()
+            .parse(&std::fs::read_to_string("tests/codegen/switch.nf").unwrap())
+            .unwrap();
+        let dense = |x: i64| match x {
+            -1 => 7,
+            0 => 10,
+            1 => 11,
+            2 => 201,
+            4 => 14,
+            _ => 99,
+        };
+        let sparse = |x: i64| match x {
+            3 => 30,
+            100 => 1000,
+            -50 => 5,
+            _ => 1,
+        };
+        let expected: i64 = (-60..105).map(|i| dense(i) * (i + 61) + sparse(i)).sum();
+        assert_eq!(Interpreter::new(&prog).call("main", &[]), Ok(expected));
+
+        for level in 0..=3 {
+            let options = CompileOptions::new(level).verify_code(true);
+            let compiled = CompiledProgram::compile(&prog, &options).unwrap();
+            assert_eq!(compiled.get_fn("main").unwrap().call(&[]), Ok(expected), "-O{}", level);
+            // Out of range either way, including values that wrap when biased
+            let table = compiled.get_fn("dense").unwrap();
+            for x in [i64::MIN, -2, 3, 5, i64::MAX] {
+                assert_eq!(table.call(&[x]), Ok(dense(x)), "dense({}) at -O{}", x, level);
+            }
+        }
+    }
+}
+
+mod optimizer {
+    use nanoforge::cpu_features::VectorWidth;
+    use nanoforge::ir::{Access, Function, Instruction, Opcode, Operand};
+    use nanoforge::optimizer::Optimizer;
+    use nanoforge::parser::Parser;
+    use nanoforge::report::{OptimizationReport, Transform};
+
+    fn optimized_main(src: &str, level: u8) -> Function {
+        let mut prog = Parser::new().parse(src).unwrap();
+        Optimizer::optimize_program(&mut prog, level);
+        prog.functions.into_iter().find(|f| f.name == "main").unwrap()
+    }
+
+    fn count(func: &Function, op: Opcode) -> usize {
+        func.instructions.iter().filter(|i| i.op == op).count()
+    }
+
+    #[test]
+    fn test_jams_unrolled_copies_into_displacements() {
+        use nanoforge::compiled_program::CompiledProgram;
+        use nanoforge::compiler::CompileOptions;
+
+        let src = "fn main(n) {
+            A = alloc(168)
+            m = n + 1
+            i = 0
+            label fill
+            if i == m goto go
+            A[i] = i
+            i = i + 1
+            goto fill
+            label go
+            s = 0
+            i = 0
+            #pragma unroll(4)
+            label sum
+            if i == n goto end
+            v = A[i]
+            w = A[i+1]
+            v = v * w
+            s = s + v
+            i = i + 1
+            goto sum
+            label end
+            free(A)
+            return s
+        }";
+        let mut prog = Parser::new().parse(src).unwrap();
+        let mut report = OptimizationReport::new(&prog, 2);
+        Optimizer::optimize_program_with_report(&mut prog, 2, VectorWidth::default(), &mut report);
+        let func = &prog.functions[0];
+        let jam = |label: &str| {
+            report.functions[0]
+                .loops
+                .iter()
+                .find(|d| d.label == label && d.transform == Transform::Jam)
+                .map(|d| (d.applied, d.reason.clone()))
+        };
+        assert_eq!(jam("sum"), Some((1, "4 copies, one index step".to_string())));
+        assert_eq!(
+            jam("fill"),
+            Some((0, "the index is used outside array subscripts".to_string()))
+        );
+        // Copy k loads A[i+k] and A[i+k+1]; the remainder A[i] and A[i+1]
+        let loads: Vec<usize> = (0..5).map(|disp| count(func, Opcode::Load(disp))).collect();
+        assert_eq!(loads, [2, 3, 2, 2, 1]);
+        let steps = func.instructions.iter().filter(|i| i.op == Opcode::Add && i.src1 == Some(Operand::Imm(4)));
+        assert_eq!(steps.count(), 1);
+
+        let prog = Parser::new().parse(src).unwrap();
+        for level in [0, 2] {
+            let options = CompileOptions::new(level).sanitize(true);
+            let compiled = CompiledProgram::compile(&prog, &options).unwrap();
+            let main = compiled.get_fn("main").unwrap();
+            // Sizes that leave 0 to 3 iterations for the remainder
+            for n in [0, 1, 4, 6, 7, 19] {
+                let expected: i64 = (0..n).map(|i| i * (i + 1)).sum();
+                assert_eq!(main.call(&[n]), Ok(expected), "O{} n={}", level, n);
+            }
+        }
+    }
+
+    #[test]
+    fn test_vectorizes_inner_dimension_of_2d_loop() {
+        use nanoforge::compiled_program::CompiledProgram;
+        use nanoforge::compiler::CompileOptions;
+
+        let src = std::fs::read_to_string("tests/codegen/mat_add.nf").unwrap();
+        let func = optimized_main(&src, 3);
+        // Row pointer arithmetic stays scalar; the column loop goes vector
+        assert!(count(&func, Opcode::VAdd) > 0);
+        assert!(func.instructions.iter().any(|i| i.dest == Some(Operand::Label("col_loop_vec".into()))));
+        assert!(!func.instructions.iter().any(|i| i.dest == Some(Operand::Label("row_loop_vec".into()))));
+
+        let prog = Parser::new().parse(&src).unwrap();
+        for level in [0, 3] {
+            let options = CompileOptions::new(level).sanitize(true);
+            let compiled = CompiledProgram::compile(&prog, &options).unwrap();
+            let main: extern "C" fn() -> u64 = compiled.get("main").unwrap();
+            assert_eq!(main(), 9, "O{}", level);
+        }
+    }
+
+    #[test]
+    fn test_vectorizes_elementwise_min_max_abs() {
+        use nanoforge::compiled_program::CompiledProgram;
+        use nanoforge::compiler::CompileOptions;
+        use nanoforge::cpu_features::CpuFeatures;
+
+        let max_src = std::fs::read_to_string("tests/codegen/minmax.nf").unwrap();
+        let abs_src = "fn main() {
+            n = 7
+            A = alloc(56)
+            i = 0
+            label fill
+            if i == n goto go
+            x = i * 3
+            x = x - 10
+            A[i] = x
+            i = i + 1
+            goto fill
+            label go
+            i = 0
+            label loop
+            if i == n goto end
+            v = A[i]
+            a = abs(v)
+            A[i] = a
+            i = i + 1
+            goto loop
+            label end
+            r = A[0]
+            q = A[3]
+            r = r * 100
+            r = r + q
+            free(A)
+            return r
+        }";
+        assert!(count(&optimized_main(&max_src, 3), Opcode::VMax) > 0);
+        assert!(count(&optimized_main(abs_src, 3), Opcode::VAbs) > 0);
+
+        // vpmaxsq/vpabsq, then the AVX2 compare-and-blend fallback
+        let detected = CpuFeatures::detect();
+        let avx2 = CpuFeatures { has_avx512vl: false, ..detected };
+        for (src, expected) in [(max_src.as_str(), 11149), (abs_src, 1001)] {
+            let prog = Parser::new().parse(src).unwrap();
+            for features in [detected, avx2] {
+                for level in [0, 3] {
+                    let options = CompileOptions::new(level).cpu_features(features);
+                    let compiled = CompiledProgram::compile(&prog, &options).unwrap();
+                    let main: extern "C" fn() -> i64 = compiled.get("main").unwrap();
+                    assert_eq!(main(), expected, "O{} {:?}", level, features);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_vectorizes_loads_at_an_offset() {
+        use nanoforge::compiled_program::CompiledProgram;
+        use nanoforge::compiler::CompileOptions;
+
+        let body = |step: &str| {
+            format!(
+                "fn main() {{
+                    n = 9
+                    A = alloc(80)
+                    B = alloc(80)
+                    C = alloc(80)
+                    i = 0
+                    label fill
+                    if i == 10 goto go
+                    x = i * i
+                    A[i] = x
+                    B[i] = i
+                    C[i] = 0
+                    i = i + 1
+                    goto fill
+                    label go
+                    i = 0
+                    label loop
+                    if i == n goto end
+                    {}
+                    i = i + 1
+                    goto loop
+                    label end
+                    r = C[0]
+                    q = C[5]
+                    s = C[8]
+                    t = A[9]
+                    q = q * 100
+                    s = s * 10000
+                    r = r + q
+                    r = r + s
+                    r = r + t
+                    free(A)
+                    free(B)
+                    free(C)
+                    return r
+                }}",
+                step
+            )
+        };
+        // C[i] = (i+1)^2 + i; A is left alone
+        let ahead = body("a = A[i+1]\nb = B[i]\nc = a + b\nC[i] = c");
+        // A[i+1] = A[i] + B[i] = (i+1)i/2 needs the previous iteration's store;
+        // C stays zero
+        let behind = body("a = A[i]\nb = B[i]\nc = a + b\nA[i+1] = c");
+
+        let func = optimized_main(&ahead, 3);
+        assert!(count(&func, Opcode::VLoad(1, Access::Unaligned)) > 0);
+        assert!(count(&func, Opcode::VStore(0, Access::Unaligned)) > 0);
+        assert_eq!(count(&optimized_main(&behind, 3), Opcode::VAdd), 0);
+
+        for (src, expected) in [(ahead, 894101 + 81), (behind, 36)] {
+            let prog = Parser::new().parse(&src).unwrap();
+            for level in [0, 3] {
+                let options = CompileOptions::new(level).sanitize(true);
+                let compiled = CompiledProgram::compile(&prog, &options).unwrap();
+                let main: extern "C" fn() -> i64 = compiled.get("main").unwrap();
+                assert_eq!(main(), expected, "O{}", level);
+            }
+        }
+    }
+
+    #[test]
+    fn test_selects_aligned_and_nontemporal_access() {
+        use nanoforge::compiled_program::CompiledProgram;
+        use nanoforge::compiler::{CompileOptions, Compiler};
+        use nanoforge::cpu_features::CpuFeatures;
+
+        let body = |alloc: &str, pragma: &str| {
+            format!(
+                "fn main() {{
+                    n = 37
+                    A = {alloc}
+                    B = {alloc}
+                    C = {alloc}
+                    i = 0
+                    label fill
+                    if i == n goto go
+                    x = i * i
+                    A[i] = x
+                    B[i] = i
+                    i = i + 1
+                    goto fill
+                    label go
+                    i = 0
+                    {pragma}
+                    label loop
+                    if i == n goto end
+                    a = A[i]
+                    b = B[i]
+                    c = a + b
+                    C[i] = c
+                    i = i + 1
+                    goto loop
+                    label end
+                    r = C[36]
+                    s = C[5]
+                    r = r * 1000
+                    r = r + s
+                    free(A)
+                    free(B)
+                    free(C)
+                    return r
+                }}"
+            )
+        };
+        // malloc only promises 16 bytes; a ymm access needs 32
+        let plain = body("alloc(320)", "");
+        let aligned = body("alloc_aligned(320, 64)", "");
+        let streamed = body("alloc_aligned(320, 64)", "#pragma nontemporal");
+
+        // The loop is unrolled: every copy must get the same accesses
+        let accesses = |src: &str, load: Access, store: Access| {
+            let func = optimized_main(src, 3);
+            let (mut loads, mut stores) = (0, 0);
+            for instr in &func.instructions {
+                match instr.op {
+                    Opcode::VLoad(_, access) => {
+                        assert_eq!(access, load);
+                        loads += 1;
+                    }
+                    Opcode::VStore(_, access) => {
+                        assert_eq!(access, store);
+                        stores += 1;
+                    }
+                    _ => {}
+                }
+            }
+            assert!(loads > 0 && stores > 0);
+        };
+        accesses(&plain, Access::Unaligned, Access::Unaligned);
+        accesses(&aligned, Access::Aligned, Access::Aligned);
+        accesses(&streamed, Access::Aligned, Access::NonTemporal);
+
+        let expected = (36 * 36 + 36) * 1000 + 30;
+        let widest = CpuFeatures::detect().vector_width();
+        for src in [plain, aligned, streamed.clone()] {
+            let prog = Parser::new().parse(&src).unwrap();
+            for width in [
+                VectorWidth::Lanes2,
+                VectorWidth::Lanes4,
+                VectorWidth::Lanes8,
+            ] {
+                if width.lanes() > widest.lanes() {
+                    continue;
+                }
+                for sanitize in [false, true] {
+                    let options = CompileOptions::new(3)
+                        .vector_width(width)
+                        .sanitize(sanitize);
+                    let compiled = CompiledProgram::compile(&prog, &options).unwrap();
+                    let main: extern "C" fn() -> i64 = compiled.get("main").unwrap();
+                    assert_eq!(main(), expected, "{:?}", width);
+                }
+            }
+        }
+
+        let prog = Parser::new().parse(&streamed).unwrap();
+        let code = Compiler::compile_with_options(&prog, &CompileOptions::new(3)).unwrap();
+        let asm = nanoforge::disasm::disassemble(&code.code);
+        for insn in ["vmovdqa ymm", "vmovntdq", "sfence"] {
+            assert!(asm.contains(insn), "{}\n{}", insn, asm);
+        }
+    }
+
+    #[test]
+    fn test_prefetches_each_array_of_vectorized_loop_once() {
+        use nanoforge::compiled_program::CompiledProgram;
+        use nanoforge::compiler::CompileOptions;
+
+        let src = std::fs::read_to_string("tests/codegen/vec_add.nf").unwrap();
+        let mut func = optimized_main(&src, 3);
+        assert!(Optimizer::insert_prefetches(&mut func, 128));
+        assert!(!Optimizer::insert_prefetches(&mut func, 128));
+
+        let at = |name: &str| {
+            let target = Some(Operand::Label(name.into()));
+            func.instructions.iter().position(|i| i.op == Opcode::Label && i.dest == target).unwrap()
+        };
+        let (start, end) = (at("loop_vec"), at("loop_cleanup"));
+        let prefetches: Vec<(usize, &Instruction)> =
+            func.instructions.iter().enumerate().filter(|(_, i)| i.op == Opcode::PrefetchT0).collect();
+        assert_eq!(prefetches.len(), 2, "one per loaded array");
+        for (idx, prefetch) in prefetches {
+            assert!(start < idx && idx < end);
+            assert_eq!(prefetch.src2, Some(Operand::Imm(128)));
+            assert_eq!(func.instructions[idx + 1].op, Opcode::VLoad(0, Access::Unaligned));
+        }
+
+        let prog = Parser::new().parse(&src).unwrap();
+        for (level, distance) in [(3, 0), (3, 256), (2, 256)] {
+            let options = CompileOptions::new(level).prefetch_distance(distance);
+            let compiled = CompiledProgram::compile(&prog, &options).unwrap();
+            let main: extern "C" fn() -> u64 = compiled.get("main").unwrap();
+            assert_eq!(main(), 20);
+            let asm = nanoforge::disasm::disassemble(&compiled.code().code);
+            assert_eq!(asm.contains("prefetcht0"), level == 3 && distance > 0, "{}", asm);
+        }
+    }
+}
+
+mod report {
+    use nanoforge::compiler::{CompileOptions, Compiler};
+    use nanoforge::parser::Parser;
+    use nanoforge::report::Transform;
+
+    const SUM: &str = "fn main(n) {
+        i = 0
+        s = 0
+        label loop
+        if i >= n goto done
+        s = s + i
+        i = i + 1
+        goto loop
+        label done
+        return s
+    }";
+
+    #[test]
+    fn test_records_passes_loops_and_sizes() {
+        let prog = Parser::new().parse(SUM).unwrap();
+        let code = Compiler::compile_with_options(&prog, &CompileOptions::new(2)).unwrap();
+        let report = &code.report;
+        assert_eq!(report.code_size, code.code.len());
+
+        let main = report.function("main").unwrap();
+        assert!(main.code_size > 0 && main.code_size <= report.code_size);
+        let unroll = main
+            .loops
+            .iter()
+            .find(|d| d.transform == Transform::Unroll)
+            .unwrap();
+        assert_eq!(unroll.label, "loop");
+        assert!(unroll.applied > 0, "{:?}", unroll);
+        assert!(main.passes["loop_unrolling"] >= unroll.applied);
+
+        // -O0 leaves loops alone
+        let code = Compiler::compile_with_options(&prog, &CompileOptions::new(0)).unwrap();
+        assert!(code.report.function("main").unwrap().loops.is_empty());
+
+        let json: serde_json::Value = serde_json::from_str(&report.to_json()).unwrap();
+        assert_eq!(json["functions"][0]["loops"][0]["transform"], "unroll");
+        assert!(report.to_markdown().contains("| loop | unroll | yes"));
+    }
+}
+
+mod pipeliner {
+    use nanoforge::compiled_program::CompiledProgram;
+    use nanoforge::compiler::CompileOptions;
+    use nanoforge::parser::Parser;
+    use nanoforge::pipeliner::pipeline_program;
+
+    const SUM_OF_SQUARES: &str = "fn main(n) {
+        a = alloc(512)
+        i = 0
+        fill:
+        if i == 64 goto filled
+        a[i] = i
+        i = i + 1
+        goto fill
+        filled:
+        i = 0
+        sum = 0
+        loop:
+        if i == n goto done
+        x = a[i]
+        x = x * x
+        sum = sum + x
+        i = i + 1
+        goto loop
+        done:
+        free(a)
+        return sum
+    }";
+
+    fn run(source: &str, pipeline: bool, input: u64) -> u64 {
+        let prog = Parser::new().parse(source).unwrap();
+        // The sanitizer catches a kernel that loads past the end of the array
+        let options = CompileOptions::new(3)
+            .software_pipeline(pipeline)
+            .sanitize(true);
+        let compiled = CompiledProgram::compile(&prog, &options).unwrap();
+        let main: extern "C" fn(u64) -> u64 = compiled.get("main").unwrap();
+        main(input)
+    }
+
+    #[test]
+    fn test_pipelines_counted_load_loop() {
+        let mut prog = Parser::new().parse(SUM_OF_SQUARES).unwrap();
+        assert!(pipeline_program(&mut prog));
+        let func = &prog.functions[0];
+        let text: Vec<String> = func.instructions.iter().map(|i| i.to_string()).collect();
+        assert!(text.contains(&"Label Label(\"loop_swp\")".to_string()));
+        assert!(text.contains(&"Label Label(\"loop_swp_last\")".to_string()));
+        // The fill loop has a store and no load: left alone
+        assert!(!text.contains(&"Label Label(\"fill_swp\")".to_string()));
+        assert_eq!(func.loop_hints["loop_swp"].unroll, Some(1));
+        assert!(func.loop_hints["loop_swp"].no_vectorize);
+
+        for n in [0, 1, 2, 7, 64] {
+            let expected: u64 = (0..n).map(|i| i * i).sum();
+            assert_eq!(run(SUM_OF_SQUARES, true, n), expected, "n = {}", n);
+            assert_eq!(run(SUM_OF_SQUARES, false, n), expected, "n = {}", n);
+        }
+    }
+
+    #[test]
+    fn test_pipelined_kernel_is_not_vectorized() {
+        // The vectorizer's pattern matches this loop; it must keep off the kernel
+        let source = std::fs::read_to_string("tests/codegen/vec_add.nf").unwrap();
+        assert_eq!(run(&source, true, 0), run(&source, false, 0));
+    }
+}
+
+mod specialize {
+    use nanoforge::compiled_program::CompiledProgram;
+    use nanoforge::compiler::CompileOptions;
+    use nanoforge::parser::Parser;
+
+    const SUM_OF_SQUARES: &str = "fn main(n) {
+        i = 0
+        s = 0
+        while i < n {
+            x = i * i
+            s = s + x
+            i = i + 1
+        }
+        return s
+    }";
+
+    #[test]
+    fn test_guarded_versions_fall_back_to_the_generic_loop() {
+        // 6 trips fully unroll; 48 run 8 copies per test
+        let versions = [(6, "fully unrolled"), (48, "remainder-free, 8 copies per test")];
+        for (expected, version) in versions {
+            let mut prog = Parser::new().parse(SUM_OF_SQUARES).unwrap();
+            prog.functions[0].hints.expected_args.insert(0, expected);
+            for level in [2, 3] {
+                let compiled =
+                    CompiledProgram::compile(&prog, &CompileOptions::new(level)).unwrap();
+                let candidate = &compiled.code().report.functions[0].specializations[0];
+                assert_eq!(candidate.trip_count, expected as u64);
+                assert_eq!(candidate.guarded_arg, Some(0));
+                assert!(candidate.applied, "{:?}", candidate);
+                assert_eq!(candidate.version, version);
+
+                let main: extern "C" fn(u64) -> u64 = compiled.get("main").unwrap();
+                for n in [0, 1, 5, 6, 7, 47, 48, 49, 100] {
+                    let sum: u64 = (0..n).map(|i| i * i).sum();
+                    assert_eq!(main(n), sum, "expected {}, n = {}, -O{}", expected, n, level);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_constant_arg_copies_fall_back_to_the_generic_body() {
+        for constant in [6, 1000] {
+            let mut prog = Parser::new().parse(SUM_OF_SQUARES).unwrap();
+            prog.functions[0].hints.constant_args.insert(0, constant);
+            for level in [2, 3] {
+                let compiled =
+                    CompiledProgram::compile(&prog, &CompileOptions::new(level)).unwrap();
+                let report = &compiled.code().report.functions[0];
+                assert_eq!(report.passes.get("specialize_constant_args"), Some(&1));
+                // The copy's loop runs a constant number of trips
+                let candidate = &report.specializations[0];
+                assert_eq!(candidate.trip_count, constant as u64);
+                assert_eq!(candidate.guarded_arg, None);
+
+                let main: extern "C" fn(u64) -> u64 = compiled.get("main").unwrap();
+                for n in [0, 5, 6, 7, 999, 1000, 1001] {
+                    let sum: u64 = (0..n).map(|i| i * i).sum();
+                    assert_eq!(main(n), sum, "constant {}, n = {}, -O{}", constant, n, level);
+                }
+            }
+        }
+    }
+}
+
+mod ranges {
+    use nanoforge::compiled_program::CompiledProgram;
+    use nanoforge::compiler::CompileOptions;
+    use nanoforge::parser::Parser;
+    use nanoforge::sanitizer::{self, FaultKind};
+
+    // The fill stays inside the 128 bytes; a[k] only if 0 <= k < 16
+    const FILL_AND_PICK: &str = "fn main(k) {
+        a = alloc(128)
+        i = 0
+        while i < 16 {
+            a[i] = i
+            i = i + 1
+        }
+        x = a[k]
+        free(a)
+        return x
+    }";
+
+    #[test]
+    fn test_proven_checks_are_dropped() {
+        let prog = Parser::new().parse(FILL_AND_PICK).unwrap();
+        let options = CompileOptions::new(0).sanitize(true);
+        let compiled = CompiledProgram::compile(&prog, &options).unwrap();
+        // Alloc, a[k] and free: the store in the loop is unchecked
+        let sites: Vec<&str> = compiled
+            .code()
+            .sanitizer_sites
+            .iter()
+            .map(|s| s.instruction.split(' ').next().unwrap())
+            .collect();
+        assert_eq!(sites, ["Alloc", "Load(0)", "Free"]);
+
+        // Sixteen iterations don't need fuel
+        let main = compiled.get_fn("main").unwrap();
+        compiled.set_fuel("main", 1).unwrap();
+        assert_eq!(main.call(&[5]), Ok(5));
+        let asm = nanoforge::disasm::disassemble(&compiled.code().code);
+        assert!(!asm.contains("dec "), "{}", asm);
+
+        assert!(main.call(&[16]).is_err());
+        let fault = sanitizer::take_fault().unwrap();
+        assert_eq!(fault.kind, FaultKind::HeapBufferOverflow);
+    }
+}
+
+mod loops {
+    use nanoforge::compiled_program::CompiledProgram;
+    use nanoforge::compiler::CompileOptions;
+    use nanoforge::interpreter::Interpreter;
+    use nanoforge::loops::rotate;
+    use nanoforge::parser::Parser;
+    use nanoforge::passes::Pass;
+
+    const TWO_SHAPES: &str = "fn main(n) {
+        s = 0
+        i = 0
+        label up
+        if i >= n goto up_done
+        s = s + i
+        i = i + 1
+        goto up
+        label up_done
+        j = 0
+        while j < n {
+            s = s + j
+            j = j + 1
+        }
+        return s
+    }";
+
+    #[test]
+    fn test_rotation_saves_a_branch_per_iteration() {
+        let prog = Parser::new().parse(TWO_SHAPES).unwrap();
+        let mut rotated = prog.clone();
+        assert!(rotate(&mut rotated.functions[0]));
+        for n in [0, 1, 100] {
+            let mut before = Interpreter::new(&prog);
+            let mut after = Interpreter::new(&rotated);
+            let expected = before.call("main", &[n]).unwrap();
+            assert_eq!(after.call("main", &[n]).unwrap(), expected);
+            // One jump back per iteration of each loop, and the while
+            // loop's exit no longer jumps past its body
+            let saved = 2 * n as u64 + u64::from(n > 0);
+            assert_eq!(before.branches() - after.branches(), saved, "n = {}", n);
+        }
+    }
+
+    #[test]
+    fn test_rotated_loops_compute_the_same() {
+        let prog = Parser::new().parse(TWO_SHAPES).unwrap();
+        for level in 1..=3 {
+            let on = CompiledProgram::compile(&prog, &CompileOptions::new(level)).unwrap();
+            let options = CompileOptions::new(level).disable_pass(Pass::RotateLoops);
+            let off = CompiledProgram::compile(&prog, &options).unwrap();
+            let report = &on.code().report.functions[0];
+            assert_eq!(report.passes.get("rotate_loops"), Some(&1), "-O{}", level);
+
+            let on: extern "C" fn(u64) -> u64 = on.get("main").unwrap();
+            let off: extern "C" fn(u64) -> u64 = off.get("main").unwrap();
+            for n in [0, 1, 2, 7, 8, 9, 1000] {
+                assert_eq!(on(n), off(n), "n = {}, -O{}", n, level);
+            }
+        }
+    }
+}
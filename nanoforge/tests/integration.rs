@@ -1,6 +1,5 @@
-use nanoforge::compiler::Compiler;
-use nanoforge::assembler::CodeGenerator;
-use nanoforge::jit_memory::DualMappedMemory;
+use nanoforge::compiled_program::CompiledProgram;
+use nanoforge::compiler::CompileOptions;
 use nanoforge::parser::Parser as NanoParser;
 use std::fs;
 use std::path::Path;
@@ -13,16 +12,12 @@ fn run_test_file(path: &Path) -> Result<(), String> {
     let prog = parser.parse(&content).map_err(|e| format!("Parse Error: {}", e))?;
     
     // Compile (Level 2 = Scalar)
-    let (code, main_offset) = Compiler::compile_program(&prog, 2)
+    let compiled = CompiledProgram::compile(&prog, &CompileOptions::new(2))
         .map_err(|e| format!("Compile Error: {}", e))?;
-        
-    let memory = DualMappedMemory::new(code.len() + 4096)
-        .map_err(|e| format!("Memory Error: {}", e))?;
-        
-    CodeGenerator::emit_to_memory(&memory, &code, 0);
     
-    let func_ptr: extern "C" fn() -> i64 = 
-        unsafe { std::mem::transmute(memory.rx_ptr.add(main_offset)) };
+    let func_ptr: extern "C" fn() -> i64 = compiled
+        .get("main")
+        .ok_or("main() must take no parameters")?;
         
     let result = func_ptr();
     
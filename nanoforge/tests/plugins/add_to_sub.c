@@ -0,0 +1,18 @@
+/* Test plugin: turns every Add into a Sub, and refuses sanitized builds. */
+#include <stdlib.h>
+#include <string.h>
+
+char *nanoforge_plugin_run(const char *program, const char *options) {
+    if (strstr(options, "\"sanitize\":true")) {
+        return strdup("{\"error\": \"sanitized builds are not supported\"}");
+    }
+    char *out = strdup(program);
+    for (char *p = out; (p = strstr(p, "\"Add\"")); p += 5) {
+        memcpy(p + 1, "Sub", 3);
+    }
+    return out;
+}
+
+void nanoforge_plugin_free(char *result) {
+    free(result);
+}
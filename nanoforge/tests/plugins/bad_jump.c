@@ -0,0 +1,19 @@
+/* Test plugin: replaces the program with one that jumps to a missing label. */
+#include <stdlib.h>
+#include <string.h>
+
+char *nanoforge_plugin_run(const char *program, const char *options) {
+    (void)program;
+    (void)options;
+    return strdup(
+        "{\"functions\": [{\"name\": \"main\", \"args\": [],"
+        " \"instructions\": [{\"op\": \"Jmp\", \"dest\": {\"Label\": \"nowhere\"},"
+        " \"src1\": null, \"src2\": null}],"
+        " \"hints\": {\"unroll\": null, \"no_vectorize\": false, \"align\": null,"
+        " \"nontemporal\": false},"
+        " \"loop_hints\": {}}]}");
+}
+
+void nanoforge_plugin_free(char *result) {
+    free(result);
+}